@@ -0,0 +1,36 @@
+//! Removing the marks left on files which didn't compress enough, for `applesauce clean-markers`
+
+use crate::display_path;
+use crate::progress::Verbosity;
+use applesauce::markers;
+use std::path::PathBuf;
+
+/// Remove the mark from every file under each of `paths`
+///
+/// Each file a mark was removed from is listed when verbose. Returns false if any file could not
+/// be cleaned.
+pub fn clean_paths(paths: &[PathBuf], verbosity: Verbosity) -> bool {
+    let mut ok = true;
+    let mut removed_count: u64 = 0;
+    for path in paths {
+        for (path, removed) in markers::clean(path) {
+            match removed {
+                Ok(true) => {
+                    removed_count += 1;
+                    if verbosity >= Verbosity::Verbose {
+                        println!("{}: removed mark", display_path(&path));
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("{}: error: {e}", display_path(&path));
+                    ok = false;
+                }
+            }
+        }
+    }
+    if verbosity >= Verbosity::Normal {
+        println!("Removed {removed_count} marks");
+    }
+    ok
+}
@@ -0,0 +1,778 @@
+//! Command line argument definitions
+
+use crate::progress::Verbosity;
+use applesauce::compressor::{self, Kind};
+use applesauce::{BackupExclusion, Durability, FileOrder, Priority, TimePreservation, Verify};
+use clap::builder::{PossibleValue, TypedValueParser};
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, clap::Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(propagate_version = true, disable_help_subcommand = true)]
+#[command(after_long_help = crate::exit_status::HELP)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Commands,
+
+    /// Output chrome tracing format to a file
+    ///
+    /// The passed file can be passed to chrome at chrome://tracing
+    #[arg(long, global(true))]
+    pub chrome_tracing: Option<PathBuf>,
+
+    #[arg(short, long, global(true), action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    #[arg(short, long, global(true), action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Run all threads at a lower priority, for fully background operation
+    ///
+    /// Compressor threads always run at a lower priority than the threads reading and writing
+    /// files, this lowers the priority of all of them.
+    #[arg(long, global(true))]
+    pub nice: bool,
+
+    /// Back off whenever the machine is in use, for running unattended
+    ///
+    /// Runs every thread at the background QoS class, and throttles reading and writing files,
+    /// so disk I/O waits while other processes use the disk. Much slower than `--nice`.
+    #[arg(long, global(true))]
+    pub throttle: bool,
+
+    /// Show a progress bar for each path passed, beneath the total
+    ///
+    /// At most 6 bars are shown: with more paths, the last bar shows the progress of the rest.
+    #[arg(long, global(true))]
+    pub group_progress: bool,
+
+    /// The format of log messages
+    ///
+    /// `json` logs an event for each file processed, skipped, or which failed, as a JSON object
+    /// per line, and disables progress bars. Which events are logged can be changed with the
+    /// RUST_LOG environment variable.
+    #[arg(long, global(true), value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Append log messages to a file, instead of writing them to stderr
+    #[arg(long, global(true))]
+    pub log_file: Option<PathBuf>,
+}
+
+impl Cli {
+    pub fn verbosity(&self) -> Verbosity {
+        let verbosity = self.verbose as i8 - self.quiet as i8;
+        match verbosity {
+            ..=-1 => Verbosity::Quiet,
+            0 => Verbosity::Normal,
+            1.. => Verbosity::Verbose,
+        }
+    }
+
+    /// Progress bars are only shown on a terminal, and never alongside structured logs
+    pub fn show_progress(&self) -> bool {
+        matches!(self.log_format, LogFormat::Text) && io::stderr().is_terminal()
+    }
+
+    pub fn priority(&self) -> Priority {
+        if self.nice {
+            Priority::Background
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Commands {
+    /// Compress files
+    ///
+    /// Each file is replaced with a compressed copy, which has the same contents, size,
+    /// permissions, owner, and times. The copy is a new file, so its inode number changes: tools
+    /// which track files by inode number will see a new file.
+    Compress(Compress),
+
+    /// Decompress files
+    Decompress(Decompress),
+
+    /// Get info about compression for file(s)
+    Info(Info),
+
+    /// Decompress recently used compressed files, and compress files which have not been used
+    /// recently
+    Rebalance(Rebalance),
+
+    /// Mark files compressed again, when only their compressed flag was removed
+    ///
+    /// Some tools (e.g. sync tools, or `chflags 0`) clear the flag which marks a file compressed,
+    /// but leave its compressed data, so the file reads as empty. Files whose compressed data is
+    /// intact, and whose data fork is empty, are marked compressed again. Files with data in their
+    /// data fork are reported and left as they are, marking them compressed could hide that data.
+    Repair(Repair),
+
+    /// Remove the marks left on files which didn't compress enough
+    ///
+    /// Files are only marked when compressing with `--remember-incompressible`.
+    CleanMarkers(CleanMarkers),
+
+    /// Compress stdin into a stream container on stdout
+    ///
+    /// The container holds the data compressed in blocks, like a compressed file, but needs no
+    /// filesystem support, so it can be written to a pipe, or any file. Restore the data with
+    /// `decode`.
+    Encode(Encode),
+
+    /// Decompress a stream container from stdin, written by `encode`, onto stdout
+    Decode(Decode),
+
+    /// Print a completion script for a shell
+    Completions(Completions),
+
+    /// Print help for a subcommand, or a help topic
+    Help(Help),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable messages, shown above the progress bars
+    Text,
+    /// A JSON object per line
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum VerifyStrategy {
+    /// Don't verify
+    Off,
+    /// Check the structure of the new file, without reading back any of its data
+    Quick,
+    /// Compare a checksum computed while reading the original file
+    Checksum,
+    /// Compare the new file against the original, byte by byte
+    Full,
+}
+
+impl From<VerifyStrategy> for Verify {
+    fn from(strategy: VerifyStrategy) -> Self {
+        match strategy {
+            VerifyStrategy::Off => Verify::Off,
+            VerifyStrategy::Quick => Verify::Quick,
+            VerifyStrategy::Checksum => Verify::Checksum,
+            VerifyStrategy::Full => Verify::Full,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Fsync {
+    /// Don't flush anything
+    None,
+    /// Flush each new file's data and xattrs before it replaces the original
+    Data,
+    /// Also flush the directory containing each new file after it replaces the original
+    Full,
+}
+
+impl From<Fsync> for Durability {
+    fn from(fsync: Fsync) -> Self {
+        match fsync {
+            Fsync::None => Durability::None,
+            Fsync::Data => Durability::Data,
+            Fsync::Full => Durability::Full,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum TmExcluded {
+    /// Only process excluded items
+    Only,
+    /// Don't process excluded items
+    Skip,
+    /// Process items whether or not they are excluded
+    Ignore,
+}
+
+impl From<TmExcluded> for BackupExclusion {
+    fn from(tm_excluded: TmExcluded) -> Self {
+        match tm_excluded {
+            TmExcluded::Only => BackupExclusion::Only,
+            TmExcluded::Skip => BackupExclusion::Skip,
+            TmExcluded::Ignore => BackupExclusion::Ignore,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum PreserveTimes {
+    /// Restore the access, modification, creation, and added times
+    All,
+    /// Restore all times except the access time
+    ModificationOnly,
+    /// Don't restore any times
+    None,
+}
+
+impl From<PreserveTimes> for TimePreservation {
+    fn from(preserve_times: PreserveTimes) -> Self {
+        match preserve_times {
+            PreserveTimes::All => TimePreservation::All,
+            PreserveTimes::ModificationOnly => TimePreservation::ModificationOnly,
+            PreserveTimes::None => TimePreservation::None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Order {
+    /// Process files as they are found
+    Discovery,
+    /// Find every file first, then process the largest files first
+    LargestFirst,
+    /// Find every file first, then process the smallest files first
+    SmallestFirst,
+}
+
+impl From<Order> for FileOrder {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Discovery => FileOrder::Discovery,
+            Order::LargestFirst => FileOrder::LargestFirst,
+            Order::SmallestFirst => FileOrder::SmallestFirst,
+        }
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Decompress {
+    /// Paths to recursively decompress
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Decompress manually, rather than allowing the OS to do decompression
+    ///
+    /// This may be useful to do decompression from an older OS version which can't
+    /// natively read the compressed file
+    #[arg(long)]
+    pub manual: bool,
+
+    /// With `--manual`, fail files with a compression type applesauce can't decompress
+    ///
+    /// By default, such files are decompressed by the OS instead, if it supports the type.
+    #[arg(long, requires = "manual")]
+    pub strict_manual: bool,
+
+    /// Clear the compression of files whose compressed data in the resource fork is missing
+    ///
+    /// These files can't be read or decompressed, their contents are lost. By default they are
+    /// skipped and left unchanged. With this flag, their compressed flag and decmpfs xattr are
+    /// removed, leaving an empty file which can be read.
+    #[arg(long)]
+    pub force_clear: bool,
+
+    /// Create each new file as an APFS clone of the file it replaces
+    ///
+    /// The clone keeps all of the original's xattrs and security info, without copying them.
+    /// APFS marks clones as possibly sharing blocks, so files written this way are skipped by
+    /// later runs with `--skip-clones`.
+    #[arg(long)]
+    pub clone_temp_files: bool,
+
+    /// Flush each new file to disk before it replaces the original
+    ///
+    /// Without this, a power loss soon after a run can leave replaced files whose compressed data
+    /// was never written to disk. `data` flushes each new file's data and xattrs before it is
+    /// renamed, `full` also flushes its directory after the rename. Much slower with many small
+    /// files.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "data"
+    )]
+    pub fsync: Option<Fsync>,
+
+    /// Verify that the compressed file has the same contents as the original before replacing it
+    ///
+    /// This is an extra safety check to ensure that the compressed file is exactly the same as the
+    /// original file. By default, this compares a checksum of the original file to the
+    /// decompressed contents of the new file, `--verify=full` compares the files byte by byte.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "checksum"
+    )]
+    pub verify: Option<VerifyStrategy>,
+
+    /// How to treat items excluded from Time Machine backups (e.g. by `tmutil addexclusion`)
+    ///
+    /// `skip` doesn't process excluded files, or anything in excluded directories. `only`
+    /// processes only excluded files, and files in excluded directories.
+    #[arg(long, value_enum, default_value_t = TmExcluded::Ignore)]
+    pub tm_excluded: TmExcluded,
+
+    /// Which times of each file (and its parent directory) to restore after processing it
+    ///
+    /// `modification-only` leaves the access time reflecting that the file was read. `none`
+    /// leaves all times as they are after the file is replaced. A directory modified by something
+    /// else while it's being processed keeps its new times.
+    #[arg(long, value_enum, default_value_t = PreserveTimes::All)]
+    pub preserve_times: PreserveTimes,
+
+    /// The order to process files in
+    ///
+    /// `largest-first` starts the largest files early, so they overlap with the many small files,
+    /// rather than leaving one thread working on a huge file found near the end of the run.
+    /// Ordering by size finds every file before processing any of them.
+    #[arg(long, value_enum, default_value_t = Order::Discovery)]
+    pub order: Order,
+
+    /// After processing, check that the times of each directory were restored
+    ///
+    /// Directories whose times could not be restored (e.g. because of permissions, or because
+    /// they were removed during the run) are reported, and counted in the summary.
+    #[arg(long)]
+    pub check_dir_times: bool,
+
+    /// Exit with an error (status 2) if any directory could not be read
+    ///
+    /// Without this, unreadable directories are reported, and the rest of the paths are still
+    /// processed, but they don't affect the exit status.
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Compress {
+    /// Paths to recursively compress
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// The compression level to use
+    #[arg(
+        short, long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..=9)
+    )]
+    pub level: u32,
+
+    /// The minimum compression ratio
+    ///
+    /// Files will be skipped if they compress to a larger size than this ratio
+    /// of the original size
+    ///
+    /// A value of 0.0 will skip all files
+    /// A value of 1.0 will only skip files which cannot be compressed at all
+    /// Values greater than 1.0 are valid, and will allow forcing compression to
+    /// be used even if it results in a larger file
+    #[arg(short = 'r', long, default_value_t = 0.95)]
+    pub minimum_compression_ratio: f64,
+
+    /// The type of compression to use
+    #[arg(short, long, default_value = kind_value_name(Kind::default()), value_parser = KindParser)]
+    pub compression: Kind,
+
+    /// Skip files whose first N blocks (of 64KiB) don't compress to the minimum compression ratio
+    ///
+    /// This avoids reading all of large files which won't compress (e.g. media files). Files
+    /// whose sample compresses well enough are compressed in full.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub sample_blocks: Option<u32>,
+
+    /// Skip files which may share their blocks with another file, e.g. APFS clones (`cp -c`)
+    ///
+    /// Compressing a clone gives it its own blocks, which can use more space than sharing them.
+    /// This uses the flag APFS sets on files which may share blocks: both a clone and the file it
+    /// was cloned from are skipped, even if the other has since been deleted.
+    #[arg(long)]
+    pub skip_clones: bool,
+
+    /// Create each new file as an APFS clone of the file it replaces
+    ///
+    /// The clone keeps all of the original's xattrs and security info, without copying them.
+    /// APFS marks clones as possibly sharing blocks, so files written this way are skipped by
+    /// later runs with `--skip-clones`.
+    #[arg(long)]
+    pub clone_temp_files: bool,
+
+    /// Flush each new file to disk before it replaces the original
+    ///
+    /// Without this, a power loss soon after a run can leave replaced files whose compressed data
+    /// was never written to disk. `data` flushes each new file's data and xattrs before it is
+    /// renamed, `full` also flushes its directory after the rename. Much slower with many small
+    /// files.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "data"
+    )]
+    pub fsync: Option<Fsync>,
+
+    /// Skip files in log locations (`/var/log`, `/Library/Logs`, `~/Library/Logs`) modified within
+    /// this long
+    ///
+    /// Logs which are still being written (e.g. by a launchd service) would lose everything
+    /// written after they are replaced. Accepts a number followed by a unit: s, m, h, d or w.
+    /// `0s` never skips log files.
+    #[arg(long, value_name = "DURATION", default_value = "10m", value_parser = parse_duration)]
+    pub active_log_window: Duration,
+
+    /// Skip any file modified within this long
+    ///
+    /// Accepts a number followed by a unit: s, m, h, d or w (e.g. `30m` or `1d`)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub skip_recently_modified: Option<Duration>,
+
+    /// Compress files even if a process has them open for writing
+    ///
+    /// By default, files which any process (that can be inspected) has open for writing are
+    /// skipped, because replacing a file which is still being written loses what is written
+    /// after it. Checking lists the open files of each process with the file open, which has a
+    /// cost for each file.
+    #[arg(long)]
+    pub no_in_use_check: bool,
+
+    /// Compress sparse files, rather than skipping them
+    ///
+    /// Compressed files can't have holes, so compressing a sparse file (e.g. a disk image)
+    /// allocates space for every hole, and its temp file can be much larger than the original.
+    #[arg(long)]
+    pub compress_sparse: bool,
+
+    /// Mark files which don't compress enough, and skip marked files which haven't changed
+    ///
+    /// Marks are small xattrs, which record the file's modification time and size, and the
+    /// compression kind and minimum ratio used. A marked file is only compressed again once it
+    /// changes, or with another kind or a lower minimum ratio. Remove marks with `clean-markers`.
+    #[arg(long)]
+    pub remember_incompressible: bool,
+
+    /// Attempt every file, even in directories where no file compresses
+    ///
+    /// By default, once 25 files have been attempted in a directory, if at least 90% of them
+    /// failed to compress to the minimum compression ratio (e.g. a directory of photos or
+    /// videos), the rest of the files in that directory are skipped.
+    #[arg(long)]
+    pub no_adaptive_skip: bool,
+
+    /// Also skip subdirectories of a directory where no file compresses
+    #[arg(long, conflicts_with = "no_adaptive_skip")]
+    pub adaptive_skip_subdirectories: bool,
+
+    /// Write compressed copies into this directory, rather than replacing the files in place
+    ///
+    /// Each path is copied into the directory under its own name, keeping the structure of
+    /// directories inside it. The originals are left untouched. Files which aren't compressed
+    /// are copied unchanged.
+    #[arg(short, long, value_name = "DIR")]
+    pub output: Option<PathBuf>,
+
+    /// Replace files which already exist in the output directory
+    ///
+    /// Without this, files which already exist are reported as errors, and left as they are.
+    #[arg(long, requires = "output")]
+    pub force: bool,
+
+    /// Verify that the compressed file has the same contents as the original before replacing it
+    ///
+    /// This is an extra safety check to ensure that the compressed file is exactly the same as the
+    /// original file. By default, this compares a checksum of the original file to the
+    /// decompressed contents of the new file, `--verify=full` compares the files byte by byte.
+    /// `--verify=quick` only checks the structure of the compressed file, without reading back
+    /// any of its data.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "checksum"
+    )]
+    pub verify: Option<VerifyStrategy>,
+
+    /// How to treat items excluded from Time Machine backups (e.g. by `tmutil addexclusion`)
+    ///
+    /// `skip` doesn't process excluded files, or anything in excluded directories. `only`
+    /// processes only excluded files, and files in excluded directories.
+    #[arg(long, value_enum, default_value_t = TmExcluded::Ignore)]
+    pub tm_excluded: TmExcluded,
+
+    /// Skip items excluded from Time Machine backups, the same as `--tm-excluded=skip`
+    ///
+    /// Excluded items are typically caches and disk images, which aren't worth compressing.
+    #[arg(long, conflicts_with = "tm_excluded")]
+    pub skip_tm_excluded: bool,
+
+    /// Don't process files in app, framework, or kernel extension bundles
+    ///
+    /// Replacing a bundle's files can stop it passing strict code signature validation. By
+    /// default, files in bundles are processed with a warning for each bundle.
+    #[arg(long)]
+    pub skip_bundles: bool,
+
+    /// Check the code signature of each bundle before and after processing its files
+    ///
+    /// A bundle whose signature was valid before, but not after, is reported as an error.
+    #[arg(long, conflicts_with = "skip_bundles")]
+    pub verify_signatures: bool,
+
+    /// Which times of each file (and its parent directory) to restore after processing it
+    ///
+    /// `modification-only` leaves the access time reflecting that the file was read. `none`
+    /// leaves all times as they are after the file is replaced. A directory modified by something
+    /// else while it's being processed keeps its new times.
+    #[arg(long, value_enum, default_value_t = PreserveTimes::All)]
+    pub preserve_times: PreserveTimes,
+
+    /// The order to process files in
+    ///
+    /// `largest-first` starts the largest files early, so they overlap with the many small files,
+    /// rather than leaving one thread working on a huge file found near the end of the run.
+    /// Ordering by size finds every file before processing any of them.
+    #[arg(long, value_enum, default_value_t = Order::Discovery)]
+    pub order: Order,
+
+    /// After processing, check that the times of each directory were restored
+    ///
+    /// Directories whose times could not be restored (e.g. because of permissions, or because
+    /// they were removed during the run) are reported, and counted in the summary.
+    #[arg(long)]
+    pub check_dir_times: bool,
+
+    /// Exit with an error (status 2) if any directory could not be read
+    ///
+    /// Without this, unreadable directories are reported, and the rest of the paths are still
+    /// processed, but they don't affect the exit status.
+    #[arg(long)]
+    pub strict: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Rebalance {
+    /// Paths to recursively rebalance
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Files accessed or modified within this long are considered in use
+    ///
+    /// Accepts a number followed by a unit: s, m, h, d or w (e.g. `12h` or `7d`)
+    #[arg(long, default_value = "7d", value_parser = parse_duration)]
+    pub hot_window: Duration,
+
+    /// Only print the files which would be compressed or decompressed
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// The compression level to use
+    #[arg(
+        short, long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..=9)
+    )]
+    pub level: u32,
+
+    /// The minimum compression ratio
+    ///
+    /// Files will be skipped if they compress to a larger size than this ratio
+    /// of the original size
+    #[arg(short = 'r', long, default_value_t = 0.95)]
+    pub minimum_compression_ratio: f64,
+
+    /// The type of compression to use
+    #[arg(short, long, default_value = kind_value_name(Kind::default()), value_parser = KindParser)]
+    pub compression: Kind,
+
+    /// Decompress manually, rather than allowing the OS to do decompression
+    #[arg(long)]
+    pub manual: bool,
+
+    /// Verify that new files have the same contents as the originals before replacing them
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "checksum"
+    )]
+    pub verify: Option<VerifyStrategy>,
+}
+
+/// Parse a duration like `30s`, `10m`, `12h`, `7d` or `2w`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in `{s}`, expected one of s, m, h, d, w"))?;
+    let (amount, unit) = s.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|e| format!("invalid duration `{s}`: {e}"))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown unit `{unit}`, expected one of s, m, h, d, w"
+            ))
+        }
+    };
+    amount
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration `{s}` is too large"))
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Repair {
+    /// Paths to recursively check
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// Repair every file which can be repaired, without asking
+    ///
+    /// Without this, each file is confirmed before it is repaired, which requires a terminal.
+    #[arg(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct CleanMarkers {
+    /// Paths to recursively clean
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Encode {
+    /// The compression level to use
+    #[arg(
+        short, long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..=9)
+    )]
+    pub level: u32,
+
+    /// The type of compression to use
+    #[arg(short, long, default_value = kind_value_name(Kind::default()), value_parser = KindParser)]
+    pub kind: Kind,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Decode {}
+
+#[derive(Debug, clap::Args)]
+pub struct Info {
+    /// Paths to inspect
+    ///
+    /// Info will be reported for each path
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
+
+    /// List every file, one per line, instead of totals for directories
+    #[arg(long)]
+    pub list: bool,
+
+    /// List every file as CSV, with a header row
+    ///
+    /// Columns are path, compressed, kind, size, on_disk_size, savings and error. Sizes are in
+    /// bytes, savings is only filled in for compressed files, and may be negative. Files which
+    /// can't be read are listed with only their path and an error.
+    #[arg(long, conflicts_with = "list")]
+    pub csv: bool,
+
+    /// Print a one line summary for each path, in the order given
+    ///
+    /// Each line is one of `compressed (<kind>) <size> → <size on disk> (<savings>% saved)`,
+    /// `not compressed, <size>`, `not stored locally, <size>` or `error: <message>`, where kind
+    /// is like `LZFSE/rfork` or `ZLIB/xattr`. This format is stable. Exits with an error if any
+    /// path couldn't be read.
+    #[arg(long, conflicts_with_all = ["list", "csv"])]
+    pub summary: bool,
+
+    /// For each directory, also list the N compressed files saving the most space, and the N
+    /// largest files which aren't compressed
+    #[arg(long, value_name = "N", conflicts_with_all = ["list", "csv", "summary"])]
+    pub top: Option<usize>,
+
+    /// Clear the compressed flag of files which are marked compressed, but have no decmpfs xattr
+    ///
+    /// These files (usually left by broken copy tools) read as empty, even though their size
+    /// isn't zero. Clearing the flag makes them read as the data actually stored. Files with a
+    /// decmpfs xattr are never changed. Prints each repaired path, and a count at the end.
+    #[arg(long, conflicts_with_all = ["list", "csv", "summary", "top"])]
+    pub repair: bool,
+}
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Completions {
+    /// The shell to generate completions for
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Help {
+    /// A subcommand, or `skip-reasons` to list the reasons files may be skipped
+    pub topic: Option<String>,
+}
+
+/// Every compression kind, in the order they're offered on the command line
+const KINDS_OFFERED: [Kind; 3] = [Kind::Lzfse, Kind::Zlib, Kind::Lzvn];
+
+/// The name a compression kind is shown with in help and completions
+///
+/// Names are parsed ignoring case, so `LZFSE` (its [`Kind::name`]) is also accepted.
+const fn kind_value_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Zlib => "zlib",
+        Kind::Lzvn => "lzvn",
+        Kind::Lzfse => "lzfse",
+    }
+}
+
+/// Parses a compression kind by name, only offering kinds which were compiled in
+#[derive(Debug, Copy, Clone)]
+struct KindParser;
+
+impl TypedValueParser for KindParser {
+    type Value = Kind;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        _arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        let value = value
+            .to_str()
+            .ok_or_else(|| clap::Error::new(clap::error::ErrorKind::InvalidUtf8).with_cmd(cmd))?;
+        value.parse().map_err(|e: compressor::ParseKindError| {
+            clap::Error::raw(clap::error::ErrorKind::InvalidValue, format!("{e}\n")).with_cmd(cmd)
+        })
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(
+            KINDS_OFFERED
+                .into_iter()
+                .filter(|kind| kind.supported())
+                .map(|kind| PossibleValue::new(kind_value_name(kind))),
+        ))
+    }
+}
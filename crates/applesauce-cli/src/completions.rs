@@ -0,0 +1,309 @@
+//! Shell completion scripts, generated from the command line argument definitions
+
+use crate::cli::Shell;
+use clap::{Arg, Command};
+use std::io::{self, Write};
+
+/// Write a completion script for `shell` to `out`
+pub fn generate(shell: Shell, cmd: &mut Command, out: &mut dyn Write) -> io::Result<()> {
+    // Building propagates global args to the subcommands
+    cmd.build();
+    match shell {
+        Shell::Bash => bash(cmd, out),
+        Shell::Zsh => zsh(cmd, out),
+        Shell::Fish => fish(cmd, out),
+    }
+}
+
+fn subcommands(cmd: &Command) -> impl Iterator<Item = &Command> {
+    cmd.get_subcommands().filter(|sub| !sub.is_hide_set())
+}
+
+/// The arguments passed by flag, rather than by position
+fn options(cmd: &Command) -> impl Iterator<Item = &Arg> {
+    cmd.get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+}
+
+/// The first line of the help of an argument or command
+fn summary(help: Option<&clap::builder::StyledStr>) -> String {
+    help.map(|help| help.to_string().lines().next().unwrap_or("").to_owned())
+        .unwrap_or_default()
+}
+
+fn flags(arg: &Arg) -> Vec<String> {
+    let mut flags = Vec::new();
+    if let Some(short) = arg.get_short() {
+        flags.push(format!("-{short}"));
+    }
+    if let Some(long) = arg.get_long() {
+        flags.push(format!("--{long}"));
+    }
+    flags
+}
+
+/// The values an option accepts, if it takes a value from a known set
+fn possible_values(arg: &Arg) -> Option<Vec<String>> {
+    if !arg.get_action().takes_values() {
+        return None;
+    }
+    let values: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_owned())
+        .collect();
+    (!values.is_empty()).then_some(values)
+}
+
+fn bash(cmd: &Command, out: &mut dyn Write) -> io::Result<()> {
+    let name = cmd.get_name();
+    let subcommand_names: Vec<&str> = subcommands(cmd).map(Command::get_name).collect();
+
+    writeln!(out, "_{name}() {{")?;
+    writeln!(out, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\"")?;
+    writeln!(out, "    local prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"")?;
+    writeln!(out, "    local subcommand=\"\" word")?;
+    writeln!(
+        out,
+        "    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do"
+    )?;
+    writeln!(out, "        case \"$word\" in")?;
+    writeln!(
+        out,
+        "            {}) subcommand=\"$word\"; break ;;",
+        subcommand_names.join("|")
+    )?;
+    writeln!(out, "        esac")?;
+    writeln!(out, "    done")?;
+
+    writeln!(out, "    case \"$prev\" in")?;
+    let mut seen_value_flags = Vec::new();
+    for arg in subcommands(cmd).flat_map(options).chain(options(cmd)) {
+        // Values must be attached with `=`, which bash splits into separate words
+        if arg.is_require_equals_set() {
+            continue;
+        }
+        let Some(values) = possible_values(arg) else {
+            continue;
+        };
+        let flags = flags(arg);
+        if seen_value_flags.contains(&flags) {
+            continue;
+        }
+        writeln!(
+            out,
+            "        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;",
+            flags.join("|"),
+            values.join(" ")
+        )?;
+        seen_value_flags.push(flags);
+    }
+    writeln!(out, "    esac")?;
+
+    writeln!(out, "    local opts")?;
+    writeln!(out, "    case \"$subcommand\" in")?;
+    let top_level: Vec<String> = subcommand_names
+        .iter()
+        .map(|&name| name.to_owned())
+        .chain(options(cmd).flat_map(flags))
+        .collect();
+    writeln!(out, "        \"\") opts=\"{}\" ;;", top_level.join(" "))?;
+    for sub in subcommands(cmd) {
+        let opts: Vec<String> = options(sub).flat_map(flags).collect();
+        writeln!(
+            out,
+            "        {}) opts=\"{}\" ;;",
+            sub.get_name(),
+            opts.join(" ")
+        )?;
+    }
+    writeln!(out, "    esac")?;
+    writeln!(
+        out,
+        "    if [[ -z \"$subcommand\" || \"$cur\" == -* ]]; then"
+    )?;
+    writeln!(
+        out,
+        "        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))"
+    )?;
+    writeln!(out, "    else")?;
+    writeln!(out, "        COMPREPLY=($(compgen -f -- \"$cur\"))")?;
+    writeln!(out, "    fi")?;
+    writeln!(out, "}}")?;
+    writeln!(out, "complete -o filenames -F _{name} {name}")
+}
+
+/// Make help text safe to use in a zsh `_arguments` spec or `_describe` entry
+fn zsh_escape(help: &str) -> String {
+    help.chars()
+        .filter(|c| !matches!(c, '[' | ']'))
+        .collect::<String>()
+        .replace('\'', "'\\''")
+        .replace(':', "\\:")
+}
+
+fn zsh_arg_specs(cmd: &Command) -> Vec<String> {
+    let mut specs = Vec::new();
+    for arg in options(cmd) {
+        let help = zsh_escape(&summary(arg.get_help()));
+        let repeat = if matches!(arg.get_action(), clap::ArgAction::Count) {
+            "*"
+        } else {
+            ""
+        };
+        let value = if arg.get_action().takes_values() {
+            let name = arg.get_id().as_str();
+            match possible_values(arg) {
+                Some(values) => format!(":{name}:({})", values.join(" ")),
+                None => format!(":{name}:"),
+            }
+        } else {
+            String::new()
+        };
+        for flag in flags(arg) {
+            specs.push(format!("'{repeat}{flag}[{help}]{value}'"));
+        }
+    }
+    if cmd.get_positionals().next().is_some() {
+        specs.push("'*:path:_files'".to_owned());
+    }
+    specs
+}
+
+fn zsh(cmd: &Command, out: &mut dyn Write) -> io::Result<()> {
+    let name = cmd.get_name();
+    writeln!(out, "#compdef {name}")?;
+    writeln!(out)?;
+    writeln!(out, "_{name}() {{")?;
+    writeln!(out, "    local -a subcommands")?;
+    writeln!(out, "    subcommands=(")?;
+    for sub in subcommands(cmd) {
+        writeln!(
+            out,
+            "        '{}:{}'",
+            sub.get_name(),
+            zsh_escape(&summary(sub.get_about()))
+        )?;
+    }
+    writeln!(out, "    )")?;
+    writeln!(out, "    if (( CURRENT == 2 )); then")?;
+    writeln!(out, "        _describe 'command' subcommands")?;
+    writeln!(out, "        return")?;
+    writeln!(out, "    fi")?;
+    writeln!(out, "    local subcommand=\"$words[2]\"")?;
+    writeln!(out, "    shift words")?;
+    writeln!(out, "    (( CURRENT-- ))")?;
+    writeln!(out, "    case \"$subcommand\" in")?;
+    for sub in subcommands(cmd) {
+        writeln!(out, "        {})", sub.get_name())?;
+        writeln!(out, "            _arguments -s \\")?;
+        for spec in zsh_arg_specs(sub) {
+            writeln!(out, "                {spec} \\")?;
+        }
+        writeln!(out, "                && return")?;
+        writeln!(out, "            ;;")?;
+    }
+    writeln!(out, "    esac")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "_{name} \"$@\"")
+}
+
+fn fish_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_option(out: &mut dyn Write, name: &str, condition: &str, arg: &Arg) -> io::Result<()> {
+    write!(out, "complete -c {name} -n '{condition}'")?;
+    if let Some(short) = arg.get_short() {
+        write!(out, " -s {short}")?;
+    }
+    if let Some(long) = arg.get_long() {
+        write!(out, " -l {long}")?;
+    }
+    if arg.get_action().takes_values() {
+        write!(out, " -r")?;
+        if let Some(values) = possible_values(arg) {
+            write!(out, " -f -a '{}'", values.join(" "))?;
+        }
+    }
+    writeln!(out, " -d '{}'", fish_escape(&summary(arg.get_help())))
+}
+
+fn fish(cmd: &Command, out: &mut dyn Write) -> io::Result<()> {
+    let name = cmd.get_name();
+    for arg in options(cmd) {
+        fish_option(out, name, "__fish_use_subcommand", arg)?;
+    }
+    for sub in subcommands(cmd) {
+        writeln!(
+            out,
+            "complete -c {name} -n '__fish_use_subcommand' -f -a {} -d '{}'",
+            sub.get_name(),
+            fish_escape(&summary(sub.get_about()))
+        )?;
+    }
+    for sub in subcommands(cmd) {
+        let condition = format!("__fish_seen_subcommand_from {}", sub.get_name());
+        for arg in options(sub) {
+            fish_option(out, name, &condition, arg)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::{CommandFactory, Parser};
+
+    #[test]
+    fn scripts_mention_subcommands() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let mut script = Vec::new();
+            generate(shell, &mut Cli::command(), &mut script).unwrap();
+            let script = String::from_utf8(script).unwrap();
+            assert!(!script.is_empty(), "{shell:?}");
+            for subcommand in [
+                "compress",
+                "decompress",
+                "info",
+                "rebalance",
+                "repair",
+                "clean-markers",
+                "encode",
+                "decode",
+                "completions",
+                "help",
+            ] {
+                assert!(script.contains(subcommand), "{shell:?}: {subcommand}");
+            }
+            assert!(script.contains("--verify"), "{shell:?}");
+        }
+    }
+
+    #[test]
+    fn compression_kinds_lowercase() {
+        let mut cmd = Cli::command();
+        let compress = cmd.find_subcommand_mut("compress").unwrap();
+        let arg = compress
+            .get_arguments()
+            .find(|arg| arg.get_id() == "compression")
+            .unwrap();
+        let values = possible_values(arg).unwrap();
+        assert!(!values.is_empty());
+        for value in &values {
+            assert_eq!(*value, value.to_lowercase());
+        }
+        for default in arg.get_default_values() {
+            let default = default.to_str().unwrap();
+            assert!(values.iter().any(|value| value == default), "{default}");
+        }
+
+        // Any case is still accepted
+        let kind = values[0].to_uppercase();
+        Cli::try_parse_from(["applesauce", "compress", "-c", &kind, "."]).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! The exit status of the process, for scripts to tell what happened
+
+use applesauce::Stats;
+use std::sync::atomic::Ordering;
+
+/// Listed in `--help`, keep in sync with [`ExitStatus::code`]
+pub const HELP: &str = "\
+Exit status:
+  0  Every file was processed (or skipped)
+  1  Fatal error: invalid arguments, or the run was stopped early
+  2  Completed, but some files or paths had errors, are on volumes which don't support
+     compression, or (with --verify-signatures) bundles no longer pass code signature checks
+  3  Completed, but none of the paths exist";
+
+/// How a run ended, in increasing order of severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExitStatus {
+    Success,
+    /// Every path passed was missing, so nothing was done
+    NothingFound,
+    /// Some files, paths, or (with `--strict`) directories had errors, paths are on volumes
+    /// which don't support compression, or bundles' code signatures were invalidated
+    FileErrors,
+    /// Invalid arguments, or the run was stopped by a fatal error
+    Fatal,
+}
+
+impl ExitStatus {
+    /// The status of a compress or decompress of `roots` paths
+    ///
+    /// Unreadable directories are only errors if `strict`.
+    pub fn of_run(stats: &Stats, roots: usize, strict: bool) -> Self {
+        let missing_roots = stats.missing_roots.load(Ordering::Relaxed);
+        let unreadable_dirs = stats.unreadable_dirs.load(Ordering::Relaxed);
+        if stats.fatal_error.is_some() {
+            Self::Fatal
+        } else if roots != 0 && missing_roots == roots as u64 {
+            Self::NothingFound
+        } else if missing_roots != 0
+            || stats.unsupported_roots.load(Ordering::Relaxed) != 0
+            || stats.invalid_signatures.load(Ordering::Relaxed) != 0
+            || stats.file_errors.load(Ordering::Relaxed) != 0
+            || (strict && unreadable_dirs != 0)
+        {
+            Self::FileErrors
+        } else {
+            Self::Success
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        match self {
+            Self::Success => 0,
+            Self::Fatal => 1,
+            Self::FileErrors => 2,
+            Self::NothingFound => 3,
+        }
+    }
+
+    /// Exit the process with this status
+    pub fn exit(self) -> ! {
+        std::process::exit(self.code())
+    }
+
+    /// Exit the process with this status, unless it's a success
+    pub fn exit_if_failed(self) {
+        if self != Self::Success {
+            self.exit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_status() {
+        let stats = Stats::default();
+        assert_eq!(ExitStatus::of_run(&stats, 2, true), ExitStatus::Success);
+        assert_eq!(ExitStatus::of_run(&stats, 0, false), ExitStatus::Success);
+
+        stats.unreadable_dirs.store(1, Ordering::Relaxed);
+        assert_eq!(ExitStatus::of_run(&stats, 2, false), ExitStatus::Success);
+        assert_eq!(ExitStatus::of_run(&stats, 2, true), ExitStatus::FileErrors);
+
+        let stats = Stats::default();
+        stats.missing_roots.store(1, Ordering::Relaxed);
+        assert_eq!(ExitStatus::of_run(&stats, 2, false), ExitStatus::FileErrors);
+        assert_eq!(
+            ExitStatus::of_run(&stats, 1, false),
+            ExitStatus::NothingFound
+        );
+
+        let stats = Stats::default();
+        stats.file_errors.store(3, Ordering::Relaxed);
+        assert_eq!(ExitStatus::of_run(&stats, 2, false), ExitStatus::FileErrors);
+
+        let stats = Stats::default();
+        stats.unsupported_roots.store(1, Ordering::Relaxed);
+        assert_eq!(ExitStatus::of_run(&stats, 1, false), ExitStatus::FileErrors);
+
+        let stats = Stats::default();
+        stats.invalid_signatures.store(1, Ordering::Relaxed);
+        assert_eq!(ExitStatus::of_run(&stats, 1, false), ExitStatus::FileErrors);
+
+        let mut stats = Stats::default();
+        stats.missing_roots.store(1, Ordering::Relaxed);
+        stats.fatal_error = Some("writer thread panicked".to_owned());
+        assert_eq!(ExitStatus::of_run(&stats, 1, false), ExitStatus::Fatal);
+
+        // Codes are distinct, and only success is 0
+        let mut codes: Vec<i32> = [
+            ExitStatus::Success,
+            ExitStatus::NothingFound,
+            ExitStatus::FileErrors,
+            ExitStatus::Fatal,
+        ]
+        .map(ExitStatus::code)
+        .into();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes, [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,30 @@
+//! Help topics which aren't about a single subcommand
+
+use applesauce::progress::SkipReason;
+use std::io::{self, Write};
+
+/// List every reason a file may be skipped, with its stable code
+pub fn skip_reasons(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "Reasons files may be skipped:")?;
+    for info in SkipReason::ALL {
+        writeln!(out)?;
+        writeln!(out, "{} ({})", info.code, info.name)?;
+        writeln!(out, "    {}", info.explanation)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_reasons_golden() {
+        let mut output = Vec::new();
+        skip_reasons(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            include_str!("../tests/golden/skip-reasons.txt")
+        );
+    }
+}
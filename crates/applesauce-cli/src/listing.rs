@@ -0,0 +1,151 @@
+//! Per-file listings for `applesauce info --list` and `--csv`
+
+use crate::{display_path, format_bytes};
+use applesauce::info::{self, AfscFileInfo};
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Csv,
+}
+
+const CSV_HEADER: &str = "path,compressed,kind,size,on_disk_size,savings,error";
+
+/// Write a line for every file under each of `paths`
+///
+/// Files are written as they are found, errors for individual files are written as rows
+/// rather than stopping the listing.
+pub fn list_files<P: AsRef<Path>>(
+    out: &mut impl Write,
+    format: Format,
+    paths: impl IntoIterator<Item = P>,
+) -> io::Result<()> {
+    if format == Format::Csv {
+        writeln!(out, "{CSV_HEADER}")?;
+    }
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            for (path, file_info) in info::walk(path) {
+                write_row(out, format, &path, &file_info)?;
+            }
+        } else {
+            write_row(out, format, path, &info::get(path))?;
+        }
+    }
+    out.flush()
+}
+
+fn write_row(
+    out: &mut impl Write,
+    format: Format,
+    path: &Path,
+    file_info: &io::Result<AfscFileInfo>,
+) -> io::Result<()> {
+    match format {
+        Format::Text => write_text_row(out, path, file_info),
+        Format::Csv => write_csv_row(out, path, file_info),
+    }
+}
+
+fn write_text_row(
+    out: &mut impl Write,
+    path: &Path,
+    file_info: &io::Result<AfscFileInfo>,
+) -> io::Result<()> {
+    let path = display_path(path);
+    let file_info = match file_info {
+        Ok(file_info) => file_info,
+        Err(e) => return writeln!(out, "{path}: error: {e}"),
+    };
+    let size = format_bytes(file_info.stat_size);
+    if file_info.is_dataless {
+        writeln!(out, "{path}: not stored locally, {size}")
+    } else if file_info.is_compressed {
+        let kind = kind(file_info).unwrap_or_else(|| "unknown compression".to_owned());
+        writeln!(
+            out,
+            "{path}: {kind}, {size} -> {} ({:.1}% saved)",
+            format_bytes(file_info.on_disk_size()),
+            (1.0 - file_info.compressed_fraction()) * 100.0,
+        )
+    } else {
+        writeln!(
+            out,
+            "{path}: not compressed, {size} ({} on disk)",
+            format_bytes(file_info.on_disk_size()),
+        )
+    }
+}
+
+fn write_csv_row(
+    out: &mut impl Write,
+    path: &Path,
+    file_info: &io::Result<AfscFileInfo>,
+) -> io::Result<()> {
+    let path = csv_field(&path.to_string_lossy());
+    let file_info = match file_info {
+        Ok(file_info) => file_info,
+        Err(e) => return writeln!(out, "{path},,,,,,{}", csv_field(&e.to_string())),
+    };
+    let kind = kind(file_info).map(|kind| csv_field(&kind));
+    let savings = (file_info.is_compressed && !file_info.is_dataless)
+        .then(|| i128::from(file_info.stat_size) - i128::from(file_info.compressed_size()));
+    writeln!(
+        out,
+        "{path},{},{},{},{},{},",
+        file_info.is_compressed,
+        kind.as_deref().unwrap_or(""),
+        file_info.stat_size,
+        file_info.on_disk_size(),
+        savings.map_or_else(String::new, |savings| savings.to_string()),
+    )
+}
+
+/// The compression type of a compressed file, if it could be read
+fn kind(file_info: &AfscFileInfo) -> Option<String> {
+    match &file_info.decmpfs_info {
+        Some(Ok(decmpfs_info)) if file_info.is_compressed => {
+            Some(decmpfs_info.compression_type.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Quote a CSV field if needed, following RFC 4180
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_escaping() {
+        assert_eq!(csv_field("plain/path.txt"), "plain/path.txt");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\rhere"), "\"cr\rhere\"");
+    }
+
+    #[test]
+    fn csv_error_row() {
+        let mut out = Vec::new();
+        let error = Err(io::Error::other("bad, \"very\" bad"));
+        write_csv_row(&mut out, Path::new("dir/a,b"), &error).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\"dir/a,b\",,,,,,\"bad, \"\"very\"\" bad\"\n"
+        );
+        assert_eq!(CSV_HEADER.split(',').count(), 7);
+    }
+}
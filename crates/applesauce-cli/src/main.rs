@@ -1,173 +1,45 @@
+use crate::cli::{
+    CleanMarkers, Cli, Commands, Compress, Decompress, LogFormat, Rebalance, Repair, TmExcluded,
+};
+use crate::exit_status::ExitStatus;
 use crate::progress::{ProgressBarWriter, ProgressBars, Verbosity};
 use applesauce::compressor::Kind;
-use applesauce::{compressor, info, Stats};
+use applesauce::info::{Inconsistency, OtherFileType};
+use applesauce::progress::FILE_EVENT_TARGET;
+use applesauce::{
+    info, rebalance, BundlePolicy, CompressionOptions, DecompressionOptions, Durability, ErrorLog,
+    FileOutcome, InvalidOptions, Stats, Verify,
+};
 use cfg_if::cfg_if;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use std::ffi::OsStr;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, LineWriter};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Component, Path, PathBuf};
 use std::sync::atomic::Ordering;
 use std::sync::Mutex;
-use std::{fmt, io};
+use std::time::{Duration, SystemTime};
+use std::{cmp, fmt, io};
 use tracing::metadata::LevelFilter;
 use tracing_chrome::ChromeLayerBuilder;
 use tracing_subscriber::fmt::time;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
 
+mod clean_markers;
+mod cli;
+mod completions;
+mod exit_status;
+mod help;
+mod listing;
+mod pipeline_env;
 mod progress;
-
-#[derive(Debug, clap::Parser)]
-#[command(author, version, about, long_about = None)]
-#[command(propagate_version = true)]
-struct Cli {
-    #[command(subcommand)]
-    command: Commands,
-
-    /// Output chrome tracing format to a file
-    ///
-    /// The passed file can be passed to chrome at chrome://tracing
-    #[arg(long, global(true))]
-    chrome_tracing: Option<PathBuf>,
-
-    #[arg(short, long, global(true), action = clap::ArgAction::Count)]
-    verbose: u8,
-
-    #[arg(short, long, global(true), action = clap::ArgAction::Count, conflicts_with = "verbose")]
-    quiet: u8,
-}
-
-impl Cli {
-    fn verbosity(&self) -> Verbosity {
-        let verbosity = self.verbose as i8 - self.quiet as i8;
-        match verbosity {
-            ..=-1 => Verbosity::Quiet,
-            0 => Verbosity::Normal,
-            1.. => Verbosity::Verbose,
-        }
-    }
-}
-
-#[derive(Debug, clap::Subcommand)]
-enum Commands {
-    /// Compress files
-    Compress(Compress),
-
-    /// Decompress files
-    Decompress(Decompress),
-
-    /// Get info about compression for file(s)
-    Info(Info),
-}
-
-#[derive(Debug, clap::Args)]
-struct Decompress {
-    /// Paths to recursively decompress
-    #[arg(required = true)]
-    paths: Vec<PathBuf>,
-
-    /// Decompress manually, rather than allowing the OS to do decompression
-    ///
-    /// This may be useful to do decompression from an older OS version which can't
-    /// natively read the compressed file
-    #[arg(long)]
-    manual: bool,
-
-    /// Verify that the compressed file has the same contents as the original before replacing it
-    ///
-    /// This is an extra safety check to ensure that the compressed file is exactly the same as the
-    /// original file.
-    #[arg(long)]
-    verify: bool,
-}
-
-#[derive(Debug, clap::Args)]
-struct Compress {
-    /// Paths to recursively compress
-    #[arg(required = true)]
-    paths: Vec<PathBuf>,
-
-    /// The compression level to use
-    #[arg(
-        short, long,
-        default_value_t = 5,
-        value_parser = clap::value_parser!(u32).range(1..=9)
-    )]
-    level: u32,
-
-    /// The minimum compression ratio
-    ///
-    /// Files will be skipped if they compress to a larger size than this ratio
-    /// of the original size
-    ///
-    /// A value of 0.0 (or less) will skip all files
-    /// A value of 1.0 will only skip files which cannot be compressed at all
-    /// Values greater than 1.0 are valid, and will allow forcing compression to
-    /// be used even if it results in a larger file
-    #[arg(short = 'r', long, default_value_t = 0.95)]
-    minimum_compression_ratio: f64,
-
-    /// The type of compression to use
-    #[arg(short, long, value_enum, default_value_t = Compression::default())]
-    compression: Compression,
-
-    /// Verify that the compressed file has the same contents as the original before replacing it
-    ///
-    /// This is an extra safety check to ensure that the compressed file is exactly the same as the
-    /// original file.
-    #[arg(long)]
-    verify: bool,
-}
-
-#[derive(Debug, clap::Args)]
-struct Info {
-    /// Paths to inspect
-    ///
-    /// Info will be reported for each path
-    #[arg(required = true)]
-    paths: Vec<PathBuf>,
-}
-
-#[derive(Debug, Copy, Clone, clap::ValueEnum, PartialEq, Eq)]
-enum Compression {
-    #[cfg(feature = "lzfse")]
-    Lzfse,
-    #[cfg(feature = "zlib")]
-    Zlib,
-    #[cfg(feature = "lzvn")]
-    Lzvn,
-}
-
-impl From<Compression> for compressor::Kind {
-    fn from(c: Compression) -> Self {
-        match c {
-            #[cfg(feature = "zlib")]
-            Compression::Zlib => compressor::Kind::Zlib,
-            #[cfg(feature = "lzfse")]
-            Compression::Lzfse => compressor::Kind::Lzfse,
-            #[cfg(feature = "lzvn")]
-            Compression::Lzvn => compressor::Kind::Lzvn,
-        }
-    }
-}
-
-impl Default for Compression {
-    fn default() -> Self {
-        cfg_if! {
-            if #[cfg(feature = "lzfse")] {
-                Self::Lzfse
-            } else if #[cfg(feature = "zlib")] {
-                Self::Zlib
-            } else if #[cfg(feature = "lzvn")] {
-                Self::Lzvn
-            } else {
-                compile_error!("At least one compression type must be configured")
-            }
-        }
-    }
-}
+mod repair;
+mod stream;
+mod summary;
 
 fn chrome_tracing_file(path: Option<&Path>) -> Option<impl io::Write> {
     let path = path?;
@@ -193,9 +65,87 @@ fn chrome_tracing_file(path: Option<&Path>) -> Option<impl io::Write> {
     Some(BufWriter::new(writer))
 }
 
+/// Open the file to append log messages to
+fn log_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Print help for a subcommand or topic, or for the whole command if there is no topic
+fn print_help(topic: Option<&str>) -> io::Result<()> {
+    let mut cmd = Cli::command();
+    let mut stdout = io::stdout().lock();
+    match topic {
+        None => cmd.write_long_help(&mut stdout),
+        Some("skip-reasons") => help::skip_reasons(&mut stdout),
+        Some(topic) => match cmd.find_subcommand_mut(topic) {
+            Some(subcommand) => subcommand.write_long_help(&mut stdout),
+            None => {
+                let subcommands: Vec<&str> =
+                    cmd.get_subcommands().map(clap::Command::get_name).collect();
+                eprintln!(
+                    "Unknown help topic `{topic}`, expected skip-reasons or one of: {}",
+                    subcommands.join(", ")
+                );
+                ExitStatus::Fatal.exit();
+            }
+        },
+    }
+}
+
 fn main() {
-    let cli = Cli::parse();
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        let _ = e.print();
+        // Help and version are "errors" from parsing, but succeed
+        if e.use_stderr() {
+            ExitStatus::Fatal.exit();
+        }
+        ExitStatus::Success.exit();
+    });
+    // These only print to stdout, so handle them before setting up progress and tracing
+    match &cli.command {
+        Commands::Completions(completions) => {
+            if let Err(e) =
+                completions::generate(completions.shell, &mut Cli::command(), &mut io::stdout())
+            {
+                eprintln!("Unable to write completions: {e}");
+                ExitStatus::Fatal.exit();
+            }
+            return;
+        }
+        Commands::Help(help) => {
+            if let Err(e) = print_help(help.topic.as_deref()) {
+                eprintln!("Unable to write help: {e}");
+                ExitStatus::Fatal.exit();
+            }
+            return;
+        }
+        // stdout is the output, so there are no progress bars or messages
+        Commands::Encode(encode) => {
+            if let Err(e) = stream::encode(encode.kind, encode.level) {
+                eprintln!("Unable to encode: {e}");
+                ExitStatus::Fatal.exit();
+            }
+            return;
+        }
+        Commands::Decode(_) => {
+            if let Err(e) = stream::decode() {
+                eprintln!("Unable to decode: {e}");
+                ExitStatus::Fatal.exit();
+            }
+            return;
+        }
+        _ => {}
+    }
+    let mut pipeline_config = match pipeline_env::pipeline_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitStatus::Fatal.exit();
+        }
+    };
+    pipeline_config.throttle = cli.throttle;
     let verbosity = cli.verbosity();
+    let priority = cli.priority();
 
     let mut _chrome_guard = None;
     let chrome_file = chrome_tracing_file(cli.chrome_tracing.as_deref());
@@ -208,20 +158,48 @@ fn main() {
         layer
     });
 
-    let progress_bars = ProgressBars::new(cli.verbosity());
-    let fmt_writer = Mutex::new(LineWriter::new(ProgressBarWriter::new(
-        progress_bars.multi_progress().clone(),
-        std::io::stderr(),
-    )));
-
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_timer(time::uptime())
-        .with_writer(fmt_writer)
-        .with_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::OFF.into())
-                .from_env_lossy(),
-        );
+    let structured = cli.log_format == LogFormat::Json;
+    let progress_bars = ProgressBars::new(cli.verbosity())
+        .group_by_root(cli.group_progress)
+        .hidden(!cli.show_progress())
+        .structured(structured);
+    let fmt_writer = match cli.log_file.as_deref() {
+        Some(path) => match log_file(path) {
+            Ok(file) => BoxMakeWriter::new(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("Unable to open log file {}: {e}", path.display());
+                ExitStatus::Fatal.exit();
+            }
+        },
+        None => BoxMakeWriter::new(Mutex::new(LineWriter::new(ProgressBarWriter::new(
+            progress_bars.multi_progress().clone(),
+            std::io::stderr(),
+        )))),
+    };
+
+    let fmt_layer = if structured {
+        // Events for each file are logged by default, as they replace the usual messages
+        let default_directive = format!("{FILE_EVENT_TARGET}=info").parse().unwrap();
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(fmt_writer)
+            .with_filter(
+                EnvFilter::builder()
+                    .with_default_directive(default_directive)
+                    .from_env_lossy(),
+            )
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer()
+            .with_timer(time::uptime())
+            .with_writer(fmt_writer)
+            .with_filter(
+                EnvFilter::builder()
+                    .with_default_directive(LevelFilter::OFF.into())
+                    .from_env_lossy(),
+            )
+            .boxed()
+    };
 
     tracing_subscriber::registry()
         .with(chrome_layer)
@@ -231,73 +209,285 @@ fn main() {
     match cli.command {
         Commands::Compress(Compress {
             paths,
-            compression,
+            compression: kind,
             minimum_compression_ratio,
             level,
+            sample_blocks,
+            skip_clones,
+            clone_temp_files,
+            fsync,
+            active_log_window,
+            skip_recently_modified,
+            no_in_use_check,
+            compress_sparse,
+            remember_incompressible,
+            no_adaptive_skip,
+            adaptive_skip_subdirectories,
+            output,
+            force,
             verify,
+            tm_excluded,
+            skip_tm_excluded,
+            skip_bundles,
+            verify_signatures,
+            preserve_times,
+            order,
+            check_dir_times,
+            strict,
         }) => {
-            let kind: Kind = compression.into();
-
             if kind != Kind::Zlib && level != 5 {
                 tracing::warn!("Compression level is ignored for non-zlib compression");
             }
 
-            let mut compressor = applesauce::FileCompressor::new();
-            let stats = compressor.recursive_compress(
-                paths.iter().map(Path::new),
-                kind,
-                minimum_compression_ratio,
-                level,
-                &progress_bars,
-                verify,
+            let mut compressor = applesauce::FileCompressor::with_config(pipeline_config);
+            compressor.set_priority(priority);
+            let tm_excluded = if skip_tm_excluded {
+                TmExcluded::Skip
+            } else {
+                tm_excluded
+            };
+            compressor.set_backup_exclusion(tm_excluded.into());
+            compressor.set_bundle_policy(if skip_bundles {
+                BundlePolicy::Skip
+            } else {
+                BundlePolicy::Warn
+            });
+            compressor.set_verify_signatures(verify_signatures);
+            compressor.set_time_preservation(preserve_times.into());
+            compressor.set_file_order(order.into());
+            compressor.set_check_dir_times(check_dir_times);
+            compressor.set_sample_blocks(sample_blocks);
+            compressor.set_skip_clones(skip_clones);
+            compressor.set_clone_temp_files(clone_temp_files);
+            compressor.set_durability(fsync.map_or(Durability::None, Durability::from));
+            compressor.set_active_log_window(Some(active_log_window));
+            compressor.set_skip_recently_modified(skip_recently_modified);
+            compressor.set_skip_in_use(!no_in_use_check);
+            compressor.set_compress_sparse(compress_sparse);
+            compressor.set_remember_incompressible(remember_incompressible);
+            compressor.set_adaptive_skip(
+                (!no_adaptive_skip).then_some(applesauce::DEFAULT_ADAPTIVE_SKIP_ATTEMPTS),
             );
+            compressor.set_adaptive_skip_subdirectories(adaptive_skip_subdirectories);
+            compressor.set_output_dir(output);
+            compressor.set_overwrite_output(force);
+            let options = CompressionOptions::new()
+                .kind(kind)
+                .level(level)
+                .minimum_ratio(minimum_compression_ratio)
+                .verify(verify.map_or(Verify::Off, Verify::from));
+            let result =
+                compressor.compress_with(paths.iter().map(Path::new), &options, &progress_bars);
             progress_bars.finish();
+            let stats = result.unwrap_or_else(|e| exit_invalid_options(&e));
             drop(progress_bars);
             tracing::info!("Finished compressing");
             if verbosity >= Verbosity::Normal {
-                // It seems dropping the progress bars may not be synchronous, so wait a little bit
-                std::thread::sleep(std::time::Duration::from_millis(100));
                 display_stats(&stats, true);
             }
+            exit_if_failed(&stats, paths.len(), strict);
         }
         Commands::Decompress(Decompress {
             paths,
             manual,
+            strict_manual,
+            force_clear,
+            clone_temp_files,
+            fsync,
             verify,
+            tm_excluded,
+            preserve_times,
+            order,
+            check_dir_times,
+            strict,
         }) => {
-            let mut compressor = applesauce::FileCompressor::new();
-            let stats = compressor.recursive_decompress(
-                paths.iter().map(Path::new),
-                manual,
-                &progress_bars,
-                verify,
-            );
+            let mut compressor = applesauce::FileCompressor::with_config(pipeline_config);
+            compressor.set_priority(priority);
+            compressor.set_strict_manual(strict_manual);
+            compressor.set_force_clear(force_clear);
+            compressor.set_clone_temp_files(clone_temp_files);
+            compressor.set_durability(fsync.map_or(Durability::None, Durability::from));
+            compressor.set_backup_exclusion(tm_excluded.into());
+            compressor.set_time_preservation(preserve_times.into());
+            compressor.set_file_order(order.into());
+            compressor.set_check_dir_times(check_dir_times);
+            let options = DecompressionOptions::new()
+                .manual(manual)
+                .verify(verify.map_or(Verify::Off, Verify::from));
+            let stats =
+                compressor.decompress_with(paths.iter().map(Path::new), &options, &progress_bars);
             progress_bars.finish();
             tracing::info!("Finished decompressing");
             if verbosity >= Verbosity::Normal {
                 display_stats(&stats, false);
             }
+            exit_if_failed(&stats, paths.len(), strict);
+        }
+        Commands::Rebalance(Rebalance {
+            paths,
+            hot_window,
+            dry_run,
+            level,
+            minimum_compression_ratio,
+            compression: kind,
+            manual,
+            verify,
+        }) => {
+            let verify = verify.map_or(Verify::Off, Verify::from);
+            let compress_options = CompressionOptions::new()
+                .kind(kind)
+                .level(level)
+                .minimum_ratio(minimum_compression_ratio)
+                .verify(verify);
+            // Checked before anything is decompressed, so an invalid option can't stop the
+            // rebalance halfway
+            if let Err(e) = compress_options.validate() {
+                progress_bars.finish();
+                exit_invalid_options(&e);
+            }
+            let plan =
+                match rebalance::plan(paths.iter().map(Path::new), hot_window, SystemTime::now()) {
+                    Ok(plan) => plan,
+                    Err(e) => {
+                        progress_bars.finish();
+                        eprintln!("Unable to scan for files to rebalance: {e}");
+                        ExitStatus::Fatal.exit();
+                    }
+                };
+            if dry_run {
+                progress_bars.finish();
+                for path in &plan.decompress {
+                    println!("decompress: {}", display_path(path));
+                }
+                for path in &plan.compress {
+                    println!("compress: {}", display_path(path));
+                }
+                println!(
+                    "Would decompress {} files and compress {} files",
+                    plan.decompress.len(),
+                    plan.compress.len(),
+                );
+                return;
+            }
+
+            let mut compressor = applesauce::FileCompressor::with_config(pipeline_config);
+            compressor.set_priority(priority);
+            let decompress_options = DecompressionOptions::new().manual(manual).verify(verify);
+            let decompress_stats = compressor.decompress_with(
+                plan.decompress.iter().map(PathBuf::as_path),
+                &decompress_options,
+                &progress_bars,
+            );
+            let compress_stats = compressor
+                .compress_with(
+                    plan.compress.iter().map(PathBuf::as_path),
+                    &compress_options,
+                    &progress_bars,
+                )
+                .expect("options were validated before decompressing");
+            progress_bars.finish();
+            drop(progress_bars);
+            tracing::info!("Finished rebalancing");
+            if verbosity >= Verbosity::Normal {
+                println!("Decompressed hot files:");
+                display_stats(&decompress_stats, false);
+                println!("\nCompressed cold files:");
+                display_stats(&compress_stats, true);
+            }
+            let decompress_status =
+                ExitStatus::of_run(&decompress_stats, plan.decompress.len(), false);
+            let compress_status = ExitStatus::of_run(&compress_stats, plan.compress.len(), false);
+            cmp::max(decompress_status, compress_status).exit_if_failed();
+        }
+        Commands::Repair(Repair { paths, yes }) => {
+            progress_bars.finish();
+            if !repair::repair_paths(&paths, yes) {
+                ExitStatus::FileErrors.exit();
+            }
+        }
+        Commands::CleanMarkers(CleanMarkers { paths }) => {
+            progress_bars.finish();
+            if !clean_markers::clean_paths(&paths, verbosity) {
+                ExitStatus::FileErrors.exit();
+            }
+        }
+        Commands::Completions(_)
+        | Commands::Help(_)
+        | Commands::Encode(_)
+        | Commands::Decode(_) => unreachable!("handled before setup"),
+        Commands::Info(info) if info.repair => {
+            if !repair::clear_orphaned_flags(&info.paths) {
+                ExitStatus::FileErrors.exit();
+            }
+        }
+        Commands::Info(info) if info.summary => {
+            let mut any_failed = false;
+            for path in &info.paths {
+                let file_info = if path.is_dir() {
+                    Err(io::Error::other("is a directory"))
+                } else {
+                    info::get(path)
+                };
+                any_failed |= file_info.is_err();
+                let file_summary = file_info.map(|info| summary::FileSummary::from(&info));
+                println!("{}", summary::summary_line(&file_summary));
+            }
+            if any_failed {
+                ExitStatus::FileErrors.exit();
+            }
+        }
+        Commands::Info(info) if info.list || info.csv => {
+            let format = if info.csv {
+                listing::Format::Csv
+            } else {
+                listing::Format::Text
+            };
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = listing::list_files(&mut stdout, format, &info.paths) {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    tracing::error!("error writing file listing: {e}");
+                    ExitStatus::Fatal.exit();
+                }
+            }
         }
         Commands::Info(info) => {
+            let top = info.top;
             for path in info.paths {
                 if path.is_dir() {
-                    let info = info::get_recursive(&path);
-                    let info = match info {
+                    let info = match top {
+                        Some(n) => info::get_recursive_top(&path, n)
+                            .map(|(info, top_files)| (info, Some(top_files))),
+                        None => info::get_recursive(&path).map(|info| (info, None)),
+                    };
+                    let (info, top_files) = match info {
                         Ok(info) => info,
                         Err(e) => {
                             tracing::error!(
                                 "error reading compression info for {}: {}",
-                                path.display(),
+                                display_path(&path),
                                 e,
                             );
                             continue;
                         }
                     };
-                    println!("\n{}:", path.display());
+                    println!("\n{}:", display_path(&path));
 
                     println!("Number of compressed files: {}", info.num_compressed_files);
                     println!("Total number of files: {}", info.num_files);
                     println!("Total number of folders: {}", info.num_folders);
+                    if info.num_other_entries != 0 {
+                        println!(
+                            "Other entries (symlinks, pipes, devices): {}",
+                            info.num_other_entries
+                        );
+                    }
+                    if info.num_dataless_files != 0 {
+                        println!(
+                            "Files not stored locally: {} ({}, not included below)",
+                            info.num_dataless_files,
+                            format_bytes(info.total_dataless_size),
+                        );
+                    }
                     println!(
                         "Total uncompressed size: {} ({})",
                         format_bytes(info.total_uncompressed_size),
@@ -312,23 +502,46 @@ fn main() {
                         "Compression Savings: {:.1}%",
                         info.compression_savings_fraction() * 100.0,
                     );
+                    if info.total_other_resource_fork_size != 0 {
+                        println!(
+                            "Resource forks of uncompressed files: {} ({}, not included above)",
+                            format_bytes(info.total_other_resource_fork_size),
+                            info.total_other_resource_fork_size,
+                        );
+                    }
+                    if let Some(top_files) = top_files {
+                        print_top_files("Most space saved", &top_files.most_saved);
+                        print_top_files(
+                            "Largest uncompressed files",
+                            &top_files.largest_uncompressed,
+                        );
+                    }
                 } else {
                     let info = info::get(&path);
                     let info = match info {
                         Ok(info) => info,
                         Err(e) => {
-                            tracing::error!(
-                                "error reading compression info for {}: {}",
-                                path.display(),
-                                e,
-                            );
+                            match OtherFileType::of_error(&e) {
+                                Some(file_type) => println!(
+                                    "{} is a {file_type}, not a regular file",
+                                    display_path(&path)
+                                ),
+                                None => tracing::error!(
+                                    "error reading compression info for {}: {}",
+                                    display_path(&path),
+                                    e,
+                                ),
+                            }
                             continue;
                         }
                     };
+                    if info.is_dataless {
+                        println!("{} is not stored locally", display_path(&path));
+                    }
                     if info.is_compressed {
-                        println!("{} is compressed", path.display());
+                        println!("{} is compressed", display_path(&path));
                     } else {
-                        println!("{} is not compressed", path.display());
+                        println!("{} is not compressed", display_path(&path));
                     }
 
                     match &info.decmpfs_info {
@@ -338,56 +551,86 @@ fn main() {
                                 "Uncompressed size in decmpfs xattr: {}",
                                 decmpfs_info.orig_file_size
                             );
+                            if let Some(storage) = decmpfs_info.storage {
+                                println!("Compressed data stored in: {storage}");
+                            }
+                            if let Some(block_count) = decmpfs_info.block_count {
+                                println!("Number of compressed blocks: {block_count}");
+                            }
                         }
                         Some(Err(decmpfs_err)) => {
-                            if info.is_compressed {
-                                tracing::error!(
-                                    "compressed file has issue with decompfs xattr: {}",
-                                    decmpfs_err
-                                );
-                            }
+                            println!("Invalid decmpfs xattr: {decmpfs_err}");
                         }
-                        None => {
-                            if info.is_compressed {
-                                tracing::error!("compressed file has no decmpfs xattr");
-                            }
+                        None => {}
+                    }
+                    match info.inconsistency {
+                        Some(inconsistency @ Inconsistency::MissingDecmpfs) => {
+                            tracing::warn!(
+                                "{inconsistency}, it reads as empty, use `applesauce info \
+                                 --repair` to clear its compressed flag"
+                            );
+                        }
+                        Some(inconsistency @ Inconsistency::MissingCompressedFlag) => {
+                            tracing::warn!(
+                                "{inconsistency}, use `applesauce repair` to check its \
+                                 compressed data"
+                            );
                         }
+                        Some(inconsistency) => tracing::error!("{inconsistency}"),
+                        None => {}
                     }
                     println!("Uncompressed size: {}", info.stat_size);
                     if info.is_compressed {
-                        println!("Compressed size: {}", info.on_disk_size);
+                        println!("Compressed size: {}", info.compressed_size());
                         println!(
                             "Compression savings: {:0.2}%",
                             (1.0 - info.compressed_fraction()) * 100.0
                         );
                     }
+                    println!("Data fork size on disk: {}", info.data_on_disk_size);
+                    if let Some(resource_fork_size) = info.resource_fork_size {
+                        println!(
+                            "Resource fork size: {resource_fork_size} ({} on disk)",
+                            info.resource_fork_on_disk_size
+                        );
+                    }
                     println!("Number of extended attributes: {}", info.xattr_count);
-                    println!(
-                        "Size of extended attributes: {} bytes",
-                        info.total_xattr_size
-                    );
+                    println!("Size of extended attributes: {} bytes", info.xattr_size);
                 }
             }
         }
     }
 }
 
+fn exit_invalid_options(e: &InvalidOptions) -> ! {
+    eprintln!("{e}");
+    ExitStatus::Fatal.exit();
+}
+
+/// Exit with an error if the run of `roots` paths didn't succeed, see [`ExitStatus::of_run`]
+fn exit_if_failed(stats: &Stats, roots: usize, strict: bool) {
+    let unreadable_dirs = stats.unreadable_dirs.load(Ordering::Relaxed);
+    if strict && unreadable_dirs != 0 {
+        eprintln!("{unreadable_dirs} directories could not be read");
+    }
+    ExitStatus::of_run(stats, roots, strict).exit_if_failed();
+}
+
 pub fn display_stats(stats: &Stats, compress_mode: bool) {
     println!("Total Files: {}", stats.files.load(Ordering::Relaxed));
     let total_file_sizes = stats.total_file_sizes.load(Ordering::Relaxed);
 
-    let compressed_count_start = stats.compressed_file_count_start.load(Ordering::Relaxed);
     let compressed_count_final = stats.compressed_file_count_final.load(Ordering::Relaxed);
     if compress_mode {
         println!(
             "New Files Compressed: {} ({} total compressed)",
-            compressed_count_final.saturating_sub(compressed_count_start),
+            stats.outcomes.get(FileOutcome::Compressed),
             compressed_count_final,
         );
     } else {
         print!(
             "Files Decompressed: {}",
-            compressed_count_start.saturating_sub(compressed_count_final),
+            stats.outcomes.get(FileOutcome::Decompressed),
         );
         if compressed_count_final != 0 {
             println!(" ({} remaining compressed)", compressed_count_final);
@@ -396,6 +639,38 @@ pub fn display_stats(stats: &Stats, compress_mode: bool) {
         }
     }
 
+    let skipped = stats.skipped.total();
+    if skipped != 0 {
+        let breakdown: Vec<String> = stats
+            .skipped
+            .counts()
+            .iter()
+            .filter(|&&(_, count)| count != 0)
+            .map(|(reason, count)| format!("{count} {reason}"))
+            .collect();
+        println!("Skipped: {skipped} ({})", breakdown.join(", "));
+    }
+    let unreadable_dirs = stats.unreadable_dirs.load(Ordering::Relaxed);
+    if unreadable_dirs != 0 {
+        println!("Unreadable directories: {unreadable_dirs} (contents not processed)");
+    }
+    let invalid_signatures = stats.invalid_signatures.load(Ordering::Relaxed);
+    if invalid_signatures != 0 {
+        println!("Code signatures invalidated: {invalid_signatures} bundles");
+    }
+    let dir_times_not_restored = stats.dir_times_not_restored.load(Ordering::Relaxed);
+    if dir_times_not_restored != 0 {
+        println!("Directory times not restored: {dir_times_not_restored}");
+    }
+    let decompressed_by_os = stats.decompressed_by_os.load(Ordering::Relaxed);
+    if decompressed_by_os != 0 {
+        println!("Decompressed by the OS: {decompressed_by_os} (unsupported compression type)");
+    }
+    let cleared = stats.cleared_missing_resource_fork.load(Ordering::Relaxed);
+    if cleared != 0 {
+        println!("Cleared: {cleared} (compressed data was missing, contents lost)");
+    }
+
     let compressed_size_start = stats.compressed_size_start.load(Ordering::Relaxed);
     let compressed_size_final = stats.compressed_size_final.load(Ordering::Relaxed);
     println!(
@@ -417,6 +692,42 @@ pub fn display_stats(stats: &Stats, compress_mode: bool) {
         "Savings:                        {:.1}%",
         stats.compression_change_portion() * 100.0
     );
+    println!(
+        "Throughput: {}/s read, {}/s written over {}",
+        format_bytes(stats.read_throughput() as u64),
+        format_bytes(stats.write_throughput() as u64),
+        format_elapsed(stats.elapsed()),
+    );
+    print_errors(&stats.errors);
+}
+
+fn print_errors(errors: &ErrorLog) {
+    let total = errors.total();
+    if total == 0 {
+        return;
+    }
+    println!("\nErrors ({total}):");
+    for error in errors.entries() {
+        println!("  {}: {}", display_path(&error.path), error.message);
+    }
+    let omitted = errors.omitted();
+    if omitted != 0 {
+        println!("  … and {omitted} more");
+    }
+}
+
+fn print_top_files(title: &str, files: &[(u64, PathBuf)]) {
+    println!("\n{title}:");
+    if files.is_empty() {
+        println!("  (none)");
+    }
+    for (size, path) in files {
+        println!(
+            "  {:>10}  {}",
+            format_bytes(*size).to_string(),
+            display_path(path)
+        );
+    }
 }
 
 #[must_use]
@@ -461,10 +772,45 @@ pub fn truncate_path(path: &Path, width: usize) -> PathBuf {
     path
 }
 
+/// Display `path`, with any bytes which aren't valid UTF-8 escaped as `\xNN`
+///
+/// [`Path::display`] replaces them with U+FFFD, so different names could display the same, and
+/// the displayed name couldn't be used to find the file.
+pub fn display_path(path: &Path) -> impl fmt::Display + '_ {
+    struct Escaped<'a>(&'a [u8]);
+
+    impl fmt::Display for Escaped<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for chunk in self.0.utf8_chunks() {
+                f.write_str(chunk.valid())?;
+                for byte in chunk.invalid() {
+                    write!(f, "\\x{byte:02X}")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    Escaped(path.as_os_str().as_bytes())
+}
+
 fn format_bytes(byte_size: u64) -> impl fmt::Display {
     humansize::SizeFormatter::new(byte_size, humansize::BINARY)
 }
 
+/// Format a duration like `2m13s`, with tenths of a second for durations under a minute
+fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours != 0 {
+        format!("{hours}h{minutes:02}m{secs:02}s")
+    } else if minutes != 0 {
+        format!("{minutes}m{secs:02}s")
+    } else {
+        format!("{:.1}s", elapsed.as_secs_f64())
+    }
+}
+
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
@@ -510,3 +856,98 @@ fn command_check() {
     use clap::CommandFactory;
     Cli::command().debug_assert()
 }
+
+#[test]
+fn elapsed_format() {
+    assert_eq!(format_elapsed(Duration::from_millis(4_300)), "4.3s");
+    assert_eq!(format_elapsed(Duration::from_secs(133)), "2m13s");
+    assert_eq!(format_elapsed(Duration::from_secs(60)), "1m00s");
+    assert_eq!(format_elapsed(Duration::from_secs(3_725)), "1h02m05s");
+}
+
+#[test]
+fn json_logs_hide_progress() {
+    let cli = Cli::try_parse_from(["applesauce", "compress", "--log-format=json", "dir"]).unwrap();
+    assert_eq!(cli.log_format, LogFormat::Json);
+    assert!(!cli.show_progress());
+
+    let cli = Cli::try_parse_from(["applesauce", "--log-file", "out.log", "info", "dir"]).unwrap();
+    assert_eq!(cli.log_format, LogFormat::Text);
+    assert_eq!(cli.log_file.as_deref(), Some(Path::new("out.log")));
+}
+
+#[test]
+fn skip_tm_excluded() {
+    let cli = Cli::try_parse_from(["applesauce", "compress", "--skip-tm-excluded", "dir"]).unwrap();
+    let Commands::Compress(compress) = cli.command else {
+        panic!("expected compress command");
+    };
+    assert!(compress.skip_tm_excluded);
+
+    let result = Cli::try_parse_from([
+        "applesauce",
+        "compress",
+        "--skip-tm-excluded",
+        "--tm-excluded=only",
+        "dir",
+    ]);
+    assert!(result.is_err());
+}
+
+#[test]
+fn bundle_flags() {
+    let cli = Cli::try_parse_from(["applesauce", "compress", "--skip-bundles", "dir"]).unwrap();
+    let Commands::Compress(compress) = cli.command else {
+        panic!("expected compress command");
+    };
+    assert!(compress.skip_bundles);
+    assert!(!compress.verify_signatures);
+
+    let result = Cli::try_parse_from([
+        "applesauce",
+        "compress",
+        "--skip-bundles",
+        "--verify-signatures",
+        "dir",
+    ]);
+    assert!(result.is_err());
+}
+
+#[test]
+fn encode_kind() {
+    let kind = Kind::default();
+    let cli =
+        Cli::try_parse_from(["applesauce", "encode", "--kind", kind.name(), "-l", "9"]).unwrap();
+    let Commands::Encode(encode) = cli.command else {
+        panic!("expected encode command");
+    };
+    assert_eq!(encode.kind, kind);
+    assert_eq!(encode.level, 9);
+
+    assert!(Cli::try_parse_from(["applesauce", "encode", "file"]).is_err());
+    assert!(Cli::try_parse_from(["applesauce", "decode"]).is_ok());
+}
+
+#[test]
+fn info_repair() {
+    let cli = Cli::try_parse_from(["applesauce", "info", "--repair", "dir"]).unwrap();
+    let Commands::Info(info) = cli.command else {
+        panic!("expected info command");
+    };
+    assert!(info.repair);
+
+    for other in ["--list", "--csv", "--summary", "--top=3"] {
+        let result = Cli::try_parse_from(["applesauce", "info", "--repair", other, "dir"]);
+        assert!(result.is_err(), "{other}");
+    }
+}
+
+#[test]
+fn display_path_escapes_invalid_utf8() {
+    let path = Path::new(OsStr::from_bytes(b"dir/caf\xc3\xa9\xff\xfe.txt"));
+    assert_eq!(display_path(path).to_string(), "dir/café\\xFF\\xFE.txt");
+    assert_eq!(
+        display_path(Path::new("plain/path")).to_string(),
+        "plain/path"
+    );
+}
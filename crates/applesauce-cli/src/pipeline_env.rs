@@ -0,0 +1,127 @@
+//! Overriding the thread and queue sizes with environment variables
+//!
+//! These are for experimenting with throughput on different storage, without rebuilding.
+
+use applesauce::PipelineConfig;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::str::FromStr;
+
+/// Read the pipeline configuration, with overrides from `APPLESAUCE_*` environment variables
+pub fn pipeline_config() -> Result<PipelineConfig, String> {
+    config_from(|name| std::env::var_os(name))
+}
+
+fn config_from(var: impl Fn(&str) -> Option<OsString>) -> Result<PipelineConfig, String> {
+    let mut config = PipelineConfig::default();
+    let sizes: [(&str, &mut NonZeroUsize); 6] = [
+        ("APPLESAUCE_READER_QUEUE", &mut config.reader_queue),
+        ("APPLESAUCE_WRITER_QUEUE", &mut config.writer_queue),
+        ("APPLESAUCE_COMPRESSOR_QUEUE", &mut config.compressor_queue),
+        ("APPLESAUCE_READER_THREADS", &mut config.reader_threads),
+        ("APPLESAUCE_WRITER_THREADS", &mut config.writer_threads),
+        (
+            "APPLESAUCE_COMPRESSOR_THREADS",
+            &mut config.compressor_threads,
+        ),
+    ];
+    for (name, size) in sizes {
+        if let Some(value) = parse(name, &var)? {
+            *size = value;
+        }
+    }
+    if let Some(byte_budget) = parse::<NonZeroU64>("APPLESAUCE_BYTE_BUDGET", &var)? {
+        config.byte_budget = Some(byte_budget.get());
+    }
+    let volume_files: [(&str, &mut Option<NonZeroUsize>); 2] = [
+        (
+            "APPLESAUCE_NETWORK_VOLUME_FILES",
+            &mut config.network_volume_files,
+        ),
+        (
+            "APPLESAUCE_LOCAL_VOLUME_FILES",
+            &mut config.local_volume_files,
+        ),
+    ];
+    for (name, files) in volume_files {
+        if let Some(value) = parse(name, &var)? {
+            *files = Some(value);
+        }
+    }
+    // 0 caches every file
+    if let Some(size) = parse::<u64>("APPLESAUCE_UNCACHED_FILE_SIZE", &var)? {
+        config.uncached_file_size = (size != 0).then_some(size);
+    }
+    Ok(config)
+}
+
+fn parse<T>(name: &str, var: impl Fn(&str) -> Option<OsString>) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Some(value) = var(name) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .ok_or_else(|| format!("{name} is not valid unicode"))?;
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| format!("invalid {name}={value:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with(vars: &[(&str, &str)]) -> Result<PipelineConfig, String> {
+        config_from(|name| {
+            vars.iter()
+                .find(|&&(var_name, _)| var_name == name)
+                .map(|&(_, value)| value.into())
+        })
+    }
+
+    #[test]
+    fn overrides() {
+        assert_eq!(config_with(&[]).unwrap(), PipelineConfig::default());
+
+        let config = config_with(&[
+            ("APPLESAUCE_WRITER_THREADS", "2"),
+            ("APPLESAUCE_READER_QUEUE", "10"),
+            ("APPLESAUCE_BYTE_BUDGET", "1048576"),
+            ("APPLESAUCE_LOCAL_VOLUME_FILES", "3"),
+            ("APPLESAUCE_UNCACHED_FILE_SIZE", "1024"),
+        ])
+        .unwrap();
+        assert_eq!(config.uncached_file_size, Some(1024));
+        assert_eq!(config.writer_threads.get(), 2);
+        assert_eq!(config.reader_queue.get(), 10);
+        assert_eq!(config.byte_budget, Some(1024 * 1024));
+        assert_eq!(config.local_volume_files, NonZeroUsize::new(3));
+        assert_eq!(
+            config.network_volume_files,
+            PipelineConfig::default().network_volume_files
+        );
+        assert_eq!(
+            config.reader_threads,
+            PipelineConfig::default().reader_threads
+        );
+
+        let config = config_with(&[("APPLESAUCE_UNCACHED_FILE_SIZE", "0")]).unwrap();
+        assert_eq!(config.uncached_file_size, None);
+    }
+
+    #[test]
+    fn invalid() {
+        for value in ["0", "-1", "many", ""] {
+            let e = config_with(&[("APPLESAUCE_COMPRESSOR_THREADS", value)]).unwrap_err();
+            assert!(e.contains("APPLESAUCE_COMPRESSOR_THREADS"), "{e}");
+        }
+        assert!(config_with(&[("APPLESAUCE_BYTE_BUDGET", "0")]).is_err());
+        assert!(config_with(&[("APPLESAUCE_UNCACHED_FILE_SIZE", "-1")]).is_err());
+    }
+}
@@ -1,8 +1,15 @@
-use applesauce::progress::{Progress, SkipReason, Task};
-use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use crate::display_path;
+use applesauce::info::CompressionType;
+use applesauce::progress::{
+    DirTimesMismatch, Progress, SkipReason, SkipSeverity, Task, FILE_EVENT_TARGET,
+};
+use indicatif::{
+    HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
+};
 use std::fmt;
-use std::io::Write;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
@@ -17,6 +24,11 @@ const DELAY: Duration = Duration::from_millis(100);
 /// immediately finishes
 const MIN_ETA: Duration = Duration::from_secs(1);
 
+/// The most progress bars shown for individual roots, when grouping progress by root
+///
+/// With more roots than this, the last bar is shared by all the remaining roots.
+const MAX_ROOT_BARS: usize = 6;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
 pub enum Verbosity {
     Quiet,
@@ -27,16 +39,104 @@ pub enum Verbosity {
 
 pub struct ProgressBars {
     style: ProgressStyle,
+    total_style: ProgressStyle,
+    scanning_style: ProgressStyle,
     total_bar: ProgressBar,
+    found_files: AtomicU64,
+    found_bytes: AtomicU64,
     bars: MultiProgress,
     verbosity: Verbosity,
+    full_disk_access_hinted: AtomicBool,
+    group_by_root: bool,
+    /// Log events for each file, rather than printing messages
+    structured: bool,
+    /// A bar for each root (or group of roots), beneath the total
+    root_bars: Mutex<Vec<ProgressBar>>,
 }
 
 impl ProgressBars {
+    /// Clear the progress bars, once nothing else will be drawn
+    ///
+    /// The scanning spinner is ticked by a background thread, which is stopped and joined first,
+    /// so nothing is drawn over output printed after this returns.
     pub fn finish(&self) {
+        self.total_bar.disable_steady_tick();
         let _ = self.bars.clear();
         self.total_bar.finish();
     }
+
+    /// Show a progress bar for each root passed, beneath the total
+    pub fn group_by_root(mut self, group_by_root: bool) -> Self {
+        self.group_by_root = group_by_root;
+        self
+    }
+
+    /// Hide the progress bars, messages are printed directly to stderr instead
+    pub fn hidden(self, hidden: bool) -> Self {
+        if hidden {
+            self.bars.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        self
+    }
+
+    /// Log a tracing event for each file message, instead of printing it
+    pub fn structured(mut self, structured: bool) -> Self {
+        self.structured = structured;
+        self
+    }
+}
+
+/// Print a message above the progress bars, or directly to stderr if they are hidden
+fn print_message(bar: &ProgressBar, message: impl AsRef<str>) {
+    if bar.is_hidden() {
+        eprintln!("{}", message.as_ref());
+    } else {
+        bar.println(message);
+    }
+}
+
+fn error_event(path: &Path, message: &str) {
+    tracing::error!(target: FILE_EVENT_TARGET, path = %display_path(path), action = "error", "{message}");
+}
+
+fn skipped_event(path: &Path, why: &SkipReason) {
+    tracing::info!(
+        target: FILE_EVENT_TARGET,
+        path = %display_path(path),
+        action = "skip",
+        reason = why.code(),
+        "Skipped: {why}",
+    );
+}
+
+/// The label of each root progress bar, for `roots`
+///
+/// There is a bar for each root, unless there are more than `max_bars` roots: then the last
+/// bar is shared by all the roots which don't get their own bar. No bars are needed for a
+/// single root, the total already shows its progress.
+fn root_bar_labels(roots: &[&Path], max_bars: usize, prefix_len: usize) -> Vec<String> {
+    if roots.len() < 2 {
+        return Vec::new();
+    }
+    let label = |root: &Path| {
+        crate::truncate_path(root, prefix_len)
+            .to_string_lossy()
+            .into_owned()
+    };
+    if roots.len() <= max_bars {
+        return roots.iter().map(|root| label(root)).collect();
+    }
+    let mut labels: Vec<String> = roots[..max_bars - 1]
+        .iter()
+        .map(|root| label(root))
+        .collect();
+    labels.push(format!("({} others)", roots.len() - (max_bars - 1)));
+    labels
+}
+
+/// The index of the bar showing progress for the root at index `root`
+fn root_bar_index(root: usize, max_bars: usize) -> usize {
+    root.min(max_bars - 1)
 }
 
 impl ProgressBars {
@@ -69,16 +169,30 @@ impl ProgressBars {
         .unwrap()
         .with_key("smoothed_eta", smoothed_eta);
 
+        // While scanning, the total size isn't known yet, so don't show a bar or an ETA
+        let scanning_style = ProgressStyle::with_template(
+            "{prefix:>25.bold} {spinner:.green} {msg} {bytes:>11} done",
+        )
+        .unwrap();
+
         let total_bar = bars
             .add(ProgressBar::new(0))
-            .with_style(total_style)
+            .with_style(total_style.clone())
             .with_prefix("Total:");
 
         Self {
             style,
+            total_style,
+            scanning_style,
             total_bar,
+            found_files: AtomicU64::new(0),
+            found_bytes: AtomicU64::new(0),
             bars,
             verbosity,
+            full_disk_access_hinted: AtomicBool::new(false),
+            group_by_root: false,
+            structured: false,
+            root_bars: Mutex::new(Vec::new()),
         }
     }
 
@@ -103,9 +217,13 @@ enum State {
 
 pub struct ProgressWithTotal {
     total: ProgressBar,
+    /// The bar for the root this file was found under, if grouping by root
+    root: Option<ProgressBar>,
     single: ProgressBar,
     state: Mutex<State>,
     verbosity: Verbosity,
+    path: PathBuf,
+    structured: bool,
 }
 
 impl ProgressWithTotal {
@@ -133,35 +251,136 @@ impl ProgressWithTotal {
     }
 }
 
+/// The verbosity required to report a file skipped for this reason
+fn skip_verbosity(why: &SkipReason) -> Verbosity {
+    match why.severity() {
+        SkipSeverity::Routine => Verbosity::Verbose,
+        SkipSeverity::Warning => Verbosity::Normal,
+    }
+}
+
 impl Progress for ProgressBars {
     type Task = ProgressWithTotal;
 
     fn error(&self, path: &Path, message: &str) {
-        self.total_bar
-            .println(format!("{}: error: {message}", path.display()))
+        if self.structured {
+            error_event(path, message);
+        } else {
+            print_message(
+                &self.total_bar,
+                format!("{}: error: {message}", display_path(path)),
+            );
+        }
+    }
+
+    fn dir_skipped(&self, path: &Path, error: io::Error) {
+        let message = format!("unable to read directory, skipping its contents: {error}");
+        if self.structured {
+            error_event(path, &message);
+        } else {
+            print_message(
+                &self.total_bar,
+                format!("{}: error: {message}", display_path(path)),
+            );
+        }
+    }
+
+    fn fatal_error(&self, message: &str) {
+        if self.structured {
+            tracing::error!(target: FILE_EVENT_TARGET, "{message}");
+        } else {
+            print_message(&self.total_bar, format!("error: {message}"));
+        }
+    }
+
+    fn dir_times_not_restored(&self, mismatch: &DirTimesMismatch) {
+        let message = format!("directory times not restored: {mismatch}");
+        if self.structured {
+            error_event(&mismatch.path, &message);
+        } else {
+            print_message(
+                &self.total_bar,
+                format!("{}: error: {message}", display_path(&mismatch.path)),
+            );
+        }
+    }
+
+    fn bundle_entered(&self, bundle: &Path) {
+        if self.verbosity < Verbosity::Normal {
+            return;
+        }
+        let message = "processing files in this bundle, which may stop it passing strict code \
+                       signature validation (--skip-bundles skips bundles)";
+        if self.structured {
+            tracing::warn!(
+                target: FILE_EVENT_TARGET,
+                path = %display_path(bundle),
+                action = "bundle",
+                "{message}",
+            );
+        } else {
+            print_message(
+                &self.total_bar,
+                format!("{}: warning: {message}", display_path(bundle)),
+            );
+        }
     }
 
     fn file_skipped(&self, path: &Path, why: SkipReason) {
-        let required_verbosity = match why {
-            SkipReason::NotFile
-            | SkipReason::AlreadyCompressed
-            | SkipReason::NotCompressed
-            | SkipReason::EmptyFile => Verbosity::Verbose,
-            SkipReason::TooLarge(_)
-            | SkipReason::ReadError(_)
-            | SkipReason::ZfsFilesystem
-            | SkipReason::HasRequiredXattr
-            | SkipReason::FsNotSupported => Verbosity::Normal,
-        };
-        if self.verbosity >= required_verbosity {
-            self.total_bar
-                .println(format!("{}: Skipped: {why}", path.display()))
+        if matches!(why, SkipReason::RequiresFullDiskAccess)
+            && self.verbosity >= Verbosity::Normal
+            && !self.full_disk_access_hinted.swap(true, Ordering::Relaxed)
+        {
+            print_message(
+                &self.total_bar,
+                "Some files require Full Disk Access: \
+                 grant Full Disk Access to your terminal to process these",
+            );
+        }
+        if self.verbosity < skip_verbosity(&why) {
+            return;
+        }
+        if self.structured {
+            skipped_event(path, &why);
+        } else {
+            print_message(
+                &self.total_bar,
+                format!("{}: Skipped: {why}", display_path(path)),
+            );
+        }
+    }
+
+    fn roots(&self, roots: &[&Path]) {
+        if !self.group_by_root {
+            return;
+        }
+        let mut root_bars = self.root_bars.lock().unwrap();
+        // Each run (e.g. both halves of a rebalance) reports its own roots
+        for bar in root_bars.drain(..) {
+            self.bars.remove(&bar);
+        }
+        for label in root_bar_labels(roots, MAX_ROOT_BARS, self.prefix_len()) {
+            let bar = self
+                .bars
+                .add(ProgressBar::new(0))
+                .with_style(self.style.clone())
+                .with_prefix(label);
+            root_bars.push(bar);
         }
     }
 
-    fn file_task(&self, path: &Path, size: u64) -> Self::Task {
+    fn file_task(&self, path: &Path, size: u64, root: usize) -> Self::Task {
         let prefix = crate::truncate_path(path, self.prefix_len());
 
+        let root = self
+            .root_bars
+            .lock()
+            .unwrap()
+            .get(root_bar_index(root, MAX_ROOT_BARS))
+            .cloned();
+        if let Some(root) = &root {
+            root.inc_length(size);
+        }
         let total = self.total_bar.clone();
         let single = ProgressBar::hidden()
             .with_style(self.style.clone())
@@ -171,31 +390,112 @@ impl Progress for ProgressBars {
         total.inc_length(size);
         ProgressWithTotal {
             total,
+            root,
             single,
             state: Mutex::new(State::Unattached {
                 bars: self.bars.clone(),
                 first_tick: None,
             }),
             verbosity: self.verbosity,
+            path: path.to_owned(),
+            structured: self.structured,
         }
     }
+
+    fn scan_started(&self) {
+        self.found_files.store(0, Ordering::Relaxed);
+        self.found_bytes.store(0, Ordering::Relaxed);
+        self.total_bar.set_style(self.scanning_style.clone());
+        self.total_bar.set_message("Scanning...");
+        self.total_bar
+            .enable_steady_tick(Duration::from_millis(100));
+    }
+
+    fn found_file(&self, _path: &Path, size: u64) {
+        let found_files = self.found_files.fetch_add(1, Ordering::Relaxed) + 1;
+        let found_bytes = self.found_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.total_bar.set_message(format!(
+            "Scanning: found {found_files} files ({})",
+            indicatif::HumanBytes(found_bytes),
+        ));
+    }
+
+    fn scan_complete(&self, _total_files: u64, _total_bytes: u64) {
+        // The length of the total bar has been increased as each file was found
+        self.total_bar.disable_steady_tick();
+        self.total_bar.set_message("");
+        self.total_bar.set_style(self.total_style.clone());
+    }
 }
 
 impl Task for ProgressWithTotal {
     fn increment(&self, amt: u64) {
         self.total.inc(amt);
+        if let Some(root) = &self.root {
+            root.inc(amt);
+        }
         self.single.inc(amt);
         self.maybe_attach();
     }
 
     fn error(&self, message: &str) {
-        self.total.println(message);
+        if self.structured {
+            error_event(&self.path, message);
+        } else {
+            print_message(&self.total, message);
+        }
     }
 
     fn not_compressible_enough(&self, path: &Path) {
-        if self.verbosity >= Verbosity::Verbose {
-            let message = format!("{}: Not compressible enough, file grew", path.display());
-            self.total.println(message);
+        if self.verbosity < Verbosity::Verbose {
+            return;
+        }
+        if self.structured {
+            tracing::info!(
+                target: FILE_EVENT_TARGET,
+                path = %display_path(path),
+                action = "not-compressible",
+                "Not compressible enough, file grew",
+            );
+        } else {
+            print_message(
+                &self.total,
+                format!("{}: Not compressible enough, file grew", display_path(path)),
+            );
+        }
+    }
+
+    fn file_skipped(&self, path: &Path, why: SkipReason) {
+        if self.verbosity < skip_verbosity(&why) {
+            return;
+        }
+        if self.structured {
+            skipped_event(path, &why);
+        } else {
+            print_message(
+                &self.total,
+                format!("{}: Skipped: {why}", display_path(path)),
+            );
+        }
+    }
+
+    fn decompressing_by_os(&self, path: &Path, compression_type: CompressionType) {
+        if self.structured {
+            tracing::info!(
+                target: FILE_EVENT_TARGET,
+                path = %display_path(path),
+                action = "decompress-by-os",
+                compression_type = %compression_type,
+                "Unsupported compression type, decompressing by reading",
+            );
+        } else {
+            print_message(
+                &self.total,
+                format!(
+                    "{}: Unsupported compression type {compression_type}, decompressing by reading",
+                    display_path(path)
+                ),
+            );
         }
     }
 }
@@ -223,3 +523,31 @@ impl<W: Write> Write for ProgressBarWriter<W> {
         self.multi_progress.suspend(|| self.inner.flush())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_bars_overflow() {
+        let paths: Vec<String> = (0..8).map(|i| format!("root{i}")).collect();
+        let roots: Vec<&Path> = paths.iter().map(Path::new).collect();
+
+        assert!(root_bar_labels(&roots[..1], 6, 25).is_empty());
+        assert_eq!(root_bar_labels(&roots[..2], 6, 25), ["root0", "root1"]);
+        assert_eq!(root_bar_labels(&roots[..6], 6, 25).len(), 6);
+        assert_eq!(
+            root_bar_labels(&roots, 6, 25),
+            ["root0", "root1", "root2", "root3", "root4", "(3 others)"]
+        );
+
+        // Without overflow, every root has its own bar
+        for root in 0..6 {
+            assert_eq!(root_bar_index(root, 6), root);
+        }
+        // With overflow, the rest share the last bar
+        for root in 5..8 {
+            assert_eq!(root_bar_index(root, 6), 5);
+        }
+    }
+}
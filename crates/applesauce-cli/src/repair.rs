@@ -0,0 +1,163 @@
+//! Marking files compressed again, for `applesauce repair`, and clearing the compressed flag of
+//! files with no compressed data, for `applesauce info --repair`
+
+use crate::{display_path, format_bytes};
+use applesauce::info::Inconsistency;
+use applesauce::{
+    check_compressed_flag, clear_orphaned_compressed_flag, info, repair_compressed_flag,
+    CompressedFlagState,
+};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// Repair every file under each of `paths` which is missing only its compressed flag
+///
+/// Unless `yes`, each file is confirmed before it is repaired. Returns false if any file could
+/// not be checked or repaired.
+pub fn repair_paths(paths: &[PathBuf], yes: bool) -> bool {
+    if !yes && !io::stdin().is_terminal() {
+        eprintln!("Not running in a terminal, use --yes to repair files without confirming each");
+        return false;
+    }
+    let mut ok = true;
+    for path in paths {
+        if !path.is_dir() {
+            ok &= repair_path(path, yes, true);
+            continue;
+        }
+        for (path, file_info) in info::walk(path) {
+            match file_info {
+                // Only files with a decmpfs xattr which aren't marked compressed can be missing
+                // their flag
+                Ok(file_info) if file_info.is_compressed || file_info.decmpfs_info.is_none() => {}
+                Ok(_) => ok &= repair_path(&path, yes, false),
+                Err(e) => {
+                    eprintln!("{}: error: {e}", display_path(&path));
+                    ok = false;
+                }
+            }
+        }
+    }
+    ok
+}
+
+/// Check and repair a single file, reporting the result
+///
+/// Files with nothing to repair are only reported if they were `given` explicitly.
+fn repair_path(path: &Path, yes: bool, given: bool) -> bool {
+    let display = display_path(path);
+    let uncompressed_size = match check_compressed_flag(path) {
+        Ok(CompressedFlagState::Stripped { uncompressed_size }) => uncompressed_size,
+        Ok(CompressedFlagState::DataForkNotEmpty { data_fork_len }) => {
+            eprintln!(
+                "{display}: not repaired, the data fork has {} which marking it compressed \
+                 would hide",
+                format_bytes(data_fork_len)
+            );
+            return false;
+        }
+        Ok(CompressedFlagState::Inconsistent(e)) => {
+            eprintln!("{display}: not repaired, the compressed data is damaged: {e}");
+            return false;
+        }
+        Ok(_) => {
+            if given {
+                println!("{display}: nothing to repair");
+            }
+            return true;
+        }
+        Err(e) => {
+            eprintln!("{display}: error: {e}");
+            return false;
+        }
+    };
+
+    if !yes {
+        let prompt = format!(
+            "{display}: compressed data is intact ({}), mark it compressed again?",
+            format_bytes(uncompressed_size)
+        );
+        match confirm(&prompt) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("{display}: skipped");
+                return true;
+            }
+            Err(e) => {
+                eprintln!("{display}: error: {e}");
+                return false;
+            }
+        }
+    }
+    match repair_compressed_flag(path) {
+        Ok(CompressedFlagState::Stripped { uncompressed_size }) => {
+            println!(
+                "{display}: repaired, {} restored",
+                format_bytes(uncompressed_size)
+            );
+            true
+        }
+        Ok(_) => {
+            eprintln!("{display}: not repaired, the file changed while checking it");
+            false
+        }
+        Err(e) => {
+            eprintln!("{display}: error: {e}");
+            false
+        }
+    }
+}
+
+/// Clear the compressed flag of every file under each of `paths` which is marked compressed, but
+/// has no decmpfs xattr
+///
+/// Returns false if any file could not be checked or repaired.
+pub fn clear_orphaned_flags(paths: &[PathBuf]) -> bool {
+    let mut ok = true;
+    let mut repaired = 0_u64;
+    for path in paths {
+        let files: Box<dyn Iterator<Item = _>> = if path.is_dir() {
+            Box::new(info::walk(path))
+        } else {
+            Box::new(std::iter::once((path.clone(), info::get(path))))
+        };
+        for (path, file_info) in files {
+            let display = display_path(&path);
+            match file_info.map(|file_info| file_info.inconsistency) {
+                Ok(Some(Inconsistency::MissingDecmpfs)) => {}
+                Ok(Some(inconsistency)) => {
+                    eprintln!("{display}: not repaired, {inconsistency}");
+                    continue;
+                }
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("{display}: error: {e}");
+                    ok = false;
+                    continue;
+                }
+            }
+            match clear_orphaned_compressed_flag(&path) {
+                Ok(true) => {
+                    println!("{display}: repaired, compressed flag cleared");
+                    repaired += 1;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("{display}: error: {e}");
+                    ok = false;
+                }
+            }
+        }
+    }
+    println!("Repaired {repaired} files");
+    ok
+}
+
+fn confirm(prompt: &str) -> io::Result<bool> {
+    let mut stderr = io::stderr().lock();
+    write!(stderr, "{prompt} [y/N] ")?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
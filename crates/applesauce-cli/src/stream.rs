@@ -0,0 +1,21 @@
+//! Compressing stdin to stdout and back, for `applesauce encode` and `applesauce decode`
+
+use applesauce::compressor::Kind;
+use applesauce::container;
+use std::io::{self, BufWriter, Write};
+
+/// Compress stdin into a container on stdout
+pub fn encode(kind: Kind, level: u32) -> io::Result<u64> {
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let len = container::encode(kind, level, io::stdin().lock(), &mut stdout)?;
+    stdout.flush()?;
+    Ok(len)
+}
+
+/// Decompress a container from stdin onto stdout
+pub fn decode() -> io::Result<u64> {
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let len = container::decode(io::stdin().lock(), &mut stdout)?;
+    stdout.flush()?;
+    Ok(len)
+}
@@ -0,0 +1,188 @@
+//! One line summaries of a file, for `applesauce info --summary`
+//!
+//! The format of these lines is stable, so other tools can embed or parse them. Each line is one
+//! of:
+//!
+//! * `compressed (<kind>) <size> → <size on disk> (<savings>% saved)`
+//! * `not compressed, <size>`
+//! * `not stored locally, <size>`
+//! * `error: <message>`
+//!
+//! Where `<kind>` is `<compressor>/<storage>` (e.g. `LZFSE/rfork` or `ZLIB/xattr`),
+//! `type <n>` for a compression type applesauce doesn't know, or `no decmpfs xattr` /
+//! `invalid decmpfs xattr` for a file which is flagged as compressed without a usable decmpfs
+//! xattr. Sizes are in binary units (KiB, MiB, ...), and savings has one decimal place, and may be
+//! negative.
+
+use crate::format_bytes;
+use applesauce::info::{AfscFileInfo, CompressionType, Storage};
+use std::io;
+
+/// The state of a file's decmpfs xattr
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Decmpfs {
+    Missing,
+    Invalid,
+    Valid(CompressionType),
+}
+
+/// The parts of a file's compression info shown in its summary
+#[derive(Debug, Copy, Clone)]
+pub struct FileSummary {
+    pub is_compressed: bool,
+    pub is_dataless: bool,
+    pub decmpfs: Decmpfs,
+    /// The size of the file's contents
+    pub size: u64,
+    /// The space used by the file on disk, including its resource fork
+    pub on_disk_size: u64,
+}
+
+impl From<&AfscFileInfo> for FileSummary {
+    fn from(info: &AfscFileInfo) -> Self {
+        let decmpfs = match &info.decmpfs_info {
+            None => Decmpfs::Missing,
+            Some(Err(_)) => Decmpfs::Invalid,
+            Some(Ok(decmpfs_info)) => Decmpfs::Valid(decmpfs_info.compression_type),
+        };
+        Self {
+            is_compressed: info.is_compressed,
+            is_dataless: info.is_dataless,
+            decmpfs,
+            size: info.stat_size,
+            on_disk_size: info.on_disk_size(),
+        }
+    }
+}
+
+/// Format the one line summary of a file, without a trailing newline
+#[must_use]
+pub fn summary_line(summary: &io::Result<FileSummary>) -> String {
+    let summary = match summary {
+        Ok(summary) => summary,
+        Err(e) => return format!("error: {}", error_message(e)),
+    };
+    let size = format_bytes(summary.size);
+    if summary.is_dataless {
+        return format!("not stored locally, {size}");
+    }
+    if !summary.is_compressed {
+        return format!("not compressed, {size}");
+    }
+    let kind = match summary.decmpfs {
+        Decmpfs::Missing => "no decmpfs xattr".to_owned(),
+        Decmpfs::Invalid => "invalid decmpfs xattr".to_owned(),
+        Decmpfs::Valid(compression_type) => match compression_type.compression_storage() {
+            Some((compressor, storage)) => {
+                let storage = match storage {
+                    Storage::Xattr => "xattr",
+                    Storage::ResourceFork => "rfork",
+                };
+                format!("{compressor}/{storage}")
+            }
+            None => format!("type {}", compression_type.raw_type()),
+        },
+    };
+    let savings = if summary.size == 0 {
+        0.0
+    } else {
+        (1.0 - summary.on_disk_size as f64 / summary.size as f64) * 100.0
+    };
+    format!(
+        "compressed ({kind}) {size} → {} ({savings:.1}% saved)",
+        format_bytes(summary.on_disk_size),
+    )
+}
+
+/// A short, stable description of an error
+///
+/// The OS's description of common errors includes the error number, which isn't useful here.
+fn error_message(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::NotFound => "not found".to_owned(),
+        io::ErrorKind::PermissionDenied => "permission denied".to_owned(),
+        _ => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write as _;
+
+    #[test]
+    fn summary_golden() {
+        let compressed = |decmpfs| FileSummary {
+            is_compressed: true,
+            is_dataless: false,
+            decmpfs,
+            size: 1_258_291,
+            on_disk_size: 308_224,
+        };
+        let cases: Vec<(&str, io::Result<FileSummary>)> = vec![
+            (
+                "compressed via rfork",
+                Ok(compressed(Decmpfs::Valid(CompressionType::from_raw_type(
+                    12,
+                )))),
+            ),
+            (
+                "compressed via xattr",
+                Ok(FileSummary {
+                    size: 3000,
+                    on_disk_size: 0,
+                    ..compressed(Decmpfs::Valid(CompressionType::from_raw_type(3)))
+                }),
+            ),
+            (
+                "not compressed",
+                Ok(FileSummary {
+                    is_compressed: false,
+                    size: 4_299_161,
+                    on_disk_size: 4_300_800,
+                    ..compressed(Decmpfs::Missing)
+                }),
+            ),
+            (
+                "not stored locally",
+                Ok(FileSummary {
+                    is_dataless: true,
+                    on_disk_size: 0,
+                    ..compressed(Decmpfs::Missing)
+                }),
+            ),
+            ("unreadable", Err(io::ErrorKind::PermissionDenied.into())),
+            ("missing", Err(io::ErrorKind::NotFound.into())),
+            ("flag without xattr", Ok(compressed(Decmpfs::Missing))),
+            ("invalid xattr", Ok(compressed(Decmpfs::Invalid))),
+            (
+                "unknown type",
+                Ok(compressed(Decmpfs::Valid(CompressionType::from_raw_type(
+                    99,
+                )))),
+            ),
+            (
+                "larger than contents",
+                Ok(FileSummary {
+                    size: 10,
+                    on_disk_size: 4096,
+                    ..compressed(Decmpfs::Valid(CompressionType::from_raw_type(8)))
+                }),
+            ),
+            (
+                "empty",
+                Ok(FileSummary {
+                    size: 0,
+                    on_disk_size: 0,
+                    ..compressed(Decmpfs::Valid(CompressionType::from_raw_type(7)))
+                }),
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, summary) in &cases {
+            writeln!(output, "{name}: {}", summary_line(summary)).unwrap();
+        }
+        assert_eq!(output, include_str!("../tests/golden/info-summary.txt"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Cheap structural checks of compressed data, which don't decompress any blocks
+
+use crate::decmpfs::{self, CompressionType, DecodeError, Storage};
+use crate::reader::Open;
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::{error, fmt};
+
+/// A structural problem with compressed data, found by [`check_structure`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StructureError {
+    /// The decmpfs xattr could not be decoded
+    Decode(DecodeError),
+    /// The compression type is unknown, or not supported in this build
+    UnsupportedType(CompressionType),
+    /// The uncompressed size in the decmpfs xattr is not the size of the original data
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The block table in the resource fork could not be read, or does not describe the number
+    /// of blocks needed for the uncompressed size
+    BlockTable(io::Error),
+    /// The resource fork is not the length described by its block table
+    ResourceForkLength { expected: u64, actual: u64 },
+    /// The stored data describes a different number of blocks than were written
+    BlockCountMismatch { expected: u64, actual: u64 },
+    /// The stored blocks have a different total size than the blocks which were written
+    CompressedSizeMismatch { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for StructureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(e) => write!(f, "invalid decmpfs xattr: {e}"),
+            Self::UnsupportedType(compression_type) => {
+                write!(f, "unsupported compression type {compression_type}")
+            }
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "decmpfs xattr has uncompressed size {actual}, expected {expected}"
+            ),
+            Self::BlockTable(e) => write!(f, "invalid block table: {e}"),
+            Self::ResourceForkLength { expected, actual } => write!(
+                f,
+                "resource fork is {actual} bytes, block table describes {expected} bytes"
+            ),
+            Self::BlockCountMismatch { expected, actual } => write!(
+                f,
+                "stored data has {actual} blocks, {expected} blocks were written"
+            ),
+            Self::CompressedSizeMismatch { expected, actual } => write!(
+                f,
+                "stored blocks total {actual} bytes, {expected} bytes were written"
+            ),
+        }
+    }
+}
+
+impl error::Error for StructureError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Decode(e) => Some(e),
+            Self::BlockTable(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<StructureError> for io::Error {
+    fn from(err: StructureError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The blocks written for a compressed file
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Written {
+    /// The number of blocks
+    pub block_count: u64,
+    /// The total size of the compressed blocks
+    pub compressed_size: u64,
+}
+
+/// Check that compressed data is consistent with `uncompressed_size` bytes of original data
+///
+/// This checks that the decmpfs xattr records `uncompressed_size`, and, if the data is stored
+/// in the resource fork, that the block table has one block for each [`BLOCK_SIZE`](crate::BLOCK_SIZE)
+/// of data, and the resource fork is exactly as long as the header, blocks, and trailer. Only
+/// the block table is read, no blocks are decompressed, so this is much cheaper than comparing
+/// the data, but cannot detect corrupted blocks.
+pub fn check_structure<O: Open>(
+    decmpfs_data: &[u8],
+    uncompressed_size: u64,
+    open: O,
+) -> Result<(), StructureError> {
+    read_structure(decmpfs_data, uncompressed_size, open).map(drop)
+}
+
+/// Check that stored compressed data describes exactly the blocks which were written
+///
+/// This does everything [`check_structure`] does, and also checks that the decmpfs xattr and
+/// block table describe `written.block_count` blocks, totalling `written.compressed_size` bytes.
+/// This catches a block table or decmpfs xattr which doesn't match the data which was written,
+/// e.g. from a torn write.
+pub fn check_written<O: Open>(
+    decmpfs_data: &[u8],
+    uncompressed_size: u64,
+    written: Written,
+    open: O,
+) -> Result<(), StructureError> {
+    let stored = read_structure(decmpfs_data, uncompressed_size, open)?;
+    if stored.block_count != written.block_count {
+        return Err(StructureError::BlockCountMismatch {
+            expected: written.block_count,
+            actual: stored.block_count,
+        });
+    }
+    if stored.compressed_size != written.compressed_size {
+        return Err(StructureError::CompressedSizeMismatch {
+            expected: written.compressed_size,
+            actual: stored.compressed_size,
+        });
+    }
+    Ok(())
+}
+
+fn read_structure<O: Open>(
+    decmpfs_data: &[u8],
+    uncompressed_size: u64,
+    open: O,
+) -> Result<Written, StructureError> {
+    let value = decmpfs::Value::from_data(decmpfs_data).map_err(StructureError::Decode)?;
+    let (kind, storage) = value
+        .compression_type
+        .compression_storage()
+        .filter(|(kind, _)| kind.supported())
+        .ok_or(StructureError::UnsupportedType(value.compression_type))?;
+    if value.uncompressed_size != uncompressed_size {
+        return Err(StructureError::SizeMismatch {
+            expected: uncompressed_size,
+            actual: value.uncompressed_size,
+        });
+    }
+    if storage == Storage::Xattr {
+        return Ok(Written {
+            block_count: u64::from(!value.extra_data.is_empty()),
+            compressed_size: value.extra_data.len() as u64,
+        });
+    }
+
+    // The block table is parsed with many small reads, so buffer them
+    let mut rfork = BufReader::new(
+        open.open_resource_fork()
+            .map_err(StructureError::BlockTable)?,
+    );
+    let block_infos = kind
+        .read_block_info(&mut rfork, uncompressed_size)
+        .map_err(StructureError::BlockTable)?;
+    let actual = rfork
+        .seek(SeekFrom::End(0))
+        .map_err(StructureError::BlockTable)?;
+
+    let blocks_len: u64 = block_infos
+        .iter()
+        .map(|info| u64::from(info.compressed_size))
+        .sum();
+    let expected = kind.header_size(block_infos.len() as u64) + blocks_len + kind.trailer_size();
+    if actual != expected {
+        return Err(StructureError::ResourceForkLength { expected, actual });
+    }
+    Ok(Written {
+        block_count: block_infos.len() as u64,
+        compressed_size: blocks_len,
+    })
+}
@@ -0,0 +1,208 @@
+use super::{Compressor, Kind};
+use crate::decmpfs::{BlockInfo, CompressionType, Storage};
+use std::fmt;
+use std::io::{self, Read, Seek, Write};
+
+/// A resource fork which can be read from and seeked, usable as a trait object
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// A resource fork which can be written to and seeked, usable as a trait object
+pub trait WriteSeek: Write + Seek {}
+
+impl<T: Write + Seek + ?Sized> WriteSeek for T {}
+
+/// A compression codec, usable as a trait object
+///
+/// This allows codecs defined outside this crate to be used with
+/// [`Writer::with_codec`](crate::writer::Writer::with_codec) and
+/// [`Reader::with_codec`](crate::reader::Reader::with_codec). The built-in codecs implement it
+/// through [`Compressor`], but are normally used directly by [`Kind`], which avoids dynamic
+/// dispatch.
+///
+/// The implementor chooses the decmpfs compression type stored for each [`Storage`]: macOS will
+/// not be able to read files compressed with a type it doesn't know.
+pub trait Codec: Send {
+    /// A human readable name for the codec
+    fn name(&self) -> &str;
+
+    /// The decmpfs compression type for data stored in `storage`
+    fn compression_type(&self, storage: Storage) -> CompressionType;
+
+    /// The offset to start data at in the resource fork, for the specified number of blocks
+    fn header_size(&self, block_count: u64) -> u64;
+
+    /// The size of the data following the blocks in the resource fork
+    fn trailer_size(&self) -> u64 {
+        0
+    }
+
+    /// Compress `src` into `dst`, returning the number of bytes written
+    fn compress(&mut self, dst: &mut [u8], src: &[u8], level: u32) -> io::Result<usize>;
+
+    /// Decompress `src` into `dst`, returning the number of bytes written
+    fn decompress(&mut self, dst: &mut [u8], src: &[u8]) -> io::Result<usize>;
+
+    /// Read the block table of a resource fork holding `orig_file_size` bytes of data in blocks
+    /// of `block_size`
+    fn read_block_info(
+        &self,
+        reader: &mut dyn ReadSeek,
+        orig_file_size: u64,
+        block_size: u64,
+    ) -> io::Result<Vec<BlockInfo>>;
+
+    /// Write the header and trailer of the resource fork, once every block has been written
+    ///
+    /// `writer` is positioned after the last block.
+    fn finish(&self, writer: &mut dyn WriteSeek, block_sizes: &[u32]) -> io::Result<()>;
+
+    /// The storage for `compression_type`, if it belongs to this codec
+    fn storage_for(&self, compression_type: CompressionType) -> Option<Storage> {
+        [Storage::Xattr, Storage::ResourceFork]
+            .into_iter()
+            .find(|&storage| self.compression_type(storage) == compression_type)
+    }
+}
+
+impl Codec for Compressor {
+    fn name(&self) -> &str {
+        self.kind().name()
+    }
+
+    fn compression_type(&self, storage: Storage) -> CompressionType {
+        CompressionType::new(self.kind(), storage)
+    }
+
+    fn header_size(&self, block_count: u64) -> u64 {
+        self.kind().header_size(block_count)
+    }
+
+    fn trailer_size(&self) -> u64 {
+        self.kind().trailer_size()
+    }
+
+    fn compress(&mut self, dst: &mut [u8], src: &[u8], level: u32) -> io::Result<usize> {
+        Compressor::compress(self, dst, src, level)
+    }
+
+    fn decompress(&mut self, dst: &mut [u8], src: &[u8]) -> io::Result<usize> {
+        Compressor::decompress(self, dst, src)
+    }
+
+    fn read_block_info(
+        &self,
+        reader: &mut dyn ReadSeek,
+        orig_file_size: u64,
+        block_size: u64,
+    ) -> io::Result<Vec<BlockInfo>> {
+        self.kind()
+            .read_block_info_with_block_size(reader, orig_file_size, block_size)
+    }
+
+    fn finish(&self, writer: &mut dyn WriteSeek, block_sizes: &[u32]) -> io::Result<()> {
+        self.kind().finish(writer, block_sizes)
+    }
+}
+
+/// The format of compressed data: either a built-in [`Kind`], or an external [`Codec`]
+pub enum Format {
+    Builtin(Kind),
+    External(Box<dyn Codec>),
+}
+
+impl Format {
+    /// The built-in kind, if this is not an external codec
+    #[must_use]
+    pub fn kind(&self) -> Option<Kind> {
+        match *self {
+            Format::Builtin(kind) => Some(kind),
+            Format::External(_) => None,
+        }
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Format::Builtin(kind) => kind.name(),
+            Format::External(codec) => codec.name(),
+        }
+    }
+
+    #[must_use]
+    pub fn compression_type(&self, storage: Storage) -> CompressionType {
+        match self {
+            Format::Builtin(kind) => CompressionType::new(*kind, storage),
+            Format::External(codec) => codec.compression_type(storage),
+        }
+    }
+
+    #[must_use]
+    pub fn header_size(&self, block_count: u64) -> u64 {
+        match self {
+            Format::Builtin(kind) => kind.header_size(block_count),
+            Format::External(codec) => codec.header_size(block_count),
+        }
+    }
+
+    #[must_use]
+    pub fn trailer_size(&self) -> u64 {
+        match self {
+            Format::Builtin(kind) => kind.trailer_size(),
+            Format::External(codec) => codec.trailer_size(),
+        }
+    }
+
+    pub fn read_block_info<R: Read + Seek>(
+        &self,
+        mut reader: R,
+        orig_file_size: u64,
+        block_size: u64,
+    ) -> io::Result<Vec<BlockInfo>> {
+        match self {
+            Format::Builtin(kind) => {
+                kind.read_block_info_with_block_size(reader, orig_file_size, block_size)
+            }
+            Format::External(codec) => {
+                codec.read_block_info(&mut reader, orig_file_size, block_size)
+            }
+        }
+    }
+
+    pub fn finish<W: Write + Seek>(&self, mut writer: W, block_sizes: &[u32]) -> io::Result<()> {
+        match self {
+            Format::Builtin(kind) => kind.finish(writer, block_sizes),
+            Format::External(codec) => codec.finish(&mut writer, block_sizes),
+        }
+    }
+}
+
+impl From<Kind> for Format {
+    #[inline]
+    fn from(kind: Kind) -> Self {
+        Format::Builtin(kind)
+    }
+}
+
+impl From<Box<dyn Codec>> for Format {
+    #[inline]
+    fn from(codec: Box<dyn Codec>) -> Self {
+        Format::External(codec)
+    }
+}
+
+impl fmt::Debug for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Builtin(kind) => f.debug_tuple("Builtin").field(kind).finish(),
+            Format::External(codec) => f.debug_tuple("External").field(&codec.name()).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
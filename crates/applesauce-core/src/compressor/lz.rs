@@ -3,10 +3,23 @@ use crate::decmpfs;
 use crate::decmpfs::BlockInfo;
 use std::io::SeekFrom;
 use std::marker::PhantomData;
-use std::{io, mem};
+use std::{cmp, io, mem};
+
+#[cfg(test)]
+thread_local! {
+    /// The number of scratch buffers allocated on this thread
+    pub(super) static SCRATCH_ALLOCATIONS: std::cell::Cell<usize> =
+        const { std::cell::Cell::new(0) };
+}
 
 pub trait Impl {
     const UNCOMPRESSED_PREFIX: Option<u8> = None;
+    /// The bytes the encoder adds around a block it stores uncompressed itself
+    ///
+    /// Only used without an [`UNCOMPRESSED_PREFIX`](Impl::UNCOMPRESSED_PREFIX): the output is
+    /// limited to this much more than the input, so incompressible data is stored raw rather
+    /// than expanded.
+    const RAW_BLOCK_OVERHEAD: usize = 0;
 
     fn scratch_size() -> usize;
 
@@ -21,6 +34,8 @@ pub struct Lz<I> {
 
 impl<I: Impl> Lz<I> {
     pub fn new() -> Self {
+        #[cfg(test)]
+        SCRATCH_ALLOCATIONS.set(SCRATCH_ALLOCATIONS.get() + 1);
         Self {
             buf: vec![0; I::scratch_size()].into_boxed_slice(),
             _impl: PhantomData,
@@ -39,11 +54,11 @@ impl<I: Impl> CompressorImpl for Lz<I> {
         let max_compress_size = if I::UNCOMPRESSED_PREFIX.is_some() {
             src.len()
         } else {
-            dst.len()
+            cmp::min(dst.len(), src.len() + I::RAW_BLOCK_OVERHEAD)
         };
         // SAFETY:
         // dst is valid to write up to len bytes
-        // len is either dst.len() or src.len(), and dst.len() > src.len()
+        // len is at most dst.len()
         // src is initialised for len bytes
         // buf is valid to write up to scratch size bytes
         let len = unsafe { I::encode(&mut dst[..max_compress_size], src, &mut self.buf) };
@@ -90,11 +105,22 @@ impl<I: Impl> CompressorImpl for Lz<I> {
     fn read_block_info<R: io::Read + io::Seek>(
         mut reader: R,
         orig_file_size: u64,
+        block_size: u64,
     ) -> io::Result<Vec<decmpfs::BlockInfo>> {
+        let block_count = crate::num_blocks_with_size(orig_file_size, block_size);
+        // The block count comes from the decmpfs xattr: ensure the offsets fit in the resource
+        // fork before trusting it
+        let end_pos = reader.seek(SeekFrom::End(0))?;
+        let blocks_start = u32::try_from(Self::header_size(block_count))
+            .ok()
+            .filter(|&blocks_start| u64::from(blocks_start) <= end_pos)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "block offsets do not fit in resource fork",
+                )
+            })?;
         reader.rewind()?;
-        let block_count = crate::num_blocks(orig_file_size);
-
-        let blocks_start = u32::try_from(Self::header_size(block_count)).unwrap();
         let mut result = Vec::with_capacity(
             block_count
                 .try_into()
@@ -107,7 +133,7 @@ impl<I: Impl> CompressorImpl for Lz<I> {
         let mut last_offset = u32::from_le_bytes(buf);
         if last_offset != blocks_start {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::InvalidData,
                 "unexpected first block offset",
             ));
         }
@@ -117,9 +143,9 @@ impl<I: Impl> CompressorImpl for Lz<I> {
         for _ in 0..block_count {
             reader.read_exact(&mut buf)?;
             let next_offset = u32::from_le_bytes(buf);
-            let compressed_size = next_offset
-                .checked_sub(last_offset)
-                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "compressed block overlap"))?;
+            let compressed_size = next_offset.checked_sub(last_offset).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "compressed block overlap")
+            })?;
             result.push(BlockInfo {
                 offset: last_offset,
                 compressed_size,
@@ -128,10 +154,9 @@ impl<I: Impl> CompressorImpl for Lz<I> {
         }
 
         // Check that the last offset is the end of the file
-        let end_pos = reader.seek(SeekFrom::End(0))?;
         if end_pos != u64::from(last_offset) {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::InvalidData,
                 "last block does not end resource fork",
             ));
         }
@@ -213,9 +238,12 @@ mod tests {
         );
 
         cursor.set_position(0);
-        let block_info =
-            Lz::<FakeLzImpl>::read_block_info(&mut cursor, (block_sizes.len() * BLOCK_SIZE) as u64)
-                .unwrap();
+        let block_info = Lz::<FakeLzImpl>::read_block_info(
+            &mut cursor,
+            (block_sizes.len() * BLOCK_SIZE) as u64,
+            BLOCK_SIZE as u64,
+        )
+        .unwrap();
         let expected_block_info: Vec<BlockInfo> = block_sizes
             .iter()
             .scan(blocks_start as u32, |acc, &size| {
@@ -5,6 +5,9 @@ use std::cmp;
 pub enum Impl {}
 
 impl lz::Impl for Impl {
+    // An uncompressed block header, and the end of stream marker
+    const RAW_BLOCK_OVERHEAD: usize = 12;
+
     fn scratch_size() -> usize {
         // SAFETY: Both of these functions are always safe to call
         unsafe { cmp::max(lzfse_encode_scratch_size(), lzfse_decode_scratch_size()) }
@@ -7,6 +7,9 @@ const ALGORITHM: bindings::compression_algorithm =
     bindings::compression_algorithm::COMPRESSION_LZFSE;
 
 impl lz::Impl for Impl {
+    // An uncompressed block header, and the end of stream marker
+    const RAW_BLOCK_OVERHEAD: usize = 12;
+
     fn scratch_size() -> usize {
         // SAFETY: Both of these functions are always safe to call
         unsafe {
@@ -7,17 +7,25 @@ use self::lzfse::Lzfse;
 use self::zlib::Zlib;
 use crate::decmpfs;
 use crate::decmpfs::BlockInfo;
-use std::{fmt, io};
+pub use codec::{Codec, Format, ReadSeek, WriteSeek};
+pub use pool::Pool;
+use std::str::FromStr;
+use std::{error, fmt, io};
 
+mod codec;
 #[cfg(any(feature = "lzfse", feature = "lzvn"))]
 mod lz;
 #[cfg(feature = "lzfse")]
 mod lzfse;
 #[cfg(feature = "lzvn")]
 mod lzvn;
+mod pool;
 #[cfg(feature = "zlib")]
 mod zlib;
 
+/// Offsets in the resource fork are 32 bits, so it can't be any larger than this
+const MAX_RESOURCE_FORK_SIZE: u64 = u32::MAX as u64;
+
 pub(crate) trait CompressorImpl {
     /// The offset to start data at, for the specified number of blocks
     #[must_use]
@@ -34,6 +42,7 @@ pub(crate) trait CompressorImpl {
     fn read_block_info<R: io::Read + io::Seek>(
         reader: R,
         orig_file_size: u64,
+        block_size: u64,
     ) -> io::Result<Vec<decmpfs::BlockInfo>>;
 
     fn finish<W: io::Write + io::Seek>(writer: W, block_sizes: &[u32]) -> io::Result<()>;
@@ -45,7 +54,7 @@ impl Compressor {
     #[cfg(feature = "zlib")]
     #[must_use]
     pub fn zlib() -> Self {
-        Self(Data::Zlib(Zlib))
+        Self(Data::Zlib(Zlib::new()))
     }
 
     #[cfg(feature = "lzfse")]
@@ -134,6 +143,15 @@ impl fmt::Display for Kind {
 }
 
 impl Kind {
+    /// All compression kinds, including those which are not supported in this build
+    ///
+    /// Use [`Kind::supported`] to filter out kinds which were not compiled in.
+    #[must_use]
+    #[inline]
+    pub const fn all() -> &'static [Self] {
+        &[Kind::Zlib, Kind::Lzvn, Kind::Lzfse]
+    }
+
     #[must_use]
     pub const fn name(self) -> &'static str {
         match self {
@@ -160,7 +178,7 @@ impl Kind {
     pub fn compressor(self) -> Option<Compressor> {
         let data = match self {
             #[cfg(feature = "zlib")]
-            Kind::Zlib => Data::Zlib(Zlib),
+            Kind::Zlib => Data::Zlib(Zlib::new()),
             #[cfg(feature = "lzfse")]
             Kind::Lzfse => Data::Lzfse(Lzfse::new()),
             #[cfg(feature = "lzvn")]
@@ -185,18 +203,87 @@ impl Kind {
         }
     }
 
+    /// The size of the data following the blocks in the resource fork
+    #[must_use]
+    pub fn trailer_size(self) -> u64 {
+        match self {
+            #[cfg(feature = "zlib")]
+            Kind::Zlib => Zlib::trailer_size(),
+            #[cfg(feature = "lzvn")]
+            Kind::Lzvn => Lzvn::trailer_size(),
+            #[cfg(feature = "lzfse")]
+            Kind::Lzfse => Lzfse::trailer_size(),
+            #[allow(unreachable_patterns)]
+            _ => panic!("Unsupported compression kind {self}"),
+        }
+    }
+
+    /// The most a block can grow by when compressed
+    ///
+    /// Incompressible blocks are stored as-is: zlib and lzvn prefix them with a single byte,
+    /// lzfse wraps them in a raw block header and an end of stream marker.
+    #[must_use]
+    pub const fn max_block_overhead(self) -> u64 {
+        match self {
+            Kind::Zlib | Kind::Lzvn => 1,
+            Kind::Lzfse => 12,
+        }
+    }
+
+    /// The size of the largest resource fork which could be needed to store `uncompressed_size`
+    /// bytes in blocks of `block_size`, however badly the data compresses
+    ///
+    /// Saturates at `u64::MAX` rather than overflowing.
+    #[must_use]
+    pub fn max_resource_fork_size(self, uncompressed_size: u64, block_size: u64) -> u64 {
+        let block_count = crate::num_blocks_with_size(uncompressed_size, block_size);
+        self.header_size(block_count)
+            .saturating_add(uncompressed_size)
+            .saturating_add(block_count.saturating_mul(self.max_block_overhead()))
+            .saturating_add(self.trailer_size())
+    }
+
+    /// Returns true if `uncompressed_size` bytes in blocks of `block_size` are guaranteed to fit
+    /// in a resource fork, even if none of the blocks compress
+    #[must_use]
+    pub fn always_fits_resource_fork(self, uncompressed_size: u64, block_size: u64) -> bool {
+        self.max_resource_fork_size(uncompressed_size, block_size) <= MAX_RESOURCE_FORK_SIZE
+    }
+
+    /// The most compressed block data that fits in a resource fork with `block_count` blocks,
+    /// once the header and trailer are accounted for
+    #[must_use]
+    pub fn max_block_data_size(self, block_count: u64) -> u64 {
+        MAX_RESOURCE_FORK_SIZE
+            .saturating_sub(self.header_size(block_count))
+            .saturating_sub(self.trailer_size())
+    }
+
+    /// Read the block table of a resource fork holding `orig_file_size` bytes of data in blocks
+    /// of [`BLOCK_SIZE`](crate::BLOCK_SIZE)
     pub fn read_block_info<R: io::Read + io::Seek>(
         self,
         reader: R,
         orig_file_size: u64,
+    ) -> io::Result<Vec<BlockInfo>> {
+        self.read_block_info_with_block_size(reader, orig_file_size, crate::BLOCK_SIZE as u64)
+    }
+
+    /// Read the block table of a resource fork holding `orig_file_size` bytes of data in blocks
+    /// of `block_size`
+    pub fn read_block_info_with_block_size<R: io::Read + io::Seek>(
+        self,
+        reader: R,
+        orig_file_size: u64,
+        block_size: u64,
     ) -> io::Result<Vec<BlockInfo>> {
         match self {
             #[cfg(feature = "zlib")]
-            Kind::Zlib => Zlib::read_block_info(reader, orig_file_size),
+            Kind::Zlib => Zlib::read_block_info(reader, orig_file_size, block_size),
             #[cfg(feature = "lzvn")]
-            Kind::Lzvn => Lzvn::read_block_info(reader, orig_file_size),
+            Kind::Lzvn => Lzvn::read_block_info(reader, orig_file_size, block_size),
             #[cfg(feature = "lzfse")]
-            Kind::Lzfse => Lzfse::read_block_info(reader, orig_file_size),
+            Kind::Lzfse => Lzfse::read_block_info(reader, orig_file_size, block_size),
             #[allow(unreachable_patterns)]
             _ => panic!("Unsupported compression kind {self}"),
         }
@@ -216,6 +303,64 @@ impl Kind {
     }
 }
 
+impl FromStr for Kind {
+    type Err = ParseKindError;
+
+    /// Parse a compression kind from its name, ignoring case
+    ///
+    /// Kinds which are recognized, but which were not compiled in, result in a
+    /// [`ParseKindError::Unsupported`] error.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = Self::all()
+            .iter()
+            .copied()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseKindError::Unknown(s.to_owned()))?;
+        if !kind.supported() {
+            return Err(ParseKindError::Unsupported(kind));
+        }
+        Ok(kind)
+    }
+}
+
+/// An error returned when parsing a [`Kind`] from a string fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseKindError {
+    /// The name does not match any compression kind
+    Unknown(String),
+    /// The name matches a compression kind, but support for it was not compiled in
+    Unsupported(Kind),
+}
+
+impl fmt::Display for ParseKindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseKindError::Unknown(name) => {
+                write!(f, "unknown compression kind {name:?}, expected one of: ")?;
+                for (i, kind) in Kind::all().iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_str(kind.name())?;
+                    if !kind.supported() {
+                        f.write_str(" (not compiled in)")?;
+                    }
+                }
+                Ok(())
+            }
+            ParseKindError::Unsupported(kind) => {
+                write!(
+                    f,
+                    "{} support not compiled in",
+                    kind.name().to_ascii_lowercase()
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for ParseKindError {}
+
 impl Default for Kind {
     #[inline]
     fn default() -> Self {
@@ -235,6 +380,89 @@ mod tests {
 
     const PLAINTEXT: &[u8] = include_bytes!("mod.rs");
 
+    #[test]
+    fn kind_from_str_round_trip() {
+        for &kind in Kind::all() {
+            let parsed = kind.to_string().parse::<Kind>();
+            if kind.supported() {
+                assert_eq!(parsed, Ok(kind));
+            } else {
+                assert_eq!(parsed, Err(ParseKindError::Unsupported(kind)));
+            }
+        }
+    }
+
+    #[test]
+    fn kind_from_str_ignores_case() {
+        let kind = Kind::default();
+        assert_eq!(kind.name().to_ascii_lowercase().parse::<Kind>(), Ok(kind));
+    }
+
+    #[test]
+    fn kind_from_str_unknown() {
+        let err = "zstd".parse::<Kind>().unwrap_err();
+        assert_eq!(err, ParseKindError::Unknown("zstd".to_owned()));
+        let message = err.to_string();
+        for kind in Kind::all() {
+            assert!(message.contains(kind.name()));
+        }
+    }
+
+    #[test]
+    fn max_block_overhead_covers_incompressible_data() {
+        use rand::RngCore;
+
+        let mut src = vec![0; crate::BLOCK_SIZE];
+        rand::thread_rng().fill_bytes(&mut src);
+        let supported = Kind::all().iter().copied().filter(|kind| kind.supported());
+        for kind in supported {
+            let mut compressor = kind.compressor().unwrap();
+            let mut dst = vec![0; crate::BLOCK_SIZE + 1024];
+            let len = compressor.compress(&mut dst, &src, 5).unwrap();
+            assert!(
+                len as u64 <= src.len() as u64 + kind.max_block_overhead(),
+                "{kind}: {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn resource_fork_size_limits() {
+        let block_size = crate::BLOCK_SIZE as u64;
+        let supported = Kind::all().iter().copied().filter(|kind| kind.supported());
+        for kind in supported {
+            assert_eq!(
+                kind.max_resource_fork_size(0, block_size),
+                kind.header_size(0) + kind.trailer_size()
+            );
+            assert_eq!(
+                kind.max_resource_fork_size(block_size + 1, block_size),
+                kind.header_size(2)
+                    + block_size
+                    + 1
+                    + 2 * kind.max_block_overhead()
+                    + kind.trailer_size()
+            );
+            assert_eq!(kind.max_resource_fork_size(u64::MAX, block_size), u64::MAX);
+
+            assert!(kind.always_fits_resource_fork(1024 * 1024 * 1024, block_size));
+            assert!(!kind.always_fits_resource_fork(crate::MAX_COMPRESSIBLE_SIZE - 1, block_size));
+
+            // The largest file which always fits is exactly at the limit, when it doesn't compress
+            let block_count = crate::num_blocks(crate::MAX_COMPRESSIBLE_SIZE);
+            let data_limit = kind.max_block_data_size(block_count);
+            assert_eq!(
+                kind.header_size(block_count) + data_limit + kind.trailer_size(),
+                MAX_RESOURCE_FORK_SIZE
+            );
+            let largest = data_limit - block_count * kind.max_block_overhead();
+            assert!(crate::num_blocks(largest) <= block_count);
+            assert!(kind.max_resource_fork_size(largest, block_size) <= MAX_RESOURCE_FORK_SIZE);
+
+            assert_eq!(kind.max_block_data_size(u64::MAX / 1024), 0);
+        }
+    }
+
     pub(super) fn compressor_round_trip<C: CompressorImpl>(c: &mut C) {
         let mut buf = vec![0u8; PLAINTEXT.len() * 2];
         let len = c.compress(&mut buf, PLAINTEXT, 6).unwrap();
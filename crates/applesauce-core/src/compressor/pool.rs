@@ -0,0 +1,119 @@
+use super::{Compressor, Kind};
+use std::cell::RefCell;
+use std::io;
+
+thread_local! {
+    static COMPRESSORS: RefCell<[Option<Compressor>; Kind::all().len()]> =
+        const { RefCell::new([None, None, None]) };
+}
+
+/// Compressors cached per thread, for compressing or decompressing blocks on any thread
+///
+/// Creating a [`Compressor`] for the lz kinds allocates a scratch buffer of several megabytes, so
+/// code which handles many blocks should reuse compressors rather than creating one per block or
+/// per file. [`Pool::with`] keeps one compressor of each kind on every thread which uses it.
+///
+/// ```
+/// use applesauce_core::compressor::{Kind, Pool};
+///
+/// let data = [1; 1024];
+/// let mut compressed = vec![0; data.len() + 1024];
+/// let len = Pool::with(Kind::default(), |compressor| {
+///     compressor.compress(&mut compressed, &data, 5)
+/// })
+/// .unwrap();
+/// assert!(len < data.len());
+/// ```
+#[derive(Debug)]
+pub struct Pool {
+    _private: (),
+}
+
+impl Pool {
+    /// Call `f` with this thread's compressor of `kind`, creating it if needed
+    ///
+    /// Returns an [`Unsupported`](io::ErrorKind::Unsupported) error without calling `f` if `kind`
+    /// was not compiled in. `f` may call `with` again: a nested call for the same kind uses a
+    /// separate compressor.
+    pub fn with<T>(kind: Kind, f: impl FnOnce(&mut Compressor) -> io::Result<T>) -> io::Result<T> {
+        // Taken out of the cache while in use, so a nested call can't borrow it twice
+        let cached = COMPRESSORS
+            .try_with(|compressors| compressors.borrow_mut()[kind as usize].take())
+            .ok()
+            .flatten();
+        let mut compressor = cached.or_else(|| kind.compressor()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{kind} compression is not supported"),
+            )
+        })?;
+        let result = f(&mut compressor);
+        // The cache is gone if this thread is exiting, the compressor is just dropped
+        let _ = COMPRESSORS.try_with(|compressors| {
+            compressors.borrow_mut()[kind as usize] = Some(compressor);
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "lzfse", feature = "lzvn"))]
+    fn compress_blocks(kind: Kind) {
+        // Small blocks: only the number of compressors created matters
+        let data = [1; 1024];
+        let mut compressed = vec![0; data.len() + 1024];
+        let mut decompressed = vec![0; data.len() + 1];
+        for _ in 0..1000 {
+            let len = Pool::with(kind, |compressor| {
+                compressor.compress(&mut compressed, &data, 5)
+            })
+            .unwrap();
+            let len = Pool::with(kind, |compressor| {
+                compressor.decompress(&mut decompressed, &compressed[..len])
+            })
+            .unwrap();
+            assert_eq!(decompressed[..len], data);
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "lzfse", feature = "lzvn"))]
+    fn scratch_allocated_once_per_thread() {
+        use crate::compressor::lz::SCRATCH_ALLOCATIONS;
+        use std::thread;
+
+        let lz_kinds = [Kind::Lzfse, Kind::Lzvn];
+        let lz_kinds = lz_kinds.iter().filter(|kind| kind.supported());
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for (i, &kind) in lz_kinds.clone().enumerate() {
+                        compress_blocks(kind);
+                        assert_eq!(SCRATCH_ALLOCATIONS.get(), i + 1, "{kind}");
+                        compress_blocks(kind);
+                        assert_eq!(SCRATCH_ALLOCATIONS.get(), i + 1, "{kind}");
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn nested() {
+        let kind = Kind::default();
+        let data = [1; 1024];
+        let mut compressed = vec![0; data.len() + 1024];
+        let mut decompressed = vec![0; data.len() + 1];
+        let len = Pool::with(kind, |outer| {
+            let len = outer.compress(&mut compressed, &data, 5)?;
+            Pool::with(kind, |inner| {
+                inner.decompress(&mut decompressed, &compressed[..len])
+            })
+        })
+        .unwrap();
+        assert_eq!(decompressed[..len], data);
+    }
+}
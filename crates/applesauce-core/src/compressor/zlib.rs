@@ -1,11 +1,38 @@
 use crate::decmpfs::{BlockInfo, ZLIB_BLOCK_TABLE_START, ZLIB_TRAILER};
-use crate::try_read_all;
-use flate2::bufread::{ZlibDecoder, ZlibEncoder};
-use flate2::Compression;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::{io, mem};
 
-pub struct Zlib;
+/// A zlib compressor, which reuses its zlib streams (and their allocations) for every block
+///
+/// Each block is still compressed as an independent zlib stream: the streams are reset before
+/// every block, so no dictionary state is shared between blocks.
+pub struct Zlib {
+    /// The encoder, and the level it was created for
+    encoder: Option<(u32, Compress)>,
+    decoder: Decompress,
+}
+
+impl Zlib {
+    pub fn new() -> Self {
+        Self {
+            encoder: None,
+            decoder: Decompress::new(true),
+        }
+    }
+
+    /// The encoder for `level`, reset to the start of a new stream
+    fn encoder(&mut self, level: u32) -> &mut Compress {
+        // Changing the level of an existing stream isn't supported by every flate2 backend, but the
+        // level rarely changes, so just create a new encoder when it does
+        if !matches!(self.encoder, Some((encoder_level, _)) if encoder_level == level) {
+            self.encoder = Some((level, Compress::new(Compression::new(level), true)));
+        }
+        let (_, encoder) = self.encoder.as_mut().unwrap();
+        encoder.reset();
+        encoder
+    }
+}
 
 impl super::CompressorImpl for Zlib {
     fn header_size(block_count: u64) -> u64 {
@@ -19,16 +46,31 @@ impl super::CompressorImpl for Zlib {
     fn compress(&mut self, dst: &mut [u8], src: &[u8], level: u32) -> io::Result<usize> {
         assert!(dst.len() > src.len());
 
-        let encoder = ZlibEncoder::new(src, Compression::new(level));
-        let bytes_read = try_read_all(encoder, &mut dst[..src.len()])?;
-        if bytes_read == src.len() {
-            tracing::trace!("writing uncompressed data");
-            dst[0] = 0xff;
-            dst[1..][..src.len()].copy_from_slice(src);
-            return Ok(src.len() + 1);
+        // Compressed data is only useful if it's smaller than the block
+        let encoder = self.encoder(level);
+        let out = &mut dst[..src.len()];
+        loop {
+            let (in_pos, out_pos) = (encoder.total_in(), encoder.total_out());
+            let status = encoder.compress(
+                &src[in_pos as usize..],
+                &mut out[out_pos as usize..],
+                FlushCompress::Finish,
+            )?;
+            if status == Status::StreamEnd {
+                return Ok(encoder.total_out() as usize);
+            }
+            // Without progress, the output must be full
+            if (encoder.total_in(), encoder.total_out()) == (in_pos, out_pos)
+                || encoder.total_out() as usize == out.len()
+            {
+                break;
+            }
         }
 
-        Ok(bytes_read)
+        tracing::trace!("writing uncompressed data");
+        dst[0] = 0xff;
+        dst[1..][..src.len()].copy_from_slice(src);
+        Ok(src.len() + 1)
     }
 
     fn decompress(&mut self, dst: &mut [u8], src: &[u8]) -> io::Result<usize> {
@@ -43,20 +85,37 @@ impl super::CompressorImpl for Zlib {
             dst[..src.len()].copy_from_slice(src);
             return Ok(src.len());
         }
-        let decoder = ZlibDecoder::new(src);
-        let bytes_read = try_read_all(decoder, dst)?;
-        if bytes_read == dst.len() {
-            return Err(io::ErrorKind::WriteZero.into());
-        }
 
-        Ok(bytes_read)
+        let decoder = &mut self.decoder;
+        decoder.reset(true);
+        loop {
+            let (in_pos, out_pos) = (decoder.total_in(), decoder.total_out());
+            let status = decoder.decompress(
+                &src[in_pos as usize..],
+                &mut dst[out_pos as usize..],
+                FlushDecompress::Finish,
+            )?;
+            let len = decoder.total_out() as usize;
+            // A block which fills the buffer may have been truncated
+            if len == dst.len() {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            if status == Status::StreamEnd {
+                return Ok(len);
+            }
+            if (decoder.total_in(), decoder.total_out()) == (in_pos, out_pos) {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
     }
 
     fn read_block_info<R: Read + Seek>(
         mut reader: R,
         orig_file_size: u64,
+        block_size: u64,
     ) -> io::Result<Vec<BlockInfo>> {
-        let block_count = u32::try_from(crate::num_blocks(orig_file_size)).unwrap();
+        let block_count = u32::try_from(crate::num_blocks_with_size(orig_file_size, block_size))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many blocks"))?;
 
         let total_size = u32::try_from(reader.seek(SeekFrom::End(0))?).map_err(|_| {
             io::Error::new(
@@ -64,7 +123,17 @@ impl super::CompressorImpl for Zlib {
                 "resource fork exceeds u32 range",
             )
         })?;
-        let data_end = total_size - u32::try_from(ZLIB_TRAILER.len()).unwrap();
+        let data_end = total_size
+            .checked_sub(u32::try_from(ZLIB_TRAILER.len()).unwrap())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "resource fork too small"))?;
+        // The block count comes from the decmpfs xattr: ensure the block table fits in the
+        // resource fork before trusting it
+        if Self::header_size(block_count.into()) > u64::from(data_end) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block table does not fit in resource fork",
+            ));
+        }
 
         reader.rewind()?;
         let mut header_buf = [0; HEADER_LEN];
@@ -87,6 +156,7 @@ impl super::CompressorImpl for Zlib {
 
         let mut buf = [0; mem::size_of::<u32>()];
         reader.read_exact(&mut buf)?;
+        // data_end is at least the header size, checked above, so this can't underflow
         if buf != u32::to_be_bytes(data_end - 0x104) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -107,7 +177,22 @@ impl super::CompressorImpl for Zlib {
         for _ in 0..block_count {
             reader.read_exact(&mut buf)?;
             let mut block_info = BlockInfo::from_bytes(buf);
-            block_info.offset += ZLIB_BLOCK_TABLE_START as u32;
+            // Blocks must be within the data, so reading a block never reads (or allocates)
+            // more than the resource fork holds
+            block_info.offset = block_info
+                .offset
+                .checked_add(ZLIB_BLOCK_TABLE_START as u32)
+                .filter(|&offset| {
+                    offset
+                        .checked_add(block_info.compressed_size)
+                        .is_some_and(|end| end <= data_end)
+                })
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "block extends past the end of the data",
+                    )
+                })?;
             result.push(block_info);
         }
 
@@ -201,10 +286,125 @@ mod tests {
 
     #[test]
     fn round_trip() {
-        let mut compressor = Zlib;
+        let mut compressor = Zlib::new();
         compressor_round_trip(&mut compressor);
     }
 
+    fn text_block(skip: usize) -> Vec<u8> {
+        let plaintext = include_bytes!("zlib.rs");
+        plaintext
+            .iter()
+            .copied()
+            .cycle()
+            .skip(skip)
+            .take(BLOCK_SIZE)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_blocks() {
+        let mut compressor = Zlib::new();
+        let mut decompressor = Zlib::new();
+        let mut compressed = vec![0; BLOCK_SIZE + 1];
+        let mut decompressed = vec![0; BLOCK_SIZE + 1];
+        for (i, level) in [1, 6, 6, 9, 0, 6].into_iter().enumerate() {
+            let block = text_block(i * 1000);
+            let len = compressor.compress(&mut compressed, &block, level).unwrap();
+            if level != 0 {
+                assert!(len < block.len() / 2, "level {level}: {len}");
+            }
+
+            // The same instance, and a fresh one, can both decompress every block
+            for decompressor in [&mut decompressor, &mut Zlib::new()] {
+                let decompressed_len = decompressor
+                    .decompress(&mut decompressed, &compressed[..len])
+                    .unwrap();
+                assert_eq!(&decompressed[..decompressed_len], block);
+            }
+        }
+    }
+
+    #[test]
+    fn incompressible_stored_uncompressed() {
+        let mut block = vec![0; BLOCK_SIZE];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut block);
+
+        let mut compressor = Zlib::new();
+        let mut compressed = vec![0; BLOCK_SIZE + 1];
+        let len = compressor.compress(&mut compressed, &block, 6).unwrap();
+        assert_eq!(len, block.len() + 1);
+        assert_eq!(compressed[0], 0xff);
+        assert_eq!(&compressed[1..], block);
+
+        let mut decompressed = vec![0; BLOCK_SIZE + 1];
+        let decompressed_len = compressor
+            .decompress(&mut decompressed, &compressed[..len])
+            .unwrap();
+        assert_eq!(&decompressed[..decompressed_len], block);
+
+        // The encoder is still usable after the output filled up
+        let block = text_block(0);
+        let len = compressor.compress(&mut compressed, &block, 6).unwrap();
+        assert_ne!(compressed[0], 0xff);
+        let decompressed_len = compressor
+            .decompress(&mut decompressed, &compressed[..len])
+            .unwrap();
+        assert_eq!(&decompressed[..decompressed_len], block);
+
+        // Empty blocks can't be compressed smaller either
+        assert_eq!(compressor.compress(&mut compressed, &[], 6).unwrap(), 1);
+        assert_eq!(compressed[0], 0xff);
+    }
+
+    #[test]
+    fn blocks_independent() {
+        let first = text_block(0);
+        let second = text_block(1234);
+
+        let mut reused = Zlib::new();
+        let mut buf = vec![0; BLOCK_SIZE + 1];
+        reused.compress(&mut buf, &first, 6).unwrap();
+        let mut reused_second = vec![0; BLOCK_SIZE + 1];
+        let reused_len = reused.compress(&mut reused_second, &second, 6).unwrap();
+
+        // If the first block's data were still in the dictionary, the second block would
+        // compress differently
+        let mut fresh_second = vec![0; BLOCK_SIZE + 1];
+        let fresh_len = Zlib::new().compress(&mut fresh_second, &second, 6).unwrap();
+        assert_eq!(reused_second[..reused_len], fresh_second[..fresh_len]);
+
+        // Each block is a complete zlib stream on its own
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(&reused_second[..reused_len])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, second);
+    }
+
+    #[test]
+    fn truncated_block() {
+        let block = text_block(0);
+        let mut compressor = Zlib::new();
+        let mut compressed = vec![0; BLOCK_SIZE + 1];
+        let len = compressor.compress(&mut compressed, &block, 6).unwrap();
+
+        let mut decompressed = vec![0; BLOCK_SIZE + 1];
+        assert!(compressor
+            .decompress(&mut decompressed, &compressed[..len / 2])
+            .is_err());
+        // Too small an output buffer is an error, not a truncated block
+        let err = compressor
+            .decompress(&mut decompressed[..BLOCK_SIZE], &compressed[..len])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+
+        // And the decoder recovers for the next block
+        let decompressed_len = compressor
+            .decompress(&mut decompressed, &compressed[..len])
+            .unwrap();
+        assert_eq!(&decompressed[..decompressed_len], block);
+    }
+
     #[test]
     fn extra_size() {
         assert_eq!(Zlib::header_size(0) + Zlib::trailer_size(), 0x13A);
@@ -237,8 +437,12 @@ mod tests {
         );
 
         cursor.set_position(0);
-        let block_info =
-            Zlib::read_block_info(&mut cursor, (block_sizes.len() * BLOCK_SIZE) as u64).unwrap();
+        let block_info = Zlib::read_block_info(
+            &mut cursor,
+            (block_sizes.len() * BLOCK_SIZE) as u64,
+            BLOCK_SIZE as u64,
+        )
+        .unwrap();
         let expected_block_info: Vec<BlockInfo> = block_sizes
             .iter()
             .scan(blocks_start as u32, |acc, &size| {
@@ -0,0 +1,326 @@
+//! A stream container for compressed data, which needs no filesystem support
+//!
+//! This stores data compressed in blocks, like a compressed file's resource fork, but can be
+//! written and read as a stream (e.g. through a pipe) in constant memory, on any platform. The
+//! resource fork layout can't be streamed: its block table comes before the blocks, and its
+//! offsets are 32 bits. Instead, each block is preceded by its length, and the total size follows
+//! the last block, so the size doesn't need to be known before encoding.
+//!
+//! The format, with all integers little endian:
+//!
+//! | Field       | Size | Value                                              |
+//! |-------------|------|----------------------------------------------------|
+//! | magic       | 4    | [`MAGIC`]                                          |
+//! | version     | 1    | [`VERSION`]                                        |
+//! | kind        | 1    | 0 for zlib, 1 for lzvn, 2 for lzfse                |
+//! | reserved    | 2    | 0                                                  |
+//! | block size  | 4    | the uncompressed size of every block but the last  |
+//!
+//! Followed by each block, as a 4 byte length and the compressed block, then a 4 byte zero
+//! length, and the 8 byte total uncompressed size.
+
+use crate::compressor::{Kind, Pool};
+use crate::{try_read_all, BLOCK_SIZE};
+use std::io::{self, Read, Write};
+
+/// The bytes which start every container
+pub const MAGIC: [u8; 4] = *b"ASZ\0";
+
+/// The version of the format written by [`encode`]
+pub const VERSION: u8 = 1;
+
+/// The size of the header before the first block
+const HEADER_SIZE: usize = 12;
+
+/// The largest block size [`decode`] accepts, so a corrupt header can't cause a huge allocation
+const MAX_BLOCK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Extra space for a compressed block which grew, which is far more than any kind needs
+const MAX_BLOCK_GROWTH: usize = 1024;
+
+/// Compress everything from `reader` into a container written to `writer`
+///
+/// The data is compressed in blocks of [`BLOCK_SIZE`], so only a few blocks are held in memory,
+/// however much data is read. `level` is only used by zlib. Returns the number of uncompressed
+/// bytes read.
+///
+/// Returns an [`Unsupported`](io::ErrorKind::Unsupported) error if `kind` was not compiled in.
+pub fn encode<R: Read, W: Write>(
+    kind: Kind,
+    level: u32,
+    mut reader: R,
+    mut writer: W,
+) -> io::Result<u64> {
+    if !kind.supported() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{kind} compression is not supported"),
+        ));
+    }
+
+    let mut header = [0; HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4] = VERSION;
+    header[5] = kind as u8;
+    header[8..].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    writer.write_all(&header)?;
+
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut compressed_block = vec![0; BLOCK_SIZE + MAX_BLOCK_GROWTH];
+    let mut total: u64 = 0;
+    loop {
+        let block_len = try_read_all(&mut reader, &mut block)?;
+        if block_len == 0 {
+            break;
+        }
+        let len = Pool::with(kind, |compressor| {
+            compressor.compress(&mut compressed_block, &block[..block_len], level)
+        })?;
+        writer.write_all(&u32::try_from(len).unwrap().to_le_bytes())?;
+        writer.write_all(&compressed_block[..len])?;
+        total += block_len as u64;
+        if block_len < BLOCK_SIZE {
+            break;
+        }
+    }
+
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&total.to_le_bytes())?;
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Decompress a container from `reader`, writing the original data to `writer`
+///
+/// Only a few blocks are held in memory, however large the data is. Returns the number of
+/// uncompressed bytes written. Data after the end of the container is not read.
+///
+/// Returns an [`InvalidData`](io::ErrorKind::InvalidData) error if the container is invalid or
+/// corrupt, and an [`Unsupported`](io::ErrorKind::Unsupported) error if it was compressed with a
+/// kind which was not compiled in. Data may already have been written when an error is returned.
+pub fn decode<R: Read, W: Write>(mut reader: R, mut writer: W) -> io::Result<u64> {
+    let mut header = [0; HEADER_SIZE];
+    read_exact(&mut reader, &mut header)?;
+    if header[..4] != MAGIC {
+        return Err(invalid_data("not an applesauce container"));
+    }
+    if header[4] != VERSION {
+        return Err(invalid_data(format!(
+            "unknown container version {}",
+            header[4]
+        )));
+    }
+    let kind = *Kind::all()
+        .iter()
+        .find(|&&kind| kind as u8 == header[5])
+        .ok_or_else(|| invalid_data(format!("unknown compression kind {}", header[5])))?;
+    let block_size = u32::from_le_bytes(header[8..].try_into().unwrap());
+    if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+        return Err(invalid_data(format!("invalid block size {block_size}")));
+    }
+    let block_size = block_size as usize;
+
+    let mut compressed_block = vec![0; block_size + MAX_BLOCK_GROWTH];
+    // One extra byte: decompressing fails if the output fills the buffer
+    let mut block = vec![0; block_size + 1];
+    let mut total: u64 = 0;
+    let mut saw_partial_block = false;
+    loop {
+        let mut len = [0; 4];
+        read_exact(&mut reader, &mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            break;
+        }
+        if len > compressed_block.len() {
+            return Err(invalid_data(format!("compressed block too large: {len}")));
+        }
+        if saw_partial_block {
+            return Err(invalid_data("only the last block may be partial"));
+        }
+        let compressed_block = &mut compressed_block[..len];
+        read_exact(&mut reader, compressed_block)?;
+        let block_len = Pool::with(kind, |compressor| {
+            compressor.decompress(&mut block, compressed_block)
+        })
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::Unsupported => e,
+            _ => invalid_data(format!("unable to decompress block: {e}")),
+        })?;
+        if block_len > block_size {
+            return Err(invalid_data("block larger than the block size"));
+        }
+        saw_partial_block = block_len < block_size;
+        writer.write_all(&block[..block_len])?;
+        total += block_len as u64;
+    }
+
+    let mut expected_total = [0; 8];
+    read_exact(&mut reader, &mut expected_total)?;
+    let expected_total = u64::from_le_bytes(expected_total);
+    if total != expected_total {
+        return Err(invalid_data(format!(
+            "container should hold {expected_total} bytes, but holds {total}"
+        )));
+    }
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Like [`Read::read_exact`], but describing a container which ends early
+fn read_exact<R: Read>(reader: R, buf: &mut [u8]) -> io::Result<()> {
+    if try_read_all(reader, buf)? != buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "container ended early",
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    const PLAINTEXT: &[u8] = include_bytes!("container.rs");
+
+    fn supported_kinds() -> impl Iterator<Item = Kind> {
+        Kind::all().iter().copied().filter(|kind| kind.supported())
+    }
+
+    fn round_trip(kind: Kind, data: &[u8]) -> Vec<u8> {
+        let mut container = Vec::new();
+        assert_eq!(
+            encode(kind, 5, data, &mut container).unwrap(),
+            data.len() as u64
+        );
+        let mut decoded = Vec::new();
+        assert_eq!(
+            decode(container.as_slice(), &mut decoded).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(decoded, data, "{kind}");
+        container
+    }
+
+    #[test]
+    fn empty() {
+        for kind in supported_kinds() {
+            let container = round_trip(kind, &[]);
+            // Just the header, the end marker, and the size
+            assert_eq!(container.len(), HEADER_SIZE + 4 + 8);
+        }
+    }
+
+    #[test]
+    fn exactly_one_block() {
+        let data: Vec<u8> = PLAINTEXT.iter().copied().cycle().take(BLOCK_SIZE).collect();
+        for kind in supported_kinds() {
+            let container = round_trip(kind, &data);
+            assert!(container.len() < data.len() / 2, "{kind}");
+        }
+    }
+
+    #[test]
+    fn partial_blocks_and_random_data() {
+        let mut random = vec![0; 3 * BLOCK_SIZE + 17];
+        rand::thread_rng().fill_bytes(&mut random);
+        for kind in supported_kinds() {
+            round_trip(kind, &random);
+            round_trip(kind, &PLAINTEXT[..100]);
+            round_trip(kind, &random[..BLOCK_SIZE + 1]);
+        }
+    }
+
+    #[test]
+    fn trailing_data_not_read() {
+        let kind = Kind::default();
+        let mut container = Vec::new();
+        encode(kind, 5, PLAINTEXT, &mut container).unwrap();
+        let len = container.len();
+        container.extend_from_slice(b"trailing");
+
+        let mut reader = container.as_slice();
+        let mut decoded = Vec::new();
+        decode(&mut reader, &mut decoded).unwrap();
+        assert_eq!(decoded, PLAINTEXT);
+        assert_eq!(reader, b"trailing");
+        assert_eq!(container.len() - reader.len(), len);
+    }
+
+    #[test]
+    fn invalid_containers() {
+        let kind = Kind::default();
+        let mut container = Vec::new();
+        encode(kind, 5, PLAINTEXT, &mut container).unwrap();
+
+        let mut bad_magic = container.clone();
+        bad_magic[0] = b'X';
+        let mut bad_version = container.clone();
+        bad_version[4] = VERSION + 1;
+        let mut bad_kind = container.clone();
+        bad_kind[5] = 200;
+        let mut bad_total = container.clone();
+        let last = bad_total.len() - 1;
+        bad_total[last] ^= 1;
+        let mut corrupt_block = container.clone();
+        corrupt_block[HEADER_SIZE + 4] ^= 0xFF;
+        corrupt_block[HEADER_SIZE + 5] ^= 0xFF;
+
+        for (name, bad) in [
+            ("magic", bad_magic),
+            ("version", bad_version),
+            ("kind", bad_kind),
+            ("total", bad_total),
+            ("block", corrupt_block),
+        ] {
+            let err = decode(bad.as_slice(), io::sink()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{name}: {err}");
+        }
+
+        let err = decode(&container[..container.len() - 1], io::sink()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Checks every byte written is `byte`, without keeping them
+    struct ExpectBytes {
+        byte: u8,
+        len: u64,
+    }
+
+    impl Write for ExpectBytes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            assert!(buf.iter().all(|&b| b == self.byte));
+            self.len += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[ignore = "compresses and decompresses 5GB"]
+    fn multi_gigabyte_stream() {
+        // Larger than any resource fork could describe
+        let len = 5 * 1024 * 1024 * 1024 + 123;
+        let kind = Kind::default();
+        let mut container = Vec::new();
+        assert_eq!(
+            encode(kind, 5, io::repeat(7).take(len), &mut container).unwrap(),
+            len
+        );
+        // Repeated bytes compress to almost nothing, so the container can be kept in memory
+        assert!(container.len() < 64 * 1024 * 1024, "{}", container.len());
+
+        let mut output = ExpectBytes { byte: 7, len: 0 };
+        assert_eq!(decode(container.as_slice(), &mut output).unwrap(), len);
+        assert_eq!(output.len, len);
+    }
+}
@@ -129,6 +129,38 @@ impl From<DecodeError> for io::Error {
     }
 }
 
+/// An error constructing a decmpfs [`Value`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InvalidValue {
+    /// The data stored in the xattr is larger than [`MAX_XATTR_DATA_SIZE`]
+    DataTooLarge { len: usize },
+    /// Data was passed to be stored in the xattr, but the compressed data is in the resource fork
+    DataWithResourceFork { len: usize },
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidValue::DataTooLarge { len } => write!(
+                f,
+                "{len} bytes of data will not fit in a decmpfs xattr (max {MAX_XATTR_DATA_SIZE})"
+            ),
+            InvalidValue::DataWithResourceFork { len } => write!(
+                f,
+                "{len} bytes of data in decmpfs xattr for data stored in the resource fork"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+impl From<InvalidValue> for io::Error {
+    fn from(err: InvalidValue) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Value<'a> {
     pub compression_type: CompressionType,
@@ -138,6 +170,58 @@ pub struct Value<'a> {
 
 #[allow(clippy::len_without_is_empty)]
 impl<'a> Value<'a> {
+    /// Create a new value, ensuring `extra_data` is valid for the storage
+    ///
+    /// Data stored in the xattr must fit in the xattr, and there must be no data when the
+    /// compressed data is stored in the resource fork.
+    pub fn new(
+        kind: compressor::Kind,
+        storage: Storage,
+        uncompressed_size: u64,
+        extra_data: &'a [u8],
+    ) -> Result<Self, InvalidValue> {
+        Self::with_compression_type(
+            CompressionType::new(kind, storage),
+            storage,
+            uncompressed_size,
+            extra_data,
+        )
+    }
+
+    /// Create a new value with an arbitrary compression type, ensuring `extra_data` is valid for
+    /// the storage
+    ///
+    /// This is used for codecs which are not built in, see
+    /// [`Codec`](crate::compressor::Codec). `storage` must be where `compression_type` stores
+    /// its data.
+    pub fn with_compression_type(
+        compression_type: CompressionType,
+        storage: Storage,
+        uncompressed_size: u64,
+        extra_data: &'a [u8],
+    ) -> Result<Self, InvalidValue> {
+        let len = extra_data.len();
+        match storage {
+            Storage::Xattr if len > MAX_XATTR_DATA_SIZE => {
+                return Err(InvalidValue::DataTooLarge { len });
+            }
+            Storage::ResourceFork if len != 0 => {
+                return Err(InvalidValue::DataWithResourceFork { len });
+            }
+            _ => {}
+        }
+        Ok(Self {
+            compression_type,
+            uncompressed_size,
+            extra_data,
+        })
+    }
+
+    /// Parse the value of a decmpfs xattr
+    ///
+    /// The compression type is not checked: values with types applesauce doesn't know are
+    /// parsed, with [`Value::kind`] returning `None`. Parsing and then writing a value with
+    /// [`Value::write_to`] reproduces `data` exactly.
     pub fn from_data(data: &'a [u8]) -> Result<Self, DecodeError> {
         if data.len() < HEADER_LEN {
             return Err(DecodeError::TooSmall);
@@ -158,6 +242,7 @@ impl<'a> Value<'a> {
         })
     }
 
+    /// Write the value as stored in the decmpfs xattr: the header, then the extra data
     pub fn write_to<W: Write>(self, mut writer: W) -> io::Result<()> {
         writer.write_all(&self.header_bytes())?;
         writer.write_all(self.extra_data)?;
@@ -165,6 +250,15 @@ impl<'a> Value<'a> {
         Ok(())
     }
 
+    /// The value as stored in the decmpfs xattr
+    #[must_use]
+    pub fn to_vec(self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.len());
+        result.extend_from_slice(&self.header_bytes());
+        result.extend_from_slice(self.extra_data);
+        result
+    }
+
     fn header_bytes(self) -> [u8; HEADER_LEN] {
         let mut result = [0; HEADER_LEN];
 
@@ -184,6 +278,22 @@ impl<'a> Value<'a> {
     pub fn len(self) -> usize {
         HEADER_LEN + self.extra_data.len()
     }
+
+    /// The compressor kind, if the compression type is known
+    #[must_use]
+    pub fn kind(self) -> Option<compressor::Kind> {
+        self.compression_type
+            .compression_storage()
+            .map(|(kind, _)| kind)
+    }
+
+    /// Where the compressed data is stored, if the compression type is known
+    #[must_use]
+    pub fn storage(self) -> Option<Storage> {
+        self.compression_type
+            .compression_storage()
+            .map(|(_, storage)| storage)
+    }
 }
 
 #[rustfmt::skip]
@@ -239,3 +349,60 @@ impl BlockInfo {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::Kind;
+
+    #[test]
+    fn new_xattr() {
+        let data = [1; MAX_XATTR_DATA_SIZE];
+        let value = Value::new(Kind::Zlib, Storage::Xattr, 100, &data).unwrap();
+        assert_eq!(value.kind(), Some(Kind::Zlib));
+        assert_eq!(value.storage(), Some(Storage::Xattr));
+        assert_eq!(value.len(), MAX_XATTR_SIZE);
+
+        let mut written = Vec::new();
+        value.write_to(&mut written).unwrap();
+        assert_eq!(written, value.to_vec());
+        let read = Value::from_data(&written).unwrap();
+        assert_eq!(read.compression_type, value.compression_type);
+        assert_eq!(read.uncompressed_size, 100);
+        assert_eq!(read.extra_data, data);
+    }
+
+    #[test]
+    fn new_xattr_too_large() {
+        let data = [1; MAX_XATTR_DATA_SIZE + 1];
+        assert_eq!(
+            Value::new(Kind::Lzfse, Storage::Xattr, 100, &data).unwrap_err(),
+            InvalidValue::DataTooLarge {
+                len: MAX_XATTR_DATA_SIZE + 1
+            }
+        );
+    }
+
+    #[test]
+    fn new_resource_fork() {
+        let value = Value::new(Kind::Lzvn, Storage::ResourceFork, 100, &[]).unwrap();
+        assert_eq!(value.kind(), Some(Kind::Lzvn));
+        assert_eq!(value.storage(), Some(Storage::ResourceFork));
+
+        assert_eq!(
+            Value::new(Kind::Lzvn, Storage::ResourceFork, 100, &[1, 2, 3]).unwrap_err(),
+            InvalidValue::DataWithResourceFork { len: 3 }
+        );
+    }
+
+    #[test]
+    fn unknown_compression_type() {
+        let value = Value {
+            compression_type: CompressionType::from_raw_type(1),
+            uncompressed_size: 0,
+            extra_data: &[],
+        };
+        assert_eq!(value.kind(), None);
+        assert_eq!(value.storage(), None);
+    }
+}
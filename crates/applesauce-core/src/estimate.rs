@@ -0,0 +1,189 @@
+//! Estimating how well data will compress, without writing anything
+//!
+//! Samples are compressed in blocks of [`BLOCK_SIZE`], as a file would be, so a sample should be
+//! a representative part of a file (e.g. a few blocks from its start). The ratio only counts the
+//! compressed blocks: the resource fork's header and block table are not included, so ratios of
+//! small samples are optimistic.
+
+use crate::compressor::{Kind, Pool};
+use crate::BLOCK_SIZE;
+use std::io;
+
+/// The estimated ratio of each kind, from [`best_kind`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct KindEstimates {
+    /// The kind with the lowest ratio (the first of the best, if several are equal)
+    pub best: Kind,
+    /// The ratio of the best kind
+    pub best_ratio: f64,
+    /// Each kind and its ratio, in the order the kinds were passed
+    pub ratios: Vec<(Kind, f64)>,
+}
+
+/// Estimate the ratio of compressed size to uncompressed size of `sample` with `kind`
+///
+/// `level` is only used by zlib. Incompressible data has a ratio slightly above 1.0, since each
+/// block stored uncompressed is marked as such. An empty sample has a ratio of 1.0.
+///
+/// Returns an [`Unsupported`](io::ErrorKind::Unsupported) error if `kind` was not compiled in.
+pub fn estimate_ratio(kind: Kind, sample: &[u8], level: u32) -> io::Result<f64> {
+    let mut buf = compress_buf();
+    let compressed = compressed_len(kind, sample, level, &mut buf)?;
+    Ok(ratio(compressed, sample.len() as u64))
+}
+
+/// Estimate the ratio of every kind in `kinds` over all of `samples`, and find the best
+///
+/// Each kind's ratio is of the total compressed size of all samples to their total size, so
+/// larger samples count for more.
+///
+/// Returns an [`InvalidInput`](io::ErrorKind::InvalidInput) error if `kinds` is empty, and an
+/// [`Unsupported`](io::ErrorKind::Unsupported) error if any of `kinds` was not compiled in.
+pub fn best_kind(samples: &[&[u8]], kinds: &[Kind], level: u32) -> io::Result<KindEstimates> {
+    let mut buf = compress_buf();
+    let total_len: u64 = samples.iter().map(|sample| sample.len() as u64).sum();
+    let ratios = kinds
+        .iter()
+        .map(|&kind| {
+            let mut compressed = 0;
+            for sample in samples {
+                compressed += compressed_len(kind, sample, level, &mut buf)?;
+            }
+            Ok((kind, ratio(compressed, total_len)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let &(best, best_ratio) = ratios
+        .iter()
+        // Only replace the best with a strictly better ratio, so ties go to the earlier kind
+        .reduce(|best, next| if next.1 < best.1 { next } else { best })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no kinds to estimate"))?;
+    Ok(KindEstimates {
+        best,
+        best_ratio,
+        ratios,
+    })
+}
+
+/// A buffer large enough to hold any compressed block
+fn compress_buf() -> Vec<u8> {
+    vec![0; BLOCK_SIZE + 1024]
+}
+
+/// The total compressed size of each block of `sample`
+fn compressed_len(kind: Kind, sample: &[u8], level: u32, buf: &mut [u8]) -> io::Result<u64> {
+    Pool::with(kind, |compressor| {
+        let mut total = 0;
+        for block in sample.chunks(BLOCK_SIZE) {
+            total += compressor.compress(buf, block, level)? as u64;
+        }
+        Ok(total)
+    })
+}
+
+fn ratio(compressed: u64, uncompressed: u64) -> f64 {
+    if uncompressed == 0 {
+        return 1.0;
+    }
+    compressed as f64 / uncompressed as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    const PLAINTEXT: &[u8] = include_bytes!("estimate.rs");
+
+    fn supported_kinds() -> Vec<Kind> {
+        Kind::all()
+            .iter()
+            .copied()
+            .filter(|kind| kind.supported())
+            .collect()
+    }
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    fn compressible_data(len: usize) -> Vec<u8> {
+        PLAINTEXT.iter().copied().cycle().take(len).collect()
+    }
+
+    #[test]
+    fn compressible_and_random() {
+        // More than one block, and a partial last block
+        let len = 2 * BLOCK_SIZE + 100;
+        let compressible = compressible_data(len);
+        let random = random_data(len);
+        for kind in supported_kinds() {
+            let compressible_ratio = estimate_ratio(kind, &compressible, 5).unwrap();
+            assert!(compressible_ratio < 0.5, "{kind}: {compressible_ratio}");
+
+            let random_ratio = estimate_ratio(kind, &random, 5).unwrap();
+            let max_ratio = (len as u64 + 3 * kind.max_block_overhead()) as f64 / len as f64;
+            assert!(random_ratio > 0.99, "{kind}: {random_ratio}");
+            assert!(random_ratio <= max_ratio, "{kind}: {random_ratio}");
+        }
+    }
+
+    #[test]
+    fn empty_sample() {
+        for kind in supported_kinds() {
+            assert_eq!(estimate_ratio(kind, &[], 5).unwrap(), 1.0);
+        }
+        let estimates = best_kind(&[], &supported_kinds(), 5).unwrap();
+        assert!(estimates.ratios.iter().all(|&(_, ratio)| ratio == 1.0));
+    }
+
+    #[test]
+    fn best_kind_of_samples() {
+        let kinds = supported_kinds();
+        let compressible = compressible_data(BLOCK_SIZE);
+        let random = random_data(BLOCK_SIZE);
+        let samples: [&[u8]; 2] = [&compressible, &random];
+        let estimates = best_kind(&samples, &kinds, 9).unwrap();
+
+        assert_eq!(
+            estimates
+                .ratios
+                .iter()
+                .map(|&(kind, _)| kind)
+                .collect::<Vec<_>>(),
+            kinds
+        );
+        for &(kind, ratio) in &estimates.ratios {
+            // Half the data is random, the other half compresses
+            assert!(ratio > 0.5 && ratio < 0.75, "{kind}: {ratio}");
+            assert!(estimates.best_ratio <= ratio);
+        }
+        assert!(estimates
+            .ratios
+            .contains(&(estimates.best, estimates.best_ratio)));
+    }
+
+    #[test]
+    fn best_kind_ties_first() {
+        let kinds = supported_kinds();
+        let estimates = best_kind(&[], &kinds, 5).unwrap();
+        assert_eq!(estimates.best, kinds[0]);
+    }
+
+    #[test]
+    fn best_kind_no_kinds() {
+        let err = best_kind(&[b"data".as_slice()], &[], 5).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn unsupported_kind() {
+        for &kind in Kind::all() {
+            if !kind.supported() {
+                let err = estimate_ratio(kind, b"data", 5).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            }
+        }
+    }
+}
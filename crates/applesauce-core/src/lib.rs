@@ -1,18 +1,53 @@
+use crate::compressor::{Kind, Pool};
 use std::io;
-use std::io::Read;
+use std::io::{Cursor, Read};
 
+pub mod check;
 pub mod compressor;
+pub mod container;
 pub mod decmpfs;
+pub mod estimate;
 pub mod reader;
 pub mod writer;
 
+/// The size of each block of uncompressed data, as macOS expects for decmpfs compressed files
+///
+/// This is the default for [`writer::Writer`] and [`reader::Reader`], other sizes can be used
+/// with [`writer::Writer::with_block_size`] and [`reader::Reader::with_block_size`], but files
+/// compressed with them can't be read by the OS.
 pub const BLOCK_SIZE: usize = 0x10000;
 
+/// The size limit for compressible files: only files smaller than this can be compressed
+///
+/// Offsets and sizes in the resource fork (and the resource fork itself) are 32 bits, so the
+/// compressed data, which may be slightly larger than the original for incompressible data, must
+/// fit in a `u32`.
+pub const MAX_COMPRESSIBLE_SIZE: u64 = u32::MAX as u64;
+
+// The resource fork format cannot describe anything larger
+const _: () = assert!(MAX_COMPRESSIBLE_SIZE <= u32::MAX as u64);
+
+/// Returns true if a file of `size` bytes is too large to be compressed
+///
+/// See [`MAX_COMPRESSIBLE_SIZE`].
+#[must_use]
+#[inline]
+pub const fn is_too_large(size: u64) -> bool {
+    size >= MAX_COMPRESSIBLE_SIZE
+}
+
 /// Returns the number of blocks needed to store `size` bytes.
 #[must_use]
 #[inline]
 pub const fn num_blocks(size: u64) -> u64 {
-    size.div_ceil(BLOCK_SIZE as u64)
+    num_blocks_with_size(size, BLOCK_SIZE as u64)
+}
+
+/// Returns the number of blocks of `block_size` bytes needed to store `size` bytes.
+#[must_use]
+#[inline]
+pub const fn num_blocks_with_size(size: u64, block_size: u64) -> u64 {
+    size.div_ceil(block_size)
 }
 
 /// Rounds `size` up to the nearest multiple of `block_size`.
@@ -27,6 +62,81 @@ pub const fn round_to_block_size(size: u64, block_size: u64) -> u64 {
     }
 }
 
+/// The result of compressing data in memory with [`compress_stream`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedArtifacts {
+    /// The value of the decmpfs xattr ([`decmpfs::XATTR_NAME`])
+    pub decmpfs_xattr: Vec<u8>,
+    /// The contents of the resource fork, if the compressed data does not fit in the decmpfs xattr
+    pub resource_fork: Option<Vec<u8>>,
+}
+
+/// Compress `uncompressed_size` bytes from `reader` in memory
+///
+/// This produces the decmpfs xattr and resource fork contents which would be stored on a
+/// compressed file, without touching the filesystem.
+///
+/// Returns an error if `reader` does not contain exactly `uncompressed_size` bytes, or if `kind`
+/// is not supported.
+pub fn compress_stream<R: Read>(
+    kind: Kind,
+    level: u32,
+    uncompressed_size: u64,
+    mut reader: R,
+) -> io::Result<CompressedArtifacts> {
+    if !kind.supported() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported compression kind {kind}"),
+        ));
+    }
+
+    let mut resource_fork = Vec::new();
+    let mut decmpfs_xattr = Vec::new();
+    {
+        let rfork_ref = &mut resource_fork;
+        let mut writer =
+            writer::Writer::new(kind, uncompressed_size, move || Cursor::new(rfork_ref))?;
+
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut compressed_block = vec![0; BLOCK_SIZE + 1024];
+        let mut remaining = uncompressed_size;
+        while remaining > 0 {
+            let block_len = usize::try_from(remaining.min(BLOCK_SIZE as u64)).unwrap();
+            let block = &mut block[..block_len];
+            if try_read_all(&mut reader, block)? != block_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "reader ended before uncompressed_size bytes",
+                ));
+            }
+            let len = Pool::with(kind, |compressor| {
+                compressor.compress(&mut compressed_block, block, level)
+            })?;
+            writer.add_block(&compressed_block[..len])?;
+            remaining -= block_len as u64;
+        }
+        if try_read_all(&mut reader, &mut [0])? != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reader contains more than uncompressed_size bytes",
+            ));
+        }
+
+        writer.finish_decmpfs_data(&mut decmpfs_xattr)?;
+    }
+
+    let value = decmpfs::Value::from_data(&decmpfs_xattr)?;
+    let resource_fork = match value.compression_type.compression_storage() {
+        Some((_, decmpfs::Storage::ResourceFork)) => Some(resource_fork),
+        _ => None,
+    };
+    Ok(CompressedArtifacts {
+        decmpfs_xattr,
+        resource_fork,
+    })
+}
+
 /// Try to read `buf.len()` bytes from `r`, returning the number of bytes read.
 ///
 /// This function will only return partial reads if EOF is reached before
@@ -59,3 +169,16 @@ fn try_read_all<R: Read>(mut r: R, buf: &mut [u8]) -> io::Result<usize> {
     bulk_read_span.record("read_len", read_len);
     Ok(read_len)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_large_boundary() {
+        assert!(!is_too_large(0));
+        assert!(!is_too_large(MAX_COMPRESSIBLE_SIZE - 1));
+        assert!(is_too_large(MAX_COMPRESSIBLE_SIZE));
+        assert!(is_too_large(MAX_COMPRESSIBLE_SIZE + 1));
+    }
+}
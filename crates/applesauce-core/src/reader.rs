@@ -1,6 +1,7 @@
 use crate::decmpfs::{BlockInfo, Storage};
 use crate::{compressor, decmpfs};
-use std::io::{self, BufReader, Cursor, Read, Seek};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::iter::FusedIterator;
 
 pub trait Open {
     type ResourceFork: Read + Seek;
@@ -23,19 +24,41 @@ enum State<R> {
     ResourceFork {
         // Stored in reverse order, so that we can pop() them off
         block_infos: Vec<BlockInfo>,
-        last_offset: u32,
-        reader: BufReader<R>,
+        reader: R,
     },
 }
 
 #[derive(Debug)]
 pub struct Reader<R> {
-    kind: compressor::Kind,
+    format: compressor::Format,
+    compression_type: decmpfs::CompressionType,
+    uncompressed_size: u64,
     state: State<R>,
 }
 
+/// An iterator over the compressed blocks of a [`Reader`], returned by [`Reader::into_blocks`]
+///
+/// Each block is read into a newly allocated `Vec`. Use [`Reader::read_block_into`] to reuse a
+/// buffer instead.
+#[derive(Debug)]
+pub struct Blocks<R> {
+    reader: Reader<R>,
+}
+
 impl<R: Read + Seek> Reader<R> {
+    /// Create a reader for data compressed in blocks of [`BLOCK_SIZE`](crate::BLOCK_SIZE)
     pub fn new<O>(decmpfs_data: &[u8], open: O) -> io::Result<Self>
+    where
+        O: Open<ResourceFork = R>,
+    {
+        Self::with_block_size(decmpfs_data, crate::BLOCK_SIZE as u64, open)
+    }
+
+    /// Create a reader for data compressed in blocks of `block_size` bytes
+    ///
+    /// `block_size` must match the block size the data was written with: it determines how many
+    /// blocks the block table is expected to have.
+    pub fn with_block_size<O>(decmpfs_data: &[u8], block_size: u64, open: O) -> io::Result<Self>
     where
         O: Open<ResourceFork = R>,
     {
@@ -44,68 +67,137 @@ impl<R: Read + Seek> Reader<R> {
             .compression_type
             .compression_storage()
             .filter(|(kind, _)| kind.supported())
-            .ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    "unsupported compression kind or storage",
-                )
-            })?;
+            .ok_or_else(unsupported_type)?;
+        Self::with_format(kind.into(), storage, decmpfs_value, block_size, open)
+    }
+
+    /// Create a reader for data compressed by an external `codec`, in blocks of `block_size`
+    ///
+    /// The compression type in the decmpfs data must be one returned by
+    /// [`Codec::compression_type`](compressor::Codec::compression_type).
+    pub fn with_codec<O>(
+        decmpfs_data: &[u8],
+        block_size: u64,
+        codec: Box<dyn compressor::Codec>,
+        open: O,
+    ) -> io::Result<Self>
+    where
+        O: Open<ResourceFork = R>,
+    {
+        let decmpfs_value = decmpfs::Value::from_data(decmpfs_data)?;
+        let storage = codec
+            .storage_for(decmpfs_value.compression_type)
+            .ok_or_else(unsupported_type)?;
+        Self::with_format(codec.into(), storage, decmpfs_value, block_size, open)
+    }
+
+    fn with_format<O>(
+        format: compressor::Format,
+        storage: Storage,
+        decmpfs_value: decmpfs::Value<'_>,
+        block_size: u64,
+        open: O,
+    ) -> io::Result<Self>
+    where
+        O: Open<ResourceFork = R>,
+    {
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size must not be zero",
+            ));
+        }
         let state = match storage {
             Storage::Xattr => State::Xattr(Cursor::new(decmpfs_value.extra_data.to_vec())),
             Storage::ResourceFork => {
+                // The block table is parsed with many small reads, so buffer them
                 let mut rfork = BufReader::new(open.open_resource_fork()?);
-                let mut blocks_info =
-                    kind.read_block_info(&mut rfork, decmpfs_value.uncompressed_size)?;
+                let mut blocks_info = format.read_block_info(
+                    &mut rfork,
+                    decmpfs_value.uncompressed_size,
+                    block_size,
+                )?;
 
-                // Seek back to the beginning of the resource fork
-                rfork.rewind()?;
                 // Reverse the block infos so that we can pop() them off
                 blocks_info.reverse();
 
                 State::ResourceFork {
                     block_infos: blocks_info,
-                    last_offset: 0,
-                    reader: rfork,
+                    // Blocks are read whole, with a seek to their offset then a single read, so
+                    // don't buffer them. Seeking a resource fork only sets the position, so each
+                    // block is one positional read.
+                    reader: rfork.into_inner(),
                 }
             }
         };
-        Ok(Self { kind, state })
+        Ok(Self {
+            format,
+            compression_type: decmpfs_value.compression_type,
+            uncompressed_size: decmpfs_value.uncompressed_size,
+            state,
+        })
     }
 
+    /// Append the next compressed block to `dst`
+    ///
+    /// Returns false, leaving `dst` unchanged, once every block has been read.
     pub fn read_block_into(&mut self, dst: &mut Vec<u8>) -> io::Result<bool> {
         match &mut self.state {
             State::Xattr(cursor) => cursor.read_to_end(dst).map(|bytes_read| bytes_read > 0),
             State::ResourceFork {
                 block_infos,
-                last_offset,
                 reader,
             } => {
                 let block = match block_infos.pop() {
                     Some(block) => block,
                     None => return Ok(false),
                 };
-                let diff = i64::from(block.offset) - i64::from(*last_offset);
-                reader.seek_relative(diff)?;
-
-                let bytes_read = reader
-                    .by_ref()
-                    .take(block.compressed_size.into())
-                    .read_to_end(dst)?;
-                if bytes_read < block.compressed_size as usize {
-                    return Err(io::ErrorKind::UnexpectedEof.into());
-                }
-                *last_offset = block
+                block
                     .offset
                     .checked_add(block.compressed_size)
                     .ok_or(io::ErrorKind::InvalidData)?;
+                reader.seek(SeekFrom::Start(block.offset.into()))?;
+
+                let start = dst.len();
+                dst.resize(start + block.compressed_size as usize, 0);
+                if let Err(e) = reader.read_exact(&mut dst[start..]) {
+                    dst.truncate(start);
+                    return Err(e);
+                }
                 Ok(true)
             }
         }
     }
 
+    /// Iterate over the remaining compressed blocks
+    #[inline]
+    pub fn into_blocks(self) -> Blocks<R> {
+        Blocks { reader: self }
+    }
+
+    /// The built-in compression kind, or `None` if the reader was created with
+    /// [`Self::with_codec`]
     #[inline]
-    pub fn compression_kind(&self) -> compressor::Kind {
-        self.kind
+    pub fn compression_kind(&self) -> Option<compressor::Kind> {
+        self.format.kind()
+    }
+
+    /// The format of the compressed data
+    #[inline]
+    pub fn format(&self) -> &compressor::Format {
+        &self.format
+    }
+
+    /// The compression type from the decmpfs data
+    #[inline]
+    pub fn compression_type(&self) -> decmpfs::CompressionType {
+        self.compression_type
+    }
+
+    /// The size of the data once decompressed, from the decmpfs data
+    #[inline]
+    pub fn uncompressed_size(&self) -> u64 {
+        self.uncompressed_size
     }
 
     #[inline]
@@ -119,3 +211,41 @@ impl<R: Read + Seek> Reader<R> {
         }
     }
 }
+
+fn unsupported_type() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "unsupported compression kind or storage",
+    )
+}
+
+impl<R: Read + Seek> Blocks<R> {
+    /// The reader the blocks are read from
+    #[inline]
+    pub fn reader(&self) -> &Reader<R> {
+        &self.reader
+    }
+}
+
+impl<R: Read + Seek> Iterator for Blocks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut block = Vec::new();
+        match self.reader.read_block_into(&mut block) {
+            Ok(true) => Some(Ok(block)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.reader.remaining_blocks();
+        (remaining, Some(remaining))
+    }
+}
+
+impl<R: Read + Seek> ExactSizeIterator for Blocks<R> {}
+
+impl<R: Read + Seek> FusedIterator for Blocks<R> {}
@@ -1,6 +1,5 @@
-use crate::decmpfs::CompressionType;
 use crate::{compressor, decmpfs};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::{io, mem};
 
 pub trait Open {
@@ -27,26 +26,153 @@ enum WriterState<O: Open> {
         block: Vec<u8>,
     },
     MultipleBlocks {
-        block_sizes: Vec<u32>,
+        blocks: Blocks,
         resource_fork: O::ResourceFork,
     },
+    /// A previous call failed, the resource fork may be incomplete
+    Failed,
 }
 
+/// Where each block has been written in the resource fork
+///
+/// Blocks are always appended to the end of the resource fork in the order they arrive, so
+/// the resource fork is never sparse. If blocks arrive in order, this is also their final
+/// position. Otherwise, the written blocks are compacted into index order once the last block
+/// arrives: see [`Writer::add_block_at`].
+struct Blocks {
+    slots: Vec<Option<Slot>>,
+    received: usize,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    offset: u64,
+    size: u32,
+}
+
+impl Blocks {
+    fn new(block_count: u64, start: u64) -> Self {
+        Self {
+            slots: vec![None; block_count.try_into().unwrap()],
+            received: 0,
+            start,
+            end: start,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.slots.len()
+    }
+
+    /// Record that a block will be written at the current end, and return its offset
+    fn push(&mut self, index: u64, size: u32) -> io::Result<u64> {
+        let slot = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.slots.get_mut(index))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many blocks"))?;
+        if slot.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block written more than once",
+            ));
+        }
+        let offset = self.end;
+        *slot = Some(Slot { offset, size });
+        self.received += 1;
+        self.end += u64::from(size);
+        Ok(offset)
+    }
+
+    /// The first block (and its final offset) which is not at its final position
+    fn first_misplaced(&self) -> Option<(usize, u64)> {
+        let mut expected_offset = self.start;
+        for (i, slot) in self.slots.iter().enumerate() {
+            let slot = slot.as_ref()?;
+            if slot.offset != expected_offset {
+                return Some((i, expected_offset));
+            }
+            expected_offset += u64::from(slot.size);
+        }
+        None
+    }
+
+    fn sizes(&self) -> Option<Vec<u32>> {
+        self.slots
+            .iter()
+            .map(|slot| slot.map(|slot| slot.size))
+            .collect()
+    }
+}
+
+/// Writes compressed blocks, and produces the decmpfs xattr for them
+///
+/// If any method returns an error, the writer is poisoned: every later call returns an error,
+/// rather than writing a block table or decmpfs xattr describing an incomplete resource fork.
+/// Anything already written to the resource fork may be partial, so the caller must discard
+/// the destination.
 pub struct Writer<O: Open> {
-    kind: compressor::Kind,
+    format: compressor::Format,
     uncompressed_size: u64,
+    block_size: u64,
     state: WriterState<O>,
 }
 
 impl<O: Open> Writer<O> {
+    /// Create a writer for `uncompressed_size` bytes, in blocks of [`BLOCK_SIZE`](crate::BLOCK_SIZE)
     pub fn new(kind: compressor::Kind, uncompressed_size: u64, open: O) -> io::Result<Self> {
-        let block_count = crate::num_blocks(uncompressed_size);
+        Self::with_block_size(kind, uncompressed_size, crate::BLOCK_SIZE as u64, open)
+    }
+
+    /// Create a writer for `uncompressed_size` bytes, in blocks of `block_size` bytes
+    ///
+    /// Each block added must be the compressed form of `block_size` bytes (except the last,
+    /// which may be shorter). macOS only reads files compressed with
+    /// [`BLOCK_SIZE`](crate::BLOCK_SIZE) blocks, so other sizes are only useful when the output is
+    /// read back with [`Reader::with_block_size`](crate::reader::Reader::with_block_size).
+    pub fn with_block_size(
+        kind: compressor::Kind,
+        uncompressed_size: u64,
+        block_size: u64,
+        open: O,
+    ) -> io::Result<Self> {
+        Self::with_format(kind.into(), uncompressed_size, block_size, open)
+    }
+
+    /// Create a writer for `uncompressed_size` bytes in blocks of `block_size` bytes, laid out by
+    /// an external `codec`
+    ///
+    /// Blocks must be compressed by the same codec. The decmpfs xattr will use the compression
+    /// types returned by [`Codec::compression_type`](compressor::Codec::compression_type).
+    pub fn with_codec(
+        codec: Box<dyn compressor::Codec>,
+        uncompressed_size: u64,
+        block_size: u64,
+        open: O,
+    ) -> io::Result<Self> {
+        Self::with_format(codec.into(), uncompressed_size, block_size, open)
+    }
+
+    fn with_format(
+        format: compressor::Format,
+        uncompressed_size: u64,
+        block_size: u64,
+        open: O,
+    ) -> io::Result<Self> {
+        if block_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block size must not be zero",
+            ));
+        }
+        let block_count = crate::num_blocks_with_size(uncompressed_size, block_size);
         let state = if block_count > 1 {
             let mut resource_fork = open.open_resource_fork()?;
-            resource_fork.seek(SeekFrom::Start(kind.header_size(block_count)))?;
+            resource_fork.seek(SeekFrom::Start(format.header_size(block_count)))?;
 
             WriterState::MultipleBlocks {
-                block_sizes: Vec::with_capacity(block_count.try_into().unwrap()),
+                blocks: Blocks::new(block_count, format.header_size(block_count)),
                 resource_fork,
             }
         } else {
@@ -56,19 +182,50 @@ impl<O: Open> Writer<O> {
             }
         };
         Ok(Self {
-            kind,
+            format,
             uncompressed_size,
+            block_size,
             state,
         })
     }
 
+    /// Add the next block, in order
+    ///
+    /// Blocks added with this function are written directly to their final position. This is
+    /// equivalent to calling [`Self::add_block_at`] with the index of the next block.
     pub fn add_block(&mut self, new_block: &[u8]) -> io::Result<()> {
-        assert!(new_block.len() as u64 <= u32::MAX as u64);
+        let index = match &self.state {
+            WriterState::SingleBlock { block, .. } => u64::from(!block.is_empty()),
+            WriterState::MultipleBlocks { blocks, .. } => blocks.received as u64,
+            WriterState::Failed => return Err(poisoned()),
+            WriterState::Empty => unreachable!(),
+        };
+        self.write_block(index, new_block)
+    }
+
+    fn write_block(&mut self, index: u64, new_block: &[u8]) -> io::Result<()> {
+        self.poison_on_error(|this| this.try_write_block(index, new_block))
+    }
+
+    /// Run `f`, moving to the failed state if it fails
+    fn poison_on_error(&mut self, f: impl FnOnce(&mut Self) -> io::Result<()>) -> io::Result<()> {
+        if matches!(self.state, WriterState::Failed) {
+            return Err(poisoned());
+        }
+        let result = f(self);
+        if result.is_err() {
+            self.state = WriterState::Failed;
+        }
+        result
+    }
+
+    fn try_write_block(&mut self, index: u64, new_block: &[u8]) -> io::Result<()> {
+        let new_block_len = u32::try_from(new_block.len()).unwrap();
 
         match &mut self.state {
             WriterState::SingleBlock { block, .. } => {
                 assert!(
-                    block.is_empty(),
+                    index == 0 && block.is_empty(),
                     "adding multiple blocks to a single-block writer"
                 );
                 if new_block.len() > decmpfs::MAX_XATTR_DATA_SIZE {
@@ -78,19 +235,14 @@ impl<O: Open> Writer<O> {
                 }
             }
             WriterState::MultipleBlocks {
-                block_sizes,
+                blocks,
                 resource_fork,
             } => {
-                if block_sizes.len() + 1 >= u32::MAX as usize {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "too many blocks",
-                    ));
-                }
-                block_sizes.push(new_block.len() as u32);
+                // Blocks are always appended, so the resource fork is already at the right place
+                blocks.push(index, new_block_len)?;
                 resource_fork.write_all(new_block)?;
             }
-            WriterState::Empty => unreachable!(),
+            WriterState::Failed | WriterState::Empty => unreachable!(),
         };
         Ok(())
     }
@@ -104,26 +256,27 @@ impl<O: Open> Writer<O> {
                 decmpfs::Storage::Xattr
             }
             WriterState::MultipleBlocks {
-                block_sizes,
+                blocks,
                 resource_fork,
             } => {
-                if block_sizes.len() as u64 != crate::num_blocks(self.uncompressed_size) {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Wrong number of blocks",
-                    ));
-                }
-                self.kind.finish(resource_fork, &block_sizes)?;
+                let block_sizes = blocks.sizes().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Wrong number of blocks")
+                })?;
+                // Out of order blocks are compacted as soon as the last block arrives
+                debug_assert!(blocks.first_misplaced().is_none());
+                self.format.finish(resource_fork, &block_sizes)?;
                 decmpfs::Storage::ResourceFork
             }
+            WriterState::Failed => return Err(poisoned()),
             WriterState::Empty => unreachable!(),
         };
 
-        let value = decmpfs::Value {
-            compression_type: CompressionType::new(self.kind, storage),
-            uncompressed_size: self.uncompressed_size,
-            extra_data: &extra_data,
-        };
+        let value = decmpfs::Value::with_compression_type(
+            self.format.compression_type(storage),
+            storage,
+            self.uncompressed_size,
+            &extra_data,
+        )?;
 
         dst.reserve(value.len());
         value.write_to(dst)?;
@@ -137,37 +290,35 @@ impl<O: Open> Writer<O> {
     ///
     /// Calling this function will ensure that a resource fork is always used.
     pub fn force_resource_fork(&mut self) -> io::Result<()> {
-        if matches!(self.state, WriterState::MultipleBlocks { .. }) {
-            return Ok(());
-        }
-
-        match &mut self.state {
+        self.poison_on_error(|this| match &mut this.state {
             WriterState::SingleBlock { block, .. } => {
                 let block = mem::take(block);
-                self.write_single_block_as_rfork(&block)?;
+                this.write_single_block_as_rfork(&block)
             }
-            _ => unreachable!("Just checked that we're not already multiple blocks"),
-        }
-
-        Ok(())
+            WriterState::MultipleBlocks { .. } => Ok(()),
+            WriterState::Failed | WriterState::Empty => unreachable!(),
+        })
     }
 
     // Only called on single-block files, to convert to multiple blocks, even with a single block
     // because the block is too large to fit in an xattr
+    //
+    // On error, the state is left empty, the caller must move to the failed state
     fn write_single_block_as_rfork(&mut self, new_block: &[u8]) -> io::Result<()> {
         match mem::replace(&mut self.state, WriterState::Empty) {
             WriterState::SingleBlock { open, block } => {
                 debug_assert!(block.is_empty());
 
                 let mut resource_fork = open.open_resource_fork()?;
-                resource_fork.seek(SeekFrom::Start(
-                    self.kind
-                        .header_size(crate::num_blocks(self.uncompressed_size)),
-                ))?;
+                resource_fork.seek(SeekFrom::Start(self.format.header_size(
+                    crate::num_blocks_with_size(self.uncompressed_size, self.block_size),
+                )))?;
+                let mut blocks = Blocks::new(1, resource_fork.stream_position()?);
+                blocks.push(0, new_block.len() as u32)?;
                 resource_fork.write_all(new_block)?;
 
                 self.state = WriterState::MultipleBlocks {
-                    block_sizes: vec![new_block.len() as u32],
+                    blocks,
                     resource_fork,
                 };
             }
@@ -176,3 +327,60 @@ impl<O: Open> Writer<O> {
         Ok(())
     }
 }
+
+impl<O: Open> Writer<O>
+where
+    O::ResourceFork: Read,
+{
+    /// Add the block at `index`, in any order
+    ///
+    /// Blocks are appended to the resource fork in the order they arrive, and their positions
+    /// recorded. When the last block arrives, if any blocks were not added in order, every block
+    /// from the first misplaced block onwards is read back into memory, and rewritten in index
+    /// order, so the final layout is identical to adding all blocks in order.
+    ///
+    /// This means adding blocks out of order may require reading and rewriting up to the whole
+    /// compressed size of the file, and holding it in memory while doing so.
+    pub fn add_block_at(&mut self, index: u64, new_block: &[u8]) -> io::Result<()> {
+        self.poison_on_error(|this| {
+            this.try_write_block(index, new_block)?;
+            match &mut this.state {
+                WriterState::MultipleBlocks {
+                    blocks,
+                    resource_fork,
+                } if blocks.is_complete() => compact(blocks, resource_fork),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("an earlier write failed, the output is incomplete and must be discarded")
+}
+
+/// Move all blocks into index order, leaving the resource fork positioned after the last block
+fn compact<W: Read + Write + Seek>(blocks: &mut Blocks, resource_fork: &mut W) -> io::Result<()> {
+    let Some((first_index, first_offset)) = blocks.first_misplaced() else {
+        return Ok(());
+    };
+
+    // Every block which needs to move was written after `first_offset`
+    let mut data = vec![0; usize::try_from(blocks.end - first_offset).unwrap()];
+    resource_fork.seek(SeekFrom::Start(first_offset))?;
+    resource_fork.read_exact(&mut data)?;
+
+    resource_fork.seek(SeekFrom::Start(first_offset))?;
+    let mut offset = first_offset;
+    for slot in &mut blocks.slots[first_index..] {
+        let slot = slot
+            .as_mut()
+            .expect("all blocks are present when compacting");
+        let start = usize::try_from(slot.offset - first_offset).unwrap();
+        resource_fork.write_all(&data[start..][..slot.size as usize])?;
+        slot.offset = offset;
+        offset += u64::from(slot.size);
+    }
+    debug_assert_eq!(offset, blocks.end);
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use applesauce_core::check::{check_structure, check_written, StructureError, Written};
+use applesauce_core::compressor::Kind;
+use applesauce_core::writer::Writer;
+use applesauce_core::{compress_stream, CompressedArtifacts, BLOCK_SIZE};
+use std::io::Cursor;
+
+const DATA_LEN: usize = 3 * BLOCK_SIZE + 1234;
+
+fn supported_kinds() -> impl Iterator<Item = Kind> {
+    Kind::all().iter().copied().filter(|kind| kind.supported())
+}
+
+fn artifacts(kind: Kind, data_len: usize) -> CompressedArtifacts {
+    let data: Vec<u8> = (0..data_len).map(|i| (i % 251) as u8).collect();
+    compress_stream(kind, 5, data_len as u64, &data[..]).unwrap()
+}
+
+fn check(decmpfs_xattr: &[u8], resource_fork: &[u8], size: u64) -> Result<(), StructureError> {
+    check_structure(decmpfs_xattr, size, || Cursor::new(resource_fork))
+}
+
+fn check_artifacts(artifacts: &CompressedArtifacts, size: u64) -> Result<(), StructureError> {
+    check(
+        &artifacts.decmpfs_xattr,
+        artifacts.resource_fork.as_deref().unwrap_or_default(),
+        size,
+    )
+}
+
+fn set_uncompressed_size(decmpfs_xattr: &mut [u8], size: u64) {
+    decmpfs_xattr[8..16].copy_from_slice(&size.to_le_bytes());
+}
+
+#[test]
+fn valid() {
+    for kind in supported_kinds() {
+        let in_fork = artifacts(kind, DATA_LEN);
+        assert!(in_fork.resource_fork.is_some());
+        check_artifacts(&in_fork, DATA_LEN as u64).unwrap();
+
+        let in_xattr = artifacts(kind, 100);
+        assert!(in_xattr.resource_fork.is_none());
+        check_artifacts(&in_xattr, 100).unwrap();
+    }
+}
+
+#[test]
+fn size_mismatch() {
+    for kind in supported_kinds() {
+        for data_len in [100, DATA_LEN] {
+            let artifacts = artifacts(kind, data_len);
+            let err = check_artifacts(&artifacts, data_len as u64 + 1).unwrap_err();
+            assert!(
+                matches!(err, StructureError::SizeMismatch { expected, actual }
+                    if expected == data_len as u64 + 1 && actual == data_len as u64),
+                "{kind}: {err}"
+            );
+        }
+    }
+}
+
+#[test]
+fn wrong_block_count() {
+    for kind in supported_kinds() {
+        let mut artifacts = artifacts(kind, DATA_LEN);
+        // The resource fork has 4 blocks, but 2 blocks are needed for the recorded size
+        let size = 2 * BLOCK_SIZE as u64;
+        set_uncompressed_size(&mut artifacts.decmpfs_xattr, size);
+        let err = check_artifacts(&artifacts, size).unwrap_err();
+        assert!(
+            matches!(err, StructureError::BlockTable(_)),
+            "{kind}: {err}"
+        );
+    }
+}
+
+#[test]
+fn truncated_resource_fork() {
+    for kind in supported_kinds() {
+        let artifacts = artifacts(kind, DATA_LEN);
+        let resource_fork = artifacts.resource_fork.unwrap();
+        let truncated = &resource_fork[..resource_fork.len() - 1];
+        let err = check(&artifacts.decmpfs_xattr, truncated, DATA_LEN as u64).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                StructureError::BlockTable(_) | StructureError::ResourceForkLength { .. }
+            ),
+            "{kind}: {err}"
+        );
+    }
+}
+
+#[test]
+fn extended_resource_fork() {
+    for kind in supported_kinds() {
+        let artifacts = artifacts(kind, DATA_LEN);
+        let mut resource_fork = artifacts.resource_fork.unwrap();
+        resource_fork.extend_from_slice(&[0; 16]);
+        let err = check(&artifacts.decmpfs_xattr, &resource_fork, DATA_LEN as u64).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                StructureError::BlockTable(_) | StructureError::ResourceForkLength { .. }
+            ),
+            "{kind}: {err}"
+        );
+    }
+}
+
+#[test]
+fn invalid_decmpfs() {
+    let mut artifacts = artifacts(Kind::default(), 100);
+    artifacts.decmpfs_xattr[0] ^= 0xFF;
+    let err = check_artifacts(&artifacts, 100).unwrap_err();
+    assert!(matches!(err, StructureError::Decode(_)), "{err}");
+
+    let err = check(&artifacts.decmpfs_xattr[..4], &[], 100).unwrap_err();
+    assert!(matches!(err, StructureError::Decode(_)), "{err}");
+}
+
+#[test]
+fn unknown_compression_type() {
+    let mut artifacts = artifacts(Kind::default(), 100);
+    artifacts.decmpfs_xattr[4..8].copy_from_slice(&1000_u32.to_le_bytes());
+    let err = check_artifacts(&artifacts, 100).unwrap_err();
+    assert!(matches!(err, StructureError::UnsupportedType(_)), "{err}");
+}
+
+/// Write `blocks` with a [`Writer`], returning the decmpfs xattr, resource fork, and what was written
+fn write_blocks(kind: Kind, data_len: usize, blocks: &[Vec<u8>]) -> (Vec<u8>, Vec<u8>, Written) {
+    let mut resource_fork = Cursor::new(Vec::new());
+    let rfork_ref = &mut resource_fork;
+    let mut writer = Writer::new(kind, data_len as u64, move || rfork_ref).unwrap();
+    let mut written = Written::default();
+    for block in blocks {
+        writer.add_block(block).unwrap();
+        written.block_count += 1;
+        written.compressed_size += block.len() as u64;
+    }
+    let mut decmpfs_xattr = Vec::new();
+    writer.finish_decmpfs_data(&mut decmpfs_xattr).unwrap();
+    (decmpfs_xattr, resource_fork.into_inner(), written)
+}
+
+#[test]
+fn written_blocks() {
+    for kind in supported_kinds() {
+        let in_fork: Vec<Vec<u8>> = (0..4).map(|i| vec![i + 1; 10 + usize::from(i)]).collect();
+        let in_xattr = [vec![1; 10]];
+        for (data_len, blocks) in [(DATA_LEN, &in_fork[..]), (100, &in_xattr[..])] {
+            let (decmpfs_xattr, resource_fork, written) = write_blocks(kind, data_len, blocks);
+            let check_written = |written| {
+                check_written(&decmpfs_xattr, data_len as u64, written, || {
+                    Cursor::new(&resource_fork)
+                })
+            };
+            check_written(written).unwrap();
+
+            let more_blocks = Written {
+                block_count: written.block_count + 1,
+                ..written
+            };
+            let err = check_written(more_blocks).unwrap_err();
+            assert!(
+                matches!(err, StructureError::BlockCountMismatch { expected, actual }
+                    if expected == more_blocks.block_count && actual == written.block_count),
+                "{kind}: {err}"
+            );
+
+            let larger = Written {
+                compressed_size: written.compressed_size + 1,
+                ..written
+            };
+            let err = check_written(larger).unwrap_err();
+            assert!(
+                matches!(err, StructureError::CompressedSizeMismatch { expected, actual }
+                    if expected == larger.compressed_size && actual == written.compressed_size),
+                "{kind}: {err}"
+            );
+        }
+    }
+}
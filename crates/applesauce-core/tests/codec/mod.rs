@@ -0,0 +1,168 @@
+use applesauce_core::compressor::{Codec, ReadSeek, WriteSeek};
+use applesauce_core::decmpfs::{self, BlockInfo, CompressionType, Storage};
+use applesauce_core::reader::Reader;
+use applesauce_core::writer::Writer;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: u64 = 1024;
+
+fn never_called_open() -> Cursor<Vec<u8>> {
+    panic!("Should not be called");
+}
+
+/// A codec which stores blocks as-is, with a table of block infos at the start of the
+/// resource fork
+struct StoreOnly;
+
+impl Codec for StoreOnly {
+    fn name(&self) -> &str {
+        "store"
+    }
+
+    fn compression_type(&self, storage: Storage) -> CompressionType {
+        match storage {
+            Storage::Xattr => CompressionType::from_raw_type(0x1000),
+            Storage::ResourceFork => CompressionType::from_raw_type(0x1001),
+        }
+    }
+
+    fn header_size(&self, block_count: u64) -> u64 {
+        block_count * BlockInfo::SIZE as u64
+    }
+
+    fn compress(&mut self, dst: &mut [u8], src: &[u8], _level: u32) -> io::Result<usize> {
+        dst.get_mut(..src.len())
+            .ok_or(io::ErrorKind::WriteZero)?
+            .copy_from_slice(src);
+        Ok(src.len())
+    }
+
+    fn decompress(&mut self, dst: &mut [u8], src: &[u8]) -> io::Result<usize> {
+        self.compress(dst, src, 0)
+    }
+
+    fn read_block_info(
+        &self,
+        reader: &mut dyn ReadSeek,
+        orig_file_size: u64,
+        block_size: u64,
+    ) -> io::Result<Vec<BlockInfo>> {
+        let block_count = applesauce_core::num_blocks_with_size(orig_file_size, block_size);
+        reader.rewind()?;
+        (0..block_count)
+            .map(|_| {
+                let mut buf = [0; BlockInfo::SIZE];
+                reader.read_exact(&mut buf)?;
+                Ok(BlockInfo::from_bytes(buf))
+            })
+            .collect()
+    }
+
+    fn finish(&self, writer: &mut dyn WriteSeek, block_sizes: &[u32]) -> io::Result<()> {
+        let mut offset = self.header_size(block_sizes.len() as u64) as u32;
+        writer.seek(SeekFrom::Start(0))?;
+        for &compressed_size in block_sizes {
+            let info = BlockInfo {
+                offset,
+                compressed_size,
+            };
+            writer.write_all(&info.to_bytes())?;
+            offset += compressed_size;
+        }
+        Ok(())
+    }
+}
+
+fn round_trip(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut codec = StoreOnly;
+    let mut resource_fork = Vec::new();
+    let mut writer = Writer::with_codec(Box::new(StoreOnly), data.len() as u64, BLOCK_SIZE, {
+        let rfork_ref = &mut resource_fork;
+        move || Cursor::new(rfork_ref)
+    })
+    .unwrap();
+    let mut compressed = vec![0; BLOCK_SIZE as usize];
+    for block in data.chunks(BLOCK_SIZE as usize) {
+        let len = codec.compress(&mut compressed, block, 0).unwrap();
+        writer.add_block(&compressed[..len]).unwrap();
+    }
+    let mut decmpfs_data = Vec::new();
+    writer.finish_decmpfs_data(&mut decmpfs_data).unwrap();
+
+    let mut reader = Reader::with_codec(&decmpfs_data, BLOCK_SIZE, Box::new(StoreOnly), || {
+        Cursor::new(&resource_fork)
+    })
+    .unwrap();
+    assert_eq!(reader.compression_kind(), None);
+    assert_eq!(reader.format().name(), "store");
+    assert_eq!(reader.uncompressed_size(), data.len() as u64);
+
+    let mut decompressed = Vec::new();
+    let mut block = Vec::new();
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+    while reader.read_block_into(&mut block).unwrap() {
+        let len = codec.decompress(&mut buf, &block).unwrap();
+        decompressed.extend_from_slice(&buf[..len]);
+        block.clear();
+    }
+    assert_eq!(decompressed, data);
+
+    (decmpfs_data, resource_fork)
+}
+
+#[test]
+fn store_only_resource_fork() {
+    let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 7).map(|i| i as u8).collect();
+    let (decmpfs_data, resource_fork) = round_trip(&data);
+
+    let value = decmpfs::Value::from_data(&decmpfs_data).unwrap();
+    assert_eq!(
+        value.compression_type,
+        CompressionType::from_raw_type(0x1001)
+    );
+    assert_eq!(value.kind(), None);
+    assert_eq!(
+        resource_fork.len() as u64,
+        4 * BlockInfo::SIZE as u64 + data.len() as u64
+    );
+}
+
+#[test]
+fn store_only_xattr() {
+    let (decmpfs_data, resource_fork) = round_trip(b"small");
+
+    let value = decmpfs::Value::from_data(&decmpfs_data).unwrap();
+    assert_eq!(
+        value.compression_type,
+        CompressionType::from_raw_type(0x1000)
+    );
+    assert_eq!(value.extra_data, b"small");
+    assert!(resource_fork.is_empty());
+}
+
+#[test]
+fn builtin_reader_rejects_external_type() {
+    let (decmpfs_data, resource_fork) = round_trip(b"small");
+    let err = Reader::new(&decmpfs_data, || Cursor::new(&resource_fork)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+fn codec_reader_rejects_other_type() {
+    let data = [0; 16];
+    let artifacts = applesauce_core::compress_stream(
+        applesauce_core::compressor::Kind::default(),
+        5,
+        data.len() as u64,
+        &data[..],
+    )
+    .unwrap();
+    let err = Reader::with_codec(
+        &artifacts.decmpfs_xattr,
+        BLOCK_SIZE,
+        Box::new(StoreOnly),
+        never_called_open,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+}
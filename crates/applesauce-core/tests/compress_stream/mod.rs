@@ -0,0 +1,108 @@
+use applesauce_core::compressor::Kind;
+use applesauce_core::reader::Reader;
+use applesauce_core::{compress_stream, decmpfs, BLOCK_SIZE};
+use std::io::Cursor;
+
+fn decompress_artifacts(kind: Kind, artifacts: &applesauce_core::CompressedArtifacts) -> Vec<u8> {
+    let mut compressor = kind.compressor().unwrap();
+    let resource_fork = artifacts.resource_fork.clone().unwrap_or_default();
+    let mut reader = Reader::new(&artifacts.decmpfs_xattr, || Cursor::new(resource_fork)).unwrap();
+    assert_eq!(reader.compression_kind(), Some(kind));
+
+    let mut result = Vec::new();
+    let mut compressed_block = Vec::new();
+    // Need an extra byte, because lzfse/lzvn needs at least one extra byte to differentiate between
+    // finishing on the last byte and running out of space
+    let mut clear_buf = vec![0; BLOCK_SIZE + 1];
+    loop {
+        compressed_block.clear();
+        if !reader.read_block_into(&mut compressed_block).unwrap() {
+            break;
+        }
+        let len = compressor
+            .decompress(&mut clear_buf, &compressed_block)
+            .unwrap();
+        result.extend_from_slice(&clear_buf[..len]);
+    }
+    result
+}
+
+fn round_trip(kind: Kind, data: &[u8], expected_storage: decmpfs::Storage) {
+    let artifacts = compress_stream(kind, 5, data.len() as u64, data).unwrap();
+
+    let value = decmpfs::Value::from_data(&artifacts.decmpfs_xattr).unwrap();
+    assert_eq!(value.uncompressed_size, data.len() as u64);
+    let (value_kind, storage) = value.compression_type.compression_storage().unwrap();
+    assert_eq!(value_kind, kind);
+    assert_eq!(storage, expected_storage);
+    assert_eq!(
+        artifacts.resource_fork.is_some(),
+        storage == decmpfs::Storage::ResourceFork
+    );
+
+    if !data.is_empty() {
+        assert_eq!(decompress_artifacts(kind, &artifacts), data);
+    }
+}
+
+#[test]
+fn reader_too_short() {
+    let err = compress_stream(Kind::default(), 5, 10, &[1, 2, 3][..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn reader_too_long() {
+    let err = compress_stream(Kind::default(), 5, 2, &[1, 2, 3][..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+macro_rules! compress_stream_tests {
+    ($($name:ident),* $(,)?) => {
+        $(
+            mod $name {
+                use super::round_trip;
+                use applesauce_core::compressor::Compressor;
+                use applesauce_core::decmpfs::Storage;
+
+                #[test]
+                fn empty() {
+                    round_trip(Compressor::$name().kind(), &[], Storage::Xattr);
+                }
+
+                #[test]
+                fn small_inline_xattr() {
+                    round_trip(Compressor::$name().kind(), &[1; 1024], Storage::Xattr);
+                }
+
+                #[test]
+                fn large_compressible() {
+                    round_trip(
+                        Compressor::$name().kind(),
+                        &[1; 1024 * 1024 - 1],
+                        Storage::ResourceFork,
+                    );
+                }
+
+                #[test]
+                fn large_rand() {
+                    use rand::RngCore;
+
+                    let mut data = vec![0; 1024 * 1024];
+                    rand::thread_rng().fill_bytes(&mut data);
+
+                    round_trip(Compressor::$name().kind(), &data, Storage::ResourceFork);
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "lzfse")]
+compress_stream_tests!(lzfse);
+
+#[cfg(feature = "lzvn")]
+compress_stream_tests!(lzvn);
+
+#[cfg(feature = "zlib")]
+compress_stream_tests!(zlib);
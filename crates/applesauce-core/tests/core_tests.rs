@@ -1,2 +1,7 @@
+mod check;
+mod codec;
+mod compress_stream;
+mod golden;
+mod malformed;
 mod reader;
 mod writer;
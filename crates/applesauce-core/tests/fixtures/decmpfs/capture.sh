@@ -0,0 +1,59 @@
+#!/bin/sh
+# Capture decmpfs fixtures compressed by macOS, see README.md
+#
+# Must be run on macOS, from this directory, on an APFS or HFS+ volume.
+
+set -eu
+
+workdir=$(mktemp -d)
+trap 'rm -rf "$workdir"' EXIT
+
+# Record what produced the fixtures, the tests only run once this exists
+{
+    sw_vers
+    echo "Kernel: $(uname -v)"
+    echo "Captured: $(date -u +%Y-%m-%dT%H:%M:%SZ)"
+    if command -v afsctool > /dev/null; then
+        echo "afsctool: $(afsctool 2>&1 | head -n 1)"
+    fi
+} > PROVENANCE.new
+
+# capture <name> <compressed file>
+capture() {
+    name=$1
+    file=$2
+    if ! ls -lO "$file" | grep -q compressed; then
+        echo "$name: $file was not compressed" >&2
+        exit 1
+    fi
+    xattr -px com.apple.decmpfs "$file" | xxd -r -p > "$name.decmpfs"
+    rsrc="$file/..namedfork/rsrc"
+    if [ -s "$rsrc" ]; then
+        cat "$rsrc" > "$name.rsrc"
+    else
+        rm -f "$name.rsrc"
+    fi
+    echo "captured $name"
+}
+
+for storage in xattr rfork; do
+    case $storage in
+        xattr) plaintext=short.txt ;;
+        rfork) plaintext=two-blocks.txt ;;
+    esac
+
+    ditto --hfsCompression "$plaintext" "$workdir/zlib-$storage"
+    capture "zlib-$storage" "$workdir/zlib-$storage"
+
+    if command -v afsctool > /dev/null; then
+        for kind in lzvn lzfse; do
+            cp "$plaintext" "$workdir/$kind-$storage"
+            afsctool -c -T "$(echo "$kind" | tr '[:lower:]' '[:upper:]')" "$workdir/$kind-$storage"
+            capture "$kind-$storage" "$workdir/$kind-$storage"
+        done
+    else
+        echo "afsctool not found, LZVN and LZFSE fixtures not captured" >&2
+    fi
+done
+
+mv PROVENANCE.new PROVENANCE
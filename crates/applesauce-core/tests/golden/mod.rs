@@ -0,0 +1,228 @@
+//! Compare against compressed files in the layout macOS reads and writes
+//!
+//! See `tests/fixtures/decmpfs/README.md` for where the fixtures come from, and which differences
+//! from applesauce's output are allowed.
+
+use applesauce_core::compressor::Kind;
+use applesauce_core::decmpfs::{self, CompressionType, Storage};
+use applesauce_core::reader::Reader;
+use applesauce_core::writer::Writer;
+use applesauce_core::{compress_stream, num_blocks, BLOCK_SIZE};
+use std::fs;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+
+struct Fixture {
+    name: &'static str,
+    kind: Kind,
+    storage: Storage,
+    decmpfs: Vec<u8>,
+    resource_fork: Option<Vec<u8>>,
+    plaintext: Vec<u8>,
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/decmpfs")
+}
+
+/// Read a fixture file, `None` if it wasn't captured
+fn read_fixture_file(name: &str) -> Option<Vec<u8>> {
+    match fs::read(fixtures_dir().join(name)) {
+        Ok(data) => Some(data),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => panic!("unable to read fixture {name}: {e}"),
+    }
+}
+
+/// Every fixture captured on macOS, with a kind supported in this build
+///
+/// Prints why if fixtures are skipped: there are none until `capture.sh` has been run on a Mac.
+fn fixtures() -> Vec<Fixture> {
+    let Some(provenance) = read_fixture_file("PROVENANCE") else {
+        eprintln!("no decmpfs fixtures captured on macOS, see tests/fixtures/decmpfs/README.md");
+        return Vec::new();
+    };
+    let provenance = String::from_utf8_lossy(&provenance);
+
+    let mut fixtures = Vec::new();
+    for (name, kind, storage) in [
+        ("zlib-xattr", Kind::Zlib, Storage::Xattr),
+        ("zlib-rfork", Kind::Zlib, Storage::ResourceFork),
+        ("lzvn-xattr", Kind::Lzvn, Storage::Xattr),
+        ("lzvn-rfork", Kind::Lzvn, Storage::ResourceFork),
+        ("lzfse-xattr", Kind::Lzfse, Storage::Xattr),
+        ("lzfse-rfork", Kind::Lzfse, Storage::ResourceFork),
+    ] {
+        if !kind.supported() {
+            continue;
+        }
+        let Some(decmpfs) = read_fixture_file(&format!("{name}.decmpfs")) else {
+            eprintln!("{name} was not captured, skipping it");
+            continue;
+        };
+        let (resource_fork, plaintext) = match storage {
+            Storage::Xattr => (None, "short.txt"),
+            Storage::ResourceFork => (
+                Some(
+                    read_fixture_file(&format!("{name}.rsrc"))
+                        .unwrap_or_else(|| panic!("{name} is missing its resource fork")),
+                ),
+                "two-blocks.txt",
+            ),
+        };
+        fixtures.push(Fixture {
+            name,
+            kind,
+            storage,
+            decmpfs,
+            resource_fork,
+            plaintext: read_fixture_file(plaintext).unwrap(),
+        });
+    }
+    assert!(
+        !fixtures.is_empty() || !Kind::Zlib.supported(),
+        "PROVENANCE exists, but no fixtures were captured:\n{provenance}"
+    );
+    fixtures
+}
+
+struct Block {
+    compressed: Vec<u8>,
+    decompressed: Vec<u8>,
+}
+
+fn read_blocks(kind: Kind, decmpfs_data: &[u8], resource_fork: Option<&[u8]>) -> Vec<Block> {
+    let mut compressor = kind.compressor().unwrap();
+    let mut reader = Reader::new(decmpfs_data, || {
+        Cursor::new(resource_fork.unwrap_or_default())
+    })
+    .unwrap();
+    assert_eq!(reader.compression_kind(), Some(kind));
+
+    let mut blocks = Vec::new();
+    // lzfse/lzvn need an extra byte to tell finishing on the last byte from running out of space
+    let mut buf = vec![0; BLOCK_SIZE + 1];
+    loop {
+        let mut compressed = Vec::new();
+        if !reader.read_block_into(&mut compressed).unwrap() {
+            break;
+        }
+        let len = compressor.decompress(&mut buf, &compressed).unwrap();
+        blocks.push(Block {
+            compressed,
+            decompressed: buf[..len].to_vec(),
+        });
+    }
+    blocks
+}
+
+#[test]
+fn fixtures_decompress() {
+    for fixture in fixtures() {
+        let name = fixture.name;
+        let value = decmpfs::Value::from_data(&fixture.decmpfs).unwrap();
+        assert_eq!(
+            value.compression_type,
+            CompressionType::new(fixture.kind, fixture.storage),
+            "{name}"
+        );
+        assert_eq!(
+            value.uncompressed_size,
+            fixture.plaintext.len() as u64,
+            "{name}"
+        );
+
+        let blocks = read_blocks(
+            fixture.kind,
+            &fixture.decmpfs,
+            fixture.resource_fork.as_deref(),
+        );
+        assert_eq!(
+            blocks.len() as u64,
+            num_blocks(fixture.plaintext.len() as u64),
+            "{name}"
+        );
+        let decompressed: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| &block.decompressed)
+            .copied()
+            .collect();
+        assert!(decompressed == fixture.plaintext, "{name}: contents differ");
+    }
+}
+
+#[test]
+fn fixtures_rewritten_exactly() {
+    for fixture in fixtures() {
+        let name = fixture.name;
+        let value = decmpfs::Value::from_data(&fixture.decmpfs).unwrap();
+        assert_eq!(value.to_vec(), fixture.decmpfs, "{name}");
+
+        // Writing the same compressed blocks must produce the same headers, block table, and
+        // trailer
+        let blocks = read_blocks(
+            fixture.kind,
+            &fixture.decmpfs,
+            fixture.resource_fork.as_deref(),
+        );
+        let mut resource_fork = Vec::new();
+        let mut decmpfs_data = Vec::new();
+        {
+            let rfork_ref = &mut resource_fork;
+            let mut writer = Writer::new(fixture.kind, value.uncompressed_size, move || {
+                Cursor::new(rfork_ref)
+            })
+            .unwrap();
+            for block in &blocks {
+                writer.add_block(&block.compressed).unwrap();
+            }
+            if fixture.storage == Storage::ResourceFork {
+                writer.force_resource_fork().unwrap();
+            }
+            writer.finish_decmpfs_data(&mut decmpfs_data).unwrap();
+        }
+        assert_eq!(decmpfs_data, fixture.decmpfs, "{name}");
+        assert!(
+            resource_fork == fixture.resource_fork.unwrap_or_default(),
+            "{name}: resource forks differ"
+        );
+    }
+}
+
+#[test]
+fn own_output_equivalent() {
+    for fixture in fixtures() {
+        let name = fixture.name;
+        let artifacts = compress_stream(
+            fixture.kind,
+            5,
+            fixture.plaintext.len() as u64,
+            fixture.plaintext.as_slice(),
+        )
+        .unwrap();
+
+        let ours = decmpfs::Value::from_data(&artifacts.decmpfs_xattr).unwrap();
+        let theirs = decmpfs::Value::from_data(&fixture.decmpfs).unwrap();
+        assert_eq!(ours.compression_type, theirs.compression_type, "{name}");
+        assert_eq!(ours.uncompressed_size, theirs.uncompressed_size, "{name}");
+
+        // The compressed bytes may differ, but each block must hold the same data
+        let our_blocks = read_blocks(
+            fixture.kind,
+            &artifacts.decmpfs_xattr,
+            artifacts.resource_fork.as_deref(),
+        );
+        let their_blocks = read_blocks(
+            fixture.kind,
+            &fixture.decmpfs,
+            fixture.resource_fork.as_deref(),
+        );
+        assert_eq!(our_blocks.len(), their_blocks.len(), "{name}");
+        for (i, (ours, theirs)) in our_blocks.iter().zip(&their_blocks).enumerate() {
+            assert!(
+                ours.decompressed == theirs.decompressed,
+                "{name}: block {i} differs"
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Feed malformed decmpfs values and resource forks to the parsers
+//!
+//! These are the parts of a compressed file read back from disk, so they must be treated as
+//! untrusted: any input should produce an error rather than a panic or a huge allocation.
+
+use applesauce_core::compressor::Kind;
+use applesauce_core::decmpfs;
+use applesauce_core::reader::Reader;
+use applesauce_core::writer::Writer;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::io::{self, Cursor};
+
+const BLOCK_SIZE: u64 = 4 * 1024;
+const ITERATIONS: usize = 2_000;
+
+fn supported_kinds() -> impl Iterator<Item = Kind> {
+    Kind::all().iter().copied().filter(|kind| kind.supported())
+}
+
+/// A well formed resource fork of `kind`, with `block_count` blocks of random data
+fn resource_fork(rng: &mut impl Rng, kind: Kind, block_count: u64) -> (Vec<u8>, u64) {
+    let uncompressed_size = block_count * BLOCK_SIZE - rng.gen_range(0..BLOCK_SIZE);
+    let mut resource_fork = Vec::new();
+    let mut writer = {
+        let rfork_ref = &mut resource_fork;
+        Writer::with_block_size(kind, uncompressed_size, BLOCK_SIZE, move || {
+            Cursor::new(rfork_ref)
+        })
+        .unwrap()
+    };
+    for _ in 0..block_count {
+        let mut block = vec![0; rng.gen_range(1..64)];
+        rng.fill_bytes(&mut block);
+        writer.add_block(&block).unwrap();
+    }
+    writer.force_resource_fork().unwrap();
+    writer.finish_decmpfs_data(&mut Vec::new()).unwrap();
+    (resource_fork, uncompressed_size)
+}
+
+fn mutate(rng: &mut impl Rng, data: &mut Vec<u8>) {
+    match rng.gen_range(0..4) {
+        0 if !data.is_empty() => {
+            for _ in 0..rng.gen_range(1..4) {
+                let i = rng.gen_range(0..data.len());
+                data[i] = rng.gen();
+            }
+        }
+        1 => data.truncate(rng.gen_range(0..=data.len())),
+        2 => {
+            let mut extra = vec![0; rng.gen_range(1..32)];
+            rng.fill_bytes(&mut extra);
+            data.extend_from_slice(&extra);
+        }
+        _ if data.len() >= 4 => {
+            // Offsets and sizes are 32 bit, so clobber a whole field
+            let i = rng.gen_range(0..=data.len() - 4);
+            data[i..i + 4].copy_from_slice(&rng.gen::<u32>().to_le_bytes());
+        }
+        _ => {}
+    }
+}
+
+fn check_block_info(kind: Kind, resource_fork: &[u8], uncompressed_size: u64) {
+    let result = kind.read_block_info_with_block_size(
+        Cursor::new(resource_fork),
+        uncompressed_size,
+        BLOCK_SIZE,
+    );
+    let Ok(block_infos) = result else {
+        return;
+    };
+    for block_info in block_infos {
+        let end = u64::from(block_info.offset) + u64::from(block_info.compressed_size);
+        assert!(
+            end <= resource_fork.len() as u64,
+            "{kind}: block {block_info:?} extends past resource fork of {} bytes",
+            resource_fork.len()
+        );
+    }
+}
+
+#[test]
+fn value_from_arbitrary_data() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..ITERATIONS {
+        let mut data = vec![0; rng.gen_range(0..64)];
+        rng.fill_bytes(&mut data);
+        if rng.gen() && data.len() >= 4 {
+            data[..4].copy_from_slice(&decmpfs::MAGIC);
+        }
+
+        let Ok(value) = decmpfs::Value::from_data(&data) else {
+            continue;
+        };
+        let _ = value.kind();
+        let _ = value.storage();
+        let mut written = Vec::new();
+        value.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+    }
+}
+
+#[test]
+fn block_info_round_trip() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for kind in supported_kinds() {
+        for block_count in [1, 2, 5, 17] {
+            let (resource_fork, uncompressed_size) = resource_fork(&mut rng, kind, block_count);
+            let block_infos = kind
+                .read_block_info_with_block_size(
+                    Cursor::new(&resource_fork),
+                    uncompressed_size,
+                    BLOCK_SIZE,
+                )
+                .unwrap();
+            assert_eq!(block_infos.len() as u64, block_count);
+            check_block_info(kind, &resource_fork, uncompressed_size);
+        }
+    }
+}
+
+#[test]
+fn block_info_mutated() {
+    let mut rng = StdRng::seed_from_u64(2);
+    for kind in supported_kinds() {
+        for _ in 0..ITERATIONS {
+            let block_count = rng.gen_range(1..8);
+            let (mut resource_fork, mut uncompressed_size) =
+                resource_fork(&mut rng, kind, block_count);
+            for _ in 0..rng.gen_range(1..4) {
+                mutate(&mut rng, &mut resource_fork);
+            }
+            if rng.gen_ratio(1, 4) {
+                uncompressed_size = rng.gen();
+            }
+            check_block_info(kind, &resource_fork, uncompressed_size);
+        }
+    }
+}
+
+#[test]
+fn block_info_arbitrary_data() {
+    let mut rng = StdRng::seed_from_u64(3);
+    for kind in supported_kinds() {
+        for _ in 0..ITERATIONS {
+            let mut resource_fork = vec![0; rng.gen_range(0..0x200)];
+            rng.fill_bytes(&mut resource_fork);
+            check_block_info(kind, &resource_fork, rng.gen_range(0..1024 * 1024));
+        }
+    }
+}
+
+#[test]
+fn reader_mutated() {
+    let mut rng = StdRng::seed_from_u64(4);
+    for kind in supported_kinds() {
+        for _ in 0..ITERATIONS / 4 {
+            let (mut resource_fork, uncompressed_size) = resource_fork(&mut rng, kind, 3);
+            mutate(&mut rng, &mut resource_fork);
+            let value =
+                decmpfs::Value::new(kind, decmpfs::Storage::ResourceFork, uncompressed_size, &[])
+                    .unwrap();
+            let mut decmpfs_data = Vec::new();
+            value.write_to(&mut decmpfs_data).unwrap();
+
+            let Ok(reader) =
+                Reader::with_block_size(&decmpfs_data, BLOCK_SIZE, || Cursor::new(&resource_fork))
+            else {
+                continue;
+            };
+            for block in reader.into_blocks() {
+                let Ok(block) = block else {
+                    break;
+                };
+                assert!(block.len() <= resource_fork.len());
+            }
+        }
+    }
+}
+
+#[test]
+fn huge_size_small_fork() {
+    let mut rng = StdRng::seed_from_u64(5);
+    for kind in supported_kinds() {
+        let (resource_fork, _) = resource_fork(&mut rng, kind, 2);
+        for uncompressed_size in [u64::from(u32::MAX) * BLOCK_SIZE, u64::MAX] {
+            let err = kind
+                .read_block_info_with_block_size(
+                    Cursor::new(&resource_fork),
+                    uncompressed_size,
+                    BLOCK_SIZE,
+                )
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+                ),
+                "{kind}: {err}"
+            );
+        }
+    }
+}
+
+#[test]
+fn tiny_fork() {
+    for kind in supported_kinds() {
+        for len in [0, 1, 4, kind.trailer_size().saturating_sub(1)] {
+            let resource_fork = vec![0; len as usize];
+            let result = kind.read_block_info_with_block_size(
+                Cursor::new(&resource_fork),
+                BLOCK_SIZE,
+                BLOCK_SIZE,
+            );
+            assert!(result.is_err(), "{kind}: accepted {len} byte resource fork");
+        }
+    }
+}
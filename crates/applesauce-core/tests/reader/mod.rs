@@ -20,17 +20,26 @@ fn invalid_magic() {
 }
 
 fn round_trip(kind: Kind, uncompressed_data: &[u8]) {
+    round_trip_with_block_size(kind, uncompressed_data, applesauce_core::BLOCK_SIZE);
+}
+
+fn round_trip_with_block_size(kind: Kind, uncompressed_data: &[u8], block_size: usize) {
     let mut compressor = kind.compressor().unwrap();
 
     let mut resource_fork = Vec::new();
-    let mut writer = applesauce_core::writer::Writer::new(kind, uncompressed_data.len() as u64, {
-        let rfork_ref = &mut resource_fork;
-        move || Cursor::new(rfork_ref)
-    })
+    let mut writer = applesauce_core::writer::Writer::with_block_size(
+        kind,
+        uncompressed_data.len() as u64,
+        block_size as u64,
+        {
+            let rfork_ref = &mut resource_fork;
+            move || Cursor::new(rfork_ref)
+        },
+    )
     .unwrap();
 
-    let mut compressed_block = vec![0; applesauce_core::BLOCK_SIZE * 2];
-    for block in uncompressed_data.chunks(applesauce_core::BLOCK_SIZE) {
+    let mut compressed_block = vec![0; block_size * 2];
+    for block in uncompressed_data.chunks(block_size) {
         let len = compressor
             .compress(&mut compressed_block, block, 5)
             .unwrap();
@@ -40,18 +49,29 @@ fn round_trip(kind: Kind, uncompressed_data: &[u8]) {
     let mut decmpfs_data = Vec::new();
     writer.finish_decmpfs_data(&mut decmpfs_data).unwrap();
 
-    let mut reader = Reader::new(&decmpfs_data, || Cursor::new(&resource_fork)).unwrap();
+    let open_reader = || {
+        Reader::with_block_size(&decmpfs_data, block_size as u64, || {
+            Cursor::new(&resource_fork)
+        })
+        .unwrap()
+    };
+    let mut reader = open_reader();
 
-    assert_eq!(reader.compression_kind(), kind);
+    assert_eq!(reader.compression_kind(), Some(kind));
+    let value = decmpfs::Value::from_data(&decmpfs_data).unwrap();
+    assert_eq!(reader.compression_type(), value.compression_type);
+    assert_eq!(reader.uncompressed_size(), uncompressed_data.len() as u64);
     assert_eq!(
         reader.remaining_blocks(),
-        applesauce_core::num_blocks(uncompressed_data.len() as u64) as usize
+        applesauce_core::num_blocks_with_size(uncompressed_data.len() as u64, block_size as u64)
+            as usize
     );
 
+    let mut blocks = Vec::new();
     let mut clear_data = Vec::new();
     // Need an extra byte, because lzfse/lzvn needs at least one extra byte to differentiate between
     // finishing on the last byte and running out of space
-    let mut clear_buf = vec![0; applesauce_core::BLOCK_SIZE + 1];
+    let mut clear_buf = vec![0; block_size + 1];
 
     loop {
         compressed_block.clear();
@@ -61,24 +81,61 @@ fn round_trip(kind: Kind, uncompressed_data: &[u8]) {
         let len = compressor
             .decompress(&mut clear_buf, &compressed_block)
             .unwrap();
-        let expected_len = if reader.remaining_blocks() != 0
-            || uncompressed_data.len() % applesauce_core::BLOCK_SIZE == 0
-        {
-            applesauce_core::BLOCK_SIZE
-        } else {
-            uncompressed_data.len() % applesauce_core::BLOCK_SIZE
-        };
+        let expected_len =
+            if reader.remaining_blocks() != 0 || uncompressed_data.len() % block_size == 0 {
+                block_size
+            } else {
+                uncompressed_data.len() % block_size
+            };
         assert_eq!(len, expected_len);
         clear_data.extend_from_slice(&clear_buf[..len]);
+        blocks.push(compressed_block.clone());
     }
     assert_eq!(clear_data, uncompressed_data);
+
+    // Iterating gives the same blocks as reading them one at a time
+    let iter = open_reader().into_blocks();
+    assert_eq!(iter.len(), blocks.len());
+    let iterated: Vec<Vec<u8>> = iter.collect::<Result<_, _>>().unwrap();
+    assert_eq!(iterated, blocks);
+}
+
+#[test]
+fn blocks_after_reading() {
+    use rand::RngCore;
+
+    // Random, so it's too large to store in the xattr
+    let mut data = vec![0; 3 * applesauce_core::BLOCK_SIZE];
+    rand::thread_rng().fill_bytes(&mut data);
+    let artifacts =
+        applesauce_core::compress_stream(Kind::default(), 5, data.len() as u64, &data[..]).unwrap();
+    let resource_fork = artifacts.resource_fork.unwrap();
+    let open_reader = || Reader::new(&artifacts.decmpfs_xattr, || Cursor::new(&resource_fork));
+
+    let mut first = Vec::new();
+    let mut reader = open_reader().unwrap();
+    assert!(reader.read_block_into(&mut first).unwrap());
+
+    // Iteration continues from the next unread block
+    let mut blocks = reader.into_blocks();
+    assert_eq!(blocks.len(), 2);
+    let rest: Vec<Vec<u8>> = blocks.by_ref().collect::<Result<_, _>>().unwrap();
+    assert!(blocks.next().is_none());
+
+    let all: Vec<Vec<u8>> = open_reader()
+        .unwrap()
+        .into_blocks()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(all[0], first);
+    assert_eq!(all[1..], rest);
 }
 
 macro_rules! round_trip_tests {
     ($($name:ident),* $(,)?) => {
         $(
             mod $name {
-                use super::round_trip;
+                use super::{round_trip, round_trip_with_block_size};
                 use applesauce_core::compressor::Compressor;
 
                 #[test]
@@ -107,6 +164,20 @@ macro_rules! round_trip_tests {
 
                     round_trip(Compressor::$name().kind(), &data);
                 }
+
+                #[test]
+                fn round_trip_other_block_sizes() {
+                    use rand::RngCore;
+
+                    let kind = Compressor::$name().kind();
+                    let mut data = vec![1; 3 * 1024 * 1024 + 5];
+                    rand::thread_rng().fill_bytes(&mut data[..100 * 1024]);
+                    for block_size in [4 * 1024, 1024 * 1024] {
+                        round_trip_with_block_size(kind, &data, block_size);
+                        round_trip_with_block_size(kind, &data[..block_size], block_size);
+                        round_trip_with_block_size(kind, &data[..block_size + 1], block_size);
+                    }
+                }
             }
         )*
     };
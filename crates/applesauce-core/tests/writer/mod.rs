@@ -1,7 +1,7 @@
 use applesauce_core::compressor::Kind;
-use applesauce_core::writer::Writer;
+use applesauce_core::writer::{Open, Writer};
 use applesauce_core::{decmpfs, BLOCK_SIZE};
-use std::io::Cursor;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 fn never_called_open() -> Cursor<Vec<u8>> {
     panic!("Should not be called");
@@ -137,3 +137,223 @@ fn multiple_small_blocks() {
         compressed_block
     );
 }
+
+fn write_blocks_in_order(kind: Kind, uncompressed_size: u64, blocks: &[Vec<u8>]) -> Vec<u8> {
+    let mut resource_fork = Cursor::new(Vec::new());
+    let rfork_ref = &mut resource_fork;
+    let mut writer = Writer::new(kind, uncompressed_size, move || rfork_ref).unwrap();
+    for block in blocks {
+        writer.add_block(block).unwrap();
+    }
+    writer.finish_decmpfs_data(&mut Vec::new()).unwrap();
+    resource_fork.into_inner()
+}
+
+#[test]
+fn out_of_order_blocks() {
+    let block_count = 5;
+    let uncompressed_size = block_count as u64 * BLOCK_SIZE as u64;
+    let blocks: Vec<Vec<u8>> = (0..block_count)
+        .map(|i| vec![i as u8 + 1; 10 + i * 7])
+        .collect();
+    let orders: &[&[usize]] = &[
+        &[0, 1, 2, 3, 4],
+        &[4, 3, 2, 1, 0],
+        &[1, 0, 3, 2, 4],
+        &[0, 1, 4, 2, 3],
+        &[2, 4, 0, 3, 1],
+    ];
+
+    for &kind in Kind::all().iter().filter(|kind| kind.supported()) {
+        let expected = write_blocks_in_order(kind, uncompressed_size, &blocks);
+        for &order in orders {
+            let mut resource_fork = Cursor::new(Vec::new());
+            let rfork_ref = &mut resource_fork;
+            let mut writer = Writer::new(kind, uncompressed_size, move || rfork_ref).unwrap();
+            for &i in order {
+                writer.add_block_at(i as u64, &blocks[i]).unwrap();
+            }
+            let mut decmpfs_data = Vec::new();
+            writer.finish_decmpfs_data(&mut decmpfs_data).unwrap();
+            let resource_fork = resource_fork.into_inner();
+            assert_eq!(resource_fork, expected, "{kind} with order {order:?}");
+
+            let block_infos = kind
+                .read_block_info(Cursor::new(&resource_fork), uncompressed_size)
+                .unwrap();
+            let mut offset = kind.header_size(block_count as u64) as u32;
+            for (block_info, block) in block_infos.iter().zip(&blocks) {
+                assert_eq!(
+                    *block_info,
+                    decmpfs::BlockInfo {
+                        offset,
+                        compressed_size: block.len() as u32,
+                    }
+                );
+                assert_eq!(
+                    &resource_fork[offset as usize..][..block.len()],
+                    block.as_slice()
+                );
+                offset += block.len() as u32;
+            }
+        }
+    }
+}
+
+#[test]
+fn out_of_order_single_block() {
+    let kind = Kind::default();
+    let compressed_block = vec![1, 2, 3];
+    let mut writer = Writer::new(kind, 10, || Cursor::new(Vec::new())).unwrap();
+    writer.add_block_at(0, &compressed_block).unwrap();
+
+    let mut decmpfs_data = Vec::new();
+    writer.finish_decmpfs_data(&mut decmpfs_data).unwrap();
+    let value = decmpfs::Value::from_data(&decmpfs_data).unwrap();
+    assert_eq!(value.extra_data, compressed_block);
+}
+
+#[test]
+fn out_of_order_errors() {
+    let kind = Kind::default();
+    let uncompressed_size = 2 * BLOCK_SIZE as u64;
+    let new_writer = || Writer::new(kind, uncompressed_size, || Cursor::new(Vec::new())).unwrap();
+
+    let mut writer = new_writer();
+    writer.add_block_at(1, &[1]).unwrap();
+    assert!(writer.add_block_at(1, &[1]).is_err());
+    assert!(writer.add_block_at(2, &[1]).is_err());
+
+    // Missing block 0
+    assert!(writer.finish_decmpfs_data(&mut Vec::new()).is_err());
+}
+
+/// An in-memory resource fork which fails writes after `write_limit` bytes
+struct FailingFork {
+    data: Cursor<Vec<u8>>,
+    write_limit: usize,
+}
+
+impl FailingFork {
+    fn new(write_limit: usize) -> Self {
+        Self {
+            data: Cursor::new(Vec::new()),
+            write_limit,
+        }
+    }
+}
+
+impl Write for FailingFork {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_limit == 0 {
+            return Err(io::Error::other("injected write failure"));
+        }
+        let len = buf.len().min(self.write_limit);
+        self.write_limit -= len;
+        self.data.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FailingFork {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Seek for FailingFork {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+struct FailingOpen;
+
+impl Open for FailingOpen {
+    type ResourceFork = Cursor<Vec<u8>>;
+
+    fn open_resource_fork(self) -> io::Result<Self::ResourceFork> {
+        Err(io::Error::other("injected open failure"))
+    }
+}
+
+fn assert_poisoned<O: Open>(mut writer: Writer<O>) {
+    let e = writer.add_block(&[1]).unwrap_err();
+    assert!(e.to_string().contains("must be discarded"), "{e}");
+    assert!(writer.force_resource_fork().is_err());
+
+    // No decmpfs data is produced which could describe the incomplete resource fork
+    let mut decmpfs_data = Vec::new();
+    assert!(writer.finish_decmpfs_data(&mut decmpfs_data).is_err());
+    assert!(decmpfs_data.is_empty());
+}
+
+#[test]
+fn failure_moving_to_resource_fork() {
+    let kind = Kind::default();
+    let uncompressed_size = BLOCK_SIZE as u64;
+    let large_block = vec![0x1A; decmpfs::MAX_XATTR_DATA_SIZE + 1];
+
+    let mut writer = Writer::new(kind, uncompressed_size, FailingOpen).unwrap();
+    assert!(writer.add_block(&large_block).is_err());
+    assert_poisoned(writer);
+
+    // The write fails part way through the block
+    let mut writer = Writer::new(kind, uncompressed_size, || FailingFork::new(100)).unwrap();
+    assert!(writer.add_block(&large_block).is_err());
+    assert_poisoned(writer);
+
+    let mut writer = Writer::new(kind, uncompressed_size, || FailingFork::new(1)).unwrap();
+    writer.add_block(&[1, 2, 3]).unwrap();
+    assert!(writer.force_resource_fork().is_err());
+    assert_poisoned(writer);
+}
+
+#[test]
+fn failure_writing_block() {
+    let kind = Kind::default();
+    let uncompressed_size = 3 * BLOCK_SIZE as u64;
+    let block = vec![0x1A; 100];
+
+    let mut writer = Writer::new(kind, uncompressed_size, || FailingFork::new(150)).unwrap();
+    writer.add_block(&block).unwrap();
+    assert!(writer.add_block(&block).is_err());
+    assert_poisoned(writer);
+
+    let mut writer = Writer::new(kind, uncompressed_size, || FailingFork::new(150)).unwrap();
+    writer.add_block(&block).unwrap();
+    assert!(writer.add_block(&block).is_err());
+    // Adding a block out of order doesn't recover either
+    assert!(writer.add_block_at(2, &block).is_err());
+    assert_poisoned(writer);
+}
+
+#[test]
+fn failure_finishing() {
+    let kind = Kind::default();
+    let uncompressed_size = 2 * BLOCK_SIZE as u64;
+    let block = vec![0x1A; 100];
+
+    // Enough for the blocks, but not for the block table written when finishing
+    let mut writer = Writer::new(kind, uncompressed_size, || FailingFork::new(200)).unwrap();
+    writer.add_block(&block).unwrap();
+    writer.add_block(&block).unwrap();
+
+    let mut decmpfs_data = Vec::new();
+    assert!(writer.finish_decmpfs_data(&mut decmpfs_data).is_err());
+    assert!(decmpfs_data.is_empty());
+}
+
+#[test]
+fn invalid_block_poisons() {
+    let kind = Kind::default();
+    let uncompressed_size = 2 * BLOCK_SIZE as u64;
+    let mut writer = Writer::new(kind, uncompressed_size, || Cursor::new(Vec::new())).unwrap();
+    writer.add_block_at(0, &[1]).unwrap();
+    assert!(writer.add_block_at(0, &[1]).is_err());
+    assert!(writer.add_block_at(1, &[1]).is_err());
+    assert_poisoned(writer);
+}
@@ -0,0 +1,16 @@
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("cbindgen.toml should be valid");
+    // The checked in include/applesauce.h is compared against this by the tests
+    cbindgen::generate_with_config(&crate_dir, config)
+        .expect("unable to generate applesauce.h")
+        .write_to_file(out_dir.join("applesauce.h"));
+}
@@ -0,0 +1,71 @@
+// Compresses a file through the C interface from Swift, and checks it reads back the same
+//
+// Usage: roundtrip-swift <file>. Build and run with run.sh.
+
+import Foundation
+
+func lastError() -> String {
+    applesauce_last_error().map { String(cString: $0) } ?? "unknown error"
+}
+
+func fileInfo(_ path: String) -> ApplesauceFileInfo {
+    var info = ApplesauceFileInfo()
+    guard applesauce_file_info(path, &info) == APPLESAUCE_STATUS_OK else {
+        fatalError("applesauce_file_info: \(lastError())")
+    }
+    return info
+}
+
+final class Processed {
+    private let lock = NSLock()
+    private var bytes: UInt64 = 0
+
+    func add(_ amount: UInt64) {
+        lock.lock()
+        defer { lock.unlock() }
+        bytes += amount
+    }
+
+    var total: UInt64 {
+        lock.lock()
+        defer { lock.unlock() }
+        return bytes
+    }
+}
+
+let arguments = CommandLine.arguments
+guard arguments.count == 2 else {
+    FileHandle.standardError.write("usage: \(arguments[0]) <file>\n".data(using: .utf8)!)
+    exit(2)
+}
+let path = arguments[1]
+let before = FileManager.default.contents(atPath: path)!
+
+let processed = Processed()
+var progress = ApplesauceProgress(
+    increment: { ctx, bytes in
+        Unmanaged<Processed>.fromOpaque(ctx!).takeUnretainedValue().add(bytes)
+    },
+    error: { _, path, message in
+        print("\(String(cString: path!)): \(String(cString: message!))")
+    }
+)
+let ctx = Unmanaged.passUnretained(processed).toOpaque()
+let status = withExtendedLifetime(processed) {
+    applesauce_compress_path(
+        path, UInt8(APPLESAUCE_KIND_LZFSE), 5, 0.95, true, &progress, ctx)
+}
+guard status == APPLESAUCE_STATUS_OK else {
+    fatalError("applesauce_compress_path: \(lastError())")
+}
+
+let info = fileInfo(path)
+print("compressed: \(info.is_compressed), type \(info.compression_type), "
+    + "\(processed.total) bytes processed, \(info.data_on_disk_size) bytes on disk")
+guard info.is_compressed, info.size == UInt64(before.count) else {
+    fatalError("file was not compressed")
+}
+guard FileManager.default.contents(atPath: path) == before else {
+    fatalError("contents changed")
+}
+print("ok")
@@ -0,0 +1,89 @@
+// Compresses a file through the C interface, and checks it reads back the same
+//
+// Usage: roundtrip <file>. Build and run with run.sh.
+
+#include <stdatomic.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "applesauce.h"
+
+static void on_increment(void *ctx, uint64_t bytes) {
+    atomic_fetch_add((_Atomic uint64_t *)ctx, bytes);
+}
+
+static void on_error(void *ctx, const char *path, const char *message) {
+    (void)ctx;
+    fprintf(stderr, "%s: %s\n", path, message);
+}
+
+static char *read_all(const char *path, size_t *len) {
+    FILE *f = fopen(path, "rb");
+    if (f == NULL) {
+        perror(path);
+        exit(1);
+    }
+    size_t cap = 1 << 16;
+    char *buf = malloc(cap);
+    *len = 0;
+    size_t n;
+    while ((n = fread(buf + *len, 1, cap - *len, f)) > 0) {
+        *len += n;
+        if (*len == cap) {
+            cap *= 2;
+            buf = realloc(buf, cap);
+        }
+    }
+    fclose(f);
+    return buf;
+}
+
+static ApplesauceFileInfo file_info(const char *path) {
+    ApplesauceFileInfo info;
+    if (applesauce_file_info(path, &info) != APPLESAUCE_STATUS_OK) {
+        fprintf(stderr, "applesauce_file_info: %s\n", applesauce_last_error());
+        exit(1);
+    }
+    return info;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s <file>\n", argv[0]);
+        return 2;
+    }
+    const char *path = argv[1];
+
+    size_t before_len;
+    char *before = read_all(path, &before_len);
+
+    _Atomic uint64_t processed = 0;
+    ApplesauceProgress progress = {.increment = on_increment, .error = on_error};
+    ApplesauceStatus status = applesauce_compress_path(path, APPLESAUCE_KIND_LZFSE, 5, 0.95,
+                                                       true, &progress, (void *)&processed);
+    if (status != APPLESAUCE_STATUS_OK) {
+        fprintf(stderr, "applesauce_compress_path: %s\n", applesauce_last_error());
+        return 1;
+    }
+
+    ApplesauceFileInfo info = file_info(path);
+    printf("compressed: %s, type %u, %llu bytes processed, %llu bytes on disk\n",
+           info.is_compressed ? "yes" : "no", info.compression_type,
+           (unsigned long long)processed, (unsigned long long)info.data_on_disk_size);
+    if (!info.is_compressed || info.size != before_len) {
+        fprintf(stderr, "file was not compressed\n");
+        return 1;
+    }
+
+    size_t after_len;
+    char *after = read_all(path, &after_len);
+    if (after_len != before_len || memcmp(before, after, before_len) != 0) {
+        fprintf(stderr, "contents changed\n");
+        return 1;
+    }
+    free(before);
+    free(after);
+    printf("ok\n");
+    return 0;
+}
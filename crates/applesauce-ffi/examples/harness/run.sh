@@ -0,0 +1,36 @@
+#!/bin/sh
+# Build the C interface, then compress a file from C and from Swift, checking it round trips
+#
+# Must be run on macOS. Run from anywhere in the repository.
+
+set -eu
+
+here=$(cd "$(dirname "$0")" && pwd)
+crate=$here/../..
+workdir=$(mktemp -d)
+trap 'rm -rf "$workdir"' EXIT
+
+cargo build --manifest-path "$crate/Cargo.toml"
+target_dir=$(cargo metadata --manifest-path "$crate/Cargo.toml" --format-version 1 --no-deps \
+    | sed -n 's/.*"target_directory":"\([^"]*\)".*/\1/p')
+lib=$target_dir/debug/libapplesauce_ffi.a
+
+# Compressible, and larger than one block
+sample() {
+    for _ in $(seq 2000); do
+        echo "the quick brown fox jumps over the lazy dog"
+    done > "$1"
+}
+
+cc -std=c11 -Wall -Wextra -I "$crate/include" "$here/roundtrip.c" "$lib" -o "$workdir/roundtrip"
+sample "$workdir/c-sample"
+"$workdir/roundtrip" "$workdir/c-sample"
+
+if command -v swiftc > /dev/null; then
+    swiftc -import-objc-header "$crate/include/applesauce.h" "$here/main.swift" "$lib" \
+        -o "$workdir/roundtrip-swift"
+    sample "$workdir/swift-sample"
+    "$workdir/roundtrip-swift" "$workdir/swift-sample"
+else
+    echo "swiftc not found, Swift harness not run" >&2
+fi
@@ -0,0 +1,119 @@
+#ifndef APPLESAUCE_H
+#define APPLESAUCE_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Compress with zlib (deflate)
+#define APPLESAUCE_KIND_ZLIB 0
+
+// Compress with LZVN
+#define APPLESAUCE_KIND_LZVN 1
+
+// Compress with LZFSE
+#define APPLESAUCE_KIND_LZFSE 2
+
+// The result of a call
+typedef enum ApplesauceStatus {
+  // Every file was processed (or skipped)
+  APPLESAUCE_STATUS_OK = 0,
+  // An argument was invalid, nothing was done
+  APPLESAUCE_STATUS_INVALID_ARGUMENT = 1,
+  // Completed, but some files or paths had errors
+  APPLESAUCE_STATUS_FILE_ERRORS = 2,
+  // The call failed, or stopped before every file was processed
+  APPLESAUCE_STATUS_FAILED = 3,
+  // applesauce panicked, the call may not have completed
+  APPLESAUCE_STATUS_PANICKED = 4,
+} ApplesauceStatus;
+
+// Callbacks for the progress of compressing or decompressing
+//
+// Callbacks are called from applesauce's threads, possibly from several at once, and must not
+// unwind. Either may be null.
+typedef struct ApplesauceProgress {
+  // Called as each file is processed, with the number of bytes of it just processed
+  void (*increment)(void *ctx, uint64_t bytes);
+  // Called with the path and a description of each error
+  //
+  // Both strings are only valid until the callback returns.
+  void (*error)(void *ctx, const char *path, const char *message);
+} ApplesauceProgress;
+
+// The compression of a single file
+typedef struct ApplesauceFileInfo {
+  // The file is marked compressed
+  bool is_compressed;
+  // The file's contents are not stored locally (e.g. evicted by iCloud Drive)
+  bool is_dataless;
+  // The size of the file's contents (uncompressed)
+  uint64_t size;
+  // The space used on disk by the data fork
+  uint64_t data_on_disk_size;
+  // The space used on disk by the resource fork, or 0 if the file has no resource fork
+  uint64_t resource_fork_on_disk_size;
+  // The length of the resource fork, or 0 if the file has no resource fork
+  uint64_t resource_fork_size;
+  // The number of xattrs, not counting the resource fork or decmpfs xattrs
+  uint32_t xattr_count;
+  // The total size of xattrs, not counting the resource fork or decmpfs xattrs
+  uint64_t xattr_size;
+  // The compression type from the decmpfs xattr, or 0 if it has none (or it's invalid)
+  uint32_t compression_type;
+  // The size of the decmpfs xattr, or 0 if it has none
+  uint64_t decmpfs_size;
+} ApplesauceFileInfo;
+
+// Describes why the last call on this thread which didn't succeed failed, or null
+//
+// The string is valid until the next call to an applesauce function on the same thread.
+const char *applesauce_last_error(void);
+
+// Compress every file under `path`
+//
+// `kind` is one of the `APPLESAUCE_KIND_*` constants. `level` is only used by zlib, from 1 to
+// 9. Files which compress to more than `min_ratio` of their size are skipped. If `verify`,
+// each compressed file is checked against the original before replacing it.
+//
+// Returns once every file has been processed. Progress is reported to `progress` (which may be
+// null), called with `ctx`.
+//
+// # Safety
+//
+// `path` must be a valid null terminated string. `progress` must be null, or point to a valid
+// `ApplesauceProgress`, whose callbacks are safe to call with `ctx` from any thread until this
+// returns.
+ApplesauceStatus applesauce_compress_path(const char *path,
+                                          uint8_t kind,
+                                          uint32_t level,
+                                          double min_ratio,
+                                          bool verify,
+                                          const ApplesauceProgress *progress,
+                                          void *ctx);
+
+// Decompress every file under `path`
+//
+// If `manual`, applesauce decompresses the data itself, rather than reading it through the OS.
+// Otherwise, like [`applesauce_compress_path`].
+//
+// # Safety
+//
+// As for [`applesauce_compress_path`].
+ApplesauceStatus applesauce_decompress_path(const char *path,
+                                            bool manual,
+                                            bool verify,
+                                            const ApplesauceProgress *progress,
+                                            void *ctx);
+
+// Get the compression of the file at `path`, into `out`
+//
+// # Safety
+//
+// `path` must be a valid null terminated string, and `out` must point to an
+// `ApplesauceFileInfo` which can be written to.
+ApplesauceStatus applesauce_file_info(const char *path, ApplesauceFileInfo *out);
+
+#endif /* APPLESAUCE_H */
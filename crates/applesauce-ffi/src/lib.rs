@@ -0,0 +1,574 @@
+//! A C interface to applesauce, for calling it from other languages (e.g. Swift)
+//!
+//! The header is `include/applesauce.h`. It is generated by cbindgen when this crate is built,
+//! and the tests check the checked in copy is up to date. Link with the static or dynamic library
+//! built from this crate.
+//!
+//! Every function catches panics, and returns an [`ApplesauceStatus`]. When a function doesn't
+//! return [`ApplesauceStatus::Ok`], [`applesauce_last_error`] describes why.
+#![warn(unsafe_op_in_unsafe_fn)]
+#![warn(clippy::undocumented_unsafe_blocks)]
+
+use applesauce::compressor::Kind;
+use applesauce::progress::{Progress, Task};
+use applesauce::{info, CompressionOptions, DecompressionOptions, FileCompressor, Stats, Verify};
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+
+/// Compress with zlib (deflate)
+pub const APPLESAUCE_KIND_ZLIB: u8 = 0;
+/// Compress with LZVN
+pub const APPLESAUCE_KIND_LZVN: u8 = 1;
+/// Compress with LZFSE
+pub const APPLESAUCE_KIND_LZFSE: u8 = 2;
+
+/// The result of a call
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ApplesauceStatus {
+    /// Every file was processed (or skipped)
+    Ok = 0,
+    /// An argument was invalid, nothing was done
+    InvalidArgument = 1,
+    /// Completed, but some files or paths had errors
+    FileErrors = 2,
+    /// The call failed, or stopped before every file was processed
+    Failed = 3,
+    /// applesauce panicked, the call may not have completed
+    Panicked = 4,
+}
+
+/// Callbacks for the progress of compressing or decompressing
+///
+/// Callbacks are called from applesauce's threads, possibly from several at once, and must not
+/// unwind. Either may be null.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ApplesauceProgress {
+    /// Called as each file is processed, with the number of bytes of it just processed
+    pub increment: Option<unsafe extern "C" fn(ctx: *mut c_void, bytes: u64)>,
+    /// Called with the path and a description of each error
+    ///
+    /// Both strings are only valid until the callback returns.
+    pub error:
+        Option<unsafe extern "C" fn(ctx: *mut c_void, path: *const c_char, message: *const c_char)>,
+}
+
+/// The compression of a single file
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ApplesauceFileInfo {
+    /// The file is marked compressed
+    pub is_compressed: bool,
+    /// The file's contents are not stored locally (e.g. evicted by iCloud Drive)
+    pub is_dataless: bool,
+    /// The size of the file's contents (uncompressed)
+    pub size: u64,
+    /// The space used on disk by the data fork
+    pub data_on_disk_size: u64,
+    /// The space used on disk by the resource fork, or 0 if the file has no resource fork
+    pub resource_fork_on_disk_size: u64,
+    /// The length of the resource fork, or 0 if the file has no resource fork
+    pub resource_fork_size: u64,
+    /// The number of xattrs, not counting the resource fork or decmpfs xattrs
+    pub xattr_count: u32,
+    /// The total size of xattrs, not counting the resource fork or decmpfs xattrs
+    pub xattr_size: u64,
+    /// The compression type from the decmpfs xattr, or 0 if it has none (or it's invalid)
+    pub compression_type: u32,
+    /// The size of the decmpfs xattr, or 0 if it has none
+    pub decmpfs_size: u64,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Describes why the last call on this thread which didn't succeed failed, or null
+///
+/// The string is valid until the next call to an applesauce function on the same thread.
+#[no_mangle]
+pub extern "C" fn applesauce_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+struct Error {
+    status: ApplesauceStatus,
+    message: String,
+}
+
+impl Error {
+    fn new(status: ApplesauceStatus, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ApplesauceStatus::InvalidArgument, message)
+    }
+}
+
+/// Any interior NUL is escaped, C can't see past it
+fn c_string(s: impl Into<Vec<u8>>) -> CString {
+    CString::new(s).unwrap_or_else(|e| {
+        let escaped = e.into_vec().escape_ascii().to_string();
+        CString::new(escaped).unwrap()
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Run `f`, recording its error (or panic) as the last error
+fn guard(f: impl FnOnce() -> Result<(), Error>) -> ApplesauceStatus {
+    let (status, message) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => (ApplesauceStatus::Ok, None),
+        Ok(Err(e)) => (e.status, Some(e.message)),
+        Err(payload) => (
+            ApplesauceStatus::Panicked,
+            Some(format!("applesauce panicked: {}", panic_message(&*payload))),
+        ),
+    };
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message.map(c_string));
+    status
+}
+
+/// # Safety
+///
+/// `path` must be null, or a valid null terminated string which outlives `'a`
+unsafe fn path_arg<'a>(path: *const c_char) -> Result<&'a Path, Error> {
+    if path.is_null() {
+        return Err(Error::invalid("path is null"));
+    }
+    // SAFETY: path is not null, and the caller guarantees it's valid
+    let path = unsafe { CStr::from_ptr(path) };
+    Ok(Path::new(OsStr::from_bytes(path.to_bytes())))
+}
+
+fn kind_arg(kind: u8) -> Result<Kind, Error> {
+    Kind::all()
+        .iter()
+        .copied()
+        .find(|&k| k as u8 == kind)
+        .ok_or_else(|| Error::invalid(format!("unknown compression kind {kind}")))
+}
+
+fn verify_arg(verify: bool) -> Verify {
+    if verify {
+        Verify::Checksum
+    } else {
+        Verify::Off
+    }
+}
+
+/// The callbacks of an [`ApplesauceProgress`], and the context passed to them
+struct Callbacks {
+    progress: ApplesauceProgress,
+    ctx: *mut c_void,
+}
+
+// SAFETY: the caller of each function taking callbacks guarantees they can be called from any
+// thread, with the context they passed
+unsafe impl Send for Callbacks {}
+// SAFETY: as above, including from several threads at once
+unsafe impl Sync for Callbacks {}
+
+impl Callbacks {
+    fn increment(&self, bytes: u64) {
+        if let Some(increment) = self.progress.increment {
+            // SAFETY: the caller guarantees the callback is valid with this context
+            unsafe { increment(self.ctx, bytes) };
+        }
+    }
+
+    fn error(&self, path: &Path, message: &str) {
+        if let Some(error) = self.progress.error {
+            let path = c_string(path.as_os_str().as_bytes());
+            let message = c_string(message);
+            // SAFETY: the caller guarantees the callback is valid with this context, the strings
+            // outlive the call
+            unsafe { error(self.ctx, path.as_ptr(), message.as_ptr()) };
+        }
+    }
+}
+
+struct CallbackProgress(Arc<Callbacks>);
+
+impl CallbackProgress {
+    /// # Safety
+    ///
+    /// `progress` must be null, or point to a valid `ApplesauceProgress`. Its callbacks must be
+    /// safe to call with `ctx` from any thread, until the call using them returns.
+    unsafe fn new(progress: *const ApplesauceProgress, ctx: *mut c_void) -> Self {
+        // SAFETY: the caller guarantees progress is null or valid
+        let progress = unsafe { progress.as_ref() }
+            .copied()
+            .unwrap_or(ApplesauceProgress {
+                increment: None,
+                error: None,
+            });
+        Self(Arc::new(Callbacks { progress, ctx }))
+    }
+}
+
+impl Progress for CallbackProgress {
+    type Task = CallbackTask;
+
+    fn error(&self, path: &Path, message: &str) {
+        self.0.error(path, message);
+    }
+
+    fn file_task(&self, path: &Path, _size: u64, _root: usize) -> Self::Task {
+        CallbackTask {
+            callbacks: Arc::clone(&self.0),
+            path: path.to_owned(),
+        }
+    }
+}
+
+struct CallbackTask {
+    callbacks: Arc<Callbacks>,
+    path: PathBuf,
+}
+
+impl Task for CallbackTask {
+    fn increment(&self, amt: u64) {
+        self.callbacks.increment(amt);
+    }
+
+    fn error(&self, message: &str) {
+        self.callbacks.error(&self.path, message);
+    }
+}
+
+/// The result of a finished compression or decompression
+fn result_of(stats: &Stats) -> Result<(), Error> {
+    if let Some(fatal_error) = &stats.fatal_error {
+        return Err(Error::new(ApplesauceStatus::Failed, fatal_error.as_str()));
+    }
+    let total = stats.errors.total();
+    let Some(first) = stats.errors.entries().into_iter().next() else {
+        return Ok(());
+    };
+    let mut message = format!("{}: {}", first.path.display(), first.message);
+    if total > 1 {
+        message.push_str(&format!(" (and {} more errors)", total - 1));
+    }
+    Err(Error::new(ApplesauceStatus::FileErrors, message))
+}
+
+/// Compress every file under `path`
+///
+/// `kind` is one of the `APPLESAUCE_KIND_*` constants. `level` is only used by zlib, from 1 to
+/// 9. Files which compress to more than `min_ratio` of their size are skipped. If `verify`,
+/// each compressed file is checked against the original before replacing it.
+///
+/// Returns once every file has been processed. Progress is reported to `progress` (which may be
+/// null), called with `ctx`.
+///
+/// # Safety
+///
+/// `path` must be a valid null terminated string. `progress` must be null, or point to a valid
+/// `ApplesauceProgress`, whose callbacks are safe to call with `ctx` from any thread until this
+/// returns.
+#[no_mangle]
+pub unsafe extern "C" fn applesauce_compress_path(
+    path: *const c_char,
+    kind: u8,
+    level: u32,
+    min_ratio: f64,
+    verify: bool,
+    progress: *const ApplesauceProgress,
+    ctx: *mut c_void,
+) -> ApplesauceStatus {
+    guard(|| {
+        // SAFETY: the caller guarantees path is valid
+        let path = unsafe { path_arg(path) }?;
+        let options = CompressionOptions::new()
+            .kind(kind_arg(kind)?)
+            .level(level)
+            .minimum_ratio(min_ratio)
+            .verify(verify_arg(verify));
+        // SAFETY: the caller guarantees progress and ctx are valid
+        let progress = unsafe { CallbackProgress::new(progress, ctx) };
+        let stats = FileCompressor::new()
+            .compress_with([path], &options, &progress)
+            .map_err(|e| Error::invalid(e.to_string()))?;
+        result_of(&stats)
+    })
+}
+
+/// Decompress every file under `path`
+///
+/// If `manual`, applesauce decompresses the data itself, rather than reading it through the OS.
+/// Otherwise, like [`applesauce_compress_path`].
+///
+/// # Safety
+///
+/// As for [`applesauce_compress_path`].
+#[no_mangle]
+pub unsafe extern "C" fn applesauce_decompress_path(
+    path: *const c_char,
+    manual: bool,
+    verify: bool,
+    progress: *const ApplesauceProgress,
+    ctx: *mut c_void,
+) -> ApplesauceStatus {
+    guard(|| {
+        // SAFETY: the caller guarantees path is valid
+        let path = unsafe { path_arg(path) }?;
+        let options = DecompressionOptions::new()
+            .manual(manual)
+            .verify(verify_arg(verify));
+        // SAFETY: the caller guarantees progress and ctx are valid
+        let progress = unsafe { CallbackProgress::new(progress, ctx) };
+        let stats = FileCompressor::new().decompress_with([path], &options, &progress);
+        result_of(&stats)
+    })
+}
+
+/// Get the compression of the file at `path`, into `out`
+///
+/// # Safety
+///
+/// `path` must be a valid null terminated string, and `out` must point to an
+/// `ApplesauceFileInfo` which can be written to.
+#[no_mangle]
+pub unsafe extern "C" fn applesauce_file_info(
+    path: *const c_char,
+    out: *mut ApplesauceFileInfo,
+) -> ApplesauceStatus {
+    guard(|| {
+        // SAFETY: the caller guarantees path is valid
+        let path = unsafe { path_arg(path) }?;
+        if out.is_null() {
+            return Err(Error::invalid("out is null"));
+        }
+        let file_info = info::get(path).map_err(|e| {
+            Error::new(ApplesauceStatus::Failed, format!("{}: {e}", path.display()))
+        })?;
+        let decmpfs_info = file_info
+            .decmpfs_info
+            .as_ref()
+            .and_then(|info| info.as_ref().ok());
+        let result = ApplesauceFileInfo {
+            is_compressed: file_info.is_compressed,
+            is_dataless: file_info.is_dataless,
+            size: file_info.stat_size,
+            data_on_disk_size: file_info.data_on_disk_size,
+            resource_fork_on_disk_size: file_info.resource_fork_on_disk_size,
+            resource_fork_size: file_info.resource_fork_size.unwrap_or(0),
+            xattr_count: file_info.xattr_count,
+            xattr_size: file_info.xattr_size,
+            compression_type: decmpfs_info.map_or(0, |info| info.compression_type.raw_type()),
+            decmpfs_size: decmpfs_info.map_or(0, |info| info.attribute_size),
+        };
+        // SAFETY: out is not null, and the caller guarantees it can be written to
+        unsafe { out.write(result) };
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorded {
+        bytes: AtomicU64,
+        errors: Mutex<Vec<(String, String)>>,
+    }
+
+    unsafe extern "C" fn record_increment(ctx: *mut c_void, bytes: u64) {
+        // SAFETY: tests pass a `Recorded` as the context
+        let recorded = unsafe { &*ctx.cast::<Recorded>() };
+        recorded.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    unsafe extern "C" fn record_error(
+        ctx: *mut c_void,
+        path: *const c_char,
+        message: *const c_char,
+    ) {
+        // SAFETY: tests pass a `Recorded` as the context, the strings are valid
+        let (recorded, path, message) = unsafe {
+            (
+                &*ctx.cast::<Recorded>(),
+                CStr::from_ptr(path),
+                CStr::from_ptr(message),
+            )
+        };
+        recorded.errors.lock().unwrap().push((
+            path.to_string_lossy().into_owned(),
+            message.to_string_lossy().into_owned(),
+        ));
+    }
+
+    const PROGRESS: ApplesauceProgress = ApplesauceProgress {
+        increment: Some(record_increment),
+        error: Some(record_error),
+    };
+
+    fn last_error() -> Option<String> {
+        let message = applesauce_last_error();
+        // SAFETY: a non-null last error is a valid string until the next call on this thread
+        (!message.is_null()).then(|| unsafe { CStr::from_ptr(message) }.to_string_lossy().into())
+    }
+
+    fn file_info(path: &CStr) -> ApplesauceFileInfo {
+        let mut info = ApplesauceFileInfo::default();
+        // SAFETY: path is a valid string, info is writable
+        let status = unsafe { applesauce_file_info(path.as_ptr(), &mut info) };
+        assert_eq!(status, ApplesauceStatus::Ok, "{:?}", last_error());
+        info
+    }
+
+    #[test]
+    fn kind_constants() {
+        assert_eq!(kind_arg(APPLESAUCE_KIND_ZLIB).ok(), Some(Kind::Zlib));
+        assert_eq!(kind_arg(APPLESAUCE_KIND_LZVN).ok(), Some(Kind::Lzvn));
+        assert_eq!(kind_arg(APPLESAUCE_KIND_LZFSE).ok(), Some(Kind::Lzfse));
+        assert!(kind_arg(3).is_err());
+    }
+
+    #[test]
+    fn compress_info_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        let contents = vec![7; 256 * 1024];
+        fs::write(&path, &contents).unwrap();
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+        let info = file_info(&c_path);
+        assert!(!info.is_compressed);
+        assert_eq!(info.size, contents.len() as u64);
+
+        let kind = Kind::default();
+        let recorded = Recorded::default();
+        // SAFETY: the path is valid, the callbacks are thread safe, and recorded outlives the call
+        let status = unsafe {
+            applesauce_compress_path(
+                c_path.as_ptr(),
+                kind as u8,
+                5,
+                0.95,
+                true,
+                &PROGRESS,
+                ptr::from_ref(&recorded).cast_mut().cast(),
+            )
+        };
+        assert_eq!(status, ApplesauceStatus::Ok, "{:?}", last_error());
+        assert_eq!(last_error(), None);
+        assert_eq!(
+            recorded.bytes.load(Ordering::Relaxed),
+            contents.len() as u64
+        );
+        assert!(recorded.errors.lock().unwrap().is_empty());
+
+        let info = file_info(&c_path);
+        assert!(info.is_compressed);
+        assert_eq!(info.size, contents.len() as u64);
+        assert_ne!(info.compression_type, 0);
+        assert!(info.decmpfs_size > 0);
+
+        // SAFETY: the path is valid, no callbacks
+        let status = unsafe {
+            applesauce_decompress_path(c_path.as_ptr(), true, true, ptr::null(), ptr::null_mut())
+        };
+        assert_eq!(status, ApplesauceStatus::Ok, "{:?}", last_error());
+        assert!(!file_info(&c_path).is_compressed);
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn errors_reported() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let missing = CString::new(dir.path().join("missing").as_os_str().as_bytes()).unwrap();
+
+        let recorded = Recorded::default();
+        // SAFETY: the path is valid, the callbacks are thread safe, and recorded outlives the call
+        let status = unsafe {
+            applesauce_compress_path(
+                missing.as_ptr(),
+                Kind::default() as u8,
+                5,
+                0.95,
+                false,
+                &PROGRESS,
+                ptr::from_ref(&recorded).cast_mut().cast(),
+            )
+        };
+        assert_eq!(status, ApplesauceStatus::FileErrors);
+        assert!(last_error().unwrap().contains("missing"));
+        assert_eq!(recorded.errors.lock().unwrap().len(), 1);
+
+        // SAFETY: the path is valid, no callbacks
+        let status = unsafe {
+            applesauce_compress_path(
+                missing.as_ptr(),
+                Kind::default() as u8,
+                5,
+                // Invalid ratio
+                -1.0,
+                false,
+                ptr::null(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, ApplesauceStatus::InvalidArgument);
+        assert!(last_error().is_some());
+
+        let mut info = ApplesauceFileInfo::default();
+        // SAFETY: null paths are rejected
+        let status = unsafe { applesauce_file_info(ptr::null(), &mut info) };
+        assert_eq!(status, ApplesauceStatus::InvalidArgument);
+        // SAFETY: the path is valid, info is writable
+        let status = unsafe { applesauce_file_info(missing.as_ptr(), &mut info) };
+        assert_eq!(status, ApplesauceStatus::Failed);
+    }
+
+    #[test]
+    fn panics_caught() {
+        let status = guard(|| panic!("oops"));
+        assert_eq!(status, ApplesauceStatus::Panicked);
+        assert_eq!(last_error().unwrap(), "applesauce panicked: oops");
+
+        // The last error is cleared by the next call
+        assert_eq!(guard(|| Ok(())), ApplesauceStatus::Ok);
+        assert_eq!(last_error(), None);
+    }
+
+    #[test]
+    fn header_up_to_date() {
+        // Copy the generated header over include/applesauce.h when this fails
+        assert_eq!(
+            include_str!(concat!(env!("OUT_DIR"), "/applesauce.h")),
+            include_str!("../include/applesauce.h"),
+            "include/applesauce.h is out of date, regenerated in {}",
+            env!("OUT_DIR")
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Skipping directories of files which don't compress
+//!
+//! Some directories hold nothing but files which are already compressed (e.g. a photo library, or
+//! a folder of videos). Attempting each of them reads and compresses (at least part of) the file,
+//! only to throw the result away. Instead, once enough files have been attempted in a directory,
+//! and nearly all of them failed to compress enough, the rest of the files in that directory are
+//! skipped.
+//!
+//! Files are queued well before they are processed, so this is checked both when a file is found,
+//! and again before it is read.
+
+use crate::progress::SkipReason;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The portion of attempted files (out of 10) which must fail before giving up on a directory
+const GIVE_UP_TENTHS: u32 = 9;
+
+/// When to give up on the files in a directory
+#[derive(Debug, Copy, Clone)]
+pub struct Settings {
+    /// The number of files which must be attempted in a directory before giving up on it
+    pub min_attempts: u32,
+    /// Also skip files in subdirectories of a directory which was given up on
+    pub subdirectories: bool,
+}
+
+#[derive(Debug, Default)]
+struct DirRecord {
+    attempted: u32,
+    failed: u32,
+}
+
+impl DirRecord {
+    fn given_up(&self, settings: Settings) -> bool {
+        self.attempted >= settings.min_attempts
+            && u64::from(self.failed) * 10 >= u64::from(self.attempted) * u64::from(GIVE_UP_TENTHS)
+    }
+}
+
+/// The outcome of compressing files, for each directory
+#[derive(Debug)]
+pub struct DirOutcomes {
+    settings: Option<Settings>,
+    dirs: Mutex<HashMap<PathBuf, DirRecord>>,
+}
+
+impl DirOutcomes {
+    /// Track outcomes with `settings`, `None` (or a minimum of 0 attempts) never skips anything
+    pub fn new(settings: Option<Settings>) -> Self {
+        Self {
+            settings: settings.filter(|settings| settings.min_attempts != 0),
+            dirs: Mutex::default(),
+        }
+    }
+
+    /// Record whether the file at `path` compressed to the minimum compression ratio
+    pub fn record(&self, path: &Path, compressed: bool) {
+        let (Some(_), Some(dir)) = (self.settings, path.parent()) else {
+            return;
+        };
+        let mut dirs = self.dirs.lock().unwrap();
+        let record = dirs.entry(dir.to_owned()).or_default();
+        record.attempted = record.attempted.saturating_add(1);
+        if !compressed {
+            record.failed = record.failed.saturating_add(1);
+        }
+    }
+
+    /// The reason to skip the file at `path`, if its directory has been given up on
+    pub fn skip_reason(&self, path: &Path) -> Option<SkipReason> {
+        let settings = self.settings?;
+        let dir = path.parent()?;
+        let dirs = self.dirs.lock().unwrap();
+        let given_up = |dir: &Path| {
+            dirs.get(dir)
+                .is_some_and(|record| record.given_up(settings))
+        };
+        let skip = if settings.subdirectories {
+            dir.ancestors().any(given_up)
+        } else {
+            given_up(dir)
+        };
+        skip.then_some(SkipReason::DirectoryDeemedIncompressible)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, NoProgress};
+    use crate::{
+        decompress_file, FileCompressor, PipelineConfig, Verify, DEFAULT_ADAPTIVE_SKIP_ATTEMPTS,
+    };
+    use sha2::{Digest, Sha256};
+
+    fn outcomes(subdirectories: bool) -> DirOutcomes {
+        DirOutcomes::new(Some(Settings {
+            min_attempts: 3,
+            subdirectories,
+        }))
+    }
+
+    #[test]
+    fn gives_up_after_min_attempts() {
+        let outcomes = outcomes(false);
+        for name in ["a", "b"] {
+            outcomes.record(&Path::new("/media").join(name), false);
+        }
+        assert!(outcomes.skip_reason(Path::new("/media/z")).is_none());
+        outcomes.record(Path::new("/media/c"), false);
+        assert!(matches!(
+            outcomes.skip_reason(Path::new("/media/z")),
+            Some(SkipReason::DirectoryDeemedIncompressible)
+        ));
+        // Other directories, including subdirectories, are unaffected
+        assert!(outcomes.skip_reason(Path::new("/other/z")).is_none());
+        assert!(outcomes.skip_reason(Path::new("/media/sub/z")).is_none());
+    }
+
+    #[test]
+    fn compressible_directory_kept() {
+        let outcomes = outcomes(false);
+        outcomes.record(Path::new("/mixed/text"), true);
+        for i in 0..8 {
+            outcomes.record(&Path::new("/mixed").join(i.to_string()), false);
+        }
+        assert!(outcomes.skip_reason(Path::new("/mixed/z")).is_none());
+        // Once 90% of attempts have failed, the directory is given up on
+        outcomes.record(Path::new("/mixed/9"), false);
+        assert!(outcomes.skip_reason(Path::new("/mixed/z")).is_some());
+    }
+
+    #[test]
+    fn subdirectories() {
+        let outcomes = outcomes(true);
+        for name in ["a", "b", "c"] {
+            outcomes.record(&Path::new("/media").join(name), false);
+        }
+        assert!(outcomes.skip_reason(Path::new("/media/sub/z")).is_some());
+        assert!(outcomes.skip_reason(Path::new("/other/z")).is_none());
+    }
+
+    #[test]
+    fn disabled() {
+        let outcomes = DirOutcomes::new(None);
+        for name in ["a", "b", "c", "d"] {
+            outcomes.record(&Path::new("/media").join(name), false);
+        }
+        assert!(outcomes.skip_reason(Path::new("/media/z")).is_none());
+    }
+
+    #[test]
+    fn adaptive_skip() {
+        let dir = Fixture::new();
+        for i in 0..100u32 {
+            let contents: Vec<u8> = (0..512u32)
+                .flat_map(|block| Sha256::digest([i.to_le_bytes(), block.to_le_bytes()].concat()))
+                .collect();
+            dir.file(format!("random{i:03}"), contents);
+        }
+        let text: String = (0..).map(|i| format!("line {i}\n")).take(2048).collect();
+        for i in 0..3 {
+            dir.file(format!("zz-text{i}"), &text);
+        }
+
+        let compress = |adaptive_skip: Option<u32>| {
+            let one = std::num::NonZeroUsize::MIN;
+            let mut fc = FileCompressor::with_config(PipelineConfig {
+                reader_threads: one,
+                writer_threads: one,
+                ..PipelineConfig::default()
+            });
+            fc.set_adaptive_skip(adaptive_skip);
+            dir.compress_with(
+                &mut fc,
+                &Fixture::options().minimum_ratio(0.95),
+                &NoProgress,
+            )
+        };
+
+        let stats = compress(None);
+        assert_eq!(stats.skipped.not_compressible_enough.into_inner(), 100);
+        assert_eq!(
+            stats.skipped.directory_deemed_incompressible.into_inner(),
+            0
+        );
+        assert_eq!(stats.compressed_file_count_final.into_inner(), 3);
+        for i in 0..3 {
+            decompress_file(&dir.path().join(format!("zz-text{i}")), Verify::Off).unwrap();
+        }
+
+        let stats = compress(Some(DEFAULT_ADAPTIVE_SKIP_ATTEMPTS));
+        let attempts = stats.skipped.not_compressible_enough.into_inner()
+            + stats.compressed_file_count_final.into_inner();
+        assert!(attempts < 50, "{attempts} files attempted");
+        assert_eq!(
+            attempts + stats.skipped.directory_deemed_incompressible.into_inner(),
+            103
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Finding app, framework, and kernel extension bundles, and checking their code signatures
+//!
+//! A code signature seals the contents of a bundle's files, which compression doesn't change,
+//! but replacing the files can still stop a bundle passing strict validation (e.g. because of
+//! the xattrs of the new files). Bundles are found by their extension, and an `Info.plist` where
+//! each kind of bundle keeps it.
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+/// The extensions of bundles which are code signed as a whole
+const BUNDLE_EXTENSIONS: &[&str] = &["app", "framework", "kext"];
+
+/// Where a bundle keeps its `Info.plist`, relative to the bundle
+///
+/// macOS apps and kernel extensions use `Contents`, frameworks use `Resources` (usually a
+/// symlink into `Versions`), and iOS style bundles keep it at the top level.
+const INFO_PLIST_PATHS: &[&str] = &["Contents/Info.plist", "Resources/Info.plist", "Info.plist"];
+
+/// Returns true if the directory at `path` is an app, framework, or kernel extension bundle
+pub fn is_bundle(path: &Path) -> bool {
+    let has_bundle_extension = path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        BUNDLE_EXTENSIONS
+            .iter()
+            .any(|b| ext.eq_ignore_ascii_case(b))
+    });
+    has_bundle_extension
+        && INFO_PLIST_PATHS
+            .iter()
+            .any(|info_plist| path.join(info_plist).is_file())
+}
+
+/// The outermost bundle containing `path` (or `path` itself, if it's a bundle)
+pub fn containing_bundle(path: &Path) -> Option<&Path> {
+    path.ancestors().filter(|dir| is_bundle(dir)).last()
+}
+
+/// Check the code signature of the bundle at `path` with `codesign --verify`
+///
+/// Nested code (e.g. frameworks inside an app) is checked too. Returns the reason the signature
+/// is not valid, including if the bundle isn't signed at all.
+pub fn verify_signature(path: &Path) -> Result<(), String> {
+    let output = Command::new("/usr/bin/codesign")
+        .args(["--verify", "--deep", "--strict"])
+        .arg(path)
+        .output()
+        .map_err(|e| format!("unable to run codesign: {e}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // codesign prefixes each message with the path
+    let message = stderr
+        .lines()
+        .map(|line| {
+            line.strip_prefix(&format!("{}: ", path.display()))
+                .unwrap_or(line)
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(if message.is_empty() {
+        format!("codesign failed: {}", output.status)
+    } else {
+        message
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Progress;
+    use crate::testing::{compressed_files, Fixture, NoProgress};
+    use crate::{BundlePolicy, FileCompressor};
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Create a fake bundle at `path`, with its `Info.plist` at `info_plist`
+    fn fake_bundle(path: &Path, info_plist: &str) {
+        let info_plist = path.join(info_plist);
+        fs::create_dir_all(info_plist.parent().unwrap()).unwrap();
+        fs::write(info_plist, "<plist></plist>").unwrap();
+    }
+
+    #[test]
+    fn bundles_found() {
+        let dir = TempDir::new().unwrap();
+        let app = dir.path().join("Some.app");
+        fake_bundle(&app, "Contents/Info.plist");
+        let framework = app.join("Contents/Frameworks/Some.framework");
+        fake_bundle(&framework, "Versions/A/Resources/Info.plist");
+        std::os::unix::fs::symlink("Versions/A/Resources", framework.join("Resources")).unwrap();
+        let kext = dir.path().join("Driver.KEXT");
+        fake_bundle(&kext, "Contents/Info.plist");
+        let ios_app = dir.path().join("Phone.app");
+        fake_bundle(&ios_app, "Info.plist");
+
+        for bundle in [&app, &framework, &kext, &ios_app] {
+            assert!(is_bundle(bundle), "{bundle:?}");
+        }
+        assert_eq!(containing_bundle(&app), Some(app.as_path()));
+        let binary = framework.join("Versions/A/Some");
+        assert_eq!(containing_bundle(&binary), Some(app.as_path()));
+        assert_eq!(containing_bundle(dir.path()), None);
+    }
+
+    #[test]
+    fn not_bundles() {
+        let dir = TempDir::new().unwrap();
+        // A bundle extension, but no Info.plist
+        let empty_app = dir.path().join("Empty.app");
+        fs::create_dir(&empty_app).unwrap();
+        // An Info.plist, but not a bundle extension
+        let plain = dir.path().join("plain");
+        fake_bundle(&plain, "Contents/Info.plist");
+        // A file with a bundle extension
+        let file_app = dir.path().join("file.app");
+        fs::write(&file_app, "").unwrap();
+
+        for path in [&empty_app, &plain, &file_app] {
+            assert!(!is_bundle(path), "{path:?}");
+            assert_eq!(containing_bundle(path), None, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn unsigned_bundle_fails_verification() {
+        let dir = TempDir::new().unwrap();
+        let app = dir.path().join("Unsigned.app");
+        fake_bundle(&app, "Contents/Info.plist");
+        assert!(verify_signature(&app).is_err());
+    }
+
+    #[derive(Default)]
+    struct BundleProgress {
+        entered: std::sync::Mutex<Vec<PathBuf>>,
+    }
+
+    impl Progress for BundleProgress {
+        type Task = NoProgress;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn bundle_entered(&self, bundle: &Path) {
+            self.entered.lock().unwrap().push(bundle.to_owned());
+        }
+
+        fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+            NoProgress
+        }
+    }
+
+    /// An app containing a framework, next to a plain directory and file
+    fn populate_bundles(dir: &Path) {
+        for path in [
+            "file",
+            "plain/file",
+            "Foo.app/Contents/Info.plist",
+            "Foo.app/Contents/MacOS/Foo",
+            "Foo.app/Contents/Frameworks/Bar.framework/Resources/Info.plist",
+            "Foo.app/Contents/Frameworks/Bar.framework/Bar",
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, [0; 16 * 1024]).unwrap();
+        }
+    }
+
+    #[test]
+    fn bundle_policy() {
+        let run = |bundle_policy, root: &str| {
+            let dir = Fixture::new();
+            populate_bundles(dir.path());
+
+            let mut fc = FileCompressor::new();
+            fc.set_bundle_policy(bundle_policy);
+            let progress = BundleProgress::default();
+            let root = dir.path().join(root);
+            let stats = fc
+                .compress_with([root.as_path()], &Fixture::options(), &progress)
+                .unwrap();
+            // Only the outermost bundle is entered, once
+            let entered: Vec<PathBuf> = progress
+                .entered
+                .into_inner()
+                .unwrap()
+                .iter()
+                .map(|bundle| bundle.file_name().unwrap().into())
+                .collect();
+            (
+                compressed_files(dir.path()),
+                stats.skipped.in_bundle.into_inner(),
+                entered,
+            )
+        };
+        let expected =
+            |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+
+        assert_eq!(
+            run(BundlePolicy::Warn, ""),
+            (
+                expected(&[
+                    "Foo.app/Contents/Frameworks/Bar.framework/Bar",
+                    "Foo.app/Contents/Frameworks/Bar.framework/Resources/Info.plist",
+                    "Foo.app/Contents/Info.plist",
+                    "Foo.app/Contents/MacOS/Foo",
+                    "file",
+                    "plain/file",
+                ]),
+                0,
+                expected(&["Foo.app"]),
+            )
+        );
+        // The bundle is reported once, not for each file inside it
+        assert_eq!(
+            run(BundlePolicy::Skip, ""),
+            (expected(&["file", "plain/file"]), 1, expected(&[]))
+        );
+
+        // A root inside a bundle is still in the bundle
+        assert_eq!(
+            run(BundlePolicy::Warn, "Foo.app/Contents/MacOS"),
+            (
+                expected(&["Foo.app/Contents/MacOS/Foo"]),
+                0,
+                expected(&["Foo.app"])
+            )
+        );
+        assert_eq!(
+            run(BundlePolicy::Skip, "Foo.app/Contents/MacOS"),
+            (expected(&[]), 1, expected(&[]))
+        );
+    }
+}
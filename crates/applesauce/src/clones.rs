@@ -0,0 +1,137 @@
+//! Detecting APFS clones
+//!
+//! A clone (made by `cp -c`, or `clonefile(2)`) shares its data blocks with the file it was cloned
+//! from, until either is modified. Compressing a clone writes a new file with its own blocks, so
+//! the space sharing is lost, and the compressed file can use more disk space than the clone did.
+//!
+//! Clones can't be found from `st_blocks`: each clone reports the full size of its blocks, even
+//! though they are shared. Instead, APFS marks files which may share blocks with another file
+//! with the `EF_MAY_SHARE_BLOCKS` extended flag. Both the original and the clone are marked, and
+//! the flag is not cleared when the other file is deleted, so this is only a heuristic: a marked
+//! file is probably, but not certainly, a clone.
+
+#[cfg(test)]
+use crate::path_cstring;
+use std::ffi::c_void;
+use std::fs::File;
+use std::mem::{self, MaybeUninit};
+use std::os::fd::AsRawFd;
+#[cfg(test)]
+use std::path::Path;
+use std::{io, ptr};
+
+/// The file may share blocks with another file (from `sys/attr.h`, not exposed by libc)
+pub(crate) const EF_MAY_SHARE_BLOCKS: u64 = 0x0000_0001;
+
+#[repr(C, packed(4))]
+struct ExtFlagsBuf {
+    len: u32,
+    returned_attrs: libc::attribute_set_t,
+    ext_flags: u64,
+}
+
+/// Returns true if the file may share its blocks with another file, e.g. because it is a clone
+///
+/// Filesystems (and OS versions) which don't report extended flags never share blocks.
+pub fn may_share_blocks(file: &File) -> io::Result<bool> {
+    // SAFETY: libc::attrlist is a POD c struct, zero is a valid value for all fields.
+    let mut attrlist: libc::attrlist = unsafe { mem::zeroed() };
+    attrlist.bitmapcount = libc::ATTR_BIT_MAP_COUNT;
+    attrlist.commonattr = libc::ATTR_CMN_RETURNED_ATTRS;
+    attrlist.forkattr = libc::ATTR_CMNEXT_EXT_FLAGS;
+
+    let mut attr_buf = MaybeUninit::<ExtFlagsBuf>::uninit();
+    // SAFETY: the fd is valid, attrlist and attr_buf are valid pointers, and attr_buf is large
+    // enough for the requested attributes
+    let rc = unsafe {
+        libc::fgetattrlist(
+            file.as_raw_fd(),
+            ptr::addr_of_mut!(attrlist).cast::<c_void>(),
+            attr_buf.as_mut_ptr().cast::<c_void>(),
+            mem::size_of::<ExtFlagsBuf>(),
+            libc::FSOPT_ATTR_CMN_EXTENDED | libc::FSOPT_PACK_INVAL_ATTRS,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fgetattrlist succeeded, and FSOPT_PACK_INVAL_ATTRS fills every field
+    let attr_buf = unsafe { attr_buf.assume_init() };
+    let returned_attrs = attr_buf.returned_attrs;
+    if returned_attrs.forkattr & libc::ATTR_CMNEXT_EXT_FLAGS == 0 {
+        return Ok(false);
+    }
+    let ext_flags = attr_buf.ext_flags;
+    Ok(ext_flags & EF_MAY_SHARE_BLOCKS != 0)
+}
+
+/// Clone `src` to `dst`, failing with `ENOTSUP` if the filesystem doesn't support clones
+#[cfg(test)]
+pub(crate) fn clone_file(src: &Path, dst: &Path) -> io::Result<()> {
+    let src = path_cstring(src)?;
+    let dst = path_cstring(dst)?;
+    // SAFETY: both paths are valid, null terminated strings
+    let rc = unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, NoProgress};
+    use crate::{info, FileCompressor};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn clone_shares_blocks() {
+        let dir = TempDir::new().unwrap();
+        let orig = dir.path().join("orig");
+        let clone = dir.path().join("clone");
+        let other = dir.path().join("other");
+        fs::write(&orig, [1; 64 * 1024]).unwrap();
+        fs::write(&other, [1; 64 * 1024]).unwrap();
+
+        assert!(!may_share_blocks(&File::open(&orig).unwrap()).unwrap());
+        if let Err(e) = clone_file(&orig, &clone) {
+            // Not on APFS
+            assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP), "{e}");
+            return;
+        }
+        assert!(may_share_blocks(&File::open(&orig).unwrap()).unwrap());
+        assert!(may_share_blocks(&File::open(&clone).unwrap()).unwrap());
+        assert!(!may_share_blocks(&File::open(&other).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn skip_clones() {
+        let dir = Fixture::new();
+        let orig = dir.path().join("orig");
+        let clone = dir.path().join("clone");
+        let other = dir.path().join("other");
+        fs::write(&orig, [0; 16 * 1024]).unwrap();
+        fs::write(&other, [0; 16 * 1024]).unwrap();
+        if let Err(e) = clone_file(&orig, &clone) {
+            // Not on APFS
+            assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP), "{e}");
+            return;
+        }
+
+        let mut fc = FileCompressor::new();
+        fc.set_skip_clones(true);
+        let stats = dir.compress(&mut fc, &NoProgress);
+        assert_eq!(stats.skipped.probably_clone.into_inner(), 2);
+        assert!(!info::get(&orig).unwrap().is_compressed);
+        assert!(!info::get(&clone).unwrap().is_compressed);
+        assert!(info::get(&other).unwrap().is_compressed);
+
+        fc.set_skip_clones(false);
+        let stats = dir.compress(&mut fc, &NoProgress);
+        assert_eq!(stats.skipped.probably_clone.into_inner(), 0);
+        assert!(info::get(&orig).unwrap().is_compressed);
+        assert!(info::get(&clone).unwrap().is_compressed);
+    }
+}
@@ -0,0 +1,205 @@
+//! Detecting files which other processes have open for writing
+//!
+//! Unlike the heuristic in [`recent`](crate::recent), this asks the kernel which processes have
+//! the file open: `proc_listpidspath` finds the processes with any descriptor for the path, then
+//! the descriptors of each of them are checked for one open for writing on the same file. Only
+//! processes the current user may inspect can be checked, so this is best-effort.
+
+use crate::path_cstring;
+use libc::{c_char, c_int, c_void};
+use std::ffi::CString;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::{io, mem, ptr};
+
+// From <sys/proc_info.h> and <sys/fcntl.h>, not exposed by libc
+const PROC_ALL_PIDS: u32 = 1;
+const PROC_LISTPIDSPATH_EXCLUDE_EVTONLY: u32 = 2;
+const PROC_PIDLISTFDS: c_int = 1;
+const PROC_PIDFDVNODEINFO: c_int = 1;
+const PROX_FDTYPE_VNODE: u32 = 1;
+const FWRITE: u32 = 0x2;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct ProcFdInfo {
+    proc_fd: i32,
+    proc_fdtype: u32,
+}
+
+#[repr(C)]
+struct ProcFileInfo {
+    fi_openflags: u32,
+    _fi_status: u32,
+    _fi_offset: libc::off_t,
+    _fi_type: i32,
+    _fi_guardflags: u32,
+}
+
+#[repr(C)]
+struct VnodeFdInfo {
+    pfi: ProcFileInfo,
+    pvi: libc::vnode_info,
+}
+
+extern "C" {
+    fn proc_listpidspath(
+        r#type: u32,
+        typeinfo: u32,
+        path: *const c_char,
+        pathflags: u32,
+        buffer: *mut c_void,
+        buffersize: c_int,
+    ) -> c_int;
+}
+
+/// Returns true if any process (including this one) has the file at `path` open for writing
+///
+/// `metadata` identifies the file: descriptors for a different file which has since been moved
+/// to `path` don't count. Processes which can't be inspected (e.g. those of other users, when
+/// not running as root) are ignored.
+pub fn is_open_for_writing(path: &Path, metadata: &Metadata) -> io::Result<bool> {
+    let path = path_cstring(path)?;
+    for pid in pids_with_path_open(&path)? {
+        // The process may have exited, or not be inspectable
+        let Ok(fds) = list_fds(pid) else {
+            continue;
+        };
+        let writing = fds
+            .iter()
+            .filter(|fd| fd.proc_fdtype == PROX_FDTYPE_VNODE)
+            .filter_map(|fd| vnode_info(pid, fd.proc_fd))
+            .any(|info| {
+                let stat = &info.pvi.vi_stat;
+                u64::from(stat.vst_dev) == metadata.dev()
+                    && stat.vst_ino == metadata.ino()
+                    && info.pfi.fi_openflags & FWRITE != 0
+            });
+        if writing {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn pids_with_path_open(path: &CString) -> io::Result<Vec<libc::pid_t>> {
+    let mut pids: Vec<libc::pid_t> = vec![0; 64];
+    loop {
+        let buffer_size = c_int::try_from(mem::size_of_val(pids.as_slice())).unwrap();
+        // SAFETY: path is null terminated, and the buffer is valid for buffer_size bytes
+        let rc = unsafe {
+            proc_listpidspath(
+                PROC_ALL_PIDS,
+                0,
+                path.as_ptr(),
+                PROC_LISTPIDSPATH_EXCLUDE_EVTONLY,
+                pids.as_mut_ptr().cast(),
+                buffer_size,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // A full buffer may have been truncated
+        if rc < buffer_size {
+            pids.truncate(usize::try_from(rc).unwrap() / mem::size_of::<libc::pid_t>());
+            return Ok(pids);
+        }
+        pids.resize(pids.len() * 2, 0);
+    }
+}
+
+fn list_fds(pid: libc::pid_t) -> io::Result<Vec<ProcFdInfo>> {
+    // SAFETY: a null buffer asks for the size needed
+    let size = unsafe { libc::proc_pidinfo(pid, PROC_PIDLISTFDS, 0, ptr::null_mut(), 0) };
+    if size <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let capacity = usize::try_from(size).unwrap() / mem::size_of::<ProcFdInfo>();
+    let mut fds = vec![
+        ProcFdInfo {
+            proc_fd: 0,
+            proc_fdtype: 0
+        };
+        capacity
+    ];
+    let buffer_size = c_int::try_from(mem::size_of_val(fds.as_slice())).unwrap();
+    // SAFETY: the buffer is valid for buffer_size bytes
+    let size = unsafe {
+        libc::proc_pidinfo(
+            pid,
+            PROC_PIDLISTFDS,
+            0,
+            fds.as_mut_ptr().cast(),
+            buffer_size,
+        )
+    };
+    if size <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+    fds.truncate(usize::try_from(size).unwrap() / mem::size_of::<ProcFdInfo>());
+    Ok(fds)
+}
+
+fn vnode_info(pid: libc::pid_t, fd: i32) -> Option<VnodeFdInfo> {
+    let mut info = mem::MaybeUninit::<VnodeFdInfo>::uninit();
+    let size = c_int::try_from(mem::size_of::<VnodeFdInfo>()).unwrap();
+    // SAFETY: info is valid for size bytes
+    let rc = unsafe {
+        libc::proc_pidfdinfo(pid, fd, PROC_PIDFDVNODEINFO, info.as_mut_ptr().cast(), size)
+    };
+    // SAFETY: proc_pidfdinfo filled the whole struct
+    (rc == size).then(|| unsafe { info.assume_init() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, NoProgress};
+    use crate::{info, FileCompressor};
+    use std::fs::{self, File, OpenOptions};
+    use tempfile::TempDir;
+
+    #[test]
+    fn open_for_writing() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"data").unwrap();
+        let metadata = path.metadata().unwrap();
+
+        let _reading = File::open(&path).unwrap();
+        assert!(!is_open_for_writing(&path, &metadata).unwrap());
+
+        let writing = OpenOptions::new().append(true).open(&path).unwrap();
+        assert!(is_open_for_writing(&path, &metadata).unwrap());
+
+        drop(writing);
+        assert!(!is_open_for_writing(&path, &metadata).unwrap());
+    }
+
+    #[test]
+    fn skip_in_use() {
+        let dir = Fixture::new();
+        let in_use = dir.path().join("in_use");
+        let closed = dir.path().join("closed");
+        fs::write(&in_use, [0; 16 * 1024]).unwrap();
+        fs::write(&closed, [0; 16 * 1024]).unwrap();
+        let writer = File::options().append(true).open(&in_use).unwrap();
+
+        let compress = |skip_in_use| {
+            let mut fc = FileCompressor::new();
+            fc.set_skip_in_use(skip_in_use);
+            dir.compress(&mut fc, &NoProgress)
+        };
+        let stats = compress(true);
+        assert_eq!(stats.skipped.in_use.into_inner(), 1);
+        assert!(!info::get(&in_use).unwrap().is_compressed);
+        assert!(info::get(&closed).unwrap().is_compressed);
+
+        let stats = compress(false);
+        assert_eq!(stats.skipped.in_use.into_inner(), 0);
+        assert!(info::get(&in_use).unwrap().is_compressed);
+        drop(writer);
+    }
+}
@@ -1,19 +1,32 @@
-use crate::{cstr_from_bytes_until_null, vol_supports_compression_cap, xattr};
-use applesauce_core::{decmpfs, round_to_block_size};
+use crate::{
+    cstr_from_bytes_until_null, os_error, path_cstring, vol_supports_compression_cap, xattr,
+};
+use applesauce_core::{
+    decmpfs, is_too_large, num_blocks, round_to_block_size, MAX_COMPRESSIBLE_SIZE,
+};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
 use std::ffi::{CStr, CString};
 use std::fmt;
-use std::fs::Metadata;
+use std::fs::{File, FileType, Metadata};
 use std::io;
 use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
 use std::os::macos::fs::MetadataExt as _;
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::MetadataExt as _;
-use std::path::Path;
+use std::os::unix::fs::{FileTypeExt as _, MetadataExt as _};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-pub use applesauce_core::decmpfs::CompressionType;
+pub use applesauce_core::decmpfs::{CompressionType, Storage};
 
 pub struct DecmpfsInfo {
     pub compression_type: CompressionType,
+    /// Where the compressed data is stored, if the compression type is known
+    pub storage: Option<Storage>,
+    /// The number of compressed blocks, if the compression type is known
+    ///
+    /// Data stored in the decmpfs xattr is a single block, or no blocks for an empty file.
+    pub block_count: Option<u64>,
     pub attribute_size: u64,
     pub orig_file_size: u64,
 }
@@ -21,17 +34,144 @@ pub struct DecmpfsInfo {
 #[non_exhaustive]
 pub struct AfscFileInfo {
     pub is_compressed: bool,
-    pub on_disk_size: u64,
+    /// The file's contents are not stored locally (e.g. evicted by iCloud Drive)
+    pub is_dataless: bool,
+    /// The space used on disk by the data fork
+    pub data_on_disk_size: u64,
+    /// The space used on disk by the resource fork, or 0 if the file has no resource fork
+    pub resource_fork_on_disk_size: u64,
     pub stat_size: u64,
 
+    /// The number of xattrs, not counting the resource fork or decmpfs xattrs
     pub xattr_count: u32,
-    pub total_xattr_size: u64,
+    /// The total size of xattrs, not counting the resource fork or decmpfs xattrs
+    pub xattr_size: u64,
 
+    /// The length of the resource fork, if the file has one
     pub resource_fork_size: Option<u64>,
 
     pub decmpfs_info: Option<Result<DecmpfsInfo, decmpfs::DecodeError>>,
+
+    /// The file's compressed flag and decmpfs xattr disagree
+    pub inconsistency: Option<Inconsistency>,
+}
+
+/// A way the compressed flag and decmpfs xattr of a file can disagree, found by [`get`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Inconsistency {
+    /// The file is marked compressed, but has no decmpfs xattr
+    ///
+    /// The OS reports the file's size from the filesystem, but reads it as empty. Usually left
+    /// by a copy tool which copied the flag but not the xattr. Clearing the compressed flag
+    /// (see [`clear_orphaned_compressed_flag`](crate::clear_orphaned_compressed_flag)) makes the
+    /// file read as the data which is actually stored.
+    MissingDecmpfs,
+    /// The file is marked compressed, but its decmpfs xattr can't be parsed
+    InvalidDecmpfs,
+    /// The file has a decmpfs xattr, but isn't marked compressed
+    ///
+    /// See [`check_compressed_flag`](crate::check_compressed_flag).
+    MissingCompressedFlag,
+}
+
+impl Inconsistency {
+    fn of(
+        is_compressed: bool,
+        decmpfs_info: Option<&Result<DecmpfsInfo, decmpfs::DecodeError>>,
+    ) -> Option<Self> {
+        match (is_compressed, decmpfs_info) {
+            (true, None) => Some(Self::MissingDecmpfs),
+            (true, Some(Err(_))) => Some(Self::InvalidDecmpfs),
+            (false, Some(_)) => Some(Self::MissingCompressedFlag),
+            (true, Some(Ok(_))) | (false, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::MissingDecmpfs => write!(f, "compressed file has no decmpfs xattr"),
+            Inconsistency::InvalidDecmpfs => {
+                write!(f, "compressed file has an invalid decmpfs xattr")
+            }
+            Inconsistency::MissingCompressedFlag => {
+                write!(f, "file has a decmpfs xattr, but is not marked compressed")
+            }
+        }
+    }
 }
 
+/// The type of something which isn't a regular file, which [`get`] has no info for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OtherFileType {
+    Directory,
+    Symlink,
+    NamedPipe,
+    Socket,
+    BlockDevice,
+    CharDevice,
+}
+
+impl OtherFileType {
+    /// The type of `file_type`, or `None` if it's a regular file
+    #[must_use]
+    #[allow(clippy::filetype_is_file)]
+    pub fn of(file_type: FileType) -> Option<Self> {
+        if file_type.is_file() {
+            None
+        } else if file_type.is_dir() {
+            Some(Self::Directory)
+        } else if file_type.is_symlink() {
+            Some(Self::Symlink)
+        } else if file_type.is_fifo() {
+            Some(Self::NamedPipe)
+        } else if file_type.is_socket() {
+            Some(Self::Socket)
+        } else if file_type.is_block_device() {
+            Some(Self::BlockDevice)
+        } else {
+            Some(Self::CharDevice)
+        }
+    }
+
+    /// The type of file `e` was returned by [`get`] for, if it wasn't a regular file
+    #[must_use]
+    pub fn of_error(e: &io::Error) -> Option<Self> {
+        e.get_ref()?
+            .downcast_ref::<NotRegularFile>()
+            .map(|not_regular| not_regular.0)
+    }
+}
+
+impl fmt::Display for OtherFileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            OtherFileType::Directory => "directory",
+            OtherFileType::Symlink => "symlink",
+            OtherFileType::NamedPipe => "named pipe",
+            OtherFileType::Socket => "socket",
+            OtherFileType::BlockDevice => "block device",
+            OtherFileType::CharDevice => "character device",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The error returned by [`get`] for a path which isn't a regular file
+#[derive(Debug)]
+struct NotRegularFile(OtherFileType);
+
+impl fmt::Display for NotRegularFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not a regular file: {}", self.0)
+    }
+}
+
+impl std::error::Error for NotRegularFile {}
+
 #[non_exhaustive]
 pub struct FileInfo {
     pub on_disk_size: u64,
@@ -41,18 +181,27 @@ pub struct FileInfo {
 #[non_exhaustive]
 pub enum IncompressibleReason {
     Empty,
-    TooLarge(u64),
+    /// The file is at least [`MAX_COMPRESSIBLE_SIZE`] bytes
+    TooLarge {
+        size: u64,
+        limit: u64,
+    },
     IoError(io::Error),
     FsNotSupported,
     HasRequiredXattr,
+    /// The file's contents are not stored locally, reading it would download it
+    Dataless,
 }
 
 impl fmt::Display for IncompressibleReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             IncompressibleReason::Empty => write!(f, "empty file"),
-            IncompressibleReason::TooLarge(size) => {
-                write!(f, "file too large to compress: {} bytes", size)
+            IncompressibleReason::TooLarge { size, limit } => {
+                write!(
+                    f,
+                    "file too large to compress: {size} bytes (limit {limit})"
+                )
             }
             IncompressibleReason::IoError(e) => e.fmt(f),
             IncompressibleReason::FsNotSupported => {
@@ -61,6 +210,7 @@ impl fmt::Display for IncompressibleReason {
             IncompressibleReason::HasRequiredXattr => {
                 write!(f, "file has a required xattr for compression already")
             }
+            IncompressibleReason::Dataless => write!(f, "file is not stored locally"),
         }
     }
 }
@@ -72,9 +222,29 @@ pub enum FileCompressionState {
 }
 
 impl AfscFileInfo {
+    /// The total space used by the file on disk, including the resource fork
+    #[must_use]
+    pub fn on_disk_size(&self) -> u64 {
+        self.data_on_disk_size + self.resource_fork_on_disk_size
+    }
+
+    /// The space used on disk to store the file's contents
+    ///
+    /// The compressed contents of a compressed file live in its resource fork, so this includes
+    /// the resource fork. The resource fork of an uncompressed file is unrelated to its contents,
+    /// and is not included.
+    #[must_use]
+    pub fn compressed_size(&self) -> u64 {
+        if self.is_compressed {
+            self.on_disk_size()
+        } else {
+            self.data_on_disk_size
+        }
+    }
+
     #[must_use]
     pub fn compressed_fraction(&self) -> f64 {
-        self.on_disk_size as f64 / self.stat_size as f64
+        self.compressed_size() as f64 / self.stat_size as f64
     }
 }
 
@@ -83,10 +253,25 @@ impl AfscFileInfo {
 pub struct AfscFolderInfo {
     pub num_files: u32,
     pub num_folders: u32,
+    /// Number of entries which are neither files nor folders (e.g. symlinks and named pipes)
+    ///
+    /// These entries are not counted in any of the other totals.
+    pub num_other_entries: u32,
     pub num_compressed_files: u32,
+    /// Number of files whose contents are not stored locally
+    ///
+    /// These files are counted in `num_files`, but not in any of the other totals.
+    pub num_dataless_files: u32,
 
     pub total_uncompressed_size: u64,
     pub total_compressed_size: u64,
+    /// Total size of files whose contents are not stored locally
+    pub total_dataless_size: u64,
+    /// Total space used on disk by the resource forks of uncompressed files
+    ///
+    /// These resource forks are unrelated to compression, so they are not included in any of
+    /// the other totals.
+    pub total_other_resource_fork_size: u64,
 }
 
 impl AfscFolderInfo {
@@ -101,7 +286,83 @@ impl AfscFolderInfo {
     }
 }
 
+/// The files with the largest compression wins under a directory, found by [`get_recursive_top`]
+///
+/// Each list is sorted largest first, and has at most the number of entries asked for.
+#[derive(Debug, Default, Clone)]
+#[non_exhaustive]
+pub struct TopFiles {
+    /// Compressed files which save the most space, with the number of bytes saved
+    pub most_saved: Vec<(u64, PathBuf)>,
+    /// The largest files which are not compressed, with their size
+    pub largest_uncompressed: Vec<(u64, PathBuf)>,
+}
+
+/// The `n` largest values pushed into it, kept in a min-heap so the smallest is replaced first
+struct Largest {
+    n: usize,
+    heap: BinaryHeap<Reverse<(u64, PathBuf)>>,
+}
+
+impl Largest {
+    fn new(n: usize) -> Self {
+        Self {
+            n,
+            heap: BinaryHeap::with_capacity(n + 1),
+        }
+    }
+
+    fn push(&mut self, value: u64, path: &Path) {
+        if self.heap.len() == self.n {
+            match self.heap.peek() {
+                Some(Reverse((smallest, _))) if value > *smallest => {}
+                _ => return,
+            }
+            self.heap.pop();
+        }
+        self.heap.push(Reverse((value, path.to_owned())));
+    }
+
+    fn into_sorted_vec(self) -> Vec<(u64, PathBuf)> {
+        // Sorting the reversed entries ascending sorts the values descending
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(entry)| entry)
+            .collect()
+    }
+}
+
 pub fn get_recursive(path: &Path) -> io::Result<AfscFolderInfo> {
+    walk_recursive(path, |_, _| {})
+}
+
+/// Get the totals for a directory, like [`get_recursive`], along with the `n` files which save
+/// the most space by being compressed, and the `n` largest files which aren't compressed
+///
+/// Only `n` files are kept at a time, however many files are under `path`.
+pub fn get_recursive_top(path: &Path, n: usize) -> io::Result<(AfscFolderInfo, TopFiles)> {
+    let mut most_saved = Largest::new(n);
+    let mut largest_uncompressed = Largest::new(n);
+    let info = walk_recursive(path, |path, info| {
+        if info.is_compressed {
+            most_saved.push(info.stat_size.saturating_sub(info.compressed_size()), path);
+        } else {
+            largest_uncompressed.push(info.stat_size, path);
+        }
+    })?;
+    let top = TopFiles {
+        most_saved: most_saved.into_sorted_vec(),
+        largest_uncompressed: largest_uncompressed.into_sorted_vec(),
+    };
+    Ok((info, top))
+}
+
+/// Total the info of every file under `path`, calling `f` with each file stored locally
+fn walk_recursive(
+    path: &Path,
+    mut f: impl FnMut(&Path, &AfscFileInfo),
+) -> io::Result<AfscFolderInfo> {
     let mut result = AfscFolderInfo::default();
     for entry in jwalk::WalkDir::new(path) {
         let entry = entry?;
@@ -109,61 +370,260 @@ pub fn get_recursive(path: &Path) -> io::Result<AfscFolderInfo> {
 
         #[allow(clippy::filetype_is_file)]
         if file_type.is_file() {
-            let info = get(&entry.path())?;
+            let path = entry.path();
+            let info = get(&path)?;
             result.num_files += 1;
+            if info.is_dataless {
+                result.num_dataless_files += 1;
+                result.total_dataless_size += info.stat_size;
+                continue;
+            }
             if info.is_compressed {
                 result.num_compressed_files += 1;
-                result.total_compressed_size += info.on_disk_size;
+                result.total_compressed_size += info.compressed_size();
             } else {
                 result.total_compressed_size += info.stat_size;
+                result.total_other_resource_fork_size += info.resource_fork_on_disk_size;
             }
             result.total_uncompressed_size += info.stat_size;
+            f(&path, &info);
         } else if file_type.is_dir() {
             result.num_folders += 1;
+        } else {
+            result.num_other_entries += 1;
         }
     }
     Ok(result)
 }
 
+/// Get the compression info of every file under `root`
+///
+/// Files are yielded as they are found, sorted by name within each directory. Unlike
+/// [`get_recursive`], errors don't stop the walk: a file (or directory) which can't be read is
+/// yielded with its error. Errors with no associated path are reported against `root`.
+pub fn walk(root: &Path) -> impl Iterator<Item = (PathBuf, io::Result<AfscFileInfo>)> {
+    let root = root.to_path_buf();
+    jwalk::WalkDir::new(&root)
+        .sort(true)
+        .into_iter()
+        .filter_map(move |entry| match entry {
+            #[allow(clippy::filetype_is_file)]
+            Ok(entry) if entry.file_type().is_file() => {
+                let path = entry.path();
+                let info = get(&path);
+                Some((path, info))
+            }
+            Ok(_) => None,
+            Err(e) => {
+                let path = e.path().map_or_else(|| root.clone(), Path::to_path_buf);
+                Some((path, Err(e.into())))
+            }
+        })
+}
+
+/// A compressed file found by [`walk_compressed`]
+#[derive(Debug)]
+pub struct CompressedEntry {
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+impl CompressedEntry {
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[must_use]
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+
+    /// The size of the file's contents
+    #[must_use]
+    pub fn uncompressed_size(&self) -> u64 {
+        self.metadata.len()
+    }
+
+    /// The space used by the file on disk
+    #[must_use]
+    pub fn on_disk_size(&self) -> u64 {
+        on_disk_size(&self.metadata)
+    }
+
+    /// Read and parse the decmpfs xattr of the file
+    ///
+    /// This is not done during the walk, it reads the xattr every time it is called.
+    pub fn decmpfs_info(&self) -> io::Result<Result<DecmpfsInfo, decmpfs::DecodeError>> {
+        let path = path_cstring(&self.path)?;
+        get_decmpfs_info(&path)
+    }
+}
+
+type CompressedWalk = jwalk::WalkDirGeneric<((), Option<io::Result<Metadata>>)>;
+
+fn compressed_walker(root: &Path) -> CompressedWalk {
+    CompressedWalk::new(root).process_read_dir(|_depth, _path, _state, entries| {
+        // Stat files in parallel as each directory is read, and drop uncompressed files
+        // before they are yielded
+        entries.retain_mut(|entry| {
+            let Ok(entry) = entry else {
+                return true;
+            };
+            #[allow(clippy::filetype_is_file)]
+            if !entry.file_type().is_file() {
+                return entry.file_type().is_dir();
+            }
+            match entry.path().symlink_metadata() {
+                Ok(metadata) if metadata.st_flags() & libc::UF_COMPRESSED == 0 => false,
+                metadata => {
+                    entry.client_state = Some(metadata);
+                    true
+                }
+            }
+        });
+    })
+}
+
+fn compressed_entries(walker: CompressedWalk) -> impl Iterator<Item = io::Result<CompressedEntry>> {
+    walker.into_iter().filter_map(|entry| {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let metadata = entry.client_state.take()?;
+        Some(metadata.map(|metadata| CompressedEntry {
+            path: entry.path(),
+            metadata,
+        }))
+    })
+}
+
+/// Find all compressed files under `root`
+///
+/// Directories are read in parallel, and only the metadata from the walk is used to decide if a
+/// file is compressed, so this is much cheaper than calling [`get`] on every file. The order of
+/// entries is unspecified, see [`walk_compressed_sorted`] for a deterministic order.
+pub fn walk_compressed(root: &Path) -> impl Iterator<Item = io::Result<CompressedEntry>> {
+    compressed_entries(compressed_walker(root))
+}
+
+/// Find all compressed files under `root`, in sorted order
+///
+/// Like [`walk_compressed`], but entries within each directory are yielded sorted by file name.
+pub fn walk_compressed_sorted(root: &Path) -> impl Iterator<Item = io::Result<CompressedEntry>> {
+    compressed_entries(compressed_walker(root).sort(true))
+}
+
+/// The space used by a file on disk
+pub(crate) fn on_disk_size(metadata: &Metadata) -> u64 {
+    round_to_block_size(metadata.blocks() * 512, metadata.st_blksize())
+}
+
 const ZFS_SUBTYPE: u32 = u32::from_be_bytes(*b"ZFS\0");
 
+/// The file's contents are not stored locally (not exposed by libc)
+const SF_DATALESS: u32 = 0x4000_0000;
+
+/// Returns true if the passed file flags mark a file as dataless
+#[must_use]
+pub fn is_dataless_flags(flags: u32) -> bool {
+    flags & SF_DATALESS != 0
+}
+
+/// Returns true if the file's contents are not stored locally
+///
+/// Dataless files (e.g. files evicted by iCloud Drive) are downloaded when they are opened, so
+/// they should not be read.
+#[must_use]
+pub fn is_dataless(metadata: &Metadata) -> bool {
+    is_dataless_flags(metadata.st_flags())
+}
+
 pub fn get_file_info(path: &Path, metadata: &Metadata) -> FileInfo {
     let compression_info = get_compression_state(path, metadata);
-    let on_disk_size = round_to_block_size(metadata.blocks() * 512, metadata.st_blksize());
+    let on_disk_size = on_disk_size(metadata);
     FileInfo {
         on_disk_size,
         compression_state: compression_info,
     }
 }
 
-#[tracing::instrument(level = "debug", skip_all)]
-pub fn get_compression_state(path: &Path, metadata: &Metadata) -> FileCompressionState {
-    if metadata.st_flags() & libc::UF_COMPRESSED != 0 {
-        return FileCompressionState::Compressed;
+/// Get the compression info of an already open file
+///
+/// Like [`get_file_info`], but all checks are made through `file`, so they apply to the file
+/// which will be read, even if the path is replaced.
+pub fn get_open_file_info(file: &File, metadata: &Metadata) -> FileInfo {
+    let compression_info = get_open_file_compression_state(file, metadata);
+    let on_disk_size = on_disk_size(metadata);
+    FileInfo {
+        on_disk_size,
+        compression_state: compression_info,
     }
+}
 
-    if metadata.len() == 0 {
-        return FileCompressionState::Incompressible(IncompressibleReason::Empty);
-    }
-    if metadata.len() >= u64::from(u32::MAX) {
-        return FileCompressionState::Incompressible(IncompressibleReason::TooLarge(
-            metadata.len(),
-        ));
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn get_compression_state(path: &Path, metadata: &Metadata) -> FileCompressionState {
+    if let Some(state) = compression_state_from_stat(metadata.st_flags(), metadata.len()) {
+        return state;
     }
 
     // TODO: Try a local buffer for non-alloc fast path
-    let path = match CString::new(path.as_os_str().as_bytes()) {
+    let path = match path_cstring(path) {
         Ok(path) => path,
-        Err(e) => {
-            return FileCompressionState::Incompressible(IncompressibleReason::IoError(e.into()))
-        }
+        Err(e) => return FileCompressionState::Incompressible(IncompressibleReason::IoError(e)),
     };
+    compression_state_of(path.as_c_str())
+}
+
+/// Get the compression state of an already open file
+///
+/// `metadata` should be the metadata of the open file (e.g. from [`File::metadata`]).
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn get_open_file_compression_state(file: &File, metadata: &Metadata) -> FileCompressionState {
+    if let Some(state) = compression_state_from_stat(metadata.st_flags(), metadata.len()) {
+        return state;
+    }
+    compression_state_of(file)
+}
+
+/// A file (or path) which can be checked for compressibility
+trait StatfsSource: xattr::XattrSource {
+    fn statfs(&self, buf: *mut libc::statfs) -> libc::c_int;
+}
+
+impl StatfsSource for CStr {
+    fn statfs(&self, buf: *mut libc::statfs) -> libc::c_int {
+        // SAFETY: self is a valid pointer, and null terminated, buf is a valid ptr, and is used as
+        // an out ptr
+        unsafe { libc::statfs(self.as_ptr(), buf) }
+    }
+}
+
+impl StatfsSource for File {
+    fn statfs(&self, buf: *mut libc::statfs) -> libc::c_int {
+        // SAFETY: the fd is valid, buf is a valid ptr, and is used as an out ptr
+        unsafe { libc::fstatfs(self.as_raw_fd(), buf) }
+    }
+}
+
+/// Returns true if the file has either of the xattrs which compression writes
+fn has_required_xattr<F: xattr::XattrSource + ?Sized>(f: &F) -> io::Result<bool> {
+    // Most files have no xattrs at all, which one call can show, instead of one call per xattr
+    if let Ok(false) = xattr::has_any(f) {
+        return Ok(false);
+    }
+    Ok(xattr::is_present(f, resource_fork::XATTR_NAME)?
+        || xattr::is_present(f, decmpfs::XATTR_NAME)?)
+}
+
+/// The compression state of a file which can't be decided from only its flags and size
+fn compression_state_of<F: StatfsSource + ?Sized>(f: &F) -> FileCompressionState {
     let mut statfs_buf = MaybeUninit::<libc::statfs>::uninit();
-    // SAFETY: path is a valid pointer, and null terminated, statfs_buf is a valid ptr, and is used as an out ptr
-    let rc = unsafe { libc::statfs(path.as_ptr(), statfs_buf.as_mut_ptr()) };
+    let rc = f.statfs(statfs_buf.as_mut_ptr());
     if rc != 0 {
         return FileCompressionState::Incompressible(IncompressibleReason::IoError(
-            io::Error::last_os_error(),
+            os_error::last(format_args!("{}statfs", F::CALL_PREFIX)),
         ));
     }
     // SAFETY: if statfs returned non-zero, we returned already, it should have filled in statfs_buf
@@ -177,16 +637,7 @@ pub fn get_compression_state(path: &Path, metadata: &Metadata) -> FileCompressio
         return FileCompressionState::Incompressible(IncompressibleReason::FsNotSupported);
     }
 
-    match xattr::is_present(&path, resource_fork::XATTR_NAME) {
-        Ok(true) => {
-            return FileCompressionState::Incompressible(IncompressibleReason::HasRequiredXattr);
-        }
-        Ok(false) => {}
-        Err(e) => {
-            return FileCompressionState::Incompressible(IncompressibleReason::IoError(e));
-        }
-    };
-    match xattr::is_present(&path, decmpfs::XATTR_NAME) {
+    match has_required_xattr(f) {
         Ok(true) => {
             return FileCompressionState::Incompressible(IncompressibleReason::HasRequiredXattr);
         }
@@ -204,7 +655,7 @@ pub fn get_compression_state(path: &Path, metadata: &Metadata) -> FileCompressio
             ));
         }
     };
-    match vol_supports_compression_cap(root_path) {
+    match vol_supports_compression(root_path) {
         Ok(true) => {}
         Ok(false) => {
             return FileCompressionState::Incompressible(IncompressibleReason::FsNotSupported);
@@ -217,15 +668,91 @@ pub fn get_compression_state(path: &Path, metadata: &Metadata) -> FileCompressio
     FileCompressionState::Compressible
 }
 
+/// Whether files on the volume containing `path` can be compressed
+///
+/// Volumes which can't store compressed files include exFAT, FAT, and network volumes, and ZFS.
+pub(crate) fn volume_supports_compression(path: &Path) -> io::Result<bool> {
+    let path = path_cstring(path)?;
+    let mut statfs_buf = MaybeUninit::<libc::statfs>::uninit();
+    if path.statfs(statfs_buf.as_mut_ptr()) != 0 {
+        return Err(os_error::last(format_args!(
+            "statfs({})",
+            path.to_string_lossy()
+        )));
+    }
+    // SAFETY: statfs succeeded, so it filled in statfs_buf
+    let statfs_buf = unsafe { statfs_buf.assume_init_ref() };
+    // See compression_state_of
+    if statfs_buf.f_fssubtype == ZFS_SUBTYPE {
+        return Ok(false);
+    }
+    let root_path = cstr_from_bytes_until_null(&statfs_buf.f_mntonname)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "mount name invalid"))?;
+    vol_supports_compression(root_path)
+}
+
+/// Whether the volume mounted at `mnt_root` supports compression
+///
+/// The answer for each mount point is cached for the life of the process, rather than asking for
+/// the capabilities of the volume again for every file on it.
+fn vol_supports_compression(mnt_root: &CStr) -> io::Result<bool> {
+    static SUPPORTED: Mutex<BTreeMap<CString, bool>> = Mutex::new(BTreeMap::new());
+
+    if let Some(&supported) = SUPPORTED.lock().unwrap().get(mnt_root) {
+        return Ok(supported);
+    }
+    let supported = vol_supports_compression_cap(mnt_root)?;
+    SUPPORTED
+        .lock()
+        .unwrap()
+        .insert(mnt_root.to_owned(), supported);
+    Ok(supported)
+}
+
+/// The compression state, if it can be decided from only the file's flags and size
+fn compression_state_from_stat(flags: u32, len: u64) -> Option<FileCompressionState> {
+    if is_dataless_flags(flags) {
+        return Some(FileCompressionState::Incompressible(
+            IncompressibleReason::Dataless,
+        ));
+    }
+    if flags & libc::UF_COMPRESSED != 0 {
+        return Some(FileCompressionState::Compressed);
+    }
+
+    if len == 0 {
+        return Some(FileCompressionState::Incompressible(
+            IncompressibleReason::Empty,
+        ));
+    }
+    if is_too_large(len) {
+        return Some(FileCompressionState::Incompressible(
+            IncompressibleReason::TooLarge {
+                size: len,
+                limit: MAX_COMPRESSIBLE_SIZE,
+            },
+        ));
+    }
+    None
+}
+
+/// Get the compression info of a file
+///
+/// Returns an [`InvalidInput`](io::ErrorKind::InvalidInput) error if `path` isn't a regular file,
+/// whose type is available from [`OtherFileType::of_error`].
 pub fn get(path: &Path) -> io::Result<AfscFileInfo> {
     let metadata = path.metadata()?;
-
-    let on_disk_size = round_to_block_size(metadata.blocks() * 512, metadata.st_blksize());
+    if let Some(file_type) = OtherFileType::of(metadata.file_type()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            NotRegularFile(file_type),
+        ));
+    }
 
     // TODO: Try a local buffer for non-alloc fast path
-    let path = CString::new(path.as_os_str().as_bytes())?;
+    let path = path_cstring(path)?;
 
-    let mut total_xattr_size = 0;
+    let mut xattr_size = 0;
     let mut xattr_count = 0;
     let mut resource_fork_size = None;
     let mut decmpfs_info = None;
@@ -235,6 +762,7 @@ pub fn get(path: &Path) -> io::Result<AfscFileInfo> {
             let info = get_decmpfs_info(&path)?;
             decmpfs_info = Some(info);
         } else {
+            // Only the length is needed, so values (which may be large) are never read
             let maybe_len = xattr::len(&path, xattr_name)?;
             let len = maybe_len.ok_or_else(|| {
                 io::Error::new(
@@ -252,20 +780,30 @@ pub fn get(path: &Path) -> io::Result<AfscFileInfo> {
                 resource_fork_size = Some(len);
             } else {
                 xattr_count += 1;
-                total_xattr_size += len;
+                xattr_size += len;
             }
         }
 
         Ok(())
     })?;
 
+    // st_blocks counts the blocks of both forks, the resource fork takes whole blocks
+    let on_disk_size = on_disk_size(&metadata);
+    let resource_fork_on_disk_size = resource_fork_size
+        .map_or(0, |len| round_to_block_size(len, metadata.st_blksize()))
+        .min(on_disk_size);
+
+    let is_compressed = (metadata.st_flags() & libc::UF_COMPRESSED) == libc::UF_COMPRESSED;
     Ok(AfscFileInfo {
-        is_compressed: (metadata.st_flags() & libc::UF_COMPRESSED) == libc::UF_COMPRESSED,
-        on_disk_size,
+        is_compressed,
+        is_dataless: is_dataless(&metadata),
+        data_on_disk_size: on_disk_size - resource_fork_on_disk_size,
+        resource_fork_on_disk_size,
         stat_size: metadata.len(),
         xattr_count,
-        total_xattr_size,
+        xattr_size,
         resource_fork_size,
+        inconsistency: Inconsistency::of(is_compressed, decmpfs_info.as_ref()),
         decmpfs_info,
     })
 }
@@ -280,9 +818,470 @@ fn get_decmpfs_info(path: &CStr) -> io::Result<Result<DecmpfsInfo, decmpfs::Deco
 
 fn decmpfs_info_from_bytes(data: &[u8]) -> Result<DecmpfsInfo, decmpfs::DecodeError> {
     let value = decmpfs::Value::from_data(data)?;
+    let storage = value.storage();
     Ok(DecmpfsInfo {
         compression_type: value.compression_type,
+        storage,
+        block_count: storage.map(|storage| match storage {
+            Storage::Xattr => u64::from(value.uncompressed_size != 0),
+            Storage::ResourceFork => num_blocks(value.uncompressed_size),
+        }),
         attribute_size: data.len().try_into().unwrap(),
         orig_file_size: value.uncompressed_size,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{populate_dir, Fixture, NoProgress};
+    use crate::{compress_file, FileCompressor, Verify};
+    use applesauce_core::compressor::Kind;
+    use std::fs;
+    use std::io::Write;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn dataless_flags() {
+        assert!(!is_dataless_flags(0));
+        assert!(!is_dataless_flags(libc::UF_COMPRESSED | libc::UF_HIDDEN));
+        assert!(is_dataless_flags(SF_DATALESS));
+        assert!(is_dataless_flags(SF_DATALESS | libc::UF_COMPRESSED));
+    }
+
+    #[test]
+    fn state_from_stat() {
+        // Dataless files are never read, even if compressed or empty
+        for flags in [SF_DATALESS, SF_DATALESS | libc::UF_COMPRESSED] {
+            for len in [0, 100] {
+                assert!(matches!(
+                    compression_state_from_stat(flags, len),
+                    Some(FileCompressionState::Incompressible(
+                        IncompressibleReason::Dataless
+                    ))
+                ));
+            }
+        }
+        assert!(matches!(
+            compression_state_from_stat(libc::UF_COMPRESSED, 100),
+            Some(FileCompressionState::Compressed)
+        ));
+        assert!(matches!(
+            compression_state_from_stat(0, 0),
+            Some(FileCompressionState::Incompressible(
+                IncompressibleReason::Empty
+            ))
+        ));
+        assert!(matches!(
+            compression_state_from_stat(0, MAX_COMPRESSIBLE_SIZE),
+            Some(FileCompressionState::Incompressible(
+                IncompressibleReason::TooLarge { .. }
+            ))
+        ));
+        assert!(compression_state_from_stat(0, 100).is_none());
+    }
+
+    #[test]
+    fn temp_volume_supports_compression() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(volume_supports_compression(dir.path()).unwrap());
+        assert!(volume_supports_compression(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn required_xattr() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = |name: &str, xattr_name: Option<&CStr>| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [1; 1024]).unwrap();
+            let file = File::open(&path).unwrap();
+            if let Some(xattr_name) = xattr_name {
+                xattr::set(&file, xattr_name, b"value", 0).unwrap();
+            }
+            file
+        };
+        assert!(!has_required_xattr(&file("none", None)).unwrap());
+        assert!(!has_required_xattr(&file("unrelated", Some(c"com.example.test"))).unwrap());
+        assert!(has_required_xattr(&file("rsrc", Some(resource_fork::XATTR_NAME))).unwrap());
+    }
+
+    #[test]
+    fn large_xattrs_only_measured() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, [1; 1024]).unwrap();
+        let file = File::open(&path).unwrap();
+        xattr::set(&file, c"com.example.test", &vec![2; 1024 * 1024], 0).unwrap();
+        // Only a resource fork can be larger than any xattr read can return
+        let chunk = vec![3; 1024 * 1024];
+        let rfork_len = xattr::MAX_READ_SIZE + 1;
+        for offset in (0..rfork_len).step_by(chunk.len()) {
+            let len = chunk.len().min(rfork_len - offset);
+            xattr::set(
+                &file,
+                resource_fork::XATTR_NAME,
+                &chunk[..len],
+                offset.try_into().unwrap(),
+            )
+            .unwrap();
+        }
+        let err = xattr::read(&file, resource_fork::XATTR_NAME).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let info = get(&path).unwrap();
+        assert_eq!(info.xattr_count, 1);
+        assert_eq!(info.xattr_size, 1024 * 1024);
+        assert_eq!(info.resource_fork_size, Some(rfork_len as u64));
+    }
+
+    fn mkfifo(path: &Path) {
+        let path = path_cstring(path).unwrap();
+        // SAFETY: path is a valid, null terminated string
+        let rc = unsafe { libc::mkfifo(path.as_ptr(), 0o644) };
+        assert_eq!(rc, 0, "{}", io::Error::last_os_error());
+    }
+
+    #[test]
+    fn not_regular_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let fifo = dir.path().join("fifo");
+        mkfifo(&fifo);
+        let err = get(&fifo).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            OtherFileType::of_error(&err),
+            Some(OtherFileType::NamedPipe)
+        );
+        assert_eq!(err.to_string(), "not a regular file: named pipe");
+
+        let err = get(dir.path()).err().unwrap();
+        assert_eq!(
+            OtherFileType::of_error(&err),
+            Some(OtherFileType::Directory)
+        );
+        let err = get(Path::new("/dev/null")).err().unwrap();
+        assert_eq!(
+            OtherFileType::of_error(&err),
+            Some(OtherFileType::CharDevice)
+        );
+        let missing = get(&dir.path().join("missing")).err().unwrap();
+        assert_eq!(OtherFileType::of_error(&missing), None);
+
+        std::fs::write(dir.path().join("file"), [1; 1024]).unwrap();
+        std::fs::create_dir(dir.path().join("subdir")).unwrap();
+        std::os::unix::fs::symlink("file", dir.path().join("link")).unwrap();
+        let info = get_recursive(dir.path()).unwrap();
+        assert_eq!(info.num_files, 1);
+        // The root is counted as a folder
+        assert_eq!(info.num_folders, 2);
+        assert_eq!(info.num_other_entries, 2);
+        assert_eq!(info.total_uncompressed_size, 1024);
+    }
+
+    #[test]
+    fn inconsistency() {
+        let valid = || {
+            let mut data = Vec::new();
+            decmpfs::Value::new(
+                applesauce_core::compressor::Kind::Zlib,
+                Storage::Xattr,
+                1,
+                &[],
+            )
+            .unwrap()
+            .write_to(&mut data)
+            .unwrap();
+            decmpfs_info_from_bytes(&data)
+        };
+        let invalid = || decmpfs_info_from_bytes(b"not decmpfs");
+        assert_eq!(Inconsistency::of(true, Some(&valid())), None);
+        assert_eq!(Inconsistency::of(false, None), None);
+        assert_eq!(
+            Inconsistency::of(true, None),
+            Some(Inconsistency::MissingDecmpfs)
+        );
+        assert_eq!(
+            Inconsistency::of(true, Some(&invalid())),
+            Some(Inconsistency::InvalidDecmpfs)
+        );
+        assert_eq!(
+            Inconsistency::of(false, Some(&valid())),
+            Some(Inconsistency::MissingCompressedFlag)
+        );
+    }
+
+    #[test]
+    fn storage_and_block_count() {
+        let info = |storage, uncompressed_size| {
+            decmpfs_info_from_bytes(
+                &decmpfs::Value::new(
+                    applesauce_core::compressor::Kind::Zlib,
+                    storage,
+                    uncompressed_size,
+                    &[],
+                )
+                .unwrap()
+                .to_vec(),
+            )
+            .unwrap()
+        };
+        let block_size = applesauce_core::BLOCK_SIZE as u64;
+        for (storage, uncompressed_size, block_count) in [
+            (Storage::Xattr, 0, 0),
+            (Storage::Xattr, 100, 1),
+            (Storage::ResourceFork, 0, 0),
+            (Storage::ResourceFork, block_size, 1),
+            (Storage::ResourceFork, block_size + 1, 2),
+        ] {
+            let info = info(storage, uncompressed_size);
+            assert_eq!(info.storage, Some(storage));
+            assert_eq!(info.block_count, Some(block_count), "{uncompressed_size}");
+        }
+
+        let mut unknown = decmpfs::MAGIC.to_vec();
+        unknown.extend_from_slice(&100u32.to_le_bytes());
+        unknown.extend_from_slice(&1000u64.to_le_bytes());
+        let info = decmpfs_info_from_bytes(&unknown).unwrap();
+        assert_eq!(info.storage, None);
+        assert_eq!(info.block_count, None);
+    }
+
+    #[test]
+    fn largest_keeps_top_n() {
+        let mut largest = Largest::new(3);
+        for (value, name) in [(5, "e"), (1, "a"), (9, "i"), (3, "c"), (7, "g"), (2, "b")] {
+            largest.push(value, Path::new(name));
+        }
+        assert_eq!(largest.heap.len(), 3);
+        assert_eq!(
+            largest.into_sorted_vec(),
+            [(9, "i".into()), (7, "g".into()), (5, "e".into())]
+        );
+
+        let mut none = Largest::new(0);
+        none.push(1, Path::new("a"));
+        assert!(none.into_sorted_vec().is_empty());
+    }
+
+    #[test]
+    fn info_sizes_compressed() {
+        let dir = Fixture::new();
+        let path = dir.path().join("file");
+        // Multiple blocks, so the compressed data is stored in the resource fork
+        fs::write(&path, vec![0; 4 * applesauce_core::BLOCK_SIZE]).unwrap();
+
+        let mut fc = FileCompressor::new();
+        dir.compress(&mut fc, &NoProgress);
+
+        let info = get(&path).unwrap();
+        assert!(info.is_compressed);
+        assert_eq!(info.data_on_disk_size, 0);
+        let resource_fork_size = info.resource_fork_size.unwrap();
+        assert!(info.resource_fork_on_disk_size >= resource_fork_size);
+        assert_eq!(info.compressed_size(), info.resource_fork_on_disk_size);
+        assert!(info.compressed_fraction() < 0.5);
+
+        let folder_info = get_recursive(dir.path()).unwrap();
+        assert_eq!(folder_info.total_compressed_size, info.compressed_size());
+        assert_eq!(folder_info.total_other_resource_fork_size, 0);
+    }
+
+    #[test]
+    fn info_top_files() {
+        let dir = Fixture::new();
+        let write = |name: &str, len: usize| dir.file(name, vec![1; len]);
+        let big = write("big", 1024 * 1024);
+        let small = write("small", 64 * 1024);
+        for path in [&big, &small] {
+            compress_file(path, Kind::default(), 5, 1.0, Verify::Off).unwrap();
+        }
+        let large_uncompressed = write("large-uncompressed", 512 * 1024);
+        write("small-uncompressed", 1024);
+
+        let (info, top) = get_recursive_top(dir.path(), 1).unwrap();
+        assert_eq!(info.num_files, 4);
+        assert_eq!(info.num_compressed_files, 2);
+        assert_eq!(top.most_saved.len(), 1);
+        assert_eq!(top.most_saved[0].1, big);
+        let big_info = get(&big).unwrap();
+        assert_eq!(
+            top.most_saved[0].0,
+            big_info.stat_size - big_info.compressed_size()
+        );
+        assert_eq!(top.largest_uncompressed, [(512 * 1024, large_uncompressed)]);
+
+        let (_, top) = get_recursive_top(dir.path(), 10).unwrap();
+        assert_eq!(top.most_saved.len(), 2);
+        assert_eq!(top.most_saved[1].1, small);
+        assert_eq!(top.largest_uncompressed.len(), 2);
+    }
+
+    #[test]
+    fn info_sizes_unrelated_resource_fork() {
+        let dir = Fixture::new();
+        let path = dir.path().join("file");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[1; 8 * 1024]).unwrap();
+        resource_fork::ResourceFork::new(&file)
+            .write_all(&[2; 64 * 1024])
+            .unwrap();
+        drop(file);
+
+        let info = get(&path).unwrap();
+        assert!(!info.is_compressed);
+        assert_eq!(info.resource_fork_size, Some(64 * 1024));
+        assert!(info.resource_fork_on_disk_size >= 64 * 1024);
+        assert!(info.data_on_disk_size >= 8 * 1024);
+        // The resource fork has nothing to do with the file's contents
+        assert_eq!(info.compressed_size(), info.data_on_disk_size);
+
+        let folder_info = get_recursive(dir.path()).unwrap();
+        assert_eq!(folder_info.total_compressed_size, 8 * 1024);
+        assert_eq!(folder_info.total_uncompressed_size, 8 * 1024);
+        assert_eq!(
+            folder_info.total_other_resource_fork_size,
+            info.resource_fork_on_disk_size
+        );
+    }
+
+    #[test]
+    fn info_storage_and_blocks() {
+        let dir = Fixture::new();
+        let text: String = (0..).map(|i| format!("line {i}\n")).take(100_000).collect();
+        let small = &text.as_bytes()[..4000];
+        let large = &text.as_bytes()[..3 * applesauce_core::BLOCK_SIZE + 1];
+        let supported = Kind::all().iter().copied().filter(|kind| kind.supported());
+        for kind in supported {
+            let small_path = dir.path().join(format!("{kind}_small"));
+            let large_path = dir.path().join(format!("{kind}_large"));
+            fs::write(&small_path, small).unwrap();
+            fs::write(&large_path, large).unwrap();
+            compress_file(&small_path, kind, 5, 1.0, Verify::Off).unwrap();
+            compress_file(&large_path, kind, 5, 1.0, Verify::Off).unwrap();
+
+            let small_info = get(&small_path).unwrap();
+            let decmpfs_info = small_info.decmpfs_info.unwrap().unwrap();
+            assert_eq!(decmpfs_info.storage, Some(Storage::Xattr), "{kind}");
+            assert_eq!(decmpfs_info.block_count, Some(1), "{kind}");
+            assert_eq!(small_info.resource_fork_size, None, "{kind}");
+
+            let large_info = get(&large_path).unwrap();
+            let decmpfs_info = large_info.decmpfs_info.unwrap().unwrap();
+            assert_eq!(decmpfs_info.storage, Some(Storage::ResourceFork), "{kind}");
+            assert_eq!(decmpfs_info.block_count, Some(4), "{kind}");
+            assert!(large_info.resource_fork_size.is_some(), "{kind}");
+        }
+    }
+
+    #[test]
+    fn walk_compressed_finds_compressed_files() {
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let uncompressed_dir = dir.path().join("uncompressed");
+        fs::create_dir(&uncompressed_dir).unwrap();
+        fs::write(uncompressed_dir.join("file"), [0; 16 * 1024]).unwrap();
+
+        let mut fc = FileCompressor::new();
+        fc.compress_with(
+            [dir.path().join("subdir").as_path(), &dir.path().join("BIG")],
+            &Fixture::options(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let mut expected: Vec<PathBuf> = WalkDir::new(dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.is_file() && get(path).unwrap().is_compressed)
+            .collect();
+        expected.sort();
+        assert!(!expected.is_empty());
+
+        let mut found = Vec::new();
+        for entry in walk_compressed(dir.path()) {
+            let entry = entry.unwrap();
+            let full_info = get(entry.path()).unwrap();
+            assert_eq!(entry.uncompressed_size(), full_info.stat_size);
+            assert_eq!(entry.on_disk_size(), full_info.on_disk_size());
+
+            let decmpfs_info = entry.decmpfs_info().unwrap().unwrap();
+            let full_decmpfs_info = full_info.decmpfs_info.unwrap().unwrap();
+            assert_eq!(
+                decmpfs_info.compression_type,
+                full_decmpfs_info.compression_type
+            );
+            assert_eq!(
+                decmpfs_info.orig_file_size,
+                full_decmpfs_info.orig_file_size
+            );
+            found.push(entry.into_path());
+        }
+        found.sort();
+        assert_eq!(found, expected);
+
+        let sorted: Vec<PathBuf> = walk_compressed_sorted(dir.path())
+            .map(|entry| entry.unwrap().into_path())
+            .collect();
+        assert_eq!(sorted.len(), expected.len());
+        assert_eq!(
+            sorted,
+            walk_compressed_sorted(dir.path())
+                .map(|entry| entry.unwrap().into_path())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn info_walk_lists_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let mut fc = FileCompressor::new();
+        fc.compress_with(
+            [dir.path().join("subdir").as_path()],
+            &Fixture::options(),
+            &NoProgress,
+        )
+        .unwrap();
+
+        let mut expected: Vec<PathBuf> = WalkDir::new(dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.symlink_metadata().unwrap().is_file())
+            .collect();
+        expected.sort();
+
+        let mut found = Vec::new();
+        for (path, file_info) in walk(dir.path()) {
+            let file_info = file_info.unwrap();
+            let full_info = get(&path).unwrap();
+            assert_eq!(file_info.is_compressed, full_info.is_compressed);
+            assert_eq!(file_info.stat_size, full_info.stat_size);
+            assert_eq!(file_info.on_disk_size(), full_info.on_disk_size());
+            found.push(path);
+        }
+        found.sort();
+        assert_eq!(found, expected);
+
+        let unreadable_dir = dir.path().join("unreadable");
+        fs::create_dir(&unreadable_dir).unwrap();
+        fs::set_permissions(&unreadable_dir, fs::Permissions::from_mode(0o000)).unwrap();
+        let readable = fs::read_dir(&unreadable_dir).is_ok();
+        let results: Vec<_> = walk(dir.path()).collect();
+        fs::set_permissions(&unreadable_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        if readable {
+            // Running as root, permissions aren't enforced
+            return;
+        }
+
+        // The unreadable directory is reported as an error, and the walk carries on
+        let errors: Vec<&PathBuf> = results
+            .iter()
+            .filter(|(_, file_info)| file_info.is_err())
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(errors, [&unreadable_dir]);
+        assert_eq!(results.len(), expected.len() + 1);
+    }
+}
@@ -1,3 +1,45 @@
+//! Transparent compression of files on macOS, using decmpfs (HFS+/APFS file compression)
+//!
+//! Files are compressed with [`FileCompressor::recursive_compress`], and decompressed with
+//! [`FileCompressor::recursive_decompress`]. Compressed files read exactly as the originals
+//! did: decompression is done by the OS as the file is read.
+//!
+//! Both return once every change they make to the filesystem is complete, including restoring
+//! the times of the directories containing processed files.
+//!
+//! # What changes when a file is compressed
+//!
+//! Compressing or decompressing a file writes a new copy of it in a temp file, then renames the
+//! copy over the original.
+//!
+//! **The inode number of every processed file changes.** To anything which identifies files
+//! by inode number (e.g. backup tools, or databases of file ids), a processed file is a new
+//! file. Open file descriptors keep referring to the original, which is unlinked, so a process
+//! still writing to the file writes to the unlinked original, not to the new file. Other hard
+//! links to the original keep referring to the original, unprocessed file.
+//!
+//! The following are guaranteed not to change:
+//!
+//! * The contents, and `st_size`: `stat` reports the uncompressed size of a compressed file
+//! * The mode (permissions), owner, and group (`st_mode`, `st_uid`, `st_gid`), and ACLs
+//! * The modification, creation (`st_birthtime`), and added times, and the access time (unless
+//!   restoring it is disabled with [`FileCompressor::set_time_preservation`])
+//! * Extended attributes, other than the ones which store the compressed data
+//! * The file's name, and its position in a directory listing
+//! * The times of the directory containing the file (with the same exception as the file's
+//!   own times)
+//!
+//! The following change:
+//!
+//! * The inode number (`st_ino`), as described above
+//! * `st_blocks`, which is usually smaller for a compressed file
+//! * `st_flags` gains `UF_COMPRESSED` when compressing, and loses it when decompressing. No other
+//!   flags change.
+//! * The status change time (`st_ctime`), which can't be set
+//! * The `com.apple.decmpfs` xattr and the resource fork hold the compressed data. The OS hides
+//!   both from compressed files.
+//!
+//! Files which are skipped (e.g. because they don't compress enough) are not changed at all.
 #![warn(unsafe_op_in_unsafe_fn)]
 #![warn(clippy::undocumented_unsafe_blocks)]
 #![warn(clippy::cast_lossless)]
@@ -13,31 +55,60 @@ extern crate core;
 compile_error!("applesauce only works on macos/ios");
 
 pub mod info;
+pub mod markers;
 pub mod progress;
-pub use applesauce_core::compressor;
+pub mod protected;
+pub mod rebalance;
+pub use applesauce_core::{compressor, container};
+pub use options::{
+    CompressionOptions, DecompressionOptions, InvalidOptions, DEFAULT_LEVEL, DEFAULT_MINIMUM_RATIO,
+};
+pub use single_file::{
+    can_compress, check_compressed_flag, clear_orphaned_compressed_flag, compress_file,
+    decompress_file, repair_compressed_flag, CompressOutcome, CompressedFlagState,
+    DecompressOutcome, WhyNot,
+};
+pub use threads::{PipelineConfig, DEFAULT_BYTE_BUDGET_PER_THREAD, DEFAULT_UNCACHED_FILE_SIZE};
 
+mod adaptive;
+mod bundles;
+mod clones;
+mod in_use;
+mod options;
+mod os_error;
+mod output;
+mod recent;
 mod rfork_storage;
 mod scan;
+mod scan_attrs;
 mod seq_queue;
+mod single_file;
+mod sparse;
+#[cfg(test)]
+mod testing;
 mod threads;
 mod times;
 mod tmpdir_paths;
 mod xattr;
 
 use libc::c_char;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::fs::{File, Metadata};
 use std::io::prelude::*;
 use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU64;
+use std::sync::Mutex;
+use std::time::Duration;
 use std::{io, mem, ptr};
 use tracing::warn;
 
 use crate::info::{FileCompressionState, FileInfo};
-use crate::progress::Progress;
-use crate::threads::{BackgroundThreads, Mode};
+use crate::progress::{Progress, SkipReason};
+use crate::threads::{BackgroundThreads, Mode, ScanOptions};
 use applesauce_core::compressor::Kind;
 
 const fn c_char_bytes(chars: &[c_char]) -> &[u8] {
@@ -82,7 +153,10 @@ fn vol_supports_compression_cap(mnt_root: &CStr) -> io::Result<bool> {
         )
     };
     if rc != 0 {
-        return Err(io::Error::last_os_error());
+        return Err(os_error::last(format_args!(
+            "getattrlist({}, ATTR_VOL_CAPABILITIES)",
+            mnt_root.to_string_lossy()
+        )));
     }
     // SAFETY: getattrlist returned success
     let vol_attrs = unsafe { vol_attrs.assume_init_ref() };
@@ -96,6 +170,20 @@ fn vol_supports_compression_cap(mnt_root: &CStr) -> io::Result<bool> {
     Ok(vol_attrs.vol_attrs.valid[IDX] & vol_attrs.vol_attrs.capabilities[IDX] & MASK != 0)
 }
 
+/// Convert `path` to a C string, to pass to the OS
+///
+/// Any bytes other than NUL (including bytes which aren't valid UTF-8) are passed through as
+/// they are. No file can have a path containing a NUL byte, so such paths fail with an error
+/// saying so, rather than the generic error from [`CString::new`].
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path contains a NUL byte, no file can have this name",
+        )
+    })
+}
+
 #[tracing::instrument(level = "trace", skip_all, fields(flags), err)]
 fn set_flags(file: &File, flags: libc::c_uint) -> io::Result<()> {
     let rc =
@@ -104,7 +192,7 @@ fn set_flags(file: &File, flags: libc::c_uint) -> io::Result<()> {
     if rc == 0 {
         Ok(())
     } else {
-        Err(io::Error::last_os_error())
+        Err(os_error::last(format_args!("fchflags({flags:#x})")))
     }
 }
 
@@ -125,37 +213,408 @@ pub struct Stats {
 
     /// Number of files that were incompressible (only present when compressing)
     pub incompressible_file_count: AtomicU64,
+
+    /// Number of files by what happened to them, one outcome for each file in [`Self::files`]
+    pub outcomes: OutcomeStats,
+
+    /// Number of files skipped, by reason
+    pub skipped: SkipStats,
+
+    /// Number of directories which could not be read, so none of their contents were processed
+    pub unreadable_dirs: AtomicU64,
+
+    /// Number of files which could not be processed because of an error, including files skipped
+    /// because they couldn't be read
+    ///
+    /// Each is reported with [`progress::Task::error`] or [`Progress::error`], or skipped with
+    /// [`SkipReason::ReadError`].
+    pub file_errors: AtomicU64,
+
+    /// The first errors processing individual files, including missing roots
+    pub errors: ErrorLog,
+
+    /// Number of paths passed to the operation which don't exist (or couldn't be read), so
+    /// nothing under them was processed
+    ///
+    /// Each is reported with [`Progress::error`].
+    pub missing_roots: AtomicU64,
+
+    /// Number of paths passed to a compression which are on a volume that doesn't support
+    /// compression (e.g. exFAT or a network volume), so none of the files under them can be
+    /// compressed
+    ///
+    /// Each is reported with [`Progress::error`], and the files under them are still scanned and
+    /// skipped with [`SkipReason::FsNotSupported`].
+    pub unsupported_roots: AtomicU64,
+
+    /// Number of files decompressing manually with a compression type applesauce can't
+    /// decompress, which were decompressed by the OS instead
+    pub decompressed_by_os: AtomicU64,
+
+    /// Number of compressed files missing their resource fork which were cleared, leaving them
+    /// empty
+    ///
+    /// Only counted when force clearing, see [`FileCompressor::set_force_clear`].
+    pub cleared_missing_resource_fork: AtomicU64,
+
+    /// Number of bundles whose code signature was valid before files in them were processed, but
+    /// not after
+    ///
+    /// Each is reported with [`Progress::error`]. Only checked when verifying signatures, see
+    /// [`FileCompressor::set_verify_signatures`].
+    pub invalid_signatures: AtomicU64,
+
+    /// Number of directories which didn't have their saved times at the end of the run
+    ///
+    /// Only counted when checking directory times.
+    pub dir_times_not_restored: AtomicU64,
+
+    /// Total bytes of file contents read
+    ///
+    /// When decompressing manually, this is the compressed data read from the resource fork or
+    /// decmpfs xattr.
+    pub bytes_read: AtomicU64,
+    /// Total bytes written to new files, including the resource fork and decmpfs xattr of
+    /// compressed files
+    pub bytes_written: AtomicU64,
+
+    /// How long the operation took, set once it has finished
+    elapsed: Duration,
+
+    /// An error which stopped the run before every file was processed
+    ///
+    /// e.g. a background thread panicking while processing a file.
+    pub fatal_error: Option<String>,
 }
 
-impl Stats {
-    fn add_start_file(&self, metadata: &Metadata, file_info: &FileInfo) {
-        self.files
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        self.total_file_sizes
-            .fetch_add(metadata.len(), std::sync::atomic::Ordering::Relaxed);
-        self.compressed_size_start
-            .fetch_add(file_info.on_disk_size, std::sync::atomic::Ordering::Relaxed);
-        match file_info.compression_state {
-            FileCompressionState::Compressed => {
-                self.compressed_file_count_start
-                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            }
-            FileCompressionState::Compressible => {}
-            FileCompressionState::Incompressible(_) => {
-                self.incompressible_file_count
-                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            }
+/// The most errors kept by an [`ErrorLog`], later errors are only counted
+pub const ERROR_LOG_LIMIT: usize = 100;
+
+/// An error processing a single path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+/// The first [`ERROR_LOG_LIMIT`] errors of an operation, and a count of the rest
+///
+/// Errors are kept in the order they were reported, which is not necessarily the order files were
+/// found in.
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    entries: Mutex<Vec<PathError>>,
+    omitted: AtomicU64,
+}
+
+impl ErrorLog {
+    fn record(&self, path: &Path, message: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() < ERROR_LOG_LIMIT {
+            entries.push(PathError {
+                path: path.to_owned(),
+                message: message.to_owned(),
+            });
+        } else {
+            self.omitted
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         }
     }
 
-    fn add_end_file(&self, _metadata: &Metadata, file_info: &FileInfo) {
-        self.compressed_size_final
-            .fetch_add(file_info.on_disk_size, std::sync::atomic::Ordering::Relaxed);
-        if let FileCompressionState::Compressed = file_info.compression_state {
-            self.compressed_file_count_final
-                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    /// The errors which were kept, in the order they were reported
+    #[must_use]
+    pub fn entries(&self) -> Vec<PathError> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// The number of errors reported after the log was full
+    #[must_use]
+    pub fn omitted(&self) -> u64 {
+        self.omitted.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// The total number of errors reported, kept or not
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.entries.lock().unwrap().len() as u64 + self.omitted()
+    }
+}
+
+/// Counts of skipped files, one for each reason a file can be skipped
+#[derive(Debug, Default)]
+pub struct SkipStats {
+    pub not_file: AtomicU64,
+    pub already_compressed: AtomicU64,
+    pub not_compressed: AtomicU64,
+    pub empty_file: AtomicU64,
+    pub too_large: AtomicU64,
+    pub read_error: AtomicU64,
+    pub zfs_filesystem: AtomicU64,
+    pub has_required_xattr: AtomicU64,
+    pub fs_not_supported: AtomicU64,
+    pub sip_protected: AtomicU64,
+    pub requires_full_disk_access: AtomicU64,
+    pub file_modified: AtomicU64,
+    /// Files whose contents are not stored locally, which would be downloaded if read
+    pub dataless: AtomicU64,
+    /// Files which may share blocks with another file, when skipping clones
+    pub probably_clone: AtomicU64,
+    /// Files in log locations which were modified recently, so are probably still being written
+    pub recently_active_log: AtomicU64,
+    /// Files modified more recently than allowed
+    pub recently_modified: AtomicU64,
+    /// Files which another process has open for writing
+    pub in_use: AtomicU64,
+    /// Sparse files, when not compressing them
+    pub sparse: AtomicU64,
+    /// Files marked as not compressing enough on a previous run, when remembering them
+    pub previously_incompressible: AtomicU64,
+    /// Files in a directory where nearly every file attempted did not compress enough
+    pub directory_deemed_incompressible: AtomicU64,
+    /// Compressed files whose resource fork, holding their compressed data, is missing
+    pub missing_resource_fork: AtomicU64,
+    /// Files and directories excluded from Time Machine backups, when skipping them
+    ///
+    /// An excluded directory counts once, however many files are inside it.
+    pub excluded: AtomicU64,
+    /// Bundles, and paths inside bundles, when skipping them
+    ///
+    /// A bundle counts once, however many files are inside it.
+    pub in_bundle: AtomicU64,
+    /// Files which did not compress to the minimum compression ratio
+    pub not_compressible_enough: AtomicU64,
+    /// Files which could not be compressed because of the size or number of their existing xattrs
+    pub xattr_limit_exceeded: AtomicU64,
+}
+
+impl SkipStats {
+    fn add(&self, reason: &SkipReason) {
+        let counter = match reason {
+            SkipReason::NotFile => &self.not_file,
+            SkipReason::AlreadyCompressed => &self.already_compressed,
+            SkipReason::NotCompressed => &self.not_compressed,
+            SkipReason::EmptyFile => &self.empty_file,
+            SkipReason::TooLarge { .. } => &self.too_large,
+            SkipReason::ReadError(_) => &self.read_error,
+            SkipReason::ZfsFilesystem => &self.zfs_filesystem,
+            SkipReason::HasRequiredXattr => &self.has_required_xattr,
+            SkipReason::FsNotSupported => &self.fs_not_supported,
+            SkipReason::SipProtected => &self.sip_protected,
+            SkipReason::RequiresFullDiskAccess => &self.requires_full_disk_access,
+            SkipReason::FileModified => &self.file_modified,
+            SkipReason::Dataless => &self.dataless,
+            SkipReason::ProbablyClone => &self.probably_clone,
+            SkipReason::RecentlyActiveLog => &self.recently_active_log,
+            SkipReason::RecentlyModified => &self.recently_modified,
+            SkipReason::DirectoryDeemedIncompressible => &self.directory_deemed_incompressible,
+            SkipReason::MissingResourceFork => &self.missing_resource_fork,
+            SkipReason::Excluded => &self.excluded,
+            SkipReason::InBundle => &self.in_bundle,
+            SkipReason::InUse => &self.in_use,
+            SkipReason::Sparse => &self.sparse,
+            SkipReason::PreviouslyIncompressible => &self.previously_incompressible,
+            SkipReason::XattrLimitExceeded(_) => &self.xattr_limit_exceeded,
+        };
+        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Short descriptions of each reason, paired with the number of files skipped for that reason
+    #[must_use]
+    pub fn counts(&self) -> [(&'static str, u64); 25] {
+        let load = |counter: &AtomicU64| counter.load(std::sync::atomic::Ordering::Relaxed);
+        [
+            ("not a file", load(&self.not_file)),
+            ("already compressed", load(&self.already_compressed)),
+            ("not compressed", load(&self.not_compressed)),
+            ("empty", load(&self.empty_file)),
+            ("too large", load(&self.too_large)),
+            ("read error", load(&self.read_error)),
+            ("zfs filesystem", load(&self.zfs_filesystem)),
+            ("compression xattrs present", load(&self.has_required_xattr)),
+            ("filesystem not supported", load(&self.fs_not_supported)),
+            ("SIP protected", load(&self.sip_protected)),
+            (
+                "requires full disk access",
+                load(&self.requires_full_disk_access),
+            ),
+            ("modified while reading", load(&self.file_modified)),
+            ("not stored locally", load(&self.dataless)),
+            ("probably a clone", load(&self.probably_clone)),
+            ("recently active log", load(&self.recently_active_log)),
+            ("recently modified", load(&self.recently_modified)),
+            (
+                "directory incompressible",
+                load(&self.directory_deemed_incompressible),
+            ),
+            ("missing resource fork", load(&self.missing_resource_fork)),
+            ("excluded from backups", load(&self.excluded)),
+            ("in a bundle", load(&self.in_bundle)),
+            ("open for writing", load(&self.in_use)),
+            ("sparse", load(&self.sparse)),
+            (
+                "previously incompressible",
+                load(&self.previously_incompressible),
+            ),
+            (
+                "not compressible enough",
+                load(&self.not_compressible_enough),
+            ),
+            ("too many xattrs", load(&self.xattr_limit_exceeded)),
+        ]
+    }
+
+    /// Total number of files skipped, for any reason
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.counts().iter().map(|&(_, count)| count).sum()
+    }
+}
+
+/// What happened to a file counted in [`Stats::files`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileOutcome {
+    /// The file was compressed
+    Compressed,
+    /// The file was decompressed (or cleared, when force clearing a file missing its resource
+    /// fork)
+    Decompressed,
+    /// The file was skipped, and left as it was
+    Skipped,
+    /// The file was left as it was because of an error
+    ///
+    /// This includes files skipped because they couldn't be read, and files which were never
+    /// processed because the run was stopped by a fatal error.
+    Error,
+}
+
+impl FileOutcome {
+    /// The outcome of a file skipped for `reason`
+    fn of_skip(reason: &SkipReason) -> Self {
+        if matches!(reason, SkipReason::ReadError(_)) {
+            Self::Error
+        } else {
+            Self::Skipped
         }
     }
+}
+
+/// Counts of files by [`FileOutcome`]
+#[derive(Debug, Default)]
+pub struct OutcomeStats {
+    pub compressed: AtomicU64,
+    pub decompressed: AtomicU64,
+    pub skipped: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl OutcomeStats {
+    fn counter(&self, outcome: FileOutcome) -> &AtomicU64 {
+        match outcome {
+            FileOutcome::Compressed => &self.compressed,
+            FileOutcome::Decompressed => &self.decompressed,
+            FileOutcome::Skipped => &self.skipped,
+            FileOutcome::Error => &self.errors,
+        }
+    }
+
+    /// The number of files with `outcome`
+    #[must_use]
+    pub fn get(&self, outcome: FileOutcome) -> u64 {
+        self.counter(outcome)
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Total number of files, with any outcome
+    ///
+    /// This is always the same as [`Stats::files`].
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        [
+            FileOutcome::Compressed,
+            FileOutcome::Decompressed,
+            FileOutcome::Skipped,
+            FileOutcome::Error,
+        ]
+        .into_iter()
+        .map(|outcome| self.get(outcome))
+        .sum()
+    }
+}
+
+/// The size and compression state of a file at the start or end of an operation
+#[derive(Debug, Copy, Clone)]
+struct FileSnapshot {
+    size: u64,
+    on_disk_size: u64,
+    compressed: bool,
+    incompressible: bool,
+}
+
+impl FileSnapshot {
+    fn new(metadata: &Metadata, file_info: &FileInfo) -> Self {
+        Self {
+            size: metadata.len(),
+            on_disk_size: file_info.on_disk_size,
+            compressed: matches!(
+                file_info.compression_state,
+                FileCompressionState::Compressed
+            ),
+            incompressible: matches!(
+                file_info.compression_state,
+                FileCompressionState::Incompressible(_)
+            ),
+        }
+    }
+}
+
+impl Stats {
+    /// Count a file, as it was at the `start` and `end` of the operation
+    ///
+    /// This is called exactly once for each file counted, once its outcome is known, so the start
+    /// and end totals always cover the same files.
+    fn add_file(&self, start: &FileSnapshot, end: &FileSnapshot, outcome: FileOutcome) {
+        let add = |counter: &AtomicU64, n: u64| {
+            counter.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+        };
+        add(&self.files, 1);
+        add(&self.total_file_sizes, start.size);
+        add(&self.compressed_size_start, start.on_disk_size);
+        add(&self.compressed_size_final, end.on_disk_size);
+        add(
+            &self.compressed_file_count_start,
+            u64::from(start.compressed),
+        );
+        add(&self.compressed_file_count_final, u64::from(end.compressed));
+        add(
+            &self.incompressible_file_count,
+            u64::from(start.incompressible),
+        );
+        add(self.outcomes.counter(outcome), 1);
+    }
+
+    /// Count a file skipped for `reason`, which is also an error if the file couldn't be read
+    fn add_skipped(&self, path: &Path, reason: &SkipReason) {
+        self.skipped.add(reason);
+        if matches!(reason, SkipReason::ReadError(_)) {
+            self.add_file_error();
+            self.errors.record(path, &reason.to_string());
+        }
+    }
+
+    fn add_file_error(&self) {
+        self.file_errors
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn add_read(&self, len: usize) {
+        self.bytes_read
+            .fetch_add(len as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn add_written(&self, len: u64) {
+        self.bytes_written
+            .fetch_add(len, std::sync::atomic::Ordering::Relaxed);
+    }
 
     #[must_use]
     pub fn compression_savings(&self) -> f64 {
@@ -180,11 +639,194 @@ impl Stats {
         // we want a smaller final size to be a positive change in compression
         (compressed_size_start as f64 - compressed_size_final as f64) / compressed_size_start as f64
     }
+
+    /// How long the operation took, from starting the scan until the last file was finished
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Bytes read per second, over the whole operation
+    #[must_use]
+    pub fn read_throughput(&self) -> f64 {
+        self.throughput(&self.bytes_read)
+    }
+
+    /// Bytes written per second, over the whole operation
+    #[must_use]
+    pub fn write_throughput(&self) -> f64 {
+        self.throughput(&self.bytes_written)
+    }
+
+    fn throughput(&self, bytes: &AtomicU64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        bytes.load(std::sync::atomic::Ordering::Relaxed) as f64 / secs
+    }
+}
+
+/// How to treat files and directories which are excluded from Time Machine backups
+///
+/// Items are excluded by the `com.apple.metadata:com_apple_backup_excludeItem` xattr, which is
+/// set by `tmutil addexclusion`. Everything inside an excluded directory is also excluded.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum BackupExclusion {
+    /// Process files whether or not they are excluded
+    #[default]
+    Ignore,
+    /// Don't process excluded files, or anything in excluded directories
+    Skip,
+    /// Only process excluded files, and files in excluded directories
+    Only,
 }
 
-#[derive(Default)]
+/// How to treat app, framework, and kernel extension bundles (`.app`, `.framework`, and `.kext`
+/// directories)
+///
+/// Compressing a file doesn't change its contents, so it doesn't invalidate the code signature
+/// of the bundle it's in. But each file is replaced with a new file, which can stop a bundle
+/// passing strict validation, so a bundle which stops launching is a risk.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum BundlePolicy {
+    /// Process files in bundles, reporting each bundle once with
+    /// [`Progress::bundle_entered`]
+    #[default]
+    Warn,
+    /// Don't process anything inside a bundle
+    Skip,
+}
+
+/// Which times of a file to restore after it is replaced
+///
+/// Compressing or decompressing a file replaces it with a new file, and reads the original, so
+/// without restoring them, all of its times would change. The times of the parent directory
+/// are restored in the same way, unless something else modified the directory during the run
+/// (e.g. by creating a file in it), in which case its new times are left as they are.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TimePreservation {
+    /// Restore the access, modification, creation, and added times
+    #[default]
+    All,
+    /// Restore the modification, creation, and added times, but leave the access time
+    /// reflecting that the file was read
+    ModificationOnly,
+    /// Don't restore any times
+    None,
+}
+
+/// The order files are processed in
+///
+/// The walk reads directories in parallel, so by default files are processed in the order
+/// they're found. A few very large files found late in a run can leave a single thread
+/// processing them long after every other file is done: starting them first lets them overlap
+/// with the many small files instead.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FileOrder {
+    /// Process files as soon as they are found
+    #[default]
+    Discovery,
+    /// Find every file first, then process the largest files first
+    LargestFirst,
+    /// Find every file first, then process the smallest files first
+    SmallestFirst,
+}
+
+/// The scheduling priority of the background threads
+///
+/// Compressor threads always run at a lower priority than the threads reading and writing files,
+/// so disk access is not held up behind compression when every core is busy.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Priority {
+    /// Run at a priority suitable for work the user is waiting for
+    #[default]
+    Normal,
+    /// Run all threads at a lower priority, for fully background operation
+    Background,
+}
+
+/// How to check that a newly written file has the same contents as the original
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Verify {
+    /// Trust that the file was written correctly
+    #[default]
+    Off,
+    /// Check the structure of the new file, without reading back any of its data
+    ///
+    /// This checks that the recorded uncompressed size matches the original, that the block
+    /// table has the right number of blocks, that the resource fork is exactly as long as its
+    /// header, blocks, and trailer, and that the file is marked compressed with an empty data
+    /// fork. This is nearly free, but cannot detect corrupted blocks. When decompressing, this
+    /// is the same as [`Verify::Off`].
+    Quick,
+    /// Hash the original while reading it, and compare it against the hash of the decompressed
+    /// blocks of the new file
+    ///
+    /// This avoids reading the original file twice, and checks the compressed data itself
+    Checksum,
+    /// Compare the new file against the original byte by byte, reading both files again
+    Full,
+}
+
+/// How much to flush to stable storage before and after replacing each file
+///
+/// Renaming the new file over the original is atomic, but without flushing, a power loss soon
+/// after a file is replaced can leave the new name pointing to a file whose data or xattrs
+/// (which hold the compressed data) were never written to disk.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Durability {
+    /// Don't flush anything, leaving it to the OS
+    #[default]
+    None,
+    /// Flush the data and xattrs of each new file (with `F_FULLFSYNC`) before it is renamed
+    Data,
+    /// Also flush the directory containing each new file after it is renamed, so the rename
+    /// itself is on disk
+    Full,
+}
+
+/// The default number of times to retry a file which changes while it is being read
+pub const DEFAULT_FILE_CHANGE_RETRIES: u32 = 1;
+
+/// The default time since a file in a log location was modified, within which it is skipped
+pub const DEFAULT_ACTIVE_LOG_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// The default number of files attempted in a directory before the rest of it may be skipped,
+/// if nearly all of them failed to compress enough
+pub const DEFAULT_ADAPTIVE_SKIP_ATTEMPTS: u32 = 25;
+
 pub struct FileCompressor {
     bg_threads: BackgroundThreads,
+    file_change_retries: u32,
+    capture_metadata: bool,
+    backup_exclusion: BackupExclusion,
+    bundle_policy: BundlePolicy,
+    verify_signatures: bool,
+    time_preservation: TimePreservation,
+    sample_blocks: Option<u32>,
+    skip_clones: bool,
+    clone_temp_files: bool,
+    durability: Durability,
+    strict_manual: bool,
+    force_clear: bool,
+    check_dir_times: bool,
+    output_dir: Option<PathBuf>,
+    overwrite_output: bool,
+    active_log_window: Option<Duration>,
+    skip_recently_modified: Option<Duration>,
+    skip_in_use: bool,
+    compress_sparse: bool,
+    remember_incompressible: bool,
+    adaptive_skip_attempts: Option<u32>,
+    adaptive_skip_subdirectories: bool,
+    file_order: FileOrder,
+}
+
+impl Default for FileCompressor {
+    fn default() -> Self {
+        Self::with_config(PipelineConfig::default())
+    }
 }
 
 impl FileCompressor {
@@ -193,15 +835,366 @@ impl FileCompressor {
         Self::default()
     }
 
+    /// Create a compressor which keeps at most `byte_budget` bytes of file data in memory at once
+    ///
+    /// By default, the budget is [`DEFAULT_BYTE_BUDGET_PER_THREAD`] for each compressor thread.
+    #[must_use]
+    pub fn with_byte_budget(byte_budget: u64) -> Self {
+        Self::with_config(PipelineConfig {
+            byte_budget: Some(byte_budget),
+            ..PipelineConfig::default()
+        })
+    }
+
+    /// Create a compressor with the thread and queue sizes in `config`
+    #[must_use]
+    pub fn with_config(config: PipelineConfig) -> Self {
+        Self {
+            bg_threads: BackgroundThreads::with_config(config),
+            file_change_retries: DEFAULT_FILE_CHANGE_RETRIES,
+            capture_metadata: false,
+            backup_exclusion: BackupExclusion::default(),
+            bundle_policy: BundlePolicy::default(),
+            verify_signatures: false,
+            time_preservation: TimePreservation::default(),
+            sample_blocks: None,
+            skip_clones: false,
+            clone_temp_files: false,
+            durability: Durability::default(),
+            strict_manual: false,
+            force_clear: false,
+            check_dir_times: false,
+            output_dir: None,
+            overwrite_output: false,
+            active_log_window: Some(DEFAULT_ACTIVE_LOG_WINDOW),
+            skip_recently_modified: None,
+            skip_in_use: true,
+            remember_incompressible: false,
+            compress_sparse: false,
+            adaptive_skip_attempts: Some(DEFAULT_ADAPTIVE_SKIP_ATTEMPTS),
+            adaptive_skip_subdirectories: false,
+            file_order: FileOrder::default(),
+        }
+    }
+
+    /// Set the number of times to retry a file which changes while it is being read
+    ///
+    /// If the file is still changing after all retries, it is skipped with
+    /// [`SkipReason::FileModified`].
+    pub fn set_file_change_retries(&mut self, retries: u32) {
+        self.file_change_retries = retries;
+    }
+
+    /// Capture the compression metadata of each compressed file
+    ///
+    /// When enabled, the [`FileSummary`](progress::FileSummary) passed to
+    /// [`Task::finished`](progress::Task::finished) includes the decmpfs xattr, and the length
+    /// and hash of the resource fork of each compressed file. This requires reading back the
+    /// resource fork of each file, so it is disabled by default.
+    pub fn set_capture_metadata(&mut self, capture_metadata: bool) {
+        self.capture_metadata = capture_metadata;
+    }
+
+    /// Set how to treat files which are excluded from Time Machine backups
+    pub fn set_backup_exclusion(&mut self, backup_exclusion: BackupExclusion) {
+        self.backup_exclusion = backup_exclusion;
+    }
+
+    /// Set how to treat app, framework, and kernel extension bundles
+    ///
+    /// By default, files in bundles are processed, and each bundle is reported once.
+    pub fn set_bundle_policy(&mut self, bundle_policy: BundlePolicy) {
+        self.bundle_policy = bundle_policy;
+    }
+
+    /// Check the code signature of each bundle files were processed in, once every file is done
+    ///
+    /// Each signature is checked with `codesign --verify` before the first file in the bundle is
+    /// processed, and again at the end. Bundles which were validly signed before, but not after,
+    /// are reported with [`Progress::error`], and counted in [`Stats::invalid_signatures`].
+    /// Disabled by default, checking a signature reads every file in the bundle.
+    pub fn set_verify_signatures(&mut self, verify_signatures: bool) {
+        self.verify_signatures = verify_signatures;
+    }
+
+    /// Set which times of each file (and its parent directory) to restore after processing it
+    ///
+    /// By default, all times are restored.
+    pub fn set_time_preservation(&mut self, time_preservation: TimePreservation) {
+        self.time_preservation = time_preservation;
+    }
+
+    /// Decide whether each file is worth compressing from only its first `sample_blocks` blocks
+    ///
+    /// When compressing, if the first `sample_blocks` blocks of a file don't compress to the
+    /// minimum compression ratio, the file is skipped as not compressible enough, and only a few
+    /// blocks past the sample are read. Otherwise, compression continues with the rest of the
+    /// file. This saves reading all of large files which are already compressed (e.g. media).
+    ///
+    /// By default (or with `None` or `Some(0)`), the whole file is always compressed before
+    /// deciding.
+    pub fn set_sample_blocks(&mut self, sample_blocks: Option<u32>) {
+        self.sample_blocks = sample_blocks;
+    }
+
+    /// Skip files which may share their blocks with another file when compressing
+    ///
+    /// Compressing an APFS clone (e.g. made by `cp -c`) replaces it with a new file which doesn't
+    /// share any blocks, so it can use more disk space than before. When enabled, files which APFS
+    /// reports may share blocks are skipped with [`SkipReason::ProbablyClone`]. Both the
+    /// original file and its clones are skipped. Disabled by default.
+    pub fn set_skip_clones(&mut self, skip_clones: bool) {
+        self.skip_clones = skip_clones;
+    }
+
+    /// Create each new file as an APFS clone of the file it replaces
+    ///
+    /// The clone starts with all of the original's xattrs and security info, so they don't need
+    /// to be copied separately, and metadata which copying misses is kept. Files on volumes which
+    /// aren't APFS, and files written to an output directory, use plain temp files.
+    ///
+    /// APFS marks every clone as possibly sharing its blocks, and the mark is never removed, so
+    /// every file written this way is skipped by later runs with
+    /// [`set_skip_clones`](Self::set_skip_clones). Disabled by default.
+    pub fn set_clone_temp_files(&mut self, clone_temp_files: bool) {
+        self.clone_temp_files = clone_temp_files;
+    }
+
+    /// Set how much to flush to stable storage when replacing each file
+    ///
+    /// Flushing makes runs much slower, especially with many small files. By default,
+    /// [`Durability::None`].
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Fail files which can't be decompressed manually, rather than letting the OS decompress them
+    ///
+    /// When decompressing manually, files with a compression type which applesauce can't
+    /// decompress (but the running OS may) are decompressed by reading them, as if not
+    /// decompressing manually. When enabled, these files fail instead. Disabled by default.
+    pub fn set_strict_manual(&mut self, strict_manual: bool) {
+        self.strict_manual = strict_manual;
+    }
+
+    /// Clear the compression of files missing the resource fork holding their compressed data
+    ///
+    /// Such a file can't be read (the OS fails reads with `EIO`), or decompressed: its contents
+    /// are lost. When decompressing, these files are skipped by default, with
+    /// [`SkipReason::MissingResourceFork`]. When enabled, the file's compressed flag and decmpfs
+    /// xattr are removed instead, leaving an empty file which can be read. Disabled by default.
+    ///
+    /// Files are only cleared in place, never when writing to an output directory.
+    pub fn set_force_clear(&mut self, force_clear: bool) {
+        self.force_clear = force_clear;
+    }
+
+    /// Skip files in log locations modified within `window` when compressing
+    ///
+    /// A log file which is still being written (e.g. a launchd service's stdout or stderr) keeps
+    /// being written to the original file after it is replaced, so everything written after that
+    /// is lost. Files under `/var/log`, `/Library/Logs`, and `~/Library/Logs` which were modified
+    /// within `window` are skipped with [`SkipReason::RecentlyActiveLog`]. By default, the window
+    /// is [`DEFAULT_ACTIVE_LOG_WINDOW`], `None` never skips log files.
+    pub fn set_active_log_window(&mut self, window: Option<Duration>) {
+        self.active_log_window = window;
+    }
+
+    /// Skip any file modified within `window` when compressing
+    ///
+    /// Files are skipped with [`SkipReason::RecentlyModified`]. Disabled by default.
+    pub fn set_skip_recently_modified(&mut self, window: Option<Duration>) {
+        self.skip_recently_modified = window;
+    }
+
+    /// Skip files which any process has open for writing when compressing
+    ///
+    /// Replacing a file which is still being written (e.g. a log, or a SQLite write-ahead log)
+    /// loses everything written after it is replaced, or fails when the file changes while it is
+    /// read. When enabled, each file is checked for descriptors open for writing in any process
+    /// which can be inspected, and skipped with [`SkipReason::InUse`]. This is best-effort: it
+    /// can't see processes of other users unless running as root, and a file may be opened after
+    /// the check. The check lists the open files of processes which have the file open, so it has
+    /// a cost for each file. Enabled by default.
+    pub fn set_skip_in_use(&mut self, skip_in_use: bool) {
+        self.skip_in_use = skip_in_use;
+    }
+
+    /// Compress sparse files, rather than skipping them
+    ///
+    /// A compressed file can't have holes, so compressing a sparse file (e.g. a disk image with
+    /// large unused areas) reads and compresses every hole as zeros, and allocates space for the
+    /// result. Even if that is small, the temp file written first can be much larger than the
+    /// space the original used. By default, files with holes, and less than half of their length
+    /// allocated, are skipped with [`SkipReason::Sparse`]. When enabled, they are compressed,
+    /// with a warning.
+    ///
+    /// Decompressing always leaves blocks of zeros as holes.
+    pub fn set_compress_sparse(&mut self, compress_sparse: bool) {
+        self.compress_sparse = compress_sparse;
+    }
+
+    /// Remember files which don't compress enough, and skip them on later runs
+    ///
+    /// When enabled, a file which fails the minimum compression ratio is marked with a small xattr
+    /// ([`markers::XATTR_NAME`]), recording its modification time and size, and the kind and
+    /// ratio it failed with. Later runs with this enabled skip marked files with
+    /// [`SkipReason::PreviouslyIncompressible`] without reading them, unless the file has changed
+    /// (its marker is then removed), or it's compressed with another kind or a looser minimum
+    /// ratio. Files are not marked when writing to an output directory. Disabled by default.
+    ///
+    /// Markers can be removed with [`markers::clean`].
+    pub fn set_remember_incompressible(&mut self, remember_incompressible: bool) {
+        self.remember_incompressible = remember_incompressible;
+    }
+
+    /// Skip the rest of a directory where nearly every file fails to compress enough
+    ///
+    /// Directories of files which are already compressed (e.g. photos or videos) are common, and
+    /// attempting each file wastes time reading and compressing it. When compressing, once at
+    /// least `min_attempts` files in a directory have been attempted, and at least 90% of them
+    /// did not compress to the minimum compression ratio, the remaining files in the directory are
+    /// skipped with [`SkipReason::DirectoryDeemedIncompressible`]. By default, the minimum is
+    /// [`DEFAULT_ADAPTIVE_SKIP_ATTEMPTS`], `None` or `Some(0)` attempts every file.
+    pub fn set_adaptive_skip(&mut self, min_attempts: Option<u32>) {
+        self.adaptive_skip_attempts = min_attempts;
+    }
+
+    /// Also skip files in subdirectories of a directory skipped by
+    /// [`FileCompressor::set_adaptive_skip`]
+    ///
+    /// Disabled by default.
+    pub fn set_adaptive_skip_subdirectories(&mut self, subdirectories: bool) {
+        self.adaptive_skip_subdirectories = subdirectories;
+    }
+
+    /// Set the order files are processed in
+    ///
+    /// With any order but [`FileOrder::Discovery`] (the default), files are only processed once
+    /// they have all been found, so nothing is processed while the walk is running. To keep the
+    /// memory used bounded, files in very large trees are ordered in batches of a few hundred
+    /// thousand files, each processed before the next is found.
+    pub fn set_file_order(&mut self, file_order: FileOrder) {
+        self.file_order = file_order;
+    }
+
+    /// Check that directory times were restored at the end of each run
+    ///
+    /// When enabled, the times of every directory restored after processing files in it are read
+    /// again once all files are processed. Each directory without its saved times (e.g. because
+    /// restoring them failed, or the directory was removed) is reported with
+    /// [`Progress::dir_times_not_restored`], and counted in
+    /// [`Stats::dir_times_not_restored`]. Disabled by default.
+    pub fn set_check_dir_times(&mut self, check_dir_times: bool) {
+        self.check_dir_times = check_dir_times;
+    }
+
+    /// Write compressed copies of files into `output_dir`, rather than replacing them
+    ///
+    /// When compressing, each path is copied into `output_dir` under its own name, with the same
+    /// directory structure, and the originals are left untouched. Files which aren't compressed
+    /// (e.g. they are skipped, or don't compress enough) are copied unchanged, except for files
+    /// which are not stored locally. Directories are created with the permissions and times of
+    /// the originals. Decompressing always replaces files in place. By default (`None`), files
+    /// are replaced in place.
+    pub fn set_output_dir(&mut self, output_dir: Option<PathBuf>) {
+        self.output_dir = output_dir;
+    }
+
+    /// Replace existing files in the output directory
+    ///
+    /// By default, a file which already exists in the output directory is reported as an error,
+    /// and left as it is. Only used with [`FileCompressor::set_output_dir`].
+    pub fn set_overwrite_output(&mut self, overwrite_output: bool) {
+        self.overwrite_output = overwrite_output;
+    }
+
+    /// Set the scheduling priority of the background threads
+    ///
+    /// By default, threads run at [`Priority::Normal`].
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.bg_threads.set_priority(priority);
+    }
+
+    fn scan_options(&self, verify: Verify) -> ScanOptions {
+        ScanOptions {
+            verify,
+            file_change_retries: self.file_change_retries,
+            capture_metadata: self.capture_metadata,
+            backup_exclusion: self.backup_exclusion,
+            bundle_policy: self.bundle_policy,
+            verify_signatures: self.verify_signatures,
+            time_preservation: self.time_preservation,
+            skip_clones: self.skip_clones,
+            clone_temp_files: self.clone_temp_files,
+            durability: self.durability,
+            strict_manual: self.strict_manual,
+            force_clear: self.force_clear,
+            check_dir_times: self.check_dir_times,
+            output_dir: None,
+            overwrite_output: self.overwrite_output,
+            recency: recent::Recency {
+                active_log_window: self.active_log_window,
+                modified_window: self.skip_recently_modified,
+            },
+            skip_in_use: self.skip_in_use,
+            compress_sparse: self.compress_sparse,
+            remember_incompressible: self.remember_incompressible,
+            adaptive_skip: self
+                .adaptive_skip_attempts
+                .map(|min_attempts| adaptive::Settings {
+                    min_attempts,
+                    subdirectories: self.adaptive_skip_subdirectories,
+                }),
+            file_order: self.file_order,
+        }
+    }
+
+    /// Compress every file under each of `paths`
+    ///
+    /// Returns an error without touching any files if `options` are invalid (see
+    /// [`CompressionOptions::validate`]).
     #[tracing::instrument(skip_all)]
-    pub fn recursive_compress<'a, P>(
+    pub fn compress_with<'a, P>(
         &mut self,
         paths: impl IntoIterator<Item = &'a Path>,
-        kind: Kind,
-        minimum_compression_ratio: f64,
-        level: u32,
+        options: &CompressionOptions,
+        progress: &P,
+    ) -> Result<Stats, InvalidOptions>
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        options.validate()?;
+        Ok(self.compress_unvalidated(paths, options, progress))
+    }
+
+    /// Decompress every file under each of `paths`
+    #[tracing::instrument(skip_all)]
+    pub fn decompress_with<'a, P>(
+        &mut self,
+        paths: impl IntoIterator<Item = &'a Path>,
+        options: &DecompressionOptions,
+        progress: &P,
+    ) -> Stats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        let mode = if options.manual {
+            Mode::DecompressManually
+        } else {
+            Mode::DecompressByReading
+        };
+        self.bg_threads
+            .scan(mode, paths, progress, self.scan_options(options.verify))
+    }
+
+    fn compress_unvalidated<'a, P>(
+        &mut self,
+        paths: impl IntoIterator<Item = &'a Path>,
+        options: &CompressionOptions,
         progress: &P,
-        verify: bool,
     ) -> Stats
     where
         P: Progress + Send + Sync,
@@ -209,34 +1202,57 @@ impl FileCompressor {
     {
         self.bg_threads.scan(
             Mode::Compress {
-                kind,
-                level,
-                minimum_compression_ratio,
+                kind: options.kind,
+                level: options.level,
+                minimum_compression_ratio: options.minimum_ratio,
+                sample_blocks: self.sample_blocks,
             },
             paths,
             progress,
-            verify,
+            ScanOptions {
+                output_dir: self.output_dir.clone(),
+                ..self.scan_options(options.verify)
+            },
         )
     }
 
-    #[tracing::instrument(skip_all)]
+    /// Like [`FileCompressor::compress_with`], but the options are not validated
+    #[deprecated(note = "use `compress_with` and `CompressionOptions`")]
+    pub fn recursive_compress<'a, P>(
+        &mut self,
+        paths: impl IntoIterator<Item = &'a Path>,
+        kind: Kind,
+        minimum_compression_ratio: f64,
+        level: u32,
+        progress: &P,
+        verify: Verify,
+    ) -> Stats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        let options = CompressionOptions::new()
+            .kind(kind)
+            .minimum_ratio(minimum_compression_ratio)
+            .level(level)
+            .verify(verify);
+        self.compress_unvalidated(paths, &options, progress)
+    }
+
+    #[deprecated(note = "use `decompress_with` and `DecompressionOptions`")]
     pub fn recursive_decompress<'a, P>(
         &mut self,
         paths: impl IntoIterator<Item = &'a Path>,
         manual: bool,
         progress: &P,
-        verify: bool,
+        verify: Verify,
     ) -> Stats
     where
         P: Progress + Send + Sync,
         P::Task: Send + Sync + 'static,
     {
-        let mode = if manual {
-            Mode::DecompressManually
-        } else {
-            Mode::DecompressByReading
-        };
-        self.bg_threads.scan(mode, paths, progress, verify)
+        let options = DecompressionOptions::new().manual(manual).verify(verify);
+        self.decompress_with(paths, &options, progress)
     }
 }
 
@@ -269,106 +1285,34 @@ fn try_read_all<R: Read>(mut r: R, buf: &mut [u8]) -> io::Result<usize> {
     Ok(read_len)
 }
 
+/// Like [`try_read_all`], but reading from `offset` in `file`, without using its file position
+fn try_read_all_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let full_len = buf.len();
+    let mut remaining = buf;
+    while !remaining.is_empty() {
+        let read_offset = offset + (full_len - remaining.len()) as u64;
+        let n = match file.read_at(remaining, read_offset) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            break;
+        }
+        remaining = &mut remaining[n..];
+    }
+    Ok(full_len - remaining.len())
+}
+
 #[cfg(test)]
+// The deprecated wrappers are still exercised by a few tests, to keep them working
+#[allow(deprecated)]
 mod tests {
     use super::*;
-    use crate::progress::Task;
+    use crate::testing::{assert_entries_equal, populate_dir, recursive_read, Fixture, NoProgress};
+    use sha2::{Digest, Sha256};
     use std::os::unix::fs::symlink;
-    use std::path::PathBuf;
-    use std::time::SystemTime;
     use std::{fs, iter};
-    use tempfile::TempDir;
-    use walkdir::WalkDir;
-
-    struct NoProgress;
-    impl Task for NoProgress {
-        fn increment(&self, _amt: u64) {}
-        fn error(&self, _message: &str) {}
-    }
-    impl Progress for NoProgress {
-        type Task = NoProgress;
-
-        fn error(&self, path: &Path, message: &str) {
-            panic!("Expected no errors, got {message} for {path:?}");
-        }
-
-        fn file_task(&self, _path: &Path, _size: u64) -> Self::Task {
-            NoProgress
-        }
-    }
-
-    #[derive(Debug)]
-    struct EntryInfo {
-        path: PathBuf,
-        modified_time: SystemTime,
-        content: Option<Vec<u8>>,
-    }
-
-    fn assert_entries_equal(old: &[EntryInfo], new: &[EntryInfo]) {
-        assert_eq!(old.len(), new.len());
-        for (old, new) in old.iter().zip(new.iter()) {
-            assert_eq!(old.path, new.path);
-            assert_eq!(
-                old.modified_time,
-                new.modified_time,
-                "modified time mismatch at {}",
-                old.path.display()
-            );
-            assert_eq!(
-                old.content,
-                new.content,
-                "content mismatch at {}",
-                old.path.display()
-            );
-        }
-    }
-
-    fn recursive_read(dir: &Path) -> Vec<EntryInfo> {
-        let mut result = Vec::new();
-        for item in WalkDir::new(dir).sort_by_file_name() {
-            let item = item.unwrap();
-            let metadata = item.metadata().unwrap();
-            let modified_time = metadata.modified().unwrap();
-            let content = if !item.file_type().is_dir() {
-                Some(fs::read(item.path()).unwrap())
-            } else {
-                None
-            };
-
-            result.push(EntryInfo {
-                path: item.into_path(),
-                modified_time,
-                content,
-            });
-        }
-        result
-    }
-
-    fn populate_dir(dir: &Path) {
-        // Empty file
-        fs::write(dir.join("EMPTY"), b"").unwrap();
-
-        // Medium files
-        for i in 0u8..=0xFF {
-            let p = dir.join(format!("{i}"));
-            fs::write(p, vec![i; usize::from(i) * 1024]).unwrap();
-        }
-
-        let subdir = dir.join("subdir");
-        fs::create_dir(&subdir).unwrap();
-        // Tiny Files
-        for i in 0u8..=0xFF {
-            let p = subdir.join(format!("{i}"));
-            fs::write(p, vec![i; usize::from(i)]).unwrap();
-        }
-
-        let big_file = dir.join("BIG");
-        let mut big_content = Vec::new();
-        for i in 0u8..=0xFF {
-            big_content.extend_from_slice(&[i; 1234]);
-        }
-        fs::write(big_file, big_content).unwrap();
-    }
 
     fn compress_folder(compressor_kind: compressor::Kind, dir: &Path) {
         let mut uncompressed_file = tempfile::NamedTempFile::new().unwrap();
@@ -380,8 +1324,14 @@ mod tests {
         let old_contents = recursive_read(dir);
 
         let mut fc = FileCompressor::new();
-        fc.recursive_compress(iter::once(dir), compressor_kind, 1.0, 2, &NoProgress, true);
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        fc.recursive_compress(
+            iter::once(dir),
+            compressor_kind,
+            1.0,
+            2,
+            &NoProgress,
+            Verify::Checksum,
+        );
 
         let new_contents = recursive_read(dir);
         assert_entries_equal(&old_contents, &new_contents);
@@ -403,12 +1353,40 @@ mod tests {
 
         // Now Decompress
         let mut fc = FileCompressor::new();
-        fc.recursive_decompress(iter::once(dir), true, &NoProgress, true);
+        fc.recursive_decompress(iter::once(dir), true, &NoProgress, Verify::Checksum);
 
         let new_contents = recursive_read(dir);
         assert_entries_equal(&old_contents, &new_contents);
     }
 
+    #[test]
+    fn compress_quick_verify() {
+        for kind in compressor::Kind::all()
+            .iter()
+            .copied()
+            .filter(|kind| kind.supported())
+        {
+            let dir = Fixture::new();
+            populate_dir(dir.path());
+            let old_contents = recursive_read(dir.path());
+
+            let mut fc = FileCompressor::new();
+            let stats = dir.compress_with(
+                &mut fc,
+                &Fixture::options().kind(kind).verify(Verify::Quick),
+                &NoProgress,
+            );
+            assert!(stats.compressed_file_count_final.into_inner() > 0, "{kind}");
+            assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+            assert!(
+                info::get_recursive(dir.path())
+                    .unwrap()
+                    .compression_savings_fraction()
+                    > 0.5
+            );
+        }
+    }
+
     #[test]
     fn compress_single_file() {
         let mut compressible_file = tempfile::NamedTempFile::new().unwrap();
@@ -423,7 +1401,7 @@ mod tests {
             1.0,
             2,
             &NoProgress,
-            true,
+            Verify::Full,
         );
 
         let new_contents = recursive_read(compressible_file.path());
@@ -434,9 +1412,118 @@ mod tests {
         assert!(info.compression_savings_fraction() > 0.5);
     }
 
+    /// The stat fields which compression must not change
+    #[derive(Debug, PartialEq, Eq)]
+    struct StableStat {
+        size: u64,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        modified: (i64, i64),
+        birthtime: (i64, i64),
+    }
+
+    impl From<&Metadata> for StableStat {
+        fn from(metadata: &Metadata) -> Self {
+            use std::os::macos::fs::MetadataExt as _;
+
+            Self {
+                size: metadata.st_size(),
+                mode: metadata.st_mode(),
+                uid: metadata.st_uid(),
+                gid: metadata.st_gid(),
+                modified: (metadata.st_mtime(), metadata.st_mtime_nsec()),
+                birthtime: (metadata.st_birthtime(), metadata.st_birthtime_nsec()),
+            }
+        }
+    }
+
+    fn dir_listing(dir: &Path) -> Vec<std::ffi::OsString> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect()
+    }
+
+    /// The guarantees in the crate docs: only the inode, blocks, flags, and change time change
+    #[test]
+    fn compression_preserves_stat() {
+        use std::os::macos::fs::MetadataExt as _;
+        use std::os::unix::fs::PermissionsExt as _;
+
+        const SIZES: &[usize] = &[
+            1,
+            100,
+            4095,
+            applesauce_core::BLOCK_SIZE - 1,
+            applesauce_core::BLOCK_SIZE,
+            applesauce_core::BLOCK_SIZE + 1,
+            3 * applesauce_core::BLOCK_SIZE + 17,
+            1024 * 1024,
+        ];
+
+        for kind in compressor::Kind::all()
+            .iter()
+            .copied()
+            .filter(|kind| kind.supported())
+        {
+            let dir = Fixture::new();
+            for (i, &size) in SIZES.iter().enumerate() {
+                let path = dir.path().join(format!("file{i}"));
+                let contents: Vec<u8> = (0..size).map(|j| (j % 13) as u8).collect();
+                fs::write(&path, contents).unwrap();
+                // Unusual modes must be kept too
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            }
+            let listing = dir_listing(dir.path());
+            let before: Vec<(PathBuf, Metadata)> = listing
+                .iter()
+                .map(|name| {
+                    let path = dir.path().join(name);
+                    let metadata = path.symlink_metadata().unwrap();
+                    (path, metadata)
+                })
+                .collect();
+
+            let mut fc = FileCompressor::new();
+            dir.compress_with(
+                &mut fc,
+                &Fixture::options().kind(kind).verify(Verify::Checksum),
+                &NoProgress,
+            );
+
+            assert_eq!(dir_listing(dir.path()), listing, "{kind}");
+            for (path, old) in &before {
+                let new = path.symlink_metadata().unwrap();
+                let context = format!("{kind}: {}", path.display());
+                assert_eq!(StableStat::from(old), StableStat::from(&new), "{context}");
+                if new.st_flags() & libc::UF_COMPRESSED == 0 {
+                    // Skipped, e.g. not compressible enough: the file is untouched
+                    assert_eq!(old.st_ino(), new.st_ino(), "{context}");
+                    continue;
+                }
+                assert_eq!(
+                    new.st_flags(),
+                    old.st_flags() | libc::UF_COMPRESSED,
+                    "{context}"
+                );
+                assert!(new.st_blocks() < old.st_blocks(), "{context}");
+                // The compressed file is a new file, renamed over the original
+                assert_ne!(old.st_ino(), new.st_ino(), "{context}");
+            }
+            assert!(
+                info::get_recursive(dir.path())
+                    .unwrap()
+                    .num_compressed_files
+                    > 0,
+                "{kind}"
+            );
+        }
+    }
+
     #[test]
     fn compress_dir_and_file() {
-        let outer_dir = TempDir::new().unwrap();
+        let outer_dir = Fixture::new();
         let inner_dir = outer_dir.path().join("inner");
         fs::create_dir(&inner_dir).unwrap();
         populate_dir(&inner_dir);
@@ -445,6 +1532,8 @@ mod tests {
         let mut inner_file = File::create(&inner_file_path).unwrap();
         inner_file.write_all(&[0; 16 * 1024]).unwrap();
         inner_file.flush().unwrap();
+        // Files open for writing are skipped
+        drop(inner_file);
 
         let contents = recursive_read(outer_dir.path());
 
@@ -455,7 +1544,7 @@ mod tests {
             1.0,
             2,
             &NoProgress,
-            false,
+            Verify::Off,
         );
 
         let new_contents = recursive_read(outer_dir.path());
@@ -469,35 +1558,226 @@ mod tests {
     #[cfg(feature = "zlib")]
     #[test]
     fn compress_zlib() {
-        let dir = TempDir::new().unwrap();
+        let dir = Fixture::new();
         compress_folder(compressor::Kind::Zlib, dir.path());
     }
 
     #[cfg(feature = "lzvn")]
     #[test]
     fn compress_lzvn() {
-        let dir = TempDir::new().unwrap();
+        let dir = Fixture::new();
         compress_folder(compressor::Kind::Lzvn, dir.path());
     }
 
     #[cfg(feature = "lzfse")]
     #[test]
     fn compress_lzfse() {
-        let dir = TempDir::new().unwrap();
+        let dir = Fixture::new();
         compress_folder(compressor::Kind::Lzfse, dir.path());
     }
 
+    #[test]
+    fn decompress_odd_sizes() {
+        let dir = Fixture::new();
+        // Sizes around the block size, and the buffer size used when writing decompressed data
+        let sizes = [
+            1,
+            applesauce_core::BLOCK_SIZE - 1,
+            applesauce_core::BLOCK_SIZE + 1,
+            4 * 1024 * 1024 - 1,
+            4 * 1024 * 1024 + 1,
+            3 * 4 * 1024 * 1024 + 12345,
+        ];
+        for size in sizes {
+            let content: Vec<u8> = (0..size).map(|i| (i % 253) as u8).collect();
+            dir.file(format!("{size}"), content);
+        }
+
+        for manual in [false, true] {
+            let orig_contents = recursive_read(dir.path());
+
+            let mut fc = FileCompressor::new();
+            dir.compress(&mut fc, &NoProgress);
+            assert_eq!(
+                info::get_recursive(dir.path())
+                    .unwrap()
+                    .num_compressed_files,
+                sizes.len() as u32
+            );
+
+            dir.decompress_with(
+                &mut fc,
+                &DecompressionOptions::new().manual(manual),
+                &NoProgress,
+            );
+            assert_eq!(
+                info::get_recursive(dir.path())
+                    .unwrap()
+                    .num_compressed_files,
+                0
+            );
+
+            let new_contents = recursive_read(dir.path());
+            assert_entries_equal(&orig_contents, &new_contents);
+        }
+    }
+
+    #[test]
+    fn compress_with_options() {
+        let dir = Fixture::new();
+        let path = dir.file("file", [1; 64 * 1024]);
+        let old_contents = recursive_read(dir.path());
+        let mut fc = FileCompressor::new();
+
+        let invalid = CompressionOptions::new().minimum_ratio(-1.0);
+        assert!(matches!(
+            fc.compress_with([dir.path()], &invalid, &NoProgress),
+            Err(InvalidOptions::MinimumRatio(_))
+        ));
+        assert!(!info::get(&path).unwrap().is_compressed);
+
+        let options = CompressionOptions::new()
+            .minimum_ratio(1.0)
+            .verify(Verify::Checksum);
+        let stats = fc
+            .compress_with([dir.path()], &options, &NoProgress)
+            .unwrap();
+        assert_eq!(stats.compressed_file_count_final.into_inner(), 1);
+        assert!(info::get(&path).unwrap().is_compressed);
+
+        let options = DecompressionOptions::new()
+            .manual(true)
+            .verify(Verify::Full);
+        fc.decompress_with([dir.path()], &options, &NoProgress);
+        assert!(!info::get(&path).unwrap().is_compressed);
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+    }
+
     #[test]
     fn compress_with_hardlinks() {
-        let dir = TempDir::new().unwrap();
-        let orig_file = dir.path().join("test1.txt");
-        fs::write(&orig_file, b"fooooooobaaaaar").unwrap();
+        let dir = Fixture::new();
+        let orig_file = dir.file("test1.txt", b"fooooooobaaaaar");
         fs::hard_link(&orig_file, dir.path().join("test2.txt")).unwrap();
 
         let orig_contents = recursive_read(dir.path());
         let mut fc = FileCompressor::new();
-        fc.recursive_compress([dir.path()], Kind::default(), 2.0, 2, &NoProgress, false);
+        dir.compress_with(&mut fc, &Fixture::options().minimum_ratio(2.0), &NoProgress);
         let next_contents = recursive_read(dir.path());
         assert_entries_equal(&orig_contents, &next_contents);
     }
+
+    #[test]
+    fn error_log_bounded() {
+        let log = ErrorLog::default();
+        for i in 0..ERROR_LOG_LIMIT + 5 {
+            log.record(Path::new(&i.to_string()), "oops");
+        }
+        let entries = log.entries();
+        assert_eq!(entries.len(), ERROR_LOG_LIMIT);
+        assert_eq!(entries[0].path, Path::new("0"));
+        assert_eq!(log.omitted(), 5);
+        assert_eq!(log.total(), ERROR_LOG_LIMIT as u64 + 5);
+    }
+
+    #[test]
+    fn path_with_nul() {
+        use std::ffi::OsStr;
+
+        let path = Path::new(OsStr::from_bytes(b"a\0b"));
+        let err = path_cstring(path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("NUL"), "{err}");
+        assert!(info::get(path).is_err());
+    }
+
+    #[test]
+    fn outcomes_of_mixed_tree() {
+        let dir = Fixture::new();
+        let text: String = (0..).map(|i| format!("line {i}\n")).take(8192).collect();
+        let random: Vec<u8> = (0..512u32)
+            .flat_map(|block| Sha256::digest(block.to_le_bytes()))
+            .collect();
+        let already = dir.file("already", &text);
+        compress_file(&already, Kind::default(), 5, 1.0, Verify::Off).unwrap();
+        dir.file("text", &text);
+        dir.file("random", &random);
+
+        fn assert_outcomes(stats: &Stats, compressed: u64, decompressed: u64, skipped: u64) {
+            assert_eq!(stats.outcomes.get(FileOutcome::Compressed), compressed);
+            assert_eq!(stats.outcomes.get(FileOutcome::Decompressed), decompressed);
+            assert_eq!(stats.outcomes.get(FileOutcome::Skipped), skipped);
+            assert_eq!(stats.outcomes.get(FileOutcome::Error), 0);
+            assert_eq!(
+                stats.outcomes.total(),
+                stats.files.load(std::sync::atomic::Ordering::Relaxed)
+            );
+        }
+        let mut fc = FileCompressor::new();
+        let compress = |fc: &mut FileCompressor| {
+            let options = CompressionOptions::new().minimum_ratio(0.95);
+            fc.compress_with([dir.path()], &options, &NoProgress)
+                .unwrap()
+        };
+        let decompress = |fc: &mut FileCompressor| {
+            fc.decompress_with([dir.path()], &DecompressionOptions::new(), &NoProgress)
+        };
+
+        // Only the text is compressed: the other file was already compressed, and the random file
+        // doesn't compress enough
+        let stats = compress(&mut fc);
+        assert_outcomes(&stats, 1, 0, 2);
+        assert_eq!(stats.compressed_file_count_start.into_inner(), 1);
+        assert_eq!(stats.compressed_file_count_final.into_inner(), 2);
+
+        // Compressing again changes nothing
+        let stats = compress(&mut fc);
+        assert_outcomes(&stats, 0, 0, 3);
+        assert_eq!(stats.compressed_file_count_final.into_inner(), 2);
+
+        let stats = decompress(&mut fc);
+        assert_outcomes(&stats, 0, 2, 1);
+        assert_eq!(stats.skipped.not_compressed.into_inner(), 1);
+        assert_eq!(stats.compressed_file_count_start.into_inner(), 2);
+        assert_eq!(stats.compressed_file_count_final.into_inner(), 0);
+
+        // Both compressible files are new again
+        let stats = compress(&mut fc);
+        assert_outcomes(&stats, 2, 0, 1);
+        assert_eq!(stats.compressed_file_count_start.into_inner(), 0);
+    }
+
+    #[test]
+    fn stats_count_bytes() {
+        let dir = Fixture::new();
+        let file = dir.path().join("file");
+        // Compressible, but too large to store in the xattr
+        let contents: String = (0..)
+            .map(|i| format!("line {i}\n"))
+            .take(24 * 1024)
+            .collect();
+        fs::write(&file, &contents).unwrap();
+        let len = contents.len() as u64;
+
+        let mut fc = FileCompressor::new();
+        let stats = dir.compress(&mut fc, &NoProgress);
+        assert!(stats.elapsed() > Duration::ZERO);
+        assert!(stats.read_throughput() > 0.0);
+        assert_eq!(stats.bytes_read.into_inner(), len);
+        // Every byte of the resource fork and xattr is counted as written
+        let rfork_len = info::get(&file).unwrap().resource_fork_size.unwrap();
+        let xattr_len = xattr::read(
+            &File::open(&file).unwrap(),
+            applesauce_core::decmpfs::XATTR_NAME,
+        )
+        .unwrap()
+        .unwrap()
+        .len() as u64;
+        assert_eq!(stats.bytes_written.into_inner(), rfork_len + xattr_len);
+
+        // Only the compressed blocks are read when decompressing manually
+        let stats = dir.decompress(&mut fc, &NoProgress);
+        assert_eq!(stats.bytes_written.into_inner(), len);
+        let bytes_read = stats.bytes_read.into_inner();
+        assert!(bytes_read > 0 && bytes_read < rfork_len);
+    }
 }
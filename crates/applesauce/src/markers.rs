@@ -0,0 +1,312 @@
+//! Remembering files which didn't compress enough, so later runs can skip them
+//!
+//! When a file fails the minimum compression ratio, a small xattr ([`XATTR_NAME`]) is written on
+//! the original, recording the file's modification time and size, and the kind and minimum ratio
+//! it failed with. A later compression with the same kind, and a minimum ratio at least as
+//! strict, skips the file without reading it, as long as its modification time and size still
+//! match. A marker which no longer matches the file is removed when the file is next scanned.
+
+use crate::compressor::Kind;
+use crate::{os_error, xattr};
+use std::ffi::{CStr, CString};
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The name of the xattr marking a file which didn't compress enough
+pub const XATTR_NAME: &CStr = c"dev.applesauce.incompressible";
+
+/// The version of the marker format, the first field of the value
+const VERSION: &str = "1";
+
+/// The contents of a marker
+#[derive(Debug, Clone, PartialEq)]
+struct Marker {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    kind: Kind,
+    minimum_ratio: f64,
+}
+
+impl Marker {
+    fn new(metadata: &Metadata, kind: Kind, minimum_ratio: f64) -> Self {
+        Self {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.len(),
+            kind,
+            minimum_ratio,
+        }
+    }
+
+    /// Returns true if the file hasn't changed since the marker was written
+    fn matches_file(&self, metadata: &Metadata) -> bool {
+        self.mtime == metadata.mtime()
+            && self.mtime_nsec == metadata.mtime_nsec()
+            && self.size == metadata.len()
+    }
+
+    /// Returns true if compressing with `kind` and `minimum_ratio` would fail again
+    fn covers(&self, kind: Kind, minimum_ratio: f64) -> bool {
+        self.kind == kind && minimum_ratio <= self.minimum_ratio
+    }
+
+    fn to_value(&self) -> String {
+        let Self {
+            mtime,
+            mtime_nsec,
+            size,
+            kind,
+            minimum_ratio,
+        } = self;
+        format!(
+            "{VERSION} {mtime} {mtime_nsec} {size} {} {minimum_ratio}",
+            kind.name()
+        )
+    }
+
+    /// Parse a marker, returning `None` if it's invalid, or from an unknown version
+    fn parse(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?;
+        let mut fields = value.split(' ');
+        if fields.next()? != VERSION {
+            return None;
+        }
+        let mtime = fields.next()?.parse().ok()?;
+        let mtime_nsec = fields.next()?.parse().ok()?;
+        let size = fields.next()?.parse().ok()?;
+        let kind_name = fields.next()?;
+        // Not `Kind::from_str`, which rejects kinds which weren't compiled in
+        let kind = *Kind::all().iter().find(|kind| kind.name() == kind_name)?;
+        let minimum_ratio = fields.next()?.parse().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            mtime,
+            mtime_nsec,
+            size,
+            kind,
+            minimum_ratio,
+        })
+    }
+}
+
+/// Returns true if `file` has a marker showing it won't compress with `kind` to `minimum_ratio`
+///
+/// A marker which no longer matches the file (or can't be parsed) is removed. Errors reading the
+/// marker are logged, and the file is treated as unmarked.
+pub(crate) fn is_marked(file: &File, metadata: &Metadata, kind: Kind, minimum_ratio: f64) -> bool {
+    let value = match xattr::read(file, XATTR_NAME) {
+        Ok(Some(value)) => value,
+        Ok(None) => return false,
+        Err(e) => {
+            tracing::debug!("unable to read incompressible marker: {e}");
+            return false;
+        }
+    };
+    match Marker::parse(&value) {
+        Some(marker) if marker.matches_file(metadata) => marker.covers(kind, minimum_ratio),
+        _ => {
+            tracing::debug!("removing stale incompressible marker");
+            if let Err(e) = remove_from(file) {
+                tracing::debug!("unable to remove stale incompressible marker: {e}");
+            }
+            false
+        }
+    }
+}
+
+/// Mark `file` as not compressing to `minimum_ratio` with `kind`
+///
+/// `metadata` is the metadata of the file when it was read. Marking is best effort: errors (e.g.
+/// no permission to write xattrs) are logged and ignored.
+pub(crate) fn mark(file: &File, metadata: &Metadata, kind: Kind, minimum_ratio: f64) {
+    let marker = Marker::new(metadata, kind, minimum_ratio);
+    if let Err(e) = xattr::set(file, XATTR_NAME, marker.to_value().as_bytes(), 0) {
+        tracing::debug!("unable to write incompressible marker: {e}");
+    }
+}
+
+/// Remove the marker from `file`, returning true if it had one
+pub(crate) fn remove_from(file: &File) -> io::Result<bool> {
+    match xattr::remove(file, XATTR_NAME) {
+        Ok(()) => Ok(true),
+        Err(e) if os_error::errno(&e) == Some(libc::ENOATTR) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove the marker from the file at `path`, returning true if it had one
+pub fn remove(path: &Path) -> io::Result<bool> {
+    let path = CString::new(path.as_os_str().as_encoded_bytes())?;
+    match xattr::remove(path.as_c_str(), XATTR_NAME) {
+        Ok(()) => Ok(true),
+        Err(e) if os_error::errno(&e) == Some(libc::ENOATTR) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove the marker from every file under `root` (or `root` itself, if it's a file)
+///
+/// Each file is yielded with whether it had a marker. Like [`info::walk`](crate::info::walk),
+/// errors don't stop the walk, and errors with no associated path are reported against `root`.
+pub fn clean(root: &Path) -> impl Iterator<Item = (PathBuf, io::Result<bool>)> {
+    let root = root.to_path_buf();
+    jwalk::WalkDir::new(&root)
+        .sort(true)
+        .into_iter()
+        .filter_map(move |entry| match entry {
+            #[allow(clippy::filetype_is_file)]
+            Ok(entry) if entry.file_type().is_file() => {
+                let path = entry.path();
+                let removed = remove(&path);
+                Some((path, removed))
+            }
+            Ok(_) => None,
+            Err(e) => {
+                let path = e.path().map_or_else(|| root.clone(), Path::to_path_buf);
+                Some((path, Err(e.into())))
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{incompressible_data, Fixture, NoProgress};
+    use crate::{info, FileCompressor};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn marker_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"contents").unwrap();
+        let metadata = path.metadata().unwrap();
+
+        let marker = Marker::new(&metadata, Kind::Lzvn, 0.95);
+        assert_eq!(
+            Marker::parse(marker.to_value().as_bytes()),
+            Some(marker.clone())
+        );
+        assert!(marker.matches_file(&metadata));
+        assert!(marker.covers(Kind::Lzvn, 0.95));
+        assert!(marker.covers(Kind::Lzvn, 0.5));
+        // A looser ratio, or another kind, might succeed
+        assert!(!marker.covers(Kind::Lzvn, 1.0));
+        assert!(!marker.covers(Kind::Zlib, 0.95));
+    }
+
+    #[test]
+    fn invalid_markers() {
+        for value in [
+            &b""[..],
+            b"1 2 3",
+            b"2 1 2 3 LZVN 0.95",
+            b"1 1 2 3 LZMA 0.95",
+            b"1 1 2 3 LZVN 0.95 extra",
+            b"\xff",
+        ] {
+            assert_eq!(Marker::parse(value), None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn stale_marker_removed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"contents").unwrap();
+        let file = File::open(&path).unwrap();
+        let metadata = file.metadata().unwrap();
+        let kind = Kind::default();
+
+        mark(&file, &metadata, kind, 0.95);
+        assert!(is_marked(&file, &metadata, kind, 0.95));
+
+        fs::write(&path, b"new contents").unwrap();
+        let metadata = file.metadata().unwrap();
+        assert!(!is_marked(&file, &metadata, kind, 0.95));
+        assert!(!xattr::is_present(&file, XATTR_NAME).unwrap());
+    }
+
+    #[test]
+    fn clean_removes_markers() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        for name in ["marked", "unmarked", "nested/marked"] {
+            fs::write(dir.path().join(name), b"contents").unwrap();
+        }
+        for name in ["marked", "nested/marked"] {
+            let file = File::open(dir.path().join(name)).unwrap();
+            mark(&file, &file.metadata().unwrap(), Kind::default(), 0.95);
+        }
+
+        let cleaned: Vec<(PathBuf, bool)> = clean(dir.path())
+            .map(|(path, removed)| {
+                (
+                    path.strip_prefix(dir.path()).unwrap().to_owned(),
+                    removed.unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            cleaned,
+            [
+                (PathBuf::from("marked"), true),
+                (PathBuf::from("nested/marked"), true),
+                (PathBuf::from("unmarked"), false),
+            ]
+        );
+        // Cleaning again finds nothing
+        assert!(clean(dir.path()).all(|(_, removed)| !removed.unwrap()));
+    }
+
+    #[test]
+    fn remember_incompressible() {
+        let len = 4 * applesauce_core::BLOCK_SIZE;
+        let dir = Fixture::new();
+        let path = dir.file("incompressible", incompressible_data(len));
+        let marked = || {
+            let path = CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+            xattr::is_present(path.as_c_str(), XATTR_NAME).unwrap()
+        };
+        let compress = |remember_incompressible, minimum_ratio| {
+            let mut fc = FileCompressor::new();
+            fc.set_remember_incompressible(remember_incompressible);
+            let stats = dir.compress_with(
+                &mut fc,
+                &Fixture::options().minimum_ratio(minimum_ratio),
+                &NoProgress,
+            );
+            (
+                stats.skipped.not_compressible_enough.into_inner(),
+                stats.skipped.previously_incompressible.into_inner(),
+            )
+        };
+
+        // Nothing is marked unless enabled
+        assert_eq!(compress(false, 0.95), (1, 0));
+        assert!(!marked());
+
+        assert_eq!(compress(true, 0.95), (1, 0));
+        assert!(marked());
+        // Skipped without reading it again, including with a stricter ratio
+        assert_eq!(compress(true, 0.95), (0, 1));
+        assert_eq!(compress(true, 0.5), (0, 1));
+        // Markers are ignored unless enabled
+        assert_eq!(compress(false, 0.95), (1, 0));
+        // A looser ratio might succeed, so the file is read again
+        assert_eq!(compress(true, 0.99), (1, 0));
+        assert_eq!(compress(true, 0.99), (0, 1));
+
+        // Once the file changes, its marker no longer applies, and isn't kept once it compresses
+        fs::write(&path, vec![0; len]).unwrap();
+        assert_eq!(compress(true, 0.95), (0, 0));
+        assert!(info::get(&path).unwrap().is_compressed);
+        assert!(!marked());
+    }
+}
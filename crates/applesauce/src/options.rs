@@ -0,0 +1,236 @@
+//! Options for compressing and decompressing files, shared by every entry point
+
+use crate::Verify;
+use applesauce_core::compressor::Kind;
+use std::ops::RangeInclusive;
+use std::{error, fmt};
+
+/// The compression level used if none is set
+pub const DEFAULT_LEVEL: u32 = 5;
+
+/// The minimum compression ratio used if none is set
+pub const DEFAULT_MINIMUM_RATIO: f64 = 0.95;
+
+/// How to compress files
+///
+/// Created with [`CompressionOptions::new`] (or [`Default`]), and built up by chaining setters:
+///
+/// ```
+/// use applesauce::compressor::Kind;
+/// use applesauce::{CompressionOptions, Verify};
+///
+/// let options = CompressionOptions::new()
+///     .kind(Kind::Zlib)
+///     .level(9)
+///     .minimum_ratio(1.0)
+///     .verify(Verify::Checksum);
+/// assert!(options.validate().is_ok());
+/// assert!(options.level(0).validate().is_err());
+/// ```
+///
+/// Setters never fail, the options are checked as a whole by [`CompressionOptions::validate`],
+/// which every function taking them calls before doing any work.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionOptions {
+    pub(crate) kind: Kind,
+    pub(crate) level: u32,
+    pub(crate) minimum_ratio: f64,
+    pub(crate) verify: Verify,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            kind: Kind::default(),
+            level: DEFAULT_LEVEL,
+            minimum_ratio: DEFAULT_MINIMUM_RATIO,
+            verify: Verify::default(),
+        }
+    }
+}
+
+impl CompressionOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The type of compression to use
+    #[must_use]
+    pub fn kind(mut self, kind: Kind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// The compression level to use
+    ///
+    /// Only some kinds have levels, see [`CompressionOptions::level_range`].
+    #[must_use]
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Skip files which compress to more than this fraction of their original size
+    ///
+    /// A ratio of 0 skips every file, 1.0 only skips files which can't be compressed at all.
+    /// Ratios over 1.0 compress files even if they get larger.
+    #[must_use]
+    pub fn minimum_ratio(mut self, minimum_ratio: f64) -> Self {
+        self.minimum_ratio = minimum_ratio;
+        self
+    }
+
+    /// How to check each newly compressed file
+    #[must_use]
+    pub fn verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// The levels accepted for `kind`, or `None` if it has no levels, and the level is ignored
+    #[must_use]
+    pub fn level_range(kind: Kind) -> Option<RangeInclusive<u32>> {
+        match kind {
+            Kind::Zlib => Some(1..=9),
+            Kind::Lzvn | Kind::Lzfse => None,
+        }
+    }
+
+    /// Check that the options can be used
+    pub fn validate(&self) -> Result<(), InvalidOptions> {
+        if !self.kind.supported() {
+            return Err(InvalidOptions::UnsupportedKind(self.kind));
+        }
+        if let Some(range) = Self::level_range(self.kind) {
+            if !range.contains(&self.level) {
+                return Err(InvalidOptions::Level {
+                    kind: self.kind,
+                    level: self.level,
+                    range,
+                });
+            }
+        }
+        if self.minimum_ratio.is_nan() || self.minimum_ratio < 0.0 {
+            return Err(InvalidOptions::MinimumRatio(self.minimum_ratio));
+        }
+        Ok(())
+    }
+}
+
+/// How to decompress files
+///
+/// Like [`CompressionOptions`], created with [`DecompressionOptions::new`] and built up by
+/// chaining setters.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DecompressionOptions {
+    pub(crate) manual: bool,
+    pub(crate) verify: Verify,
+}
+
+impl DecompressionOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decompress blocks with applesauce's own decompressors, instead of reading the file
+    /// through the OS
+    #[must_use]
+    pub fn manual(mut self, manual: bool) -> Self {
+        self.manual = manual;
+        self
+    }
+
+    /// How to check each newly decompressed file
+    #[must_use]
+    pub fn verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+}
+
+/// Why [`CompressionOptions`] can't be used
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum InvalidOptions {
+    /// The compression kind was not compiled in
+    UnsupportedKind(Kind),
+    /// The level is outside the levels of the compression kind
+    Level {
+        kind: Kind,
+        level: u32,
+        range: RangeInclusive<u32>,
+    },
+    /// The minimum compression ratio is negative (or NaN)
+    MinimumRatio(f64),
+}
+
+impl fmt::Display for InvalidOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidOptions::UnsupportedKind(kind) => {
+                write!(f, "{kind} compression is not supported")
+            }
+            InvalidOptions::Level { kind, level, range } => write!(
+                f,
+                "invalid level {level} for {kind}, expected {} to {}",
+                range.start(),
+                range.end()
+            ),
+            InvalidOptions::MinimumRatio(ratio) => {
+                write!(
+                    f,
+                    "invalid minimum compression ratio {ratio}, must be at least 0"
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for InvalidOptions {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let supported = Kind::all().iter().copied().filter(|kind| kind.supported());
+        for kind in supported {
+            let options = CompressionOptions::new().kind(kind);
+            assert_eq!(options.validate(), Ok(()), "{kind}");
+            for ratio in [0.0, 1.0, 2.5] {
+                assert_eq!(options.clone().minimum_ratio(ratio).validate(), Ok(()));
+            }
+            for ratio in [-0.5, f64::NAN] {
+                assert!(matches!(
+                    options.clone().minimum_ratio(ratio).validate(),
+                    Err(InvalidOptions::MinimumRatio(_))
+                ));
+            }
+            match CompressionOptions::level_range(kind) {
+                Some(range) => {
+                    for level in range.clone() {
+                        assert_eq!(options.clone().level(level).validate(), Ok(()));
+                    }
+                    for level in [range.start() - 1, range.end() + 1] {
+                        assert!(matches!(
+                            options.clone().level(level).validate(),
+                            Err(InvalidOptions::Level { .. })
+                        ));
+                    }
+                }
+                None => assert_eq!(options.level(0).validate(), Ok(())),
+            }
+        }
+
+        let unsupported = Kind::all().iter().copied().find(|kind| !kind.supported());
+        if let Some(kind) = unsupported {
+            assert_eq!(
+                CompressionOptions::new().kind(kind).validate(),
+                Err(InvalidOptions::UnsupportedKind(kind))
+            );
+        }
+    }
+}
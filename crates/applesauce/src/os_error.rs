@@ -0,0 +1,88 @@
+//! Naming the system call (and its important arguments) in errors from the OS
+//!
+//! An errno alone (e.g. "File too large (os error 27)") rarely says which of the many calls made
+//! for a file failed. Errors are wrapped with a description of the call, keeping the error's
+//! kind, so they display as e.g. `fsetxattr(com.apple.ResourceFork, offset=655360) failed: File
+//! too large (os error 27)`. Wrapping hides the errno from [`io::Error::raw_os_error`], use
+//! [`errno`] instead.
+
+use std::{error, fmt, io};
+
+/// An OS error, and the call which returned it
+#[derive(Debug)]
+struct CallError {
+    call: String,
+    source: io::Error,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.call, self.source)
+    }
+}
+
+impl error::Error for CallError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Wrap `source` with a description of the call which returned it, e.g. `fgetxattr(name)`
+pub(crate) fn with_call(source: io::Error, call: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        source.kind(),
+        CallError {
+            call: call.to_string(),
+            source,
+        },
+    )
+}
+
+/// The last OS error, returned by `call`
+pub(crate) fn last(call: impl fmt::Display) -> io::Error {
+    with_call(io::Error::last_os_error(), call)
+}
+
+/// The errno of `e`, whether or not it was wrapped by [`with_call`]
+pub(crate) fn errno(e: &io::Error) -> Option<i32> {
+    e.raw_os_error().or_else(|| {
+        e.get_ref()?
+            .downcast_ref::<CallError>()?
+            .source
+            .raw_os_error()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_named() {
+        let source = io::Error::from_raw_os_error(libc::EFBIG);
+        let kind = source.kind();
+        let e = with_call(
+            source,
+            format_args!("fsetxattr({}, offset={})", "com.apple.ResourceFork", 655360),
+        );
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "fsetxattr(com.apple.ResourceFork, offset=655360) failed: {}",
+                io::Error::from_raw_os_error(libc::EFBIG)
+            )
+        );
+        assert_eq!(e.kind(), kind);
+        assert_eq!(e.raw_os_error(), None);
+        assert_eq!(errno(&e), Some(libc::EFBIG));
+    }
+
+    #[test]
+    fn errno_of_unwrapped() {
+        assert_eq!(
+            errno(&io::Error::from_raw_os_error(libc::ENOENT)),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(errno(&io::Error::other("not from the OS")), None);
+    }
+}
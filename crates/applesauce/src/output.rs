@@ -0,0 +1,263 @@
+//! Writing processed copies of files into a separate directory
+//!
+//! Each root path is copied into the output directory under its own name: compressing `a/b` and
+//! `c` into `out` writes `out/b` and `out/c`. The originals are only ever read: temp files are
+//! created in the output directory, and no times or flags of the originals are changed.
+
+use crate::{path_cstring, times, TimePreservation};
+use std::fs::{self, Metadata, Permissions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+#[derive(Debug)]
+pub(crate) struct Output {
+    dir: PathBuf,
+    /// The path of each root as it was given, and the path it is copied to
+    roots: Vec<(PathBuf, PathBuf)>,
+    /// Replace existing files in the output directory, rather than failing them
+    overwrite: bool,
+}
+
+/// A directory created in the output directory, whose attributes are copied from the original
+/// once every file in it has been written
+#[derive(Debug)]
+pub(crate) struct OutputDir {
+    path: PathBuf,
+    permissions: Permissions,
+    times: Option<times::Saved>,
+}
+
+impl Output {
+    /// Create the output directory, and map each of `roots` to a path inside it
+    ///
+    /// Fails if two roots would be copied to the same path, or if the output directory is inside
+    /// one of the roots (or the other way around).
+    pub fn new(dir: &Path, roots: &[&Path], overwrite: bool) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let dir = dir.canonicalize()?;
+        let mut mapped_roots: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(roots.len());
+        for &root in roots {
+            let canonical_root = root.canonicalize()?;
+            let name = canonical_root.file_name().ok_or_else(|| {
+                io::Error::other(format!(
+                    "{} has no name to use in the output directory",
+                    root.display()
+                ))
+            })?;
+            let dst = dir.join(name);
+            if dir.starts_with(&canonical_root) || canonical_root.starts_with(&dst) {
+                return Err(io::Error::other(format!(
+                    "output directory {} overlaps {}",
+                    dir.display(),
+                    root.display()
+                )));
+            }
+            if let Some((other, _)) = mapped_roots.iter().find(|(_, other_dst)| *other_dst == dst) {
+                return Err(io::Error::other(format!(
+                    "{} and {} would both be written to {}",
+                    other.display(),
+                    root.display(),
+                    dst.display()
+                )));
+            }
+            mapped_roots.push((root.to_owned(), dst));
+        }
+        Ok(Self {
+            dir,
+            roots: mapped_roots,
+            overwrite,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn overwrite(&self) -> bool {
+        self.overwrite
+    }
+
+    /// The path in the output directory of `path`, found under the root at index `root`
+    pub fn path_for(&self, root: usize, path: &Path) -> PathBuf {
+        let (src_root, dst_root) = &self.roots[root];
+        match path.strip_prefix(src_root) {
+            Ok(relative) if relative.as_os_str().is_empty() => dst_root.clone(),
+            Ok(relative) => dst_root.join(relative),
+            Err(_) => unreachable!("{} is not inside its root", path.display()),
+        }
+    }
+
+    /// Fail if `dst` exists, and existing files are not being replaced
+    pub fn ensure_free(&self, dst: &Path) -> io::Result<()> {
+        if !self.overwrite && dst.symlink_metadata().is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dst.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Create the copy of the directory `dir`, found under the root at index `root`
+    ///
+    /// The directory is created writable, the returned [`OutputDir`] copies the permissions and
+    /// times of the original once everything inside it has been written.
+    pub fn create_dir(
+        &self,
+        root: usize,
+        dir: &Path,
+        metadata: &Metadata,
+        time_preservation: TimePreservation,
+    ) -> io::Result<OutputDir> {
+        let path = self.path_for(root, dir);
+        match fs::create_dir(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => {}
+            res => res?,
+        }
+        Ok(OutputDir {
+            path,
+            permissions: metadata.permissions(),
+            times: times::save_times_for(dir, time_preservation)?,
+        })
+    }
+
+    /// Copy `src` unchanged to `dst`, with its xattrs, flags, and times
+    ///
+    /// Symlinks are copied as symlinks.
+    pub fn copy_original(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let src = path_cstring(src)?;
+        let dst = path_cstring(dst)?;
+        let mut flags = libc::COPYFILE_METADATA | libc::COPYFILE_DATA | libc::COPYFILE_NOFOLLOW;
+        if !self.overwrite {
+            flags |= libc::COPYFILE_EXCL;
+        }
+        // SAFETY: both paths are valid, null terminated strings, passing null state is allowed
+        let rc = unsafe { libc::copyfile(src.as_ptr(), dst.as_ptr(), ptr::null_mut(), flags) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl OutputDir {
+    /// Copy the permissions and times of the original directory
+    ///
+    /// Directories should be finished after everything inside them, in reverse of the order they
+    /// were created.
+    pub fn finish(&self) -> io::Result<()> {
+        fs::set_permissions(&self.path, self.permissions.clone())?;
+        if let Some(times) = &self.times {
+            times::reset_times(self.path.as_path(), times)?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        assert_entries_equal, populate_dir, recursive_read, ErrorProgress, Fixture,
+    };
+    use crate::{info, FileCompressor, Verify};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn compress_to_output_dir() {
+        let dir = Fixture::new();
+        let src = dir.path().join("tree");
+        let out = dir.path().join("out");
+        fs::create_dir(&src).unwrap();
+        populate_dir(&src);
+        fs::create_dir(src.join("empty")).unwrap();
+        symlink("BIG", src.join("symlink")).unwrap();
+        // Not compressible at all, so copied unchanged
+        let incompressible: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        fs::write(src.join("incompressible"), incompressible).unwrap();
+        let old_contents = recursive_read(&src);
+
+        let compress = |overwrite: bool| {
+            let progress = ErrorProgress::default();
+            let mut fc = FileCompressor::new();
+            fc.set_output_dir(Some(out.clone()));
+            fc.set_overwrite_output(overwrite);
+            let stats = fc
+                .compress_with(
+                    [src.as_path()],
+                    &Fixture::options()
+                        .minimum_ratio(0.95)
+                        .verify(Verify::Checksum),
+                    &progress,
+                )
+                .unwrap();
+            let errors = progress.errors.lock().unwrap().clone();
+            (stats, errors)
+        };
+
+        let (stats, errors) = compress(false);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(stats.compressed_file_count_final.into_inner() > 0);
+        // The originals are untouched
+        assert_entries_equal(&old_contents, &recursive_read(&src));
+        assert_eq!(info::get_recursive(&src).unwrap().num_compressed_files, 0);
+
+        let copy_contents = recursive_read(&out.join("tree"));
+        assert_eq!(old_contents.len(), copy_contents.len());
+        for (old, new) in old_contents.iter().zip(&copy_contents) {
+            let path = old.path.strip_prefix(&src).unwrap();
+            assert_eq!(path, new.path.strip_prefix(out.join("tree")).unwrap());
+            assert_eq!(
+                old.modified_time,
+                new.modified_time,
+                "modified time mismatch at {}",
+                path.display()
+            );
+            assert_eq!(
+                old.content,
+                new.content,
+                "content mismatch at {}",
+                path.display()
+            );
+        }
+        assert!(info::get(&out.join("tree/BIG")).unwrap().is_compressed);
+        assert!(
+            !info::get(&out.join("tree/incompressible"))
+                .unwrap()
+                .is_compressed
+        );
+        assert!(out.join("tree/symlink").is_symlink());
+        assert!(out.join("tree/empty").is_dir());
+        // Only the copy of the tree is left in the output directory
+        assert_eq!(fs::read_dir(&out).unwrap().count(), 1);
+
+        let (_, errors) = compress(false);
+        assert!(
+            errors.iter().any(|e| e.contains("already exists")),
+            "{errors:?}"
+        );
+        let (_, errors) = compress(true);
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(info::get(&out.join("tree/BIG")).unwrap().is_compressed);
+    }
+
+    #[test]
+    fn output_dir_inside_input() {
+        let dir = Fixture::new();
+        dir.file("file", [0; 16 * 1024]);
+
+        let progress = ErrorProgress::default();
+        let mut fc = FileCompressor::new();
+        fc.set_output_dir(Some(dir.path().join("out")));
+        let stats = dir.compress(&mut fc, &progress);
+        assert!(stats.fatal_error.is_some());
+        assert!(!info::get(&dir.path().join("file")).unwrap().is_compressed);
+    }
+}
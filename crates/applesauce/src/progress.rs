@@ -1,29 +1,405 @@
-use crate::info::IncompressibleReason;
-use std::path::Path;
+use crate::info::{CompressionType, IncompressibleReason};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use std::{fmt, io};
 
+/// The target of the events logged for each file
+///
+/// When a file is compressed or decompressed, an info event is logged with this target, with
+/// `path`, `action`, `before_size` and `after_size` (the space used on disk) fields.
+pub const FILE_EVENT_TARGET: &str = "applesauce::file";
+
+/// Why a file was not compressed or decompressed
+///
+/// Every variant has a stable [`code`](SkipReason::code), which won't change between versions,
+/// and a [`severity`](SkipReason::severity). New variants may be added, so matches should fall
+/// back to the severity, rather than listing every variant. The documentation of each variant
+/// says whether it is reported when compressing, decompressing, or both.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SkipReason {
+    /// The path is not a regular file (both)
     NotFile,
+    /// The file is already compressed (compress)
     AlreadyCompressed,
+    /// The file is not compressed (decompress)
     NotCompressed,
+    /// The file is empty (compress)
     EmptyFile,
-    TooLarge(u64),
+    /// The file is at least [`MAX_COMPRESSIBLE_SIZE`](applesauce_core::MAX_COMPRESSIBLE_SIZE)
+    /// bytes, or so close to it that it compressed too poorly to fit in a resource fork
+    /// (compress)
+    TooLarge { size: u64, limit: u64 },
+    /// The file, or information about it, couldn't be read (both)
     ReadError(io::Error),
+    /// The file is on a ZFS filesystem
+    ///
+    /// Not currently reported: files on ZFS are skipped with [`SkipReason::FsNotSupported`].
     ZfsFilesystem,
+    /// The file already has a resource fork or decmpfs xattr (compress)
     HasRequiredXattr,
+    /// The file's volume doesn't support compression (compress)
     FsNotSupported,
+    /// The file is protected by System Integrity Protection, and cannot be replaced (both)
+    SipProtected,
+    /// The file is in a location protected by TCC, and the process lacks Full Disk Access (both)
+    RequiresFullDiskAccess,
+    /// The file kept changing while it was being read (both)
+    FileModified,
+    /// The file's contents are not stored locally (e.g. evicted by iCloud Drive) (both)
+    Dataless,
+    /// The file may share its blocks with another file (e.g. an APFS clone), compressing it would
+    /// stop sharing them (compress)
+    ProbablyClone,
+    /// The file is in a log location, and was modified recently, so it is probably still being
+    /// written (compress)
+    RecentlyActiveLog,
+    /// The file was modified more recently than allowed (compress)
+    RecentlyModified,
+    /// Nearly every file attempted in the same directory failed to compress enough, so the rest
+    /// are assumed not to compress either (compress)
+    DirectoryDeemedIncompressible,
+    /// The file is marked compressed, and its decmpfs xattr says the compressed data is in the
+    /// resource fork, but it has no resource fork (decompress)
+    MissingResourceFork,
+    /// The item is excluded from Time Machine backups, and excluded items are skipped (both)
+    ///
+    /// An excluded directory is reported once, rather than for each file inside it.
+    Excluded,
+    /// The item is an app, framework, or kernel extension bundle (or is inside one), and bundles
+    /// are skipped (both)
+    ///
+    /// A bundle is reported once, rather than for each file inside it.
+    InBundle,
+    /// Another process has the file open for writing, so it is probably still being written
+    /// (compress)
+    InUse,
+    /// The file has holes, and most of its length is not allocated, compressing it would allocate
+    /// space for the holes (compress)
+    Sparse,
+    /// The file is marked as not compressing enough on a previous run, and hasn't changed since
+    /// (compress)
+    PreviouslyIncompressible,
+    /// The compression xattr couldn't be added, because of the size or number of the file's
+    /// existing xattrs, which the error describes (compress)
+    XattrLimitExceeded(io::Error),
+}
+
+/// How much a skipped file may need the user's attention
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipSeverity {
+    /// Skipped because there's nothing to do, or as asked for by an option, e.g. an already
+    /// compressed file when compressing
+    Routine,
+    /// Skipped because the file couldn't be processed, e.g. it couldn't be read, or its
+    /// filesystem doesn't support compression
+    Warning,
+}
+
+/// A description of one kind of [`SkipReason`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SkipReasonInfo {
+    /// The name of the [`SkipReason`] variant
+    pub name: &'static str,
+    /// A short identifier for the reason, which will not change between versions
+    pub code: &'static str,
+    /// How much a file skipped for this reason may need attention
+    pub severity: SkipSeverity,
+    /// A one line explanation of why files are skipped for this reason
+    pub explanation: &'static str,
+}
+
+impl SkipReason {
+    /// Every kind of skip reason, in the same order as the variants of [`SkipReason`]
+    pub const ALL: &'static [SkipReasonInfo] = &[
+        SkipReasonInfo {
+            name: "NotFile",
+            code: "not-file",
+            severity: SkipSeverity::Routine,
+            explanation: "The path is not a regular file (e.g. a symlink or a device)",
+        },
+        SkipReasonInfo {
+            name: "AlreadyCompressed",
+            code: "already-compressed",
+            severity: SkipSeverity::Routine,
+            explanation: "The file is already compressed, so there is nothing to compress",
+        },
+        SkipReasonInfo {
+            name: "NotCompressed",
+            code: "not-compressed",
+            severity: SkipSeverity::Routine,
+            explanation: "The file is not compressed, so there is nothing to decompress",
+        },
+        SkipReasonInfo {
+            name: "EmptyFile",
+            code: "empty",
+            severity: SkipSeverity::Routine,
+            explanation: "The file is empty, so compressing it cannot save any space",
+        },
+        SkipReasonInfo {
+            name: "TooLarge",
+            code: "too-large",
+            severity: SkipSeverity::Warning,
+            explanation: "The file is larger than the largest file which can be compressed",
+        },
+        SkipReasonInfo {
+            name: "ReadError",
+            code: "read-error",
+            severity: SkipSeverity::Warning,
+            explanation: "The file, or information about it, could not be read",
+        },
+        SkipReasonInfo {
+            name: "ZfsFilesystem",
+            code: "zfs",
+            severity: SkipSeverity::Warning,
+            explanation: "The file is on a ZFS filesystem, which does not support compression",
+        },
+        SkipReasonInfo {
+            name: "HasRequiredXattr",
+            code: "has-required-xattr",
+            severity: SkipSeverity::Warning,
+            explanation: "The file already has a resource fork or decmpfs xattr",
+        },
+        SkipReasonInfo {
+            name: "FsNotSupported",
+            code: "fs-not-supported",
+            severity: SkipSeverity::Warning,
+            explanation: "The file is on a filesystem which does not support compression",
+        },
+        SkipReasonInfo {
+            name: "SipProtected",
+            code: "sip-protected",
+            severity: SkipSeverity::Routine,
+            explanation:
+                "The file is protected by System Integrity Protection, and cannot be replaced",
+        },
+        SkipReasonInfo {
+            name: "RequiresFullDiskAccess",
+            code: "requires-full-disk-access",
+            severity: SkipSeverity::Routine,
+            explanation:
+                "The file is in a location protected by TCC, and the process lacks Full Disk Access",
+        },
+        SkipReasonInfo {
+            name: "FileModified",
+            code: "modified",
+            severity: SkipSeverity::Routine,
+            explanation: "The file changed, or was replaced, while it was being processed",
+        },
+        SkipReasonInfo {
+            name: "Dataless",
+            code: "dataless",
+            severity: SkipSeverity::Routine,
+            explanation: "The file's contents are not stored locally, reading it would download it",
+        },
+        SkipReasonInfo {
+            name: "ProbablyClone",
+            code: "probably-clone",
+            severity: SkipSeverity::Routine,
+            explanation: "The file may share blocks with another file (e.g. an APFS clone), with --skip-clones",
+        },
+        SkipReasonInfo {
+            name: "RecentlyActiveLog",
+            code: "recently-active-log",
+            severity: SkipSeverity::Routine,
+            explanation: "The file is a log which was modified recently, so it is probably still being written",
+        },
+        SkipReasonInfo {
+            name: "RecentlyModified",
+            code: "recently-modified",
+            severity: SkipSeverity::Routine,
+            explanation: "The file was modified recently, with --skip-recently-modified",
+        },
+        SkipReasonInfo {
+            name: "DirectoryDeemedIncompressible",
+            code: "directory-incompressible",
+            severity: SkipSeverity::Routine,
+            explanation: "Nearly every file attempted in the same directory did not compress enough, without --no-adaptive-skip",
+        },
+        SkipReasonInfo {
+            name: "MissingResourceFork",
+            code: "missing-resource-fork",
+            severity: SkipSeverity::Warning,
+            explanation: "The file is compressed, but its compressed data in the resource fork is missing, without --force-clear",
+        },
+        SkipReasonInfo {
+            name: "Excluded",
+            code: "tm-excluded",
+            severity: SkipSeverity::Routine,
+            explanation: "The item (or a directory it is in) is excluded from Time Machine backups, with --skip-tm-excluded",
+        },
+        SkipReasonInfo {
+            name: "InBundle",
+            code: "in-bundle",
+            severity: SkipSeverity::Routine,
+            explanation: "The item is (or is inside) an app, framework, or kernel extension bundle, with --skip-bundles",
+        },
+        SkipReasonInfo {
+            name: "InUse",
+            code: "in-use",
+            severity: SkipSeverity::Routine,
+            explanation: "A process has the file open for writing, without --no-in-use-check",
+        },
+        SkipReasonInfo {
+            name: "Sparse",
+            code: "sparse",
+            severity: SkipSeverity::Routine,
+            explanation: "The file is sparse, compressing it would allocate its holes, without --compress-sparse",
+        },
+        SkipReasonInfo {
+            name: "PreviouslyIncompressible",
+            code: "previously-incompressible",
+            severity: SkipSeverity::Routine,
+            explanation: "The file didn't compress enough on a previous run, and hasn't changed since, with --remember-incompressible",
+        },
+        SkipReasonInfo {
+            name: "XattrLimitExceeded",
+            code: "xattr-limit",
+            severity: SkipSeverity::Warning,
+            explanation: "The file's existing extended attributes leave no room for the compression xattr",
+        },
+    ];
+
+    /// The description of this kind of skip reason
+    #[must_use]
+    pub fn info(&self) -> &'static SkipReasonInfo {
+        let index = match self {
+            SkipReason::NotFile => 0,
+            SkipReason::AlreadyCompressed => 1,
+            SkipReason::NotCompressed => 2,
+            SkipReason::EmptyFile => 3,
+            SkipReason::TooLarge { .. } => 4,
+            SkipReason::ReadError(_) => 5,
+            SkipReason::ZfsFilesystem => 6,
+            SkipReason::HasRequiredXattr => 7,
+            SkipReason::FsNotSupported => 8,
+            SkipReason::SipProtected => 9,
+            SkipReason::RequiresFullDiskAccess => 10,
+            SkipReason::FileModified => 11,
+            SkipReason::Dataless => 12,
+            SkipReason::ProbablyClone => 13,
+            SkipReason::RecentlyActiveLog => 14,
+            SkipReason::RecentlyModified => 15,
+            SkipReason::DirectoryDeemedIncompressible => 16,
+            SkipReason::MissingResourceFork => 17,
+            SkipReason::Excluded => 18,
+            SkipReason::InBundle => 19,
+            SkipReason::InUse => 20,
+            SkipReason::Sparse => 21,
+            SkipReason::PreviouslyIncompressible => 22,
+            SkipReason::XattrLimitExceeded(_) => 23,
+        };
+        &Self::ALL[index]
+    }
+
+    /// The stable code of this kind of skip reason
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        self.info().code
+    }
+
+    /// How much a file skipped for this reason may need attention
+    #[must_use]
+    pub fn severity(&self) -> SkipSeverity {
+        self.info().severity
+    }
 }
 
 impl From<IncompressibleReason> for SkipReason {
     fn from(reason: IncompressibleReason) -> SkipReason {
         match reason {
             IncompressibleReason::Empty => SkipReason::EmptyFile,
-            IncompressibleReason::TooLarge(size) => SkipReason::TooLarge(size),
+            IncompressibleReason::TooLarge { size, limit } => SkipReason::TooLarge { size, limit },
             IncompressibleReason::IoError(err) => SkipReason::ReadError(err),
             IncompressibleReason::FsNotSupported => SkipReason::FsNotSupported,
             IncompressibleReason::HasRequiredXattr => SkipReason::HasRequiredXattr,
+            IncompressibleReason::Dataless => SkipReason::Dataless,
+        }
+    }
+}
+
+/// Metadata about a file which was successfully processed
+///
+/// Fields are only populated when capturing metadata is enabled, see
+/// [`FileCompressor::set_capture_metadata`](crate::FileCompressor::set_capture_metadata).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FileSummary {
+    /// The value of the decmpfs xattr written to the compressed file
+    pub decmpfs_xattr: Option<Vec<u8>>,
+    /// The length of the resource fork of the compressed file, if it has one
+    pub resource_fork_len: Option<u64>,
+    /// The BLAKE3 hash of the resource fork of the compressed file, if it has one
+    ///
+    /// This is computed from the data as it's written, rather than by reading the resource fork back.
+    pub resource_fork_hash: Option<[u8; 32]>,
+}
+
+/// The times of a directory which are restored after processing files in it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DirTimes {
+    pub created: SystemTime,
+    pub modified: SystemTime,
+    /// `None` if the access time is not restored
+    pub accessed: Option<SystemTime>,
+    pub added: SystemTime,
+}
+
+/// A directory which doesn't have the times it should have been restored to
+///
+/// See [`FileCompressor::set_check_dir_times`](crate::FileCompressor::set_check_dir_times).
+#[derive(Debug)]
+pub struct DirTimesMismatch {
+    pub path: PathBuf,
+    /// The times saved by the scan, which were restored
+    pub expected: DirTimes,
+    /// The times of the directory at the end of the run, or the error reading them (e.g. if the
+    /// directory was removed)
+    pub actual: io::Result<DirTimes>,
+    /// The error restoring the times, if restoring them failed
+    pub restore_error: Option<io::Error>,
+}
+
+impl fmt::Display for DirTimesMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn secs(time: Option<SystemTime>) -> String {
+            match time.map(|time| time.duration_since(SystemTime::UNIX_EPOCH)) {
+                Some(Ok(since_epoch)) => format!("{:.9}", since_epoch.as_secs_f64()),
+                Some(Err(e)) => format!("-{:.9}", e.duration().as_secs_f64()),
+                None => "none".to_owned(),
+            }
+        }
+
+        match &self.actual {
+            Ok(actual) => {
+                let expected = &self.expected;
+                let times = [
+                    ("created", Some(expected.created), Some(actual.created)),
+                    ("modified", Some(expected.modified), Some(actual.modified)),
+                    ("accessed", expected.accessed, actual.accessed),
+                    ("added", Some(expected.added), Some(actual.added)),
+                ];
+                let mut first = true;
+                for (name, expected, actual) in times {
+                    if expected != actual {
+                        let sep = if first { "" } else { ", " };
+                        first = false;
+                        write!(
+                            f,
+                            "{sep}{name} {} instead of {}",
+                            secs(actual),
+                            secs(expected)
+                        )?;
+                    }
+                }
+            }
+            Err(e) => write!(f, "unable to read times: {e}")?,
+        }
+        if let Some(e) = &self.restore_error {
+            write!(f, " (restoring failed: {e})")?;
         }
+        Ok(())
     }
 }
 
@@ -32,13 +408,61 @@ pub trait Progress {
 
     fn error(&self, path: &Path, message: &str);
     fn file_skipped(&self, _path: &Path, _why: SkipReason) {}
-    fn file_task(&self, path: &Path, size: u64) -> Self::Task;
+    /// Called when a directory could not be read, so none of its contents will be processed
+    ///
+    /// The rest of the walk continues.
+    fn dir_skipped(&self, _path: &Path, _error: io::Error) {}
+    /// Called at the end of the run for each directory which doesn't have the times it was
+    /// restored to
+    ///
+    /// Only called when checking directory times.
+    fn dir_times_not_restored(&self, _mismatch: &DirTimesMismatch) {}
+    /// Called once for each app, framework, or kernel extension bundle, before the first file in
+    /// it is processed
+    ///
+    /// Files in nested bundles (e.g. a framework in an app) are reported as part of the outermost
+    /// bundle. Not called when skipping bundles.
+    fn bundle_entered(&self, _bundle: &Path) {}
+    /// Called at the end of the run if an error stopped it before every file was processed
+    ///
+    /// The error is also available as [`Stats::fatal_error`](crate::Stats::fatal_error).
+    fn fatal_error(&self, _message: &str) {}
+    /// Create the task for a file which will be processed
+    ///
+    /// `root` is the index of the path the file was found under, in the paths passed to
+    /// [`Progress::roots`].
+    fn file_task(&self, path: &Path, size: u64, root: usize) -> Self::Task;
+
+    /// Called before scanning, with each path which will be scanned
+    ///
+    /// Paths which don't exist are not included.
+    fn roots(&self, _roots: &[&Path]) {}
+    /// Called before the paths are scanned for files
+    fn scan_started(&self) {}
+    /// Called for each file found while scanning which will be processed
+    ///
+    /// Files are found while earlier files are already being processed, this is called just
+    /// before the file's task is created.
+    fn found_file(&self, _path: &Path, _size: u64) {}
+    /// Called once all paths have been scanned, with the total number and size of files found
+    fn scan_complete(&self, _total_files: u64, _total_bytes: u64) {}
 }
 
 pub trait Task {
+    /// Called as the file is processed, with the number of uncompressed bytes just processed
+    ///
+    /// This is in uncompressed bytes whether compressing or decompressing, so the increments for
+    /// a file which is processed completely add up to the size passed to
+    /// [`Progress::file_task`].
     fn increment(&self, amt: u64);
     fn error(&self, message: &str);
     fn not_compressible_enough(&self, _path: &Path) {}
+    fn file_skipped(&self, _path: &Path, _why: SkipReason) {}
+    /// Called when a file is being decompressed by the OS, because applesauce can't decompress
+    /// its compression type manually
+    fn decompressing_by_os(&self, _path: &Path, _compression_type: CompressionType) {}
+    /// Called once the file has been successfully compressed or decompressed, and replaced
+    fn finished(&self, _summary: FileSummary) {}
 }
 
 impl<P: Progress> Progress for &'_ P {
@@ -52,8 +476,40 @@ impl<P: Progress> Progress for &'_ P {
         P::file_skipped(self, path, why)
     }
 
-    fn file_task(&self, path: &Path, size: u64) -> Self::Task {
-        P::file_task(self, path, size)
+    fn dir_skipped(&self, path: &Path, error: io::Error) {
+        P::dir_skipped(self, path, error)
+    }
+
+    fn dir_times_not_restored(&self, mismatch: &DirTimesMismatch) {
+        P::dir_times_not_restored(self, mismatch)
+    }
+
+    fn bundle_entered(&self, bundle: &Path) {
+        P::bundle_entered(self, bundle)
+    }
+
+    fn fatal_error(&self, message: &str) {
+        P::fatal_error(self, message)
+    }
+
+    fn file_task(&self, path: &Path, size: u64, root: usize) -> Self::Task {
+        P::file_task(self, path, size, root)
+    }
+
+    fn roots(&self, roots: &[&Path]) {
+        P::roots(self, roots)
+    }
+
+    fn scan_started(&self) {
+        P::scan_started(self)
+    }
+
+    fn found_file(&self, path: &Path, size: u64) {
+        P::found_file(self, path, size)
+    }
+
+    fn scan_complete(&self, total_files: u64, total_bytes: u64) {
+        P::scan_complete(self, total_files, total_bytes)
     }
 }
 
@@ -69,6 +525,18 @@ impl<T: Task> Task for &'_ T {
     fn not_compressible_enough(&self, path: &Path) {
         T::not_compressible_enough(self, path)
     }
+
+    fn file_skipped(&self, path: &Path, why: SkipReason) {
+        T::file_skipped(self, path, why)
+    }
+
+    fn decompressing_by_os(&self, path: &Path, compression_type: CompressionType) {
+        T::decompressing_by_os(self, path, compression_type)
+    }
+
+    fn finished(&self, summary: FileSummary) {
+        T::finished(self, summary)
+    }
 }
 
 impl fmt::Display for SkipReason {
@@ -77,12 +545,38 @@ impl fmt::Display for SkipReason {
             SkipReason::NotFile => write!(f, "Not a file"),
             SkipReason::AlreadyCompressed => write!(f, "Already compressed"),
             SkipReason::NotCompressed => write!(f, "Not compressed"),
-            SkipReason::TooLarge(size) => write!(f, "File too large: {size} > {}", u32::MAX),
+            SkipReason::TooLarge { size, limit } => {
+                write!(
+                    f,
+                    "File too large to compress: {size} bytes (limit {limit})"
+                )
+            }
             SkipReason::ReadError(ref err) => write!(f, "Read error: {err}"),
             SkipReason::ZfsFilesystem => write!(f, "ZFS filesystem (not supported)"),
             SkipReason::HasRequiredXattr => write!(f, "Compression xattrs already present"),
             SkipReason::FsNotSupported => write!(f, "Filesystem does not support compression"),
             SkipReason::EmptyFile => write!(f, "Empty file"),
+            SkipReason::SipProtected => write!(f, "Protected by System Integrity Protection"),
+            SkipReason::RequiresFullDiskAccess => write!(f, "Requires Full Disk Access"),
+            SkipReason::FileModified => write!(f, "Modified while being read"),
+            SkipReason::Dataless => write!(f, "Not stored locally"),
+            SkipReason::ProbablyClone => write!(f, "Probably a clone of another file"),
+            SkipReason::RecentlyActiveLog => write!(f, "Log file which is probably still active"),
+            SkipReason::RecentlyModified => write!(f, "Modified recently"),
+            SkipReason::DirectoryDeemedIncompressible => {
+                write!(f, "Other files in the directory did not compress")
+            }
+            SkipReason::MissingResourceFork => {
+                write!(f, "corrupt compressed file: missing resource fork")
+            }
+            SkipReason::Excluded => write!(f, "Excluded from Time Machine backups"),
+            SkipReason::InBundle => write!(f, "App, framework, or kernel extension bundle"),
+            SkipReason::InUse => write!(f, "Open for writing by a process"),
+            SkipReason::Sparse => write!(f, "Sparse file"),
+            SkipReason::PreviouslyIncompressible => {
+                write!(f, "Did not compress enough on a previous run")
+            }
+            SkipReason::XattrLimitExceeded(ref err) => write!(f, "Too many xattrs: {err}"),
         }
     }
 }
@@ -92,3 +586,71 @@ impl From<io::Error> for SkipReason {
         SkipReason::ReadError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn one_of_each() -> Vec<SkipReason> {
+        vec![
+            SkipReason::NotFile,
+            SkipReason::AlreadyCompressed,
+            SkipReason::NotCompressed,
+            SkipReason::EmptyFile,
+            SkipReason::TooLarge { size: 1, limit: 0 },
+            SkipReason::ReadError(io::Error::other("oops")),
+            SkipReason::ZfsFilesystem,
+            SkipReason::HasRequiredXattr,
+            SkipReason::FsNotSupported,
+            SkipReason::SipProtected,
+            SkipReason::RequiresFullDiskAccess,
+            SkipReason::FileModified,
+            SkipReason::Dataless,
+            SkipReason::ProbablyClone,
+            SkipReason::RecentlyActiveLog,
+            SkipReason::RecentlyModified,
+            SkipReason::DirectoryDeemedIncompressible,
+            SkipReason::MissingResourceFork,
+            SkipReason::Excluded,
+            SkipReason::InBundle,
+            SkipReason::InUse,
+            SkipReason::Sparse,
+            SkipReason::PreviouslyIncompressible,
+            SkipReason::XattrLimitExceeded(io::Error::other("oops")),
+        ]
+    }
+
+    #[test]
+    fn table_matches_variants() {
+        let reasons = one_of_each();
+        assert_eq!(reasons.len(), SkipReason::ALL.len());
+        for (reason, info) in reasons.iter().zip(SkipReason::ALL) {
+            assert_eq!(reason.info(), info);
+            assert!(format!("{reason:?}").starts_with(info.name), "{reason:?}");
+        }
+    }
+
+    #[test]
+    fn severity() {
+        assert_eq!(
+            SkipReason::AlreadyCompressed.severity(),
+            SkipSeverity::Routine
+        );
+        assert_eq!(SkipReason::Excluded.severity(), SkipSeverity::Routine);
+        assert_eq!(
+            SkipReason::ReadError(io::Error::other("oops")).severity(),
+            SkipSeverity::Warning
+        );
+        assert_eq!(
+            SkipReason::MissingResourceFork.severity(),
+            SkipSeverity::Warning
+        );
+    }
+
+    #[test]
+    fn codes_unique() {
+        let codes: HashSet<&str> = SkipReason::ALL.iter().map(|info| info.code).collect();
+        assert_eq!(codes.len(), SkipReason::ALL.len());
+    }
+}
@@ -0,0 +1,152 @@
+//! Classification of files which macOS protects from modification
+//!
+//! Files protected by System Integrity Protection have the `SF_RESTRICTED` flag, and can never be
+//! replaced. Files in locations protected by TCC (Transparency, Consent, and Control) fail with
+//! `EPERM`, even as root, unless the process has been granted Full Disk Access.
+
+use crate::os_error;
+use crate::progress::SkipReason;
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::os::macos::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+
+/// The file is protected by System Integrity Protection (not exposed by libc)
+const SF_RESTRICTED: u32 = 0x0008_0000;
+
+/// Locations (relative to the home directory) which are protected by TCC
+const HOME_TCC_ROOTS: &[&str] = &[
+    "Desktop",
+    "Documents",
+    "Downloads",
+    "Library/Application Support/AddressBook",
+    "Library/Application Support/CallHistoryDB",
+    "Library/Application Support/CallHistoryTransactions",
+    "Library/Application Support/com.apple.TCC",
+    "Library/Application Support/FaceTime",
+    "Library/Application Support/Knowledge",
+    "Library/Autosave Information",
+    "Library/Calendars",
+    "Library/Containers/com.apple.mail",
+    "Library/Containers/com.apple.Safari",
+    "Library/Cookies",
+    "Library/HomeKit",
+    "Library/IdentityServices",
+    "Library/Mail",
+    "Library/Messages",
+    "Library/Metadata/CoreSpotlight",
+    "Library/PersonalizationPortrait",
+    "Library/Reminders",
+    "Library/Safari",
+    "Library/Suggestions",
+    "Pictures/Photos Library.photoslibrary",
+];
+
+/// System-wide locations which are protected by TCC
+const SYSTEM_TCC_ROOTS: &[&str] = &[
+    "/Library/Application Support/com.apple.TCC",
+    "/Library/Application Support/com.apple.backupd",
+];
+
+/// Returns true if the passed file flags mark a file as protected by System Integrity Protection
+#[must_use]
+pub fn is_restricted(flags: u32) -> bool {
+    flags & SF_RESTRICTED != 0
+}
+
+/// Returns true if the file is protected by System Integrity Protection
+#[must_use]
+pub fn is_sip_protected(metadata: &Metadata) -> bool {
+    is_restricted(metadata.st_flags())
+}
+
+/// The roots of locations protected by TCC, for the current user
+#[must_use]
+pub fn tcc_roots() -> Vec<PathBuf> {
+    tcc_roots_in(std::env::var_os("HOME"))
+}
+
+fn tcc_roots_in(home: Option<OsString>) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = SYSTEM_TCC_ROOTS.iter().map(PathBuf::from).collect();
+    if let Some(home) = home {
+        let home = PathBuf::from(home);
+        roots.extend(HOME_TCC_ROOTS.iter().map(|root| home.join(root)));
+    }
+    roots
+}
+
+/// Returns true if `path` is inside (or is) one of the passed TCC protected roots
+#[must_use]
+pub fn is_under_tcc_root(path: &Path, roots: &[PathBuf]) -> bool {
+    roots.iter().any(|root| path.starts_with(root))
+}
+
+/// Convert a generic read error into a more specific reason, if the path is inside one of the
+/// TCC protected `roots` (from [`tcc_roots`])
+///
+/// `EPERM` for a path in a TCC protected location is almost certainly caused by missing
+/// Full Disk Access, rather than a real error.
+#[must_use]
+pub fn classify_skip_with_roots(path: &Path, reason: SkipReason, roots: &[PathBuf]) -> SkipReason {
+    match reason {
+        SkipReason::ReadError(e)
+            if os_error::errno(&e) == Some(libc::EPERM) && is_under_tcc_root(path, roots) =>
+        {
+            SkipReason::RequiresFullDiskAccess
+        }
+        reason => reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn restricted_flag() {
+        assert!(!is_restricted(0));
+        assert!(!is_restricted(libc::UF_COMPRESSED | libc::UF_HIDDEN));
+        assert!(is_restricted(SF_RESTRICTED));
+        assert!(is_restricted(SF_RESTRICTED | libc::UF_COMPRESSED));
+    }
+
+    #[test]
+    fn eperm_under_tcc_root() {
+        let roots = tcc_roots_in(Some("/Users/fake".into()));
+        let path = Path::new("/Users/fake/Library/Mail/V10/message.emlx");
+        let reason = classify_skip_with_roots(
+            path,
+            SkipReason::ReadError(io::Error::from_raw_os_error(libc::EPERM)),
+            &roots,
+        );
+        assert!(matches!(reason, SkipReason::RequiresFullDiskAccess));
+    }
+
+    #[test]
+    fn other_errors_unchanged() {
+        let roots = tcc_roots_in(Some("/Users/fake".into()));
+
+        // EPERM outside of a TCC root is a real error
+        let reason = classify_skip_with_roots(
+            Path::new("/Users/fake/Projects/file"),
+            SkipReason::ReadError(io::Error::from_raw_os_error(libc::EPERM)),
+            &roots,
+        );
+        assert!(matches!(reason, SkipReason::ReadError(_)));
+
+        // Other errors under a TCC root are real errors
+        let reason = classify_skip_with_roots(
+            Path::new("/Users/fake/Library/Mail/file"),
+            SkipReason::ReadError(io::Error::from_raw_os_error(libc::EIO)),
+            &roots,
+        );
+        assert!(matches!(reason, SkipReason::ReadError(_)));
+
+        // Only whole path components match
+        assert!(!is_under_tcc_root(
+            Path::new("/Users/fake/Library/MailArchive/file"),
+            &roots
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+//! Choosing which files to compress or decompress based on how recently they were accessed
+//!
+//! Frequently accessed ("hot") compressed files pay for decompression on every read, while
+//! rarely accessed ("cold") uncompressed files waste space. A rebalance decompresses hot
+//! compressed files, and compresses cold uncompressed files.
+//!
+//! When applesauce compresses a file, it records the time of compression in the
+//! [`COMPRESSED_AT_XATTR`] xattr, as decimal seconds since the unix epoch. Compressing a file
+//! creates a new file, which updates its access time, so without this marker, a freshly
+//! compressed file would look like it had been accessed recently.
+
+use crate::info::{self, FileCompressionState};
+use crate::{os_error, path_cstring, xattr};
+use std::ffi::CStr;
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::macos::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The xattr storing the time a file was compressed by applesauce
+pub const COMPRESSED_AT_XATTR: &CStr = c"dev.applesauce.compressed-at";
+
+/// A change to make to a file to rebalance it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// The file is compressed, and has been accessed recently
+    Decompress,
+    /// The file is not compressed, and has not been accessed recently
+    Compress,
+}
+
+/// The files to change to rebalance a set of paths
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Hot compressed files, which should be decompressed
+    pub decompress: Vec<PathBuf>,
+    /// Cold uncompressed files, which should be compressed
+    pub compress: Vec<PathBuf>,
+}
+
+/// The access information about a file used to decide if it is hot or cold
+#[derive(Debug, Copy, Clone)]
+pub struct Access {
+    pub is_compressed: bool,
+    pub accessed: SystemTime,
+    pub modified: SystemTime,
+    /// When applesauce compressed the file, if it was compressed by applesauce
+    pub compressed_at: Option<SystemTime>,
+}
+
+/// Decide what should be done with a file, if anything
+///
+/// A file is hot if it was accessed or modified within `hot_window` of `now`. For compressed
+/// files with a compression time recorded, only accesses after compression count.
+#[must_use]
+pub fn classify(access: &Access, hot_window: Duration, now: SystemTime) -> Option<Action> {
+    let last_used = access.accessed.max(access.modified);
+    let recently_used = now
+        .duration_since(last_used)
+        .map_or(true, |since| since <= hot_window);
+
+    if access.is_compressed {
+        let used_since_compression = access
+            .compressed_at
+            .is_none_or(|compressed_at| last_used > compressed_at);
+        (recently_used && used_since_compression).then_some(Action::Decompress)
+    } else {
+        (!recently_used).then_some(Action::Compress)
+    }
+}
+
+/// Walk `paths`, and find which files should be compressed or decompressed
+///
+/// Only files which can be compressed will be planned to be compressed.
+pub fn plan<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+    hot_window: Duration,
+    now: SystemTime,
+) -> io::Result<Plan> {
+    let mut plan = Plan::default();
+    for path in paths {
+        for entry in jwalk::WalkDir::new(path).sort(true) {
+            let entry = entry?;
+            #[allow(clippy::filetype_is_file)]
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = path.symlink_metadata()?;
+            // Rebalancing a file which is not stored locally would download it
+            if info::is_dataless(&metadata) {
+                continue;
+            }
+            let access = access_for(&path, &metadata)?;
+            match classify(&access, hot_window, now) {
+                Some(Action::Decompress) => plan.decompress.push(path),
+                Some(Action::Compress) => {
+                    if let FileCompressionState::Compressible =
+                        info::get_compression_state(&path, &metadata)
+                    {
+                        plan.compress.push(path);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn access_for(path: &Path, metadata: &Metadata) -> io::Result<Access> {
+    let is_compressed = metadata.st_flags() & libc::UF_COMPRESSED != 0;
+    let compressed_at = if is_compressed {
+        let path = path_cstring(path)?;
+        compressed_at(path.as_c_str())?
+    } else {
+        None
+    };
+    Ok(Access {
+        is_compressed,
+        accessed: metadata.accessed()?,
+        modified: metadata.modified()?,
+        compressed_at,
+    })
+}
+
+/// Read the time a file was compressed by applesauce
+///
+/// Returns `None` if the file has no (valid) compression time recorded.
+pub fn compressed_at<F: xattr::XattrSource + ?Sized>(f: &F) -> io::Result<Option<SystemTime>> {
+    let Some(value) = xattr::read(f, COMPRESSED_AT_XATTR)? else {
+        return Ok(None);
+    };
+    let secs = std::str::from_utf8(&value)
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok());
+    Ok(secs.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+/// Record the time a file was compressed
+///
+/// The time is rounded up to the next second, so accesses during compression (e.g. verification)
+/// are not counted as accesses after compression. This should be called as late as possible
+/// before replacing the original file.
+pub fn set_compressed_at<F: xattr::XattrSource + ?Sized>(
+    f: &F,
+    time: SystemTime,
+) -> io::Result<()> {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| {
+            since.as_secs() + u64::from(since.subsec_nanos() != 0)
+        });
+    xattr::set(f, COMPRESSED_AT_XATTR, secs.to_string().as_bytes(), 0)
+}
+
+/// Remove the compression time from a file which is no longer compressed
+pub(crate) fn clear_compressed_at(f: &File) -> io::Result<()> {
+    match xattr::remove(f, COMPRESSED_AT_XATTR) {
+        Err(e) if os_error::errno(&e) == Some(libc::ENOATTR) => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Fixture;
+    use crate::{compress_file, Verify};
+    use applesauce_core::compressor::Kind;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn access(is_compressed: bool, days_ago: u64, compressed_days_ago: Option<u64>) -> Access {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        Access {
+            is_compressed,
+            accessed: now - days_ago as u32 * DAY,
+            modified: now - 500 * DAY,
+            compressed_at: compressed_days_ago.map(|days| now - days as u32 * DAY),
+        }
+    }
+
+    fn classify_access(access: &Access) -> Option<Action> {
+        classify(access, 7 * DAY, SystemTime::UNIX_EPOCH + 1000 * DAY)
+    }
+
+    #[test]
+    fn hot_compressed() {
+        assert_eq!(
+            classify_access(&access(true, 1, Some(30))),
+            Some(Action::Decompress)
+        );
+        // Compressed by something else, trust the access time
+        assert_eq!(
+            classify_access(&access(true, 1, None)),
+            Some(Action::Decompress)
+        );
+    }
+
+    #[test]
+    fn cold_compressed() {
+        assert_eq!(classify_access(&access(true, 30, Some(60))), None);
+        // Accessed recently, but only by compression itself
+        assert_eq!(classify_access(&access(true, 1, Some(1))), None);
+    }
+
+    #[test]
+    fn hot_uncompressed() {
+        assert_eq!(classify_access(&access(false, 1, None)), None);
+    }
+
+    #[test]
+    fn cold_uncompressed() {
+        assert_eq!(
+            classify_access(&access(false, 30, None)),
+            Some(Action::Compress)
+        );
+    }
+
+    #[test]
+    fn recently_modified_is_hot() {
+        let mut access = access(false, 30, None);
+        access.modified = SystemTime::UNIX_EPOCH + 999 * DAY;
+        assert_eq!(classify_access(&access), None);
+    }
+
+    #[test]
+    fn rebalance_plan() {
+        use std::ffi::CString;
+        use std::fs::FileTimes;
+        use std::os::unix::ffi::OsStrExt;
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        let dir = Fixture::new();
+
+        let make_file = |name: &str,
+                         compressed_days_ago: Option<u32>,
+                         accessed_days_ago: u32,
+                         modified_days_ago: u32| {
+            let path = dir.file(name, [0; 16 * 1024]);
+            if let Some(days) = compressed_days_ago {
+                assert!(
+                    compress_file(&path, Kind::default(), 5, 1.0, Verify::Off)
+                        .unwrap()
+                        .compressed
+                );
+                let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+                set_compressed_at(c_path.as_c_str(), now - days * DAY).unwrap();
+            }
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_times(
+                    FileTimes::new()
+                        .set_accessed(now - accessed_days_ago * DAY)
+                        .set_modified(now - modified_days_ago * DAY),
+                )
+                .unwrap();
+            path
+        };
+
+        let hot_compressed = make_file("hot_compressed", Some(30), 1, 60);
+        make_file("cold_compressed", Some(60), 30, 90);
+        make_file("hot_uncompressed", None, 1, 60);
+        let cold_uncompressed = make_file("cold_uncompressed", None, 30, 30);
+
+        let plan = plan([dir.path()], 7 * DAY, now).unwrap();
+        assert_eq!(plan.decompress, vec![hot_compressed]);
+        assert_eq!(plan.compress, vec![cold_uncompressed]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Skipping files which are probably still being written to
+//!
+//! Replacing a file which another process has open for writing loses everything written after it
+//! is replaced: the process keeps writing to the original file, which is unlinked, until it
+//! reopens the path. The most common case is a log file which a launchd service's stdout or
+//! stderr is redirected to. Finding every open file is expensive, so this uses a cheap heuristic
+//! instead: log files modified recently are probably still being written.
+
+use crate::progress::SkipReason;
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Locations (relative to the home directory) which logs are written to
+const HOME_LOG_ROOTS: &[&str] = &["Library/Logs"];
+
+/// System-wide locations which logs are written to
+///
+/// `/var` is a symlink to `/private/var`, so both are included.
+const SYSTEM_LOG_ROOTS: &[&str] = &["/var/log", "/private/var/log", "/Library/Logs"];
+
+/// How recently modified files are skipped
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Recency {
+    /// Skip files in log locations modified within this long
+    pub active_log_window: Option<Duration>,
+    /// Skip files anywhere modified within this long
+    pub modified_window: Option<Duration>,
+}
+
+/// The roots of locations logs are written to, for the current user
+#[must_use]
+pub fn log_roots() -> Vec<PathBuf> {
+    log_roots_in(std::env::var_os("HOME"))
+}
+
+fn log_roots_in(home: Option<OsString>) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = SYSTEM_LOG_ROOTS.iter().map(PathBuf::from).collect();
+    if let Some(home) = home {
+        let home = PathBuf::from(home);
+        roots.extend(HOME_LOG_ROOTS.iter().map(|root| home.join(root)));
+    }
+    roots
+}
+
+/// The reason to skip a file modified too recently, if any
+///
+/// A file with a modification time in the future is treated as modified just now.
+#[must_use]
+pub fn skip_reason(
+    path: &Path,
+    metadata: &Metadata,
+    recency: Recency,
+    log_roots: &[PathBuf],
+    now: SystemTime,
+) -> Option<SkipReason> {
+    let modified = metadata.modified().ok()?;
+    let age = now.duration_since(modified).unwrap_or(Duration::ZERO);
+    skip_reason_for_age(path, age, recency, log_roots)
+}
+
+fn skip_reason_for_age(
+    path: &Path,
+    age: Duration,
+    recency: Recency,
+    log_roots: &[PathBuf],
+) -> Option<SkipReason> {
+    let within = |window: Option<Duration>| window.is_some_and(|window| age < window);
+    if within(recency.active_log_window) && log_roots.iter().any(|root| path.starts_with(root)) {
+        Some(SkipReason::RecentlyActiveLog)
+    } else if within(recency.modified_window) {
+        Some(SkipReason::RecentlyModified)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, NoProgress};
+    use crate::DEFAULT_ACTIVE_LOG_WINDOW;
+    use crate::{info, FileCompressor};
+    use std::fs::{self, File};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn defaults() -> Recency {
+        Recency {
+            active_log_window: Some(DEFAULT_ACTIVE_LOG_WINDOW),
+            modified_window: None,
+        }
+    }
+
+    #[test]
+    fn recent_log_skipped() {
+        let roots = log_roots_in(Some("/Users/fake".into()));
+        let path = Path::new("/Users/fake/Library/Logs/com.example.agent/stdout.log");
+        assert!(matches!(
+            skip_reason_for_age(path, MINUTE, defaults(), &roots),
+            Some(SkipReason::RecentlyActiveLog)
+        ));
+        assert!(matches!(
+            skip_reason_for_age(Path::new("/var/log/system.log"), MINUTE, defaults(), &roots),
+            Some(SkipReason::RecentlyActiveLog)
+        ));
+    }
+
+    #[test]
+    fn old_log_not_skipped() {
+        let roots = log_roots_in(Some("/Users/fake".into()));
+        let path = Path::new("/Users/fake/Library/Logs/com.example.agent/stdout.log");
+        assert!(skip_reason_for_age(path, 11 * MINUTE, defaults(), &roots).is_none());
+
+        let no_log_window = Recency {
+            active_log_window: None,
+            ..defaults()
+        };
+        assert!(skip_reason_for_age(path, MINUTE, no_log_window, &roots).is_none());
+    }
+
+    #[test]
+    fn recent_file_outside_logs() {
+        let roots = log_roots_in(Some("/Users/fake".into()));
+        let path = Path::new("/Users/fake/Projects/build.log");
+        assert!(skip_reason_for_age(path, MINUTE, defaults(), &roots).is_none());
+
+        let recency = Recency {
+            modified_window: Some(60 * MINUTE),
+            ..defaults()
+        };
+        assert!(matches!(
+            skip_reason_for_age(path, MINUTE, recency, &roots),
+            Some(SkipReason::RecentlyModified)
+        ));
+        assert!(skip_reason_for_age(path, 2 * 60 * MINUTE, recency, &roots).is_none());
+        // Only whole path components match
+        assert!(skip_reason_for_age(
+            Path::new("/Users/fake/Library/LogsArchive/file"),
+            MINUTE,
+            defaults(),
+            &roots
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn skip_recently_modified() {
+        let dir = Fixture::new();
+        let recent = dir.path().join("recent");
+        let old = dir.path().join("old");
+        fs::write(&recent, [0; 16 * 1024]).unwrap();
+        fs::write(&old, [0; 16 * 1024]).unwrap();
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+
+        let mut fc = FileCompressor::new();
+        fc.set_skip_recently_modified(Some(Duration::from_secs(60 * 60)));
+        let stats = dir.compress(&mut fc, &NoProgress);
+        assert_eq!(stats.skipped.recently_modified.into_inner(), 1);
+        assert!(!info::get(&recent).unwrap().is_compressed);
+        assert!(info::get(&old).unwrap().is_compressed);
+    }
+}
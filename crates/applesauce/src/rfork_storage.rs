@@ -4,8 +4,47 @@ use applesauce_core::decmpfs;
 use applesauce_core::BLOCK_SIZE;
 use resource_fork::ResourceFork;
 use std::fs::File;
-use std::io;
+use std::{error, fmt, io};
 
+/// The file is compressed with a compression type which applesauce can't decompress
+#[derive(Debug)]
+pub struct UnsupportedType(pub decmpfs::CompressionType);
+
+impl fmt::Display for UnsupportedType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported compression type {}", self.0)
+    }
+}
+
+impl error::Error for UnsupportedType {}
+
+/// The compression type, if the error is because the compression type is not supported
+pub fn unsupported_type(e: &io::Error) -> Option<decmpfs::CompressionType> {
+    e.get_ref()?
+        .downcast_ref::<UnsupportedType>()
+        .map(|unsupported| unsupported.0)
+}
+
+/// Returns true if the file's decmpfs xattr says its compressed data is in the resource fork, but
+/// the file has no resource fork
+///
+/// The compressed data of such a file is lost: the OS fails to read it, and it can't be
+/// decompressed.
+pub fn missing_resource_fork(file: &File) -> io::Result<bool> {
+    let Some(decmpfs_data) = xattr::read(file, decmpfs::XATTR_NAME)? else {
+        return Ok(false);
+    };
+    let compression_type = decmpfs::Value::from_data(&decmpfs_data)?.compression_type;
+    let in_resource_fork = compression_type
+        .compression_storage()
+        .is_some_and(|(_, storage)| storage == decmpfs::Storage::ResourceFork);
+    Ok(in_resource_fork && !xattr::is_present(file, resource_fork::XATTR_NAME)?)
+}
+
+/// Call `f` with the kind of compression, then the returned function with each compressed block
+///
+/// If applesauce can't decompress the file's compression type, this fails with an
+/// [`UnsupportedType`] error (see [`unsupported_type`]) before calling `f`.
 pub fn with_compressed_blocks<F, F2>(file: &File, f: F) -> io::Result<()>
 where
     F: FnOnce(Kind) -> F2,
@@ -13,10 +52,20 @@ where
 {
     let decmpfs_data = xattr::read(file, decmpfs::XATTR_NAME)?
         .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "file is not compressed"))?;
+    let compression_type = decmpfs::Value::from_data(&decmpfs_data)?.compression_type;
+    let Some((kind, _)) = compression_type
+        .compression_storage()
+        .filter(|(kind, _)| kind.supported())
+    else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            UnsupportedType(compression_type),
+        ));
+    };
     let mut reader =
         applesauce_core::reader::Reader::new(&decmpfs_data, || ResourceFork::new(file))?;
 
-    let mut per_block = f(reader.compression_kind());
+    let mut per_block = f(kind);
     let mut buf = Vec::with_capacity(BLOCK_SIZE);
     loop {
         buf.clear();
@@ -1,22 +1,65 @@
-use crate::progress::Progress;
-use crate::times;
 use crate::tmpdir_paths::TmpdirPaths;
+use crate::{
+    bundles, path_cstring, times, xattr, BackupExclusion, BundlePolicy, FileOrder, TimePreservation,
+};
+use std::cmp::Reverse;
 use std::collections::HashSet;
-use std::fs::FileType;
+use std::ffi::CStr;
+use std::fs::{self, FileType, Metadata};
+use std::io;
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+/// The most files held at once to be sorted for a [`FileOrder`]
+///
+/// Files in larger trees are sorted in batches of this many, so memory use stays bounded.
+const MAX_ORDERED_FILES: usize = 256 * 1024;
+
+/// The xattr set on items excluded from Time Machine backups (by `tmutil addexclusion`)
+const BACKUP_EXCLUDE_XATTR: &CStr = c"com.apple.metadata:com_apple_backup_excludeItem";
+
+/// Returns true if the item at `path` is excluded from Time Machine backups
+///
+/// Errors reading the xattr are treated as not excluded.
+fn is_backup_excluded(path: &Path) -> bool {
+    path_cstring(path)
+        .is_ok_and(|path| xattr::is_present(&path, BACKUP_EXCLUDE_XATTR).unwrap_or(false))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn walk_dir_over(
     path: &Path,
     ignored_dirs: Arc<HashSet<PathBuf>>,
-) -> jwalk::WalkDirGeneric<((), State)> {
+    backup_exclusion: BackupExclusion,
+    bundle_policy: BundlePolicy,
+    root_bundle: Option<Arc<Path>>,
+    time_preservation: TimePreservation,
+    restore_log: Option<Arc<times::RestoreLog>>,
+    pending_resets: Arc<times::PendingResets>,
+) -> jwalk::WalkDirGeneric<(DirState, EntryState)> {
     let walker = jwalk::WalkDirGeneric::new(path);
     walker.process_read_dir(
-        move |_depth,
+        move |depth,
               path: &Path,
-              _state,
-              entries: &mut Vec<jwalk::Result<jwalk::DirEntry<((), State)>>>| {
-            let mut reset_times: Option<State> = None;
+              dir_state: &mut DirState,
+              entries: &mut Vec<jwalk::Result<jwalk::DirEntry<(DirState, EntryState)>>>| {
+            // The root entry is yielded with no depth, its exclusion is checked by the caller
+            if backup_exclusion == BackupExclusion::Only
+                && depth.is_some()
+                && !dir_state.in_excluded_dir
+            {
+                // Inherited by every directory below this one
+                dir_state.in_excluded_dir = is_backup_excluded(path);
+            }
+            // Like exclusion, the bundle the root is in was found by the caller, and a bundle is
+            // inherited by every directory below it
+            if depth.is_none() {
+                dir_state.bundle.clone_from(&root_bundle);
+            } else if dir_state.bundle.is_none() && bundles::is_bundle(path) {
+                dir_state.bundle = Some(Arc::from(path));
+            }
+            let mut reset_times: Option<Option<Arc<times::Resetter>>> = None;
             // Remove ignored directories from the list of entries.
             // Also, add the client state to the entry.
             entries.retain_mut(|entry| {
@@ -24,18 +67,60 @@ fn walk_dir_over(
                     if entry.file_type().is_dir() && ignored_dirs.contains(entry.path().as_path()) {
                         return false;
                     }
+                    match backup_exclusion {
+                        BackupExclusion::Ignore => {}
+                        // Prune excluded directories before they are read, but keep the entry so
+                        // it can be reported
+                        BackupExclusion::Skip => {
+                            if depth.is_some() && is_backup_excluded(&entry.path()) {
+                                entry.read_children_path = None;
+                                entry.client_state.backup_excluded = true;
+                                return true;
+                            }
+                        }
+                        // Directories must still be read to find excluded items inside them
+                        BackupExclusion::Only => {
+                            if !entry.file_type().is_dir()
+                                && depth.is_some()
+                                && !dir_state.in_excluded_dir
+                                && !is_backup_excluded(&entry.path())
+                            {
+                                return false;
+                            }
+                        }
+                    }
+                    // Prune bundles before they are read, like excluded directories
+                    if bundle_policy == BundlePolicy::Skip
+                        && depth.is_some()
+                        && entry.file_type().is_dir()
+                        && bundles::is_bundle(&entry.path())
+                    {
+                        entry.read_children_path = None;
+                        entry.client_state.skipped_bundle = true;
+                        return true;
+                    }
                     #[allow(clippy::filetype_is_file)]
                     if entry.file_type().is_file() {
+                        entry.client_state.bundle.clone_from(&dir_state.bundle);
                         let reset_times = match &mut reset_times {
                             Some(reset_times) => reset_times,
                             None => reset_times.insert(
-                                times::save_times(path)
-                                    .and_then(|saved_times| times::Resetter::new(path, saved_times))
+                                times::save_times_for(path, time_preservation)
                                     .ok()
+                                    .flatten()
+                                    .and_then(|saved_times| {
+                                        times::Resetter::new(
+                                            path,
+                                            saved_times,
+                                            restore_log.clone(),
+                                            Some(Arc::clone(&pending_resets)),
+                                        )
+                                        .ok()
+                                    })
                                     .map(Arc::new),
                             ),
                         };
-                        entry.client_state.clone_from(reset_times);
+                        entry.client_state.reset_times.clone_from(reset_times);
                     }
                 }
                 true
@@ -44,18 +129,97 @@ fn walk_dir_over(
     )
 }
 
-type State = Option<Arc<times::Resetter>>;
+/// Find the paths which are the same as, or inside, another of `paths`
+///
+/// Returns `(nested, containing)` pairs of indexes into `paths`: walking `nested` as well as
+/// `containing` would visit the same files twice. Of identical paths, the first is kept. Paths are
+/// compared after resolving symlinks and `..`, so paths which can't be resolved never overlap.
+pub fn nested_roots(paths: &[&Path]) -> Vec<(usize, usize)> {
+    let resolved: Vec<Option<PathBuf>> = paths
+        .iter()
+        .map(|path| fs::canonicalize(path).ok())
+        .collect();
+    let mut nested = Vec::new();
+    for (i, path) in resolved.iter().enumerate() {
+        let Some(path) = path else { continue };
+        let containing = resolved.iter().enumerate().find(|&(j, other)| {
+            other
+                .as_ref()
+                .is_some_and(|other| path.starts_with(other) && (path != other || j < i))
+        });
+        if let Some((containing, _)) = containing {
+            nested.push((i, containing));
+        }
+    }
+    nested
+}
 
-pub struct Walker<'a, P> {
+/// The state of the directory being read, inherited by every directory below it
+#[derive(Debug, Default, Clone)]
+struct DirState {
+    /// The directory is (or is inside) a directory excluded from backups
+    ///
+    /// Only tracked when processing only excluded items.
+    in_excluded_dir: bool,
+    /// The outermost bundle the directory is (or is inside)
+    bundle: Option<Arc<Path>>,
+}
+
+#[derive(Debug, Default)]
+struct EntryState {
+    /// Resets the times of the directory the file is in, once every file in it is done
+    reset_times: Option<Arc<times::Resetter>>,
+    /// The entry is excluded from backups, and excluded items are skipped
+    backup_excluded: bool,
+    /// The entry is a bundle, and bundles are skipped
+    skipped_bundle: bool,
+    /// The outermost bundle the file is in
+    bundle: Option<Arc<Path>>,
+}
+
+/// A file found by the walk
+pub struct FoundFile {
+    /// The index of the path the file was found under
+    pub root: usize,
+    pub file_type: FileType,
+    pub path: PathBuf,
+    /// Resets the times of the directory the file is in, once every file in it is done
+    pub dir_reset: Option<Arc<times::Resetter>>,
+    /// The outermost app, framework, or kernel extension bundle the file is in
+    pub bundle: Option<Arc<Path>>,
+}
+
+pub struct Walker<'a> {
     paths: Vec<&'a Path>,
-    progress: &'a P,
+    order: FileOrder,
+    backup_exclusion: BackupExclusion,
+    bundle_policy: BundlePolicy,
+    time_preservation: TimePreservation,
+    restore_log: Option<Arc<times::RestoreLog>>,
+    pending_resets: Arc<times::PendingResets>,
 }
 
-impl<'a, P: Progress + Send + Sync> Walker<'a, P> {
-    pub fn new(progress: &'a P) -> Self {
+impl<'a> Walker<'a> {
+    /// Create a walker, which records each restoration of directory times in `restore_log`, if
+    /// given
+    ///
+    /// Every directory time resetter created by the walk is tracked by `pending_resets`.
+    pub fn new(
+        order: FileOrder,
+        backup_exclusion: BackupExclusion,
+        bundle_policy: BundlePolicy,
+        time_preservation: TimePreservation,
+        restore_log: Option<Arc<times::RestoreLog>>,
+        pending_resets: Arc<times::PendingResets>,
+    ) -> Self {
         Self {
             paths: Vec::new(),
-            progress,
+            order,
+            backup_exclusion,
+            bundle_policy,
+            time_preservation,
+            restore_log,
+            pending_resets,
         }
     }
 
@@ -63,38 +227,440 @@ impl<'a, P: Progress + Send + Sync> Walker<'a, P> {
         self.paths.push(path);
     }
 
+    /// The paths which will be walked, in order
+    pub fn paths(&self) -> &[&'a Path] {
+        &self.paths
+    }
+
+    /// Walk every path, calling `f` with each file found
+    ///
+    /// Unless the walker's order is [`FileOrder::Discovery`], files are collected (in batches of
+    /// at most `MAX_ORDERED_FILES`) and passed to `f` sorted by size, once the walk (or the
+    /// batch) is complete.
+    ///
+    /// `dir_found` is called for each directory (including the paths themselves) before anything
+    /// inside it. When skipping items excluded from backups, `excluded` is called for each
+    /// excluded file or directory, and nothing inside an excluded directory is visited. Likewise
+    /// when skipping bundles, `bundle_skipped` is called for each bundle (or path inside one).
+    /// `error` is called for each entry which couldn't be scanned.
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         self,
         tmpdirs: &TmpdirPaths,
-        f: impl Fn(FileType, PathBuf, Option<Arc<times::Resetter>>) + Send + Sync,
+        f: impl Fn(FoundFile) -> ControlFlow<()> + Send + Sync,
+        dir_found: impl Fn(usize, &Path, &Metadata),
+        dir_unreadable: impl Fn(&Path, io::Error),
+        excluded: impl Fn(usize, &Path),
+        bundle_skipped: impl Fn(usize, &Path),
+        error: impl Fn(&Path, &str),
     ) {
         let ignored_dirs: Arc<HashSet<PathBuf>> =
             Arc::new(tmpdirs.paths().map(PathBuf::from).collect());
-        for path in self.paths {
-            let walker = walk_dir_over(path, Arc::clone(&ignored_dirs));
+        let order = self.order;
+        let mut ordered: Vec<(u64, FoundFile)> = Vec::new();
+        for (root, path) in self.paths.into_iter().enumerate() {
+            let mut backup_exclusion = self.backup_exclusion;
+            if backup_exclusion != BackupExclusion::Ignore {
+                // The root itself, or a parent of it, may be excluded
+                let root_excluded = path.ancestors().any(is_backup_excluded);
+                match (backup_exclusion, root_excluded) {
+                    (BackupExclusion::Skip, true) => {
+                        excluded(root, path);
+                        continue;
+                    }
+                    (BackupExclusion::Only, true) => backup_exclusion = BackupExclusion::Ignore,
+                    // A file which isn't excluded has nothing excluded inside it
+                    (BackupExclusion::Only, false) if !path.is_dir() => continue,
+                    _ => {}
+                }
+            }
+            // The root itself, or a parent of it, may be a bundle
+            let root_bundle = bundles::containing_bundle(path).map(Arc::from);
+            if root_bundle.is_some() && self.bundle_policy == BundlePolicy::Skip {
+                bundle_skipped(root, path);
+                continue;
+            }
+            let walker = walk_dir_over(
+                path,
+                Arc::clone(&ignored_dirs),
+                backup_exclusion,
+                self.bundle_policy,
+                root_bundle,
+                self.time_preservation,
+                self.restore_log.clone(),
+                Arc::clone(&self.pending_resets),
+            );
             for entry in walker {
                 let mut entry = match entry {
                     Ok(entry) => entry,
                     Err(e) => {
-                        self.progress
-                            .error(Path::new("?"), &format!("error scanning: {e}"));
+                        // Errors reading a directory only stop the walk of that directory
+                        match e.path().map(Path::to_owned) {
+                            Some(dir) if e.io_error().is_some() => {
+                                dir_unreadable(&dir, e.into_io_error().unwrap());
+                            }
+                            _ => error(Path::new("?"), &format!("error scanning: {e}")),
+                        }
                         continue;
                     }
                 };
                 let path = entry.path();
+                if entry.client_state.backup_excluded {
+                    excluded(root, &path);
+                    continue;
+                }
+                if entry.client_state.skipped_bundle {
+                    bundle_skipped(root, &path);
+                    continue;
+                }
                 let metadata = match entry.metadata() {
                     Ok(metadata) => metadata,
                     Err(e) => {
-                        self.progress
-                            .error(&path, &format!("error getting metadata: {e}"));
+                        error(&path, &format!("error getting metadata: {e}"));
                         continue;
                     }
                 };
                 if metadata.is_dir() {
+                    dir_found(root, &path, &metadata);
+                    continue;
+                }
+                let found = FoundFile {
+                    root,
+                    file_type: metadata.file_type(),
+                    path,
+                    dir_reset: entry.client_state.reset_times.take(),
+                    bundle: entry.client_state.bundle.take(),
+                };
+                if order == FileOrder::Discovery {
+                    if f(found).is_break() {
+                        return;
+                    }
                     continue;
                 }
-                f(metadata.file_type(), path, entry.client_state.take())
+                ordered.push((metadata.len(), found));
+                if ordered.len() >= MAX_ORDERED_FILES
+                    && in_order(order, &mut ordered, &f).is_break()
+                {
+                    return;
+                }
             }
         }
+        let _ = in_order(order, &mut ordered, &f);
+    }
+}
+
+/// Sort `files` by size for `order`, and pass each to `f`, until `f` breaks
+///
+/// `files` is empty afterwards.
+fn in_order(
+    order: FileOrder,
+    files: &mut Vec<(u64, FoundFile)>,
+    f: impl Fn(FoundFile) -> ControlFlow<()>,
+) -> ControlFlow<()> {
+    // Stable sorts, so files of the same size stay in the order they were found
+    match order {
+        FileOrder::Discovery => {}
+        FileOrder::LargestFirst => files.sort_by_key(|&(len, _)| Reverse(len)),
+        FileOrder::SmallestFirst => files.sort_by_key(|&(len, _)| len),
+    }
+    for (_, found) in files.drain(..) {
+        f(found)?;
+    }
+    ControlFlow::Continue(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Progress;
+    use crate::testing::{compressed_files, populate_dir, ErrorProgress, Fixture, NoProgress};
+    use crate::{info, CompressionOptions, FileCompressor};
+
+    #[derive(Default)]
+    struct ScanProgress {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl Progress for ScanProgress {
+        type Task = NoProgress;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+            NoProgress
+        }
+
+        fn scan_started(&self) {
+            self.events.lock().unwrap().push("started".into());
+        }
+
+        fn found_file(&self, path: &Path, size: u64) {
+            let name = path.file_name().unwrap().to_string_lossy();
+            self.events.lock().unwrap().push(format!("{name}: {size}"));
+        }
+
+        fn scan_complete(&self, total_files: u64, total_bytes: u64) {
+            self.events.lock().unwrap().push(format!(
+                "complete: {total_files} files, {total_bytes} bytes"
+            ));
+        }
+    }
+
+    #[test]
+    fn scan_progress() {
+        let dir = Fixture::new();
+        dir.file("a", [1; 1000]);
+        dir.file("b", [2; 2000]);
+        // Skipped files are not found
+        dir.file("empty", []);
+
+        let progress = ScanProgress::default();
+        let mut fc = FileCompressor::new();
+        dir.compress(&mut fc, &progress);
+
+        let mut events = progress.events.into_inner().unwrap();
+        assert_eq!(events.first().map(String::as_str), Some("started"));
+        assert_eq!(
+            events.last().map(String::as_str),
+            Some("complete: 2 files, 3000 bytes")
+        );
+        events[1..3].sort();
+        assert_eq!(
+            events,
+            [
+                "started",
+                "a: 1000",
+                "b: 2000",
+                "complete: 2 files, 3000 bytes"
+            ]
+        );
+    }
+
+    #[test]
+    fn file_order() {
+        let dir = Fixture::new();
+        fs::create_dir(dir.path().join("subdir")).unwrap();
+        let files = [
+            ("a", 3000),
+            ("subdir/b", 1000),
+            ("c", 4000),
+            ("subdir/d", 2000),
+        ];
+        for (name, size) in files {
+            dir.file(name, vec![1; size]);
+        }
+
+        let run = |file_order| {
+            let progress = ScanProgress::default();
+            let mut fc = FileCompressor::new();
+            fc.set_file_order(file_order);
+            fc.compress_with([dir.path()], &CompressionOptions::new(), &progress)
+                .unwrap();
+            let events = progress.events.into_inner().unwrap();
+            // Only the files, without the start and end of the scan
+            events[1..events.len() - 1].to_vec()
+        };
+
+        let largest_first = run(FileOrder::LargestFirst);
+        assert_eq!(
+            largest_first,
+            ["c: 4000", "a: 3000", "b: 2000", "d: 1000"].map(String::from)
+        );
+        // Every file was compressed by the first run, so start again with uncompressed copies
+        for (name, size) in files {
+            fs::remove_file(dir.path().join(name)).unwrap();
+            dir.file(name, vec![1; size]);
+        }
+        let smallest_first = run(FileOrder::SmallestFirst);
+        assert_eq!(
+            smallest_first,
+            ["d: 1000", "b: 2000", "a: 3000", "c: 4000"].map(String::from)
+        );
+    }
+
+    #[derive(Default)]
+    struct DirSkipProgress {
+        skipped_dirs: std::sync::Mutex<Vec<PathBuf>>,
+    }
+
+    impl Progress for DirSkipProgress {
+        type Task = NoProgress;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn dir_skipped(&self, path: &Path, error: io::Error) {
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+            self.skipped_dirs.lock().unwrap().push(path.to_owned());
+        }
+
+        fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+            NoProgress
+        }
+    }
+
+    #[test]
+    fn unreadable_dir_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let unreadable_dir = dir.path().join("unreadable");
+        fs::create_dir(&unreadable_dir).unwrap();
+        fs::write(unreadable_dir.join("file"), [0; 16 * 1024]).unwrap();
+        let sibling = dir.path().join("zz_sibling");
+        fs::create_dir(&sibling).unwrap();
+        fs::write(sibling.join("file"), [0; 16 * 1024]).unwrap();
+
+        fs::set_permissions(&unreadable_dir, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(&unreadable_dir).is_ok() {
+            // Running as root, permissions aren't enforced
+            fs::set_permissions(&unreadable_dir, fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let progress = DirSkipProgress::default();
+        let mut fc = FileCompressor::new();
+        let stats = dir.compress(&mut fc, &progress);
+        fs::set_permissions(&unreadable_dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(
+            progress.skipped_dirs.into_inner().unwrap(),
+            std::slice::from_ref(&unreadable_dir)
+        );
+        assert_eq!(stats.unreadable_dirs.into_inner(), 1);
+        // The rest of the tree, including later siblings, is still compressed
+        assert!(info::get(&sibling.join("file")).unwrap().is_compressed);
+        assert!(info::get(&dir.path().join("BIG")).unwrap().is_compressed);
+        assert!(
+            !info::get(&unreadable_dir.join("file"))
+                .unwrap()
+                .is_compressed
+        );
+    }
+
+    #[test]
+    fn overlapping_roots_counted_once() {
+        let compress = |paths: &[&Path]| {
+            let mut fc = FileCompressor::new();
+            let stats = fc
+                .compress_with(
+                    paths.iter().copied(),
+                    &CompressionOptions::new(),
+                    &ErrorProgress::default(),
+                )
+                .unwrap();
+            assert_eq!(stats.fatal_error, None);
+            stats
+        };
+
+        let expected_dir = Fixture::new();
+        populate_dir(expected_dir.path());
+        let expected = compress(&[expected_dir.path()]);
+
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let subdir = dir.path().join("subdir");
+        let file = dir.path().join("BIG");
+        // Nested roots are dropped whichever order they're passed in, and however they're spelled
+        let dotted = subdir.join("..").join("subdir");
+        let stats = compress(&[
+            subdir.as_path(),
+            dir.path(),
+            file.as_path(),
+            dotted.as_path(),
+            dir.path(),
+        ]);
+
+        assert_eq!(stats.files.into_inner(), expected.files.into_inner());
+        assert_eq!(
+            stats.total_file_sizes.into_inner(),
+            expected.total_file_sizes.into_inner()
+        );
+        assert_eq!(
+            stats.compressed_file_count_final.into_inner(),
+            expected.compressed_file_count_final.into_inner()
+        );
+        assert_eq!(
+            stats.compressed_size_final.into_inner(),
+            expected.compressed_size_final.into_inner()
+        );
+        assert_eq!(
+            stats.skipped.already_compressed.into_inner(),
+            expected.skipped.already_compressed.into_inner()
+        );
+    }
+
+    #[test]
+    fn backup_exclusion() {
+        const EXCLUDE_XATTR: &CStr = c"com.apple.metadata:com_apple_backup_excludeItem";
+        let exclude = |path: &Path| {
+            let path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+            xattr::set(path.as_c_str(), EXCLUDE_XATTR, b"excluded", 0).unwrap();
+        };
+        let expected =
+            |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+
+        let run = |backup_exclusion| {
+            let dir = Fixture::new();
+            let excluded_dir = dir.path().join("excluded_dir");
+            fs::create_dir_all(excluded_dir.join("nested")).unwrap();
+            for path in [
+                "file",
+                "excluded_file",
+                "excluded_dir/file",
+                "excluded_dir/nested/file",
+            ] {
+                dir.file(path, [0; 16 * 1024]);
+            }
+            exclude(&dir.path().join("excluded_file"));
+            exclude(&excluded_dir);
+
+            let mut fc = FileCompressor::new();
+            fc.set_backup_exclusion(backup_exclusion);
+            let stats = dir.compress(&mut fc, &NoProgress);
+            // The exclusion is kept on compressed files
+            let excluded_file = std::ffi::CString::new(
+                dir.path()
+                    .join("excluded_file")
+                    .as_os_str()
+                    .as_encoded_bytes(),
+            )
+            .unwrap();
+            assert!(xattr::is_present(&excluded_file, EXCLUDE_XATTR).unwrap());
+            (
+                compressed_files(dir.path()),
+                stats.skipped.excluded.into_inner(),
+            )
+        };
+
+        assert_eq!(
+            run(BackupExclusion::Ignore),
+            (
+                expected(&[
+                    "excluded_dir/file",
+                    "excluded_dir/nested/file",
+                    "excluded_file",
+                    "file",
+                ]),
+                0
+            )
+        );
+        // The excluded directory is reported once, not for each file inside it
+        assert_eq!(run(BackupExclusion::Skip), (expected(&["file"]), 2));
+        assert_eq!(
+            run(BackupExclusion::Only),
+            (
+                expected(&[
+                    "excluded_dir/file",
+                    "excluded_dir/nested/file",
+                    "excluded_file",
+                ]),
+                0
+            )
+        );
     }
 }
@@ -0,0 +1,326 @@
+//! Gathering the attributes the scan needs from an open file
+//!
+//! The scan may need both the times of a file (to restore them after replacing it) and its
+//! extended flags (to skip clones). Both are available from `fgetattrlist`, so they are fetched
+//! with a single call, rather than one call for each. Filesystems (or OS versions) which reject
+//! the combined request fall back to fetching each separately.
+
+use crate::{clones, os_error, times, TimePreservation};
+use std::ffi::c_void;
+use std::fs::File;
+use std::mem::{self, MaybeUninit};
+use std::os::fd::AsRawFd;
+use std::{io, ptr};
+
+#[derive(Debug)]
+pub(crate) struct ScanAttrs {
+    /// The times to restore after replacing the file
+    pub saved_times: io::Result<Option<times::Saved>>,
+    /// The file may share blocks with another file (only checked if requested)
+    pub may_share_blocks: bool,
+}
+
+#[repr(C, packed(4))]
+struct AttrGetBuf {
+    len: u32,
+    returned_attrs: libc::attribute_set_t,
+    create_time: libc::timespec,
+    mod_time: libc::timespec,
+    access_time: libc::timespec,
+    add_time: libc::timespec,
+    ext_flags: u64,
+}
+
+/// Get the attributes of a file found by the scan
+///
+/// Only the times needed for `time_preservation` are returned, and sharing blocks is only
+/// checked if `check_clones` is true. A failure to check for shared blocks is treated as not
+/// sharing blocks.
+pub(crate) fn get(
+    file: &File,
+    time_preservation: TimePreservation,
+    check_clones: bool,
+) -> ScanAttrs {
+    get_from(&Fs, file, time_preservation, check_clones)
+}
+
+/// The calls the attributes of a file are fetched with
+trait AttrSource {
+    /// The times and whether the file may share blocks, in a single call
+    fn combined(&self, file: &File) -> io::Result<(times::Saved, bool)>;
+    /// Whether the file may share blocks
+    fn may_share_blocks(&self, file: &File) -> io::Result<bool>;
+    /// The times needed for `time_preservation`
+    fn saved_times(
+        &self,
+        file: &File,
+        time_preservation: TimePreservation,
+    ) -> io::Result<Option<times::Saved>>;
+}
+
+/// Fetches attributes from the filesystem
+struct Fs;
+
+impl AttrSource for Fs {
+    fn combined(&self, file: &File) -> io::Result<(times::Saved, bool)> {
+        get_combined(file)
+    }
+
+    fn may_share_blocks(&self, file: &File) -> io::Result<bool> {
+        clones::may_share_blocks(file)
+    }
+
+    fn saved_times(
+        &self,
+        file: &File,
+        time_preservation: TimePreservation,
+    ) -> io::Result<Option<times::Saved>> {
+        times::save_times_for(file, time_preservation)
+    }
+}
+
+fn get_from(
+    source: &impl AttrSource,
+    file: &File,
+    time_preservation: TimePreservation,
+    check_clones: bool,
+) -> ScanAttrs {
+    let wants_times = time_preservation != TimePreservation::None;
+    if !wants_times && !check_clones {
+        return ScanAttrs {
+            saved_times: Ok(None),
+            may_share_blocks: false,
+        };
+    }
+    match source.combined(file) {
+        Ok((saved_times, may_share_blocks)) => ScanAttrs {
+            saved_times: Ok(saved_times.for_preservation(time_preservation)),
+            may_share_blocks: check_clones && may_share_blocks,
+        },
+        Err(e) => {
+            tracing::debug!("unable to get combined attributes, getting separately: {e}");
+            get_separately(source, file, time_preservation, check_clones)
+        }
+    }
+}
+
+fn get_combined(file: &File) -> io::Result<(times::Saved, bool)> {
+    // SAFETY: libc::attrlist is a POD c struct, zero is a valid value for all fields.
+    let mut attrlist: libc::attrlist = unsafe { mem::zeroed() };
+    attrlist.bitmapcount = libc::ATTR_BIT_MAP_COUNT;
+    attrlist.commonattr = libc::ATTR_CMN_RETURNED_ATTRS
+        | libc::ATTR_CMN_CRTIME
+        | libc::ATTR_CMN_MODTIME
+        | libc::ATTR_CMN_ACCTIME
+        | libc::ATTR_CMN_ADDEDTIME;
+    attrlist.forkattr = libc::ATTR_CMNEXT_EXT_FLAGS;
+
+    let mut attr_buf = MaybeUninit::<AttrGetBuf>::uninit();
+    // SAFETY: the fd is valid, attrlist and attr_buf are valid pointers, and attr_buf is large
+    // enough for the requested attributes
+    let rc = unsafe {
+        libc::fgetattrlist(
+            file.as_raw_fd(),
+            ptr::addr_of_mut!(attrlist).cast::<c_void>(),
+            attr_buf.as_mut_ptr().cast::<c_void>(),
+            mem::size_of::<AttrGetBuf>(),
+            libc::FSOPT_ATTR_CMN_EXTENDED | libc::FSOPT_PACK_INVAL_ATTRS,
+        )
+    };
+    if rc != 0 {
+        return Err(os_error::last("fgetattrlist(times, ext flags)"));
+    }
+    // SAFETY: fgetattrlist succeeded, and FSOPT_PACK_INVAL_ATTRS fills every field
+    let attr_buf = unsafe { attr_buf.assume_init() };
+    assert_eq!(attr_buf.len as usize, mem::size_of::<AttrGetBuf>());
+    let saved_times = times::Saved::new(
+        attr_buf.create_time,
+        attr_buf.mod_time,
+        attr_buf.access_time,
+        attr_buf.add_time,
+    );
+    // Extended flags which aren't reported are packed as zero, but check rather than rely on it
+    let returned_attrs = attr_buf.returned_attrs;
+    let ext_flags = attr_buf.ext_flags;
+    let may_share_blocks = returned_attrs.forkattr & libc::ATTR_CMNEXT_EXT_FLAGS != 0
+        && ext_flags & clones::EF_MAY_SHARE_BLOCKS != 0;
+    Ok((saved_times, may_share_blocks))
+}
+
+fn get_separately(
+    source: &impl AttrSource,
+    file: &File,
+    time_preservation: TimePreservation,
+    check_clones: bool,
+) -> ScanAttrs {
+    let may_share_blocks = check_clones
+        && source.may_share_blocks(file).unwrap_or_else(|e| {
+            tracing::debug!("unable to check if file shares blocks: {e}");
+            false
+        });
+    ScanAttrs {
+        saved_times: source.saved_times(file, time_preservation),
+        may_share_blocks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::populate_dir;
+    use std::cell::Cell;
+    use std::fs;
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    /// Fetches attributes from the filesystem, counting the calls made
+    #[derive(Default)]
+    struct Counting {
+        calls: Cell<u32>,
+        /// Fail the combined call, like a filesystem which doesn't support it
+        reject_combined: bool,
+    }
+
+    impl Counting {
+        fn rejecting_combined() -> Self {
+            Self {
+                reject_combined: true,
+                ..Self::default()
+            }
+        }
+
+        fn count(&self) {
+            self.calls.set(self.calls.get() + 1);
+        }
+    }
+
+    impl AttrSource for Counting {
+        fn combined(&self, file: &File) -> io::Result<(times::Saved, bool)> {
+            self.count();
+            if self.reject_combined {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            Fs.combined(file)
+        }
+
+        fn may_share_blocks(&self, file: &File) -> io::Result<bool> {
+            self.count();
+            Fs.may_share_blocks(file)
+        }
+
+        fn saved_times(
+            &self,
+            file: &File,
+            time_preservation: TimePreservation,
+        ) -> io::Result<Option<times::Saved>> {
+            if time_preservation != TimePreservation::None {
+                self.count();
+            }
+            Fs.saved_times(file, time_preservation)
+        }
+    }
+
+    #[test]
+    fn combined_matches_separate() {
+        let dir = TempDir::new().unwrap();
+        let orig = dir.path().join("orig");
+        fs::write(&orig, [1; 64 * 1024]).unwrap();
+        fs::write(dir.path().join("other"), [2; 64 * 1024]).unwrap();
+        fs::write(dir.path().join("empty"), []).unwrap();
+        let with_xattr = dir.path().join("with_xattr");
+        fs::write(&with_xattr, [3; 1024]).unwrap();
+        crate::xattr::set(
+            &File::open(&with_xattr).unwrap(),
+            c"com.example.test",
+            b"value",
+            0,
+        )
+        .unwrap();
+        // Not on APFS, there will just be no clones
+        let _ = clones::clone_file(&orig, &dir.path().join("clone"));
+
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let file = File::open(entry.unwrap().path()).unwrap();
+            for time_preservation in [
+                TimePreservation::All,
+                TimePreservation::ModificationOnly,
+                TimePreservation::None,
+            ] {
+                for check_clones in [false, true] {
+                    let combined_source = Counting::default();
+                    let combined =
+                        get_from(&combined_source, &file, time_preservation, check_clones);
+                    let separate_source = Counting::default();
+                    let separate =
+                        get_separately(&separate_source, &file, time_preservation, check_clones);
+                    assert_eq!(combined.saved_times.unwrap(), separate.saved_times.unwrap());
+                    assert_eq!(combined.may_share_blocks, separate.may_share_blocks);
+
+                    let wants_times = time_preservation != TimePreservation::None;
+                    let wanted = u32::from(wants_times) + u32::from(check_clones);
+                    assert_eq!(separate_source.calls.get(), wanted);
+                    assert_eq!(combined_source.calls.get(), wanted.min(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn forced_fallback() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, [1; 1024]).unwrap();
+        let file = File::open(&path).unwrap();
+
+        let source = Counting::rejecting_combined();
+        let attrs = get_from(&source, &file, TimePreservation::All, true);
+        // The rejected combined call, then one for each attribute
+        assert_eq!(source.calls.get(), 3);
+        assert_eq!(
+            attrs.saved_times.unwrap(),
+            Some(times::save_times(&file).unwrap())
+        );
+        assert!(!attrs.may_share_blocks);
+    }
+
+    #[test]
+    fn scan_attrs_fallback_equivalent() {
+        let dir = TempDir::new().unwrap();
+        populate_dir(dir.path());
+        let orig = dir.path().join("BIG");
+        // Not on APFS, there will just be no clones
+        let _ = clones::clone_file(&orig, &dir.path().join("BIG clone"));
+
+        // Everything the scan decides from these attributes is the same when they're fetched
+        // separately
+        let combined_source = Counting::default();
+        let separate_source = Counting::rejecting_combined();
+        let mut files = 0;
+        for entry in WalkDir::new(dir.path()) {
+            let entry = entry.unwrap();
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            files += 1;
+            let file = File::open(entry.path()).unwrap();
+            let combined = get_from(&combined_source, &file, TimePreservation::All, true);
+            let separate = get_from(&separate_source, &file, TimePreservation::All, true);
+            assert_eq!(
+                combined.saved_times.unwrap(),
+                separate.saved_times.unwrap(),
+                "{}",
+                entry.path().display()
+            );
+            assert_eq!(
+                combined.may_share_blocks,
+                separate.may_share_blocks,
+                "{}",
+                entry.path().display()
+            );
+        }
+        // Times and shared blocks are fetched together, rather than one call each (after the
+        // rejected combined call)
+        assert_eq!(combined_source.calls.get(), files);
+        assert_eq!(separate_source.calls.get(), 3 * files);
+    }
+}
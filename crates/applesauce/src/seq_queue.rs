@@ -1,6 +1,11 @@
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::{fmt, io};
 
+/// The least each item counts against the cap of a queue created by [`bounded_bytes`]
+///
+/// Keeps a queue of tiny items bounded in length, not just in bytes.
+pub const MIN_ITEM_BYTES: u64 = 4 * 1024;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct UnknownError;
 
@@ -91,33 +96,144 @@ impl<E> Drop for FinalErrorOnDrop<E> {
     }
 }
 
+#[derive(Debug)]
+struct ByteState {
+    in_flight: u64,
+    /// The receiver was dropped, nothing more will be released
+    closed: bool,
+}
+
+/// The bytes sent but not yet received on a queue created by [`bounded_bytes`]
+#[derive(Debug)]
+struct ByteLimit {
+    cap: u64,
+    state: Mutex<ByteState>,
+    released: Condvar,
+}
+
+impl ByteLimit {
+    fn new(cap: u64) -> Self {
+        Self {
+            cap,
+            state: Mutex::new(ByteState {
+                in_flight: 0,
+                closed: false,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until fewer than `cap` bytes are in flight, then add `bytes`
+    ///
+    /// Returns false if the receiver was dropped.
+    fn acquire(&self, bytes: u64) -> bool {
+        let mut state = self
+            .released
+            .wait_while(self.state.lock().unwrap(), |state| {
+                state.in_flight >= self.cap && !state.closed
+            })
+            .unwrap();
+        if state.closed {
+            return false;
+        }
+        state.in_flight += bytes;
+        true
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= bytes;
+        self.released.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.released.notify_all();
+    }
+
+    #[cfg(test)]
+    fn in_flight(&self) -> u64 {
+        self.state.lock().unwrap().in_flight
+    }
+}
+
+/// Wakes a sender waiting for bytes to be released when the receiver is dropped
+#[derive(Debug)]
+struct CloseOnDrop(Option<Arc<ByteLimit>>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        if let Some(limit) = &self.0 {
+            limit.close();
+        }
+    }
+}
+
+/// Each item is sent with the number of bytes it counts against the queue's cap
+type Item<T> = (oneshot::Receiver<T>, u64);
+
 #[derive(Debug)]
 pub struct Sender<T, E>(
-    crossbeam_channel::Sender<oneshot::Receiver<T>>,
+    crossbeam_channel::Sender<Item<T>>,
     FinalSuccess<E>,
+    Option<Arc<ByteLimit>>,
 );
 
 #[derive(Debug)]
 pub struct Receiver<T, E>(
-    crossbeam_channel::Receiver<oneshot::Receiver<T>>,
+    crossbeam_channel::Receiver<Item<T>>,
     FinalSuccess<E>,
+    CloseOnDrop,
 );
 
 pub struct Slot<T, E>(oneshot::Sender<T>, FinalErrorOnDrop<E>);
 
+/// A queue holding at most `cap` items which have been sent but not received
+// The reader bounds its queues by bytes
+#[cfg_attr(not(test), allow(dead_code))]
 pub fn bounded<T, E>(cap: usize) -> (Sender<T, E>, Receiver<T, E>) {
     let final_success = FinalSuccess::new();
     let (tx, rx) = crossbeam_channel::bounded(cap);
     (
-        Sender(tx, final_success.clone()),
-        Receiver(rx, final_success),
+        Sender(tx, final_success.clone(), None),
+        Receiver(rx, final_success, CloseOnDrop(None)),
+    )
+}
+
+/// A queue holding about `max_bytes` of items which have been sent but not received
+///
+/// Each item counts the size passed to [`Sender::prepare_send`] (at least [`MIN_ITEM_BYTES`])
+/// from when its slot is prepared until it is received. Preparing a slot waits while `max_bytes`
+/// or more are in flight, so the queue can go over the cap by one item, but an item larger than
+/// the cap can always be sent.
+pub fn bounded_bytes<T, E>(max_bytes: u64) -> (Sender<T, E>, Receiver<T, E>) {
+    let final_success = FinalSuccess::new();
+    let limit = Arc::new(ByteLimit::new(max_bytes));
+    let (tx, rx) = crossbeam_channel::unbounded();
+    (
+        Sender(tx, final_success.clone(), Some(Arc::clone(&limit))),
+        Receiver(rx, final_success, CloseOnDrop(Some(limit))),
     )
 }
 
 impl<T, E> Sender<T, E> {
-    pub fn prepare_send(&self) -> Option<Slot<T, E>> {
+    /// Reserve the next place in the queue, for an item of about `bytes`
+    ///
+    /// `bytes` is only used by queues created by [`bounded_bytes`]. Returns `None` if the receiver
+    /// was dropped.
+    pub fn prepare_send(&self, bytes: u64) -> Option<Slot<T, E>> {
+        let bytes = match &self.2 {
+            Some(limit) => {
+                let bytes = bytes.max(MIN_ITEM_BYTES);
+                if !limit.acquire(bytes) {
+                    return None;
+                }
+                bytes
+            }
+            None => 0,
+        };
         let (tx, rx) = oneshot::channel();
-        self.0.send(rx).ok()?;
+        self.0.send((rx, bytes)).ok()?;
         Some(Slot(tx, FinalErrorOnDrop(Some(self.1.clone()))))
     }
 
@@ -163,19 +279,23 @@ impl<T, E> Receiver<T, E> {
     }
 
     pub fn recv(&self) -> Result<T, RecvError> {
-        let inner_chan = self.0.recv().map_err(|_| RecvError::Finished)?;
-        inner_chan.recv().map_err(|_| RecvError::ItemRecvError)
+        let (inner_chan, bytes) = self.0.recv().map_err(|_| RecvError::Finished)?;
+        let result = inner_chan.recv().map_err(|_| RecvError::ItemRecvError);
+        if let Some(limit) = &self.2 .0 {
+            limit.release(bytes);
+        }
+        result
     }
 
     pub fn finish(self) -> Result<(), Option<E>> {
-        let Self(receiver, final_success) = self;
+        let Self(receiver, final_success, close_on_drop) = self;
         if receiver.recv().is_ok() {
             tracing::error!("finish on seq queue received an item");
             return Err(None);
         }
         // Make sure to drop the receiver, to make sure the sender won't block trying to send
         // anything
-        drop(receiver);
+        drop((receiver, close_on_drop));
         final_success.get_result()
     }
 }
@@ -215,9 +335,9 @@ mod tests {
     fn order_after_sending() {
         let (tx, rx) = bounded::<u8, ()>(2);
 
-        let first = tx.prepare_send().unwrap();
+        let first = tx.prepare_send(1).unwrap();
         assert_eq!(rx.0.len(), 1);
-        let second = tx.prepare_send().unwrap();
+        let second = tx.prepare_send(1).unwrap();
         assert_eq!(rx.0.len(), 2);
         tx.finish(Ok(()));
 
@@ -236,7 +356,7 @@ mod tests {
     fn no_success_becomes_err() {
         let (tx, rx) = bounded::<u8, ()>(2);
 
-        let first = tx.prepare_send().unwrap();
+        let first = tx.prepare_send(1).unwrap();
         first.finish(1).unwrap();
         drop(tx);
 
@@ -249,7 +369,7 @@ mod tests {
     fn unfinished_send_becomes_err() {
         let (tx, rx) = bounded::<u8, &str>(2);
 
-        let first = tx.prepare_send().unwrap();
+        let first = tx.prepare_send(1).unwrap();
         drop(first);
         tx.finish(Ok(()));
 
@@ -261,7 +381,7 @@ mod tests {
     fn explicit_send_err() {
         let (tx, rx) = bounded::<u8, &str>(2);
 
-        let first = tx.prepare_send().unwrap();
+        let first = tx.prepare_send(1).unwrap();
         first.finish(1).unwrap();
         tx.finish(Err("error"));
 
@@ -277,7 +397,127 @@ mod tests {
         let sender_handle = std::thread::spawn(move || {
             let tx = tx;
             for i in 0..1000 {
-                let slot = tx.prepare_send().unwrap();
+                let slot = tx.prepare_send(1).unwrap();
+                std::thread::spawn(move || {
+                    // slow down some finishes
+                    if i % 3 == 0 {
+                        std::thread::sleep(Duration::from_micros(10));
+                    }
+                    slot.finish(i).unwrap();
+                });
+            }
+            tx.finish(Ok(()));
+        });
+
+        for i in 0..1000 {
+            assert_eq!(rx.recv().unwrap(), i);
+        }
+        assert_eq!(rx.recv().unwrap_err(), RecvError::Finished);
+        assert_eq!(rx.finish(), Ok(()));
+
+        sender_handle.join().unwrap();
+    }
+
+    #[test]
+    fn bytes_order_after_sending() {
+        let (tx, rx) = bounded_bytes::<u8, ()>(1024 * 1024);
+
+        let first = tx.prepare_send(10).unwrap();
+        let second = tx.prepare_send(10).unwrap();
+        tx.finish(Ok(()));
+
+        second.finish(2).unwrap();
+        first.finish(1).unwrap();
+
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(rx.recv().unwrap_err(), RecvError::Finished);
+        assert_eq!(rx.finish(), Ok(()));
+    }
+
+    #[test]
+    fn bytes_unfinished_send_becomes_err() {
+        let (tx, rx) = bounded_bytes::<u8, &str>(1024 * 1024);
+
+        let first = tx.prepare_send(10).unwrap();
+        drop(first);
+        tx.finish(Ok(()));
+
+        assert_eq!(rx.recv().unwrap_err(), RecvError::ItemRecvError);
+        // A dropped item is still released
+        assert_eq!(rx.2 .0.as_ref().unwrap().in_flight(), 0);
+        assert_eq!(rx.finish(), Err(None));
+    }
+
+    #[test]
+    fn bytes_accounting() {
+        let (tx, rx) = bounded_bytes::<u8, ()>(64 * 1024);
+        let limit = Arc::clone(rx.2 .0.as_ref().unwrap());
+
+        let first = tx.prepare_send(40 * 1024).unwrap();
+        assert_eq!(limit.in_flight(), 40 * 1024);
+        // Tiny items count as MIN_ITEM_BYTES
+        let second = tx.prepare_send(1).unwrap();
+        assert_eq!(limit.in_flight(), 40 * 1024 + MIN_ITEM_BYTES);
+        // Under the cap, so a third item goes over it
+        let third = tx.prepare_send(40 * 1024).unwrap();
+        assert_eq!(limit.in_flight(), 80 * 1024 + MIN_ITEM_BYTES);
+
+        // Finishing a slot doesn't release it, receiving it does
+        first.finish(1).unwrap();
+        second.finish(2).unwrap();
+        third.finish(3).unwrap();
+        assert_eq!(limit.in_flight(), 80 * 1024 + MIN_ITEM_BYTES);
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(limit.in_flight(), 40 * 1024 + MIN_ITEM_BYTES);
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(rx.recv().unwrap(), 3);
+        assert_eq!(limit.in_flight(), 0);
+    }
+
+    #[test]
+    fn bytes_cap_blocks_sender() {
+        let (tx, rx) = bounded_bytes::<u32, ()>(64 * 1024);
+        let first = tx.prepare_send(64 * 1024).unwrap();
+        first.finish(1).unwrap();
+
+        let (sent_tx, sent_rx) = crossbeam_channel::bounded(1);
+        let sender_handle = std::thread::spawn(move || {
+            let slot = tx.prepare_send(64 * 1024).unwrap();
+            sent_tx.send(()).unwrap();
+            slot.finish(2).unwrap();
+            tx.finish(Ok(()));
+        });
+
+        // Waits for the first item to be received
+        assert!(sent_rx.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(rx.recv().unwrap(), 1);
+        sent_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(rx.recv().unwrap(), 2);
+        assert_eq!(rx.finish(), Ok(()));
+
+        sender_handle.join().unwrap();
+    }
+
+    #[test]
+    fn bytes_dropped_receiver_wakes_sender() {
+        let (tx, rx) = bounded_bytes::<u32, ()>(64 * 1024);
+        let _first = tx.prepare_send(64 * 1024).unwrap();
+
+        let sender_handle = std::thread::spawn(move || tx.prepare_send(64 * 1024).is_none());
+        std::thread::sleep(Duration::from_millis(10));
+        drop(rx);
+        assert!(sender_handle.join().unwrap());
+    }
+
+    #[test]
+    fn bytes_across_threads() {
+        let (tx, rx) = bounded_bytes::<u32, ()>(16 * 1024);
+
+        let sender_handle = std::thread::spawn(move || {
+            let tx = tx;
+            for i in 0..1000 {
+                let slot = tx.prepare_send(u64::from(i % 7) * 1024).unwrap();
                 std::thread::spawn(move || {
                     // slow down some finishes
                     if i % 3 == 0 {
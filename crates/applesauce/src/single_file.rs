@@ -0,0 +1,787 @@
+//! Compression and decompression of a single file, on the calling thread
+//!
+//! This avoids starting the background threads used by [`FileCompressor`](crate::FileCompressor),
+//! which is useful when only a handful of files need to be processed.
+
+use crate::info::{self, FileCompressionState, IncompressibleReason};
+use crate::threads::reader::ensure_unchanged;
+use crate::threads::writer::{
+    copy_metadata, copy_xattrs, ensure_checksum_matches, ensure_compressed_structure,
+    ensure_identical_files, ensure_written_intact, preallocate, set_decmpfs_xattr, Checksum,
+    UNCOMPRESSED_BUF_SIZE,
+};
+use crate::tmpdir_paths::{TmpFile, TmpdirPaths};
+use crate::{protected, rebalance, set_flags, times, try_read_all, xattr, Verify};
+use applesauce_core::check::{check_structure, Written};
+use applesauce_core::compressor::{Kind, Pool};
+use applesauce_core::reader::Reader;
+use applesauce_core::{decmpfs, BLOCK_SIZE};
+use resource_fork::ResourceFork;
+use sha2::{Digest, Sha256};
+use std::fs::{File, Metadata};
+use std::io::{self, BufReader, BufWriter, Seek, Write};
+use std::os::macos::fs::MetadataExt as _;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::Path;
+use std::time::SystemTime;
+use std::{error, fmt};
+
+/// The result of [`compress_file`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompressOutcome {
+    /// The space used by the file on disk before compression
+    pub on_disk_size_before: u64,
+    /// The space used by the file on disk after compression
+    pub on_disk_size_after: u64,
+    /// True if the file was compressed
+    ///
+    /// False if the file was already compressed, was empty, or did not compress to the minimum
+    /// compression ratio.
+    pub compressed: bool,
+}
+
+/// The result of [`decompress_file`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DecompressOutcome {
+    /// The space used by the file on disk before decompression
+    pub on_disk_size_before: u64,
+    /// The space used by the file on disk after decompression
+    pub on_disk_size_after: u64,
+    /// True if the file was decompressed, false if it was not compressed
+    pub decompressed: bool,
+}
+
+/// Why a file can't be compressed, returned by [`can_compress`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WhyNot {
+    /// The file is already compressed
+    AlreadyCompressed,
+    /// The path isn't a regular file
+    NotFile,
+    /// The file has other hard links
+    ///
+    /// Compressing a file replaces it, the other links would keep referring to the uncompressed
+    /// file, and the space it uses would not be freed.
+    HardLink,
+    /// The file is protected by System Integrity Protection
+    SipProtected,
+    Empty,
+    /// The file is at least [`MAX_COMPRESSIBLE_SIZE`](applesauce_core::MAX_COMPRESSIBLE_SIZE)
+    /// bytes
+    TooLarge {
+        size: u64,
+        limit: u64,
+    },
+    FsNotSupported,
+    HasRequiredXattr,
+    /// The file's contents are not stored locally, reading it would download it
+    Dataless,
+    IoError(io::Error),
+}
+
+impl From<IncompressibleReason> for WhyNot {
+    fn from(reason: IncompressibleReason) -> Self {
+        match reason {
+            IncompressibleReason::Empty => Self::Empty,
+            IncompressibleReason::TooLarge { size, limit } => Self::TooLarge { size, limit },
+            IncompressibleReason::IoError(e) => Self::IoError(e),
+            IncompressibleReason::FsNotSupported => Self::FsNotSupported,
+            IncompressibleReason::HasRequiredXattr => Self::HasRequiredXattr,
+            IncompressibleReason::Dataless => Self::Dataless,
+        }
+    }
+}
+
+impl fmt::Display for WhyNot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WhyNot::AlreadyCompressed => write!(f, "file is already compressed"),
+            WhyNot::NotFile => write!(f, "not a regular file"),
+            WhyNot::HardLink => write!(f, "file has multiple hard links"),
+            WhyNot::SipProtected => write!(f, "protected by System Integrity Protection"),
+            WhyNot::Empty => write!(f, "empty file"),
+            &WhyNot::TooLarge { size, limit } => {
+                IncompressibleReason::TooLarge { size, limit }.fmt(f)
+            }
+            WhyNot::FsNotSupported => IncompressibleReason::FsNotSupported.fmt(f),
+            WhyNot::HasRequiredXattr => IncompressibleReason::HasRequiredXattr.fmt(f),
+            WhyNot::Dataless => IncompressibleReason::Dataless.fmt(f),
+            WhyNot::IoError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for WhyNot {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            WhyNot::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether compressing the file at `path` could save any space
+///
+/// This only looks at the file's metadata, and the capabilities of the volume it is on (which are
+/// cached for the life of the process): it doesn't read the file, so it can't tell whether its
+/// contents compress well. A file which can be compressed may still be left unchanged by
+/// [`compress_file`], if it doesn't compress enough.
+pub fn can_compress(path: &Path) -> Result<(), WhyNot> {
+    let metadata = path.symlink_metadata().map_err(WhyNot::IoError)?;
+    if !metadata.is_file() {
+        return Err(WhyNot::NotFile);
+    }
+    if protected::is_sip_protected(&metadata) {
+        return Err(WhyNot::SipProtected);
+    }
+    match info::get_compression_state(path, &metadata) {
+        FileCompressionState::Compressed => Err(WhyNot::AlreadyCompressed),
+        FileCompressionState::Incompressible(reason) => Err(reason.into()),
+        FileCompressionState::Compressible if metadata.nlink() > 1 => Err(WhyNot::HardLink),
+        FileCompressionState::Compressible => Ok(()),
+    }
+}
+
+/// Compress a single file in place, on the calling thread
+///
+/// Files which are already compressed, empty, or which do not compress to at least
+/// `minimum_compression_ratio` of their original size are left unchanged, and are not an error.
+pub fn compress_file(
+    path: &Path,
+    kind: Kind,
+    level: u32,
+    minimum_compression_ratio: f64,
+    verify: Verify,
+) -> io::Result<CompressOutcome> {
+    let metadata = regular_file_metadata(path)?;
+    let on_disk_size_before = info::on_disk_size(&metadata);
+    let unchanged = CompressOutcome {
+        on_disk_size_before,
+        on_disk_size_after: on_disk_size_before,
+        compressed: false,
+    };
+
+    match info::get_compression_state(path, &metadata) {
+        FileCompressionState::Compressed
+        | FileCompressionState::Incompressible(IncompressibleReason::Empty) => {
+            return Ok(unchanged)
+        }
+        FileCompressionState::Compressible => {}
+        FileCompressionState::Incompressible(IncompressibleReason::IoError(e)) => return Err(e),
+        FileCompressionState::Incompressible(reason) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} cannot be compressed: {reason}", path.display()),
+            ));
+        }
+    }
+    if !kind.supported() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{kind} compression is not supported"),
+        ));
+    }
+
+    let saved_times = times::save_times(path)?;
+    let parent_resetter = parent_resetter(path);
+    let mut file = File::open(path)?;
+    let mut tmp_file = tmp_file_for(path, &metadata)?;
+    copy_xattrs(&file, tmp_file.as_file())?;
+
+    let uncompressed_size = metadata.len();
+    let max_compressed_size = (uncompressed_size as f64 * minimum_compression_ratio) as u64;
+    let mut hasher = (verify == Verify::Checksum).then(Sha256::new);
+    let mut decmpfs_data = Vec::new();
+    let mut written = Written::default();
+    {
+        let mut writer = applesauce_core::writer::Writer::new(kind, uncompressed_size, || {
+            BufWriter::new(ResourceFork::new(tmp_file.as_file()))
+        })?;
+
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut compressed = vec![0; BLOCK_SIZE + 1024];
+        let mut total_read = 0;
+        while total_read < uncompressed_size {
+            let block_len = (uncompressed_size - total_read).min(BLOCK_SIZE as u64) as usize;
+            let n = try_read_all(&file, &mut block[..block_len])?;
+            if n == 0 {
+                break;
+            }
+            total_read += n as u64;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&block[..n]);
+            }
+
+            let compressed_len = Pool::with(kind, |compressor| {
+                compressor.compress(&mut compressed, &block[..n], level)
+            })?;
+            written.block_count += 1;
+            written.compressed_size += compressed_len as u64;
+            if written.compressed_size > max_compressed_size {
+                return Ok(unchanged);
+            }
+            writer.add_block(&compressed[..compressed_len])?;
+        }
+        if total_read != uncompressed_size {
+            return Err(io::Error::other("file size changed while reading"));
+        }
+        ensure_unchanged(&file, &metadata)?;
+        writer.finish_decmpfs_data(&mut decmpfs_data)?;
+    }
+    set_decmpfs_xattr(tmp_file.as_file(), &decmpfs_data)?;
+    ensure_written_intact(
+        tmp_file.as_file(),
+        &decmpfs_data,
+        uncompressed_size,
+        written,
+    )
+    .map_err(|e| {
+        io::Error::other(format!(
+            "self-check failed: {e}, {} unchanged",
+            path.display()
+        ))
+    })?;
+
+    // The data fork can only be seen before the file is marked compressed
+    let data_fork_len = if verify == Verify::Quick {
+        tmp_file.as_file().metadata()?.len()
+    } else {
+        0
+    };
+    copy_metadata(&file, tmp_file.as_file())?;
+    set_flags(
+        tmp_file.as_file(),
+        metadata.st_flags() | libc::UF_COMPRESSED,
+    )?;
+
+    match verify {
+        Verify::Off => {}
+        Verify::Quick => {
+            ensure_compressed_structure(
+                tmp_file.as_file(),
+                &decmpfs_data,
+                uncompressed_size,
+                data_fork_len,
+            )
+            .map_err(|e| verification_failed(path, e))?;
+        }
+        Verify::Checksum => {
+            let expected: Checksum = hasher.take().unwrap().finalize().into();
+            ensure_checksum_matches(tmp_file.as_file(), &expected)
+                .map_err(|e| verification_failed(path, e))?;
+        }
+        Verify::Full => {
+            ensure_same_contents(&mut file, tmp_file.as_file_mut())
+                .map_err(|e| verification_failed(path, e))?;
+        }
+    }
+    drop(file);
+
+    if let Err(e) = rebalance::set_compressed_at(tmp_file.as_file(), SystemTime::now()) {
+        tracing::warn!("Unable to record compression time: {e}");
+    }
+    let new_file = tmp_file.persist(path)?;
+    finish_replace(&new_file, &saved_times, parent_resetter.as_ref());
+    Ok(CompressOutcome {
+        on_disk_size_before,
+        on_disk_size_after: info::on_disk_size(&new_file.metadata()?),
+        compressed: true,
+    })
+}
+
+/// Decompress a single file in place, on the calling thread
+///
+/// The file is decompressed by reading it, letting the OS do the decompression. Files which are
+/// not compressed are left unchanged, and are not an error.
+pub fn decompress_file(path: &Path, verify: Verify) -> io::Result<DecompressOutcome> {
+    let metadata = regular_file_metadata(path)?;
+    let on_disk_size_before = info::on_disk_size(&metadata);
+    if metadata.st_flags() & libc::UF_COMPRESSED == 0 {
+        return Ok(DecompressOutcome {
+            on_disk_size_before,
+            on_disk_size_after: on_disk_size_before,
+            decompressed: false,
+        });
+    }
+
+    let saved_times = times::save_times(path)?;
+    let parent_resetter = parent_resetter(path);
+    let mut file = File::open(path)?;
+    let mut tmp_file = tmp_file_for(path, &metadata)?;
+    copy_xattrs(&file, tmp_file.as_file())?;
+    if let Err(e) = rebalance::clear_compressed_at(tmp_file.as_file()) {
+        tracing::warn!("Unable to remove compression time: {e}");
+    }
+
+    if let Err(e) = preallocate(tmp_file.as_file(), metadata.len()) {
+        tracing::debug!("unable to preallocate {} bytes: {e}", metadata.len());
+    }
+    let written = {
+        let mut writer = BufWriter::with_capacity(UNCOMPRESSED_BUF_SIZE, tmp_file.as_file());
+        let written = io::copy(&mut file, &mut writer)?;
+        writer.flush()?;
+        written
+    };
+    if written != metadata.len() {
+        return Err(io::Error::other("file size changed while reading"));
+    }
+    ensure_unchanged(&file, &metadata)?;
+    tmp_file.as_file().set_len(written)?;
+
+    copy_metadata(&file, tmp_file.as_file())?;
+    set_flags(
+        tmp_file.as_file(),
+        metadata.st_flags() & !libc::UF_COMPRESSED,
+    )?;
+
+    if matches!(verify, Verify::Checksum | Verify::Full) {
+        ensure_same_contents(&mut file, tmp_file.as_file_mut())
+            .map_err(|e| verification_failed(path, e))?;
+    }
+    drop(file);
+
+    let new_file = tmp_file.persist(path)?;
+    finish_replace(&new_file, &saved_times, parent_resetter.as_ref());
+    Ok(DecompressOutcome {
+        on_disk_size_before,
+        on_disk_size_after: info::on_disk_size(&new_file.metadata()?),
+        decompressed: true,
+    })
+}
+
+/// Whether a file is missing its compressed flag, found by [`check_compressed_flag`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CompressedFlagState {
+    /// The file is marked compressed, there's nothing to repair
+    Marked,
+    /// The file has no decmpfs xattr, it isn't a compressed file
+    NotCompressed,
+    /// The file's compressed data is intact, but the file isn't marked compressed, so it reads as
+    /// empty
+    ///
+    /// Marking it compressed again restores its contents.
+    Stripped {
+        /// The size of the file's contents, once it is marked compressed again
+        uncompressed_size: u64,
+    },
+    /// The file has a decmpfs xattr, but its data fork isn't empty
+    ///
+    /// The data fork may hold the real contents of the file (e.g. written by a tool which ignored
+    /// the compressed data), so marking the file compressed could hide it.
+    DataForkNotEmpty {
+        /// The length of the data fork
+        data_fork_len: u64,
+    },
+    /// The file has a decmpfs xattr, but its compressed data is damaged
+    Inconsistent(io::Error),
+}
+
+/// Check whether a file has intact compressed data, but isn't marked compressed
+///
+/// Some tools (e.g. sync tools, or `chflags 0`) clear the `UF_COMPRESSED` flag of a file, but
+/// leave its decmpfs xattr and resource fork. The OS then treats it as an uncompressed file,
+/// which reads as empty. The compressed data is checked the same way as a newly compressed file,
+/// and the first block is decompressed.
+pub fn check_compressed_flag(path: &Path) -> io::Result<CompressedFlagState> {
+    regular_file_metadata(path)?;
+    let file = File::open(path)?;
+    compressed_flag_state(&file, &file.metadata()?)
+}
+
+/// Mark a file compressed again, if it's missing only its compressed flag
+///
+/// The file is only changed if [`check_compressed_flag`] would return
+/// [`CompressedFlagState::Stripped`], every other state is returned without changing the file.
+/// The file's times are kept.
+pub fn repair_compressed_flag(path: &Path) -> io::Result<CompressedFlagState> {
+    regular_file_metadata(path)?;
+    let saved_times = times::save_times(path)?;
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let state = compressed_flag_state(&file, &metadata)?;
+    let CompressedFlagState::Stripped { uncompressed_size } = state else {
+        return Ok(state);
+    };
+
+    set_flags(&file, metadata.st_flags() | libc::UF_COMPRESSED)?;
+    // The OS reports the size of a compressed file's contents
+    let len = file.metadata()?.len();
+    if len != uncompressed_size {
+        set_flags(&file, metadata.st_flags())?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has size {len} once marked compressed, expected {uncompressed_size}, \
+                 left unchanged",
+                path.display()
+            ),
+        ));
+    }
+    if let Err(e) = times::reset_times(&file, &saved_times) {
+        tracing::error!("Unable to reset times: {e}");
+    }
+    Ok(state)
+}
+
+/// Clear the compressed flag of a file which is marked compressed, but has no decmpfs xattr
+///
+/// Without a decmpfs xattr, the OS can't find the compressed data, and the file reads as empty
+/// (see [`Inconsistency::MissingDecmpfs`](crate::info::Inconsistency::MissingDecmpfs)). Once the
+/// flag is cleared, the file reads as the data actually stored in its data fork. The file's
+/// times are kept.
+///
+/// Returns false without changing the file if it isn't marked compressed. Returns an error
+/// without changing the file if it has a decmpfs xattr: its compressed data may be intact.
+pub fn clear_orphaned_compressed_flag(path: &Path) -> io::Result<bool> {
+    regular_file_metadata(path)?;
+    let saved_times = times::save_times(path)?;
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if metadata.st_flags() & libc::UF_COMPRESSED == 0 {
+        return Ok(false);
+    }
+    if xattr::is_present(&file, decmpfs::XATTR_NAME)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} has a decmpfs xattr, not clearing its compressed flag",
+                path.display()
+            ),
+        ));
+    }
+
+    set_flags(&file, metadata.st_flags() & !libc::UF_COMPRESSED)?;
+    if let Err(e) = times::reset_times(&file, &saved_times) {
+        tracing::error!("Unable to reset times: {e}");
+    }
+    Ok(true)
+}
+
+fn compressed_flag_state(file: &File, metadata: &Metadata) -> io::Result<CompressedFlagState> {
+    if metadata.st_flags() & libc::UF_COMPRESSED != 0 {
+        return Ok(CompressedFlagState::Marked);
+    }
+    let Some(decmpfs_data) = xattr::read(file, decmpfs::XATTR_NAME)? else {
+        return Ok(CompressedFlagState::NotCompressed);
+    };
+    // Without the flag, the size is the size of the data fork
+    if metadata.len() != 0 {
+        return Ok(CompressedFlagState::DataForkNotEmpty {
+            data_fork_len: metadata.len(),
+        });
+    }
+    Ok(match ensure_compressed_data_intact(file, &decmpfs_data) {
+        Ok(uncompressed_size) => CompressedFlagState::Stripped { uncompressed_size },
+        Err(e) => CompressedFlagState::Inconsistent(e),
+    })
+}
+
+/// Check the structure of compressed data, and that its first block decompresses to a full block
+///
+/// Returns the size of the data once decompressed.
+fn ensure_compressed_data_intact(file: &File, decmpfs_data: &[u8]) -> io::Result<u64> {
+    let uncompressed_size = decmpfs::Value::from_data(decmpfs_data)?.uncompressed_size;
+    check_structure(decmpfs_data, uncompressed_size, || ResourceFork::new(file))?;
+
+    let mut reader = Reader::new(decmpfs_data, || ResourceFork::new(file))?;
+    // Reader::new only accepts built-in kinds
+    let kind = reader
+        .compression_kind()
+        .ok_or(io::ErrorKind::Unsupported)?;
+    let mut block = Vec::new();
+    if reader.read_block_into(&mut block)? {
+        // Need an extra byte, because lzfse/lzvn needs at least one extra byte to differentiate
+        // between finishing on the last byte and running out of space
+        let mut decompressed = vec![0; BLOCK_SIZE + 1];
+        let len = Pool::with(kind, |compressor| {
+            compressor.decompress(&mut decompressed, &block)
+        })?;
+        let expected_len = uncompressed_size.min(BLOCK_SIZE as u64);
+        if len as u64 != expected_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("first block decompressed to {len} bytes, expected {expected_len}"),
+            ));
+        }
+    }
+    Ok(uncompressed_size)
+}
+
+fn regular_file_metadata(path: &Path) -> io::Result<Metadata> {
+    let metadata = path.symlink_metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a regular file", path.display()),
+        ));
+    }
+    if protected::is_sip_protected(&metadata) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is protected by System Integrity Protection",
+                path.display()
+            ),
+        ));
+    }
+    if info::is_dataless(&metadata) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} is not stored locally, reading it would download it",
+                path.display()
+            ),
+        ));
+    }
+    Ok(metadata)
+}
+
+fn tmp_file_for(path: &Path, metadata: &Metadata) -> io::Result<TmpFile> {
+    let mut tmpdirs = TmpdirPaths::new();
+    tmpdirs.add_dst(path, metadata)?;
+    tmpdirs.tempfile_for(path, metadata)
+}
+
+/// Save the times of the parent directory, so they can be restored after replacing the file
+fn parent_resetter(path: &Path) -> Option<times::Resetter> {
+    let parent = path.parent()?;
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    times::save_times(parent)
+        .and_then(|saved_times| times::Resetter::new(parent, saved_times, None, None))
+        .ok()
+}
+
+fn finish_replace(
+    new_file: &File,
+    saved_times: &times::Saved,
+    parent_resetter: Option<&times::Resetter>,
+) {
+    if let Some(resetter) = parent_resetter {
+        resetter.activate();
+    }
+    if let Err(e) = times::reset_times(new_file, saved_times) {
+        tracing::error!("Unable to reset times: {e}");
+    }
+}
+
+fn ensure_same_contents(orig_file: &mut File, new_file: &mut File) -> io::Result<()> {
+    orig_file.rewind()?;
+    new_file.rewind()?;
+    ensure_identical_files(BufReader::new(orig_file), BufReader::new(new_file))
+}
+
+fn verification_failed(path: &Path, e: io::Error) -> io::Error {
+    io::Error::other(format!(
+        "verification failed: {e}, {} unchanged",
+        path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{assert_entries_equal, recursive_read, Fixture};
+    use std::fs;
+
+    #[test]
+    fn single_file_round_trip() {
+        let dir = Fixture::new();
+        let path = dir.file("file", [0; 128 * 1024]);
+        let orig_contents = recursive_read(dir.path());
+
+        for verify in [Verify::Off, Verify::Quick, Verify::Checksum, Verify::Full] {
+            let outcome = compress_file(&path, Kind::default(), 5, 1.0, verify).unwrap();
+            assert!(outcome.compressed);
+            assert!(outcome.on_disk_size_after < outcome.on_disk_size_before);
+            assert!(info::get(&path).unwrap().is_compressed);
+            assert_entries_equal(&orig_contents, &recursive_read(dir.path()));
+
+            // Compressing again does nothing
+            let outcome = compress_file(&path, Kind::default(), 5, 1.0, verify).unwrap();
+            assert!(!outcome.compressed);
+            assert_eq!(outcome.on_disk_size_after, outcome.on_disk_size_before);
+
+            let outcome = decompress_file(&path, verify).unwrap();
+            assert!(outcome.decompressed);
+            assert!(outcome.on_disk_size_after > outcome.on_disk_size_before);
+            assert!(!info::get(&path).unwrap().is_compressed);
+            assert_entries_equal(&orig_contents, &recursive_read(dir.path()));
+
+            // Decompressing again does nothing
+            let outcome = decompress_file(&path, verify).unwrap();
+            assert!(!outcome.decompressed);
+            assert_eq!(outcome.on_disk_size_after, outcome.on_disk_size_before);
+        }
+    }
+
+    #[test]
+    fn single_file_not_compressible_enough() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        // Hashes don't compress
+        let content: Vec<u8> = (0u32..4096)
+            .flat_map(|i| Sha256::digest(i.to_le_bytes()))
+            .collect();
+        fs::write(file.path(), &content).unwrap();
+
+        let outcome = compress_file(file.path(), Kind::default(), 5, 0.5, Verify::Off).unwrap();
+        assert!(!outcome.compressed);
+        assert!(!info::get(file.path()).unwrap().is_compressed);
+        assert_eq!(fs::read(file.path()).unwrap(), content);
+    }
+
+    fn clear_compressed_flag(path: &Path) {
+        let file = File::open(path).unwrap();
+        set_flags(
+            &file,
+            file.metadata().unwrap().st_flags() & !libc::UF_COMPRESSED,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn can_compress_reasons() {
+        let dir = Fixture::new();
+        let path = dir.file("file", "hello world\n".repeat(1024));
+        can_compress(&path).unwrap();
+
+        assert!(matches!(can_compress(dir.path()), Err(WhyNot::NotFile)));
+        assert!(matches!(
+            can_compress(&dir.path().join("missing")),
+            Err(WhyNot::IoError(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+        let empty = dir.file("empty", "");
+        assert!(matches!(can_compress(&empty), Err(WhyNot::Empty)));
+
+        let link = dir.path().join("link");
+        fs::hard_link(&path, &link).unwrap();
+        assert!(matches!(can_compress(&path), Err(WhyNot::HardLink)));
+        fs::remove_file(&link).unwrap();
+
+        compress_file(&path, Kind::default(), 5, 1.0, Verify::Off).unwrap();
+        assert!(matches!(
+            can_compress(&path),
+            Err(WhyNot::AlreadyCompressed)
+        ));
+    }
+
+    #[test]
+    fn repair_stripped_compressed_flag() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let content: String = (0..)
+            .map(|i| format!("line {i}\n"))
+            .take(32 * 1024)
+            .collect();
+        fs::write(file.path(), &content).unwrap();
+        assert!(
+            compress_file(file.path(), Kind::default(), 5, 1.0, Verify::Off)
+                .unwrap()
+                .compressed
+        );
+        assert!(matches!(
+            check_compressed_flag(file.path()).unwrap(),
+            CompressedFlagState::Marked
+        ));
+        let modified = file.path().metadata().unwrap().modified().unwrap();
+
+        clear_compressed_flag(file.path());
+        assert!(fs::read(file.path()).unwrap().is_empty());
+        assert!(matches!(
+            check_compressed_flag(file.path()).unwrap(),
+            CompressedFlagState::Stripped { uncompressed_size } if uncompressed_size == content.len() as u64
+        ));
+
+        let state = repair_compressed_flag(file.path()).unwrap();
+        assert!(matches!(state, CompressedFlagState::Stripped { .. }));
+        assert!(info::get(file.path()).unwrap().is_compressed);
+        assert_eq!(fs::read(file.path()).unwrap(), content.as_bytes());
+        assert_eq!(
+            file.path().metadata().unwrap().modified().unwrap(),
+            modified
+        );
+
+        // Nothing left to repair
+        assert!(matches!(
+            repair_compressed_flag(file.path()).unwrap(),
+            CompressedFlagState::Marked
+        ));
+    }
+
+    #[test]
+    fn repair_refuses_non_empty_data_fork() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), [1; 64 * 1024]).unwrap();
+        compress_file(file.path(), Kind::default(), 5, 1.0, Verify::Off).unwrap();
+        clear_compressed_flag(file.path());
+        fs::write(file.path(), b"new contents").unwrap();
+
+        assert!(matches!(
+            repair_compressed_flag(file.path()).unwrap(),
+            CompressedFlagState::DataForkNotEmpty { data_fork_len: 12 }
+        ));
+        assert!(!info::get(file.path()).unwrap().is_compressed);
+        assert_eq!(fs::read(file.path()).unwrap(), b"new contents");
+
+        let plain = tempfile::NamedTempFile::new().unwrap();
+        fs::write(plain.path(), b"plain").unwrap();
+        assert!(matches!(
+            repair_compressed_flag(plain.path()).unwrap(),
+            CompressedFlagState::NotCompressed
+        ));
+    }
+
+    #[test]
+    fn clear_flag_without_decmpfs() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), b"raw data").unwrap();
+        let modified = file.path().metadata().unwrap().modified().unwrap();
+        assert!(!clear_orphaned_compressed_flag(file.path()).unwrap());
+
+        set_flags(file.as_file(), libc::UF_COMPRESSED).unwrap();
+        let file_info = info::get(file.path()).unwrap();
+        assert!(file_info.is_compressed);
+        assert_eq!(
+            file_info.inconsistency,
+            Some(info::Inconsistency::MissingDecmpfs)
+        );
+
+        assert!(clear_orphaned_compressed_flag(file.path()).unwrap());
+        let file_info = info::get(file.path()).unwrap();
+        assert!(!file_info.is_compressed);
+        assert_eq!(file_info.inconsistency, None);
+        assert_eq!(fs::read(file.path()).unwrap(), b"raw data");
+        assert_eq!(
+            file.path().metadata().unwrap().modified().unwrap(),
+            modified
+        );
+    }
+
+    #[test]
+    fn clear_flag_refuses_with_decmpfs() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let content = [1; 64 * 1024];
+        fs::write(file.path(), content).unwrap();
+        compress_file(file.path(), Kind::default(), 5, 1.0, Verify::Off).unwrap();
+        assert_eq!(info::get(file.path()).unwrap().inconsistency, None);
+
+        let err = clear_orphaned_compressed_flag(file.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(info::get(file.path()).unwrap().is_compressed);
+        assert_eq!(fs::read(file.path()).unwrap(), content);
+
+        // With the flag stripped, it's the opposite inconsistency, and still left alone
+        clear_compressed_flag(file.path());
+        assert_eq!(
+            info::get(file.path()).unwrap().inconsistency,
+            Some(info::Inconsistency::MissingCompressedFlag)
+        );
+        assert!(!clear_orphaned_compressed_flag(file.path()).unwrap());
+    }
+}
@@ -0,0 +1,199 @@
+//! Detecting sparse files, and keeping files sparse when decompressing
+//!
+//! A compressed file has no holes: its data is the compressed blocks. So compressing a sparse
+//! file (e.g. a disk image with large unused areas) reads every hole as zeros, and the temp file
+//! and the result can be far larger than the space the original used. Decompressing writes the
+//! zeros back out, unless whole blocks of zeros are left as holes.
+
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+/// The size of the units `st_blocks` counts
+const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Returns true if `file` has holes, and less than half of its length is allocated
+///
+/// `metadata` must be the metadata of `file`. This moves the file position.
+pub fn is_sparse(file: &File, metadata: &Metadata) -> io::Result<bool> {
+    let len = metadata.len();
+    let allocated = metadata.blocks().saturating_mul(STAT_BLOCK_SIZE);
+    if allocated.saturating_mul(2) >= len {
+        return Ok(false);
+    }
+    // The block count may be low for other reasons (e.g. the file is dataless), only files with
+    // real holes are sparse
+    Ok(first_hole(file)? < len)
+}
+
+/// The offset of the first hole in `file`, or its length if it has none
+fn first_hole(file: &File) -> io::Result<u64> {
+    // SAFETY: fd is valid
+    let offset = unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_HOLE) };
+    if offset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(u64::try_from(offset).unwrap())
+}
+
+/// Returns true if every byte of `block` is zero
+#[must_use]
+pub fn is_zeros(block: &[u8]) -> bool {
+    // Comparing a chunk at a time vectorizes, unlike stopping at the first non-zero byte
+    block
+        .chunks(64)
+        .all(|chunk| chunk.iter().fold(0, |acc, &b| acc | b) == 0)
+}
+
+/// Ranges of a file which were skipped rather than written, to be turned into holes
+#[derive(Debug, Default)]
+pub struct Holes {
+    /// Sorted, non-adjacent `(offset, len)` ranges
+    ranges: Vec<(u64, u64)>,
+}
+
+impl Holes {
+    /// Record that `len` bytes at `offset` were skipped
+    ///
+    /// Ranges must be added in increasing order of offset.
+    pub fn add(&mut self, offset: u64, len: u64) {
+        match self.ranges.last_mut() {
+            Some((last_offset, last_len)) if *last_offset + *last_len == offset => {
+                *last_len += len;
+            }
+            _ => self.ranges.push((offset, len)),
+        }
+    }
+
+    /// The total number of bytes skipped
+    #[must_use]
+    pub fn total_len(&self) -> u64 {
+        self.ranges.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Free any space allocated for the skipped ranges of `file`
+    ///
+    /// Skipped ranges read as zeros whether or not this succeeds, but may still use space if the
+    /// file was preallocated.
+    pub fn punch(&self, file: &File) -> io::Result<()> {
+        for &(offset, len) in &self.ranges {
+            let mut args = libc::fpunchhole_t {
+                fp_flags: 0,
+                reserved: 0,
+                fp_offset: libc::off_t::try_from(offset)
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+                fp_length: libc::off_t::try_from(len)
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+            };
+            // SAFETY: fd is valid, args is a valid fpunchhole_t, which F_PUNCHHOLE expects
+            let rc = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PUNCHHOLE, &mut args) };
+            if rc == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixture, NoProgress};
+    use crate::{info, FileCompressor};
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn zeros() {
+        assert!(is_zeros(&[]));
+        assert!(is_zeros(&[0; 1001]));
+        let mut block = vec![0; 1001];
+        for i in [0, 7, 500, 1000] {
+            block[i] = 1;
+            assert!(!is_zeros(&block), "{i}");
+            assert!(!is_zeros(&block[i..]), "{i}");
+            block[i] = 0;
+        }
+    }
+
+    #[test]
+    fn holes_merge_adjacent() {
+        let mut holes = Holes::default();
+        holes.add(0, 10);
+        holes.add(10, 5);
+        holes.add(20, 5);
+        assert_eq!(holes.ranges, [(0, 15), (20, 5)]);
+        assert_eq!(holes.total_len(), 20);
+    }
+
+    #[test]
+    fn sparse_detected() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        let dense = file.as_file().metadata().unwrap();
+        assert!(!is_sparse(file.as_file(), &dense).unwrap());
+
+        file.as_file().set_len(64 * 1024 * 1024).unwrap();
+        let metadata = file.as_file().metadata().unwrap();
+        if metadata.blocks() * STAT_BLOCK_SIZE >= metadata.len() {
+            // The filesystem doesn't support sparse files
+            return;
+        }
+        assert!(is_sparse(file.as_file(), &metadata).unwrap());
+    }
+
+    #[test]
+    fn skip_sparse() {
+        let dir = Fixture::new();
+        let path = dir.path().join("sparse");
+        let file = File::create(&path).unwrap();
+        file.write_all_at(&[1; 4 * 1024 * 1024], 0).unwrap();
+        file.set_len(1024 * 1024 * 1024).unwrap();
+        drop(file);
+        let metadata = path.metadata().unwrap();
+        if metadata.blocks() * 512 >= metadata.len() {
+            // The filesystem doesn't support sparse files
+            return;
+        }
+
+        let mut fc = FileCompressor::new();
+        let stats = dir.compress(&mut fc, &NoProgress);
+        assert_eq!(stats.skipped.sparse.into_inner(), 1);
+        // Nothing was written
+        assert_eq!(stats.bytes_written.into_inner(), 0);
+        assert!(!info::get(&path).unwrap().is_compressed);
+        assert_eq!(path.metadata().unwrap().blocks(), metadata.blocks());
+    }
+
+    #[test]
+    fn decompress_leaves_holes() {
+        let dir = Fixture::new();
+        let path = dir.path().join("sparse");
+        let file = File::create(&path).unwrap();
+        file.write_all_at(&[1; 64 * 1024], 0).unwrap();
+        file.set_len(64 * 1024 * 1024).unwrap();
+        drop(file);
+        let metadata = path.metadata().unwrap();
+        if metadata.blocks() * 512 >= metadata.len() {
+            // The filesystem doesn't support sparse files
+            return;
+        }
+
+        let mut fc = FileCompressor::new();
+        fc.set_compress_sparse(true);
+        dir.compress(&mut fc, &NoProgress);
+        assert!(info::get(&path).unwrap().is_compressed);
+
+        dir.decompress(&mut fc, &NoProgress);
+        assert!(!info::get(&path).unwrap().is_compressed);
+        let decompressed = path.metadata().unwrap();
+        assert_eq!(decompressed.len(), metadata.len());
+        assert!(decompressed.blocks() * 512 < decompressed.len() / 2);
+        let contents = fs::read(&path).unwrap();
+        assert!(contents[..64 * 1024].iter().all(|&b| b == 1));
+        assert!(contents[64 * 1024..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,238 @@
+//! Helpers shared by the tests of every module
+
+use crate::progress::{Progress, Task};
+use crate::{info, CompressionOptions, DecompressionOptions, FileCompressor, Stats, Verify};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+pub(crate) struct NoProgress;
+impl Task for NoProgress {
+    fn increment(&self, _amt: u64) {}
+    fn error(&self, _message: &str) {}
+}
+impl Progress for NoProgress {
+    type Task = NoProgress;
+
+    fn error(&self, path: &Path, message: &str) {
+        panic!("Expected no errors, got {message} for {path:?}");
+    }
+
+    fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+        NoProgress
+    }
+}
+
+pub(crate) type Errors = Arc<Mutex<Vec<String>>>;
+
+/// Collects every error reported, instead of panicking
+#[derive(Default)]
+pub(crate) struct ErrorProgress {
+    pub errors: Errors,
+}
+
+pub(crate) struct ErrorTask {
+    pub errors: Errors,
+}
+
+impl Task for ErrorTask {
+    fn increment(&self, _amt: u64) {}
+    fn error(&self, message: &str) {
+        self.errors.lock().unwrap().push(message.to_owned());
+    }
+}
+
+impl Progress for ErrorProgress {
+    type Task = ErrorTask;
+
+    fn error(&self, _path: &Path, message: &str) {
+        self.errors.lock().unwrap().push(message.to_owned());
+    }
+
+    fn fatal_error(&self, message: &str) {
+        self.errors.lock().unwrap().push(message.to_owned());
+    }
+
+    fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+        ErrorTask {
+            errors: Arc::clone(&self.errors),
+        }
+    }
+}
+
+/// A temporary directory to compress and decompress
+pub(crate) struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self {
+            dir: TempDir::new().unwrap(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes a file at `name`, relative to the fixture, returning its full path
+    pub fn file(&self, name: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Options which compress anything that gets smaller, quickly, without verifying
+    pub fn options() -> CompressionOptions {
+        CompressionOptions::new()
+            .minimum_ratio(1.0)
+            .level(2)
+            .verify(Verify::Off)
+    }
+
+    pub fn compress<P>(&self, fc: &mut FileCompressor, progress: &P) -> Stats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        self.compress_with(fc, &Self::options(), progress)
+    }
+
+    pub fn compress_with<P>(
+        &self,
+        fc: &mut FileCompressor,
+        options: &CompressionOptions,
+        progress: &P,
+    ) -> Stats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        fc.compress_with([self.path()], options, progress).unwrap()
+    }
+
+    pub fn decompress<P>(&self, fc: &mut FileCompressor, progress: &P) -> Stats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        let options = DecompressionOptions::new().manual(true);
+        self.decompress_with(fc, &options, progress)
+    }
+
+    pub fn decompress_with<P>(
+        &self,
+        fc: &mut FileCompressor,
+        options: &DecompressionOptions,
+        progress: &P,
+    ) -> Stats
+    where
+        P: Progress + Send + Sync,
+        P::Task: Send + Sync + 'static,
+    {
+        fc.decompress_with([self.path()], options, progress)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EntryInfo {
+    pub path: PathBuf,
+    pub modified_time: SystemTime,
+    pub content: Option<Vec<u8>>,
+}
+
+pub(crate) fn assert_entries_equal(old: &[EntryInfo], new: &[EntryInfo]) {
+    assert_eq!(old.len(), new.len());
+    for (old, new) in old.iter().zip(new.iter()) {
+        assert_eq!(old.path, new.path);
+        assert_eq!(
+            old.modified_time,
+            new.modified_time,
+            "modified time mismatch at {}",
+            old.path.display()
+        );
+        assert_eq!(
+            old.content,
+            new.content,
+            "content mismatch at {}",
+            old.path.display()
+        );
+    }
+}
+
+pub(crate) fn recursive_read(dir: &Path) -> Vec<EntryInfo> {
+    let mut result = Vec::new();
+    for item in WalkDir::new(dir).sort_by_file_name() {
+        let item = item.unwrap();
+        let metadata = item.metadata().unwrap();
+        let modified_time = metadata.modified().unwrap();
+        let content = if !item.file_type().is_dir() {
+            Some(fs::read(item.path()).unwrap())
+        } else {
+            None
+        };
+
+        result.push(EntryInfo {
+            path: item.into_path(),
+            modified_time,
+            content,
+        });
+    }
+    result
+}
+
+pub(crate) fn populate_dir(dir: &Path) {
+    // Empty file
+    fs::write(dir.join("EMPTY"), b"").unwrap();
+
+    // Medium files
+    for i in 0u8..=0xFF {
+        let p = dir.join(format!("{i}"));
+        fs::write(p, vec![i; usize::from(i) * 1024]).unwrap();
+    }
+
+    let subdir = dir.join("subdir");
+    fs::create_dir(&subdir).unwrap();
+    // Tiny Files
+    for i in 0u8..=0xFF {
+        let p = subdir.join(format!("{i}"));
+        fs::write(p, vec![i; usize::from(i)]).unwrap();
+    }
+
+    let big_file = dir.join("BIG");
+    let mut big_content = Vec::new();
+    for i in 0u8..=0xFF {
+        big_content.extend_from_slice(&[i; 1234]);
+    }
+    fs::write(big_file, big_content).unwrap();
+}
+
+/// Data which no compressor can do anything with
+pub(crate) fn incompressible_data(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x1234_5678_9abc_def1;
+    (0..len)
+        .map(|_| {
+            // xorshift
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// The compressed files under `dir`, relative to it, sorted
+pub(crate) fn compressed_files(dir: &Path) -> Vec<PathBuf> {
+    let mut compressed: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.is_file() && info::get(path).unwrap().is_compressed)
+        .map(|path| path.strip_prefix(dir).unwrap().to_owned())
+        .collect();
+    compressed.sort();
+    compressed
+}
@@ -0,0 +1,115 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A limit on a shared resource: the number of bytes of block data in flight between the reader
+/// and the writer, the number of files queued for the writer, or the number of files held open
+///
+/// Readers acquire a [`Permit`] before allocating a block, and the permit is released when the
+/// block is dropped (usually after the writer has written it). Similarly, the scan acquires a
+/// permit before opening each file, which is released when the file is closed.
+#[derive(Debug)]
+pub(super) struct Budget {
+    limit: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+impl Budget {
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Wait until `size` is available in the budget, and reserve it
+    ///
+    /// A request is always granted if nothing else is in flight, even if it is larger than the
+    /// whole budget, so a request larger than the budget doesn't wait forever. Waiting can still
+    /// deadlock if the permits in flight are only released by something waiting on this request:
+    /// see [`reader::Work::writer_slots`](super::reader::Work::writer_slots).
+    pub fn acquire(self: &Arc<Self>, size: u64) -> Permit {
+        let mut used = self.used.lock().unwrap();
+        while *used != 0 && *used + size > self.limit {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += size;
+        Permit {
+            budget: Arc::clone(self),
+            size,
+        }
+    }
+
+    #[cfg(test)]
+    fn used(&self) -> u64 {
+        *self.used.lock().unwrap()
+    }
+}
+
+/// A reservation from a [`Budget`], released on drop
+#[derive(Debug)]
+pub(super) struct Permit {
+    budget: Arc<Budget>,
+    size: u64,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().unwrap();
+        *used -= self.size;
+        drop(used);
+        self.budget.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn release_on_drop() {
+        let budget = Budget::new(100);
+        let a = budget.acquire(60);
+        let b = budget.acquire(40);
+        assert_eq!(budget.used(), 100);
+        drop(a);
+        assert_eq!(budget.used(), 40);
+        drop(b);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn oversized_request_when_empty() {
+        let budget = Budget::new(10);
+        let permit = budget.acquire(100);
+        assert_eq!(budget.used(), 100);
+        drop(permit);
+    }
+
+    #[test]
+    fn blocks_when_exceeded() {
+        let budget = Budget::new(100);
+        let held = budget.acquire(80);
+
+        let (tx, rx) = crossbeam_channel::bounded(0);
+        let waiter = thread::spawn({
+            let budget = Arc::clone(&budget);
+            move || {
+                let permit = budget.acquire(40);
+                tx.send(()).unwrap();
+                drop(permit);
+            }
+        });
+
+        // A stalled consumer holding the budget stops any more allocation
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(budget.used(), 80);
+
+        drop(held);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        waiter.join().unwrap();
+        assert_eq!(budget.used(), 0);
+    }
+}
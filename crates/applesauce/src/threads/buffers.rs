@@ -0,0 +1,86 @@
+//! Reusing block buffers between the reader, compressor, and writer threads
+//!
+//! Each block is read into one buffer, and compressed (or decompressed) into another. Rather than
+//! allocating both for every block, buffers are returned to a shared pool once the compressor (for
+//! the buffer read into) or the writer (for the buffer it writes) is done with them.
+
+use applesauce_core::BLOCK_SIZE;
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::Arc;
+
+/// The capacity of every pooled buffer, large enough for a block, or for a compressed block which
+/// grew
+pub(super) const BUF_CAPACITY: usize = BLOCK_SIZE + 1024;
+
+#[derive(Debug)]
+pub(super) struct BufferPool {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// A pool which keeps up to enough buffers for the blocks in flight with `byte_budget`
+    ///
+    /// There are at most two buffers for each block in flight: the block as read, and after it
+    /// has been compressed. Any buffers returned beyond that are freed.
+    pub fn new(byte_budget: u64) -> Arc<Self> {
+        let blocks = usize::try_from(byte_budget.div_ceil(BLOCK_SIZE as u64)).unwrap_or(usize::MAX);
+        let (tx, rx) = crossbeam_channel::bounded(blocks.max(1).saturating_mul(2));
+        Arc::new(Self { tx, rx })
+    }
+
+    /// A buffer of `len` zeroed bytes, reusing a returned buffer if there is one
+    pub fn get(&self, len: usize) -> Vec<u8> {
+        let mut buf = self
+            .rx
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(BUF_CAPACITY));
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Return a buffer to the pool, to be reused by a later call to [`Self::get`]
+    pub fn put(&self, buf: Vec<u8>) {
+        if buf.capacity() < BUF_CAPACITY {
+            return;
+        }
+        // If the pool is full, the buffer is freed
+        let _ = self.tx.try_send(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers() {
+        let pool = BufferPool::new(BLOCK_SIZE as u64);
+        let mut buf = pool.get(10);
+        assert_eq!(buf, [0; 10]);
+        buf.fill(0xFF);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+
+        let buf = pool.get(BLOCK_SIZE);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(buf.len(), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn bounded() {
+        // One block in flight needs at most two buffers
+        let pool = BufferPool::new(BLOCK_SIZE as u64);
+        for _ in 0..3 {
+            pool.put(Vec::with_capacity(BUF_CAPACITY));
+        }
+        assert_eq!(pool.rx.len(), 2);
+
+        // Buffers too small to hold a block aren't kept
+        let pool = BufferPool::new(BLOCK_SIZE as u64);
+        pool.put(Vec::with_capacity(16));
+        assert!(pool.rx.is_empty());
+    }
+}
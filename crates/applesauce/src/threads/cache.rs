@@ -0,0 +1,152 @@
+//! Hints to the OS about how files are read and written
+//!
+//! Each file is read once, from start to end, and each new file is written once. Caching large
+//! files only evicts the data other processes are using, so they bypass the cache.
+
+use libc::c_int;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+/// The hints which can be set on a file, a seam so tests can see which are attempted
+pub(super) trait CacheControl {
+    /// Don't keep the file's data in the cache (`F_NOCACHE`)
+    fn disable_cache(&self) -> io::Result<()>;
+    /// Read ahead of sequential reads (`F_RDAHEAD`)
+    fn enable_read_ahead(&self) -> io::Result<()>;
+}
+
+impl CacheControl for File {
+    fn disable_cache(&self) -> io::Result<()> {
+        set_fcntl(self, libc::F_NOCACHE, 1)
+    }
+
+    fn enable_read_ahead(&self) -> io::Result<()> {
+        set_fcntl(self, libc::F_RDAHEAD, 1)
+    }
+}
+
+fn set_fcntl(file: &File, cmd: c_int, arg: c_int) -> io::Result<()> {
+    // SAFETY: fd is valid, cmd takes an int argument
+    let rc = unsafe { libc::fcntl(file.as_raw_fd(), cmd, arg) };
+    if rc == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Which files bypass the cache
+#[derive(Debug, Clone, Copy)]
+pub(super) struct CacheHints {
+    uncached_file_size: Option<u64>,
+}
+
+impl CacheHints {
+    /// Files of at least `uncached_file_size` bytes bypass the cache, or none if `None`
+    pub fn new(uncached_file_size: Option<u64>) -> Self {
+        Self { uncached_file_size }
+    }
+
+    fn uncached(&self, len: u64) -> bool {
+        self.uncached_file_size.is_some_and(|size| len >= size)
+    }
+
+    /// Set the hints for a file of `len` bytes, which will be read from start to end
+    ///
+    /// Hints are only hints: failures are logged, and never stop a file being processed.
+    pub fn reading(&self, file: &impl CacheControl, len: u64) {
+        if let Err(e) = file.enable_read_ahead() {
+            tracing::debug!("unable to enable read ahead: {e}");
+        }
+        if self.uncached(len) {
+            if let Err(e) = file.disable_cache() {
+                tracing::debug!("unable to disable caching: {e}");
+            }
+        }
+    }
+
+    /// Set the hints for a new file, written in place of a file of `len` bytes
+    pub fn writing(&self, file: &impl CacheControl, len: u64) {
+        if self.uncached(len) {
+            if let Err(e) = file.disable_cache() {
+                tracing::debug!("unable to disable caching: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<&'static str>>,
+        fail: bool,
+    }
+
+    impl Recorder {
+        fn record(&self, call: &'static str) -> io::Result<()> {
+            self.calls.borrow_mut().push(call);
+            if self.fail {
+                return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+            }
+            Ok(())
+        }
+    }
+
+    impl CacheControl for Recorder {
+        fn disable_cache(&self) -> io::Result<()> {
+            self.record("nocache")
+        }
+
+        fn enable_read_ahead(&self) -> io::Result<()> {
+            self.record("rdahead")
+        }
+    }
+
+    #[test]
+    fn calls_attempted() {
+        let hints = CacheHints::new(Some(1024));
+        let cases: [(u64, &[&str], &[&str]); 3] = [
+            (0, &["rdahead"], &[]),
+            (1023, &["rdahead"], &[]),
+            (1024, &["rdahead", "nocache"], &["nocache"]),
+        ];
+        for (len, reading, writing) in cases {
+            let file = Recorder::default();
+            hints.reading(&file, len);
+            assert_eq!(file.calls.take(), reading, "{len}");
+            hints.writing(&file, len);
+            assert_eq!(file.calls.take(), writing, "{len}");
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let file = Recorder::default();
+        CacheHints::new(None).reading(&file, u64::MAX);
+        CacheHints::new(None).writing(&file, u64::MAX);
+        assert_eq!(file.calls.take(), ["rdahead"]);
+    }
+
+    #[test]
+    fn failures_ignored() {
+        let file = Recorder {
+            fail: true,
+            ..Recorder::default()
+        };
+        let hints = CacheHints::new(Some(0));
+        hints.reading(&file, 1);
+        hints.writing(&file, 1);
+        assert_eq!(file.calls.take(), ["rdahead", "nocache", "nocache"]);
+    }
+
+    #[test]
+    fn file_calls_succeed() {
+        let file = tempfile::tempfile().unwrap();
+        file.enable_read_ahead().unwrap();
+        file.disable_cache().unwrap();
+    }
+}
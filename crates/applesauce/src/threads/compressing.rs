@@ -1,7 +1,8 @@
 use crate::seq_queue;
+use crate::threads::budget::Permit;
+use crate::threads::buffers::{BufferPool, BUF_CAPACITY};
 use crate::threads::{writer, BgWork, Context, Mode, WorkHandler};
-use applesauce_core::compressor::{self, Compressor};
-use applesauce_core::BLOCK_SIZE;
+use applesauce_core::compressor::{self, Pool};
 use std::io;
 use std::sync::Arc;
 
@@ -12,9 +13,12 @@ pub(super) struct WorkItem {
     pub data: Vec<u8>,
     pub kind: compressor::Kind,
     pub slot: seq_queue::Slot<writer::Chunk, io::Error>,
+    pub permit: Permit,
 }
 
-pub(super) struct Work;
+pub(super) struct Work {
+    pub buffers: Arc<BufferPool>,
+}
 
 impl BgWork for Work {
     type Item = WorkItem;
@@ -23,39 +27,35 @@ impl BgWork for Work {
 
     fn make_handler(&self) -> Self::Handler {
         Handler {
-            compressors: (0..3).map(|_| None).collect(),
-            buf: vec![0; BLOCK_SIZE + 1024],
+            buffers: Arc::clone(&self.buffers),
         }
     }
-
-    fn queue_capacity(&self) -> usize {
-        8
-    }
 }
 
 pub(super) struct Handler {
-    compressors: Vec<Option<Compressor>>,
-    buf: Vec<u8>,
+    buffers: Arc<BufferPool>,
 }
 
 impl WorkHandler<WorkItem> for Handler {
+    fn context(item: &WorkItem) -> Option<&Arc<Context>> {
+        Some(&item.context)
+    }
+
     fn handle_item(&mut self, item: WorkItem) {
         let _entered =
             tracing::debug_span!("compressing block", path=%item.context.path.display()).entered();
 
-        // TODO: Unwrap?
-        let compressor = self.compressors[item.kind as usize]
-            .get_or_insert_with(|| item.kind.compressor().unwrap());
-        let size = match item.context.operation.mode {
+        let mut buf = self.buffers.get(BUF_CAPACITY);
+        let size = Pool::with(item.kind, |compressor| match item.context.operation.mode {
             Mode::Compress { kind, level, .. } => {
                 debug_assert_eq!(kind, item.kind);
-                compressor.compress(&mut self.buf, &item.data, level)
+                compressor.compress(&mut buf, &item.data, level)
             }
-            Mode::DecompressManually => compressor.decompress(&mut self.buf, &item.data),
+            Mode::DecompressManually => compressor.decompress(&mut buf, &item.data),
             Mode::DecompressByReading => {
                 panic!("decompressing by reading should not be using the compressor thread")
             }
-        };
+        });
         let size = match size {
             Ok(size) => size,
             Err(e) => {
@@ -64,11 +64,14 @@ impl WorkHandler<WorkItem> for Handler {
             }
         };
         debug_assert!(size != 0);
+        buf.truncate(size);
 
         let chunk = writer::Chunk {
-            block: self.buf[..size].to_vec(),
+            block: buf,
             orig_size: item.data.len().try_into().unwrap(),
+            permit: item.permit,
         };
+        self.buffers.put(item.data);
         if item.slot.finish(chunk).is_err() {
             // This should only be because of a failure already reported by the writer
             tracing::debug!("unable to finish slot");
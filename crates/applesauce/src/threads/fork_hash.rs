@@ -0,0 +1,210 @@
+//! Hashing a resource fork with BLAKE3 while it's written
+//!
+//! The compressed blocks are written in order after the space reserved for the header and block
+//! table, which are only written once every block is known. The blocks are hashed as they are
+//! written, in BLAKE3 subtrees which don't include the header, and the (small) header is kept in
+//! memory until the end, when the subtrees are merged into the hash of the whole fork.
+
+use blake3::hazmat::{
+    left_subtree_len, merge_subtrees_non_root, merge_subtrees_root, ChainingValue, HasherExt, Mode,
+};
+use blake3::{Hasher, CHUNK_LEN};
+
+const CHUNK: u64 = CHUNK_LEN as u64;
+
+/// Computes the BLAKE3 hash of a resource fork from the writes made to it
+pub(super) struct ForkHasher {
+    /// Everything before the first chunk boundary after the header, in place
+    ///
+    /// Anything not written is zero, as in the resource fork.
+    prefix: Vec<u8>,
+    /// The offset, length, and chaining value of each complete subtree after the prefix
+    subtrees: Vec<(u64, u64, ChainingValue)>,
+    /// The subtree currently being written, which starts at `current_start`
+    current: Hasher,
+    current_start: u64,
+    /// The offset the next write after the prefix must start at
+    next: u64,
+    /// The length of the resource fork
+    len: u64,
+    /// Cleared if anything after the prefix is written out of order, which can't be hashed
+    sequential: bool,
+}
+
+impl ForkHasher {
+    /// A hasher for a resource fork whose blocks start at `blocks_start`
+    pub fn new(blocks_start: u64) -> Self {
+        let streamed_from = blocks_start.div_ceil(CHUNK).max(1) * CHUNK;
+        Self {
+            prefix: vec![0; usize::try_from(streamed_from).unwrap()],
+            subtrees: Vec::new(),
+            current: subtree_hasher(streamed_from),
+            current_start: streamed_from,
+            next: streamed_from,
+            len: 0,
+            sequential: true,
+        }
+    }
+
+    fn streamed_from(&self) -> u64 {
+        self.prefix.len() as u64
+    }
+
+    /// Record that `buf` was written at `offset`
+    pub fn write_at(&mut self, mut offset: u64, mut buf: &[u8]) {
+        self.len = self.len.max(offset + buf.len() as u64);
+        let streamed_from = self.streamed_from();
+        if offset < streamed_from {
+            let in_prefix = buf.len().min((streamed_from - offset) as usize);
+            self.prefix[offset as usize..][..in_prefix].copy_from_slice(&buf[..in_prefix]);
+            buf = &buf[in_prefix..];
+            offset = streamed_from;
+        }
+        if buf.is_empty() {
+            return;
+        }
+        if offset != self.next {
+            self.sequential = false;
+            return;
+        }
+
+        while !buf.is_empty() {
+            // Each subtree is as large as its offset allows, so it is a node of the final tree
+            let max_len = CHUNK << (self.current_start / CHUNK).trailing_zeros();
+            let n = buf
+                .len()
+                .min((self.current_start + max_len - self.next) as usize);
+            self.current.update(&buf[..n]);
+            self.next += n as u64;
+            buf = &buf[n..];
+            if self.next == self.current_start + max_len {
+                self.subtrees.push((
+                    self.current_start,
+                    max_len,
+                    self.current.finalize_non_root(),
+                ));
+                self.current = subtree_hasher(self.next);
+                self.current_start = self.next;
+            }
+        }
+    }
+
+    /// The length and hash of the resource fork, if anything was written to it
+    ///
+    /// `None` if it was written out of order.
+    pub fn finish(mut self) -> Option<(u64, [u8; 32])> {
+        let len = self.len;
+        if len == 0 || !self.sequential {
+            return None;
+        }
+        if len <= self.streamed_from() {
+            return Some((len, blake3::hash(&self.prefix[..len as usize]).into()));
+        }
+        if self.next != len {
+            // Something after the prefix was never written
+            return None;
+        }
+        if self.next > self.current_start {
+            // The last subtree, on the right edge of the tree, can be short
+            self.subtrees.push((
+                self.current_start,
+                self.next - self.current_start,
+                self.current.finalize_non_root(),
+            ));
+        }
+        let left = left_subtree_len(len);
+        let hash = merge_subtrees_root(
+            &self.subtree(0, left),
+            &self.subtree(left, len - left),
+            Mode::Hash,
+        );
+        Some((len, hash.into()))
+    }
+
+    /// The chaining value of the subtree of `len` bytes at `start`
+    fn subtree(&self, start: u64, len: u64) -> ChainingValue {
+        if let Some(&(_, _, cv)) = self
+            .subtrees
+            .iter()
+            .find(|(s, l, _)| (*s, *l) == (start, len))
+        {
+            return cv;
+        }
+        if start + len <= self.streamed_from() {
+            return subtree_hasher(start)
+                .update(&self.prefix[start as usize..][..len as usize])
+                .finalize_non_root();
+        }
+        let left = left_subtree_len(len);
+        merge_subtrees_non_root(
+            &self.subtree(start, left),
+            &self.subtree(start + left, len - left),
+            Mode::Hash,
+        )
+    }
+}
+
+fn subtree_hasher(offset: u64) -> Hasher {
+    let mut hasher = Hasher::new();
+    hasher.set_input_offset(offset);
+    hasher
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the blocks in uneven pieces, then the trailer, then the header with a gap, like the
+    /// compressors do
+    fn hash_written(header_len: usize, data_len: usize) -> (Vec<u8>, Option<(u64, [u8; 32])>) {
+        let mut state = 0x1234_5678_9abc_def1_u64;
+        let mut fork: Vec<u8> = (0..header_len + data_len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let gap = header_len / 3..header_len / 2;
+        fork[gap.clone()].fill(0);
+
+        let mut hasher = ForkHasher::new(header_len as u64);
+        let mut offset = header_len;
+        for piece in fork[header_len..].chunks(7 * 1000 + 3) {
+            hasher.write_at(offset as u64, piece);
+            offset += piece.len();
+        }
+        hasher.write_at(0, &fork[..gap.start]);
+        hasher.write_at(gap.end as u64, &fork[gap.end..header_len]);
+        (fork, hasher.finish())
+    }
+
+    #[test]
+    fn matches_whole_hash() {
+        for header_len in [1, 16, 0x104 + 4 + 8 * 5, 1024, 1025, 3000, 40 * 1024] {
+            for data_len in [0, 1, 100, 1023, 1024, 5000, 64 * 1024, 1024 * 1024 + 17] {
+                let (fork, digest) = hash_written(header_len, data_len);
+                let expected: [u8; 32] = blake3::hash(&fork).into();
+                assert_eq!(
+                    digest,
+                    Some((fork.len() as u64, expected)),
+                    "header {header_len}, data {data_len}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_written() {
+        assert_eq!(ForkHasher::new(100).finish(), None);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut hasher = ForkHasher::new(100);
+        hasher.write_at(2048, &[1; 100]);
+        hasher.write_at(0, &[1; 100]);
+        assert_eq!(hasher.finish(), None);
+    }
+}
@@ -1,18 +1,37 @@
 use crate::info::{FileCompressionState, IncompressibleReason};
+use crate::output::{Output, OutputDir};
 use crate::progress::{self, Progress, SkipReason};
 use crate::tmpdir_paths::TmpdirPaths;
-use crate::{info, scan, times, Stats};
+use crate::{
+    adaptive, bundles, in_use, info, markers, protected, recent, scan, scan_attrs, sparse, times,
+    BackupExclusion, BundlePolicy, Durability, FileOrder, FileOutcome, FileSnapshot, Priority,
+    Stats, TimePreservation, Verify,
+};
 use applesauce_core::compressor;
-use std::fs::Metadata;
+use std::collections::HashSet;
+use std::fs::{File, Metadata};
+use std::io;
+use std::mem::MaybeUninit;
 use std::num::NonZeroUsize;
+use std::ops::ControlFlow;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime};
 use std::{fmt, mem};
 use tracing::warn;
 
+mod budget;
+mod buffers;
+mod cache;
 pub mod compressing;
+mod fork_hash;
+mod qos;
 pub mod reader;
+pub(crate) mod volumes;
 pub mod writer;
 
 struct ThreadJoiner {
@@ -27,8 +46,16 @@ impl ThreadJoiner {
 
 impl Drop for ThreadJoiner {
     fn drop(&mut self) {
-        for handle in self.threads.drain(..) {
-            handle.join().unwrap();
+        // Panics handling items are caught, a panic anywhere else shouldn't take down the
+        // process while it's dropping the threads
+        let panicked = self
+            .threads
+            .drain(..)
+            .map(JoinHandle::join)
+            .filter(Result::is_err)
+            .count();
+        if panicked != 0 {
+            warn!("{panicked} background threads panicked");
         }
     }
 }
@@ -37,6 +64,11 @@ pub struct BackgroundThreads {
     reader: BgWorker<reader::Work>,
     _compressor: BgWorker<compressing::Work>,
     _writer: BgWorker<writer::Work>,
+    priority: qos::SharedPriority,
+    /// Limits the number of files opened by the scan which haven't finished processing
+    open_files: Arc<budget::Budget>,
+    /// Limits the number of files being processed on each volume
+    volumes: volumes::VolumeLimits,
 }
 
 #[derive(Debug)]
@@ -45,7 +77,27 @@ pub struct OperationContext {
     stats: Stats,
     finished_stats: crossbeam_channel::Sender<Stats>,
     tempdirs: TmpdirPaths,
-    verify: bool,
+    verify: Verify,
+    file_change_retries: u32,
+    capture_metadata: bool,
+    time_preservation: TimePreservation,
+    strict_manual: bool,
+    force_clear: bool,
+    /// Create temp files as clones of the originals, where the volume supports it
+    clone_temp_files: bool,
+    /// What to flush to stable storage when replacing each file
+    durability: Durability,
+    /// Where to write copies of files, if not replacing them in place
+    output: Option<Output>,
+    /// Mark files which don't compress enough, so later runs can skip them
+    remember_incompressible: bool,
+    /// Errors which stop the whole operation, e.g. a background thread panicking
+    fatal_errors: Mutex<Vec<String>>,
+    started: Instant,
+    /// Which directories' files compress, to skip directories where none do
+    dir_outcomes: adaptive::DirOutcomes,
+    /// The `(device, inode)` of every file found, so no file is processed twice
+    seen_files: Mutex<HashSet<(u64, u64)>>,
 }
 
 impl OperationContext {
@@ -53,21 +105,118 @@ impl OperationContext {
         mode: Mode,
         finished_stats: crossbeam_channel::Sender<Stats>,
         tempdirs: TmpdirPaths,
-        verify: bool,
+        output: Option<Output>,
+        options: &ScanOptions,
     ) -> Self {
         Self {
             mode,
             stats: Stats::default(),
             finished_stats,
             tempdirs,
-            verify,
+            verify: options.verify,
+            file_change_retries: options.file_change_retries,
+            capture_metadata: options.capture_metadata,
+            time_preservation: options.time_preservation,
+            strict_manual: options.strict_manual,
+            force_clear: options.force_clear,
+            clone_temp_files: options.clone_temp_files,
+            durability: options.durability,
+            output,
+            remember_incompressible: options.remember_incompressible,
+            fatal_errors: Mutex::default(),
+            started: Instant::now(),
+            dir_outcomes: adaptive::DirOutcomes::new(options.adaptive_skip),
+            seen_files: Mutex::default(),
         }
     }
+
+    /// Record an error which stops the operation: no more files will be scanned
+    fn fatal_error(&self, message: String) {
+        tracing::error!("{message}");
+        self.fatal_errors.lock().unwrap().push(message);
+    }
+
+    /// Returns false if the file was already found by this operation
+    ///
+    /// Each hard link to a file is its own path, and is always processed (files with other hard
+    /// links are skipped when compressing).
+    fn first_found(&self, metadata: &Metadata) -> bool {
+        metadata.nlink() > 1
+            || self
+                .seen_files
+                .lock()
+                .unwrap()
+                .insert((metadata.dev(), metadata.ino()))
+    }
+
+    fn has_fatal_error(&self) -> bool {
+        !self.fatal_errors.lock().unwrap().is_empty()
+    }
+
+    /// Record that the threads for a stage of processing have all stopped, returning an error
+    /// for the file being processed
+    fn threads_stopped(&self, name: &str) -> io::Error {
+        let message = format!("{name} threads stopped unexpectedly");
+        self.fatal_error(message.clone());
+        io::Error::other(message)
+    }
+}
+
+/// The bundles files were found in during a scan
+#[derive(Default)]
+struct EnteredBundles {
+    seen: HashSet<Arc<Path>>,
+    /// Each bundle in the order it was entered, and whether its code signature was valid before
+    /// any of its files were processed (only checked when verifying signatures)
+    entered: Vec<(Arc<Path>, bool)>,
+}
+
+/// Options for a single scan
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub verify: Verify,
+    pub file_change_retries: u32,
+    pub capture_metadata: bool,
+    pub backup_exclusion: BackupExclusion,
+    pub bundle_policy: BundlePolicy,
+    /// Check the code signature of each bundle files were processed in, before and after
+    pub verify_signatures: bool,
+    pub time_preservation: TimePreservation,
+    pub skip_clones: bool,
+    /// Create temp files as clones of the originals on APFS volumes
+    pub clone_temp_files: bool,
+    /// What to flush to stable storage when replacing each file
+    pub durability: Durability,
+    pub strict_manual: bool,
+    /// Clear compressed files missing their resource fork, rather than skipping them
+    pub force_clear: bool,
+    pub check_dir_times: bool,
+    /// Write copies of files into this directory, rather than replacing them
+    pub output_dir: Option<PathBuf>,
+    /// Replace existing files in the output directory
+    pub overwrite_output: bool,
+    /// Which recently modified files to skip when compressing
+    pub recency: recent::Recency,
+    /// Skip files which another process has open for writing when compressing
+    pub skip_in_use: bool,
+    /// Compress sparse files, rather than skipping them
+    pub compress_sparse: bool,
+    /// Skip files marked as not compressing enough, and mark files which don't
+    pub remember_incompressible: bool,
+    /// When to skip the rest of a directory of files which don't compress
+    pub adaptive_skip: Option<adaptive::Settings>,
+    /// The order to process files in
+    pub file_order: FileOrder,
 }
 
 impl Drop for OperationContext {
     fn drop(&mut self) {
-        let stats = mem::take(&mut self.stats);
+        let mut stats = mem::take(&mut self.stats);
+        stats.elapsed = self.started.elapsed();
+        let fatal_errors = mem::take(self.fatal_errors.get_mut().unwrap());
+        if !fatal_errors.is_empty() {
+            stats.fatal_error = Some(fatal_errors.join("; "));
+        }
         let _ = self.finished_stats.send(stats);
     }
 }
@@ -79,18 +228,71 @@ pub struct Context {
     parent_resetter: Option<Arc<times::Resetter>>,
     operation: Arc<OperationContext>,
     path: PathBuf,
+    /// Where the new file is written, if not replacing the file at `path`
+    output_path: Option<PathBuf>,
     progress: Box<dyn progress::Task + Send + Sync>,
+    /// The file, opened during the scan, and used for every step after that
+    file: File,
+    _open_file_permit: budget::Permit,
+    /// `None` if files on the volume are not limited
+    _volume_permit: Option<budget::Permit>,
     orig_metadata: Metadata,
-    orig_times: times::Saved,
+    /// The file when it was found, counted in the stats with the file as it is when dropped
+    start: FileSnapshot,
+    /// `None` if times are not being preserved
+    orig_times: Option<times::Saved>,
+    /// An error processing the file has been reported
+    failed: AtomicBool,
+    /// The file has been skipped
+    skipped: AtomicBool,
+    /// The file has been compressed or decompressed
+    succeeded: AtomicBool,
+}
+
+impl Context {
+    /// Report an error processing the file, which is counted as failed
+    fn error(&self, message: &str) {
+        self.failed.store(true, Ordering::Relaxed);
+        self.operation.stats.errors.record(&self.path, message);
+        self.progress.error(message);
+    }
+
+    /// Skip the file, counting it in the stats
+    fn skip(&self, reason: SkipReason) {
+        self.skipped.store(true, Ordering::Relaxed);
+        self.operation.stats.add_skipped(&self.path, &reason);
+        self.progress.file_skipped(&self.path, reason);
+    }
+
+    /// True if the file was skipped, or an error processing it was reported
+    fn reported(&self) -> bool {
+        self.failed.load(Ordering::Relaxed) || self.skipped.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
-        let Ok(metadata) = self.path.symlink_metadata() else {
-            return;
+        let outcome = if *self.failed.get_mut() {
+            self.operation.stats.add_file_error();
+            FileOutcome::Error
+        } else if *self.skipped.get_mut() {
+            FileOutcome::Skipped
+        } else if *self.succeeded.get_mut() {
+            if self.operation.mode.is_compressing() {
+                FileOutcome::Compressed
+            } else {
+                FileOutcome::Decompressed
+            }
+        } else {
+            // Never processed, the run was stopped by a fatal error
+            FileOutcome::Error
         };
-        let file_info = info::get_file_info(&self.path, &metadata);
-        self.operation.stats.add_end_file(&metadata, &file_info);
+        let path = self.output_path.as_deref().unwrap_or(&self.path);
+        // A file which is gone (or was never copied to the output) is counted as it was
+        let end = path.symlink_metadata().map_or(self.start, |metadata| {
+            FileSnapshot::new(&metadata, &info::get_file_info(path, &metadata))
+        });
+        self.operation.stats.add_file(&self.start, &end, outcome);
     }
 }
 
@@ -100,6 +302,9 @@ pub enum Mode {
         kind: compressor::Kind,
         minimum_compression_ratio: f64,
         level: u32,
+        /// Give up on a file early if its first `sample_blocks` blocks don't compress to
+        /// `minimum_compression_ratio`
+        sample_blocks: Option<u32>,
     },
     DecompressManually,
     DecompressByReading,
@@ -111,35 +316,172 @@ impl Mode {
     }
 }
 
+/// The default number of bytes of block data in flight, per compressor thread
+pub const DEFAULT_BYTE_BUDGET_PER_THREAD: u64 = 64 * 1024 * 1024;
+
+/// The default size of files which bypass the filesystem cache
+pub const DEFAULT_UNCACHED_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The sizes of the thread pools and queues which files and blocks pass through
+///
+/// Files found by the scan are queued for the reader threads, which queue blocks for the
+/// compressor threads, which queue compressed blocks for the writer threads. The defaults suit
+/// most storage, but different values may have better throughput on some (e.g. slow external
+/// drives).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    /// The number of files which can be queued for the reader threads
+    ///
+    /// This is large, so the scan can run well ahead of processing, and the total progress
+    /// is accurate sooner. Queued files are held open, so the scan is also limited to half the
+    /// process's limit on open files, which is raised to the hard limit when the threads start.
+    pub reader_queue: NonZeroUsize,
+    /// The number of items which can be queued for the writer threads
+    pub writer_queue: NonZeroUsize,
+    /// The number of blocks which can be queued for the compressor threads
+    pub compressor_queue: NonZeroUsize,
+    pub reader_threads: NonZeroUsize,
+    pub writer_threads: NonZeroUsize,
+    /// Defaults to the available parallelism
+    pub compressor_threads: NonZeroUsize,
+    /// The number of bytes of block data which can be in flight between reading and writing
+    ///
+    /// Readers wait for blocks to be written before reading more once the budget is used.
+    /// Defaults to [`DEFAULT_BYTE_BUDGET_PER_THREAD`] for each compressor thread.
+    pub byte_budget: Option<u64>,
+    /// The number of files on a single network volume which can be processed at once
+    ///
+    /// Volumes with the filesystem types `smbfs`, `nfs`, `afpfs`, and `webdav` are network
+    /// volumes. Writing many files to one in parallel is much slower than writing a few at a
+    /// time. Defaults to 4, `None` is unlimited.
+    pub network_volume_files: Option<NonZeroUsize>,
+    /// The number of files on a single local volume (e.g. `apfs` or `hfs`) which can be
+    /// processed at once
+    ///
+    /// Defaults to `None`, unlimited: local volumes handle many files at once, and files are
+    /// still limited by the number of threads.
+    pub local_volume_files: Option<NonZeroUsize>,
+    /// Back off whenever the machine is in use, for running unattended
+    ///
+    /// Every thread runs at the background QoS class, whatever the
+    /// [`Priority`], and the threads reading and writing files have their disk I/O throttled.
+    /// Operations can take much longer. Defaults to `false`.
+    pub throttle: bool,
+    /// Files at least this large are read and written without keeping their data in the
+    /// filesystem cache
+    ///
+    /// Each file is only read and written once, caching large files evicts data other
+    /// processes are using. `None` caches every file. Defaults to
+    /// [`DEFAULT_UNCACHED_FILE_SIZE`].
+    pub uncached_file_size: Option<u64>,
+}
+
+impl PipelineConfig {
+    /// The byte budget, accounting for the default
+    #[must_use]
+    pub fn effective_byte_budget(&self) -> u64 {
+        self.byte_budget.unwrap_or_else(|| {
+            DEFAULT_BYTE_BUDGET_PER_THREAD * self.compressor_threads.get() as u64
+        })
+    }
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            reader_queue: NonZeroUsize::new(100 * 1024).unwrap(),
+            writer_queue: NonZeroUsize::new(4).unwrap(),
+            compressor_queue: NonZeroUsize::new(8).unwrap(),
+            reader_threads: NonZeroUsize::new(8).unwrap(),
+            writer_threads: NonZeroUsize::new(16).unwrap(),
+            compressor_threads: compressor_thread_count(),
+            byte_budget: None,
+            network_volume_files: NonZeroUsize::new(4),
+            local_volume_files: None,
+            throttle: false,
+            uncached_file_size: Some(DEFAULT_UNCACHED_FILE_SIZE),
+        }
+    }
+}
+
 impl BackgroundThreads {
     #[must_use]
     pub fn new() -> Self {
-        let compressor_threads = thread::available_parallelism()
-            .map(NonZeroUsize::get)
-            .unwrap_or(1);
+        Self::with_config(PipelineConfig::default())
+    }
+
+    /// Create background threads with the thread and queue sizes in `config`
+    #[must_use]
+    pub fn with_config(config: PipelineConfig) -> Self {
+        let byte_budget = config.effective_byte_budget();
+        tracing::debug!(?config, byte_budget, "starting background threads");
+        let priority = qos::SharedPriority::new(config.throttle);
+        let buffers = buffers::BufferPool::new(byte_budget);
+        let cache = cache::CacheHints::new(config.uncached_file_size);
 
-        let compressor = BgWorker::new(compressor_threads, &compressing::Work);
-        let writer = BgWorker::new(16, &writer::Work);
+        let compressor = BgWorker::new(
+            config.compressor_threads,
+            config.compressor_queue,
+            qos::Pool::Compressor,
+            &priority,
+            &compressing::Work {
+                buffers: Arc::clone(&buffers),
+            },
+        );
+        let writer = BgWorker::new(
+            config.writer_threads,
+            config.writer_queue,
+            qos::Pool::Io,
+            &priority,
+            &writer::Work {
+                buffers: Arc::clone(&buffers),
+                cache,
+            },
+        );
         let reader = BgWorker::new(
-            8,
+            config.reader_threads,
+            config.reader_queue,
+            qos::Pool::Io,
+            &priority,
             &reader::Work {
                 compressor: compressor.chan().clone(),
                 writer: writer.chan().clone(),
+                writer_slots: budget::Budget::new(config.writer_threads.get() as u64),
+                budget: budget::Budget::new(byte_budget),
+                buffers,
+                cache,
             },
         );
         Self {
             reader,
             _compressor: compressor,
             _writer: writer,
+            priority,
+            open_files: budget::Budget::new(open_file_limit()),
+            volumes: volumes::VolumeLimits::new(
+                config.network_volume_files,
+                config.local_volume_files,
+            ),
         }
     }
 
+    /// Set the scheduling priority of all background threads
+    ///
+    /// The change applies to each thread from the next item it handles.
+    pub fn set_priority(&self, priority: Priority) {
+        self.priority.set(priority);
+    }
+
+    /// Process every file under `paths`
+    ///
+    /// Returns once every change to the filesystem made by the operation is complete, including
+    /// restoring the times of every directory a file was replaced in.
     pub fn scan<'a, P>(
         &self,
         mode: Mode,
         paths: impl IntoIterator<Item = &'a Path>,
         progress: &P,
-        verify: bool,
+        options: ScanOptions,
     ) -> Stats
     where
         P: Progress + Send + Sync,
@@ -147,39 +489,230 @@ impl BackgroundThreads {
     {
         let (finished_stats, finished_stats_rx) = crossbeam_channel::bounded(1);
         let mut tmpdirs = TmpdirPaths::new();
-        let mut walker = scan::Walker::new(progress);
+        let restore_log = options
+            .check_dir_times
+            .then(|| Arc::new(times::RestoreLog::default()));
+        // Directories are only read when writing copies, so their times don't need restoring
+        let dir_time_preservation = if options.output_dir.is_some() {
+            TimePreservation::None
+        } else {
+            options.time_preservation
+        };
+        let pending_resets = Arc::new(times::PendingResets::default());
+        let mut walker = scan::Walker::new(
+            options.file_order,
+            options.backup_exclusion,
+            options.bundle_policy,
+            dir_time_preservation,
+            restore_log.clone(),
+            Arc::clone(&pending_resets),
+        );
+        let mut missing_roots = Vec::new();
+        let mut unsupported_roots = Vec::new();
+        let mut roots = Vec::new();
         for path in paths {
-            let Ok(metadata) = path.metadata() else {
+            match path.metadata() {
+                Ok(metadata) => roots.push((path, metadata)),
+                Err(e) => {
+                    let message = format!("error getting metadata: {e}");
+                    progress.error(path, &message);
+                    missing_roots.push((path, message));
+                }
+            }
+        }
+        // Walking a path inside another would process (and count) its files twice
+        let root_paths: Vec<&Path> = roots.iter().map(|&(path, _)| path).collect();
+        let mut nested = vec![false; roots.len()];
+        for (i, containing) in scan::nested_roots(&root_paths) {
+            warn!(
+                "skipping {}, it is already included in {}",
+                root_paths[i].display(),
+                root_paths[containing].display()
+            );
+            nested[i] = true;
+        }
+        for (&(path, ref metadata), nested) in roots.iter().zip(nested) {
+            if nested {
                 continue;
-            };
-            if let Err(e) = tmpdirs.add_dst(path, &metadata) {
-                warn!(
-                    "failed to find a temp directory for {}: {e}",
-                    path.display()
-                );
+            }
+            // Otherwise, the only sign would be every file being skipped
+            if mode.is_compressing() {
+                match info::volume_supports_compression(path) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let message = "the volume this is on does not support compression, \
+                                       none of its files can be compressed";
+                        progress.error(path, message);
+                        unsupported_roots.push((path, message));
+                    }
+                    Err(e) => tracing::debug!(
+                        "unable to check if {} supports compression: {e}",
+                        path.display()
+                    ),
+                }
+            }
+            // Temp files for copies are created in the output directory, never next to the
+            // originals
+            if options.output_dir.is_none() {
+                if let Err(e) = tmpdirs.add_dst(path, metadata) {
+                    warn!(
+                        "failed to find a temp directory for {}: {e}",
+                        path.display()
+                    );
+                }
             }
             walker.add_path(path);
         }
-        let operation = Arc::new(OperationContext::new(mode, finished_stats, tmpdirs, verify));
+        let (output, output_error) = match &options.output_dir {
+            None => (None, None),
+            Some(dir) => match Output::new(dir, walker.paths(), options.overwrite_output) {
+                Ok(output) => {
+                    if let Err(e) = output
+                        .dir()
+                        .metadata()
+                        .and_then(|metadata| tmpdirs.add_dst(output.dir(), &metadata))
+                    {
+                        warn!(
+                            "failed to find a temp directory for {}: {e}",
+                            output.dir().display()
+                        );
+                    }
+                    (Some(output), None)
+                }
+                Err(e) => (
+                    None,
+                    Some(format!("unable to write to {}: {e}", dir.display())),
+                ),
+            },
+        };
+        let operation = Arc::new(OperationContext::new(
+            mode,
+            finished_stats,
+            tmpdirs,
+            output,
+            &options,
+        ));
+        let output = operation.output.as_ref();
+        let output_dirs: Mutex<Vec<OutputDir>> = Mutex::default();
         let stats = &operation.stats;
+        for (path, message) in &missing_roots {
+            stats.errors.record(path, message);
+        }
+        for &(path, message) in &unsupported_roots {
+            stats.errors.record(path, message);
+        }
         let chan = self.reader.chan();
+        let tcc_roots = protected::tcc_roots();
 
-        walker.run(&operation.tempdirs, |file_type, path, dir_reset| {
+        let file_error = |path: &Path, message: &str| {
+            stats.add_file_error();
+            stats.errors.record(path, message);
+            progress.error(path, message);
+        };
+        let file_skipped = |root: usize, path: &Path, reason: SkipReason| {
+            let reason = protected::classify_skip_with_roots(path, reason, &tcc_roots);
+            stats.add_skipped(path, &reason);
+            // Skipped files are still copied, unless they are unreadable or excluded from backups
+            let copy = output.filter(|_| {
+                !matches!(
+                    reason,
+                    SkipReason::Dataless
+                        | SkipReason::ReadError(_)
+                        | SkipReason::Excluded
+                        | SkipReason::InBundle
+                )
+            });
+            progress.file_skipped(path, reason);
+            if let Some(output) = copy {
+                if let Err(e) = output.copy_original(path, &output.path_for(root, path)) {
+                    file_error(path, &format!("error copying to output: {e}"));
+                }
+            }
+        };
+        // Skip a file which has been looked at, so is counted in the stats, before it's queued
+        let counted_file_skipped =
+            |root: usize, path: &Path, snapshot: &FileSnapshot, reason: SkipReason| {
+                let reason = protected::classify_skip_with_roots(path, reason, &tcc_roots);
+                stats.add_file(snapshot, snapshot, FileOutcome::of_skip(&reason));
+                file_skipped(root, path, reason);
+            };
+
+        let log_roots = recent::log_roots();
+        let scan_started = SystemTime::now();
+        let found_files = AtomicU64::new(0);
+        let found_bytes = AtomicU64::new(0);
+        let entered_bundles: Mutex<EnteredBundles> = Mutex::default();
+        progress.roots(walker.paths());
+        progress.scan_started();
+        let process_file = |found: scan::FoundFile| {
+            let scan::FoundFile {
+                root,
+                file_type,
+                path,
+                dir_reset,
+                bundle,
+            } = found;
             // We really only want to deal with files, not symlinks to files, or fifos, etc.
             #[allow(clippy::filetype_is_file)]
             if !file_type.is_file() {
-                progress.file_skipped(&path, SkipReason::NotFile);
+                file_skipped(root, &path, SkipReason::NotFile);
                 return;
             }
             let metadata = match path.symlink_metadata() {
                 Ok(metadata) => metadata,
                 Err(e) => {
-                    progress.file_skipped(&path, SkipReason::ReadError(e));
+                    file_skipped(root, &path, SkipReason::ReadError(e));
+                    return;
+                }
+            };
+            if !operation.first_found(&metadata) {
+                tracing::debug!("{} was already found, skipping", path.display());
+                return;
+            }
+            let output_path = output.map(|output| output.path_for(root, &path));
+            if let (Some(output), Some(output_path)) = (output, &output_path) {
+                if let Err(e) = output.ensure_free(output_path) {
+                    file_error(&path, &e.to_string());
+                    return;
+                }
+            }
+            // Decide what can be decided without opening the file: opening a dataless file would
+            // download it, whether compressing or decompressing
+            let stat_skip_reason = if protected::is_sip_protected(&metadata) {
+                Some(SkipReason::SipProtected)
+            } else if info::is_dataless(&metadata) {
+                Some(SkipReason::Dataless)
+            } else if mode.is_compressing() {
+                recent::skip_reason(&path, &metadata, options.recency, &log_roots, scan_started)
+                    .or_else(|| operation.dir_outcomes.skip_reason(&path))
+            } else {
+                None
+            };
+            if let Some(skip_reason) = stat_skip_reason {
+                let snapshot = FileSnapshot::new(&metadata, &info::get_file_info(&path, &metadata));
+                counted_file_skipped(root, &path, &snapshot, skip_reason);
+                return;
+            }
+
+            // Everything from here on uses this file, rather than the path, so a file swapped in
+            // at the path after this point is never processed in place of the original
+            let volume_permit = self.volumes.acquire(&path, &metadata);
+            let open_file_permit = self.open_files.acquire(1);
+            let (file, metadata) = match open_for_scan(&path) {
+                Ok(Some(opened)) => opened,
+                Ok(None) => {
+                    file_skipped(root, &path, SkipReason::NotFile);
+                    return;
+                }
+                Err(e) => {
+                    let snapshot =
+                        FileSnapshot::new(&metadata, &info::get_file_info(&path, &metadata));
+                    counted_file_skipped(root, &path, &snapshot, SkipReason::ReadError(e));
                     return;
                 }
             };
-            let mut file_info = info::get_file_info(&path, &metadata);
-            stats.add_start_file(&metadata, &file_info);
+            let mut file_info = info::get_open_file_info(&file, &metadata);
+            let snapshot = FileSnapshot::new(&metadata, &file_info);
 
             let skip_reason: Option<SkipReason> = match &mut file_info.compression_state {
                 FileCompressionState::Compressed => {
@@ -209,40 +742,264 @@ impl BackgroundThreads {
                 }
             };
             if let Some(skip_reason) = skip_reason {
-                progress.file_skipped(&path, skip_reason);
-                stats.add_end_file(&metadata, &file_info);
+                counted_file_skipped(root, &path, &snapshot, skip_reason);
                 return;
             }
-            let saved_times = match times::save_times(path.as_path()) {
+            if let Mode::Compress {
+                kind,
+                minimum_compression_ratio,
+                ..
+            } = mode
+            {
+                if options.remember_incompressible
+                    && markers::is_marked(&file, &metadata, kind, minimum_compression_ratio)
+                {
+                    counted_file_skipped(
+                        root,
+                        &path,
+                        &snapshot,
+                        SkipReason::PreviouslyIncompressible,
+                    );
+                    return;
+                }
+            }
+            if mode.is_compressing() {
+                match sparse::is_sparse(&file, &metadata) {
+                    Ok(true) if !options.compress_sparse => {
+                        counted_file_skipped(root, &path, &snapshot, SkipReason::Sparse);
+                        return;
+                    }
+                    Ok(true) => warn!(
+                        "{} is sparse, compressing it will allocate space for its holes",
+                        path.display()
+                    ),
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::debug!("unable to check if {} is sparse: {e}", path.display())
+                    }
+                }
+            }
+            if mode.is_compressing() && options.skip_in_use {
+                match in_use::is_open_for_writing(&path, &metadata) {
+                    Ok(true) => {
+                        counted_file_skipped(root, &path, &snapshot, SkipReason::InUse);
+                        return;
+                    }
+                    Ok(false) => {}
+                    // Best-effort: a file which can't be checked is processed as usual
+                    Err(e) => {
+                        tracing::debug!("unable to check if {} is in use: {e}", path.display())
+                    }
+                }
+            }
+            let check_clones = mode.is_compressing() && options.skip_clones;
+            let attrs = scan_attrs::get(&file, options.time_preservation, check_clones);
+            if attrs.may_share_blocks {
+                counted_file_skipped(root, &path, &snapshot, SkipReason::ProbablyClone);
+                return;
+            }
+            let saved_times = match attrs.saved_times {
                 Ok(saved_times) => saved_times,
                 Err(e) => {
-                    progress.file_skipped(&path, SkipReason::ReadError(e));
-                    stats.add_end_file(&metadata, &file_info);
+                    counted_file_skipped(root, &path, &snapshot, SkipReason::ReadError(e));
                     return;
                 }
             };
 
-            let inner_progress = Box::new(progress.file_task(&path, metadata.len()));
-            chan.send(reader::WorkItem {
+            if let Some(bundle) = &bundle {
+                let mut entered_bundles = entered_bundles.lock().unwrap();
+                if entered_bundles.seen.insert(Arc::clone(bundle)) {
+                    // Nothing in the bundle has been queued yet, so this is the signature before
+                    let signed =
+                        options.verify_signatures && bundles::verify_signature(bundle).is_ok();
+                    entered_bundles.entered.push((Arc::clone(bundle), signed));
+                    progress.bundle_entered(bundle);
+                }
+            }
+
+            found_files.fetch_add(1, Ordering::Relaxed);
+            found_bytes.fetch_add(metadata.len(), Ordering::Relaxed);
+            // Every check which can skip the file must come before this: once the task is
+            // created, its size counts toward the total progress
+            progress.found_file(&path, metadata.len());
+            let inner_progress = Box::new(progress.file_task(&path, metadata.len(), root));
+            let item = reader::WorkItem {
                 context: Arc::new(Context {
                     operation: Arc::clone(&operation),
                     path,
+                    output_path,
                     progress: inner_progress,
                     orig_metadata: metadata,
+                    start: snapshot,
                     parent_resetter: dir_reset,
+                    file,
+                    _open_file_permit: open_file_permit,
+                    _volume_permit: volume_permit,
                     orig_times: saved_times,
+                    failed: AtomicBool::new(false),
+                    skipped: AtomicBool::new(false),
+                    succeeded: AtomicBool::new(false),
                 }),
-            })
-            .unwrap();
-        });
+            };
+            if chan.send(item).is_err() {
+                let _ = operation.threads_stopped("reader");
+            }
+        };
+        if let Some(message) = output_error {
+            operation.fatal_error(message);
+        } else {
+            walker.run(
+                &operation.tempdirs,
+                |found| {
+                    process_file(found);
+                    if operation.has_fatal_error() {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                },
+                |root, dir, metadata| {
+                    let Some(output) = output else {
+                        return;
+                    };
+                    match output.create_dir(root, dir, metadata, options.time_preservation) {
+                        Ok(output_dir) => output_dirs.lock().unwrap().push(output_dir),
+                        Err(e) => {
+                            let message = format!("error creating output directory: {e}");
+                            stats.errors.record(dir, &message);
+                            progress.error(dir, &message);
+                        }
+                    }
+                },
+                |dir, e| {
+                    stats.unreadable_dirs.fetch_add(1, Ordering::Relaxed);
+                    progress.dir_skipped(dir, e);
+                },
+                |root, path| file_skipped(root, path, SkipReason::Excluded),
+                |root, path| file_skipped(root, path, SkipReason::InBundle),
+                file_error,
+            );
+        }
+        progress.scan_complete(found_files.into_inner(), found_bytes.into_inner());
         drop(operation);
 
-        finished_stats_rx
+        let mut stats = finished_stats_rx
             .recv()
-            .expect("OperationContext will send stats on drop of all arcs")
+            .expect("OperationContext will send stats on drop of all arcs");
+        *stats.missing_roots.get_mut() = missing_roots.len() as u64;
+        *stats.unsupported_roots.get_mut() = unsupported_roots.len() as u64;
+        if let Some(message) = &stats.fatal_error {
+            progress.fatal_error(message);
+        }
+        // Each file's context resets its directory's times before releasing the operation, but a
+        // walk which stopped early may still be dropping entries holding resetters
+        pending_resets.wait();
+        // Every file has been written, so the directories they were written to can be finished
+        for output_dir in output_dirs.into_inner().unwrap().iter().rev() {
+            if let Err(e) = output_dir.finish() {
+                let message = format!("error copying directory attributes: {e}");
+                stats.errors.record(output_dir.path(), &message);
+                progress.error(output_dir.path(), &message);
+            }
+        }
+        // Every file has been replaced, so the signatures are final
+        if options.verify_signatures {
+            for (bundle, signed) in entered_bundles.into_inner().unwrap().entered {
+                // Bundles which weren't signed (validly) can't be made any worse
+                if !signed {
+                    continue;
+                }
+                if let Err(e) = bundles::verify_signature(&bundle) {
+                    let message = format!("code signature is no longer valid: {e}");
+                    *stats.invalid_signatures.get_mut() += 1;
+                    stats.errors.record(&bundle, &message);
+                    progress.error(&bundle, &message);
+                }
+            }
+        }
+        // Every file has been processed, so every directory has been restored
+        if let Some(restore_log) = restore_log {
+            for mismatch in restore_log.mismatches() {
+                *stats.dir_times_not_restored.get_mut() += 1;
+                progress.dir_times_not_restored(&mismatch);
+            }
+        }
+        stats
     }
 }
 
+/// Open a file found by the scan, without following symlinks
+///
+/// Returns `None` if the path is no longer a regular file.
+fn open_for_scan(path: &Path) -> io::Result<Option<(File, Metadata)>> {
+    // Non-blocking, so a fifo which replaced the file can't block the open
+    let file = File::options()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    Ok(Some((file, metadata)))
+}
+
+/// The maximum number of files to hold open at once, leaving room under the process's limit for
+/// temp files, directories, and anything else
+///
+/// Every file found by the scan is held open until it's written, so this limits how far the scan
+/// can run ahead of processing. The soft limit is raised as far as it can be first: the default
+/// soft limit on macOS is only 256.
+fn open_file_limit() -> u64 {
+    const MIN: u64 = 32;
+    const MAX: u64 = 64 * 1024;
+
+    let Some(soft_limit) = raise_open_file_limit() else {
+        return MIN;
+    };
+    (soft_limit / 2).clamp(MIN, MAX)
+}
+
+/// Raise the soft limit on open files to the hard limit, returning the new soft limit
+///
+/// Returns `None` if the limit can't be read. If it can't be raised, the current soft limit is
+/// returned.
+fn raise_open_file_limit() -> Option<u64> {
+    // setrlimit(2): the hard limit may be infinite, but the soft limit can't be set above OPEN_MAX
+    const OPEN_MAX: libc::rlim_t = 10240;
+
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    // SAFETY: limit is a valid pointer to an rlimit, which getrlimit fills on success
+    let rc = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) };
+    if rc != 0 {
+        return None;
+    }
+    // SAFETY: getrlimit succeeded, so limit is initialized
+    let mut limit = unsafe { limit.assume_init() };
+    let target = limit.rlim_max.min(OPEN_MAX);
+    if limit.rlim_cur < target {
+        let raised = libc::rlimit {
+            rlim_cur: target,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: raised is a valid rlimit
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            tracing::debug!(from = limit.rlim_cur, to = target, "raised open file limit");
+            limit = raised;
+        } else {
+            tracing::debug!(
+                "unable to raise open file limit: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+    Some(limit.rlim_cur)
+}
+
+fn compressor_thread_count() -> NonZeroUsize {
+    thread::available_parallelism().unwrap_or(NonZeroUsize::MIN)
+}
+
 impl Default for BackgroundThreads {
     fn default() -> Self {
         Self::new()
@@ -251,6 +1008,30 @@ impl Default for BackgroundThreads {
 
 trait WorkHandler<WorkItem> {
     fn handle_item(&mut self, item: WorkItem);
+
+    /// The file an item is for, so a panic handling it can be recorded on its operation
+    fn context(_item: &WorkItem) -> Option<&Arc<Context>> {
+        None
+    }
+}
+
+/// Records a panic on the operation of the item being handled
+///
+/// This holds a reference to the context, so the panic is recorded before the operation can
+/// finish, even though the item itself is dropped as the panic unwinds.
+struct PanicGuard(Arc<Context>);
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let thread = thread::current();
+            self.0.operation.fatal_error(format!(
+                "{} thread panicked while processing {}",
+                thread.name().unwrap_or("background"),
+                self.0.path.display()
+            ));
+        }
+    }
 }
 
 trait BgWork {
@@ -260,9 +1041,6 @@ trait BgWork {
     const NAME: &'static str;
 
     fn make_handler(&self) -> Self::Handler;
-    fn queue_capacity(&self) -> usize {
-        1
-    }
 }
 
 struct BgWorker<Work: BgWork> {
@@ -271,18 +1049,23 @@ struct BgWorker<Work: BgWork> {
 }
 
 impl<Work: BgWork> BgWorker<Work> {
-    pub fn new(thread_count: usize, work: &Work) -> Self {
-        assert!(thread_count > 0);
-
-        let (tx, rx) = crossbeam_channel::bounded(work.queue_capacity());
-        let threads: Vec<_> = (0..thread_count)
+    pub fn new(
+        thread_count: NonZeroUsize,
+        queue_capacity: NonZeroUsize,
+        pool: qos::Pool,
+        priority: &qos::SharedPriority,
+        work: &Work,
+    ) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(queue_capacity.get());
+        let threads: Vec<_> = (0..thread_count.get())
             .map(|i| {
                 let rx = rx.clone();
                 let handler = work.make_handler();
+                let priority = priority.clone();
 
                 thread::Builder::new()
                     .name(format!("{} {i}", Work::NAME))
-                    .spawn(move || handle_fn(rx, handler))
+                    .spawn(move || handle_fn(rx, handler, pool, &priority))
                     .unwrap()
             })
             .collect();
@@ -301,9 +1084,33 @@ impl<Work: BgWork> BgWorker<Work> {
 fn handle_fn<WorkItem, Handler: WorkHandler<WorkItem>>(
     rx: crossbeam_channel::Receiver<WorkItem>,
     mut handler: Handler,
+    pool: qos::Pool,
+    priority: &qos::SharedPriority,
 ) {
+    if priority.throttle() && pool == qos::Pool::Io {
+        if let Err(e) = qos::throttle_current_thread_io() {
+            warn!("unable to throttle thread I/O: {e}");
+        }
+    }
+    let mut applied = None;
     for item in rx {
-        handler.handle_item(item);
+        let class = priority.class_for(pool);
+        if applied != Some(class) {
+            if let Err(e) = qos::set_current_thread(class) {
+                warn!("unable to set thread QoS class to {class:?}: {e}");
+            }
+            applied = Some(class);
+        }
+        // A panic handling one item only fails that item's operation: the thread keeps handling
+        // items, so nothing is left stuck in its queue
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _panic_guard =
+                Handler::context(&item).map(|context| PanicGuard(Arc::clone(context)));
+            handler.handle_item(item);
+        }));
+        if res.is_err() {
+            warn!("recovered from a panic handling an item");
+        }
     }
 }
 
@@ -316,3 +1123,742 @@ impl fmt::Debug for Context {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Task;
+    use crate::testing::{
+        assert_entries_equal, populate_dir, recursive_read, ErrorProgress, ErrorTask, Fixture,
+        NoProgress,
+    };
+    use crate::{xattr, CompressionOptions, DecompressionOptions, FileCompressor};
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::time::Duration;
+
+    #[test]
+    fn open_file_limit_raised() {
+        let soft_limit = raise_open_file_limit().unwrap();
+        let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+        // SAFETY: limit is a valid pointer to an rlimit, which getrlimit fills on success
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) },
+            0
+        );
+        // SAFETY: getrlimit succeeded, so limit is initialized
+        let limit = unsafe { limit.assume_init() };
+        assert_eq!(limit.rlim_cur, soft_limit);
+        assert!(soft_limit >= limit.rlim_max.min(10240));
+        // Far more files than the default soft limit of 256 can be queued
+        assert!(open_file_limit() > 128);
+    }
+
+    #[test]
+    fn minimal_pipeline() {
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let old_contents = recursive_read(dir.path());
+
+        let one = NonZeroUsize::MIN;
+        let mut fc = FileCompressor::with_config(PipelineConfig {
+            reader_queue: one,
+            writer_queue: one,
+            compressor_queue: one,
+            reader_threads: one,
+            writer_threads: one,
+            compressor_threads: one,
+            // Smaller than a single block
+            byte_budget: Some(1024),
+            network_volume_files: Some(one),
+            local_volume_files: Some(one),
+            throttle: false,
+            uncached_file_size: None,
+        });
+        let stats = dir.compress_with(
+            &mut fc,
+            &Fixture::options().verify(Verify::Checksum),
+            &NoProgress,
+        );
+        assert!(stats.compressed_file_count_final.into_inner() > 0);
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+    }
+
+    #[test]
+    fn more_readers_than_writers() {
+        // Blocks of files queued for the only writer must not use the budget the file being
+        // written needs
+        let dir = Fixture::new();
+        let contents: Vec<u8> = (0..8 * applesauce_core::BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for i in 0..16 {
+            dir.file(format!("{i}"), &contents);
+        }
+
+        let one = NonZeroUsize::MIN;
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        let path = dir.path().to_path_buf();
+        let compressing = thread::spawn(move || {
+            let mut fc = FileCompressor::with_config(PipelineConfig {
+                reader_threads: NonZeroUsize::new(8).unwrap(),
+                writer_threads: one,
+                byte_budget: Some(applesauce_core::BLOCK_SIZE as u64),
+                ..PipelineConfig::default()
+            });
+            let stats = fc
+                .compress_with([path.as_path()], &Fixture::options(), &NoProgress)
+                .unwrap();
+            done_tx.send(stats).unwrap();
+        });
+        let stats = done_rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("compressing deadlocked");
+        compressing.join().unwrap();
+        assert_eq!(stats.compressed_file_count_final.into_inner(), 16);
+    }
+
+    #[test]
+    fn exact_block_multiples() {
+        let dir = Fixture::new();
+        for blocks in [1, 2, 64] {
+            let contents: Vec<u8> = (0..blocks * applesauce_core::BLOCK_SIZE)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            dir.file(format!("{blocks}"), contents);
+        }
+        let old_contents = recursive_read(dir.path());
+
+        let all_compressed = |compressed: bool| {
+            for blocks in [1, 2, 64] {
+                let path = dir.path().join(format!("{blocks}"));
+                assert_eq!(
+                    info::get(&path).unwrap().is_compressed,
+                    compressed,
+                    "{blocks}"
+                );
+            }
+        };
+
+        for kind in compressor::Kind::all()
+            .iter()
+            .copied()
+            .filter(|kind| kind.supported())
+        {
+            for manual in [true, false] {
+                let mut fc = FileCompressor::new();
+                let progress = ErrorProgress::default();
+                dir.compress_with(
+                    &mut fc,
+                    &Fixture::options().kind(kind).verify(Verify::Full),
+                    &progress,
+                );
+                all_compressed(true);
+                assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+
+                dir.decompress_with(
+                    &mut fc,
+                    &DecompressionOptions::new()
+                        .manual(manual)
+                        .verify(Verify::Full),
+                    &progress,
+                );
+                all_compressed(false);
+                assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+                assert_eq!(
+                    *progress.errors.lock().unwrap(),
+                    Vec::<String>::new(),
+                    "{kind}"
+                );
+            }
+        }
+    }
+
+    type Summaries = Arc<Mutex<Vec<(PathBuf, progress::FileSummary)>>>;
+
+    #[derive(Default)]
+    struct SummaryProgress {
+        summaries: Summaries,
+    }
+
+    struct SummaryTask {
+        path: PathBuf,
+        summaries: Summaries,
+    }
+
+    impl Task for SummaryTask {
+        fn increment(&self, _amt: u64) {}
+        fn error(&self, message: &str) {
+            panic!("Expected no errors, got {message} for {:?}", self.path);
+        }
+        fn finished(&self, summary: progress::FileSummary) {
+            self.summaries
+                .lock()
+                .unwrap()
+                .push((self.path.clone(), summary));
+        }
+    }
+
+    impl Progress for SummaryProgress {
+        type Task = SummaryTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_task(&self, path: &Path, _size: u64, _root: usize) -> Self::Task {
+            SummaryTask {
+                path: path.to_owned(),
+                summaries: Arc::clone(&self.summaries),
+            }
+        }
+    }
+
+    #[test]
+    fn capture_metadata() {
+        let dir = Fixture::new();
+        // Small enough to be stored in the decmpfs xattr
+        dir.file("small", [1; 1024]);
+        // Multiple blocks, stored in the resource fork
+        let big_content: Vec<u8> = (0..4 * applesauce_core::BLOCK_SIZE)
+            .map(|i| (i / 100) as u8)
+            .collect();
+        dir.file("big", big_content);
+
+        let progress = SummaryProgress::default();
+        let mut fc = FileCompressor::new();
+        fc.set_capture_metadata(true);
+        dir.compress(&mut fc, &progress);
+
+        let mut summaries = progress.summaries.lock().unwrap().clone();
+        summaries.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+        assert_eq!(summaries.len(), 2);
+        for (path, summary) in summaries {
+            let file = File::open(&path).unwrap();
+            let decmpfs_xattr = xattr::read(&file, applesauce_core::decmpfs::XATTR_NAME).unwrap();
+            assert!(decmpfs_xattr.is_some());
+            assert_eq!(summary.decmpfs_xattr, decmpfs_xattr);
+
+            let rfork = fs::read(path.join("..namedfork/rsrc")).unwrap();
+            if path.ends_with("small") {
+                assert!(rfork.is_empty());
+                assert_eq!(summary.resource_fork_len, None);
+                assert_eq!(summary.resource_fork_hash, None);
+            } else {
+                assert!(!rfork.is_empty());
+                assert_eq!(summary.resource_fork_len, Some(rfork.len() as u64));
+                let expected: [u8; 32] = blake3::hash(&rfork).into();
+                assert_eq!(summary.resource_fork_hash, Some(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn no_metadata_captured_by_default() {
+        let dir = Fixture::new();
+        dir.file("file", [1; 1024]);
+
+        let progress = SummaryProgress::default();
+        let mut fc = FileCompressor::new();
+        dir.compress(&mut fc, &progress);
+
+        let summaries = progress.summaries.lock().unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].1, progress::FileSummary::default());
+    }
+
+    /// Records the sizes of every task created, and every file skipped
+    #[derive(Default)]
+    struct TaskSizeProgress {
+        task_sizes: Mutex<Vec<(PathBuf, u64)>>,
+        skipped: Mutex<Vec<PathBuf>>,
+        scanned_bytes: AtomicU64,
+    }
+
+    impl Progress for TaskSizeProgress {
+        type Task = NoProgress;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_skipped(&self, path: &Path, why: SkipReason) {
+            assert!(matches!(why, SkipReason::Sparse), "{why:?}");
+            self.skipped.lock().unwrap().push(path.to_owned());
+        }
+
+        fn file_task(&self, path: &Path, size: u64, _root: usize) -> Self::Task {
+            self.task_sizes
+                .lock()
+                .unwrap()
+                .push((path.to_owned(), size));
+            NoProgress
+        }
+
+        fn scan_complete(&self, _total_files: u64, total_bytes: u64) {
+            self.scanned_bytes.store(total_bytes, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn no_task_for_file_skipped_after_opening() {
+        let dir = Fixture::new();
+        let ok_path = dir.file("ok", [0; 16 * 1024]);
+        // Sparse files are only found to be sparse once they are open
+        let skip_path = dir.path().join("sparse");
+        File::create(&skip_path)
+            .unwrap()
+            .set_len(64 * 1024 * 1024)
+            .unwrap();
+        let metadata = skip_path.metadata().unwrap();
+        if metadata.blocks() * 512 >= metadata.len() {
+            // The filesystem doesn't support sparse files
+            return;
+        }
+
+        let progress = TaskSizeProgress::default();
+        let mut fc = FileCompressor::new();
+        dir.compress(&mut fc, &progress);
+
+        assert_eq!(
+            progress.skipped.into_inner().unwrap(),
+            std::slice::from_ref(&skip_path)
+        );
+        assert_eq!(
+            progress.task_sizes.into_inner().unwrap(),
+            [(ok_path.clone(), 16 * 1024)]
+        );
+        assert_eq!(progress.scanned_bytes.into_inner(), 16 * 1024);
+        assert!(info::get(&ok_path).unwrap().is_compressed);
+        assert!(!info::get(&skip_path).unwrap().is_compressed);
+    }
+
+    type RootTotals = Arc<Mutex<std::collections::BTreeMap<usize, u64>>>;
+
+    /// Records the roots, and the progress made for files under each root
+    #[derive(Default)]
+    struct RootsProgress {
+        roots: Mutex<Vec<PathBuf>>,
+        totals: RootTotals,
+    }
+
+    struct RootTask {
+        root: usize,
+        totals: RootTotals,
+    }
+
+    impl Task for RootTask {
+        fn increment(&self, amt: u64) {
+            *self.totals.lock().unwrap().entry(self.root).or_default() += amt;
+        }
+
+        fn error(&self, message: &str) {
+            panic!("Expected no errors, got {message}");
+        }
+    }
+
+    impl Progress for RootsProgress {
+        type Task = RootTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn roots(&self, roots: &[&Path]) {
+            let mut recorded = self.roots.lock().unwrap();
+            assert!(recorded.is_empty(), "roots should only be reported once");
+            recorded.extend(roots.iter().map(|root| root.to_path_buf()));
+        }
+
+        fn file_task(&self, path: &Path, _size: u64, root: usize) -> Self::Task {
+            let roots = self.roots.lock().unwrap();
+            assert!(path.starts_with(&roots[root]), "{path:?} not in {root}");
+            RootTask {
+                root,
+                totals: Arc::clone(&self.totals),
+            }
+        }
+    }
+
+    #[test]
+    fn progress_attributed_to_roots() {
+        let dir = Fixture::new();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        let single_file = dir.path().join("single_file");
+        fs::create_dir_all(first.join("nested")).unwrap();
+        fs::create_dir(&second).unwrap();
+        fs::write(first.join("a"), [0; 16 * 1024]).unwrap();
+        fs::write(first.join("nested/b"), [0; 100 * 1024]).unwrap();
+        fs::write(second.join("c"), [0; 32 * 1024]).unwrap();
+        fs::write(&single_file, [0; 8 * 1024]).unwrap();
+        let missing = dir.path().join("missing");
+
+        let progress = RootsProgress::default();
+        let mut fc = FileCompressor::new();
+        fc.compress_with(
+            [
+                first.as_path(),
+                &missing,
+                second.as_path(),
+                single_file.as_path(),
+            ],
+            &Fixture::options(),
+            &progress,
+        )
+        .unwrap();
+
+        // Paths which don't exist aren't roots, so indexes are into the paths which do
+        assert_eq!(
+            progress.roots.into_inner().unwrap(),
+            [first, second, single_file]
+        );
+        assert_eq!(
+            *progress.totals.lock().unwrap(),
+            [(0, 116 * 1024), (1, 32 * 1024), (2, 8 * 1024)].into()
+        );
+    }
+
+    type FileTotals = Arc<Mutex<std::collections::BTreeMap<PathBuf, (u64, u64)>>>;
+
+    /// Records the size of each file's task, and the total progress made for it
+    #[derive(Default)]
+    struct IncrementProgress {
+        totals: FileTotals,
+    }
+
+    struct IncrementTask {
+        path: PathBuf,
+        totals: FileTotals,
+    }
+
+    impl Task for IncrementTask {
+        fn increment(&self, amt: u64) {
+            self.totals.lock().unwrap().get_mut(&self.path).unwrap().1 += amt;
+        }
+
+        fn error(&self, message: &str) {
+            panic!("Expected no errors, got {message} for {:?}", self.path);
+        }
+    }
+
+    impl Progress for IncrementProgress {
+        type Task = IncrementTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_task(&self, path: &Path, size: u64, _root: usize) -> Self::Task {
+            let previous = self
+                .totals
+                .lock()
+                .unwrap()
+                .insert(path.to_owned(), (size, 0));
+            assert_eq!(previous, None, "{path:?} processed twice");
+            IncrementTask {
+                path: path.to_owned(),
+                totals: Arc::clone(&self.totals),
+            }
+        }
+    }
+
+    impl IncrementProgress {
+        /// Check every file in `dir` had a task, and made exactly its size in progress
+        fn assert_complete(self, dir: &Path, file_count: usize) {
+            let totals = self.totals.lock().unwrap();
+            assert_eq!(totals.len(), file_count);
+            for (path, &(size, total)) in totals.iter() {
+                assert!(path.starts_with(dir));
+                assert_eq!(size, fs::metadata(path).unwrap().len(), "{path:?}");
+                assert_eq!(total, size, "{path:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn progress_adds_up_to_file_sizes() {
+        let dir = Fixture::new();
+        let block_size = applesauce_core::BLOCK_SIZE;
+        let sizes = [
+            1000,
+            block_size - 1,
+            block_size,
+            block_size + 1,
+            5 * block_size / 2,
+        ];
+        for size in sizes {
+            let contents: Vec<u8> = (0..size).map(|i| (i % 7) as u8).collect();
+            dir.file(format!("{size}"), contents);
+        }
+        let old_contents = recursive_read(dir.path());
+
+        for manual in [true, false] {
+            let mut fc = FileCompressor::new();
+            let progress = IncrementProgress::default();
+            let stats = dir.compress(&mut fc, &progress);
+            assert_eq!(
+                stats.compressed_file_count_final.into_inner(),
+                sizes.len() as u64
+            );
+            progress.assert_complete(dir.path(), sizes.len());
+
+            let progress = IncrementProgress::default();
+            let stats = dir.decompress_with(
+                &mut fc,
+                &DecompressionOptions::new().manual(manual),
+                &progress,
+            );
+            assert_eq!(stats.compressed_file_count_final.into_inner(), 0);
+            progress.assert_complete(dir.path(), sizes.len());
+            assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+        }
+    }
+
+    /// Replaces each file with an impostor as soon as the scan has found it
+    struct SwapProgress {
+        impostor: PathBuf,
+        skipped: Mutex<Vec<PathBuf>>,
+    }
+
+    impl Progress for SwapProgress {
+        type Task = NoProgress;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+            NoProgress
+        }
+
+        fn found_file(&self, path: &Path, _size: u64) {
+            fs::rename(&self.impostor, path).unwrap();
+        }
+
+        fn file_skipped(&self, path: &Path, why: SkipReason) {
+            assert!(matches!(why, SkipReason::FileModified), "{why:?}");
+            self.skipped.lock().unwrap().push(path.to_owned());
+        }
+    }
+
+    #[test]
+    fn replaced_after_scan() {
+        let dir = Fixture::new();
+        let path = dir.file("file", [1; 64 * 1024]);
+        let impostor_dir = Fixture::new();
+        let impostor = impostor_dir.path().join("impostor");
+        fs::write(&impostor, [2; 32 * 1024]).unwrap();
+
+        let progress = SwapProgress {
+            impostor,
+            skipped: Mutex::new(Vec::new()),
+        };
+        let mut fc = FileCompressor::new();
+        let stats = dir.compress(&mut fc, &progress);
+
+        // The impostor is left alone, rather than being replaced by the compressed original
+        assert_eq!(
+            progress.skipped.into_inner().unwrap(),
+            std::slice::from_ref(&path)
+        );
+        assert_eq!(stats.skipped.file_modified.into_inner(), 1);
+        assert_eq!(fs::read(&path).unwrap(), [2; 32 * 1024]);
+        assert!(!info::get(&path).unwrap().is_compressed);
+    }
+
+    #[test]
+    fn read_only_dir_unchanged() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = Fixture::new();
+        let read_only_dir = dir.path().join("read_only");
+        fs::create_dir(&read_only_dir).unwrap();
+        let path = read_only_dir.join("file");
+        fs::write(&path, [0; 16 * 1024]).unwrap();
+        let set_mode = |mode| {
+            fs::set_permissions(&read_only_dir, fs::Permissions::from_mode(mode)).unwrap();
+        };
+
+        // The new file can be written, but can't replace the original
+        set_mode(0o555);
+        let mut fc = FileCompressor::new();
+        dir.compress(&mut fc, &NoProgress);
+        assert!(!info::get(&path).unwrap().is_compressed);
+        assert_eq!(fs::read(&path).unwrap(), [0; 16 * 1024]);
+
+        set_mode(0o755);
+        dir.compress(&mut fc, &NoProgress);
+        assert!(info::get(&path).unwrap().is_compressed);
+
+        set_mode(0o555);
+        dir.decompress(&mut fc, &NoProgress);
+        assert!(info::get(&path).unwrap().is_compressed);
+        assert_eq!(fs::read(&path).unwrap(), [0; 16 * 1024]);
+        set_mode(0o755);
+    }
+
+    #[test]
+    fn throttled_round_trip() {
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let old_contents = recursive_read(dir.path());
+
+        let mut fc = FileCompressor::with_config(PipelineConfig {
+            throttle: true,
+            ..PipelineConfig::default()
+        });
+        let stats = dir.compress_with(
+            &mut fc,
+            &Fixture::options().verify(Verify::Quick),
+            &ErrorProgress::default(),
+        );
+        assert!(stats.compressed_file_count_final.into_inner() > 0);
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+
+        dir.decompress_with(
+            &mut fc,
+            &DecompressionOptions::new()
+                .manual(true)
+                .verify(Verify::Quick),
+            &ErrorProgress::default(),
+        );
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+    }
+
+    /// Panics on the writer thread when progress is made on one path
+    struct PanicProgress {
+        panic_path: PathBuf,
+        errors: ErrorProgress,
+    }
+
+    struct PanicTask {
+        path: Option<PathBuf>,
+        task: ErrorTask,
+    }
+
+    impl Task for PanicTask {
+        fn increment(&self, _amt: u64) {
+            if let Some(path) = &self.path {
+                panic!("panic handling {}", path.display());
+            }
+        }
+        fn error(&self, message: &str) {
+            self.task.error(message);
+        }
+    }
+
+    impl Progress for PanicProgress {
+        type Task = PanicTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            self.errors.error(path, message);
+        }
+
+        fn fatal_error(&self, message: &str) {
+            self.errors.fatal_error(message);
+        }
+
+        fn file_task(&self, path: &Path, size: u64, root: usize) -> Self::Task {
+            PanicTask {
+                path: (path == self.panic_path).then(|| path.to_owned()),
+                task: self.errors.file_task(path, size, root),
+            }
+        }
+    }
+
+    #[test]
+    fn worker_panic_stops_run() {
+        let dir = Fixture::new();
+        for i in 0..4 {
+            dir.file(format!("{i}"), [0; 16 * 1024]);
+        }
+        let panic_path = dir.path().join("0");
+
+        let mut fc = FileCompressor::with_config(PipelineConfig {
+            writer_threads: NonZeroUsize::MIN,
+            ..PipelineConfig::default()
+        });
+        let progress = PanicProgress {
+            panic_path: panic_path.clone(),
+            errors: ErrorProgress::default(),
+        };
+        let stats = dir.compress(&mut fc, &progress);
+
+        let fatal_error = stats.fatal_error.unwrap();
+        assert!(fatal_error.contains("writer"), "{fatal_error}");
+        assert!(
+            fatal_error.contains(&panic_path.display().to_string()),
+            "{fatal_error}"
+        );
+        assert!(progress
+            .errors
+            .errors
+            .lock()
+            .unwrap()
+            .contains(&fatal_error));
+        assert!(!info::get(&panic_path).unwrap().is_compressed);
+        assert_eq!(fs::read(&panic_path).unwrap(), [0; 16 * 1024]);
+
+        // The thread survived the panic, and the next run is unaffected
+        let stats = dir.compress(&mut fc, &NoProgress);
+        assert_eq!(stats.fatal_error, None);
+        assert!(info::get(&panic_path).unwrap().is_compressed);
+    }
+
+    #[test]
+    fn unusual_file_names() {
+        use std::ffi::OsStr;
+
+        let dir = Fixture::new();
+        let contents = [0; 16 * 1024];
+        let control_chars = dir.file("line\nbreak\ttab", contents);
+        let mut paths = vec![control_chars];
+        let invalid_utf8 = dir.path().join(OsStr::from_bytes(b"\xff\xfe"));
+        match fs::write(&invalid_utf8, contents) {
+            Ok(()) => paths.push(invalid_utf8),
+            // APFS and HFS+ only allow names which are valid UTF-8
+            Err(e) if e.raw_os_error() == Some(libc::EILSEQ) => {}
+            Err(e) => panic!("{e}"),
+        }
+
+        let progress = ErrorProgress::default();
+        let mut fc = FileCompressor::new();
+        let stats = fc
+            .compress_with([dir.path()], &CompressionOptions::new(), &progress)
+            .unwrap();
+        assert_eq!(stats.file_errors.into_inner(), 0);
+        assert!(progress.errors.lock().unwrap().is_empty());
+        for path in &paths {
+            assert!(info::get(path).unwrap().is_compressed, "{path:?}");
+            assert_eq!(fs::read(path).unwrap(), contents);
+        }
+    }
+
+    #[test]
+    fn missing_roots_reported() {
+        let dir = Fixture::new();
+        let path = dir.file("file", [0; 16 * 1024]);
+        let missing = dir.path().join("missing");
+
+        let progress = ErrorProgress::default();
+        let mut fc = FileCompressor::new();
+        let stats = fc
+            .compress_with(
+                [missing.as_path(), path.as_path()],
+                &CompressionOptions::new(),
+                &progress,
+            )
+            .unwrap();
+
+        assert_eq!(stats.fatal_error, None);
+        assert_eq!(stats.missing_roots.into_inner(), 1);
+        assert_eq!(stats.file_errors.into_inner(), 0);
+        assert_eq!(progress.errors.lock().unwrap().len(), 1);
+        let logged = stats.errors.entries();
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].path, missing);
+        // The paths which exist are still processed
+        assert!(info::get(&path).unwrap().is_compressed);
+    }
+}
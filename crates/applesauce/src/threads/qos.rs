@@ -0,0 +1,311 @@
+//! Scheduling priority of the background threads
+//!
+//! Readers and writers need low latency to keep the disks busy, so compressor threads run at a
+//! lower QoS class than readers and writers. Otherwise, when there is more work than cores,
+//! readers and writers are scheduled behind long compression quanta.
+//!
+//! Throttled threads (see [`PipelineConfig::throttle`](super::PipelineConfig::throttle)) ignore
+//! the priority: every thread runs at the background QoS class, and readers and writers have
+//! their disk I/O throttled, so they back off whenever other processes use the disk.
+
+use crate::Priority;
+use libc::c_int;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The kind of work done by a pool of background threads
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) enum Pool {
+    /// Reading or writing files
+    Io,
+    /// Compressing blocks
+    Compressor,
+}
+
+/// A macOS QoS class, ordered from lowest to highest priority
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum QosClass {
+    Background,
+    Utility,
+    UserInitiated,
+}
+
+impl QosClass {
+    pub fn for_pool(pool: Pool, priority: Priority) -> Self {
+        match (priority, pool) {
+            (Priority::Normal, Pool::Io) => Self::UserInitiated,
+            (Priority::Normal, Pool::Compressor) | (Priority::Background, Pool::Io) => {
+                Self::Utility
+            }
+            (Priority::Background, Pool::Compressor) => Self::Background,
+        }
+    }
+
+    fn to_raw(self) -> libc::qos_class_t {
+        match self {
+            Self::Background => libc::qos_class_t::QOS_CLASS_BACKGROUND,
+            Self::Utility => libc::qos_class_t::QOS_CLASS_UTILITY,
+            Self::UserInitiated => libc::qos_class_t::QOS_CLASS_USER_INITIATED,
+        }
+    }
+
+    #[cfg(test)]
+    fn from_raw(raw: libc::qos_class_t) -> Option<Self> {
+        match raw {
+            libc::qos_class_t::QOS_CLASS_BACKGROUND => Some(Self::Background),
+            libc::qos_class_t::QOS_CLASS_UTILITY => Some(Self::Utility),
+            libc::qos_class_t::QOS_CLASS_USER_INITIATED => Some(Self::UserInitiated),
+            _ => None,
+        }
+    }
+}
+
+/// The priority of all pools, which can be changed while the threads are running
+///
+/// Each thread checks the priority before handling each item, so a change applies from the next
+/// item handled.
+#[derive(Debug, Clone, Default)]
+pub(super) struct SharedPriority {
+    background: Arc<AtomicBool>,
+    /// Fixed when the threads are created
+    throttle: bool,
+}
+
+impl SharedPriority {
+    pub fn new(throttle: bool) -> Self {
+        Self {
+            background: Arc::default(),
+            throttle,
+        }
+    }
+
+    pub fn throttle(&self) -> bool {
+        self.throttle
+    }
+
+    /// The QoS class for threads in `pool`
+    pub fn class_for(&self, pool: Pool) -> QosClass {
+        if self.throttle {
+            QosClass::Background
+        } else {
+            QosClass::for_pool(pool, self.get())
+        }
+    }
+
+    pub fn get(&self) -> Priority {
+        if self.background.load(Ordering::Relaxed) {
+            Priority::Background
+        } else {
+            Priority::Normal
+        }
+    }
+
+    pub fn set(&self, priority: Priority) {
+        self.background
+            .store(priority == Priority::Background, Ordering::Relaxed);
+    }
+}
+
+/// Set the QoS class of the calling thread
+pub(super) fn set_current_thread(class: QosClass) -> io::Result<()> {
+    // SAFETY: only affects the calling thread
+    let rc = unsafe { libc::pthread_set_qos_class_self_np(class.to_raw(), 0) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    Ok(())
+}
+
+// From <sys/resource.h>, not exposed by libc
+const IOPOL_TYPE_DISK: c_int = 0;
+const IOPOL_SCOPE_THREAD: c_int = 1;
+const IOPOL_THROTTLE: c_int = 3;
+
+extern "C" {
+    fn setiopolicy_np(iotype: c_int, scope: c_int, policy: c_int) -> c_int;
+    #[cfg(test)]
+    fn getiopolicy_np(iotype: c_int, scope: c_int) -> c_int;
+}
+
+/// Throttle the disk I/O of the calling thread
+///
+/// Throttled I/O is delayed while other processes are using the same disk.
+pub(super) fn throttle_current_thread_io() -> io::Result<()> {
+    // SAFETY: only affects the calling thread
+    let rc = unsafe { setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_THREAD, IOPOL_THROTTLE) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns true if the disk I/O of the calling thread is throttled
+#[cfg(test)]
+fn current_thread_io_throttled() -> io::Result<bool> {
+    // SAFETY: only reads the policy of the calling thread
+    let rc = unsafe { getiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_THREAD) };
+    if rc == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(rc == IOPOL_THROTTLE)
+}
+
+/// Get the QoS class of the calling thread
+///
+/// Returns `None` if the thread has a class not used by applesauce
+#[cfg(test)]
+pub(super) fn current_thread() -> io::Result<Option<QosClass>> {
+    let mut raw = libc::qos_class_t::QOS_CLASS_UNSPECIFIED;
+    // SAFETY: pthread_self is always a valid thread, and raw is a valid pointer to write to
+    let rc = unsafe {
+        libc::pthread_get_qos_class_np(libc::pthread_self(), &mut raw, std::ptr::null_mut())
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    Ok(QosClass::from_raw(raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{compressed_files, Fixture, NoProgress};
+    use crate::threads::{BgWork, BgWorker, WorkHandler};
+    use crate::{FileCompressor, Verify};
+    use std::fs;
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
+
+    struct Probe;
+
+    type Reply = crossbeam_channel::Sender<(Option<QosClass>, bool)>;
+
+    impl BgWork for Probe {
+        type Item = Reply;
+        type Handler = Probe;
+        const NAME: &'static str = "probe";
+
+        fn make_handler(&self) -> Self::Handler {
+            Probe
+        }
+    }
+
+    impl WorkHandler<Reply> for Probe {
+        fn handle_item(&mut self, reply: Reply) {
+            reply
+                .send((
+                    current_thread().unwrap(),
+                    current_thread_io_throttled().unwrap(),
+                ))
+                .unwrap();
+        }
+    }
+
+    fn probe(worker: &BgWorker<Probe>) -> Option<QosClass> {
+        probe_throttled(worker).0
+    }
+
+    fn probe_throttled(worker: &BgWorker<Probe>) -> (Option<QosClass>, bool) {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        worker.chan().send(tx).unwrap();
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn compressors_below_io() {
+        for priority in [Priority::Normal, Priority::Background] {
+            assert!(
+                QosClass::for_pool(Pool::Compressor, priority)
+                    < QosClass::for_pool(Pool::Io, priority)
+            );
+        }
+        assert!(
+            QosClass::for_pool(Pool::Io, Priority::Background)
+                < QosClass::for_pool(Pool::Io, Priority::Normal)
+        );
+    }
+
+    #[test]
+    fn pools_apply_class() {
+        for priority in [Priority::Normal, Priority::Background] {
+            for pool in [Pool::Io, Pool::Compressor] {
+                let shared = SharedPriority::default();
+                shared.set(priority);
+                let worker =
+                    BgWorker::new(NonZeroUsize::MIN, NonZeroUsize::MIN, pool, &shared, &Probe);
+                assert_eq!(probe(&worker), Some(QosClass::for_pool(pool, priority)));
+            }
+        }
+    }
+
+    #[test]
+    fn throttled_pools() {
+        for pool in [Pool::Io, Pool::Compressor] {
+            let shared = SharedPriority::new(true);
+            // Throttling overrides the priority
+            shared.set(Priority::Normal);
+            let worker = BgWorker::new(NonZeroUsize::MIN, NonZeroUsize::MIN, pool, &shared, &Probe);
+            assert_eq!(
+                probe_throttled(&worker),
+                (Some(QosClass::Background), pool == Pool::Io)
+            );
+        }
+
+        let worker = BgWorker::new(
+            NonZeroUsize::MIN,
+            NonZeroUsize::MIN,
+            Pool::Io,
+            &SharedPriority::default(),
+            &Probe,
+        );
+        assert!(!probe_throttled(&worker).1);
+    }
+
+    #[test]
+    fn priority_change_applies_to_running_threads() {
+        let shared = SharedPriority::default();
+        let worker = BgWorker::new(
+            NonZeroUsize::MIN,
+            NonZeroUsize::MIN,
+            Pool::Io,
+            &shared,
+            &Probe,
+        );
+        assert_eq!(probe(&worker), Some(QosClass::UserInitiated));
+        shared.set(Priority::Background);
+        assert_eq!(probe(&worker), Some(QosClass::Utility));
+        shared.set(Priority::Normal);
+        assert_eq!(probe(&worker), Some(QosClass::UserInitiated));
+    }
+
+    #[test]
+    fn each_priority() {
+        for priority in [Priority::Normal, Priority::Background] {
+            let dir = Fixture::new();
+            for name in ["a", "b", "c"] {
+                dir.file(name, [0; 16 * 1024]);
+            }
+
+            let mut fc = FileCompressor::new();
+            fc.set_priority(priority);
+            let stats = dir.compress_with(
+                &mut fc,
+                &Fixture::options().verify(Verify::Checksum),
+                &NoProgress,
+            );
+            assert_eq!(
+                stats.compressed_file_count_final.into_inner(),
+                3,
+                "{priority:?}"
+            );
+            assert_eq!(
+                compressed_files(dir.path()),
+                [PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")]
+            );
+            for name in ["a", "b", "c"] {
+                assert_eq!(fs::read(dir.path().join(name)).unwrap(), [0; 16 * 1024]);
+            }
+        }
+    }
+}
@@ -1,11 +1,19 @@
+use crate::progress::SkipReason;
 use crate::seq_queue::Slot;
+use crate::threads::budget::{Budget, Permit};
+use crate::threads::buffers::BufferPool;
+use crate::threads::cache::CacheHints;
 use crate::threads::{compressing, writer, BgWork, Context, Mode, WorkHandler};
-use crate::{rfork_storage, seq_queue, try_read_all};
+use crate::{rfork_storage, seq_queue, set_flags, times, try_read_all_at, xattr, Verify};
+use applesauce_core::decmpfs::{self, CompressionType};
 use applesauce_core::BLOCK_SIZE;
-use std::fs::File;
+use sha2::{Digest, Sha256};
+use std::fs::{File, Metadata};
 use std::num::NonZeroUsize;
+use std::os::macos::fs::MetadataExt as _;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::{io, thread};
+use std::{cmp, error, fmt, io, thread};
 
 pub(super) struct WorkItem {
     pub context: Arc<Context>,
@@ -14,6 +22,17 @@ pub(super) struct WorkItem {
 pub(super) struct Work {
     pub compressor: compressing::Sender,
     pub writer: writer::Sender,
+    /// One permit for each writer thread, held by each file sent to the writer until it is
+    /// written
+    ///
+    /// Files are only read once a writer is free to write them, so the blocks holding the byte
+    /// `budget` are always being drained. Otherwise, with fewer writers than readers (or a small
+    /// budget), blocks of files still queued for the writer could use the whole budget, while the
+    /// reader of the file being written waits for budget to read its next block.
+    pub writer_slots: Arc<Budget>,
+    pub budget: Arc<Budget>,
+    pub buffers: Arc<BufferPool>,
+    pub cache: CacheHints,
 }
 
 impl BgWork for Work {
@@ -22,36 +41,61 @@ impl BgWork for Work {
     const NAME: &'static str = "reader";
 
     fn make_handler(&self) -> Self::Handler {
-        Handler::new(self.compressor.clone(), self.writer.clone())
-    }
-
-    fn queue_capacity(&self) -> usize {
-        // Allow quite a few queued up paths, to allow the total progress bar to be accurate
-        100 * 1024
+        Handler::new(
+            self.compressor.clone(),
+            self.writer.clone(),
+            Arc::clone(&self.writer_slots),
+            Arc::clone(&self.budget),
+            Arc::clone(&self.buffers),
+            self.cache,
+        )
     }
 }
 
 pub(super) struct Handler {
     compressor: compressing::Sender,
     writer: writer::Sender,
+    writer_slots: Arc<Budget>,
+    budget: Arc<Budget>,
+    buffers: Arc<BufferPool>,
+    cache: CacheHints,
 }
 
 impl Handler {
-    fn new(compressor: compressing::Sender, writer: writer::Sender) -> Self {
-        Self { compressor, writer }
+    fn new(
+        compressor: compressing::Sender,
+        writer: writer::Sender,
+        writer_slots: Arc<Budget>,
+        budget: Arc<Budget>,
+        buffers: Arc<BufferPool>,
+        cache: CacheHints,
+    ) -> Self {
+        Self {
+            compressor,
+            writer,
+            writer_slots,
+            budget,
+            buffers,
+            cache,
+        }
     }
 
     fn read_file_into(
         &mut self,
         context: &Arc<Context>,
         file: &File,
-        expected_len: u64,
+        orig_metadata: &Metadata,
         tx: &seq_queue::Sender<writer::Chunk, io::Error>,
+        mut hasher: Option<&mut Sha256>,
     ) -> io::Result<()> {
         match context.operation.mode {
             Mode::Compress { kind, .. } => {
                 let compressor = self.compressor.clone();
-                self.with_file_chunks(file, expected_len, tx, |slot, data| {
+                self.with_file_chunks(file, orig_metadata, tx, |slot, data, permit| {
+                    context.operation.stats.add_read(data.len());
+                    if let Some(hasher) = hasher.as_deref_mut() {
+                        hasher.update(&data);
+                    }
                     let _enter = tracing::debug_span!("waiting to send to compressor").entered();
                     compressor
                         .send(compressing::WorkItem {
@@ -59,60 +103,133 @@ impl Handler {
                             data,
                             slot,
                             kind,
+                            permit,
                         })
-                        .unwrap();
-                    Ok(())
+                        .map_err(|_| context.operation.threads_stopped("compressor"))
                 })?;
             }
             Mode::DecompressManually => {
-                rfork_storage::with_compressed_blocks(file, |kind| {
+                let (budget, compressor, buffers) = (&self.budget, &self.compressor, &self.buffers);
+                let res = rfork_storage::with_compressed_blocks(file, |kind| {
                     move |data| {
                         // TODO: This waits for a slot after we have already read.
                         // TODO: This should be able to exit early, without an error
-                        let slot = tx.prepare_send().ok_or_else(|| {
+                        // Each block is queued decompressed, which is at most a whole block
+                        let slot = tx.prepare_send(BLOCK_SIZE as u64).ok_or_else(|| {
                             io::Error::new(io::ErrorKind::Other, "error must have occurred writing")
                         })?;
+                        context.operation.stats.add_read(data.len());
+                        let permit = {
+                            let _enter = tracing::debug_span!("waiting for byte budget").entered();
+                            budget.acquire(data.len() as u64)
+                        };
+                        let mut buf = buffers.get(data.len());
+                        buf.copy_from_slice(data);
                         let _enter =
                             tracing::debug_span!("waiting to send to compressor").entered();
-                        self.compressor
+                        compressor
                             .send(compressing::WorkItem {
                                 context: Arc::clone(context),
-                                data: data.to_vec(),
+                                data: buf,
                                 slot,
                                 kind,
+                                permit,
                             })
-                            .unwrap();
-                        Ok(())
+                            .map_err(|_| context.operation.threads_stopped("compressor"))
                     }
-                })?;
+                });
+                match res {
+                    Err(e) if !context.operation.strict_manual => {
+                        let Some(compression_type) = rfork_storage::unsupported_type(&e) else {
+                            return Err(e);
+                        };
+                        self.read_decompressed_by_os(
+                            context,
+                            file,
+                            orig_metadata,
+                            tx,
+                            compression_type,
+                        )?;
+                    }
+                    res => res?,
+                }
             }
             Mode::DecompressByReading => {
-                self.with_file_chunks(file, expected_len, tx, |slot, data| {
-                    let orig_size = data.len() as u64;
-                    let res = slot.finish(writer::Chunk {
-                        block: data,
-                        orig_size,
-                    });
-                    if let Err(e) = res {
-                        // This should only happen if the writer had an error
-                        tracing::debug!("error finishing chunk: {e}");
-                    }
-                    Ok(())
-                })?;
+                self.read_uncompressed_into(context, file, orig_metadata, tx)?;
             }
         }
 
         Ok(())
     }
 
+    /// Send the contents of the file to the writer, as read
+    ///
+    /// Reading a compressed file returns its decompressed contents, so this is how files are
+    /// decompressed by the OS.
+    fn read_uncompressed_into(
+        &mut self,
+        context: &Context,
+        file: &File,
+        orig_metadata: &Metadata,
+        tx: &seq_queue::Sender<writer::Chunk, io::Error>,
+    ) -> io::Result<()> {
+        self.with_file_chunks(file, orig_metadata, tx, |slot, data, permit| {
+            context.operation.stats.add_read(data.len());
+            let orig_size = data.len() as u64;
+            let res = slot.finish(writer::Chunk {
+                block: data,
+                orig_size,
+                permit,
+            });
+            if let Err(e) = res {
+                // This should only happen if the writer had an error
+                tracing::debug!("error finishing chunk: {e}");
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Decompress a file applesauce can't decompress manually, by letting the OS decompress it
+    fn read_decompressed_by_os(
+        &mut self,
+        context: &Context,
+        file: &File,
+        orig_metadata: &Metadata,
+        tx: &seq_queue::Sender<writer::Chunk, io::Error>,
+        compression_type: CompressionType,
+    ) -> io::Result<()> {
+        // If the OS can't decompress this type either, reading any of the file fails
+        let mut probe = [0; 1];
+        try_read_all_at(file, &mut probe, 0).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "compression type {compression_type} is not supported by applesauce, \
+                     or by the OS: {e}"
+                ),
+            )
+        })?;
+        context
+            .operation
+            .stats
+            .decompressed_by_os
+            .fetch_add(1, Ordering::Relaxed);
+        context
+            .progress
+            .decompressing_by_os(&context.path, compression_type);
+        self.read_uncompressed_into(context, file, orig_metadata, tx)
+    }
+
     // return true if reading succeeded, false if the writer closed the channel
     fn with_file_chunks(
         &mut self,
         file: &File,
-        expected_len: u64,
+        orig_metadata: &Metadata,
         tx: &seq_queue::Sender<writer::Chunk, io::Error>,
-        mut f: impl FnMut(Slot<writer::Chunk, io::Error>, Vec<u8>) -> io::Result<()>,
+        mut f: impl FnMut(Slot<writer::Chunk, io::Error>, Vec<u8>, Permit) -> io::Result<()>,
     ) -> io::Result<bool> {
+        let expected_len = orig_metadata.len();
         let mut total_read = 0;
         let block_span = tracing::debug_span!("reading blocks");
         loop {
@@ -120,100 +237,464 @@ impl Handler {
 
             // make sure we don't reserve a slot if we won't be sending a chunk
             if total_read == expected_len {
-                let mut buf = [0];
-                let n = try_read_all(file, &mut buf)?;
-                total_read += u64::try_from(n).unwrap();
-                // Outside the loop, we'll error if we read more than expected_len
+                // Rather than trying to read past the end, check if the file grew (or was
+                // otherwise modified) with the fd we already have open
+                ensure_unchanged(file, orig_metadata)?;
                 break;
             }
 
+            // Never read past the expected end of the file
+            let block_len = cmp::min(expected_len - total_read, BLOCK_SIZE as u64);
+
             let slot = {
                 let _enter = tracing::debug_span!("waiting for free slot").entered();
-                match tx.prepare_send() {
+                match tx.prepare_send(block_len) {
                     Some(slot) => slot,
                     None => return Ok(false),
                 }
             };
 
-            #[allow(clippy::uninit_vec)]
-            // SAFETY: we just allocated this with capacity, and we will truncate it before
-            //         allowing it to escape. This is not technically safe, but there's no
-            //         io api that lets us use an uninit buffer yet. However, file is a
-            //         std::io::File, which won't do wonky things in read, and won't lie about
-            //         the return value.
-            let buf = unsafe {
-                let mut buf = Vec::with_capacity(BLOCK_SIZE);
-                buf.set_len(BLOCK_SIZE);
-
-                let n = try_read_all(file, &mut buf)?;
-                if n == 0 {
-                    break;
-                }
-                total_read += u64::try_from(n).unwrap();
-                if total_read > expected_len {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "file size changed while reading",
-                    ));
-                }
-                buf.truncate(n);
-                buf
+            let permit = {
+                let _enter = tracing::debug_span!("waiting for byte budget").entered();
+                self.budget.acquire(BLOCK_SIZE as u64)
             };
 
-            f(slot, buf)?;
+            let mut buf = self.buffers.get(usize::try_from(block_len).unwrap());
+            let n = try_read_all_at(file, &mut buf, total_read)?;
+            if n == 0 {
+                break;
+            }
+            total_read += u64::try_from(n).unwrap();
+            buf.truncate(n);
+
+            f(slot, buf, permit)?;
         }
         if total_read != expected_len {
             // The writer will be notified by returning an error
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "file size changed while reading",
-            ));
+            return Err(file_changed_error());
         }
         Ok(true)
     }
 }
 
-impl WorkHandler<WorkItem> for Handler {
-    fn handle_item(&mut self, item: WorkItem) {
-        let WorkItem { context } = item;
-        let _guard = tracing::info_span!("reading file", path=%context.path.display()).entered();
-        let file = match File::open(&context.path) {
-            Ok(file) => file,
-            Err(e) => {
-                context
-                    .progress
-                    .error(&format!("Error opening {}: {}", context.path.display(), e));
-                return;
+/// The file was modified while it was being read
+#[derive(Debug)]
+struct FileChanged;
+
+impl fmt::Display for FileChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("file changed while reading")
+    }
+}
+
+impl error::Error for FileChanged {}
+
+fn file_changed_error() -> io::Error {
+    io::Error::other(FileChanged)
+}
+
+pub(super) fn is_file_changed(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<FileChanged>())
+}
+
+/// Ensure the open file still has the same size and modification time as when reading started
+pub(crate) fn ensure_unchanged(file: &File, orig_metadata: &Metadata) -> io::Result<()> {
+    let metadata = file.metadata()?;
+    if metadata.len() != orig_metadata.len()
+        || metadata.st_mtime() != orig_metadata.st_mtime()
+        || metadata.st_mtime_nsec() != orig_metadata.st_mtime_nsec()
+    {
+        return Err(file_changed_error());
+    }
+    Ok(())
+}
+
+impl Handler {
+    /// Make one attempt at reading the file, sending its blocks to the writer
+    ///
+    /// Returns true if the file changed while it was being read
+    fn read_attempt(&mut self, context: &Arc<Context>, metadata: &Metadata) -> bool {
+        let file = &context.file;
+        let times = if metadata.modified().ok() == context.orig_metadata.modified().ok() {
+            context.orig_times
+        } else {
+            match times::save_times_for(file, context.operation.time_preservation) {
+                Ok(times) => times,
+                Err(e) => {
+                    context.error(&format!(
+                        "Error reading times for {}: {}",
+                        context.path.display(),
+                        e
+                    ));
+                    return false;
+                }
             }
         };
-        let file = Arc::new(file);
 
-        let file_size = context.orig_metadata.len();
-        let (tx, rx) = seq_queue::bounded(
+        // The checksum of the original is computed as it is read, so the writer can verify
+        // without reading the original again
+        let (checksum_tx, checksum_rx) = if context.operation.mode.is_compressing()
+            && context.operation.verify == Verify::Checksum
+        {
+            let (checksum_tx, checksum_rx) = oneshot::channel();
+            (Some(checksum_tx), Some(checksum_rx))
+        } else {
+            (None, None)
+        };
+        let mut hasher = checksum_tx.as_ref().map(|_| Sha256::new());
+
+        // Enough to keep every compressor thread busy with this one file
+        let (tx, rx) = seq_queue::bounded_bytes(
             thread::available_parallelism()
                 .map(NonZeroUsize::get)
-                .unwrap_or(4),
+                .unwrap_or(4) as u64
+                * BLOCK_SIZE as u64,
         );
 
         {
+            let writer_slot = {
+                let _enter = tracing::debug_span!("waiting for a free writer").entered();
+                self.writer_slots.acquire(1)
+            };
             let _enter = tracing::debug_span!("waiting for space in writer").entered();
-            self.writer
-                .send(writer::WorkItem {
-                    context: Arc::clone(&context),
-                    file: Arc::clone(&file),
-                    blocks: rx,
-                })
-                .unwrap();
-        }
-
-        let result = self.read_file_into(&context, &file, file_size, &tx);
-        // ensure the file is dropped before tx is finished
-        drop(file);
+            let sent = self.writer.send(writer::WorkItem {
+                context: Arc::clone(context),
+                _writer_slot: writer_slot,
+                metadata: metadata.clone(),
+                times,
+                blocks: rx,
+                checksum: checksum_rx,
+            });
+            if sent.is_err() {
+                let e = context.operation.threads_stopped("writer");
+                context.error(&format!("Error reading {}: {e}", context.path.display()));
+                return false;
+            }
+        }
+
+        let result = self.read_file_into(context, file, metadata, &tx, hasher.as_mut());
+        if let (Ok(()), Some(checksum_tx), Some(hasher)) = (&result, checksum_tx, hasher) {
+            // The writer may have already given up on this file
+            let _ = checksum_tx.send(hasher.finalize().into());
+        }
+        let changed = result.as_ref().is_err_and(is_file_changed);
         if let Err(e) = &result {
-            context
-                .progress
-                .error(&format!("Error reading {}: {}", context.path.display(), e));
+            if !changed {
+                context.error(&format!("Error reading {}: {}", context.path.display(), e));
+            }
         }
         tx.finish(result);
+        changed
+    }
+}
+
+impl Handler {
+    /// Skip (or clear, if force clearing) a compressed file missing its resource fork
+    ///
+    /// Returns true if the file was missing its resource fork, and has been handled
+    fn handled_missing_resource_fork(&self, context: &Context) -> bool {
+        match rfork_storage::missing_resource_fork(&context.file) {
+            Ok(true) => {}
+            // Any other problem with the compressed data is reported when reading it
+            Ok(false) | Err(_) => return false,
+        }
+        let operation = &context.operation;
+        if !operation.force_clear || context.output_path.is_some() {
+            context.skip(SkipReason::MissingResourceFork);
+            writer::copy_original_to_output(context);
+            return true;
+        }
+        match clear_compression(context) {
+            Ok(()) => {
+                context.succeeded.store(true, Ordering::Relaxed);
+                operation
+                    .stats
+                    .cleared_missing_resource_fork
+                    .fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "{}: compressed data was missing, cleared compression",
+                    context.path.display()
+                );
+            }
+            Err(e) => context.error(&format!(
+                "Error clearing compression of {}: {e}",
+                context.path.display()
+            )),
+        }
+        true
+    }
+}
+
+/// Remove the compressed flag and decmpfs xattr of a file, leaving it empty
+///
+/// The file's times are restored afterwards, if times are being preserved.
+fn clear_compression(context: &Context) -> io::Result<()> {
+    let file = &context.file;
+    set_flags(
+        file,
+        context.orig_metadata.st_flags() & !libc::UF_COMPRESSED,
+    )?;
+    xattr::remove(file, decmpfs::XATTR_NAME)?;
+    if let Some(times) = &context.orig_times {
+        if let Err(e) = times::reset_times(file, times) {
+            tracing::error!("Unable to reset times: {e}");
+        }
+    }
+    Ok(())
+}
+
+impl WorkHandler<WorkItem> for Handler {
+    fn context(item: &WorkItem) -> Option<&Arc<Context>> {
+        Some(&item.context)
+    }
+
+    fn handle_item(&mut self, item: WorkItem) {
+        let WorkItem { context } = item;
+        let _guard = tracing::info_span!("reading file", path=%context.path.display()).entered();
+        if context.operation.has_fatal_error() {
+            // The scan has stopped, drop files which were already queued
+            return;
+        }
+        // Files are queued long before they're read, their directory may have been given up on
+        // since this file was found
+        if let Some(reason) = context.operation.dir_outcomes.skip_reason(&context.path) {
+            context.skip(reason);
+            writer::copy_original_to_output(&context);
+            return;
+        }
+        if !context.operation.mode.is_compressing() && self.handled_missing_resource_fork(&context)
+        {
+            return;
+        }
+
+        self.cache
+            .reading(&context.file, context.orig_metadata.len());
+        let mut metadata = context.orig_metadata.clone();
+        let mut retries_left = context.operation.file_change_retries;
+        while self.read_attempt(&context, &metadata) {
+            if retries_left == 0 {
+                context.skip(SkipReason::FileModified);
+                return;
+            }
+            retries_left -= 1;
+            tracing::debug!("file changed while reading, retrying");
+
+            metadata = match context.file.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    context.error(&format!("Error reading {}: {}", context.path.display(), e));
+                    return;
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn read_all(file: &File, orig_metadata: &Metadata) -> io::Result<Vec<u8>> {
+        read_all_with(file, orig_metadata, |_| {})
+    }
+
+    /// Reads the whole file, calling `after_block` with the number of blocks read so far after
+    /// each one
+    fn read_all_with(
+        file: &File,
+        orig_metadata: &Metadata,
+        mut after_block: impl FnMut(usize),
+    ) -> io::Result<Vec<u8>> {
+        let (compressor, _compressor_rx) = crossbeam_channel::unbounded();
+        let (writer, _writer_rx) = crossbeam_channel::unbounded();
+        let byte_budget = 16 * BLOCK_SIZE as u64;
+        let mut handler = Handler::new(
+            compressor,
+            writer,
+            Budget::new(1),
+            Budget::new(byte_budget),
+            BufferPool::new(byte_budget),
+            CacheHints::new(None),
+        );
+        let (tx, _rx) = seq_queue::bounded(16);
+
+        let mut data = Vec::new();
+        let mut blocks = 0;
+        handler.with_file_chunks(file, orig_metadata, &tx, |slot, block, permit| {
+            data.extend_from_slice(&block);
+            blocks += 1;
+            after_block(blocks);
+            let orig_size = block.len() as u64;
+            slot.finish(writer::Chunk {
+                block,
+                orig_size,
+                permit,
+            })
+            .unwrap();
+            Ok(())
+        })?;
+        Ok(data)
+    }
+
+    fn temp_file_with(len: usize) -> (tempfile::NamedTempFile, Vec<u8>) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        file.write_all(&content).unwrap();
+        file.flush().unwrap();
+        (file, content)
+    }
+
+    #[test]
+    fn stable_file() {
+        for len in [
+            0,
+            1,
+            BLOCK_SIZE - 1,
+            BLOCK_SIZE,
+            3 * BLOCK_SIZE,
+            3 * BLOCK_SIZE + 7,
+        ] {
+            let (file, content) = temp_file_with(len);
+            let metadata = file.as_file().metadata().unwrap();
+            let file = File::open(file.path()).unwrap();
+            assert_eq!(read_all(&file, &metadata).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn file_grew() {
+        let (file, _) = temp_file_with(2 * BLOCK_SIZE);
+        let metadata = file.as_file().metadata().unwrap();
+        let mut appender = OpenOptions::new().append(true).open(file.path()).unwrap();
+        appender.write_all(&[1]).unwrap();
+
+        let file = File::open(file.path()).unwrap();
+        assert!(is_file_changed(&read_all(&file, &metadata).unwrap_err()));
+    }
+
+    #[test]
+    fn file_grew_by_a_block() {
+        // Reading stops at the expected length, exactly at a block boundary
+        for len in [BLOCK_SIZE, 2 * BLOCK_SIZE] {
+            let (file, _) = temp_file_with(len);
+            let metadata = file.as_file().metadata().unwrap();
+            let mut appender = OpenOptions::new().append(true).open(file.path()).unwrap();
+            appender.write_all(&[1; BLOCK_SIZE]).unwrap();
+
+            let file = File::open(file.path()).unwrap();
+            assert!(
+                is_file_changed(&read_all(&file, &metadata).unwrap_err()),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn file_shrank() {
+        let (file, _) = temp_file_with(2 * BLOCK_SIZE);
+        let metadata = file.as_file().metadata().unwrap();
+        file.as_file().set_len(BLOCK_SIZE as u64 + 1).unwrap();
+
+        let file = File::open(file.path()).unwrap();
+        assert!(is_file_changed(&read_all(&file, &metadata).unwrap_err()));
+    }
+
+    #[test]
+    fn file_modified_same_size() {
+        let (file, _) = temp_file_with(BLOCK_SIZE + 1);
+        let metadata = file.as_file().metadata().unwrap();
+        file.as_file()
+            .set_modified(metadata.modified().unwrap() + Duration::from_secs(1))
+            .unwrap();
+
+        let file = File::open(file.path()).unwrap();
+        assert!(is_file_changed(&read_all(&file, &metadata).unwrap_err()));
+    }
+
+    #[test]
+    fn file_changed_during_read() {
+        type Change = fn(&Path, &Metadata);
+        let changes: [(&str, Change); 4] = [
+            ("grew", |path, _| {
+                let mut appender = OpenOptions::new().append(true).open(path).unwrap();
+                appender.write_all(&[1]).unwrap();
+            }),
+            ("grew by a block", |path, _| {
+                let mut appender = OpenOptions::new().append(true).open(path).unwrap();
+                appender.write_all(&[1; BLOCK_SIZE]).unwrap();
+            }),
+            ("shrank", |path, _| {
+                let file = OpenOptions::new().write(true).open(path).unwrap();
+                file.set_len(BLOCK_SIZE as u64 + 1).unwrap();
+            }),
+            ("modified, same size", |path, metadata| {
+                let file = OpenOptions::new().write(true).open(path).unwrap();
+                file.write_all_at(&[0xFF; 10], BLOCK_SIZE as u64).unwrap();
+                file.set_modified(metadata.modified().unwrap() + Duration::from_secs(1))
+                    .unwrap();
+            }),
+        ];
+        for (name, change) in changes {
+            let (file, _) = temp_file_with(3 * BLOCK_SIZE);
+            let metadata = file.as_file().metadata().unwrap();
+            let path = file.path().to_owned();
+
+            let reader = File::open(&path).unwrap();
+            // Change the file part way through reading it, once the first block has been read
+            let result = read_all_with(&reader, &metadata, |blocks| {
+                if blocks == 1 {
+                    change(&path, &metadata);
+                }
+            });
+            assert!(is_file_changed(&result.unwrap_err()), "{name}");
+        }
+    }
+
+    /// Compares the cost of checking for changes at the end of each file with `fstat`, against
+    /// the 1 byte read past the end it replaced, over many small files
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn small_files_end_check_cost() {
+        use std::time::Instant;
+
+        const FILES: usize = 10_000;
+        let dir = tempfile::TempDir::new().unwrap();
+        let files: Vec<(File, Metadata)> = (0..FILES)
+            .map(|i| {
+                let path = dir.path().join(i.to_string());
+                std::fs::write(&path, vec![i as u8; 1 + i % 4096]).unwrap();
+                let file = File::open(&path).unwrap();
+                let metadata = file.metadata().unwrap();
+                (file, metadata)
+            })
+            .collect();
+
+        let start = Instant::now();
+        for (file, metadata) in &files {
+            read_all(file, metadata).unwrap();
+        }
+        let read = start.elapsed();
+
+        let start = Instant::now();
+        for (file, metadata) in &files {
+            ensure_unchanged(file, metadata).unwrap();
+        }
+        let fstat = start.elapsed();
+
+        let start = Instant::now();
+        for (file, metadata) in &files {
+            assert_eq!(file.read_at(&mut [0], metadata.len()).unwrap(), 0);
+        }
+        let sentinel_read = start.elapsed();
+
+        eprintln!(
+            "{FILES} files: reading took {read:?}, fstat checks took {fstat:?}, \
+             1 byte reads past the end took {sentinel_read:?}"
+        );
     }
 }
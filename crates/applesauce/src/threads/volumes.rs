@@ -0,0 +1,122 @@
+//! Limiting the number of files processed at once on each volume
+//!
+//! Each file is written to a temp file and renamed over the original on the same volume. Network
+//! volumes handle many of these in parallel much worse than a few at a time, so files on them
+//! are limited separately from files on local volumes.
+
+use crate::threads::budget::{Budget, Permit};
+use crate::{os_error, path_cstring};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::Metadata;
+use std::mem::MaybeUninit;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Filesystem types (`f_fstypename`) of network volumes
+const NETWORK_FS_TYPES: &[&[u8]] = &[b"smbfs", b"nfs", b"afpfs", b"webdav"];
+
+#[derive(Debug)]
+pub(super) struct VolumeLimits {
+    network_files: Option<NonZeroUsize>,
+    local_files: Option<NonZeroUsize>,
+    /// The budget of each volume by device id, or `None` if files on it are not limited
+    budgets: Mutex<HashMap<u64, Option<Arc<Budget>>>>,
+}
+
+impl VolumeLimits {
+    pub fn new(network_files: Option<NonZeroUsize>, local_files: Option<NonZeroUsize>) -> Self {
+        Self {
+            network_files,
+            local_files,
+            budgets: Mutex::default(),
+        }
+    }
+
+    /// Wait until another file can be processed on the volume containing `path`
+    ///
+    /// Returns `None` if files on the volume are not limited. The volume's filesystem type is
+    /// only checked for the first file found on it.
+    pub fn acquire(&self, path: &Path, metadata: &Metadata) -> Option<Permit> {
+        let budget = {
+            let mut budgets = self.budgets.lock().unwrap();
+            budgets
+                .entry(metadata.dev())
+                .or_insert_with(|| {
+                    let is_network = fs_type_name(path).is_some_and(|name| is_network_fs(&name));
+                    let limit = if is_network {
+                        self.network_files
+                    } else {
+                        self.local_files
+                    };
+                    tracing::debug!(path=%path.display(), is_network, ?limit, "found volume");
+                    limit.map(|limit| Budget::new(limit.get() as u64))
+                })
+                .clone()?
+        };
+        // Not holding the lock, so files on other volumes aren't blocked
+        Some(budget.acquire(1))
+    }
+}
+
+fn is_network_fs(fs_type_name: &CStr) -> bool {
+    NETWORK_FS_TYPES.contains(&fs_type_name.to_bytes())
+}
+
+/// Returns true if `path` is on an APFS volume, which supports clones
+pub(crate) fn is_apfs(path: &Path) -> bool {
+    fs_type_name(path).is_some_and(|name| name.to_bytes() == b"apfs")
+}
+
+/// The filesystem type of the volume containing `path`, e.g. `apfs` or `smbfs`
+fn fs_type_name(path: &Path) -> Option<CString> {
+    let path = path_cstring(path).ok()?;
+    let mut statfs_buf = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: path is null terminated, buf is a valid ptr, and is used as an out ptr
+    let rc = unsafe { libc::statfs(path.as_ptr(), statfs_buf.as_mut_ptr()) };
+    if rc != 0 {
+        let e = os_error::last(format_args!("statfs({})", path.to_string_lossy()));
+        tracing::debug!("unable to get filesystem type: {e}");
+        return None;
+    }
+    // SAFETY: statfs succeeded, so it filled in statfs_buf
+    let statfs_buf = unsafe { statfs_buf.assume_init_ref() };
+    crate::cstr_from_bytes_until_null(&statfs_buf.f_fstypename).map(CStr::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn network_fs_types() {
+        for name in [c"smbfs", c"nfs", c"afpfs", c"webdav"] {
+            assert!(is_network_fs(name), "{name:?}");
+        }
+        for name in [c"apfs", c"hfs", c"msdos", c"exfat"] {
+            assert!(!is_network_fs(name), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn local_volume() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"data").unwrap();
+        let metadata = path.metadata().unwrap();
+
+        // Temp dirs are on a local volume
+        assert!(!is_network_fs(&fs_type_name(&path).unwrap()));
+        let unlimited = VolumeLimits::new(NonZeroUsize::new(1), None);
+        assert!(unlimited.acquire(&path, &metadata).is_none());
+
+        let limited = VolumeLimits::new(None, NonZeroUsize::new(2));
+        let first = limited.acquire(&path, &metadata).unwrap();
+        let second = limited.acquire(&path, &metadata).unwrap();
+        assert_eq!(limited.budgets.lock().unwrap().len(), 1);
+        drop((first, second));
+    }
+}
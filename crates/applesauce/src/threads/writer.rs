@@ -1,30 +1,70 @@
-use crate::threads::{BgWork, Context, Mode, WorkHandler};
-use crate::{seq_queue, set_flags, times, xattr};
-use applesauce_core::compressor::Kind;
-use applesauce_core::decmpfs;
+use crate::progress::{FileSummary, SkipReason, FILE_EVENT_TARGET};
+use crate::threads::budget::Permit;
+use crate::threads::buffers::BufferPool;
+use crate::threads::cache::CacheHints;
+use crate::threads::fork_hash::ForkHasher;
+use crate::threads::{reader, BgWork, Context, Mode, WorkHandler};
+use crate::tmpdir_paths::TmpFile;
+use crate::xattr::XattrSource;
+use crate::{
+    info, markers, os_error, rebalance, rfork_storage, seq_queue, set_flags, sparse, times, xattr,
+    Durability, Verify,
+};
+use applesauce_core::check::{check_structure, check_written, Written};
+use applesauce_core::compressor::{Kind, Pool};
+use applesauce_core::{decmpfs, num_blocks, BLOCK_SIZE};
 use resource_fork::ResourceFork;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
+use sha2::{Digest, Sha256};
+use std::ffi::CString;
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
 use std::os::fd::AsRawFd;
 use std::os::macos::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::{cmp, io, ptr};
-use tempfile::NamedTempFile;
+use std::time::SystemTime;
+use std::{cmp, error, fmt, io, ptr};
 
 pub(super) type Sender = crossbeam_channel::Sender<WorkItem>;
 
+/// A SHA-256 hash of the uncompressed contents of a file
+pub(crate) type Checksum = [u8; 32];
+
 pub(super) struct Chunk {
     pub block: Vec<u8>,
+    /// The length of the data `block` was made from
+    ///
+    /// When compressing, this is the uncompressed length. When decompressing manually, it's the
+    /// compressed length, progress is reported by the length of the decompressed block instead.
     pub orig_size: u64,
+    /// Released once the block has been written
+    pub permit: Permit,
 }
 
 pub(super) struct WorkItem {
     pub context: Arc<Context>,
-    pub file: Arc<File>,
+    /// Released once the writer is done with the file, see
+    /// [`reader::Work::writer_slots`](super::reader::Work::writer_slots)
+    pub _writer_slot: Permit,
+    /// The metadata of the file when reading started
+    ///
+    /// This may differ from the metadata in the context if the file was modified, and reading was
+    /// retried
+    pub metadata: Metadata,
+    /// The times of the file when reading started, if times are being preserved
+    pub times: Option<times::Saved>,
     pub blocks: seq_queue::Receiver<Chunk, io::Error>,
+    /// The checksum of the original file, sent once it has been completely read
+    ///
+    /// Only present when verifying with [`Verify::Checksum`]
+    pub checksum: Option<oneshot::Receiver<Checksum>>,
 }
 
-pub(super) struct Work;
+pub(super) struct Work {
+    pub buffers: Arc<BufferPool>,
+    pub cache: CacheHints,
+}
 
 impl BgWork for Work {
     type Item = WorkItem;
@@ -32,22 +72,29 @@ impl BgWork for Work {
     const NAME: &'static str = "writer";
 
     fn make_handler(&self) -> Handler {
-        Handler::new()
-    }
-
-    fn queue_capacity(&self) -> usize {
-        4
+        Handler::new(Arc::clone(&self.buffers), self.cache)
     }
 }
 
+/// Writes the new version of each file to a temp file, then replaces the original file with it
+///
+/// Every step before replacing the original file is fatal: the original is only replaced once the
+/// new file has the correct contents, xattrs, and flags (and has been verified, if requested).
+/// Failures after the original file has been replaced (restoring times), or in recording the
+/// compression time for [`rebalance`], are only logged.
 pub(super) struct Handler {
     decomp_xattr_val_buf: Vec<u8>,
+    buffers: Arc<BufferPool>,
+    cache: CacheHints,
 }
 
 impl Handler {
-    fn new() -> Self {
+    fn new(buffers: Arc<BufferPool>, cache: CacheHints) -> Self {
         Self {
+            // Only holds xattrs written when compressing, never one read from a compressed file
             decomp_xattr_val_buf: Vec::with_capacity(decmpfs::MAX_XATTR_SIZE),
+            buffers,
+            cache,
         }
     }
 
@@ -55,145 +102,372 @@ impl Handler {
     fn write_blocks(
         &mut self,
         context: &Context,
+        uncompressed_file_size: u64,
         writer: &mut applesauce_core::writer::Writer<impl applesauce_core::writer::Open>,
         chunks: seq_queue::Receiver<Chunk, io::Error>,
-    ) -> io::Result<()> {
+    ) -> io::Result<Written> {
         let block_span = tracing::debug_span!("write block");
 
         let mut total_compressed_size = 0;
-        let minimum_compression_ratio = match context.operation.mode {
+        let mut total_orig_size = 0;
+        let mut block_count: u32 = 0;
+        let (kind, minimum_compression_ratio, sample_blocks) = match context.operation.mode {
             Mode::Compress {
+                kind,
                 minimum_compression_ratio,
+                sample_blocks,
                 ..
-            } => minimum_compression_ratio,
+            } => (kind, minimum_compression_ratio, sample_blocks),
             _ => unreachable!("write_blocks called in non-compress mode"),
         };
         let max_compressed_size =
-            (context.orig_metadata.len() as f64 * minimum_compression_ratio) as u64;
+            (uncompressed_file_size as f64 * minimum_compression_ratio) as u64;
+        // Only files within about a MiB of 4 GiB can overflow the resource fork, for those stop
+        // before writing a block which can't fit, rather than failing part way through the fork
+        let file_block_count = num_blocks(uncompressed_file_size);
+        let max_block_data_size =
+            if kind.always_fits_resource_fork(uncompressed_file_size, BLOCK_SIZE as u64) {
+                None
+            } else {
+                Some(kind.max_block_data_size(file_block_count))
+            };
+        let too_large = |max_block_data_size: u64| {
+            let reason = SkipReason::TooLarge {
+                size: uncompressed_file_size,
+                // The largest file which fits however badly it compresses
+                limit: max_block_data_size
+                    .saturating_sub(file_block_count * kind.max_block_overhead()),
+            };
+            let message = format!(
+                "{} did not compress enough to fit in a resource fork",
+                context.path.display()
+            );
+            context.skip(reason);
+            context.operation.dir_outcomes.record(&context.path, false);
+            copy_original_to_output(context);
+            io::Error::other(message)
+        };
+        let not_compressible_enough = |what: &str| {
+            context
+                .operation
+                .stats
+                .skipped
+                .not_compressible_enough
+                .fetch_add(1, Ordering::Relaxed);
+            context.skipped.store(true, Ordering::Relaxed);
+            context.progress.not_compressible_enough(&context.path);
+            context.operation.dir_outcomes.record(&context.path, false);
+            // The original is left untouched when writing to an output directory
+            if context.operation.remember_incompressible && context.operation.output.is_none() {
+                markers::mark(
+                    &context.file,
+                    &context.orig_metadata,
+                    kind,
+                    minimum_compression_ratio,
+                );
+            }
+            copy_original_to_output(context);
+            io::Error::other(format!(
+                "{what} did not compress to at least {}% of original size",
+                minimum_compression_ratio * 100.0
+            ))
+        };
 
         chunks.try_for_each(|chunk| {
             total_compressed_size += u64::try_from(chunk.block.len()).unwrap();
+            total_orig_size += chunk.orig_size;
+            block_count += 1;
             if total_compressed_size > max_compressed_size {
-                context.progress.not_compressible_enough(&context.path);
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
-                        "did not compress to at least {}% of original size",
-                        minimum_compression_ratio * 100.0
-                    ),
-                ));
+                return Err(not_compressible_enough("file"));
+            }
+            if let Some(limit) = max_block_data_size {
+                if total_compressed_size > limit {
+                    return Err(too_large(limit));
+                }
+            }
+            // Returning an error drops the receiver, which stops the reader from reading the rest
+            if sample_blocks == Some(block_count)
+                && total_compressed_size as f64 > total_orig_size as f64 * minimum_compression_ratio
+            {
+                return Err(not_compressible_enough("sampled blocks"));
             }
 
-            let Chunk { block, orig_size } = chunk;
+            let Chunk {
+                block,
+                orig_size,
+                permit,
+            } = chunk;
             let _enter = block_span.enter();
 
             writer.add_block(&block)?;
+            context
+                .operation
+                .stats
+                .add_written(u64::try_from(block.len()).unwrap());
+            self.buffers.put(block);
+            drop(permit);
             context.progress.increment(orig_size);
             Ok(())
         })?;
-        Ok(())
+        Ok(Written {
+            block_count: block_count.into(),
+            compressed_size: total_compressed_size,
+        })
     }
 
     fn write_compressed_file(
         &mut self,
         mut item: WorkItem,
         compressor_kind: Kind,
-    ) -> io::Result<()> {
-        let uncompressed_file_size = item.context.orig_metadata.len();
+    ) -> io::Result<File> {
+        let uncompressed_file_size = item.metadata.len();
 
         let mut tmp_file = tmp_file_for(&item)?;
-        copy_xattrs(&item.file, tmp_file.as_file())?;
+        self.cache
+            .writing(tmp_file.as_file(), uncompressed_file_size);
+        if !tmp_file.is_clone() {
+            copy_xattrs(&item.context.file, tmp_file.as_file())?;
+        }
+        // A marker from a previous run that failed doesn't belong on the compressed file
+        if let Err(e) = markers::remove_from(tmp_file.as_file()) {
+            tracing::debug!("unable to remove incompressible marker: {e}");
+        }
 
+        let mut fork_hasher = item.context.operation.capture_metadata.then(|| {
+            ForkHasher::new(compressor_kind.header_size(num_blocks(uncompressed_file_size)))
+        });
+        let hasher = fork_hasher.as_mut();
+        // If writing fails, the writer refuses to produce a decmpfs xattr, and the temp file is
+        // discarded when it's dropped
         let mut writer =
             applesauce_core::writer::Writer::new(compressor_kind, uncompressed_file_size, || {
-                BufWriter::new(ResourceFork::new(tmp_file.as_file()))
+                resource_fork_writer(tmp_file.as_file(), hasher)
             })?;
 
-        self.write_blocks(&item.context, &mut writer, item.blocks)?;
+        let written = self.write_blocks(
+            &item.context,
+            uncompressed_file_size,
+            &mut writer,
+            item.blocks,
+        )?;
+        item.context
+            .operation
+            .dir_outcomes
+            .record(&item.context.path, true);
 
         self.decomp_xattr_val_buf.clear();
         writer.finish_decmpfs_data(&mut self.decomp_xattr_val_buf)?;
         {
             let _entered = tracing::debug_span!("set decmpfs xattr").entered();
-            xattr::set(
-                tmp_file.as_file(),
-                decmpfs::XATTR_NAME,
-                &self.decomp_xattr_val_buf,
-                0,
-            )?;
+            set_decmpfs_xattr(tmp_file.as_file(), &self.decomp_xattr_val_buf).map_err(|e| {
+                if !is_xattr_limit_error(&e) {
+                    return e;
+                }
+                // A property of the file, like its size, rather than a failure
+                let context = &item.context;
+                let message = e.to_string();
+                context.skip(SkipReason::XattrLimitExceeded(e));
+                copy_original_to_output(context);
+                io::Error::other(message)
+            })?;
         }
+        item.context
+            .operation
+            .stats
+            .add_written(framing_len(&self.decomp_xattr_val_buf, written));
+        ensure_written_intact(
+            tmp_file.as_file(),
+            &self.decomp_xattr_val_buf,
+            uncompressed_file_size,
+            written,
+        )
+        .map_err(|e| {
+            let message = format!(
+                "self-check failed: {e}, {} unchanged",
+                item.context.path.display()
+            );
+            item.context.error(&message);
+            io::Error::other(message)
+        })?;
 
-        copy_metadata(&item.file, tmp_file.as_file())?;
+        let summary = if item.context.operation.capture_metadata {
+            let (resource_fork_len, resource_fork_hash) =
+                fork_hasher.and_then(ForkHasher::finish).unzip();
+            FileSummary {
+                decmpfs_xattr: Some(self.decomp_xattr_val_buf.clone()),
+                resource_fork_len,
+                resource_fork_hash,
+            }
+        } else {
+            FileSummary::default()
+        };
+
+        // The data fork can only be seen before the file is marked compressed
+        let data_fork_len = if item.context.operation.verify == Verify::Quick {
+            tmp_file.as_file().metadata()?.len()
+        } else {
+            0
+        };
+        if !tmp_file.is_clone() {
+            copy_metadata(&item.context.file, tmp_file.as_file())?;
+        }
         set_flags(
             tmp_file.as_file(),
-            item.context.orig_metadata.st_flags() | libc::UF_COMPRESSED,
+            item.metadata.st_flags() | libc::UF_COMPRESSED,
         )?;
 
-        if item.context.operation.verify {
-            let _entered = tracing::info_span!("verify").entered();
-
-            let orig_file = Arc::get_mut(&mut item.file)
-                .expect("Reader should drop file before finishing writing blocks, writer should have the only reference");
-            let mut orig_file = BufReader::new(orig_file);
-            let mut new_file = BufReader::new(tmp_file.as_file_mut());
+        // Verifying against an original which changed after it was read would fail, as a mismatch
+        ensure_unmodified(&item.context, &item.metadata)?;
+        match item.context.operation.verify {
+            Verify::Off => {}
+            Verify::Quick => {
+                let _entered = tracing::info_span!("verify structure").entered();
 
-            orig_file.rewind()?;
-            new_file.rewind()?;
+                ensure_compressed_structure(
+                    tmp_file.as_file(),
+                    &self.decomp_xattr_val_buf,
+                    uncompressed_file_size,
+                    data_fork_len,
+                )
+                .map_err(|e| {
+                    io::Error::other(format!(
+                        "verification failed: {e}, {} unchanged",
+                        item.context.path.display()
+                    ))
+                })?;
+            }
+            Verify::Checksum => {
+                let _entered = tracing::info_span!("verify checksum").entered();
 
-            ensure_identical_files(orig_file, new_file).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!(
+                let expected = item
+                    .checksum
+                    .take()
+                    .expect("reader should send a checksum when verifying by checksum")
+                    .recv()
+                    .map_err(|_| io::Error::other("original file was not completely read"))?;
+                ensure_checksum_matches(tmp_file.as_file(), &expected).map_err(|e| {
+                    io::Error::other(format!(
                         "verification failed: {e}, {} unchanged",
                         item.context.path.display()
-                    ),
-                )
-            })?;
+                    ))
+                })?;
+            }
+            Verify::Full => {
+                let _entered = tracing::info_span!("verify").entered();
+
+                // The reader reads positionally, so the file position is only used here
+                let mut orig_file = BufReader::new(&item.context.file);
+                let mut new_file = BufReader::new(tmp_file.as_file_mut());
+
+                orig_file.rewind()?;
+                new_file.rewind()?;
+
+                ensure_identical_files(orig_file, new_file).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "verification failed: {e}, {} unchanged",
+                            item.context.path.display()
+                        ),
+                    )
+                })?;
+            }
         }
 
+        if let Err(e) = rebalance::set_compressed_at(tmp_file.as_file(), SystemTime::now()) {
+            tracing::warn!("Unable to record compression time: {e}");
+        }
         let new_file = {
             let _entered = tracing::debug_span!("rename tmp file").entered();
-            tmp_file.persist(&item.context.path)?
+            persist(tmp_file, &item.context, &item.metadata)?
         };
         if let Some(resetter) = &item.context.parent_resetter {
             resetter.activate();
         }
-        if let Err(e) = times::reset_times(&new_file, &item.context.orig_times) {
-            tracing::error!("Unable to reset times: {e}");
+        if let Some(times) = &item.times {
+            if let Err(e) = times::reset_times(&new_file, times) {
+                tracing::error!("Unable to reset times: {e}");
+            }
         }
-        Ok(())
+        item.context.progress.finished(summary);
+        Ok(new_file)
     }
 
-    fn write_uncompressed_file(&mut self, item: WorkItem) -> io::Result<()> {
-        let mut tmp_file = tmp_file_for(&item)?;
-        copy_xattrs(&item.file, tmp_file.as_file())?;
+    fn write_uncompressed_file(&mut self, item: WorkItem) -> io::Result<File> {
+        let tmp_file = tmp_file_for(&item)?;
+        self.cache.writing(tmp_file.as_file(), item.metadata.len());
+        if !tmp_file.is_clone() {
+            copy_xattrs(&item.context.file, tmp_file.as_file())?;
+        }
+        if let Err(e) = rebalance::clear_compressed_at(tmp_file.as_file()) {
+            tracing::warn!("Unable to remove compression time: {e}");
+        }
 
-        item.blocks.try_for_each(|chunk| {
-            tmp_file.write_all(&chunk.block)?;
-            // Increment progress by the uncompressed size of the block,
-            // not the "original" (compressed) size
-            item.context.progress.increment(chunk.block.len() as u64);
-            Ok(())
-        })?;
+        // The stat size of a compressed file is its uncompressed size
+        let expected_len = item.metadata.len();
+        if let Err(e) = preallocate(tmp_file.as_file(), expected_len) {
+            tracing::debug!("unable to preallocate {expected_len} bytes: {e}");
+        }
+
+        let mut written = 0;
+        let mut holes = sparse::Holes::default();
+        {
+            let _entered = tracing::debug_span!("write blocks").entered();
+            let mut writer = BufWriter::with_capacity(UNCOMPRESSED_BUF_SIZE, tmp_file.as_file());
+            item.blocks.try_for_each(|chunk| {
+                let len = chunk.block.len() as u64;
+                // Leave blocks of zeros as holes, so a file compressed from a sparse file is
+                // sparse again, rather than having every hole written out
+                if sparse::is_zeros(&chunk.block) {
+                    writer.seek(SeekFrom::Current(len as i64))?;
+                    holes.add(written, len);
+                } else {
+                    writer.write_all(&chunk.block)?;
+                }
+                written += len;
+                item.context.operation.stats.add_written(len);
+                // Progress is always in uncompressed bytes, so it adds up to the size of the file.
+                // When decompressing manually, `orig_size` is the compressed size.
+                item.context.progress.increment(len);
+                self.buffers.put(chunk.block);
+                Ok(())
+            })?;
+            writer.flush()?;
+        }
+        // Preallocation doesn't change the length, but ensure the length is exact regardless
+        tmp_file.as_file().set_len(written)?;
+        // Skipped blocks read as zeros, but were preallocated
+        if let Err(e) = holes.punch(tmp_file.as_file()) {
+            tracing::debug!("unable to free {} bytes of zeros: {e}", holes.total_len());
+        }
 
-        copy_metadata(&item.file, tmp_file.as_file())?;
+        if !tmp_file.is_clone() {
+            copy_metadata(&item.context.file, tmp_file.as_file())?;
+        }
         set_flags(
             tmp_file.as_file(),
-            item.context.orig_metadata.st_flags() & !libc::UF_COMPRESSED,
+            item.metadata.st_flags() & !libc::UF_COMPRESSED,
         )?;
 
-        let new_file = tmp_file.persist(&item.context.path)?;
+        let new_file = persist(tmp_file, &item.context, &item.metadata)?;
         if let Some(resetter) = &item.context.parent_resetter {
             resetter.activate();
         }
-        if let Err(e) = times::reset_times(&new_file, &item.context.orig_times) {
-            tracing::error!("Unable to reset times: {e}");
+        if let Some(times) = &item.times {
+            if let Err(e) = times::reset_times(&new_file, times) {
+                tracing::error!("Unable to reset times: {e}");
+            }
         }
-        Ok(())
+        item.context.progress.finished(FileSummary::default());
+        Ok(new_file)
     }
 }
 
 impl WorkHandler<WorkItem> for Handler {
+    fn context(item: &WorkItem) -> Option<&Arc<Context>> {
+        Some(&item.context)
+    }
+
     fn handle_item(&mut self, item: WorkItem) {
         let context = Arc::clone(&item.context);
         let _entered = tracing::info_span!("writing file", path=%context.path.display()).entered();
@@ -205,24 +479,213 @@ impl WorkHandler<WorkItem> for Handler {
             }
         };
 
-        if res.is_ok() {
-            let compressing = context.operation.mode.is_compressing();
-            let prefix = if compressing { "" } else { "de" };
-            tracing::info!("Successfully {prefix}compressed {}", context.path.display());
+        let compressing = context.operation.mode.is_compressing();
+        let prefix = if compressing { "" } else { "de" };
+        let new_file = match res {
+            Ok(new_file) => new_file,
+            // Most errors are reported where they happen, a file which changed while it was being
+            // read is read again, and after a fatal error every file fails the same way
+            Err(e)
+                if !context.reported()
+                    && !reader::is_file_changed(&e)
+                    && !context.operation.has_fatal_error() =>
+            {
+                context.error(&format!(
+                    "Error {prefix}compressing {}: {e}",
+                    context.path.display()
+                ));
+                return;
+            }
+            Err(_) => return,
+        };
+        context.succeeded.store(true, Ordering::Relaxed);
+        let after_size = new_file
+            .metadata()
+            .map_or(0, |metadata| info::on_disk_size(&metadata));
+        tracing::info!(
+            target: FILE_EVENT_TARGET,
+            path = %context.path.display(),
+            action = if compressing { "compress" } else { "decompress" },
+            before_size = info::on_disk_size(&context.orig_metadata),
+            after_size,
+            "Successfully {prefix}compressed {}",
+            context.path.display(),
+        );
+    }
+}
+
+/// The resource fork of a file, with the offset of a failed write in its error
+///
+/// Everything written is also fed to `hasher`, if there is one.
+struct NamedWrites<'a> {
+    fork: ResourceFork<'a>,
+    hasher: Option<&'a mut ForkHasher>,
+}
+
+impl Write for NamedWrites<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let offset = self.fork.position();
+        let written = self.fork.write(buf).map_err(|e| {
+            os_error::with_call(
+                e,
+                format_args!(
+                    "fsetxattr({}, offset={offset})",
+                    resource_fork::XATTR_NAME.to_string_lossy(),
+                ),
+            )
+        })?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.write_at(u64::from(offset), &buf[..written]);
         }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fork.flush()
+    }
+}
+
+impl Seek for NamedWrites<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.fork.seek(pos)
+    }
+}
+
+fn resource_fork_writer<'a>(
+    file: &'a File,
+    hasher: Option<&'a mut ForkHasher>,
+) -> BufWriter<NamedWrites<'a>> {
+    BufWriter::new(NamedWrites {
+        fork: ResourceFork::new(file),
+        hasher,
+    })
+}
+
+/// Ensure the original file hasn't been written to since it was read
+///
+/// The reader only checks once it reaches the end of the file, so this catches changes (e.g. a
+/// block appended) made after that, which would otherwise be lost when the file is replaced.
+/// A modified file is skipped, rather than read again.
+fn ensure_unmodified(context: &Context, read_metadata: &Metadata) -> io::Result<()> {
+    match reader::ensure_unchanged(&context.file, read_metadata) {
+        Err(e) if reader::is_file_changed(&e) => {
+            context.skip(SkipReason::FileModified);
+            Err(io::Error::other(format!(
+                "{} was modified while it was being processed",
+                context.path.display()
+            )))
+        }
+        res => res,
+    }
+}
+
+/// Ensure the path still refers to the file which was read
+///
+/// If the file was replaced (e.g. by an atomic save) after it was opened, the new file must not
+/// be replaced by a processed copy of the old one, so the file is skipped as modified.
+fn ensure_not_replaced(context: &Context) -> io::Result<()> {
+    let opened = context.file.metadata()?;
+    let current = context.path.symlink_metadata()?;
+    if opened.st_dev() != current.st_dev() || opened.st_ino() != current.st_ino() {
+        context.skip(SkipReason::FileModified);
+        return Err(io::Error::other(format!(
+            "{} was replaced while it was being processed",
+            context.path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Replace the original file with `tmp_file`, or move it to the output path
+///
+/// Flushes the new file (and the directory it's moved into) to stable storage first, if the
+/// operation requires it. `read_metadata` is the metadata of the original when it was read.
+fn persist(tmp_file: TmpFile, context: &Context, read_metadata: &Metadata) -> io::Result<File> {
+    let durability = context.operation.durability;
+    if durability != Durability::None {
+        let _entered = tracing::debug_span!("sync tmp file").entered();
+        // On macOS, this uses F_FULLFSYNC, which also flushes the drive's cache
+        tmp_file.as_file().sync_all()?;
+    }
+    ensure_unmodified(context, read_metadata)?;
+    let path = context.output_path.as_deref().unwrap_or(&context.path);
+    let new_file = if context.output_path.is_none() {
+        ensure_not_replaced(context)?;
+        tmp_file.persist(path)?
+    } else {
+        let overwrite = context
+            .operation
+            .output
+            .as_ref()
+            .is_some_and(|output| output.overwrite());
+        if overwrite {
+            tmp_file.persist(path)?
+        } else {
+            tmp_file.persist_noclobber(path)?
+        }
+    };
+    if durability == Durability::Full {
+        let _entered = tracing::debug_span!("sync parent dir").entered();
+        // The file has already replaced the original, so this can't fail the file
+        if let Err(e) = sync_parent_dir(path) {
+            tracing::error!("Unable to sync directory of {}: {e}", path.display());
+        }
+    }
+    Ok(new_file)
+}
+
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    File::open(parent)?.sync_all()
+}
+
+/// Copy the original file to the output path unchanged, when it won't be written compressed
+///
+/// Does nothing when replacing files in place.
+pub(super) fn copy_original_to_output(context: &Context) {
+    let (Some(output), Some(output_path)) = (&context.operation.output, &context.output_path)
+    else {
+        return;
+    };
+    if let Err(e) = output.copy_original(&context.path, output_path) {
+        context.error(&format!(
+            "Error copying {} to output: {e}",
+            context.path.display()
+        ));
     }
 }
 
 #[tracing::instrument(level="debug", skip_all, err, fields(path=%item.context.path.display()))]
-fn tmp_file_for(item: &WorkItem) -> io::Result<NamedTempFile> {
-    item.context
-        .operation
-        .tempdirs
-        .tempfile_for(&item.context.path, &item.context.orig_metadata)
+fn tmp_file_for(item: &WorkItem) -> io::Result<TmpFile> {
+    let tempdirs = &item.context.operation.tempdirs;
+    match &item.context.output_path {
+        // The temp file must be on the same device as the path it will be moved to
+        Some(output_path) => {
+            let output_parent = output_path
+                .parent()
+                .ok_or_else(|| io::Error::other("expected output path to have a parent"))?;
+            tempdirs.tempfile_for(output_path, &output_parent.metadata()?)
+        }
+        None => {
+            let clone = item
+                .context
+                .operation
+                .clone_temp_files
+                .then(|| tempdirs.clone_for(&item.context.file, &item.context.path, &item.metadata))
+                .flatten();
+            match clone {
+                Some(tmp_file) => Ok(tmp_file),
+                None => tempdirs.tempfile_for(&item.context.path, &item.metadata),
+            }
+        }
+    }
 }
 
 #[tracing::instrument(level = "debug", skip_all, err)]
-fn copy_xattrs(src: &File, dst: &File) -> io::Result<()> {
+pub(crate) fn copy_xattrs(src: &File, dst: &File) -> io::Result<()> {
     // SAFETY:
     //   src and dst fds are valid
     //   passing null state is allowed
@@ -238,12 +701,12 @@ fn copy_xattrs(src: &File, dst: &File) -> io::Result<()> {
     if rc == 0 {
         Ok(())
     } else {
-        Err(io::Error::last_os_error())
+        Err(os_error::last("fcopyfile(COPYFILE_XATTR)"))
     }
 }
 
 #[tracing::instrument(level = "debug", skip_all, err)]
-fn copy_metadata(src: &File, dst: &File) -> io::Result<()> {
+pub(crate) fn copy_metadata(src: &File, dst: &File) -> io::Result<()> {
     // SAFETY:
     //   src and dst fds are valid
     //   passing null state is allowed
@@ -259,11 +722,242 @@ fn copy_metadata(src: &File, dst: &File) -> io::Result<()> {
     if rc == 0 {
         Ok(())
     } else {
-        Err(io::Error::last_os_error())
+        Err(os_error::last("fcopyfile(COPYFILE_SECURITY)"))
+    }
+}
+
+/// The buffer size used to coalesce writes of decompressed blocks
+pub(crate) const UNCOMPRESSED_BUF_SIZE: usize = 4 * 1024 * 1024;
+
+/// Reserve space for `len` bytes in `file`, to avoid growing the file one block at a time
+///
+/// This does not change the length of the file.
+#[tracing::instrument(level = "debug", skip(file), err)]
+pub(crate) fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    let Ok(length) = libc::off_t::try_from(len) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file too large to preallocate",
+        ));
+    };
+    if length == 0 {
+        return Ok(());
+    }
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: length,
+        fst_bytesalloc: 0,
+    };
+    // SAFETY: fd is valid, store is a valid fstore_t, which F_PREALLOCATE expects
+    let mut rc = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) };
+    if rc == -1 {
+        // Contiguous space is not available, allow fragmented allocation
+        store.fst_flags = libc::F_ALLOCATEALL;
+        // SAFETY: fd is valid, store is a valid fstore_t, which F_PREALLOCATE expects
+        rc = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) };
+    }
+    if rc == -1 {
+        Err(os_error::last(format_args!(
+            "fcntl(F_PREALLOCATE, length={length})"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// The number of attributes to name when the decmpfs xattr doesn't fit
+const LARGEST_XATTRS_REPORTED: usize = 3;
+
+/// The decmpfs xattr could not be added, because of the xattrs already on the file
+#[derive(Debug)]
+struct XattrLimitError {
+    source: io::Error,
+    xattr_count: usize,
+    total_xattr_size: u64,
+    /// The largest existing xattrs, largest first
+    largest: Vec<(CString, u64)>,
+}
+
+impl fmt::Display for XattrLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unable to add compression xattr ({}): file already has {} extended attributes \
+             totalling {} bytes",
+            self.source, self.xattr_count, self.total_xattr_size,
+        )?;
+        for (i, (name, size)) in self.largest.iter().enumerate() {
+            let sep = if i == 0 { ", largest: " } else { ", " };
+            write!(f, "{sep}{} ({size} bytes)", name.to_string_lossy())?;
+        }
+        write!(
+            f,
+            "; remove some extended attributes from the file to allow it to be compressed"
+        )
+    }
+}
+
+impl error::Error for XattrLimitError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+fn is_xattr_limit_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<XattrLimitError>())
+}
+
+#[tracing::instrument(level = "debug", skip_all, err)]
+pub(crate) fn set_decmpfs_xattr<F: XattrSource + ?Sized>(f: &F, data: &[u8]) -> io::Result<()> {
+    xattr::set(f, decmpfs::XATTR_NAME, data, 0).map_err(|e| match os_error::errno(&e) {
+        Some(libc::E2BIG | libc::ENOSPC | libc::ERANGE) => explain_xattr_limit(f, e),
+        _ => e,
+    })
+}
+
+/// Describe the existing xattrs on a file which caused the decmpfs xattr not to fit
+///
+/// This is only called after a failure, so gathering the xattr sizes costs nothing when
+/// compression succeeds.
+fn explain_xattr_limit<F: XattrSource + ?Sized>(f: &F, source: io::Error) -> io::Error {
+    let mut xattrs = Vec::new();
+    let res = xattr::with_names(f, |name| {
+        if name == decmpfs::XATTR_NAME || name == resource_fork::XATTR_NAME {
+            return Ok(());
+        }
+        let len = xattr::len(f, name)?.unwrap_or(0);
+        xattrs.push((name.to_owned(), u64::try_from(len).unwrap()));
+        Ok(())
+    });
+    if let Err(e) = res {
+        tracing::debug!("unable to list xattrs after failing to set decmpfs xattr: {e}");
+        return source;
+    }
+
+    let xattr_count = xattrs.len();
+    let total_xattr_size = xattrs.iter().map(|&(_, len)| len).sum();
+    xattrs.sort_by(|(_, lhs), (_, rhs)| rhs.cmp(lhs));
+    xattrs.truncate(LARGEST_XATTRS_REPORTED);
+    io::Error::other(XattrLimitError {
+        source,
+        xattr_count,
+        total_xattr_size,
+        largest: xattrs,
+    })
+}
+
+/// The bytes written for a compressed file beyond its compressed blocks
+///
+/// This is the decmpfs xattr, and the header and trailer of the resource fork. Blocks stored in
+/// the xattr are part of it, so they aren't counted twice.
+fn framing_len(decmpfs_data: &[u8], written: Written) -> u64 {
+    let xattr_len = u64::try_from(decmpfs_data.len()).unwrap();
+    let storage = decmpfs::Value::from_data(decmpfs_data)
+        .ok()
+        .and_then(|value| value.compression_type.compression_storage());
+    match storage {
+        Some((kind, decmpfs::Storage::ResourceFork)) => {
+            xattr_len + kind.header_size(written.block_count) + kind.trailer_size()
+        }
+        _ => xattr_len.saturating_sub(written.compressed_size),
     }
 }
 
-fn ensure_identical_files<R1: BufRead, R2: BufRead>(mut lhs: R1, mut rhs: R2) -> io::Result<()> {
+/// Check that the decmpfs xattr and resource fork stored in a file describe the blocks written
+///
+/// Only the xattr and block table are read, so this is cheap enough to always do before
+/// replacing the original file. It catches torn writes, and the filesystem truncating the xattr.
+pub(crate) fn ensure_written_intact(
+    file: &File,
+    decmpfs_data: &[u8],
+    uncompressed_size: u64,
+    written: Written,
+) -> io::Result<()> {
+    let stored = xattr::read(file, decmpfs::XATTR_NAME)?.unwrap_or_default();
+    if stored != decmpfs_data {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "stored decmpfs xattr ({} bytes) does not match the {} bytes written",
+                stored.len(),
+                decmpfs_data.len()
+            ),
+        ));
+    }
+    check_written(&stored, uncompressed_size, written, || {
+        ResourceFork::new(file)
+    })?;
+    Ok(())
+}
+
+/// Check that a compressed file is consistent, without decompressing any of its blocks
+///
+/// `data_fork_len` is the length of the file before it was marked compressed: once it is
+/// marked compressed, the length of the file is reported as its uncompressed size.
+pub(crate) fn ensure_compressed_structure(
+    file: &File,
+    decmpfs_data: &[u8],
+    uncompressed_size: u64,
+    data_fork_len: u64,
+) -> io::Result<()> {
+    if data_fork_len != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("data fork is {data_fork_len} bytes, expected it to be empty"),
+        ));
+    }
+    let metadata = file.metadata()?;
+    if metadata.st_flags() & libc::UF_COMPRESSED == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file is not marked compressed",
+        ));
+    }
+    if metadata.len() != uncompressed_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "compressed file has size {}, expected {uncompressed_size}",
+                metadata.len()
+            ),
+        ));
+    }
+    check_structure(decmpfs_data, uncompressed_size, || ResourceFork::new(file))?;
+    Ok(())
+}
+
+/// Decompress every block of a compressed file, and ensure the result has the expected checksum
+pub(crate) fn ensure_checksum_matches(file: &File, expected: &Checksum) -> io::Result<()> {
+    let mut hasher = Sha256::new();
+    // Need an extra byte, because lzfse/lzvn needs at least one extra byte to differentiate
+    // between finishing on the last byte and running out of space
+    let mut buf = vec![0; applesauce_core::BLOCK_SIZE + 1];
+    rfork_storage::with_compressed_blocks(file, |kind| {
+        let hasher = &mut hasher;
+        let buf = &mut buf;
+        move |block| {
+            let len = Pool::with(kind, |decompressor| decompressor.decompress(buf, block))?;
+            hasher.update(&buf[..len]);
+            Ok(())
+        }
+    })?;
+
+    let actual: Checksum = hasher.finalize().into();
+    if actual != *expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "checksum of compressed data does not match the original",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn ensure_identical_files<R1: BufRead, R2: BufRead>(
+    mut lhs: R1,
+    mut rhs: R2,
+) -> io::Result<()> {
     loop {
         let l = lhs.fill_buf()?;
         let r = rhs.fill_buf()?;
@@ -293,3 +987,585 @@ fn ensure_identical_files<R1: BufRead, R2: BufRead>(mut lhs: R1, mut rhs: R2) ->
         rhs.consume(min_len)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{Progress, Task};
+    use crate::testing::{
+        assert_entries_equal, incompressible_data, populate_dir, recursive_read, ErrorProgress,
+        Fixture, NoProgress,
+    };
+    use crate::{DecompressionOptions, FileCompressor};
+    use applesauce_core::BLOCK_SIZE;
+    use std::fs;
+    use std::sync::atomic::AtomicU64;
+    use tempfile::NamedTempFile;
+
+    fn compressed_file(data: &[u8], corrupt: bool) -> NamedTempFile {
+        let artifacts =
+            applesauce_core::compress_stream(Kind::default(), 5, data.len() as u64, data).unwrap();
+        let mut resource_fork = artifacts
+            .resource_fork
+            .expect("test data should be stored in the resource fork");
+        if corrupt {
+            let mid = resource_fork.len() / 2;
+            resource_fork[mid] ^= 0xFF;
+        }
+
+        let file = NamedTempFile::new().unwrap();
+        ResourceFork::new(file.as_file())
+            .write_all(&resource_fork)
+            .unwrap();
+        xattr::set(
+            file.as_file(),
+            decmpfs::XATTR_NAME,
+            &artifacts.decmpfs_xattr,
+            0,
+        )
+        .unwrap();
+        file
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..4 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Delegates to a real file, but fails to set the decmpfs xattr as if it was too large
+    struct DecmpfsTooBig<'a>(&'a File);
+
+    impl XattrSource for DecmpfsTooBig<'_> {
+        unsafe fn get_xattr(
+            &self,
+            xattr_name: &std::ffi::CStr,
+            value: *mut u8,
+            size: usize,
+        ) -> libc::ssize_t {
+            // SAFETY: Defer to file impl
+            unsafe { self.0.get_xattr(xattr_name, value, size) }
+        }
+
+        unsafe fn set_xattr(
+            &self,
+            xattr_name: &std::ffi::CStr,
+            value: *const u8,
+            size: usize,
+            offset: u32,
+        ) -> libc::c_int {
+            if xattr_name == decmpfs::XATTR_NAME {
+                // SAFETY: __error returns a valid pointer to this thread's errno
+                unsafe { *libc::__error() = libc::E2BIG };
+                return -1;
+            }
+            // SAFETY: Defer to file impl
+            unsafe { self.0.set_xattr(xattr_name, value, size, offset) }
+        }
+
+        unsafe fn remove_xattr(&self, xattr_name: &std::ffi::CStr) -> libc::c_int {
+            // SAFETY: Defer to file impl
+            unsafe { self.0.remove_xattr(xattr_name) }
+        }
+
+        unsafe fn list_xattr(&self, name_buf: *mut u8, size: usize) -> libc::ssize_t {
+            // SAFETY: Defer to file impl
+            unsafe { self.0.list_xattr(name_buf, size) }
+        }
+    }
+
+    #[test]
+    fn xattr_limit_explained() {
+        let file = NamedTempFile::new().unwrap();
+        for (name, len) in [
+            (c"user.small", 10),
+            (c"user.medium", 1024),
+            (c"user.large", 16 * 1024),
+            (c"user.huge", 64 * 1024),
+        ] {
+            xattr::set(file.as_file(), name, &vec![1; len], 0).unwrap();
+        }
+
+        let err = set_decmpfs_xattr(&DecmpfsTooBig(file.as_file()), &[0; 16]).unwrap_err();
+        assert!(is_xattr_limit_error(&err));
+        let message = err.to_string();
+        assert!(
+            message.contains("4 extended attributes totalling 82954 bytes"),
+            "{message}"
+        );
+        assert!(
+            message.contains(
+                "largest: user.huge (65536 bytes), user.large (16384 bytes), \
+                 user.medium (1024 bytes);"
+            ),
+            "{message}"
+        );
+        assert!(!message.contains("user.small"), "{message}");
+    }
+
+    #[test]
+    fn other_xattr_errors_unchanged() {
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_owned();
+        drop(file);
+        let path = CString::new(path.into_os_string().into_encoded_bytes()).unwrap();
+
+        let err = set_decmpfs_xattr(path.as_c_str(), &[0; 16]).unwrap_err();
+        assert!(!is_xattr_limit_error(&err));
+        assert_eq!(os_error::errno(&err), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn checksum_matches() {
+        let data = test_data();
+        let file = compressed_file(&data, false);
+        let expected: Checksum = Sha256::digest(&data).into();
+        ensure_checksum_matches(file.as_file(), &expected).unwrap();
+    }
+
+    #[test]
+    fn corrupted_file_detected() {
+        let data = test_data();
+        let file = compressed_file(&data, true);
+        let expected: Checksum = Sha256::digest(&data).into();
+        assert!(ensure_checksum_matches(file.as_file(), &expected).is_err());
+    }
+
+    /// Fails every write after the first `write_limit` bytes
+    struct FailingWrites<W> {
+        inner: W,
+        write_limit: usize,
+    }
+
+    impl<W: Write> Write for FailingWrites<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.write_limit == 0 {
+                return Err(io::Error::other("resource fork write failed"));
+            }
+            let len = self.inner.write(&buf[..buf.len().min(self.write_limit)])?;
+            self.write_limit -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<W: Seek> Seek for FailingWrites<W> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn resource_fork_write_failure_leaves_no_xattr() {
+        let data = test_data();
+        let file = NamedTempFile::new().unwrap();
+        let mut writer =
+            applesauce_core::writer::Writer::new(Kind::default(), data.len() as u64, || {
+                FailingWrites {
+                    inner: resource_fork_writer(file.as_file(), None),
+                    // Fail part way through the blocks, after the writer has moved to the resource fork
+                    write_limit: 100,
+                }
+            })
+            .unwrap();
+        let mut compressor = Kind::default().compressor().unwrap();
+        let mut block = vec![0; BLOCK_SIZE * 2];
+        let failed = data.chunks(BLOCK_SIZE).any(|chunk| {
+            let len = compressor.compress(&mut block, chunk, 5).unwrap();
+            writer.add_block(&block[..len]).is_err()
+        });
+        assert!(failed);
+
+        // The writer refuses to describe blocks it didn't write, so the file is never marked
+        let mut decmpfs_data = Vec::new();
+        writer.finish_decmpfs_data(&mut decmpfs_data).unwrap_err();
+        assert_eq!(
+            xattr::read(file.as_file(), decmpfs::XATTR_NAME).unwrap(),
+            None
+        );
+    }
+
+    /// The blocks recorded in the block table of a compressed file
+    fn written_blocks(file: &File, uncompressed_size: u64) -> Written {
+        let block_infos = Kind::default()
+            .read_block_info(ResourceFork::new(file), uncompressed_size)
+            .unwrap();
+        Written {
+            block_count: block_infos.len() as u64,
+            compressed_size: block_infos
+                .iter()
+                .map(|info| u64::from(info.compressed_size))
+                .sum(),
+        }
+    }
+
+    #[test]
+    fn written_intact() {
+        let data = test_data();
+        let file = compressed_file(&data, false);
+        let xattr = decmpfs_xattr(file.as_file());
+        let written = written_blocks(file.as_file(), data.len() as u64);
+        ensure_written_intact(file.as_file(), &xattr, data.len() as u64, written).unwrap();
+    }
+
+    #[test]
+    fn truncated_xattr_caught() {
+        let data = test_data();
+        let file = compressed_file(&data, false);
+        let xattr = decmpfs_xattr(file.as_file());
+        let written = written_blocks(file.as_file(), data.len() as u64);
+        xattr::remove(file.as_file(), decmpfs::XATTR_NAME).unwrap();
+        xattr::set(
+            file.as_file(),
+            decmpfs::XATTR_NAME,
+            &xattr[..xattr.len() - 1],
+            0,
+        )
+        .unwrap();
+
+        let err =
+            ensure_written_intact(file.as_file(), &xattr, data.len() as u64, written).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn short_block_table_caught() {
+        let data = test_data();
+        let file = compressed_file(&data, false);
+        let xattr = decmpfs_xattr(file.as_file());
+        let written = written_blocks(file.as_file(), data.len() as u64);
+        let block_infos = Kind::default()
+            .read_block_info(ResourceFork::new(file.as_file()), data.len() as u64)
+            .unwrap();
+        let sizes: Vec<u32> = block_infos
+            .iter()
+            .map(|info| info.compressed_size)
+            .collect();
+        Kind::default()
+            .finish(ResourceFork::new(file.as_file()), &sizes[..sizes.len() - 1])
+            .unwrap();
+
+        assert!(ensure_written_intact(file.as_file(), &xattr, data.len() as u64, written).is_err());
+    }
+
+    fn decmpfs_xattr(file: &File) -> Vec<u8> {
+        xattr::read(file, decmpfs::XATTR_NAME).unwrap().unwrap()
+    }
+
+    #[test]
+    fn structure_matches() {
+        let data = test_data();
+        let file = compressed_file(&data, false);
+        set_flags(file.as_file(), libc::UF_COMPRESSED).unwrap();
+        let decmpfs_data = decmpfs_xattr(file.as_file());
+        ensure_compressed_structure(file.as_file(), &decmpfs_data, data.len() as u64, 0).unwrap();
+
+        // The wrong size is recorded
+        assert!(ensure_compressed_structure(
+            file.as_file(),
+            &decmpfs_data,
+            data.len() as u64 + 1,
+            0
+        )
+        .is_err());
+        // Data was written to the data fork
+        assert!(
+            ensure_compressed_structure(file.as_file(), &decmpfs_data, data.len() as u64, 10)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn structure_requires_compressed_flag() {
+        let data = test_data();
+        let file = compressed_file(&data, false);
+        let decmpfs_data = decmpfs_xattr(file.as_file());
+        let err = ensure_compressed_structure(file.as_file(), &decmpfs_data, data.len() as u64, 0)
+            .unwrap_err();
+        assert!(err.to_string().contains("not marked compressed"), "{err}");
+    }
+
+    #[test]
+    fn clone_temp_files() {
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let old_contents = recursive_read(dir.path());
+        let mut fc = FileCompressor::new();
+        fc.set_clone_temp_files(true);
+
+        let stats = dir.compress_with(
+            &mut fc,
+            &Fixture::options().verify(Verify::Checksum),
+            &NoProgress,
+        );
+        assert!(stats.compressed_file_count_final.into_inner() > 0);
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+
+        let options = DecompressionOptions::new()
+            .manual(true)
+            .verify(Verify::Full);
+        dir.decompress_with(&mut fc, &options, &NoProgress);
+        assert_eq!(
+            info::get_recursive(dir.path())
+                .unwrap()
+                .num_compressed_files,
+            0
+        );
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+    }
+
+    #[test]
+    fn full_durability() {
+        let dir = Fixture::new();
+        populate_dir(dir.path());
+        let old_contents = recursive_read(dir.path());
+        let mut fc = FileCompressor::new();
+        fc.set_durability(Durability::Full);
+
+        let stats = dir.compress_with(&mut fc, &Fixture::options().level(5), &NoProgress);
+        assert!(stats.compressed_file_count_final.into_inner() > 0);
+        assert_eq!(stats.file_errors.into_inner(), 0);
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+
+        let stats = dir.decompress(&mut fc, &NoProgress);
+        assert_eq!(stats.file_errors.into_inner(), 0);
+        assert_entries_equal(&old_contents, &recursive_read(dir.path()));
+    }
+
+    /// Counts the bytes written for all files
+    #[derive(Default)]
+    struct WrittenProgress {
+        written: Arc<AtomicU64>,
+    }
+
+    struct WrittenTask {
+        written: Arc<AtomicU64>,
+    }
+
+    impl Task for WrittenTask {
+        fn increment(&self, amt: u64) {
+            self.written.fetch_add(amt, Ordering::Relaxed);
+        }
+
+        fn error(&self, _message: &str) {}
+    }
+
+    impl Progress for WrittenProgress {
+        type Task = WrittenTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+            WrittenTask {
+                written: Arc::clone(&self.written),
+            }
+        }
+    }
+
+    #[test]
+    fn sample_blocks() {
+        const BLOCKS: usize = 16;
+        let block_size = BLOCK_SIZE;
+
+        let dir = Fixture::new();
+        let incompressible = dir.path().join("incompressible");
+        let incompressible_data = incompressible_data(BLOCKS * block_size);
+        fs::write(&incompressible, &incompressible_data).unwrap();
+
+        let mut fc = FileCompressor::new();
+        fc.set_sample_blocks(Some(2));
+        let progress = WrittenProgress::default();
+        let stats = dir.compress_with(&mut fc, &Fixture::options().minimum_ratio(0.95), &progress);
+        assert_eq!(stats.skipped.not_compressible_enough.into_inner(), 1);
+        // Skipped after the sample, rather than after most of the file
+        assert!(progress.written.load(Ordering::Relaxed) < 2 * block_size as u64);
+        assert!(!info::get(&incompressible).unwrap().is_compressed);
+        assert_eq!(fs::read(&incompressible).unwrap(), incompressible_data);
+
+        // Without sampling, most of the file is written before giving up
+        fc.set_sample_blocks(None);
+        let progress = WrittenProgress::default();
+        dir.compress_with(&mut fc, &Fixture::options().minimum_ratio(0.95), &progress);
+        assert!(progress.written.load(Ordering::Relaxed) > 2 * block_size as u64);
+
+        // A file which compresses well is still fully compressed
+        fs::remove_file(&incompressible).unwrap();
+        let compressible = dir.path().join("compressible");
+        let compressible_data: Vec<u8> =
+            (0..BLOCKS * block_size).map(|i| (i / 100) as u8).collect();
+        fs::write(&compressible, &compressible_data).unwrap();
+        fc.set_sample_blocks(Some(2));
+        dir.compress_with(
+            &mut fc,
+            &Fixture::options()
+                .minimum_ratio(0.95)
+                .verify(Verify::Checksum),
+            &NoProgress,
+        );
+        assert!(info::get(&compressible).unwrap().is_compressed);
+        assert_eq!(fs::read(&compressible).unwrap(), compressible_data);
+    }
+
+    /// Create a file compressed with a raw compression type, storing `contents` in the xattr
+    fn create_with_raw_type(path: &Path, raw_type: u32, contents: &[u8]) {
+        let file = File::create(path).unwrap();
+        let mut data = Vec::new();
+        decmpfs::Value {
+            compression_type: decmpfs::CompressionType::from_raw_type(raw_type),
+            uncompressed_size: contents.len() as u64,
+            extra_data: contents,
+        }
+        .write_to(&mut data)
+        .unwrap();
+        set_decmpfs_xattr(&file, &data).unwrap();
+        set_flags(&file, libc::UF_COMPRESSED).unwrap();
+    }
+
+    #[test]
+    fn unsupported_type_decompressed_by_os() {
+        let dir = Fixture::new();
+        let path = dir.path().join("file");
+        let contents = b"stored uncompressed in the decmpfs xattr";
+        // Type 1 is uncompressed data in the xattr: readable by the OS, but not by applesauce
+        create_with_raw_type(&path, 1, contents);
+        if fs::read(&path).ok().as_deref() != Some(&contents[..]) {
+            // This OS doesn't support the type either
+            return;
+        }
+
+        let mut fc = FileCompressor::new();
+        fc.set_strict_manual(true);
+        let progress = ErrorProgress::default();
+        let stats = dir.decompress(&mut fc, &progress);
+        assert_eq!(progress.errors.lock().unwrap().len(), 1);
+        assert_eq!(stats.decompressed_by_os.into_inner(), 0);
+        assert!(info::get(&path).unwrap().is_compressed);
+
+        fc.set_strict_manual(false);
+        let stats = dir.decompress_with(
+            &mut fc,
+            &DecompressionOptions::new()
+                .manual(true)
+                .verify(Verify::Checksum),
+            &NoProgress,
+        );
+        assert_eq!(stats.decompressed_by_os.into_inner(), 1);
+        assert!(!info::get(&path).unwrap().is_compressed);
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn missing_resource_fork() {
+        let dir = Fixture::new();
+        let path = dir.path().join("file");
+        let file = File::create(&path).unwrap();
+        let mut data = Vec::new();
+        // Type 4 is zlib, stored in the resource fork, which the file doesn't have
+        decmpfs::Value {
+            compression_type: decmpfs::CompressionType::from_raw_type(4),
+            uncompressed_size: 4096,
+            extra_data: &[],
+        }
+        .write_to(&mut data)
+        .unwrap();
+        set_decmpfs_xattr(&file, &data).unwrap();
+        set_flags(&file, libc::UF_COMPRESSED).unwrap();
+        drop(file);
+
+        let mut fc = FileCompressor::new();
+        let progress = ErrorProgress::default();
+        let stats = dir.decompress(&mut fc, &progress);
+        assert!(progress.errors.lock().unwrap().is_empty());
+        assert_eq!(stats.skipped.missing_resource_fork.into_inner(), 1);
+        assert!(info::get(&path).unwrap().is_compressed);
+
+        fc.set_force_clear(true);
+        let stats = dir.decompress(&mut fc, &progress);
+        assert!(progress.errors.lock().unwrap().is_empty());
+        assert_eq!(stats.cleared_missing_resource_fork.into_inner(), 1);
+        assert!(!info::get(&path).unwrap().is_compressed);
+        assert!(fs::read(&path).unwrap().is_empty());
+        let file = File::open(&path).unwrap();
+        assert!(!xattr::is_present(&file, decmpfs::XATTR_NAME).unwrap());
+    }
+
+    #[test]
+    fn oversized_decmpfs_xattr() {
+        // Compressible, but not enough to fit in a decmpfs xattr
+        let mut state = 0x1234_5678_u32;
+        let contents: Vec<u8> = (0..32 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b'a' + (state >> 28) as u8
+            })
+            .collect();
+        let mut compressed = vec![0; contents.len() + 1024];
+        let len = applesauce_core::compressor::Compressor::zlib()
+            .compress(&mut compressed, &contents, 5)
+            .unwrap();
+        assert!(len > decmpfs::MAX_XATTR_DATA_SIZE);
+
+        for manual in [true, false] {
+            let dir = Fixture::new();
+            let path = dir.path().join("file");
+            let file = File::create(&path).unwrap();
+            // Written by other tools, the data is stored in the xattr despite its size
+            let mut data = Vec::new();
+            decmpfs::Value {
+                compression_type: decmpfs::CompressionType::new(
+                    Kind::Zlib,
+                    decmpfs::Storage::Xattr,
+                ),
+                uncompressed_size: contents.len() as u64,
+                extra_data: &compressed[..len],
+            }
+            .write_to(&mut data)
+            .unwrap();
+            assert!(data.len() > decmpfs::MAX_XATTR_SIZE);
+            set_decmpfs_xattr(&file, &data).unwrap();
+            set_flags(&file, libc::UF_COMPRESSED).unwrap();
+            drop(file);
+            assert_eq!(fs::read(&path).unwrap(), contents);
+
+            let mut fc = FileCompressor::new();
+            let progress = ErrorProgress::default();
+            dir.decompress_with(
+                &mut fc,
+                &DecompressionOptions::new()
+                    .manual(manual)
+                    .verify(Verify::Full),
+                &progress,
+            );
+            assert!(
+                progress.errors.lock().unwrap().is_empty(),
+                "manual: {manual}"
+            );
+            assert!(!info::get(&path).unwrap().is_compressed);
+            assert_eq!(fs::read(&path).unwrap(), contents);
+            let file = File::open(&path).unwrap();
+            assert!(!xattr::is_present(&file, decmpfs::XATTR_NAME).unwrap());
+        }
+    }
+
+    #[test]
+    fn unknown_type_fails_cleanly() {
+        let dir = Fixture::new();
+        let path = dir.path().join("file");
+        create_with_raw_type(&path, 0x7777, b"not a real compression type");
+        if fs::read(&path).is_ok() {
+            return;
+        }
+
+        let mut fc = FileCompressor::new();
+        let progress = ErrorProgress::default();
+        let stats = dir.decompress(&mut fc, &progress);
+        let errors = progress.errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("not supported"), "{}", errors[0]);
+        assert_eq!(stats.decompressed_by_os.into_inner(), 0);
+        assert!(info::get(&path).unwrap().is_compressed);
+    }
+}
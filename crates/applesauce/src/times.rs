@@ -1,11 +1,14 @@
-use crate::times;
-use std::ffi::{c_void, CStr, CString};
+use crate::progress::{DirTimes, DirTimesMismatch};
+use crate::{os_error, path_cstring, times, TimePreservation};
+use std::ffi::{c_void, CStr, CString, OsStr};
 use std::fs::File;
 use std::mem::MaybeUninit;
 use std::os::fd::AsRawFd;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
 use std::{io, mem, ptr};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -14,18 +17,105 @@ pub struct Saved {
     mod_time: libc::timespec,
     access_time: libc::timespec,
     add_time: libc::timespec,
+    /// If false, the access time is left as it is when resetting times
+    restore_access_time: bool,
 }
 
 impl Saved {
+    pub(crate) fn new(
+        create_time: libc::timespec,
+        mod_time: libc::timespec,
+        access_time: libc::timespec,
+        add_time: libc::timespec,
+    ) -> Self {
+        Self {
+            create_time,
+            mod_time,
+            access_time,
+            add_time,
+            restore_access_time: true,
+        }
+    }
+
     fn from_attr_buf(attr_buf: &AttrGetBuf) -> Self {
         assert_eq!(attr_buf.len as usize, mem::size_of_val(attr_buf));
+        Self::new(
+            attr_buf.create_time,
+            attr_buf.mod_time,
+            attr_buf.access_time,
+            attr_buf.add_time,
+        )
+    }
+
+    /// The times to restore according to `preservation`, if any
+    #[must_use]
+    pub fn for_preservation(self, preservation: TimePreservation) -> Option<Self> {
+        match preservation {
+            TimePreservation::All => Some(self),
+            TimePreservation::ModificationOnly => Some(self.without_access_time()),
+            TimePreservation::None => None,
+        }
+    }
+
+    /// Don't restore the access time when resetting times
+    #[must_use]
+    pub fn without_access_time(self) -> Self {
         Self {
-            create_time: attr_buf.create_time,
-            mod_time: attr_buf.mod_time,
-            access_time: attr_buf.access_time,
-            add_time: attr_buf.add_time,
+            restore_access_time: false,
+            ..self
         }
     }
+
+    /// Returns true if `current` has every time which would be restored from these saved times
+    fn restored_in(&self, current: &Saved) -> bool {
+        let eq =
+            |a: libc::timespec, b: libc::timespec| a.tv_sec == b.tv_sec && a.tv_nsec == b.tv_nsec;
+        eq(self.create_time, current.create_time)
+            && eq(self.mod_time, current.mod_time)
+            && eq(self.add_time, current.add_time)
+            && (!self.restore_access_time || eq(self.access_time, current.access_time))
+    }
+
+    /// The times which would be restored from these saved times
+    fn dir_times(&self) -> DirTimes {
+        DirTimes {
+            created: system_time(self.create_time),
+            modified: system_time(self.mod_time),
+            accessed: self
+                .restore_access_time
+                .then(|| system_time(self.access_time)),
+            added: system_time(self.add_time),
+        }
+    }
+
+    /// The attributes to set, and the buffer of their values, in the order setattrlist expects
+    fn attr_set_buf(&self) -> (libc::attrlist, AttrSetBuf) {
+        let mut attrlist = attrlist_set();
+        let buf = if self.restore_access_time {
+            AttrSetBuf {
+                times: [
+                    self.create_time,
+                    self.mod_time,
+                    self.access_time,
+                    self.add_time,
+                ],
+                len: 4,
+            }
+        } else {
+            attrlist.commonattr &= !libc::ATTR_CMN_ACCTIME;
+            // The last time is unused
+            AttrSetBuf {
+                times: [
+                    self.create_time,
+                    self.mod_time,
+                    self.add_time,
+                    self.add_time,
+                ],
+                len: 3,
+            }
+        };
+        (attrlist, buf)
+    }
 }
 
 #[repr(C, packed(4))]
@@ -38,15 +128,19 @@ struct AttrGetBuf {
     add_time: libc::timespec,
 }
 
-#[repr(C, packed(4))]
 struct AttrSetBuf {
-    create_time: libc::timespec,
-    mod_time: libc::timespec,
-    access_time: libc::timespec,
-    add_time: libc::timespec,
+    /// Only the first `len` times are set
+    times: [libc::timespec; 4],
+    len: usize,
 }
 
-trait GetSet {
+impl AttrSetBuf {
+    fn size(&self) -> usize {
+        self.len * mem::size_of::<libc::timespec>()
+    }
+}
+
+pub(crate) trait GetSet {
     fn get_times(&self) -> io::Result<Saved>;
 
     fn reset_times(&self, saved: &Saved) -> io::Result<()>;
@@ -91,7 +185,7 @@ impl GetSet for File {
                 libc::FSOPT_PACK_INVAL_ATTRS,
             );
             if rc != 0 {
-                return Err(io::Error::last_os_error());
+                return Err(os_error::last("fgetattrlist(times)"));
             }
             let attr_buf = attr_buf.assume_init_ref();
             Ok(Saved::from_attr_buf(attr_buf))
@@ -99,26 +193,19 @@ impl GetSet for File {
     }
 
     fn reset_times(&self, saved: &Saved) -> io::Result<()> {
-        let mut attrlist = attrlist_set();
-
-        let mut attr_buf = AttrSetBuf {
-            create_time: saved.create_time,
-            mod_time: saved.mod_time,
-            access_time: saved.access_time,
-            add_time: saved.add_time,
-        };
+        let (mut attrlist, mut attr_buf) = saved.attr_set_buf();
 
         // Safety: attr_buf is filled by a successful call, the fd is valid
         unsafe {
             let rc = libc::fsetattrlist(
                 self.as_raw_fd(),
                 ptr::addr_of_mut!(attrlist).cast::<c_void>(),
-                ptr::addr_of_mut!(attr_buf).cast::<c_void>(),
-                mem::size_of::<AttrSetBuf>(),
+                attr_buf.times.as_mut_ptr().cast::<c_void>(),
+                attr_buf.size(),
                 0,
             );
             if rc != 0 {
-                return Err(io::Error::last_os_error());
+                return Err(os_error::last("fsetattrlist(times)"));
             }
 
             Ok(())
@@ -142,7 +229,7 @@ impl GetSet for CStr {
                 libc::FSOPT_PACK_INVAL_ATTRS,
             );
             if rc != 0 {
-                return Err(io::Error::last_os_error());
+                return Err(os_error::last("getattrlist(times)"));
             }
             let attr_buf = attr_buf.assume_init_ref();
             Ok(Saved::from_attr_buf(attr_buf))
@@ -150,26 +237,19 @@ impl GetSet for CStr {
     }
 
     fn reset_times(&self, saved: &Saved) -> io::Result<()> {
-        let mut attrlist = attrlist_set();
-
-        let mut attr_buf = AttrSetBuf {
-            create_time: saved.create_time,
-            mod_time: saved.mod_time,
-            access_time: saved.access_time,
-            add_time: saved.add_time,
-        };
+        let (mut attrlist, mut attr_buf) = saved.attr_set_buf();
 
         // Safety: attr_buf is filled by a successful call
         unsafe {
             let rc = libc::setattrlist(
                 self.as_ptr(),
                 ptr::addr_of_mut!(attrlist).cast::<c_void>(),
-                ptr::addr_of_mut!(attr_buf).cast::<c_void>(),
-                mem::size_of::<AttrSetBuf>(),
+                attr_buf.times.as_mut_ptr().cast::<c_void>(),
+                attr_buf.size(),
                 0,
             );
             if rc != 0 {
-                return Err(io::Error::last_os_error());
+                return Err(os_error::last("setattrlist(times)"));
             }
 
             Ok(())
@@ -179,12 +259,12 @@ impl GetSet for CStr {
 
 impl GetSet for Path {
     fn get_times(&self) -> io::Result<Saved> {
-        let cstr = CString::new(self.as_os_str().as_bytes())?;
+        let cstr = path_cstring(self)?;
         <CStr as GetSet>::get_times(&cstr)
     }
 
     fn reset_times(&self, saved: &Saved) -> io::Result<()> {
-        let cstr = CString::new(self.as_os_str().as_bytes())?;
+        let cstr = path_cstring(self)?;
         <CStr as GetSet>::reset_times(&cstr, saved)
     }
 }
@@ -195,42 +275,427 @@ pub fn save_times<F: GetSet + std::fmt::Debug + ?Sized>(f: &F) -> io::Result<Sav
     f.get_times()
 }
 
+/// Save the times of a file which should be restored according to `preservation`
+///
+/// Returns `None` without reading any times if no times should be restored.
+pub fn save_times_for<F: GetSet + std::fmt::Debug + ?Sized>(
+    f: &F,
+    preservation: TimePreservation,
+) -> io::Result<Option<Saved>> {
+    match preservation {
+        TimePreservation::None => Ok(None),
+        _ => save_times(f).map(|saved| saved.for_preservation(preservation)),
+    }
+}
+
 #[tracing::instrument(level = "debug")]
 #[inline]
 pub fn reset_times<F: GetSet + std::fmt::Debug + ?Sized>(f: &F, saved: &Saved) -> io::Result<()> {
     f.reset_times(saved)
 }
 
+fn system_time(time: libc::timespec) -> SystemTime {
+    let nanos = Duration::from_nanos(time.tv_nsec as u64);
+    match u64::try_from(time.tv_sec) {
+        Ok(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs) + nanos,
+        Err(_) => SystemTime::UNIX_EPOCH - Duration::from_secs(time.tv_sec.unsigned_abs()) + nanos,
+    }
+}
+
+/// A restoration of the times of a directory, which can be checked at the end of the run
+#[derive(Debug)]
+struct Restored {
+    dir_path: CString,
+    saved_times: Saved,
+    error: Option<io::Error>,
+}
+
+/// A record of every restoration of directory times during a run
+#[derive(Debug, Default)]
+pub struct RestoreLog {
+    restored: Mutex<Vec<Restored>>,
+}
+
+impl RestoreLog {
+    /// Re-read the current times of every directory restored so far, returning those which don't
+    /// have the restored times
+    ///
+    /// Each restoration is only checked once.
+    pub fn mismatches(&self) -> Vec<DirTimesMismatch> {
+        let restored = mem::take(&mut *self.restored.lock().unwrap());
+        restored
+            .into_iter()
+            .filter_map(|restored| {
+                let current = times::save_times(restored.dir_path.as_c_str());
+                if let Ok(current) = &current {
+                    if restored.saved_times.restored_in(current) {
+                        return None;
+                    }
+                }
+                Some(DirTimesMismatch {
+                    path: PathBuf::from(OsStr::from_bytes(restored.dir_path.as_bytes())),
+                    expected: restored.saved_times.dir_times(),
+                    actual: current.map(|current| {
+                        // Only compare the times which would have been restored
+                        let current = if restored.saved_times.restore_access_time {
+                            current
+                        } else {
+                            current.without_access_time()
+                        };
+                        current.dir_times()
+                    }),
+                    restore_error: restored.error,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The number of [`Resetter`]s of a run which have not been dropped yet
+///
+/// Resetters are dropped by whichever thread finishes with the last file in their directory, so
+/// this allows waiting until every one has restored its directory's times.
+#[derive(Debug, Default)]
+pub struct PendingResets {
+    count: Mutex<usize>,
+    done: Condvar,
+}
+
+impl PendingResets {
+    /// Block until every resetter tracked by this has been dropped
+    pub fn wait(&self) {
+        let count = self.count.lock().unwrap();
+        let _count = self.done.wait_while(count, |count| *count != 0).unwrap();
+    }
+
+    fn add(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    fn remove(&self) {
+        let mut count = self.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.done.notify_all();
+        }
+    }
+}
+
 /// Reset the times of a file/dir
 ///
 /// By default, will do nothing on drop, unless `activate` is called at least once
+///
+/// The times are only reset if the directory hasn't been modified since the last change attributed
+/// to us: the modification time of the directory is read after each of our changes, and if it has
+/// moved on by the time the directory is restored, something else (e.g. another process creating a
+/// file) changed the directory, and that change is left visible. A change made by something else
+/// in the short window between one of our changes and reading the time after it can't be
+/// distinguished from ours, and is still hidden.
 #[derive(Debug)]
 pub struct Resetter {
     dir_path: CString,
     saved_times: Saved,
     activated: AtomicBool,
+    /// The latest modification time of the directory seen after one of our changes
+    own_mod_time: Mutex<Option<libc::timespec>>,
+    /// Where to record the restoration, if it should be checked later
+    log: Option<Arc<RestoreLog>>,
+    /// Notified once this has been dropped
+    pending: Option<Arc<PendingResets>>,
 }
 
 impl Resetter {
-    pub fn new(path: &Path, saved_times: Saved) -> io::Result<Self> {
-        let dir_path = CString::new(path.as_os_str().as_bytes())?;
+    pub fn new(
+        path: &Path,
+        saved_times: Saved,
+        log: Option<Arc<RestoreLog>>,
+        pending: Option<Arc<PendingResets>>,
+    ) -> io::Result<Self> {
+        let dir_path = path_cstring(path)?;
+        if let Some(pending) = &pending {
+            pending.add();
+        }
         Ok(Self {
             dir_path,
             saved_times,
             activated: AtomicBool::new(false),
+            own_mod_time: Mutex::new(None),
+            log,
+            pending,
         })
     }
 
+    /// Record that we have just changed the directory, so its times should be reset
     pub fn activate(&self) {
         self.activated
             .store(true, std::sync::atomic::Ordering::Relaxed);
+        match times::save_times(self.dir_path.as_c_str()) {
+            Ok(current) => {
+                let mut own_mod_time = self.own_mod_time.lock().unwrap();
+                // Changes by other threads may be seen out of order, keep the latest
+                if own_mod_time.is_none_or(|own| timespec_key(own) < timespec_key(current.mod_time))
+                {
+                    *own_mod_time = Some(current.mod_time);
+                }
+            }
+            Err(e) => tracing::debug!("unable to read times of {:?}: {e}", self.dir_path),
+        }
+    }
+
+    /// Returns true if the directory has been modified since the last change attributed to us
+    fn modified_externally(&self) -> bool {
+        let Some(own_mod_time) = *self.own_mod_time.lock().unwrap() else {
+            // Without knowing the time after our changes, restore the times as before
+            return false;
+        };
+        match times::save_times(self.dir_path.as_c_str()) {
+            Ok(current) => timespec_key(current.mod_time) != timespec_key(own_mod_time),
+            Err(_) => false,
+        }
     }
 }
 
+fn timespec_key(time: libc::timespec) -> (libc::time_t, libc::c_long) {
+    (time.tv_sec, time.tv_nsec)
+}
+
 impl Drop for Resetter {
     fn drop(&mut self) {
         if self.activated.load(std::sync::atomic::Ordering::Relaxed) {
-            let _ = times::reset_times(self.dir_path.as_c_str(), &self.saved_times);
+            if self.modified_externally() {
+                tracing::debug!(
+                    "not resetting times of {:?}, it was modified by something else",
+                    self.dir_path
+                );
+            } else {
+                let res = times::reset_times(self.dir_path.as_c_str(), &self.saved_times);
+                if let Err(e) = &res {
+                    tracing::debug!("unable to reset times of {:?}: {e}", self.dir_path);
+                }
+                if let Some(log) = &self.log {
+                    log.restored.lock().unwrap().push(Restored {
+                        dir_path: mem::take(&mut self.dir_path),
+                        saved_times: self.saved_times,
+                        error: res.err(),
+                    });
+                }
+            }
+        }
+        if let Some(pending) = &self.pending {
+            pending.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::{FileSummary, Progress, Task};
+    use crate::testing::{compressed_files, Fixture, NoProgress};
+    use crate::{info, set_flags, FileCompressor};
+    use std::fs;
+
+    /// The path, expected and actual modification times, restore error, and description of a
+    /// directory whose times were not restored
+    type DirTimesReport = (
+        PathBuf,
+        SystemTime,
+        SystemTime,
+        Option<io::ErrorKind>,
+        String,
+    );
+
+    struct DirTimesProgress {
+        lock_dir: PathBuf,
+        mismatches: Mutex<Vec<DirTimesReport>>,
+    }
+
+    struct LockingTask {
+        path: PathBuf,
+        lock_dir: PathBuf,
+    }
+
+    impl Task for LockingTask {
+        fn increment(&self, _amt: u64) {}
+        fn error(&self, message: &str) {
+            panic!("Expected no errors, got {message} for {:?}", self.path);
+        }
+        fn finished(&self, _summary: FileSummary) {
+            // The file has been replaced, but the times of its directory haven't been restored
+            if self.path.parent() == Some(&self.lock_dir) {
+                set_flags(&File::open(&self.lock_dir).unwrap(), libc::UF_IMMUTABLE).unwrap();
+            }
+        }
+    }
+
+    impl Progress for DirTimesProgress {
+        type Task = LockingTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn dir_times_not_restored(&self, mismatch: &DirTimesMismatch) {
+            self.mismatches.lock().unwrap().push((
+                mismatch.path.clone(),
+                mismatch.expected.modified,
+                mismatch.actual.as_ref().unwrap().modified,
+                mismatch.restore_error.as_ref().map(io::Error::kind),
+                mismatch.to_string(),
+            ));
+        }
+
+        fn file_task(&self, path: &Path, _size: u64, _root: usize) -> Self::Task {
+            LockingTask {
+                path: path.to_owned(),
+                lock_dir: self.lock_dir.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn check_dir_times() {
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let dir = Fixture::new();
+        let locked = dir.path().join("locked");
+        let unlocked = dir.path().join("unlocked");
+        for subdir in [&locked, &unlocked] {
+            fs::create_dir(subdir).unwrap();
+            fs::write(subdir.join("file"), [0; 16 * 1024]).unwrap();
+            File::open(subdir).unwrap().set_modified(old_time).unwrap();
         }
+
+        let progress = DirTimesProgress {
+            lock_dir: locked.clone(),
+            mismatches: Mutex::default(),
+        };
+        let mut fc = FileCompressor::new();
+        fc.set_check_dir_times(true);
+        let stats = dir.compress(&mut fc, &progress);
+        set_flags(&File::open(&locked).unwrap(), 0).unwrap();
+
+        assert_eq!(stats.dir_times_not_restored.into_inner(), 1);
+        let mismatches = progress.mismatches.into_inner().unwrap();
+        assert_eq!(mismatches.len(), 1);
+        let (path, expected, actual, restore_error, description) = &mismatches[0];
+        assert_eq!(path, &locked);
+        assert_eq!(*expected, old_time);
+        assert_ne!(*actual, old_time);
+        assert_eq!(*restore_error, Some(io::ErrorKind::PermissionDenied));
+        assert!(description.contains("modified"), "{description}");
+        assert!(info::get(&locked.join("file")).unwrap().is_compressed);
+        assert_eq!(unlocked.metadata().unwrap().modified().unwrap(), old_time);
+    }
+
+    /// Creates a file in `changed_dir` whenever a file in it has been replaced, like another process
+    /// changing the directory during the run
+    struct ExternalChangeProgress {
+        changed_dir: PathBuf,
+    }
+
+    struct ExternalChangeTask {
+        path: PathBuf,
+        changed_dir: PathBuf,
+    }
+
+    impl Task for ExternalChangeTask {
+        fn increment(&self, _amt: u64) {}
+        fn error(&self, message: &str) {
+            panic!("Expected no errors, got {message} for {:?}", self.path);
+        }
+        fn finished(&self, _summary: FileSummary) {
+            if self.path.parent() == Some(&self.changed_dir) {
+                fs::write(self.changed_dir.join("external"), b"external").unwrap();
+            }
+        }
+    }
+
+    impl Progress for ExternalChangeProgress {
+        type Task = ExternalChangeTask;
+
+        fn error(&self, path: &Path, message: &str) {
+            panic!("Expected no errors, got {message} for {path:?}");
+        }
+
+        fn file_task(&self, path: &Path, _size: u64, _root: usize) -> Self::Task {
+            ExternalChangeTask {
+                path: path.to_owned(),
+                changed_dir: self.changed_dir.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn dir_times_external_change() {
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let run = |time_preservation| {
+            let dir = Fixture::new();
+            let changed = dir.path().join("changed");
+            let unchanged = dir.path().join("unchanged");
+            for subdir in [&changed, &unchanged] {
+                fs::create_dir(subdir).unwrap();
+                fs::write(subdir.join("file"), [0; 16 * 1024]).unwrap();
+                File::open(subdir).unwrap().set_modified(old_time).unwrap();
+            }
+
+            let progress = ExternalChangeProgress {
+                changed_dir: changed.clone(),
+            };
+            let mut fc = FileCompressor::new();
+            fc.set_check_dir_times(true);
+            fc.set_time_preservation(time_preservation);
+            let stats = dir.compress(&mut fc, &progress);
+
+            assert_eq!(
+                compressed_files(dir.path()),
+                [
+                    PathBuf::from("changed/file"),
+                    PathBuf::from("unchanged/file")
+                ]
+            );
+            assert!(changed.join("external").exists());
+            // Leaving a directory changed by something else isn't a failure to restore it
+            assert_eq!(stats.dir_times_not_restored.into_inner(), 0);
+            let modified = |dir: &Path| dir.metadata().unwrap().modified().unwrap();
+            (
+                modified(&changed) == old_time,
+                modified(&unchanged) == old_time,
+            )
+        };
+
+        // The file created during the run is still visible in the directory's modification time
+        assert_eq!(run(TimePreservation::All), (false, true));
+        assert_eq!(run(TimePreservation::ModificationOnly), (false, true));
+        assert_eq!(run(TimePreservation::None), (false, false));
+    }
+
+    #[test]
+    fn time_preservation() {
+        use std::fs::FileTimes;
+        let old = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        let run = |time_preservation| {
+            let dir = Fixture::new();
+            let path = dir.file("file", [0; 16 * 1024]);
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_times(FileTimes::new().set_accessed(old).set_modified(old))
+                .unwrap();
+
+            let mut fc = FileCompressor::new();
+            fc.set_time_preservation(time_preservation);
+            dir.compress(&mut fc, &NoProgress);
+            assert_eq!(compressed_files(dir.path()), [PathBuf::from("file")]);
+            let metadata = path.metadata().unwrap();
+            (
+                metadata.accessed().unwrap() == old,
+                metadata.modified().unwrap() == old,
+            )
+        };
+
+        assert_eq!(run(TimePreservation::All), (true, true));
+        assert_eq!(run(TimePreservation::ModificationOnly), (false, true));
+        assert_eq!(run(TimePreservation::None), (false, false));
     }
 }
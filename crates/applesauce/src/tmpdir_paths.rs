@@ -1,7 +1,11 @@
+use crate::threads::volumes;
+use crate::{os_error, path_cstring, set_flags, xattr};
+use applesauce_core::decmpfs;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs::Metadata;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata};
 use std::io;
+use std::os::fd::AsRawFd;
 use std::os::macos::fs::MetadataExt;
 use std::path::Path;
 use tempfile::{NamedTempFile, TempDir};
@@ -13,6 +17,8 @@ const TEMPFILE_PREFIX: &str = "applesauce_tmp";
 pub struct TmpdirPaths {
     /// Map from device to temp dir
     dirs: HashMap<u64, TempDir>,
+    /// Devices with a temp dir which are APFS volumes, where temp files can be clones
+    apfs: HashSet<u64>,
 }
 
 impl TmpdirPaths {
@@ -33,7 +39,10 @@ impl TmpdirPaths {
             }
         }
 
-        Self { dirs }
+        Self {
+            dirs,
+            apfs: HashSet::new(),
+        }
     }
 
     pub fn paths(&self) -> impl Iterator<Item = &Path> + '_ {
@@ -61,13 +70,16 @@ impl TmpdirPaths {
                     parent
                 };
                 let dir = TempDir::with_prefix_in(TEMPDIR_PREFIX, tmpdir_parent)?;
+                if volumes::is_apfs(dir.path()) {
+                    self.apfs.insert(device);
+                }
                 entry.insert(dir);
             }
         }
         Ok(())
     }
 
-    pub fn tempfile_for(&self, path: &Path, metadata: &Metadata) -> io::Result<NamedTempFile> {
+    pub fn tempfile_for(&self, path: &Path, metadata: &Metadata) -> io::Result<TmpFile> {
         let device = metadata.st_dev();
         let dir = match self.dirs.get(&device) {
             Some(dir) => dir.path(),
@@ -82,11 +94,262 @@ impl TmpdirPaths {
             }
         };
 
-        let mut builder = tempfile::Builder::new();
-        builder.prefix(TEMPFILE_PREFIX);
-        if let Some(file_name) = path.file_name() {
-            builder.suffix(file_name);
+        builder(path).tempfile_in(dir).map(TmpFile::new)
+    }
+
+    /// Create a temp file to replace `path`, as an APFS clone of `file` (the open original)
+    ///
+    /// A clone starts with the original's xattrs, ACLs, and ownership, so they don't need to be
+    /// copied to it. Its flags are cleared, and its contents and any compressed data are removed,
+    /// so it can be written like any other temp file.
+    ///
+    /// Returns `None` if the file's volume isn't APFS, or the clone couldn't be made.
+    pub fn clone_for(&self, file: &File, path: &Path, metadata: &Metadata) -> Option<TmpFile> {
+        let device = metadata.st_dev();
+        if !self.apfs.contains(&device) {
+            return None;
+        }
+        let dir = self.dirs.get(&device)?;
+        let result = builder(path)
+            .make_in(dir.path(), |tmp_path| clone_into(file, tmp_path))
+            .map(|file| TmpFile {
+                file: Some(file),
+                cloned: true,
+            })
+            .and_then(|tmp_file| {
+                remove_contents(tmp_file.as_file())?;
+                Ok(tmp_file)
+            });
+        match result {
+            Ok(tmp_file) => Some(tmp_file),
+            Err(e) => {
+                tracing::debug!("unable to clone {}: {e}", path.display());
+                None
+            }
+        }
+    }
+}
+
+fn builder(path: &Path) -> tempfile::Builder<'_, '_> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(TEMPFILE_PREFIX);
+    if let Some(file_name) = path.file_name() {
+        builder.suffix(file_name);
+    }
+    builder
+}
+
+/// Clone `src` to a new file at `dst`, with no flags set, and open it for writing
+fn clone_into(src: &File, dst: &Path) -> io::Result<File> {
+    let dst_cstr = path_cstring(dst)?;
+    // SAFETY: src is a valid fd, dst is a valid, null terminated path, and a relative dst (which
+    // it never is) would be resolved against the current dir
+    let rc = unsafe { libc::fclonefileat(src.as_raw_fd(), libc::AT_FDCWD, dst_cstr.as_ptr(), 0) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The original's flags (e.g. `UF_IMMUTABLE`) may stop the clone being opened for writing, but
+    // don't stop it being opened to change them
+    let open = || {
+        set_flags(&File::open(dst)?, 0)?;
+        File::options().read(true).write(true).open(dst)
+    };
+    open().inspect_err(|_| {
+        if let Err(e) = fs::remove_file(dst) {
+            tracing::warn!("unable to remove clone {}: {e}", dst.display());
+        }
+    })
+}
+
+/// Remove the contents of a clone, including the compressed data of a compressed original
+fn remove_contents(file: &File) -> io::Result<()> {
+    for xattr_name in [decmpfs::XATTR_NAME, resource_fork::XATTR_NAME] {
+        match xattr::remove(file, xattr_name) {
+            Err(e) if os_error::errno(&e) != Some(libc::ENOATTR) => return Err(e),
+            _ => {}
+        }
+    }
+    file.set_len(0)
+}
+
+/// A temp file which will replace another file, deleted if it is dropped without being persisted
+///
+/// Before the file is deleted, its flags, decmpfs xattr, and resource fork are removed: flags
+/// copied from the original file may stop the temp file from being deleted (e.g. `UF_IMMUTABLE`),
+/// and a failure part way through writing a file should never leave anything behind in the temp
+/// dir.
+pub struct TmpFile {
+    file: Option<NamedTempFile>,
+    /// Created by [`TmpdirPaths::clone_for`]
+    cloned: bool,
+}
+
+impl TmpFile {
+    fn new(file: NamedTempFile) -> Self {
+        Self {
+            file: Some(file),
+            cloned: false,
+        }
+    }
+
+    /// Returns true if this is a clone of the original, which already has its xattrs and
+    /// security info
+    pub fn is_clone(&self) -> bool {
+        self.cloned
+    }
+
+    pub fn as_file(&self) -> &File {
+        self.file.as_ref().unwrap().as_file()
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        self.file.as_mut().unwrap().as_file_mut()
+    }
+
+    /// Replace `path` with this file
+    pub fn persist(mut self, path: &Path) -> io::Result<File> {
+        let file = self.file.take().unwrap();
+        file.persist(path).map_err(|e| {
+            discard(e.file.as_file());
+            e.error
+        })
+    }
+
+    /// Move this file to `path`, failing if anything already exists there
+    pub fn persist_noclobber(mut self, path: &Path) -> io::Result<File> {
+        let file = self.file.take().unwrap();
+        file.persist_noclobber(path).map_err(|e| {
+            discard(e.file.as_file());
+            e.error
+        })
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            discard(file.as_file());
+        }
+    }
+}
+
+fn discard(file: &File) {
+    if let Err(e) = set_flags(file, 0) {
+        tracing::warn!("unable to clear flags of temp file: {e}");
+    }
+    for xattr_name in [decmpfs::XATTR_NAME, resource_fork::XATTR_NAME] {
+        match xattr::remove(file, xattr_name) {
+            Err(e) if os_error::errno(&e) != Some(libc::ENOATTR) => {
+                tracing::warn!(
+                    "unable to remove {} from temp file: {e}",
+                    xattr_name.to_string_lossy()
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn dropped_tmp_file_is_removed() {
+        let dir = TempDir::new().unwrap();
+        let mut file = TmpFile::new(NamedTempFile::new_in(dir.path()).unwrap());
+        file.as_file_mut().write_all(b"data").unwrap();
+        xattr::set(
+            file.as_file(),
+            decmpfs::XATTR_NAME,
+            b"not really decmpfs",
+            0,
+        )
+        .unwrap();
+        resource_fork::ResourceFork::new(file.as_file())
+            .write_all(b"resource fork")
+            .unwrap();
+        // An immutable file cannot be deleted
+        set_flags(file.as_file(), libc::UF_IMMUTABLE).unwrap();
+        assert_ne!(
+            file.as_file().metadata().unwrap().st_flags() & libc::UF_IMMUTABLE,
+            0
+        );
+
+        drop(file);
+        assert_eq!(dir.path().read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failed_persist_is_removed() {
+        let dir = TempDir::new().unwrap();
+        let file = TmpFile::new(NamedTempFile::new_in(dir.path()).unwrap());
+        set_flags(file.as_file(), libc::UF_IMMUTABLE).unwrap();
+
+        let missing_dir = dir.path().join("missing").join("file");
+        assert!(file.persist(&missing_dir).is_err());
+        assert_eq!(dir.path().read_dir().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn clone_for() {
+        let dir = TempDir::new().unwrap();
+        if !volumes::is_apfs(dir.path()) {
+            return;
+        }
+        let plain = dir.path().join("plain");
+        fs::write(&plain, [1; 64 * 1024]).unwrap();
+        xattr::set(
+            &File::open(&plain).unwrap(),
+            c"com.example.test",
+            b"value",
+            0,
+        )
+        .unwrap();
+        let compressed = dir.path().join("compressed");
+        fs::write(&compressed, [1; 64 * 1024]).unwrap();
+        crate::compress_file(&compressed, Default::default(), 5, 1.0, crate::Verify::Off).unwrap();
+
+        let mut tmpdirs = TmpdirPaths::new();
+        tmpdirs
+            .add_dst(dir.path(), &dir.path().metadata().unwrap())
+            .unwrap();
+        for path in [&plain, &compressed] {
+            let file = File::open(path).unwrap();
+            // An immutable original can still be cloned, the clone's flags are cleared
+            set_flags(
+                &file,
+                file.metadata().unwrap().st_flags() | libc::UF_IMMUTABLE,
+            )
+            .unwrap();
+            let clone = tmpdirs
+                .clone_for(&file, path, &file.metadata().unwrap())
+                .unwrap();
+            assert!(clone.is_clone());
+            let metadata = clone.as_file().metadata().unwrap();
+            assert_eq!(metadata.len(), 0, "{}", path.display());
+            assert_eq!(metadata.st_flags(), 0, "{}", path.display());
+            assert!(!xattr::is_present(clone.as_file(), decmpfs::XATTR_NAME).unwrap());
+            assert!(!xattr::is_present(clone.as_file(), resource_fork::XATTR_NAME).unwrap());
+            set_flags(
+                &file,
+                file.metadata().unwrap().st_flags() & !libc::UF_IMMUTABLE,
+            )
+            .unwrap();
+        }
+        let file = File::open(&plain).unwrap();
+        let clone = tmpdirs
+            .clone_for(&file, &plain, &file.metadata().unwrap())
+            .unwrap();
+        assert_eq!(
+            xattr::read(clone.as_file(), c"com.example.test").unwrap(),
+            Some(b"value".to_vec())
+        );
+        drop(clone);
+        for dir in tmpdirs.paths() {
+            assert_eq!(dir.read_dir().unwrap().count(), 0);
         }
-        builder.tempfile_in(dir)
     }
 }
@@ -1,14 +1,26 @@
+use crate::os_error;
 use libc::ssize_t;
 use memchr::memchr;
 use std::cmp::Ordering;
 use std::ffi::{c_int, CStr, CString};
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
-use std::{io, ptr};
+use std::{fmt, io, ptr};
 
 const FLAGS: c_int = libc::XATTR_SHOWCOMPRESSION;
 
+/// The largest xattr [`read`] will read, the largest xattr macOS allows to be set
+///
+/// Only the resource fork can be larger, and it is never read whole.
+pub const MAX_READ_SIZE: usize = 64 * 1024 * 1024;
+
+/// How many times to try reading an xattr which keeps changing size before giving up
+const MAX_READ_ATTEMPTS: u32 = 8;
+
 pub trait XattrSource {
+    /// The prefix of the names of the calls used, in errors: `f` for calls on a file descriptor
+    const CALL_PREFIX: &'static str = "";
+
     unsafe fn get_xattr(&self, xattr_name: &CStr, value: *mut u8, size: usize) -> ssize_t;
     unsafe fn set_xattr(
         &self,
@@ -99,6 +111,8 @@ impl XattrSource for CString {
 }
 
 impl XattrSource for File {
+    const CALL_PREFIX: &'static str = "f";
+
     unsafe fn get_xattr(&self, xattr_name: &CStr, value: *mut u8, size: usize) -> ssize_t {
         // SAFETY:
         //   self.as_raw_fd is a valid fd
@@ -155,26 +169,69 @@ impl XattrSource for File {
     }
 }
 
+/// Call `f` until it is not interrupted by a signal
+///
+/// A negative return value is converted to the error from `errno`.
+fn retry_interrupted<T: Copy + PartialOrd + From<i8>>(mut f: impl FnMut() -> T) -> io::Result<T> {
+    loop {
+        let rc = f();
+        if rc >= T::from(0) {
+            return Ok(rc);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Name the call on `f` which returned `e`, and the xattr it was for
+fn call_error<F: XattrSource + ?Sized>(
+    e: io::Error,
+    call: &str,
+    xattr_name: &CStr,
+    args: fmt::Arguments<'_>,
+) -> io::Error {
+    os_error::with_call(
+        e,
+        format_args!(
+            "{}{call}({}{args})",
+            F::CALL_PREFIX,
+            xattr_name.to_string_lossy()
+        ),
+    )
+}
+
+/// Name the call to list the xattrs of `f` which returned `e`
+fn list_error<F: XattrSource + ?Sized>(e: io::Error) -> io::Error {
+    os_error::with_call(e, format_args!("{}listxattr", F::CALL_PREFIX))
+}
+
 pub fn len<F: XattrSource + ?Sized>(f: &F, xattr_name: &CStr) -> io::Result<Option<usize>> {
     // SAFETY:
     // f is valid, xattr_name is a valid pointer and is null terminated
     // value == NULL, size == 0 is allowed to just return the size
-    let rc = unsafe { f.get_xattr(xattr_name, ptr::null_mut(), 0) };
-    if rc == -1 {
-        let last_error = io::Error::last_os_error();
-        return if last_error.raw_os_error() == Some(libc::ENOATTR) {
-            Ok(None)
-        } else {
-            Err(last_error)
-        };
+    match retry_interrupted(|| unsafe { f.get_xattr(xattr_name, ptr::null_mut(), 0) }) {
+        Ok(rc) => Ok(Some(rc as usize)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOATTR) => Ok(None),
+        Err(e) => Err(call_error::<F>(e, "getxattr", xattr_name, format_args!(""))),
     }
-    Ok(Some(rc as usize))
 }
 
 pub fn is_present<F: XattrSource + ?Sized>(f: &F, xattr_name: &CStr) -> io::Result<bool> {
     len(f, xattr_name).map(|len| len.is_some())
 }
 
+/// Returns true if the file has any xattrs
+///
+/// This is a single call, so it can cheaply rule out every xattr at once.
+pub fn has_any<F: XattrSource + ?Sized>(f: &F) -> io::Result<bool> {
+    // SAFETY: it is safe to pass list=null, size=0 to get the size of the list
+    let rc = retry_interrupted(|| unsafe { f.list_xattr(ptr::null_mut(), 0) })
+        .map_err(list_error::<F>)?;
+    Ok(rc > 0)
+}
+
 pub fn set<F: XattrSource + ?Sized>(
     f: &F,
     xattr_name: &CStr,
@@ -185,22 +242,56 @@ pub fn set<F: XattrSource + ?Sized>(
     // f is valid
     // xattr name is valid and null terminated
     // value is valid, writable, and initialized up to `.len()` bytes
-    let rc = unsafe { f.set_xattr(xattr_name, data.as_ptr(), data.len(), offset) };
-    if rc == 0 {
-        Ok(())
-    } else {
-        Err(io::Error::last_os_error())
-    }
+    retry_interrupted(|| unsafe { f.set_xattr(xattr_name, data.as_ptr(), data.len(), offset) })
+        .map_err(|e| {
+            call_error::<F>(e, "setxattr", xattr_name, format_args!(", offset={offset}"))
+        })?;
+    Ok(())
+}
+
+pub fn remove<F: XattrSource + ?Sized>(f: &F, xattr_name: &CStr) -> io::Result<()> {
+    // SAFETY:
+    // f is valid
+    // xattr name is valid and null terminated
+    retry_interrupted(|| unsafe { f.remove_xattr(xattr_name) })
+        .map_err(|e| call_error::<F>(e, "removexattr", xattr_name, format_args!("")))?;
+    Ok(())
 }
 
+/// Read the whole value of an xattr, if it exists
+///
+/// Returns an [`InvalidData`](io::ErrorKind::InvalidData) error if the xattr is larger than
+/// [`MAX_READ_SIZE`], see [`read_limited`].
 pub fn read<F: XattrSource + ?Sized>(f: &F, xattr_name: &CStr) -> io::Result<Option<Vec<u8>>> {
+    read_limited(f, xattr_name, MAX_READ_SIZE)
+}
+
+/// Read the whole value of an xattr, if it exists and is at most `max_len` bytes
+///
+/// Returns an [`InvalidData`](io::ErrorKind::InvalidData) error if the xattr is larger than
+/// `max_len`, without allocating space for it. An xattr which keeps changing size while it's being
+/// read is only retried a few times before returning an error.
+pub fn read_limited<F: XattrSource + ?Sized>(
+    f: &F,
+    xattr_name: &CStr,
+    max_len: usize,
+) -> io::Result<Option<Vec<u8>>> {
     let mut buf = Vec::new();
 
-    loop {
+    for _ in 0..MAX_READ_ATTEMPTS {
         let len = match len(f, xattr_name)? {
             Some(len) => len,
             None => return Ok(None),
         };
+        if len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "xattr {} is {len} bytes, larger than the limit of {max_len} bytes",
+                    xattr_name.to_string_lossy()
+                ),
+            ));
+        }
         if len > buf.len() {
             buf.resize(len, 0);
         }
@@ -208,28 +299,41 @@ pub fn read<F: XattrSource + ?Sized>(f: &F, xattr_name: &CStr) -> io::Result<Opt
         // SAFETY:
         // path/xattr_name are valid pointers and are null terminated
         // value == NULL, size == 0 is allowed to just return the size
-        let rc = unsafe { f.get_xattr(xattr_name, buf.as_mut_ptr(), buf.len()) };
-        if rc < 0 {
-            let last_error = io::Error::last_os_error();
-            return match last_error.raw_os_error() {
-                Some(libc::ERANGE) => continue,
-                Some(libc::ENOATTR) => Ok(None),
-                _ => Err(last_error),
-            };
-        }
-        let new_len = rc as usize;
+        let rc =
+            retry_interrupted(|| unsafe { f.get_xattr(xattr_name, buf.as_mut_ptr(), buf.len()) });
+        let new_len = match rc {
+            Ok(rc) => rc as usize,
+            Err(e) => {
+                return match e.raw_os_error() {
+                    Some(libc::ERANGE) => continue,
+                    Some(libc::ENOATTR) => Ok(None),
+                    _ => Err(call_error::<F>(
+                        e,
+                        "getxattr",
+                        xattr_name,
+                        format_args!(", size={}", buf.len()),
+                    )),
+                }
+            }
+        };
         match len.cmp(&new_len) {
             Ordering::Less => {
                 buf.truncate(new_len);
-                break;
+                return Ok(Some(buf));
             }
-            Ordering::Equal => break,
+            Ordering::Equal => return Ok(Some(buf)),
             Ordering::Greater => {
                 buf.resize(new_len, 0);
             }
         }
     }
-    Ok(Some(buf))
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "xattr {} kept changing size while being read",
+            xattr_name.to_string_lossy()
+        ),
+    ))
 }
 
 fn raw_names<F: XattrSource + ?Sized>(f: &F) -> io::Result<Vec<u8>> {
@@ -237,30 +341,31 @@ fn raw_names<F: XattrSource + ?Sized>(f: &F) -> io::Result<Vec<u8>> {
     loop {
         // Safety:
         // it is safe to pass list=null,size=0
-        let rc = unsafe { f.list_xattr(ptr::null_mut(), 0) };
-        if rc < 0 {
-            let e = io::Error::last_os_error();
-            return match e.raw_os_error() {
-                Some(libc::ENOTSUP | libc::EPERM) => Ok(Vec::new()),
-                _ => Err(e),
-            };
-        }
-        let size = rc as usize;
+        let size = match retry_interrupted(|| unsafe { f.list_xattr(ptr::null_mut(), 0) }) {
+            Ok(rc) => rc as usize,
+            Err(e) => {
+                return match e.raw_os_error() {
+                    Some(libc::ENOTSUP | libc::EPERM) => Ok(Vec::new()),
+                    _ => Err(list_error::<F>(e)),
+                }
+            }
+        };
         if size > buf.len() {
             buf.resize(size, 0);
         }
 
         // Safety:
         // buf is valid, and writable for len bytes
-        let rc = unsafe { f.list_xattr(buf.as_mut_ptr(), buf.len()) };
-        if rc < 0 {
-            let e = io::Error::last_os_error();
-            return match e.raw_os_error() {
-                Some(libc::ERANGE) => continue,
-                _ => Err(e),
-            };
-        }
-        let size = rc as usize;
+        let size = match retry_interrupted(|| unsafe { f.list_xattr(buf.as_mut_ptr(), buf.len()) })
+        {
+            Ok(rc) => rc as usize,
+            Err(e) => {
+                return match e.raw_os_error() {
+                    Some(libc::ERANGE) => continue,
+                    _ => Err(list_error::<F>(e)),
+                }
+            }
+        };
         buf.truncate(size);
         break;
     }
@@ -290,3 +395,262 @@ pub fn with_names<T: XattrSource + ?Sized, F: FnMut(&CStr) -> io::Result<()>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    const NAME: &CStr = c"user.test";
+
+    /// An in-memory xattr, which is interrupted by a signal a number of times before each call
+    /// succeeds
+    struct Interrupted {
+        interrupts: u32,
+        remaining: Cell<u32>,
+        value: RefCell<Option<Vec<u8>>>,
+    }
+
+    impl Interrupted {
+        fn new(interrupts: u32) -> Self {
+            Self {
+                interrupts,
+                remaining: Cell::new(interrupts),
+                value: RefCell::new(None),
+            }
+        }
+
+        fn fail(&self, errno: c_int) -> c_int {
+            // SAFETY: __error returns a valid pointer to this thread's errno
+            unsafe { *libc::__error() = errno };
+            -1
+        }
+
+        /// Fail with EINTR until the call has been interrupted enough times
+        fn interrupt(&self) -> bool {
+            match self.remaining.get() {
+                0 => {
+                    self.remaining.set(self.interrupts);
+                    false
+                }
+                n => {
+                    self.remaining.set(n - 1);
+                    self.fail(libc::EINTR);
+                    true
+                }
+            }
+        }
+    }
+
+    impl XattrSource for Interrupted {
+        unsafe fn get_xattr(&self, xattr_name: &CStr, value: *mut u8, size: usize) -> ssize_t {
+            assert_eq!(xattr_name, NAME);
+            if self.interrupt() {
+                return -1;
+            }
+            let stored = self.value.borrow();
+            let Some(stored) = stored.as_deref() else {
+                return self.fail(libc::ENOATTR) as ssize_t;
+            };
+            if !value.is_null() {
+                if size < stored.len() {
+                    return self.fail(libc::ERANGE) as ssize_t;
+                }
+                // SAFETY: caller ensures value is valid for size bytes
+                unsafe { ptr::copy_nonoverlapping(stored.as_ptr(), value, stored.len()) };
+            }
+            stored.len() as ssize_t
+        }
+
+        unsafe fn set_xattr(
+            &self,
+            xattr_name: &CStr,
+            value: *const u8,
+            size: usize,
+            offset: u32,
+        ) -> c_int {
+            assert_eq!(xattr_name, NAME);
+            if self.interrupt() {
+                return -1;
+            }
+            // SAFETY: caller ensures value is valid for size bytes
+            let data = unsafe { std::slice::from_raw_parts(value, size) };
+            let mut stored = self.value.borrow_mut();
+            let stored = stored.get_or_insert_with(Vec::new);
+            let offset = offset as usize;
+            stored.resize(stored.len().max(offset + size), 0);
+            stored[offset..][..size].copy_from_slice(data);
+            0
+        }
+
+        unsafe fn remove_xattr(&self, xattr_name: &CStr) -> c_int {
+            assert_eq!(xattr_name, NAME);
+            if self.interrupt() {
+                return -1;
+            }
+            match self.value.borrow_mut().take() {
+                Some(_) => 0,
+                None => self.fail(libc::ENOATTR),
+            }
+        }
+
+        unsafe fn list_xattr(&self, name_buf: *mut u8, size: usize) -> ssize_t {
+            if self.interrupt() {
+                return -1;
+            }
+            let names: &[u8] = if self.value.borrow().is_some() {
+                NAME.to_bytes_with_nul()
+            } else {
+                &[]
+            };
+            if !name_buf.is_null() {
+                if size < names.len() {
+                    return self.fail(libc::ERANGE) as ssize_t;
+                }
+                // SAFETY: caller ensures name_buf is valid for size bytes
+                unsafe { ptr::copy_nonoverlapping(names.as_ptr(), name_buf, names.len()) };
+            }
+            names.len() as ssize_t
+        }
+    }
+
+    #[test]
+    fn retries_interrupted_calls() {
+        let source = Interrupted::new(3);
+
+        assert_eq!(read(&source, NAME).unwrap(), None);
+        assert!(!has_any(&source).unwrap());
+
+        set(&source, NAME, b"hello", 0).unwrap();
+        set(&source, NAME, b" world", 5).unwrap();
+        assert_eq!(len(&source, NAME).unwrap(), Some(11));
+        assert_eq!(
+            read(&source, NAME).unwrap().as_deref(),
+            Some(&b"hello world"[..])
+        );
+
+        let mut names = Vec::new();
+        with_names(&source, |name| {
+            names.push(name.to_owned());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(names, [NAME.to_owned()]);
+
+        remove(&source, NAME).unwrap();
+        assert!(!is_present(&source, NAME).unwrap());
+    }
+
+    #[test]
+    fn other_errors_not_retried() {
+        let source = Interrupted::new(0);
+        let err = remove(&source, NAME).unwrap_err();
+        assert_eq!(os_error::errno(&err), Some(libc::ENOATTR));
+    }
+
+    #[test]
+    fn errors_name_the_call() {
+        let file = tempfile::tempfile().unwrap();
+        // Longer than any xattr name allowed (XATTR_MAXNAMELEN is 127)
+        let long_name = CString::new("user.".to_owned() + &"x".repeat(200)).unwrap();
+
+        let err = set(&file, &long_name, b"value", 0).unwrap_err();
+        assert!(os_error::errno(&err).is_some(), "{err}");
+        let message = err.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "fsetxattr({}, offset=0) failed: ",
+                long_name.to_string_lossy()
+            )),
+            "{message}"
+        );
+
+        let path = CString::new("/nonexistent/applesauce-xattr-test").unwrap();
+        let err = len(path.as_c_str(), NAME).unwrap_err();
+        assert_eq!(os_error::errno(&err), Some(libc::ENOENT));
+        assert!(
+            err.to_string().starts_with("getxattr(user.test) failed: "),
+            "{err}"
+        );
+    }
+
+    /// An xattr which grows by a byte every time its size is read
+    struct Growing {
+        len: Cell<usize>,
+    }
+
+    impl XattrSource for Growing {
+        unsafe fn get_xattr(&self, xattr_name: &CStr, value: *mut u8, size: usize) -> ssize_t {
+            assert_eq!(xattr_name, NAME);
+            let len = self.len.get();
+            if value.is_null() {
+                self.len.set(len + 1);
+            } else if size < len {
+                // SAFETY: __error returns a valid pointer to this thread's errno
+                unsafe { *libc::__error() = libc::ERANGE };
+                return -1;
+            } else {
+                // SAFETY: caller ensures value is valid for size bytes
+                unsafe { ptr::write_bytes(value, 0, len) };
+            }
+            len as ssize_t
+        }
+
+        unsafe fn set_xattr(
+            &self,
+            _xattr_name: &CStr,
+            _value: *const u8,
+            _size: usize,
+            _offset: u32,
+        ) -> c_int {
+            unreachable!()
+        }
+
+        unsafe fn remove_xattr(&self, _xattr_name: &CStr) -> c_int {
+            unreachable!()
+        }
+
+        unsafe fn list_xattr(&self, _name_buf: *mut u8, _size: usize) -> ssize_t {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn growing_xattr_read_bounded() {
+        let source = Growing { len: Cell::new(10) };
+        let err = read(&source, NAME).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err.to_string().contains("user.test"), "{err}");
+        assert_eq!(source.len.get(), 10 + MAX_READ_ATTEMPTS as usize);
+
+        // Growing past the limit stops reading immediately
+        let source = Growing { len: Cell::new(10) };
+        let err = read_limited(&source, NAME, 11).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(source.len.get(), 13);
+    }
+
+    #[test]
+    fn read_limit() {
+        let file = tempfile::tempfile().unwrap();
+        let value = vec![7; 1024 * 1024];
+        set(&file, NAME, &value, 0).unwrap();
+
+        assert_eq!(read(&file, NAME).unwrap().unwrap(), value);
+        assert_eq!(
+            read_limited(&file, NAME, value.len()).unwrap().unwrap(),
+            value
+        );
+        let err = read_limited(&file, NAME, value.len() - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "xattr user.test is {} bytes, larger than the limit of {} bytes",
+                value.len(),
+                value.len() - 1
+            )
+        );
+        assert_eq!(read_limited(&file, c"user.missing", 0).unwrap(), None);
+    }
+}
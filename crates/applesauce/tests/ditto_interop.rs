@@ -0,0 +1,192 @@
+//! Interoperability with the system tools that understand decmpfs compression
+//!
+//! Files compressed by applesauce should be preserved as compressed when copied with
+//! `ditto --hfsCompression` or cloned with `cp -c`, and files compressed by `ditto` should be
+//! readable, verifiable and decompressible by applesauce.
+
+use applesauce::compressor::Kind;
+use applesauce::info::{self, AfscFileInfo};
+use applesauce::progress::{Progress, Task};
+use applesauce::{CompressionOptions, DecompressionOptions, FileCompressor, Verify};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+struct NoProgress;
+impl Task for NoProgress {
+    fn increment(&self, _amt: u64) {}
+    fn error(&self, message: &str) {
+        panic!("Expected no errors, got {message}");
+    }
+}
+impl Progress for NoProgress {
+    type Task = NoProgress;
+
+    fn error(&self, path: &Path, message: &str) {
+        panic!("Expected no errors, got {message} for {path:?}");
+    }
+
+    fn file_task(&self, _path: &Path, _size: u64, _root: usize) -> Self::Task {
+        NoProgress
+    }
+}
+
+/// Contents which exercise both xattr storage (small) and resource fork storage (multi-block)
+fn fixtures() -> Vec<(&'static str, Vec<u8>)> {
+    let mut multi_block = Vec::new();
+    for i in 0u8..=0xFF {
+        multi_block.extend_from_slice(&[i; 1234]);
+    }
+    vec![
+        ("small", b"hello hello hello hello hello hello".repeat(10)),
+        ("single_block", vec![b'a'; 16 * 1024]),
+        ("multi_block", multi_block),
+    ]
+}
+
+fn write_fixtures(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    fixtures()
+        .into_iter()
+        .map(|(name, content)| {
+            let path = dir.join(name);
+            fs::write(&path, &content).unwrap();
+            (path, content)
+        })
+        .collect()
+}
+
+fn run(command: &mut Command) {
+    let status = command.status().unwrap();
+    assert!(status.success(), "{command:?} failed with {status}");
+}
+
+fn assert_same_compression(orig: &AfscFileInfo, copy: &AfscFileInfo, path: &Path) {
+    assert!(
+        copy.is_compressed,
+        "{} was not kept compressed",
+        path.display()
+    );
+    assert_eq!(orig.stat_size, copy.stat_size);
+
+    let orig_decmpfs = orig.decmpfs_info.as_ref().unwrap().as_ref().unwrap();
+    let copy_decmpfs = copy.decmpfs_info.as_ref().unwrap().as_ref().unwrap();
+    assert_eq!(orig_decmpfs.compression_type, copy_decmpfs.compression_type);
+    assert_eq!(orig_decmpfs.attribute_size, copy_decmpfs.attribute_size);
+    assert_eq!(orig_decmpfs.orig_file_size, copy_decmpfs.orig_file_size);
+    assert_eq!(orig.resource_fork_size, copy.resource_fork_size);
+}
+
+fn copy_preserves_compression(kind: Kind, copy: impl Fn(&Path, &Path) -> Command) {
+    let src_dir = TempDir::new().unwrap();
+    let dst_dir = TempDir::new().unwrap();
+    let files = write_fixtures(src_dir.path());
+
+    let mut fc = FileCompressor::new();
+    let options = CompressionOptions::new()
+        .kind(kind)
+        .minimum_ratio(1.0)
+        .verify(Verify::Checksum);
+    fc.compress_with([src_dir.path()], &options, &NoProgress)
+        .unwrap();
+
+    for (path, content) in files {
+        let orig_info = info::get(&path).unwrap();
+        assert!(orig_info.is_compressed, "{} not compressed", path.display());
+
+        let dst = dst_dir.path().join(path.file_name().unwrap());
+        run(&mut copy(&path, &dst));
+
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        assert_same_compression(&orig_info, &info::get(&dst).unwrap(), &dst);
+    }
+}
+
+fn ditto(src: &Path, dst: &Path) -> Command {
+    let mut command = Command::new("ditto");
+    command.arg("--hfsCompression").arg(src).arg(dst);
+    command
+}
+
+fn clone(src: &Path, dst: &Path) -> Command {
+    let mut command = Command::new("cp");
+    command.arg("-c").arg(src).arg(dst);
+    command
+}
+
+macro_rules! interop_tests {
+    ($($name:ident => $kind:expr),* $(,)?) => {
+        $(
+            mod $name {
+                use super::*;
+
+                #[test]
+                fn ditto_preserves_compression() {
+                    copy_preserves_compression($kind, ditto);
+                }
+
+                #[test]
+                fn clone_preserves_compression() {
+                    copy_preserves_compression($kind, clone);
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "zlib")]
+interop_tests!(zlib => Kind::Zlib);
+
+#[cfg(feature = "lzvn")]
+interop_tests!(lzvn => Kind::Lzvn);
+
+#[cfg(feature = "lzfse")]
+interop_tests!(lzfse => Kind::Lzfse);
+
+#[test]
+fn ditto_compressed_files_are_readable() {
+    let src_dir = TempDir::new().unwrap();
+    let dst_dir = TempDir::new().unwrap();
+    let files = write_fixtures(src_dir.path());
+
+    for (path, content) in files {
+        let dst = dst_dir.path().join(path.file_name().unwrap());
+        run(&mut ditto(&path, &dst));
+
+        let ditto_info = info::get(&dst).unwrap();
+        assert!(
+            ditto_info.is_compressed,
+            "ditto did not compress {}",
+            dst.display()
+        );
+        let decmpfs_info = ditto_info.decmpfs_info.as_ref().unwrap().as_ref().unwrap();
+        assert_eq!(decmpfs_info.orig_file_size, content.len() as u64);
+        assert!(
+            decmpfs_info
+                .compression_type
+                .compression_storage()
+                .is_some(),
+            "unknown compression type {} from ditto",
+            decmpfs_info.compression_type,
+        );
+
+        // Already compressed files are skipped, and left intact
+        let mut fc = FileCompressor::new();
+        let options = CompressionOptions::new()
+            .minimum_ratio(1.0)
+            .verify(Verify::Checksum);
+        fc.compress_with([dst.as_path()], &options, &NoProgress)
+            .unwrap();
+        assert_same_compression(&ditto_info, &info::get(&dst).unwrap(), &dst);
+
+        // Only decompress manually if we were built with support for the kind ditto chose
+        let (kind, _) = decmpfs_info.compression_type.compression_storage().unwrap();
+        let mut fc = FileCompressor::new();
+        let options = DecompressionOptions::new()
+            .manual(kind.supported())
+            .verify(Verify::Checksum);
+        fc.decompress_with([dst.as_path()], &options, &NoProgress);
+        assert!(!info::get(&dst).unwrap().is_compressed);
+        assert_eq!(fs::read(&dst).unwrap(), content);
+    }
+}
@@ -1,7 +1,8 @@
 use libc::XATTR_SHOWCOMPRESSION;
 use std::ffi::CStr;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
 use std::os::unix::io::AsRawFd;
 use std::{cmp, io, ptr};
 
@@ -11,162 +12,360 @@ pub const XATTR_NAME: &CStr = {
     unsafe { CStr::from_bytes_with_nul_unchecked(bytes) }
 };
 
-/// A Handle to a Resource Fork
+/// The name of the xattr holding the compression header of a compressed file
+const DECMPFS_XATTR_NAME: &CStr = {
+    let bytes: &'static [u8] = b"com.apple.decmpfs\0";
+    // SAFETY: bytes are static, and null terminated, without internal nulls
+    unsafe { CStr::from_bytes_with_nul_unchecked(bytes) }
+};
+
+/// Call `f` until it is not interrupted by a signal
 ///
-/// A Resource Fork is a macos specific file attribute that contains arbitrary
-/// binary data.
-pub struct ResourceFork<'a> {
+/// A negative return value is converted to the error from `errno`.
+fn retry_interrupted<T: Copy + PartialOrd + From<i8>>(mut f: impl FnMut() -> T) -> io::Result<T> {
+    loop {
+        let rc = f();
+        if rc >= T::from(0) {
+            return Ok(rc);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// A handle to a single xattr of a file, which can be read, written, and seeked like a file
+///
+/// The resource fork can be read and written at any offset, so reads and writes go straight to
+/// the file. macOS doesn't allow an offset for any other xattr: the whole value is read into
+/// memory the first time it's needed, and writes change that copy, which is only written to the
+/// file when flushed (or dropped, ignoring any error, like [`io::BufWriter`]). Changes made to the
+/// xattr by anything else after it was read are not seen, and are overwritten by a flush.
+pub struct XattrStream<'a> {
     file: &'a File,
+    name: &'a CStr,
+    options: libc::c_int,
     position: u32,
+    /// The value of an xattr which can't be accessed at an offset
+    ///
+    /// `None` for the resource fork.
+    whole: Option<WholeValue>,
 }
 
-impl<'a> ResourceFork<'a> {
-    /// Create a new Resource Fork handle
+#[derive(Default)]
+struct WholeValue {
+    /// The value, once it has been read (empty if the xattr doesn't exist)
+    value: Option<Vec<u8>>,
+    /// The value has been written to, but not written to the file
+    dirty: bool,
+}
+
+impl<'a> XattrStream<'a> {
+    /// Create a new handle to the xattr named `name`
     ///
-    /// Note that if the file does not already have a resource fork, it will
-    /// only be created when the first write is performed.
+    /// Note that if the file does not already have the xattr, it will only be created when the
+    /// first write is performed (or flushed, for xattrs other than the resource fork).
     #[must_use]
-    pub fn new(file: &'a File) -> Self {
-        Self { file, position: 0 }
+    pub fn new(file: &'a File, name: &'a CStr) -> Self {
+        // Only the compression xattrs are hidden without XATTR_SHOWCOMPRESSION
+        let options = if name == XATTR_NAME || name == DECMPFS_XATTR_NAME {
+            XATTR_SHOWCOMPRESSION
+        } else {
+            0
+        };
+        let whole = (name != XATTR_NAME).then(WholeValue::default);
+        Self {
+            file,
+            name,
+            options,
+            position: 0,
+            whole,
+        }
+    }
+
+    /// The name of the xattr
+    #[must_use]
+    pub fn name(&self) -> &'a CStr {
+        self.name
     }
 
-    /// Returns the current position of the resource fork
+    /// Returns the current position in the xattr
     #[must_use]
     pub fn position(&self) -> u32 {
         self.position
     }
 
-    /// Seek to a new position in the resource fork infallibly
+    /// Seek to a new position in the xattr infallibly
     pub fn set_position(&mut self, position: u32) {
         self.position = position;
     }
 
-    /// Remove the resource fork from the file
+    /// Remove the xattr from the file
     ///
-    /// This will remove any existing resource fork
+    /// This will remove any existing value, including any unflushed writes.
     ///
     /// Note that this does not reset the current offset, it may be desired to
-    /// seek to the beginning of the resource fork after calling this, if you wish to
-    /// continue writing to the resource fork
+    /// seek to the beginning of the xattr after calling this, if you wish to
+    /// continue writing to it
     pub fn delete(&mut self) -> io::Result<()> {
         // SAFETY:
         //   fd is valid because we have a handle to the file
-        //   xattr name is valid, and null terminated because it's a static CStr
-        let rc = unsafe {
-            libc::fremovexattr(
+        //   xattr name is valid, and null terminated because it's a CStr
+        retry_interrupted(|| unsafe {
+            libc::fremovexattr(self.file.as_raw_fd(), self.name.as_ptr(), self.options)
+        })?;
+        if let Some(whole) = &mut self.whole {
+            whole.value = Some(Vec::new());
+            whole.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Read from the xattr at `offset`, without changing the current position
+    ///
+    /// Returns the number of bytes read, which will be zero if `offset` is at or past the end
+    /// of the xattr (or if the file doesn't have the xattr).
+    ///
+    /// Reads of the resource fork are performed with a single call to `fgetxattr`, so it is much
+    /// more efficient to read large chunks at once than to perform many small reads. Until the
+    /// value of any other xattr has been read with [`Read`], each call reads the whole value.
+    pub fn read_at(&self, buf: &mut [u8], offset: u32) -> io::Result<usize> {
+        if let Some(whole) = &self.whole {
+            let read;
+            let value = match &whole.value {
+                Some(value) => value,
+                None => {
+                    read = self.read_whole()?;
+                    &read
+                }
+            };
+            return Ok(copy_from_offset(value, buf, offset));
+        }
+
+        // Despite the manpage for getxattr saying:
+        // > On success, the size of the extended attribute data is returned
+        // it actually returns the size remaining _after_ the passed index
+
+        // SAFETY:
+        //   fd is valid because we have a handle to the file
+        //   xattr name is valid, and null terminated because it's a CStr
+        //   buf is valid, and writable for up to len() bytes because it's passed as a mut slice
+        let rc = retry_interrupted(|| unsafe {
+            libc::fgetxattr(
                 self.file.as_raw_fd(),
-                XATTR_NAME.as_ptr(),
-                XATTR_SHOWCOMPRESSION,
+                self.name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                offset,
+                self.options,
             )
+        });
+        let remaining_len = match rc {
+            Ok(rc) => rc as usize,
+            Err(e) if e.raw_os_error() == Some(libc::ENOATTR) => 0,
+            Err(e) => return Err(e),
         };
-        if rc != 0 {
-            return Err(io::Error::last_os_error());
+        Ok(cmp::min(remaining_len, buf.len()))
+    }
+
+    /// Read exactly `buf.len()` bytes from the xattr at `offset`, without changing the
+    /// current position
+    ///
+    /// Returns an error of kind [`io::ErrorKind::UnexpectedEof`] if the xattr ends
+    /// before `buf` could be filled.
+    pub fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u32) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset = offset
+                        .checked_add(u32::try_from(n).unwrap())
+                        .ok_or(io::ErrorKind::InvalidInput)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
         }
         Ok(())
     }
+
+    /// The length of the xattr as stored in the file, or 0 if the file doesn't have it
+    fn stored_len(&self) -> io::Result<usize> {
+        // SAFETY:
+        // fd is valid because we have a handle to the file
+        // xattr name is valid, and null terminated because it's a CStr
+        // value == NULL && size == 0 is allowed, to just return the length of the value
+        let rc = retry_interrupted(|| unsafe {
+            libc::fgetxattr(
+                self.file.as_raw_fd(),
+                self.name.as_ptr(),
+                ptr::null_mut(),
+                0,
+                0,
+                self.options,
+            )
+        });
+        match rc {
+            Ok(rc) => Ok(rc.try_into().unwrap()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOATTR) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the whole value of the xattr, or an empty value if the file doesn't have it
+    fn read_whole(&self) -> io::Result<Vec<u8>> {
+        loop {
+            let mut value = vec![0; self.stored_len()?];
+            // SAFETY:
+            //   fd is valid because we have a handle to the file
+            //   xattr name is valid, and null terminated because it's a CStr
+            //   value is writable for up to len() bytes
+            let rc = retry_interrupted(|| unsafe {
+                libc::fgetxattr(
+                    self.file.as_raw_fd(),
+                    self.name.as_ptr(),
+                    value.as_mut_ptr().cast(),
+                    value.len(),
+                    0,
+                    self.options,
+                )
+            });
+            match rc {
+                Ok(len) => {
+                    value.truncate(len as usize);
+                    return Ok(value);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOATTR) => return Ok(Vec::new()),
+                // The value grew since its length was read
+                Err(e) if e.raw_os_error() == Some(libc::ERANGE) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The value of an xattr which can't be accessed at an offset, read if it hasn't been yet
+    ///
+    /// Returns `None` for the resource fork.
+    fn whole_value(&mut self) -> io::Result<Option<&mut WholeValue>> {
+        if matches!(&self.whole, Some(whole) if whole.value.is_none()) {
+            let value = self.read_whole()?;
+            self.whole.as_mut().unwrap().value = Some(value);
+        }
+        Ok(self.whole.as_mut())
+    }
 }
 
-impl io::Write for ResourceFork<'_> {
+/// Copy from `value` at `offset` into `buf`, returning the number of bytes copied
+fn copy_from_offset(value: &[u8], buf: &mut [u8], offset: u32) -> usize {
+    let remaining = value.get(offset as usize..).unwrap_or_default();
+    let len = cmp::min(remaining.len(), buf.len());
+    buf[..len].copy_from_slice(&remaining[..len]);
+    len
+}
+
+impl Write for XattrStream<'_> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len: u32 = buf
             .len()
             .try_into()
             .map_err(|_| io::ErrorKind::InvalidInput)?;
         let end_offset = self.position.checked_add(len).ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                "unable to fit resource fork in 32 bits",
-            )
+            io::Error::new(io::ErrorKind::Other, "unable to fit xattr in 32 bits")
         })?;
+
+        let position = self.position;
+        if let Some(whole) = self.whole_value()? {
+            let value = whole.value.as_mut().unwrap();
+            let end = end_offset as usize;
+            if value.len() < end {
+                // Writing past the end fills the gap with zeros, like a file
+                value.resize(end, 0);
+            }
+            value[position as usize..end].copy_from_slice(buf);
+            whole.dirty = true;
+            self.position = end_offset;
+            return Ok(buf.len());
+        }
+
+        // fsetxattr either writes the whole buffer or fails, there are no partial writes
+
         // SAFETY:
         // fd is valid
         // xattr name is valid
-        let rc = unsafe {
+        retry_interrupted(|| unsafe {
             libc::fsetxattr(
                 self.file.as_raw_fd(),
-                XATTR_NAME.as_ptr(),
+                self.name.as_ptr(),
                 buf.as_ptr().cast(),
                 buf.len(),
                 self.position,
-                XATTR_SHOWCOMPRESSION,
+                self.options,
             )
-        };
-        if rc != 0 {
-            return Err(io::Error::last_os_error());
-        }
+        })?;
         self.position = end_offset;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        let Some(whole) = &mut self.whole else {
+            return Ok(());
+        };
+        if !whole.dirty {
+            return Ok(());
+        }
+        let value = whole.value.as_deref().unwrap_or_default();
+        // SAFETY:
+        // fd is valid
+        // xattr name is valid
+        // value is valid for len() bytes
+        retry_interrupted(|| unsafe {
+            libc::fsetxattr(
+                self.file.as_raw_fd(),
+                self.name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+                self.options,
+            )
+        })?;
+        whole.dirty = false;
         Ok(())
     }
 }
 
-impl Read for ResourceFork<'_> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        // Despite the manpage for getxattr saying:
-        // > On success, the size of the extended attribute data is returned
-        // it actually returns the size remaining _after_ the passed index
+impl Drop for XattrStream<'_> {
+    fn drop(&mut self) {
+        // Like BufWriter, errors writing on drop are ignored, flush to see them
+        let _ = self.flush();
+    }
+}
 
-        // SAFETY:
-        //   fd is valid because we have a handle to the file
-        //   xattr name is valid, and null terminated because it's a static CStr
-        //   buf is valid, and writable for up to len() bytes because it's passed as a mut slice
-        let rc = unsafe {
-            libc::fgetxattr(
-                self.file.as_raw_fd(),
-                XATTR_NAME.as_ptr(),
-                buf.as_mut_ptr().cast(),
-                buf.len(),
-                self.position,
-                XATTR_SHOWCOMPRESSION,
-            )
-        };
-        let remaining_len = if rc < 0 {
-            let e = io::Error::last_os_error();
-            if e.raw_os_error() == Some(libc::ENOATTR) {
-                0
-            } else {
-                return Err(e);
-            }
-        } else {
-            rc as usize
+impl Read for XattrStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position;
+        let bytes_read = match self.whole_value()? {
+            Some(whole) => copy_from_offset(whole.value.as_ref().unwrap(), buf, position),
+            None => self.read_at(buf, position)?,
         };
-        let bytes_read = cmp::min(remaining_len, buf.len());
         self.position += u32::try_from(bytes_read).unwrap();
         Ok(bytes_read)
     }
 }
 
-impl Seek for ResourceFork<'_> {
+impl Seek for XattrStream<'_> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let new_offset: u32 = match pos {
             SeekFrom::Start(i) => i.try_into().map_err(|_| io::ErrorKind::InvalidInput)?,
             SeekFrom::End(i) => {
-                // SAFETY:
-                // fd is valid because we have a handle to the file
-                // xattr name is valid, and null terminated because it's a static CStr
-                // value == NULL && size == 0 is allowed, to just return the length of the value
-                let mut rc = unsafe {
-                    libc::fgetxattr(
-                        self.file.as_raw_fd(),
-                        XATTR_NAME.as_ptr(),
-                        ptr::null_mut(),
-                        0,
-                        0,
-                        XATTR_SHOWCOMPRESSION,
-                    )
+                let end = match &self.whole {
+                    Some(WholeValue {
+                        value: Some(value), ..
+                    }) => value.len(),
+                    _ => self.stored_len()?,
                 };
-                if rc < 0 {
-                    let e = io::Error::last_os_error();
-                    if e.raw_os_error() == Some(libc::ENOATTR) {
-                        rc = 0;
-                    } else {
-                        return Err(e);
-                    }
-                }
-                let end: u64 = rc.try_into().unwrap();
+                let end: u64 = end.try_into().unwrap();
                 let offset = end
                     .checked_add_signed(i)
                     .ok_or(io::ErrorKind::InvalidInput)?;
@@ -185,6 +384,59 @@ impl Seek for ResourceFork<'_> {
     }
 }
 
+/// A Handle to a Resource Fork
+///
+/// A Resource Fork is a macos specific file attribute that contains arbitrary
+/// binary data. This is an [`XattrStream`] for the [`XATTR_NAME`] xattr.
+pub struct ResourceFork<'a>(XattrStream<'a>);
+
+impl<'a> ResourceFork<'a> {
+    /// Create a new Resource Fork handle
+    ///
+    /// Note that if the file does not already have a resource fork, it will
+    /// only be created when the first write is performed.
+    #[must_use]
+    pub fn new(file: &'a File) -> Self {
+        Self(XattrStream::new(file, XATTR_NAME))
+    }
+}
+
+impl<'a> Deref for ResourceFork<'a> {
+    type Target = XattrStream<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ResourceFork<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Write for ResourceFork<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Read for ResourceFork<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for ResourceFork<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -320,4 +572,237 @@ mod tests {
         // We read it all
         assert_eq!(rfork.read(&mut buf).unwrap(), 0);
     }
+
+    #[test]
+    fn read_at() {
+        let file = tempfile::tempfile().unwrap();
+        let mut rfork = ResourceFork::new(&file);
+
+        let data = b"hi there";
+        assert_eq!(rfork.write(data).unwrap(), data.len());
+
+        let mut buf = [0; 5];
+        assert_eq!(rfork.read_at(&mut buf, 3).unwrap(), 5);
+        assert_eq!(&buf, b"there");
+        assert_eq!(rfork.read_at(&mut buf, 6).unwrap(), 2);
+        assert_eq!(&buf[..2], b"re");
+        assert_eq!(rfork.read_at(&mut buf, 100).unwrap(), 0);
+        // Positional reads don't move the current position
+        assert_eq!(rfork.position(), data.len() as u32);
+
+        let mut buf = [0; 2];
+        rfork.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hi");
+        let mut buf = [0; 4];
+        let err = rfork.read_exact_at(&mut buf, 6).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(rfork.position(), data.len() as u32);
+    }
+
+    #[test]
+    fn sequential_reads() {
+        let file = tempfile::tempfile().unwrap();
+        let mut rfork = ResourceFork::new(&file);
+        rfork.write_all(b"hi there").unwrap();
+        rfork.rewind().unwrap();
+
+        let mut buf = [0; 3];
+        assert_eq!(rfork.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hi ");
+        assert_eq!(rfork.position(), 3);
+        // A read after a partial read continues where the last read stopped
+        assert_eq!(rfork.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"the");
+        assert_eq!(rfork.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"re");
+        assert_eq!(rfork.read(&mut buf).unwrap(), 0);
+        assert_eq!(rfork.position(), 8);
+    }
+
+    #[test]
+    fn read_to_end_after_seek() {
+        let file = tempfile::tempfile().unwrap();
+        let mut rfork = ResourceFork::new(&file);
+        rfork.write_all(b"hi there").unwrap();
+
+        rfork.seek(SeekFrom::Start(3)).unwrap();
+        let mut buf = [0; 2];
+        rfork.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"th");
+
+        let mut buf_vec = Vec::new();
+        assert_eq!(rfork.read_to_end(&mut buf_vec).unwrap(), 3);
+        assert_eq!(buf_vec, b"ere");
+        assert_eq!(rfork.position(), 8);
+
+        rfork.seek(SeekFrom::End(-5)).unwrap();
+        let mut buf_string = String::new();
+        rfork.read_to_string(&mut buf_string).unwrap();
+        assert_eq!(buf_string, "there");
+    }
+
+    #[test]
+    fn interleaved_read_write() {
+        let file = tempfile::tempfile().unwrap();
+        let mut rfork = ResourceFork::new(&file);
+        rfork.write_all(b"hello world").unwrap();
+
+        rfork.rewind().unwrap();
+        let mut buf = [0; 6];
+        rfork.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello ");
+        // Writes happen at the position after the read
+        rfork.write_all(b"there").unwrap();
+        assert_eq!(rfork.position(), 11);
+        rfork.write_all(b"!").unwrap();
+
+        rfork.seek(SeekFrom::Current(-6)).unwrap();
+        let mut buf_vec = Vec::new();
+        rfork.read_to_end(&mut buf_vec).unwrap();
+        assert_eq!(buf_vec, b"there!");
+
+        rfork.rewind().unwrap();
+        buf_vec.clear();
+        rfork.read_to_end(&mut buf_vec).unwrap();
+        assert_eq!(buf_vec, b"hello there!");
+    }
+
+    const CUSTOM_NAME: &CStr = {
+        let bytes: &'static [u8] = b"com.example.resource-fork.test\0";
+        // SAFETY: bytes are static, and null terminated, without internal nulls
+        unsafe { CStr::from_bytes_with_nul_unchecked(bytes) }
+    };
+
+    #[test]
+    fn custom_xattr_round_trip() {
+        const LEN: usize = 4 * 1024 * 1024;
+        let file = NamedTempFile::new().unwrap();
+        let path = CString::new(file.path().as_os_str().as_bytes()).unwrap();
+        let mut expected: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+
+        let mut stream = XattrStream::new(file.as_file(), CUSTOM_NAME);
+        for chunk in expected.chunks(64 * 1024) {
+            stream.write_all(chunk).unwrap();
+        }
+        // Only written to the file when flushed
+        assert!(!xattr::is_present(&path, CUSTOM_NAME).unwrap());
+        stream.flush().unwrap();
+        assert_eq!(xattr::len(&path, CUSTOM_NAME).unwrap(), Some(LEN));
+
+        let mut buf = [0; 100];
+        stream.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[1024 * 1024..][..100]);
+        stream.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], expected[LEN - 10..]);
+
+        // Overwrite in the middle, and write past the end
+        stream.seek(SeekFrom::Current(-(LEN as i64) / 2)).unwrap();
+        stream.write_all(b"middle").unwrap();
+        expected[LEN / 2..][..6].copy_from_slice(b"middle");
+        stream.seek(SeekFrom::End(10)).unwrap();
+        stream.write_all(b"end").unwrap();
+        expected.extend_from_slice(&[0; 10]);
+        expected.extend_from_slice(b"end");
+        // Dropping writes unflushed changes
+        drop(stream);
+
+        let mut stream = XattrStream::new(file.as_file(), CUSTOM_NAME);
+        let mut buf = [0; 6];
+        stream.read_exact_at(&mut buf, LEN as u32 / 2).unwrap();
+        assert_eq!(&buf, b"middle");
+        let mut contents = Vec::new();
+        stream.read_to_end(&mut contents).unwrap();
+        assert!(contents == expected, "contents differ");
+
+        stream.delete().unwrap();
+        assert!(!xattr::is_present(&path, CUSTOM_NAME).unwrap());
+        assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn custom_xattr_not_exist() {
+        let file = tempfile::tempfile().unwrap();
+        let mut stream = XattrStream::new(&file, CUSTOM_NAME);
+        assert_eq!(stream.name(), CUSTOM_NAME);
+
+        let mut buf = [0; 16];
+        assert_eq!(stream.read_at(&mut buf, 0).unwrap(), 0);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), 0);
+        // Nothing was written, so flushing doesn't create it
+        stream.flush().unwrap();
+        assert_eq!(stream.stored_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn retries_interrupted() {
+        let mut calls = 0;
+        let rc = retry_interrupted(|| {
+            calls += 1;
+            if calls <= 3 {
+                // SAFETY: __error returns a valid pointer to this thread's errno
+                unsafe { *libc::__error() = libc::EINTR };
+                -1
+            } else {
+                0
+            }
+        });
+        assert_eq!(rc.unwrap(), 0);
+        assert_eq!(calls, 4);
+
+        let mut calls = 0;
+        let err = retry_interrupted(|| {
+            calls += 1;
+            // SAFETY: __error returns a valid pointer to this thread's errno
+            unsafe { *libc::__error() = libc::ENOSPC };
+            -1
+        })
+        .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    #[ignore = "writes a 1GB resource fork"]
+    fn large_fork_reads_scale_linearly() {
+        use std::time::{Duration, Instant};
+
+        const CHUNK_SIZE: usize = 64 * 1024;
+        const TOTAL_SIZE: usize = 1024 * 1024 * 1024;
+        const SAMPLE_CHUNKS: usize = 256;
+
+        let file = tempfile::tempfile().unwrap();
+        let mut rfork = ResourceFork::new(&file);
+        let chunk = vec![0x5A; 1024 * 1024];
+        for _ in 0..TOTAL_SIZE / chunk.len() {
+            rfork.write_all(&chunk).unwrap();
+        }
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        // Blocks are read like applesauce_core::reader::Reader reads them: a seek to the block,
+        // then one read of the whole block
+        let mut time_reads = |start: usize| -> Duration {
+            let start_time = Instant::now();
+            for i in 0..SAMPLE_CHUNKS {
+                let offset = u64::try_from(start + i * CHUNK_SIZE).unwrap();
+                rfork.seek(SeekFrom::Start(offset)).unwrap();
+                rfork.read_exact(&mut buf).unwrap();
+            }
+            start_time.elapsed()
+        };
+        // Warm up, so the first timing doesn't include faulting in the buffer
+        time_reads(0);
+        let start = time_reads(0);
+        let end = time_reads(TOTAL_SIZE - SAMPLE_CHUNKS * CHUNK_SIZE);
+
+        // Reads near the end of the fork should cost the same as reads near the start. If each
+        // read cost time proportional to its offset, reads at the end would be hundreds of times
+        // slower.
+        assert!(
+            end.as_secs_f64() < start.as_secs_f64() * 1.5,
+            "reads at end of fork took {end:?}, reads at start took {start:?}"
+        );
+    }
 }